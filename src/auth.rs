@@ -0,0 +1,419 @@
+// src/auth.rs
+
+//! SASL authentication of connecting clients.
+//!
+//! The broker offers the mechanisms of its `AuthProvider` that
+//! `Config::sasl_mechanisms` allows in `Connection.Start`. The client picks
+//! one in `Start-Ok`, which opens a `SaslSession`; picking one the config
+//! leaves out is refused with `NOT_ALLOWED`. The session is fed the
+//! response carried by `Start-Ok` and answers with either the outcome or a
+//! challenge. A challenge is sent in `Connection.Secure`, and the client's
+//! `Secure-Ok` response is fed back to the session, until it reaches an
+//! outcome. Single-step mechanisms such as PLAIN never challenge.
+//!
+//! `PlainAuth` takes every client at its word. `PasswordAuth` checks PLAIN
+//! passwords against a `UserStore`, which also holds each user's
+//! permissions per virtual host and can be changed while the broker runs;
+//! changes apply to authentications from then on. The store keeps only a
+//! salted hash of each password, computed as RabbitMQ's
+//! `rabbit_password_hashing_sha256` does, so users exported by RabbitMQ
+//! log in here unchanged. Fresh hashes take a longer salt than RabbitMQ's
+//! four bytes. Of the permissions, only
+//! whether a user may open a virtual host at all is enforced so far.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+/// What a `SaslSession` wants after consuming a client response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SaslStep {
+    /// Send this challenge in `Connection.Secure` and wait for `Secure-Ok`.
+    Challenge(Vec<u8>),
+    /// The client is authenticated, as the given user if the mechanism
+    /// establishes one.
+    Authenticated(Option<String>),
+}
+
+/// Why a client was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthFailure(pub String);
+
+impl fmt::Display for AuthFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// One client's run through a mechanism.
+pub trait SaslSession: Send {
+    /// Consumes the next client response.
+    fn step(&mut self, response: &[u8]) -> Result<SaslStep, AuthFailure>;
+}
+
+/// Source of the SASL mechanisms a broker accepts.
+pub trait AuthProvider: Send + Sync {
+    /// Mechanism names offered in `Connection.Start`, most preferred first.
+    fn mechanisms(&self) -> Vec<String>;
+
+    /// Begins authenticating with `mechanism`, or returns `None` if the
+    /// provider does not support it.
+    fn start(&self, mechanism: &str) -> Option<Box<dyn SaslSession>>;
+
+    /// The users behind the provider, if they can be changed while the
+    /// broker runs.
+    fn users(&self) -> Option<&UserStore> {
+        None
+    }
+}
+
+/// The default provider: SASL PLAIN, taking the client's word for who it is.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlainAuth;
+
+impl AuthProvider for PlainAuth {
+    fn mechanisms(&self) -> Vec<String> {
+        vec!["PLAIN".into()]
+    }
+
+    fn start(&self, mechanism: &str) -> Option<Box<dyn SaslSession>> {
+        (mechanism == "PLAIN").then(|| Box::new(PlainSession) as Box<dyn SaslSession>)
+    }
+}
+
+struct PlainSession;
+
+impl SaslSession for PlainSession {
+    fn step(&mut self, response: &[u8]) -> Result<SaslStep, AuthFailure> {
+        Ok(SaslStep::Authenticated(plain_username(response)))
+    }
+}
+
+/// Extracts the authentication identity from a SASL PLAIN response
+/// (`authzid NUL authcid NUL password`).
+pub fn plain_username(response: &[u8]) -> Option<String> {
+    plain_credentials(response).map(|(username, _)| username)
+}
+
+/// Extracts the authentication identity and password from a SASL PLAIN
+/// response.
+pub fn plain_credentials(response: &[u8]) -> Option<(String, String)> {
+    let mut parts = response.split(|&b| b == 0);
+    let (_authzid, authcid, password) = (parts.next()?, parts.next()?, parts.next()?);
+    let username = String::from_utf8(authcid.to_vec()).ok()?;
+    let password = String::from_utf8(password.to_vec()).ok()?;
+    Some((username, password))
+}
+
+/// Tag of users allowed to change users and permissions.
+pub const ADMIN_TAG: &str = "administrator";
+
+/// Name of the scheme of `PasswordHash` in definitions documents.
+pub const PASSWORD_HASHING: &str = "rabbit_password_hashing_sha256";
+
+/// Bytes of salt in a fresh `PasswordHash`.
+const SALT_LEN: usize = 16;
+
+/// A password as a `UserStore` keeps it: SHA-256 of a random salt followed
+/// by the password. The default matches no password.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PasswordHash {
+    salt: Vec<u8>,
+    digest: [u8; 32],
+}
+
+impl PasswordHash {
+    /// Hashes `password` with a fresh salt from the operating system's
+    /// random number generator.
+    pub fn new(password: &str) -> Self {
+        let mut salt = vec![0; SALT_LEN];
+        getrandom::getrandom(&mut salt).expect("the system random number generator failed");
+        PasswordHash::with_salt(salt, password)
+    }
+
+    fn with_salt(salt: Vec<u8>, password: &str) -> Self {
+        let digest = Sha256::new()
+            .chain_update(&salt)
+            .chain_update(password.as_bytes())
+            .finalize()
+            .into();
+        PasswordHash { salt, digest }
+    }
+
+    /// Whether `password` hashes to this digest, compared in constant time.
+    pub fn matches(&self, password: &str) -> bool {
+        let candidate = PasswordHash::with_salt(self.salt.clone(), password);
+        let difference = self
+            .digest
+            .iter()
+            .zip(candidate.digest)
+            .fold(0, |difference, (a, b)| difference | (a ^ b));
+        difference == 0
+    }
+
+    /// The salt followed by the digest, base64-encoded, as RabbitMQ writes
+    /// `password_hash`.
+    pub fn encode(&self) -> String {
+        let mut bytes = self.salt.to_vec();
+        bytes.extend_from_slice(&self.digest);
+        base64::engine::general_purpose::STANDARD.encode(bytes)
+    }
+
+    /// Reads a hash `encode` wrote, whatever the length of its salt.
+    pub fn decode(encoded: &str) -> Option<Self> {
+        let bytes = base64::engine::general_purpose::STANDARD.decode(encoded).ok()?;
+        let (salt, digest) = bytes.split_last_chunk::<32>()?;
+        if salt.is_empty() {
+            return None;
+        }
+        Some(PasswordHash {
+            salt: salt.to_vec(),
+            digest: *digest,
+        })
+    }
+}
+
+/// A user `UserStore` knows.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct User {
+    pub password: PasswordHash,
+    pub tags: Vec<String>,
+}
+
+impl User {
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+}
+
+/// What a user may do in one virtual host, as glob patterns of the
+/// resource names it may configure, write to and read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Permission {
+    pub configure: String,
+    pub write: String,
+    pub read: String,
+}
+
+impl Permission {
+    /// Permission on every resource.
+    pub fn full() -> Self {
+        Permission {
+            configure: "*".into(),
+            write: "*".into(),
+            read: "*".into(),
+        }
+    }
+}
+
+/// A user to create at startup, as the config file lists it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserConfig {
+    pub name: String,
+    pub password: String,
+    pub tags: Vec<String>,
+    /// Virtual hosts the user gets full permission on.
+    pub vhosts: Vec<String>,
+}
+
+/// Users and their permissions, by virtual host.
+#[derive(Debug, Default)]
+pub struct UserStore {
+    users: Mutex<HashMap<String, User>>,
+    /// Keyed by virtual host and user name.
+    permissions: Mutex<HashMap<(String, String), Permission>>,
+}
+
+impl UserStore {
+    pub fn new() -> Self {
+        UserStore::default()
+    }
+
+    /// A store holding the configured `users`.
+    pub fn from_config(users: &[UserConfig]) -> Self {
+        let store = UserStore::new();
+        for user in users {
+            store.put_user(
+                &user.name,
+                User {
+                    password: PasswordHash::new(&user.password),
+                    tags: user.tags.clone(),
+                },
+            );
+            for vhost in &user.vhosts {
+                store.set_permission(vhost, &user.name, Permission::full());
+            }
+        }
+        store
+    }
+
+    pub fn user(&self, name: &str) -> Option<User> {
+        self.users.lock().unwrap().get(name).cloned()
+    }
+
+    /// Every user, by name.
+    pub fn all_users(&self) -> Vec<(String, User)> {
+        let mut users: Vec<(String, User)> = self
+            .users
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, user)| (name.clone(), user.clone()))
+            .collect();
+        users.sort_by(|a, b| a.0.cmp(&b.0));
+        users
+    }
+
+    /// Creates or replaces user `name`. Returns whether it is new.
+    pub fn put_user(&self, name: &str, user: User) -> bool {
+        self.users.lock().unwrap().insert(name.to_string(), user).is_none()
+    }
+
+    /// Removes user `name` and its permissions. Returns whether it existed.
+    pub fn delete_user(&self, name: &str) -> bool {
+        let removed = self.users.lock().unwrap().remove(name).is_some();
+        self.permissions.lock().unwrap().retain(|(_, user), _| user != name);
+        removed
+    }
+
+    /// The user `name` if `password` is its password.
+    pub fn check_password(&self, name: &str, password: &str) -> Option<User> {
+        self.user(name).filter(|user| user.password.matches(password))
+    }
+
+    pub fn permission(&self, vhost: &str, user: &str) -> Option<Permission> {
+        let key = (vhost.to_string(), user.to_string());
+        self.permissions.lock().unwrap().get(&key).cloned()
+    }
+
+    /// Every permission with its virtual host and user, in that order.
+    pub fn all_permissions(&self) -> Vec<(String, String, Permission)> {
+        let mut permissions: Vec<(String, String, Permission)> = self
+            .permissions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((vhost, user), permission)| (vhost.clone(), user.clone(), permission.clone()))
+            .collect();
+        permissions.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        permissions
+    }
+
+    /// Sets what `user` may do in `vhost`. Returns whether the user had no
+    /// permission there before.
+    pub fn set_permission(&self, vhost: &str, user: &str, permission: Permission) -> bool {
+        let key = (vhost.to_string(), user.to_string());
+        self.permissions.lock().unwrap().insert(key, permission).is_none()
+    }
+}
+
+/// SASL PLAIN, with passwords checked against a `UserStore`.
+#[derive(Debug, Clone)]
+pub struct PasswordAuth {
+    users: Arc<UserStore>,
+}
+
+impl PasswordAuth {
+    pub fn new(users: Arc<UserStore>) -> Self {
+        PasswordAuth { users }
+    }
+}
+
+impl AuthProvider for PasswordAuth {
+    fn mechanisms(&self) -> Vec<String> {
+        vec!["PLAIN".into()]
+    }
+
+    fn start(&self, mechanism: &str) -> Option<Box<dyn SaslSession>> {
+        let session = PasswordSession {
+            users: self.users.clone(),
+        };
+        (mechanism == "PLAIN").then(|| Box::new(session) as Box<dyn SaslSession>)
+    }
+
+    fn users(&self) -> Option<&UserStore> {
+        Some(&self.users)
+    }
+}
+
+struct PasswordSession {
+    users: Arc<UserStore>,
+}
+
+impl SaslSession for PasswordSession {
+    fn step(&mut self, response: &[u8]) -> Result<SaslStep, AuthFailure> {
+        let refused = || AuthFailure("invalid username or password".into());
+        let (username, password) = plain_credentials(response).ok_or_else(refused)?;
+        self.users.check_password(&username, &password).ok_or_else(refused)?;
+        Ok(SaslStep::Authenticated(Some(username)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_username() {
+        assert_eq!(plain_username(b"\0guest\0secret").as_deref(), Some("guest"));
+        assert_eq!(plain_username(b"admin\0guest\0secret").as_deref(), Some("guest"));
+        assert_eq!(plain_username(b"guest"), None);
+    }
+
+    #[test]
+    fn test_plain_auth_offers_only_plain() {
+        assert_eq!(PlainAuth.mechanisms(), vec!["PLAIN".to_string()]);
+        assert!(PlainAuth.start("AMQPLAIN").is_none());
+        let mut session = PlainAuth.start("PLAIN").unwrap();
+        assert_eq!(
+            session.step(b"\0guest\0guest"),
+            Ok(SaslStep::Authenticated(Some("guest".into())))
+        );
+    }
+
+    #[test]
+    fn test_password_hashes_match_rabbitmq() {
+        // From a RabbitMQ definitions export, for the password "test12".
+        let hash = PasswordHash::decode("kI3GCqW5JLMJa4iX1lo7X4D6XbYqlLgxIs30+P6tENUV2POR").unwrap();
+        assert!(hash.matches("test12"));
+        assert!(!hash.matches("test13"));
+        assert_eq!(hash.encode(), "kI3GCqW5JLMJa4iX1lo7X4D6XbYqlLgxIs30+P6tENUV2POR");
+        assert!(PasswordHash::decode("kI3GCqW5").is_none());
+
+        let fresh = PasswordHash::new("secret");
+        assert!(fresh.matches("secret"));
+        assert!(!fresh.matches("secreT"));
+        assert_ne!(PasswordHash::new("secret").encode(), fresh.encode());
+        assert_eq!(PasswordHash::decode(&fresh.encode()), Some(fresh));
+        assert!(!PasswordHash::default().matches(""));
+    }
+
+    #[test]
+    fn test_password_auth_follows_store_changes() {
+        let users = Arc::new(UserStore::new());
+        let auth = PasswordAuth::new(users.clone());
+        let login = |response: &[u8]| auth.start("PLAIN").unwrap().step(response);
+        assert!(login(b"\0alice\0secret").is_err());
+
+        users.put_user(
+            "alice",
+            User {
+                password: PasswordHash::new("secret"),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            login(b"\0alice\0secret"),
+            Ok(SaslStep::Authenticated(Some("alice".into())))
+        );
+        assert!(login(b"\0alice\0wrong").is_err());
+
+        users.set_permission("/", "alice", Permission::full());
+        assert!(users.delete_user("alice"));
+        assert!(login(b"\0alice\0secret").is_err());
+        assert_eq!(users.permission("/", "alice"), None);
+    }
+}
@@ -0,0 +1,4611 @@
+// src/broker.rs
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+
+use crate::auth::{AuthProvider, PasswordAuth, Permission, PlainAuth, User, UserStore};
+use crate::clock::{Clock, TokioClock};
+use crate::config::{Config, Topology};
+use crate::definitions::{self, ImportOutcome};
+use crate::error::AmqpError;
+use crate::events::{self, BrokerEvent, EventLog, RecordedEvent, TracedBinding};
+use tokio::sync::{broadcast, watch};
+
+use crate::exchange::{self, Binding, Exchange, ExchangeStats, ExchangeType};
+use crate::field_table::{FieldTable, FieldValue};
+use crate::histogram::{Histogram, HistogramSnapshot, MESSAGE_SIZE_BUCKETS};
+use crate::intercept::{InterceptAction, Interceptor, PublishCtx};
+use crate::latency::{Operation, SlowOperations};
+use crate::message::Message;
+use crate::methods::{CLASS_BASIC, CLASS_EXCHANGE, CLASS_QUEUE};
+use crate::policy::{self, ApplyTo, Policy};
+use crate::queue::{
+    Consumer, DeliverySender, MessageTotals, Overflow, Prefetch, Queue, QueueNameGenerator, QueueOptions, QueueType,
+    QueuedMessage, RandomQueueNames, RateHistory, Rates, UnackedLimit, Utilization,
+};
+use crate::rate_limit::{RatePolicy, TokenBucket};
+use crate::registry::{ChurnLimits, ConnectionRegistry, HandshakeLimits};
+use crate::reply_codes;
+use crate::store::{CompactionStats, MemoryMessageStore, MessageStore, Storage};
+use crate::vhost::{HeldPublish, QueueRef, VHost, DEFAULT_VHOST, QUOTA_RETRY_INTERVAL};
+use crate::wire::SHORTSTR_MAX_LEN;
+
+/// Arguments of a `Queue.Declare` request.
+#[derive(Debug, Clone, Default)]
+pub struct QueueDeclare {
+    pub queue: String,
+    pub passive: bool,
+    pub durable: bool,
+    pub exclusive: bool,
+    pub auto_delete: bool,
+    pub arguments: FieldTable,
+}
+
+/// Arguments of an `Exchange.Declare` request.
+#[derive(Debug, Clone, Default)]
+pub struct ExchangeDeclare {
+    pub exchange: String,
+    pub kind: String,
+    pub passive: bool,
+    pub durable: bool,
+    pub auto_delete: bool,
+    pub internal: bool,
+    pub arguments: FieldTable,
+}
+
+/// Arguments of a `Basic.Consume` request.
+#[derive(Debug, Clone)]
+pub struct BasicConsume {
+    pub queue: String,
+    /// Requested consumer tag; empty to let the broker pick one.
+    pub consumer_tag: String,
+    pub no_ack: bool,
+    pub exclusive: bool,
+    /// Channel the consumer lives on.
+    pub channel: u16,
+    /// Inbox of the connection that owns the channel.
+    pub sender: DeliverySender,
+    /// `Basic.Qos` window the consumer's deliveries count against.
+    pub prefetch: Option<Arc<Prefetch>>,
+    /// `x-priority`: consumers with room get deliveries in proportion to
+    /// their priority plus one.
+    pub priority: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueDeclareOk {
+    pub queue: String,
+    pub message_count: u32,
+    pub consumer_count: u32,
+}
+
+/// A message fetched with `Basic.Get`.
+#[derive(Debug, Clone)]
+pub struct BasicGetOk {
+    /// Queue-local id of the delivery, used to ack or requeue it.
+    pub message_id: u64,
+    pub redelivered: bool,
+    pub message: Message,
+    /// Messages still ready in the queue after this one was taken.
+    pub message_count: u32,
+}
+
+/// What became of a published message.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PublishOutcome {
+    /// Number of queues the message was routed to.
+    pub routed: usize,
+    /// Whether a queue at its `x-max-length` or `x-max-publish-rate`
+    /// refused the message, which the publisher must then be nacked for.
+    pub rejected: bool,
+    /// Set when the message went over a queue's `x-max-publish-rate` under
+    /// `RatePolicy::Flow`: how long the publisher should pause.
+    pub throttle: Option<Duration>,
+    /// Whether the message belongs in the store but was not written to it
+    /// because of the disk alarm. It is kept in memory and stored once
+    /// `Broker::retry_store` succeeds; its confirm waits until then.
+    pub unstored: bool,
+}
+
+/// A queue's message totals and the rates sampled from them; see
+/// `Broker::queue_rates`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueRates {
+    pub totals: MessageTotals,
+    /// Rates over the whole window; `None` until two samples fall in it.
+    pub average: Option<Rates>,
+    /// Rates between consecutive samples, oldest first, each with when its
+    /// interval ended.
+    pub series: Vec<(Instant, Rates)>,
+}
+
+/// A consumer holding up its queue; see `Broker::slow_consumers`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowConsumer {
+    pub vhost: String,
+    pub queue: String,
+    /// Broker-wide consumer id.
+    pub id: u64,
+    pub consumer_tag: String,
+}
+
+/// A snapshot of one queue; see `Broker::queue_summaries`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueSummary {
+    pub vhost: String,
+    pub name: String,
+    /// Messages ready for delivery.
+    pub messages: usize,
+    /// Deliveries awaiting an ack.
+    pub unacked: usize,
+    /// Ready and unacked messages together.
+    pub total: usize,
+    pub consumers: usize,
+    /// Share of the measuring window in which consumers could take the
+    /// queue's messages at once; `None` without consumers.
+    pub consumer_utilization: Option<f64>,
+    /// Placement hints the queue was declared with, which a single node
+    /// has no use for.
+    pub master_locator: Option<String>,
+    pub leader_locator: Option<String>,
+    /// The policy that applies to the queue.
+    pub policy: Option<String>,
+    /// Unix times the queue was declared and last active; see
+    /// `Queue::last_active`.
+    pub created_at: Duration,
+    pub last_active: Duration,
+}
+
+/// A snapshot of one exchange; see `Broker::exchange_summaries`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExchangeSummary {
+    pub vhost: String,
+    pub name: String,
+    pub kind: ExchangeType,
+    pub durable: bool,
+    pub builtin: bool,
+    /// The policy that applies to the exchange.
+    pub policy: Option<String>,
+    /// Unix times the exchange was declared and last declared or published
+    /// to.
+    pub created_at: Duration,
+    pub last_active: Duration,
+}
+
+/// How many resources a virtual host has, and the most it may have; see
+/// `Broker::vhost_summaries`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VHostSummary {
+    pub name: String,
+    pub queues: usize,
+    /// Declared exchanges, leaving out the built-in ones.
+    pub exchanges: usize,
+    pub bindings: usize,
+    pub max_queues: Option<usize>,
+    pub max_exchanges: Option<usize>,
+    pub max_bindings: Option<usize>,
+}
+
+/// Fraction of `Config::memory_high_watermark` at which the health
+/// summary starts warning.
+pub const MEMORY_WARNING_FRACTION: f64 = 0.8;
+
+/// Names clients may not declare exchanges or queues under; the built-in
+/// exchanges and server-named queues use it.
+pub const RESERVED_PREFIX: &str = "amq.";
+
+/// Name of the topology file in the message store's data directory.
+const TOPOLOGY_FILE: &str = "topology.json";
+
+/// Overall broker health, from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HealthStatus {
+    Green,
+    /// Degraded: some clients are held back, or a limit is close.
+    Yellow,
+    /// Publishers are blocked broker-wide, or a listener is down.
+    Red,
+}
+
+impl HealthStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HealthStatus::Green => "green",
+            HealthStatus::Yellow => "yellow",
+            HealthStatus::Red => "red",
+        }
+    }
+}
+
+/// A condition that makes the broker less than healthy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthAlarm {
+    /// `disk`, `memory`, `vhost_quota`, `handshakes`, `listener`,
+    /// `listeners_paused` or `recovery`.
+    pub name: &'static str,
+    pub status: HealthStatus,
+    pub detail: String,
+}
+
+/// Operational health of the broker, as `GET /api/health` reports it.
+#[derive(Debug, Clone)]
+pub struct HealthSummary {
+    /// The worst status of the alarms; green without any.
+    pub status: HealthStatus,
+    pub alarms: Vec<HealthAlarm>,
+    pub connections: usize,
+    /// Connections in their handshake, and the most there may be.
+    pub pending_handshakes: Option<(usize, usize)>,
+    /// Whether listeners refuse new connections; see `pause_listeners`.
+    pub listeners_paused: bool,
+    /// Whether startup recovery has yet to finish; see `Broker::recover`.
+    pub recovering: bool,
+    /// Messages queued in all virtual hosts, ready or unacked.
+    pub messages: u64,
+    /// Their memory footprint.
+    pub message_bytes: u64,
+}
+
+/// Exceptions the broker raised, counted by reply code.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExceptionCounts {
+    pub channel: BTreeMap<u16, u64>,
+    pub connection: BTreeMap<u16, u64>,
+}
+
+/// The topology of a broker: virtual hosts, exchanges, queues and
+/// bindings, and the users and permissions of a `UserStore`. Built-in
+/// exchanges and exclusive queues, which belong to a connection, are left
+/// out.
+#[derive(Debug, Clone, Default)]
+pub struct Definitions {
+    pub vhosts: Vec<String>,
+    /// Users, each with its name.
+    pub users: Vec<(String, User)>,
+    /// Permissions, each with its virtual host and user.
+    pub permissions: Vec<(String, String, Permission)>,
+    /// Exchanges, each with its virtual host.
+    pub exchanges: Vec<(String, ExchangeDeclare)>,
+    /// Queues, each with its virtual host.
+    pub queues: Vec<(String, QueueDeclare)>,
+    /// Bindings, each with its virtual host and the exchange it binds to.
+    pub bindings: Vec<(String, String, Binding)>,
+}
+
+/// A startup recovery phase that failed; see `Broker::recover`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveryError {
+    /// `exchanges`, `queues`, `bindings` or `messages`.
+    pub phase: &'static str,
+    pub detail: String,
+}
+
+impl fmt::Display for RecoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "recovery failed at {}: {}", self.phase, self.detail)
+    }
+}
+
+impl std::error::Error for RecoveryError {}
+
+/// Shared broker state, accessed by every connection.
+///
+/// `state` holds the virtual hosts with their topology: exchanges, bindings
+/// and the queue table. Every topology and message method names the virtual
+/// host it works in. Each
+/// queue has its own lock, so once a publish has been routed it only touches
+/// the target queues. Locks are always taken in the order state, queue,
+/// store, and the state lock is released before any queue is locked on the
+/// publish path.
+///
+/// Messages published on one channel reach each queue in the order they
+/// were published, and a consumer of that queue is handed them in that
+/// order. A connection handles its frames one at a time and routes each
+/// complete publish, interceptors included, onto every target queue before
+/// it reads the next frame, so two publishes of a channel never race each
+/// other. Publishes held in a drained virtual host are enqueued in arrival
+/// order when it resumes, before any new publish. The order breaks only
+/// where the spec allows: deliveries put back by a nack, a recover or a
+/// closing channel are delivered again ahead of later messages, and
+/// several consumers of one queue each see their share in order. Nothing
+/// is promised between channels, whose publishes interleave at the queue
+/// lock, nor for messages that reach a queue by dead-lettering.
+pub struct Broker {
+    config: Config,
+    auth: Box<dyn AuthProvider>,
+    /// Run in order on every published message before it is routed.
+    interceptors: Vec<Box<dyn Interceptor>>,
+    connections: Arc<ConnectionRegistry>,
+    state: Mutex<BrokerState>,
+    store: Mutex<Option<Box<dyn MessageStore>>>,
+    events: broadcast::Sender<BrokerEvent>,
+    /// Recent events, once `spawn_event_log` fills it.
+    event_log: Arc<EventLog>,
+    next_connection_id: AtomicU64,
+    slow_operations: SlowOperations,
+    /// Body sizes of the messages clients published.
+    message_sizes: Histogram,
+    exceptions: Mutex<ExceptionCounts>,
+    /// Times a connection went past `Config::channel_warning_threshold`.
+    channel_warnings: AtomicU64,
+    /// Unacked deliveries of every queue, against
+    /// `Config::max_unacked_messages`.
+    unacked_limit: Arc<UnackedLimit>,
+    /// Raised when a store write fails, lowered once `retry_store` catches
+    /// up; connections watch it to block and unblock their publishers.
+    disk_alarm: watch::Sender<bool>,
+    /// Set once by `shut_down`; connections watch it to close.
+    shutdown: watch::Sender<bool>,
+    /// Listeners that stopped accepting, with the error that stopped them.
+    failed_listeners: Mutex<Vec<(String, String)>>,
+    /// Set while listeners close the sockets they accept, for maintenance.
+    listeners_paused: AtomicBool,
+    /// Set while `recover` runs; listeners refuse connections meanwhile.
+    recovering: AtomicBool,
+    clock: Arc<dyn Clock>,
+}
+
+struct BrokerState {
+    vhosts: HashMap<String, VHost>,
+    /// Names queues declared with an empty name. Kept under the state lock
+    /// so a generated name is reserved as soon as it is found to be free.
+    queue_names: Box<dyn QueueNameGenerator>,
+    next_consumer_id: u64,
+}
+
+impl BrokerState {
+    fn vhost(&mut self, name: &str) -> Result<&mut VHost, AmqpError> {
+        self.vhosts.get_mut(name).ok_or_else(|| unknown_vhost(name))
+    }
+}
+
+fn precondition_failed(text: String) -> AmqpError {
+    AmqpError::channel(reply_codes::PRECONDITION_FAILED, text, CLASS_QUEUE, 10)
+}
+
+fn access_refused(text: String) -> AmqpError {
+    AmqpError::channel(reply_codes::ACCESS_REFUSED, text, CLASS_BASIC, 20)
+}
+
+fn not_found(text: String, class_id: u16, method_id: u16) -> AmqpError {
+    AmqpError::channel(reply_codes::NOT_FOUND, text, class_id, method_id)
+}
+
+/// Checks that `value`, decoded from a short string, still fits in one.
+/// Replacing invalid UTF-8 can make a string longer than it was on the wire.
+fn check_length(what: &str, value: &str, class_id: u16, method_id: u16) -> Result<(), AmqpError> {
+    if value.len() > SHORTSTR_MAX_LEN {
+        return Err(AmqpError::connection(
+            reply_codes::SYNTAX_ERROR,
+            format!(
+                "SYNTAX_ERROR - {} of {} bytes is longer than a short string",
+                what,
+                value.len()
+            ),
+            class_id,
+            method_id,
+        ));
+    }
+    Ok(())
+}
+
+/// Checks a name given to `Exchange.Declare` or `Queue.Declare`: besides
+/// fitting in a short string, it may only hold letters, digits, `-`, `_`,
+/// `.` and `:`, as the spec says.
+fn check_name(kind: &str, name: &str, class_id: u16) -> Result<(), AmqpError> {
+    check_length(&format!("{} name", kind), name, class_id, 10)?;
+    if name.chars().any(|c| !c.is_ascii_alphanumeric() && !"-_.:".contains(c)) {
+        return Err(AmqpError::channel(
+            reply_codes::PRECONDITION_FAILED,
+            format!(
+                "PRECONDITION_FAILED - invalid {} name '{}': only letters, digits, '-', '_', '.' and ':' are allowed",
+                kind, name
+            ),
+            class_id,
+            10,
+        ));
+    }
+    Ok(())
+}
+
+/// Refuses a client-declared `kind` whose name starts with the `amq.`
+/// prefix the broker keeps for itself.
+fn reserved_name(kind: &str, name: &str, class_id: u16) -> AmqpError {
+    AmqpError::channel(
+        reply_codes::ACCESS_REFUSED,
+        format!(
+            "ACCESS_REFUSED - {} name '{}' contains reserved prefix '{}*'",
+            kind, name, RESERVED_PREFIX
+        ),
+        class_id,
+        10,
+    )
+}
+
+/// Refuses a new resource in a virtual host that already has `count` of
+/// them, `limit` being the most it may have.
+fn check_limit(
+    what: &str,
+    count: usize,
+    limit: Option<usize>,
+    vhost: &str,
+    class_id: u16,
+    method_id: u16,
+) -> Result<(), AmqpError> {
+    match limit {
+        Some(limit) if count >= limit => Err(AmqpError::channel(
+            reply_codes::ACCESS_REFUSED,
+            format!(
+                "ACCESS_REFUSED - vhost '{}' already has the most {} it may have ({})",
+                vhost, what, limit
+            ),
+            class_id,
+            method_id,
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn unknown_vhost(name: &str) -> AmqpError {
+    AmqpError::connection(
+        reply_codes::NOT_ALLOWED,
+        format!("NOT_ALLOWED - vhost '{}' not found", name),
+        0,
+        0,
+    )
+}
+
+impl Broker {
+    pub fn new(config: Config) -> Self {
+        let mut state = BrokerState {
+            vhosts: HashMap::new(),
+            queue_names: Box::new(RandomQueueNames::new()),
+            next_consumer_id: 0,
+        };
+        for name in std::iter::once(DEFAULT_VHOST).chain(config.vhosts.iter().map(String::as_str)) {
+            state.vhosts.insert(name.to_string(), new_vhost(&config, name));
+        }
+        for name in config
+            .vhost_settings
+            .keys()
+            .filter(|name| !state.vhosts.contains_key(*name))
+        {
+            warn!("Ignoring settings of unknown vhost '{}'", name);
+        }
+        let auth: Box<dyn AuthProvider> = if config.users.is_empty() {
+            Box::new(PlainAuth)
+        } else {
+            Box::new(PasswordAuth::new(Arc::new(UserStore::from_config(&config.users))))
+        };
+        let store: Option<Box<dyn MessageStore>> = match config.storage {
+            Storage::Memory => Some(Box::new(MemoryMessageStore::default())),
+            Storage::Disk => None,
+        };
+        let event_log = Arc::new(EventLog::new(config.event_log_size));
+        Broker {
+            connections: Arc::new(
+                ConnectionRegistry::new(config.max_connections_per_user, config.max_connections_per_ip)
+                    .with_handshake_limits(HandshakeLimits {
+                        max_pending: config.max_pending_handshakes,
+                        max_failures: config.handshake_failure_limit,
+                        cooldown: config.handshake_failure_cooldown,
+                    })
+                    .with_churn_limits(ChurnLimits {
+                        max_short_lived: config.churn_limit,
+                        min_lifetime: config.churn_min_lifetime,
+                        window: config.churn_window,
+                        cooldown: config.churn_cooldown,
+                    }),
+            ),
+            unacked_limit: Arc::new(UnackedLimit::new(config.max_unacked_messages)),
+            config,
+            auth,
+            interceptors: Vec::new(),
+            state: Mutex::new(state),
+            store: Mutex::new(store),
+            events: events::channel(),
+            event_log,
+            next_connection_id: AtomicU64::new(0),
+            slow_operations: SlowOperations::default(),
+            message_sizes: Histogram::new(MESSAGE_SIZE_BUCKETS),
+            exceptions: Mutex::new(ExceptionCounts::default()),
+            channel_warnings: AtomicU64::new(0),
+            disk_alarm: watch::channel(false).0,
+            shutdown: watch::channel(false).0,
+            failed_listeners: Mutex::new(Vec::new()),
+            listeners_paused: AtomicBool::new(false),
+            recovering: AtomicBool::new(false),
+            clock: Arc::new(TokioClock),
+        }
+    }
+
+    /// Creates a broker that persists persistent messages on durable queues
+    /// to `store`. With `Storage::Memory` configured, `Broker::new` already
+    /// keeps them in a `MemoryMessageStore`.
+    pub fn with_store(config: Config, store: Box<dyn MessageStore>) -> Self {
+        Broker {
+            store: Mutex::new(Some(store)),
+            ..Broker::new(config)
+        }
+    }
+
+    /// Creates a broker that authenticates clients with `auth` instead of
+    /// `PlainAuth`, or `PasswordAuth` over the configured users.
+    pub fn with_auth(config: Config, auth: Box<dyn AuthProvider>) -> Self {
+        Broker {
+            auth,
+            ..Broker::new(config)
+        }
+    }
+
+    /// Creates a broker that runs `interceptors`, in order, on every
+    /// published message before routing it.
+    pub fn with_interceptors(config: Config, interceptors: Vec<Box<dyn Interceptor>>) -> Self {
+        Broker {
+            interceptors,
+            ..Broker::new(config)
+        }
+    }
+
+    /// Creates a broker that reads the time from `clock` instead of
+    /// `tokio::time`.
+    pub fn with_clock(config: Config, clock: Arc<dyn Clock>) -> Self {
+        Broker {
+            clock,
+            ..Broker::new(config)
+        }
+    }
+
+    /// Creates a broker that names server-named queues with `queue_names`.
+    pub fn with_queue_names(config: Config, queue_names: Box<dyn QueueNameGenerator>) -> Self {
+        let broker = Broker::new(config);
+        broker.state.lock().unwrap().queue_names = queue_names;
+        broker
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    pub fn auth(&self) -> &dyn AuthProvider {
+        self.auth.as_ref()
+    }
+
+    /// Whether `Config::sasl_mechanisms` lets clients use `mechanism`.
+    pub fn allows_mechanism(&self, mechanism: &str) -> bool {
+        let allowed = &self.config.sasl_mechanisms;
+        allowed.is_empty() || allowed.iter().any(|m| m == mechanism)
+    }
+
+    /// Mechanisms offered in `Connection.Start`: those of the auth provider
+    /// the config allows.
+    pub fn mechanisms(&self) -> Vec<String> {
+        let mut mechanisms = self.auth.mechanisms();
+        mechanisms.retain(|mechanism| self.allows_mechanism(mechanism));
+        mechanisms
+    }
+
+    pub fn connections(&self) -> &Arc<ConnectionRegistry> {
+        &self.connections
+    }
+
+    /// Subscribes to the events this broker emits from now on.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<BrokerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Publishes an event to the current subscribers, if any.
+    pub(crate) fn emit(&self, event: BrokerEvent) {
+        let _ = self.events.send(event);
+    }
+
+    /// The `limit` most recent events in the event log, oldest first.
+    pub fn recent_events(&self, limit: usize) -> Vec<RecordedEvent> {
+        self.event_log.recent(limit)
+    }
+
+    /// Counts a channel exception the broker closed `channel` with, and
+    /// emits it as an event.
+    pub(crate) fn channel_exception(&self, connection: u64, channel: u16, reply_code: u16, reply_text: &str) {
+        *self.exceptions.lock().unwrap().channel.entry(reply_code).or_default() += 1;
+        self.emit(BrokerEvent::ChannelException {
+            connection,
+            channel,
+            reply_code,
+            reply_text: reply_text.to_string(),
+        });
+    }
+
+    /// Counts a connection exception the broker closed a connection with,
+    /// and emits it as an event.
+    pub(crate) fn connection_exception(&self, connection: Option<u64>, reply_code: u16, reply_text: &str) {
+        *self
+            .exceptions
+            .lock()
+            .unwrap()
+            .connection
+            .entry(reply_code)
+            .or_default() += 1;
+        self.emit(BrokerEvent::ConnectionException {
+            connection,
+            reply_code,
+            reply_text: reply_text.to_string(),
+        });
+    }
+
+    /// The channel and connection exceptions raised so far.
+    pub fn exception_counts(&self) -> ExceptionCounts {
+        self.exceptions.lock().unwrap().clone()
+    }
+
+    /// Warns that `connection` has `channels` open, past
+    /// `Config::channel_warning_threshold`, and counts the warning.
+    pub(crate) fn channel_warning(&self, connection: u64, channels: usize) {
+        warn!(
+            "Connection {} has {} channels open, past the warning threshold of {}",
+            connection, channels, self.config.channel_warning_threshold
+        );
+        self.channel_warnings.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Times a connection went past `Config::channel_warning_threshold`.
+    pub fn channel_warnings(&self) -> u64 {
+        self.channel_warnings.load(Ordering::Relaxed)
+    }
+
+    /// Deliveries awaiting an ack, across every queue.
+    pub fn unacked_messages(&self) -> u64 {
+        self.unacked_limit.count()
+    }
+
+    /// Assigns the id a new connection is known by in events.
+    pub(crate) fn next_connection_id(&self) -> u64 {
+        self.next_connection_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Creates an empty virtual host, returning `false` if it already exists.
+    pub fn add_vhost(&self, name: &str) -> bool {
+        let mut state = self.lock_state();
+        if state.vhosts.contains_key(name) {
+            return false;
+        }
+        state.vhosts.insert(name.to_string(), new_vhost(&self.config, name));
+        true
+    }
+
+    pub fn has_vhost(&self, name: &str) -> bool {
+        self.lock_state().vhosts.contains_key(name)
+    }
+
+    /// Watches the drained flag of a virtual host.
+    pub fn watch_vhost(&self, name: &str) -> Option<watch::Receiver<bool>> {
+        self.lock_state().vhosts.get(name).map(VHost::watch)
+    }
+
+    pub fn is_drained(&self, vhost: &str) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .vhosts
+            .get(vhost)
+            .is_some_and(VHost::is_drained)
+    }
+
+    /// Sets the policy `policy.name` of a virtual host, replacing one of the
+    /// same name, and applies the vhost's policies anew to its queues and
+    /// exchanges. Returns whether the policy is new, or `None` for an
+    /// unknown virtual host. The definition is expected to be valid; see
+    /// `Policy::validate`.
+    pub fn set_policy(&self, vhost: &str, policy: Policy) -> Option<bool> {
+        let mut state = self.lock_state();
+        let vhost = state.vhosts.get_mut(vhost)?;
+        let created = vhost.policies.insert(policy.name.clone(), policy).is_none();
+        apply_policies(vhost);
+        Some(created)
+    }
+
+    /// Removes a policy of a virtual host and applies the remaining ones
+    /// anew. Returns whether there was such a policy.
+    pub fn delete_policy(&self, vhost: &str, name: &str) -> bool {
+        let mut state = self.lock_state();
+        let Some(vhost) = state.vhosts.get_mut(vhost) else {
+            return false;
+        };
+        if vhost.policies.remove(name).is_none() {
+            return false;
+        }
+        apply_policies(vhost);
+        true
+    }
+
+    /// Every policy, with its virtual host, by vhost and name.
+    pub fn policies(&self) -> Vec<(String, Policy)> {
+        let state = self.lock_state();
+        let mut policies: Vec<(String, Policy)> = state
+            .vhosts
+            .values()
+            .flat_map(|vhost| {
+                vhost
+                    .policies
+                    .values()
+                    .map(|policy| (vhost.name.clone(), policy.clone()))
+            })
+            .collect();
+        policies.sort_by(|(a, p), (b, q)| (a, &p.name).cmp(&(b, &q.name)));
+        policies
+    }
+
+    /// Stops message flow in a virtual host: its queues stop delivering and
+    /// publishes are held until `resume_vhost`. Returns `false` for an
+    /// unknown virtual host.
+    pub fn drain_vhost(&self, name: &str) -> bool {
+        let state = self.lock_state();
+        let Some(vhost) = state.vhosts.get(name) else {
+            return false;
+        };
+        vhost.set_drained(true);
+        for queue in vhost.queues.values() {
+            self.lock_queue(queue).paused = true;
+        }
+        info!("Drained vhost '{}'", name);
+        true
+    }
+
+    /// Restarts message flow in a drained virtual host, enqueueing the held
+    /// publishes in the order they arrived.
+    pub fn resume_vhost(&self, name: &str) -> bool {
+        let mut state = self.lock_state();
+        let Some(vhost) = state.vhosts.get_mut(name) else {
+            return false;
+        };
+        for queue in vhost.queues.values() {
+            let settled = {
+                let mut queue = self.lock_queue(queue);
+                queue.paused = false;
+                queue.dispatch()
+            };
+            self.settle(settled);
+        }
+        // Still under the state lock, so no new publish can overtake these.
+        // Their publishers were confirmed when the messages were held, so
+        // a refusal now only dead-letters them.
+        let mut dead_letters = Vec::new();
+        for held in std::mem::take(&mut vhost.held) {
+            self.enqueue(name, &held.targets, &held.message, &mut dead_letters);
+        }
+        vhost.set_drained(false);
+        drop(state);
+        info!("Resumed vhost '{}'", name);
+        for message in dead_letters {
+            self.publish(name, message, false);
+        }
+        true
+    }
+
+    /// Restores the broker at startup, in dependency order: the configured
+    /// exchanges, queues and bindings in the default virtual host, then the
+    /// durable ones clients declared, from the topology file, then the
+    /// messages the store kept for those queues. Unlike `apply_topology`,
+    /// the first failure stops recovery, so startup can abort instead of
+    /// serving half the topology. Listeners refuse connections and health
+    /// reports red until every phase has finished.
+    ///
+    /// The topology file is kept apart from messages: a durable queue comes
+    /// back empty if none of its messages were persistent, and a transient
+    /// queue never comes back. Neither do consumers: clients subscribe again
+    /// once they reconnect. So exclusive and auto-delete queues, which only
+    /// live as long as their connection or consumers, are not recovered
+    /// either, even from a topology file that still lists them. A stored
+    /// resource that conflicts with the configuration is skipped with a
+    /// warning, the configuration winning. Stored messages for a queue that
+    /// is in neither are left in the store.
+    pub fn recover(&self, topology: &Topology) -> Result<(), RecoveryError> {
+        self.recovering.store(true, Ordering::SeqCst);
+        let failed = |phase, detail: String| RecoveryError { phase, detail };
+        for declare in &topology.exchanges {
+            self.declare_exchange(DEFAULT_VHOST, declare.clone())
+                .map_err(|e| failed("exchanges", format!("exchange '{}': {}", declare.exchange, e)))?;
+        }
+        info!("Recovered {} exchanges", topology.exchanges.len());
+        for declare in &topology.queues {
+            self.declare_queue(DEFAULT_VHOST, declare.clone())
+                .map_err(|e| failed("queues", format!("queue '{}': {}", declare.queue, e)))?;
+        }
+        info!("Recovered {} queues", topology.queues.len());
+        for (exchange, binding) in &topology.bindings {
+            self.bind_queue(DEFAULT_VHOST, binding.clone(), exchange).map_err(|e| {
+                failed(
+                    "bindings",
+                    format!("binding of '{}' to '{}': {}", binding.queue, exchange, e),
+                )
+            })?;
+        }
+        info!("Recovered {} bindings", topology.bindings.len());
+        if let Some(stored) = self.stored_topology().map_err(|e| failed("topology", e))? {
+            let listed = stored.queues.len();
+            let stored = durable_part(stored);
+            if stored.queues.len() < listed {
+                info!(
+                    "Not recovering {} auto-delete queues from the topology file",
+                    listed - stored.queues.len()
+                );
+            }
+            let results = definitions::import(self, &stored);
+            for result in &results {
+                if let ImportOutcome::Failed(reason) = &result.outcome {
+                    warn!("Not recovering stored {} {}: {}", result.kind, result.resource, reason);
+                }
+            }
+            let created = results.iter().filter(|r| r.outcome == ImportOutcome::Created).count();
+            info!("Recovered {} stored exchanges, queues and bindings", created);
+        }
+        let mut stored = match self.store.lock().unwrap().as_mut() {
+            Some(store) => store.recover().map_err(|e| failed("messages", e.to_string()))?,
+            None => Vec::new(),
+        };
+        stored.sort_by_key(|stored| stored.id);
+        let (mut recovered, mut orphaned) = (0, 0);
+        for stored in stored {
+            let Some(queue) = self.queue(&stored.vhost, &stored.queue) else {
+                orphaned += 1;
+                continue;
+            };
+            let settled = queue
+                .lock()
+                .unwrap()
+                .enqueue(QueuedMessage::new(stored.message, Some(stored.id)));
+            self.settle(settled);
+            recovered += 1;
+        }
+        if orphaned > 0 {
+            warn!("Left {} stored messages of unconfigured queues in the store", orphaned);
+        }
+        info!("Recovered {} messages", recovered);
+        self.recovering.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// The file durable topology is kept in: `topology.json` in the data
+    /// directory of the message store, beside but apart from its segments.
+    /// `None` without a store on disk.
+    fn topology_path(&self) -> Option<PathBuf> {
+        self.config
+            .store
+            .as_ref()
+            .map(|store| store.data_dir.join(TOPOLOGY_FILE))
+    }
+
+    /// The durable part of `definitions`; see `durable_part`.
+    pub fn durable_definitions(&self) -> Definitions {
+        durable_part(self.definitions())
+    }
+
+    /// Writes `durable_definitions` to the topology file, replacing the
+    /// old one only once the new one is synced. Does nothing without a
+    /// store on disk.
+    pub fn save_topology(&self) -> io::Result<()> {
+        let Some(path) = self.topology_path() else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let document = definitions::to_json(&self.durable_definitions());
+        let temp = path.with_extension("json.tmp");
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(document.to_string().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp, &path)
+    }
+
+    /// Reads the topology file, if there is one.
+    fn stored_topology(&self) -> Result<Option<Definitions>, String> {
+        let Some(path) = self.topology_path() else {
+            return Ok(None);
+        };
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        let document = serde_json::from_slice(&bytes).map_err(|e| format!("{}: {}", path.display(), e))?;
+        definitions::from_json(&document)
+            .map(Some)
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Whether `recover` is still running, or stopped at a failed phase.
+    pub fn recovering(&self) -> bool {
+        self.recovering.load(Ordering::SeqCst)
+    }
+
+    /// Declares the configured startup topology in the default virtual host.
+    /// Anything that conflicts with an existing definition is logged and
+    /// skipped.
+    pub fn apply_topology(&self, topology: &Topology) {
+        for declare in &topology.exchanges {
+            if let Err(e) = self.declare_exchange(DEFAULT_VHOST, declare.clone()) {
+                warn!("Skipping configured exchange '{}': {}", declare.exchange, e);
+            }
+        }
+        for declare in &topology.queues {
+            if let Err(e) = self.declare_queue(DEFAULT_VHOST, declare.clone()) {
+                warn!("Skipping configured queue '{}': {}", declare.queue, e);
+            }
+        }
+        for (exchange, binding) in &topology.bindings {
+            if let Err(e) = self.bind_queue(DEFAULT_VHOST, binding.clone(), exchange) {
+                warn!(
+                    "Skipping configured binding of '{}' to '{}': {}",
+                    binding.queue, exchange, e
+                );
+            }
+        }
+    }
+
+    pub fn declare_queue(&self, vhost: &str, declare: QueueDeclare) -> Result<QueueDeclareOk, AmqpError> {
+        let mut state = self.lock_state();
+        let state = &mut *state;
+        let queue_names = &mut state.queue_names;
+        let vhost = state.vhosts.get_mut(vhost).ok_or_else(|| unknown_vhost(vhost))?;
+        check_length("queue name", &declare.queue, CLASS_QUEUE, 10)?;
+
+        if declare.passive {
+            return match vhost.queues.get(&declare.queue).map(|q| self.lock_queue(q)) {
+                Some(mut queue) => {
+                    queue.touch();
+                    Ok(QueueDeclareOk {
+                        queue: queue.name.clone(),
+                        message_count: queue.messages.len() as u32,
+                        consumer_count: queue.consumers.len() as u32,
+                    })
+                }
+                None => Err(not_found(
+                    format!("NOT_FOUND - no queue '{}'", declare.queue),
+                    CLASS_QUEUE,
+                    10,
+                )),
+            };
+        }
+
+        check_name("queue", &declare.queue, CLASS_QUEUE)?;
+        if declare.queue.starts_with(RESERVED_PREFIX) {
+            return Err(reserved_name("queue", &declare.queue, CLASS_QUEUE));
+        }
+        let queue_type = QueueType::from_arguments(&declare.arguments, self.config.default_queue_type)?;
+        let durable = match queue_type {
+            QueueType::Classic => declare.durable,
+            QueueType::Quorum => {
+                if declare.exclusive {
+                    return Err(precondition_failed(format!(
+                        "PRECONDITION_FAILED - invalid property 'exclusive' for quorum queue '{}'",
+                        declare.queue
+                    )));
+                }
+                true
+            }
+        };
+
+        let name = if declare.queue.is_empty() {
+            loop {
+                let name = queue_names.generate();
+                if !vhost.queues.contains_key(&name) {
+                    break name;
+                }
+            }
+        } else {
+            declare.queue
+        };
+        let policy = policy::select(vhost.policies.values(), ApplyTo::Queues, &name);
+        let options = QueueOptions::from_arguments(&policy::merge(&declare.arguments, policy))?;
+        let policy = policy.map(|policy| policy.name.clone());
+
+        let mut message_count = 0;
+        let mut consumer_count = 0;
+        if let Some(existing) = vhost.queues.get(&name) {
+            let mut existing = self.lock_queue(existing);
+            if existing.queue_type != queue_type
+                || existing.durable != durable
+                || existing.exclusive != declare.exclusive
+                || existing.auto_delete != declare.auto_delete
+                || existing.arguments != declare.arguments
+            {
+                return Err(precondition_failed(format!(
+                    "PRECONDITION_FAILED - inequivalent arguments for queue '{}'",
+                    name
+                )));
+            }
+            existing.touch();
+            message_count = existing.messages.len() as u32;
+            consumer_count = existing.consumers.len() as u32;
+        } else {
+            check_limit(
+                "queues",
+                vhost.queues.len(),
+                vhost.settings.max_queues,
+                &vhost.name,
+                CLASS_QUEUE,
+                10,
+            )?;
+            let mut queue = Queue::new(name.clone(), queue_type);
+            queue.durable = durable;
+            queue.exclusive = declare.exclusive;
+            queue.auto_delete = declare.auto_delete;
+            queue.arguments = declare.arguments;
+            queue.rate_limiter = options.max_publish_rate.map(TokenBucket::new);
+            queue.clock = self.clock.clone();
+            queue.created_at = self.clock.unix_time();
+            queue.touch();
+            queue.utilization = Utilization::new(self.config.consumer_utilization_window, self.clock.now());
+            queue.rates = RateHistory::new(self.config.rate_samples);
+            queue.options = options;
+            queue.policy = policy;
+            queue.paused = vhost.is_drained();
+            queue.usage = vhost.usage.clone();
+            queue.unacked_limit = self.unacked_limit.clone();
+            vhost.queues.insert(name.clone(), Arc::new(Mutex::new(queue)));
+            self.emit(BrokerEvent::QueueDeclared {
+                vhost: vhost.name.clone(),
+                queue: name.clone(),
+            });
+        }
+
+        Ok(QueueDeclareOk {
+            queue: name,
+            message_count,
+            consumer_count,
+        })
+    }
+
+    pub fn declare_exchange(&self, vhost: &str, declare: ExchangeDeclare) -> Result<(), AmqpError> {
+        let mut state = self.lock_state();
+        let vhost = state.vhost(vhost)?;
+        check_length("exchange name", &declare.exchange, CLASS_EXCHANGE, 10)?;
+
+        if declare.passive {
+            let Some(exchange) = vhost.exchanges.get_mut(&declare.exchange) else {
+                return Err(not_found(
+                    format!("NOT_FOUND - no exchange '{}'", declare.exchange),
+                    CLASS_EXCHANGE,
+                    10,
+                ));
+            };
+            exchange.last_active = self.clock.unix_time();
+            return Ok(());
+        }
+
+        check_name("exchange", &declare.exchange, CLASS_EXCHANGE)?;
+        // Redeclaring a built-in exchange only checks its definition below.
+        if declare.exchange.starts_with(RESERVED_PREFIX)
+            && !vhost.exchanges.get(&declare.exchange).is_some_and(|e| e.builtin)
+        {
+            return Err(reserved_name("exchange", &declare.exchange, CLASS_EXCHANGE));
+        }
+        let kind = ExchangeType::parse(&declare.kind)?;
+        let shards = match kind {
+            ExchangeType::Modulus => exchange::shard_count(&declare.arguments)?,
+            _ => 0,
+        };
+        if let Some(existing) = vhost.exchanges.get_mut(&declare.exchange) {
+            if existing.kind != kind
+                || existing.durable != declare.durable
+                || existing.auto_delete != declare.auto_delete
+                || existing.internal != declare.internal
+                || existing.arguments != declare.arguments
+            {
+                return Err(AmqpError::channel(
+                    reply_codes::PRECONDITION_FAILED,
+                    format!(
+                        "PRECONDITION_FAILED - inequivalent arguments for exchange '{}'",
+                        declare.exchange
+                    ),
+                    CLASS_EXCHANGE,
+                    10,
+                ));
+            }
+            existing.last_active = self.clock.unix_time();
+            return Ok(());
+        }
+        check_limit(
+            "exchanges",
+            vhost.declared_exchanges(),
+            vhost.settings.max_exchanges,
+            &vhost.name,
+            CLASS_EXCHANGE,
+            10,
+        )?;
+
+        let mut exchange = Exchange::new(declare.exchange.clone(), kind);
+        exchange.durable = declare.durable;
+        exchange.auto_delete = declare.auto_delete;
+        exchange.internal = declare.internal;
+        exchange.arguments = declare.arguments;
+        exchange.created_at = self.clock.unix_time();
+        exchange.last_active = exchange.created_at;
+        if let Some(policy) = policy::select(vhost.policies.values(), ApplyTo::Exchanges, &exchange.name) {
+            exchange.policy = Some(policy.name.clone());
+            exchange.policy_arguments = policy.definition.clone();
+        }
+        self.emit(BrokerEvent::ExchangeDeclared {
+            vhost: vhost.name.clone(),
+            exchange: declare.exchange.clone(),
+        });
+        let vhost_name = vhost.name.clone();
+        vhost.exchanges.insert(declare.exchange.clone(), exchange);
+        drop(state);
+        self.declare_shards(&vhost_name, &declare.exchange, shards, declare.durable)
+    }
+
+    /// Declares the shard queues of a new modulus exchange and binds them
+    /// to it. Shard queues outlive their exchange, like any bound queue.
+    /// If a shard cannot be declared or bound, the exchange and the shard
+    /// queues this call created are deleted again.
+    fn declare_shards(&self, vhost: &str, exchange: &str, shards: u32, durable: bool) -> Result<(), AmqpError> {
+        let mut created = Vec::new();
+        let mut declare_shard = |shard: u32| {
+            let queue = exchange::shard_queue(exchange, shard);
+            let declare = QueueDeclare {
+                queue: queue.clone(),
+                durable,
+                ..Default::default()
+            };
+            if self.queue(vhost, &queue).is_none() {
+                created.push(queue.clone());
+            }
+            self.declare_queue(vhost, declare)?;
+            let binding = Binding {
+                queue,
+                routing_key: shard.to_string(),
+                arguments: FieldTable::new(),
+            };
+            self.bind_queue(vhost, binding, exchange)
+        };
+        let result = (0..shards).try_for_each(&mut declare_shard);
+        if result.is_err() {
+            warn!(
+                "Could not declare the shards of exchange '{}' in vhost '{}'",
+                exchange, vhost
+            );
+            self.delete_exchange(vhost, exchange, false).ok();
+            for queue in created.iter().filter(|queue| self.queue(vhost, queue).is_some()) {
+                self.delete_queue(vhost, queue, false, false).ok();
+            }
+        }
+        result
+    }
+
+    pub fn delete_exchange(&self, vhost: &str, exchange: &str, if_unused: bool) -> Result<(), AmqpError> {
+        let mut state = self.lock_state();
+        let vhost = state.vhost(vhost)?;
+        let Some(existing) = vhost.exchanges.get(exchange) else {
+            return Err(not_found(
+                format!("NOT_FOUND - no exchange '{}'", exchange),
+                CLASS_EXCHANGE,
+                20,
+            ));
+        };
+        if existing.builtin {
+            return Err(AmqpError::channel(
+                reply_codes::ACCESS_REFUSED,
+                format!("ACCESS_REFUSED - exchange '{}' is built in and cannot be deleted", exchange),
+                CLASS_EXCHANGE,
+                20,
+            ));
+        }
+        if if_unused && !existing.bindings.is_empty() {
+            return Err(AmqpError::channel(
+                reply_codes::PRECONDITION_FAILED,
+                format!("PRECONDITION_FAILED - exchange '{}' in use", exchange),
+                CLASS_EXCHANGE,
+                20,
+            ));
+        }
+        vhost.exchanges.remove(exchange);
+        self.emit(BrokerEvent::ExchangeDeleted {
+            vhost: vhost.name.clone(),
+            exchange: exchange.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Deletes a queue, dropping its messages and consumers, and returns how
+    /// many ready messages it held. `if_unused` refuses a queue with
+    /// consumers and `if_empty` one with ready messages.
+    pub fn delete_queue(&self, vhost: &str, queue: &str, if_unused: bool, if_empty: bool) -> Result<u32, AmqpError> {
+        let mut state = self.lock_state();
+        let vhost = state.vhost(vhost)?;
+        let Some(existing) = vhost.queues.get(queue) else {
+            return Err(not_found(format!("NOT_FOUND - no queue '{}'", queue), CLASS_QUEUE, 40));
+        };
+        let (message_count, in_use) = {
+            let existing = self.lock_queue(existing);
+            (existing.messages.len() as u32, !existing.consumers.is_empty())
+        };
+        let refusal = if if_unused && in_use {
+            Some("in use")
+        } else if if_empty && message_count > 0 {
+            Some("not empty")
+        } else {
+            None
+        };
+        if let Some(refusal) = refusal {
+            return Err(AmqpError::channel(
+                reply_codes::PRECONDITION_FAILED,
+                format!("PRECONDITION_FAILED - queue '{}' {}", queue, refusal),
+                CLASS_QUEUE,
+                40,
+            ));
+        }
+        info!("Deleting queue '{}' in vhost '{}'", queue, vhost.name);
+        self.remove_queue(vhost, queue);
+        drop(state);
+        self.resume_deliveries();
+        Ok(message_count)
+    }
+
+    pub fn bind_queue(&self, vhost: &str, binding: Binding, exchange: &str) -> Result<(), AmqpError> {
+        let mut state = self.lock_state();
+        let vhost = state.vhost(vhost)?;
+        if !vhost.queues.contains_key(&binding.queue) {
+            return Err(not_found(
+                format!("NOT_FOUND - no queue '{}'", binding.queue),
+                CLASS_QUEUE,
+                20,
+            ));
+        }
+        let bindings = vhost.bindings();
+        let Some(exchange) = vhost.exchanges.get_mut(exchange) else {
+            return Err(not_found(
+                format!("NOT_FOUND - no exchange '{}'", exchange),
+                CLASS_QUEUE,
+                20,
+            ));
+        };
+        exchange.check_binding(&binding)?;
+        let binding = exchange.canonical_binding(binding);
+        if exchange.kind == ExchangeType::Topic {
+            self.check_topic_key("binding key", &binding.routing_key, CLASS_QUEUE, 20)?;
+        }
+        if !exchange.bindings.contains(&binding) {
+            check_limit(
+                "bindings",
+                bindings,
+                vhost.settings.max_bindings,
+                &vhost.name,
+                CLASS_QUEUE,
+                20,
+            )?;
+        }
+        // Binding again what is already bound changes nothing.
+        let event = BrokerEvent::QueueBound {
+            vhost: vhost.name.clone(),
+            exchange: exchange.name.clone(),
+            queue: binding.queue.clone(),
+            routing_key: binding.routing_key.clone(),
+        };
+        if exchange.bind(binding) {
+            self.emit(event);
+        }
+        Ok(())
+    }
+
+    /// Removes the binding matching queue, key and arguments exactly.
+    pub fn unbind_queue(&self, vhost: &str, binding: &Binding, exchange: &str) -> Result<(), AmqpError> {
+        let mut state = self.lock_state();
+        let vhost = state.vhost(vhost)?;
+        let Some(exchange) = vhost.exchanges.get_mut(exchange) else {
+            return Err(not_found(
+                format!("NOT_FOUND - no exchange '{}'", exchange),
+                CLASS_QUEUE,
+                50,
+            ));
+        };
+        if exchange.is_default() {
+            return Err(AmqpError::channel(
+                reply_codes::ACCESS_REFUSED,
+                "ACCESS_REFUSED - operation not permitted on the default exchange",
+                CLASS_QUEUE,
+                50,
+            ));
+        }
+        if !exchange.unbind(&exchange.canonical_binding(binding.clone())) {
+            return Err(not_found(
+                format!(
+                    "NOT_FOUND - no binding '{}' between exchange '{}' and queue '{}'",
+                    binding.routing_key, exchange.name, binding.queue
+                ),
+                CLASS_QUEUE,
+                50,
+            ));
+        }
+        self.emit(BrokerEvent::QueueUnbound {
+            vhost: vhost.name.clone(),
+            exchange: exchange.name.clone(),
+            queue: binding.queue.clone(),
+            routing_key: binding.routing_key.clone(),
+        });
+        Ok(())
+    }
+
+    /// Attaches a consumer to a queue, returning its id and consumer tag.
+    ///
+    /// An exclusive consumer is refused if the queue already has consumers,
+    /// and while one is attached every other consumer is refused.
+    pub fn consume(&self, vhost: &str, consume: BasicConsume) -> Result<(u64, String), AmqpError> {
+        let (id, queue) = {
+            let mut state = self.lock_state();
+            state.next_consumer_id += 1;
+            let id = state.next_consumer_id;
+            match state.vhost(vhost)?.queues.get(&consume.queue) {
+                Some(queue) => (id, queue.clone()),
+                None => {
+                    return Err(not_found(
+                        format!("NOT_FOUND - no queue '{}'", consume.queue),
+                        CLASS_BASIC,
+                        20,
+                    ))
+                }
+            }
+        };
+        let mut queue = self.lock_queue(&queue);
+        if queue.consumers.iter().any(|c| c.exclusive) {
+            return Err(access_refused(format!(
+                "ACCESS_REFUSED - queue '{}' has an exclusive consumer",
+                queue.name
+            )));
+        }
+        if consume.exclusive && !queue.consumers.is_empty() {
+            return Err(access_refused(format!(
+                "ACCESS_REFUSED - queue '{}' already has consumers, cannot consume exclusively",
+                queue.name
+            )));
+        }
+
+        let tag = if consume.consumer_tag.is_empty() {
+            server_consumer_tag(id)
+        } else {
+            consume.consumer_tag
+        };
+        queue.consumers.push(Consumer {
+            id,
+            tag: tag.clone(),
+            channel: consume.channel,
+            no_ack: consume.no_ack,
+            exclusive: consume.exclusive,
+            sender: consume.sender,
+            prefetch: consume.prefetch,
+            weight: u32::from(consume.priority) + 1,
+            credit: 0,
+        });
+        queue.touch();
+        let settled = queue.dispatch();
+        drop(queue);
+        self.settle(settled);
+        Ok((id, tag))
+    }
+
+    /// A fresh server-generated consumer tag, for a channel to check against
+    /// the tags its client chose before consuming with it.
+    pub fn generate_consumer_tag(&self) -> String {
+        let mut state = self.lock_state();
+        state.next_consumer_id += 1;
+        server_consumer_tag(state.next_consumer_id)
+    }
+
+    /// Takes the next ready message from `queue`, or `None` if it is empty.
+    ///
+    /// Without `no_ack` the message stays outstanding until it is acked or
+    /// requeued, exactly like a consumer delivery.
+    pub fn get(&self, vhost: &str, queue: &str, no_ack: bool) -> Result<Option<BasicGetOk>, AmqpError> {
+        let Some(queue_ref) = self.queue(vhost, queue) else {
+            return Err(not_found(format!("NOT_FOUND - no queue '{}'", queue), CLASS_BASIC, 70));
+        };
+        let mut queue = self.lock_queue(&queue_ref);
+        queue.touch();
+        let Some((message_id, queued)) = queue.get(no_ack) else {
+            return Ok(None);
+        };
+        let message_count = queue.messages.len() as u32;
+        drop(queue);
+        if no_ack {
+            self.settle(queued.store_id);
+        }
+        Ok(Some(BasicGetOk {
+            message_id,
+            redelivered: queued.redelivered,
+            message: queued.for_delivery(),
+            message_count,
+        }))
+    }
+
+    /// Detaches the consumer with `id` from `queue`, returning whether it was attached.
+    pub fn cancel(&self, vhost: &str, queue: &str, id: u64) -> bool {
+        let Some(queue) = self.queue(vhost, queue) else {
+            return false;
+        };
+        let (cancelled, settled) = {
+            let mut queue = self.lock_queue(&queue);
+            let before = queue.consumers.len();
+            queue.consumers.retain(|c| c.id != id);
+            // An idle `x-expires` queue starts counting down from its last consumer.
+            queue.touch();
+            (queue.consumers.len() != before, queue.dispatch())
+        };
+        self.settle(settled);
+        cancelled
+    }
+
+    /// Offers the ready messages of `queue` to its consumers again, as
+    /// after room opened in a prefetch window it shares with other queues.
+    pub fn dispatch(&self, vhost: &str, queue: &str) {
+        if let Some(queue) = self.queue(vhost, queue) {
+            let settled = self.lock_queue(&queue).dispatch();
+            self.settle(settled);
+        }
+    }
+
+    /// Whether the consumer with `id` is still attached to `queue`.
+    pub fn has_consumer(&self, vhost: &str, queue: &str, id: u64) -> bool {
+        self.queue(vhost, queue)
+            .is_some_and(|queue| self.lock_queue(&queue).consumers.iter().any(|c| c.id == id))
+    }
+
+    /// Acknowledges a delivery made from `queue`, returning whether it was outstanding.
+    pub fn ack(&self, vhost: &str, queue: &str, message_id: u64) -> bool {
+        let Some(queue) = self.queue(vhost, queue) else {
+            return false;
+        };
+        let (acked, settled) = {
+            let mut queue = self.lock_queue(&queue);
+            let acked = queue.ack(message_id);
+            // The ack may have opened a consumer's prefetch window.
+            (acked, queue.dispatch())
+        };
+        self.settle(settled);
+        self.resume_deliveries();
+        match acked {
+            Some(queued) => {
+                self.settle(queued.store_id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Dead-letters an unacked delivery the consumer rejected without
+    /// requeueing, returning whether it was outstanding.
+    pub fn reject(&self, vhost: &str, queue: &str, message_id: u64) -> bool {
+        let Some(queue) = self.queue(vhost, queue) else {
+            return false;
+        };
+        let (rejected, dead_letter) = {
+            let mut queue = self.lock_queue(&queue);
+            let Some(rejected) = queue.ack(message_id) else {
+                return false;
+            };
+            let dead_letter = dead_letter(&queue, rejected.message, "rejected", self.config.dead_letter_max_depth);
+            let mut settled = queue.dispatch();
+            settled.extend(rejected.store_id);
+            (settled, dead_letter)
+        };
+        self.settle(rejected);
+        self.resume_deliveries();
+        if let Some(message) = dead_letter {
+            self.publish(vhost, message, false);
+        }
+        true
+    }
+
+    /// Returns unacked deliveries that reached the consumer to `queue` so
+    /// they are delivered again. Messages that exhausted the queue's
+    /// `x-delivery-limit` are dead-lettered instead.
+    pub fn requeue(&self, vhost: &str, queue: &str, message_ids: &[u64]) {
+        self.return_deliveries(vhost, queue, |queue, poisoned| {
+            queue.requeue(message_ids, true, poisoned)
+        });
+    }
+
+    /// Returns the unacked deliveries of consumer `tag`, whose channel went
+    /// away, to `queue`. A queue with `x-consumer-reconnect-window` holds
+    /// them for a consumer with the same tag for that long first.
+    pub fn requeue_for(&self, vhost: &str, queue: &str, tag: &str, message_ids: &[u64]) {
+        self.return_deliveries(vhost, queue, |queue, poisoned| {
+            queue.requeue_for(tag, message_ids, poisoned, self.clock.now())
+        });
+    }
+
+    /// Puts deliveries back with `requeue`, then settles what it returns
+    /// and dead-letters the messages it found past the delivery limit.
+    fn return_deliveries(
+        &self,
+        vhost: &str,
+        queue: &str,
+        requeue: impl FnOnce(&mut Queue, &mut Vec<QueuedMessage>) -> Vec<u64>,
+    ) {
+        let Some(queue) = self.queue(vhost, queue) else {
+            return;
+        };
+        let mut poisoned = Vec::new();
+        let (mut settled, dead_letters) = {
+            let mut queue = self.lock_queue(&queue);
+            let settled = requeue(&mut queue, &mut poisoned);
+            if !poisoned.is_empty() {
+                warn!(
+                    "Dead-lettering {} message(s) past the delivery limit of queue '{}'",
+                    poisoned.len(),
+                    queue.name
+                );
+            }
+            let dead_letters: Vec<Message> = poisoned
+                .iter()
+                .filter_map(|queued| {
+                    dead_letter(
+                        &queue,
+                        queued.message.clone(),
+                        "delivery_limit",
+                        self.config.dead_letter_max_depth,
+                    )
+                })
+                .collect();
+            (settled, dead_letters)
+        };
+        settled.extend(poisoned.iter().filter_map(|queued| queued.store_id));
+        self.settle(settled);
+        self.resume_deliveries();
+        for message in dead_letters {
+            self.publish(vhost, message, false);
+        }
+    }
+
+    /// Returns unacked deliveries that never reached the consumer, such as
+    /// those sent to a consumer cancelled in the meantime, to `queue`. They
+    /// do not count toward its `x-delivery-limit`.
+    pub fn restore(&self, vhost: &str, queue: &str, message_ids: &[u64]) {
+        if let Some(queue) = self.queue(vhost, queue) {
+            let settled = self.lock_queue(&queue).requeue(message_ids, false, &mut Vec::new());
+            self.settle(settled);
+            self.resume_deliveries();
+        }
+    }
+
+    /// Offers ready messages to consumers in every queue again once
+    /// deliveries that stopped at `Config::max_unacked_messages` have room.
+    fn resume_deliveries(&self) {
+        if !self.unacked_limit.take_resumed() {
+            return;
+        }
+        let queues: Vec<QueueRef> = {
+            let state = self.lock_state();
+            state
+                .vhosts
+                .values()
+                .flat_map(|vhost| vhost.queues.values().cloned())
+                .collect()
+        };
+        for queue in queues {
+            let settled = self.lock_queue(&queue).dispatch();
+            self.settle(settled);
+        }
+    }
+
+    /// Consumers that have held up a queue with ready messages for longer
+    /// than `Config::slow_consumer_threshold` because their prefetch window
+    /// stayed full. Empty when the threshold is zero.
+    pub fn slow_consumers(&self) -> Vec<SlowConsumer> {
+        let threshold = self.config.slow_consumer_threshold;
+        if threshold.is_zero() {
+            return Vec::new();
+        }
+        let Some(cutoff) = self.clock.now().checked_sub(threshold) else {
+            return Vec::new();
+        };
+        let state = self.lock_state();
+        let mut slow = Vec::new();
+        for vhost in state.vhosts.values() {
+            for (name, queue) in &vhost.queues {
+                let queue = self.lock_queue(queue);
+                slow.extend(queue.slow_consumers(cutoff).into_iter().map(|consumer| SlowConsumer {
+                    vhost: vhost.name.clone(),
+                    queue: name.clone(),
+                    id: consumer.id,
+                    consumer_tag: consumer.tag.clone(),
+                }));
+            }
+        }
+        slow
+    }
+
+    /// Every queue, sorted by virtual host and name.
+    pub fn queue_summaries(&self) -> Vec<QueueSummary> {
+        let now = self.clock.now();
+        let state = self.lock_state();
+        let mut summaries = Vec::new();
+        for vhost in state.vhosts.values() {
+            for (name, queue) in &vhost.queues {
+                let mut queue = self.lock_queue(queue);
+                let counts = queue.message_counts();
+                summaries.push(QueueSummary {
+                    vhost: vhost.name.clone(),
+                    name: name.clone(),
+                    messages: counts.ready,
+                    unacked: counts.unacked,
+                    total: counts.total(),
+                    consumers: queue.consumers.len(),
+                    consumer_utilization: queue.consumer_utilization(now),
+                    master_locator: queue.options.master_locator.clone(),
+                    leader_locator: queue.options.leader_locator.clone(),
+                    policy: queue.policy.clone(),
+                    created_at: queue.created_at,
+                    last_active: queue.last_active,
+                });
+            }
+        }
+        summaries.sort_by(|a, b| (&a.vhost, &a.name).cmp(&(&b.vhost, &b.name)));
+        summaries
+    }
+
+    /// Every exchange, built-in ones included, sorted by virtual host and
+    /// name.
+    pub fn exchange_summaries(&self) -> Vec<ExchangeSummary> {
+        let state = self.lock_state();
+        let mut summaries: Vec<ExchangeSummary> = state
+            .vhosts
+            .values()
+            .flat_map(|vhost| {
+                vhost.exchanges.values().map(|exchange| ExchangeSummary {
+                    vhost: vhost.name.clone(),
+                    name: exchange.name.clone(),
+                    kind: exchange.kind,
+                    durable: exchange.durable,
+                    builtin: exchange.builtin,
+                    policy: exchange.policy.clone(),
+                    created_at: exchange.created_at,
+                    last_active: exchange.last_active,
+                })
+            })
+            .collect();
+        summaries.sort_by(|a, b| (&a.vhost, &a.name).cmp(&(&b.vhost, &b.name)));
+        summaries
+    }
+
+    /// A snapshot of every virtual host's resource counts, sorted by name.
+    pub fn vhost_summaries(&self) -> Vec<VHostSummary> {
+        let state = self.lock_state();
+        let mut summaries: Vec<VHostSummary> = state
+            .vhosts
+            .values()
+            .map(|vhost| VHostSummary {
+                name: vhost.name.clone(),
+                queues: vhost.queues.len(),
+                exchanges: vhost.declared_exchanges(),
+                bindings: vhost.bindings(),
+                max_queues: vhost.settings.max_queues,
+                max_exchanges: vhost.settings.max_exchanges,
+                max_bindings: vhost.settings.max_bindings,
+            })
+            .collect();
+        summaries.sort_by(|a, b| a.name.cmp(&b.name));
+        summaries
+    }
+
+    /// Notes that `listener` stopped accepting because of `error`.
+    pub fn record_listener_failure(&self, listener: &str, error: &io::Error) {
+        warn!("Listener {} failed: {}", listener, error);
+        self.failed_listeners
+            .lock()
+            .unwrap()
+            .push((listener.to_string(), error.to_string()));
+    }
+
+    /// Makes listeners close the sockets they accept from now on, or with
+    /// `false` accept them again. Open connections are left alone.
+    pub fn pause_listeners(&self, paused: bool) {
+        if self.listeners_paused.swap(paused, Ordering::SeqCst) != paused {
+            info!("Listeners {}", if paused { "paused" } else { "resumed" });
+        }
+    }
+
+    /// Whether listeners are paused.
+    pub fn listeners_paused(&self) -> bool {
+        self.listeners_paused.load(Ordering::SeqCst)
+    }
+
+    /// Alarms, resource pressure and failed listeners, summed up.
+    pub fn health(&self) -> HealthSummary {
+        let mut alarms = Vec::new();
+        if self.disk_alarm() {
+            alarms.push(HealthAlarm {
+                name: "disk",
+                status: HealthStatus::Red,
+                detail: "the message store does not take writes; publishers are blocked".into(),
+            });
+        }
+        let (mut messages, mut message_bytes) = (0, 0);
+        {
+            let state = self.lock_state();
+            let mut vhosts: Vec<&VHost> = state.vhosts.values().collect();
+            vhosts.sort_by(|a, b| a.name.cmp(&b.name));
+            for vhost in vhosts {
+                messages += vhost.usage.messages();
+                message_bytes += vhost.usage.bytes();
+                if vhost.exceeds_quota(1, 1) {
+                    alarms.push(HealthAlarm {
+                        name: "vhost_quota",
+                        status: HealthStatus::Yellow,
+                        detail: format!("vhost '{}' is at its message quota", vhost.name),
+                    });
+                }
+            }
+        }
+        let watermark = self.config.memory_high_watermark;
+        if watermark != 0 && message_bytes as f64 >= watermark as f64 * MEMORY_WARNING_FRACTION {
+            let status = if message_bytes >= watermark {
+                HealthStatus::Red
+            } else {
+                HealthStatus::Yellow
+            };
+            alarms.push(HealthAlarm {
+                name: "memory",
+                status,
+                detail: format!("queued messages take {} of {} bytes", message_bytes, watermark),
+            });
+        }
+        let pending_handshakes = self.connections.pending_handshakes();
+        if let Some((pending, max)) = pending_handshakes.filter(|(pending, max)| pending >= max) {
+            alarms.push(HealthAlarm {
+                name: "handshakes",
+                status: HealthStatus::Yellow,
+                detail: format!(
+                    "{} of {} connections are in their handshake; accepts are paused",
+                    pending, max
+                ),
+            });
+        }
+        for (listener, error) in self.failed_listeners.lock().unwrap().iter() {
+            alarms.push(HealthAlarm {
+                name: "listener",
+                status: HealthStatus::Red,
+                detail: format!("listener {} failed: {}", listener, error),
+            });
+        }
+        let recovering = self.recovering();
+        if recovering {
+            alarms.push(HealthAlarm {
+                name: "recovery",
+                status: HealthStatus::Red,
+                detail: "startup recovery has not finished; listeners refuse new connections".into(),
+            });
+        }
+        let listeners_paused = self.listeners_paused();
+        if listeners_paused {
+            alarms.push(HealthAlarm {
+                name: "listeners_paused",
+                status: HealthStatus::Yellow,
+                detail: "listeners are paused and refuse new connections".into(),
+            });
+        }
+        HealthSummary {
+            status: alarms
+                .iter()
+                .map(|alarm| alarm.status)
+                .max()
+                .unwrap_or(HealthStatus::Green),
+            alarms,
+            connections: self.connections.open_connections().len(),
+            pending_handshakes,
+            listeners_paused,
+            recovering,
+            messages,
+            message_bytes,
+        }
+    }
+
+    /// A snapshot of the topology, sorted by virtual host and name.
+    pub fn definitions(&self) -> Definitions {
+        let state = self.lock_state();
+        let mut definitions = Definitions::default();
+        let mut vhosts: Vec<&VHost> = state.vhosts.values().collect();
+        vhosts.sort_by(|a, b| a.name.cmp(&b.name));
+        for vhost in vhosts {
+            definitions.vhosts.push(vhost.name.clone());
+            let mut queues = Vec::new();
+            for queue in vhost.queues.values() {
+                let queue = self.lock_queue(queue);
+                if queue.exclusive {
+                    continue;
+                }
+                queues.push(QueueDeclare {
+                    queue: queue.name.clone(),
+                    durable: queue.durable,
+                    auto_delete: queue.auto_delete,
+                    arguments: queue.arguments.clone(),
+                    ..Default::default()
+                });
+            }
+            queues.sort_by(|a, b| a.queue.cmp(&b.queue));
+            let mut exchanges: Vec<&Exchange> = vhost.exchanges.values().collect();
+            exchanges.sort_by(|a, b| a.name.cmp(&b.name));
+            for exchange in exchanges {
+                if !exchange.builtin {
+                    definitions.exchanges.push((
+                        vhost.name.clone(),
+                        ExchangeDeclare {
+                            exchange: exchange.name.clone(),
+                            kind: exchange.kind.as_str().into(),
+                            durable: exchange.durable,
+                            auto_delete: exchange.auto_delete,
+                            internal: exchange.internal,
+                            arguments: exchange.arguments.clone(),
+                            ..Default::default()
+                        },
+                    ));
+                }
+                for binding in &exchange.bindings {
+                    if queues.iter().any(|q| q.queue == binding.queue) {
+                        let target = (vhost.name.clone(), exchange.name.clone(), binding.clone());
+                        definitions.bindings.push(target);
+                    }
+                }
+            }
+            let named = queues.into_iter().map(|queue| (vhost.name.clone(), queue));
+            definitions.queues.extend(named);
+        }
+        if let Some(users) = self.auth().users() {
+            definitions.users = users.all_users();
+            definitions.permissions = users.all_permissions();
+        }
+        definitions
+    }
+
+    /// Samples the message totals of every queue; see `RateHistory`.
+    pub fn sample_rates(&self) {
+        let now = self.clock.now();
+        let state = self.lock_state();
+        for queue in state.vhosts.values().flat_map(|vhost| vhost.queues.values()) {
+            let mut queue = self.lock_queue(queue);
+            let totals = queue.totals;
+            queue.rates.record(now, totals);
+        }
+    }
+
+    /// The rates of a queue over the last `window`, or over every sample
+    /// it has without one. `None` for an unknown queue.
+    pub fn queue_rates(&self, vhost: &str, queue: &str, window: Option<Duration>) -> Option<QueueRates> {
+        let queue = self.queue(vhost, queue)?;
+        let queue = self.lock_queue(&queue);
+        let since = window.and_then(|window| self.clock.now().checked_sub(window));
+        Some(QueueRates {
+            totals: queue.totals,
+            average: queue.rates.average(since),
+            series: queue.rates.series(since),
+        })
+    }
+
+    /// Deletes every queue that has gone unused for its `x-expires`,
+    /// returning the virtual host and name of each.
+    pub fn expire_queues(&self) -> Vec<(String, String)> {
+        let now = self.clock.now();
+        let mut state = self.lock_state();
+        let mut expired = Vec::new();
+        for vhost in state.vhosts.values_mut() {
+            let names: Vec<String> = vhost
+                .queues
+                .iter()
+                .filter(|(_, queue)| self.lock_queue(queue).is_expired(now))
+                .map(|(name, _)| name.clone())
+                .collect();
+            for name in names {
+                info!("Queue '{}' in vhost '{}' expired", name, vhost.name);
+                self.remove_queue(vhost, &name);
+                expired.push((vhost.name.clone(), name));
+            }
+        }
+        drop(state);
+        self.resume_deliveries();
+        expired
+    }
+
+    /// Returns messages held for consumers that did not reconnect within
+    /// their queue's `x-consumer-reconnect-window` to their queues.
+    pub fn expire_pending_lanes(&self) {
+        let now = self.clock.now();
+        let queues: Vec<QueueRef> = {
+            let state = self.lock_state();
+            state
+                .vhosts
+                .values()
+                .flat_map(|vhost| vhost.queues.values().cloned())
+                .collect()
+        };
+        for queue in queues {
+            let settled = self.lock_queue(&queue).expire_lanes(now);
+            self.settle(settled);
+        }
+    }
+
+    /// Removes a queue and its bindings, dropping its consumers and messages.
+    fn remove_queue(&self, vhost: &mut VHost, name: &str) {
+        let Some(queue) = vhost.queues.remove(name) else {
+            return;
+        };
+        for exchange in vhost.exchanges.values_mut() {
+            exchange.unbind_queue(name);
+        }
+        let mut queue = self.lock_queue(&queue);
+        queue.consumers.clear();
+        let stored: Vec<u64> = queue.clear().into_iter().filter_map(|m| m.store_id).collect();
+        self.settle(stored);
+        self.emit(BrokerEvent::QueueDeleted {
+            vhost: vhost.name.clone(),
+            queue: name.to_string(),
+        });
+    }
+
+    fn queue(&self, vhost: &str, name: &str) -> Option<QueueRef> {
+        let state = self.lock_state();
+        state.vhosts.get(vhost)?.queues.get(name).cloned()
+    }
+
+    /// Purges the ready messages of every queue in `vhost` whose name
+    /// `matches`, returning each purged queue with the number of messages
+    /// removed, sorted by name. Returns `None` for an unknown vhost.
+    pub fn purge_queues(&self, vhost: &str, matches: impl Fn(&str) -> bool) -> Option<Vec<(String, usize)>> {
+        let mut purged = Vec::new();
+        let mut store_ids = Vec::new();
+        {
+            let state = self.lock_state();
+            let vhost = state.vhosts.get(vhost)?;
+            for (name, queue) in vhost.queues.iter().filter(|(name, _)| matches(name)) {
+                let messages = self.lock_queue(queue).purge();
+                store_ids.extend(messages.iter().filter_map(|queued| queued.store_id));
+                purged.push((name.clone(), messages.len()));
+            }
+        }
+        self.settle(store_ids);
+        purged.sort();
+        Some(purged)
+    }
+
+    /// Copies up to `count` ready messages from the head of a queue, leaving
+    /// the queue untouched. Returns `None` for an unknown queue.
+    pub fn peek(&self, vhost: &str, queue: &str, count: usize) -> Option<Vec<QueuedMessage>> {
+        let queue = self.queue(vhost, queue)?;
+        let queue = self.lock_queue(&queue);
+        Some(queue.peek(0, count).cloned().collect())
+    }
+
+    /// Drops the persisted copies of messages that have been fully handled.
+    fn settle(&self, store_ids: impl IntoIterator<Item = u64>) {
+        for id in store_ids {
+            if let Err(e) = self.discard_stored(id) {
+                error!("Failed to discard stored message {}: {}", id, e);
+            }
+        }
+    }
+
+    pub fn has_interceptors(&self) -> bool {
+        !self.interceptors.is_empty()
+    }
+
+    /// Runs the interceptor chain on a message about to be published,
+    /// stopping at the first interceptor that does not pass it on.
+    pub async fn intercept(&self, message: &mut Message, ctx: &PublishCtx) -> InterceptAction {
+        for interceptor in &self.interceptors {
+            match interceptor.on_publish(message, ctx).await {
+                InterceptAction::Pass => {}
+                action => return action,
+            }
+        }
+        InterceptAction::Pass
+    }
+
+    /// Routes a message through its exchange and enqueues it on every
+    /// matching queue. `mandatory` only affects which unroutable counter is
+    /// bumped. In a drained virtual host the routed message is held until
+    /// it resumes. Messages that queues drop or refuse for being full are
+    /// republished to the queues' dead-letter exchanges.
+    pub fn publish(&self, vhost_name: &str, message: Message, mandatory: bool) -> PublishOutcome {
+        let started = Instant::now();
+        let outcome = self.route(vhost_name, message, mandatory);
+        self.check_latency(Operation::Route, started);
+        outcome
+    }
+
+    fn route(&self, vhost_name: &str, mut message: Message, mandatory: bool) -> PublishOutcome {
+        let (targets, over_quota) = {
+            let mut state = self.lock_state();
+            let Some(vhost) = state.vhosts.get_mut(vhost_name) else {
+                return PublishOutcome::default();
+            };
+            if let Some(mode) = vhost.settings.delivery_mode {
+                message.properties.delivery_mode = Some(mode.delivery_mode());
+            }
+            let Some(exchange) = vhost.exchanges.get(&message.exchange) else {
+                return PublishOutcome::default();
+            };
+            let routed = destinations(
+                vhost,
+                exchange,
+                &message.routing_key,
+                message.properties.headers.as_ref(),
+            );
+            if is_traced(&message) {
+                self.emit(route_trace(vhost_name, exchange, &message, &routed));
+            }
+            let targets: Vec<QueueRef> = routed.into_iter().map(|(_, queue)| queue).collect();
+
+            let exchange = vhost.exchanges.get_mut(&message.exchange).unwrap();
+            exchange.last_active = self.clock.unix_time();
+            let stats = &mut exchange.stats;
+            if !targets.is_empty() {
+                stats.messages_routed += 1;
+            } else if mandatory {
+                stats.messages_unroutable_returned += 1;
+            } else {
+                stats.messages_unroutable_dropped += 1;
+            }
+            if vhost.is_drained() {
+                let routed = targets.len();
+                if routed > 0 {
+                    vhost.held.push(HeldPublish { targets, message });
+                }
+                return PublishOutcome {
+                    routed,
+                    ..Default::default()
+                };
+            }
+            let copies = targets.len() as u64;
+            let over_quota = copies > 0 && vhost.exceeds_quota(copies, copies * message.memory_footprint());
+            if over_quota && vhost.settings.quota_policy == RatePolicy::Nack {
+                debug!(
+                    "Refusing publish to '{}' beyond the quota of vhost '{}'",
+                    message.exchange, vhost_name
+                );
+                return PublishOutcome {
+                    routed: targets.len(),
+                    rejected: true,
+                    ..Default::default()
+                };
+            }
+            (targets, over_quota)
+        };
+        let mut dead_letters = Vec::new();
+        let mut outcome = self.enqueue(vhost_name, &targets, &message, &mut dead_letters);
+        if over_quota {
+            outcome.throttle = outcome.throttle.max(Some(QUOTA_RETRY_INTERVAL));
+        }
+        for message in dead_letters {
+            self.publish(vhost_name, message, false);
+        }
+        outcome.routed = targets.len();
+        outcome
+    }
+
+    /// Names the queues a message published to `exchange` with `routing_key`
+    /// and `headers` would be routed to, without publishing anything.
+    /// Returns `None` for an unknown virtual host or exchange.
+    pub fn route_queues(
+        &self,
+        vhost: &str,
+        exchange: &str,
+        routing_key: &str,
+        headers: Option<&FieldTable>,
+    ) -> Option<Vec<String>> {
+        let state = self.lock_state();
+        let vhost = state.vhosts.get(vhost)?;
+        let exchange = vhost.exchanges.get(exchange)?;
+        let routed = destinations(vhost, exchange, routing_key, headers);
+        Some(routed.into_iter().map(|(name, _)| name).collect())
+    }
+
+    /// Persists `message` where required and adds it to every target queue
+    /// of `vhost` with room for it, applying each queue's publish rate limit and each
+    /// full queue's `x-overflow` behaviour. Dropped and refused messages
+    /// bound for a dead-letter exchange are pushed to `dead_letters`.
+    fn enqueue(
+        &self,
+        vhost: &str,
+        targets: &[QueueRef],
+        message: &Message,
+        dead_letters: &mut Vec<Message>,
+    ) -> PublishOutcome {
+        let persistent = message.properties.delivery_mode == Some(2);
+        let mut outcome = PublishOutcome::default();
+        let now = tokio::time::Instant::from_std(self.clock.now());
+        for queue in targets {
+            let mut queue = self.lock_queue(queue);
+            if let Some(limiter) = queue.rate_limiter.as_mut() {
+                match self.config.publish_rate_policy {
+                    RatePolicy::Nack => {
+                        if !limiter.try_take(now) {
+                            outcome.rejected = true;
+                            continue;
+                        }
+                    }
+                    RatePolicy::Flow => {
+                        if let Some(wait) = limiter.take(now) {
+                            outcome.throttle = outcome.throttle.max(Some(wait));
+                        }
+                    }
+                }
+            }
+            if queue.is_full() {
+                match queue.options.overflow {
+                    Overflow::DropHead => {
+                        for dropped in queue.drop_head() {
+                            self.settle(dropped.store_id);
+                            dead_letters.extend(dead_letter(
+                                &queue,
+                                dropped.message,
+                                "maxlen",
+                                self.config.dead_letter_max_depth,
+                            ));
+                        }
+                    }
+                    Overflow::RejectPublish => {
+                        outcome.rejected = true;
+                        continue;
+                    }
+                    Overflow::RejectPublishDlx => {
+                        outcome.rejected = true;
+                        dead_letters.extend(dead_letter(
+                            &queue,
+                            message.clone(),
+                            "maxlen",
+                            self.config.dead_letter_max_depth,
+                        ));
+                        continue;
+                    }
+                }
+                // Still full with `x-max-length` 0: the message itself is the head.
+                if queue.is_full() {
+                    dead_letters.extend(dead_letter(
+                        &queue,
+                        message.clone(),
+                        "maxlen",
+                        self.config.dead_letter_max_depth,
+                    ));
+                    continue;
+                }
+            }
+            let mut store_id = None;
+            // A queue that will not come back has no use for a stored copy.
+            if persistent && queue.survives_restart() {
+                if let Some(store) = self.store.lock().unwrap().as_mut() {
+                    if self.disk_alarm() {
+                        outcome.unstored = true;
+                    } else {
+                        let started = Instant::now();
+                        let appended = store.append(vhost, &queue.name, message);
+                        self.check_latency(Operation::Store, started);
+                        match appended {
+                            Ok(id) => store_id = Some(id),
+                            Err(e) => {
+                                error!("Failed to persist message for queue '{}': {}", queue.name, e);
+                                self.raise_disk_alarm();
+                                outcome.unstored = true;
+                            }
+                        }
+                    }
+                }
+            }
+            let settled = queue.enqueue(QueuedMessage::new(message.clone(), store_id));
+            drop(queue);
+            self.settle(settled);
+        }
+        outcome
+    }
+
+    /// Checks that a client may publish to `exchange` with `routing_key`:
+    /// both must fit in a short string, and internal exchanges only take
+    /// messages the broker routes to them itself.
+    pub fn check_publish(&self, vhost: &str, exchange: &str, routing_key: &str) -> Result<(), AmqpError> {
+        check_length("exchange name", exchange, CLASS_BASIC, 40)?;
+        check_length("routing key", routing_key, CLASS_BASIC, 40)?;
+        let state = self.lock_state();
+        let target = state.vhosts.get(vhost).and_then(|v| v.exchanges.get(exchange));
+        if target.is_none() && !exchange.is_empty() {
+            return Err(not_found(
+                format!("NOT_FOUND - no exchange '{}'", exchange),
+                CLASS_BASIC,
+                40,
+            ));
+        }
+        if target.is_some_and(|e| e.kind == ExchangeType::Topic) {
+            self.check_topic_key("routing key", routing_key, CLASS_BASIC, 40)?;
+        }
+        if target.is_some_and(|e| e.internal) {
+            return Err(AmqpError::channel(
+                reply_codes::ACCESS_REFUSED,
+                format!("ACCESS_REFUSED - cannot publish to internal exchange '{}'", exchange),
+                CLASS_BASIC,
+                40,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks a topic routing key or binding pattern against
+    /// `Config::topic_max_words` and `Config::topic_max_length`.
+    fn check_topic_key(&self, what: &str, key: &str, class_id: u16, method_id: u16) -> Result<(), AmqpError> {
+        let words = key.split('.').count();
+        let (max_words, max_length) = (self.config.topic_max_words, self.config.topic_max_length);
+        let problem = if max_words > 0 && words > max_words {
+            format!("has {} words, more than the limit of {}", words, max_words)
+        } else if max_length > 0 && key.len() > max_length {
+            format!("is {} bytes long, more than the limit of {}", key.len(), max_length)
+        } else {
+            return Ok(());
+        };
+        Err(AmqpError::channel(
+            reply_codes::PRECONDITION_FAILED,
+            format!("PRECONDITION_FAILED - topic {} {}", what, problem),
+            class_id,
+            method_id,
+        ))
+    }
+
+    pub fn exchange_stats(&self, vhost: &str, exchange: &str) -> Option<ExchangeStats> {
+        let state = self.lock_state();
+        state.vhosts.get(vhost)?.exchanges.get(exchange).map(|e| e.stats)
+    }
+
+    /// Routing counters of every exchange as (vhost, exchange, counters),
+    /// sorted by virtual host and exchange name.
+    pub fn all_exchange_stats(&self) -> Vec<(String, String, ExchangeStats)> {
+        let state = self.lock_state();
+        let mut stats: Vec<_> = state
+            .vhosts
+            .values()
+            .flat_map(|v| v.exchanges.values().map(|e| (v.name.clone(), e.name.clone(), e.stats)))
+            .collect();
+        stats.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        stats
+    }
+
+    /// Marks a persisted message as no longer needed, e.g. once it is acked.
+    pub fn discard_stored(&self, store_id: u64) -> io::Result<()> {
+        let mut store = self.store.lock().unwrap();
+        let Some(store) = store.as_mut() else {
+            return Ok(());
+        };
+        let started = Instant::now();
+        let acked = store.ack(store_id);
+        self.check_latency(Operation::Store, started);
+        acked
+    }
+
+    /// Locks the broker's state, counting a long wait for it.
+    fn lock_state(&self) -> MutexGuard<'_, BrokerState> {
+        self.slow_operations
+            .lock(self.config.slow_operation_threshold, Operation::StateLock, &self.state)
+    }
+
+    /// Locks `queue`, counting a long wait for it.
+    fn lock_queue<'a>(&self, queue: &'a QueueRef) -> MutexGuard<'a, Queue> {
+        self.slow_operations
+            .lock(self.config.slow_operation_threshold, Operation::QueueLock, queue)
+    }
+
+    /// Logs and counts an `operation` begun at `started` if it was slow.
+    pub(crate) fn check_latency(&self, operation: Operation, started: Instant) {
+        self.slow_operations
+            .check(self.config.slow_operation_threshold, operation, started);
+    }
+
+    /// How many `operation`s took longer than the slow operation threshold.
+    pub fn slow_operations(&self, operation: Operation) -> u64 {
+        self.slow_operations.count(operation)
+    }
+
+    /// Counts the body size of a message a client published, once its
+    /// content is complete.
+    pub(crate) fn observe_message_size(&self, bytes: usize) {
+        self.message_sizes.observe(bytes as u64);
+    }
+
+    /// Body sizes of the messages clients published so far.
+    pub fn message_sizes(&self) -> HistogramSnapshot {
+        self.message_sizes.snapshot()
+    }
+
+    /// Whether the disk alarm is raised.
+    pub fn disk_alarm(&self) -> bool {
+        *self.disk_alarm.borrow()
+    }
+
+    pub fn watch_disk_alarm(&self) -> watch::Receiver<bool> {
+        self.disk_alarm.subscribe()
+    }
+
+    fn raise_disk_alarm(&self) {
+        if !self.disk_alarm.send_replace(true) {
+            warn!("Disk alarm raised: blocking publishers until the message store takes writes again");
+            self.emit(BrokerEvent::DiskAlarm { raised: true });
+        }
+    }
+
+    /// Writes the persistent messages of durable queues that were kept in
+    /// memory while the disk alarm was raised, and lowers it once all of them
+    /// are stored. Returns how many messages were written.
+    ///
+    /// Lowering the alarm acks the held publisher confirms, so messages
+    /// delivered in the meantime are written too, whether they wait for an
+    /// ack or in a pending lane. Only those already settled are not: they
+    /// were consumed, and need no stored copy.
+    pub fn retry_store(&self) -> io::Result<usize> {
+        if !self.disk_alarm() {
+            return Ok(0);
+        }
+        let mut written = 0;
+        let state = self.lock_state();
+        for (vhost_name, vhost) in &state.vhosts {
+            for queue in vhost.queues.values() {
+                let mut queue = self.lock_queue(queue);
+                if !queue.survives_restart() {
+                    continue;
+                }
+                let name = queue.name.clone();
+                let mut store = self.store.lock().unwrap();
+                let Some(store) = store.as_mut() else {
+                    continue;
+                };
+                let unstored = queue
+                    .held_messages_mut()
+                    .into_iter()
+                    .filter(|m| m.store_id.is_none() && m.message.properties.delivery_mode == Some(2));
+                for queued in unstored {
+                    let started = Instant::now();
+                    let appended = store.append(vhost_name, &name, &queued.message);
+                    self.check_latency(Operation::Store, started);
+                    queued.store_id = Some(appended?);
+                    written += 1;
+                }
+            }
+        }
+        drop(state);
+        self.disk_alarm.send_replace(false);
+        info!("Disk alarm cleared after storing {} held messages", written);
+        self.emit(BrokerEvent::DiskAlarm { raised: false });
+        Ok(written)
+    }
+
+    /// Starts shutting down: held messages get one last chance to be
+    /// stored, delivered ones included, then every connection resolves its
+    /// outstanding publisher confirms and closes with `CONNECTION_FORCED`.
+    /// The confirms are acks only if every held message was stored, and
+    /// nacks otherwise.
+    pub fn shut_down(&self) {
+        if let Err(e) = self.retry_store() {
+            warn!("Shutting down with messages the store did not take: {}", e);
+        }
+        if !self.shutdown.send_replace(true) {
+            info!("Shutting down, closing client connections");
+        }
+    }
+
+    pub fn watch_shutdown(&self) -> watch::Receiver<bool> {
+        self.shutdown.subscribe()
+    }
+
+    /// Runs compaction on the message store, if there is one.
+    pub fn compact_store(&self) -> io::Result<Option<CompactionStats>> {
+        self.store.lock().unwrap().as_mut().map(|store| store.compact()).transpose()
+    }
+}
+
+/// What of `definitions` survives a restart: every virtual host, the
+/// durable exchanges, the durable queues but for auto-delete ones, and the
+/// bindings between those. `definitions` already leave out exclusive
+/// queues. Users and permissions come from the configuration, and are
+/// left out too.
+fn durable_part(mut definitions: Definitions) -> Definitions {
+    definitions.users.clear();
+    definitions.permissions.clear();
+    let transient: HashSet<(String, String)> = definitions
+        .exchanges
+        .iter()
+        .filter(|(_, exchange)| !exchange.durable)
+        .map(|(vhost, exchange)| (vhost.clone(), exchange.exchange.clone()))
+        .collect();
+    definitions.exchanges.retain(|(_, exchange)| exchange.durable);
+    definitions
+        .queues
+        .retain(|(_, queue)| queue.durable && !queue.auto_delete);
+    let queues = &definitions.queues;
+    definitions.bindings.retain(|(vhost, exchange, binding)| {
+        !transient.contains(&(vhost.clone(), exchange.clone()))
+            && queues
+                .iter()
+                .any(|(v, queue)| v == vhost && queue.queue == binding.queue)
+    });
+    definitions
+}
+
+/// Creates virtual host `name` with its settings from `config`.
+fn new_vhost(config: &Config, name: &str) -> VHost {
+    let mut vhost = VHost::new(name.to_string());
+    vhost.settings = config.vhost_settings.get(name).cloned().unwrap_or_default();
+    vhost
+}
+
+/// The queues of `vhost` that `exchange` routes `routing_key` and `headers`
+/// to. Bindings to queues that no longer exist are skipped.
+fn destinations(
+    vhost: &VHost,
+    exchange: &Exchange,
+    routing_key: &str,
+    headers: Option<&FieldTable>,
+) -> Vec<(String, QueueRef)> {
+    exchange
+        .route(routing_key, headers)
+        .into_iter()
+        .filter_map(|name| vhost.queues.get(&name).cloned().map(|queue| (name, queue)))
+        .collect()
+}
+
+/// Applies the policies of `vhost` to its queues and exchanges, after one
+/// was set or removed. A queue whose arguments do not combine with its new
+/// policy keeps the options it had.
+fn apply_policies(vhost: &mut VHost) {
+    for queue in vhost.queues.values() {
+        let mut queue = queue.lock().unwrap();
+        let policy = policy::select(vhost.policies.values(), ApplyTo::Queues, &queue.name);
+        let options = match QueueOptions::from_arguments(&policy::merge(&queue.arguments, policy)) {
+            Ok(options) => options,
+            Err(e) => {
+                warn!("Not applying policies to queue '{}': {}", queue.name, e);
+                continue;
+            }
+        };
+        if options.max_publish_rate != queue.options.max_publish_rate {
+            queue.rate_limiter = options.max_publish_rate.map(TokenBucket::new);
+        }
+        queue.options = options;
+        queue.policy = policy.map(|policy| policy.name.clone());
+    }
+    for exchange in vhost.exchanges.values_mut().filter(|exchange| !exchange.builtin) {
+        let policy = policy::select(vhost.policies.values(), ApplyTo::Exchanges, &exchange.name);
+        exchange.policy = policy.map(|policy| policy.name.clone());
+        exchange.policy_arguments = policy.map(|policy| policy.definition.clone()).unwrap_or_default();
+    }
+}
+
+/// Whether the publisher asked for the routing of `message` to be traced.
+fn is_traced(message: &Message) -> bool {
+    let headers = message.properties.headers.as_ref();
+    headers
+        .and_then(|headers| headers.get("x-trace"))
+        .is_some_and(|value| *value != FieldValue::Boolean(false))
+}
+
+/// Records how `message` was routed through `exchange` to the queues in
+/// `routed`.
+fn route_trace(vhost: &str, exchange: &Exchange, message: &Message, routed: &[(String, QueueRef)]) -> BrokerEvent {
+    let headers = message.properties.headers.as_ref();
+    let bindings: Vec<TracedBinding> = exchange
+        .matched_bindings(&message.routing_key, headers)
+        .into_iter()
+        .map(|binding| TracedBinding {
+            queue: binding.queue.clone(),
+            routing_key: binding.routing_key.clone(),
+        })
+        .collect();
+    let queues: Vec<String> = routed.iter().map(|(name, _)| name.clone()).collect();
+    // The newest death comes first.
+    let dead_lettered_from = match headers.and_then(|headers| headers.get("x-death")) {
+        Some(FieldValue::FieldArray(deaths)) => match deaths.first() {
+            Some(FieldValue::FieldTable(death)) => death.get("queue").and_then(FieldValue::as_str).map(String::from),
+            _ => None,
+        },
+        _ => None,
+    };
+    info!(
+        "Traced message to exchange '{}' in vhost '{}' with key '{}': {} bindings matched, routed to {:?}",
+        exchange.name,
+        vhost,
+        message.routing_key,
+        bindings.len(),
+        queues
+    );
+    BrokerEvent::MessageRouted {
+        vhost: vhost.to_string(),
+        exchange: exchange.name.clone(),
+        routing_key: message.routing_key.clone(),
+        bindings,
+        queues,
+        dead_lettered_from,
+    }
+}
+
+/// Prepares `message`, which `queue` gave up on for `reason`, for
+/// republishing to the queue's dead-letter exchange, recording the death in
+/// the `x-death` header as RabbitMQ does: one entry per queue and reason,
+/// counting how often the message died there. The publisher's headers are
+/// left as they are, in their order, with `x-death` and the
+/// `x-first-death-*` keys added after them. The message keeps its routing
+/// key unless the queue has an `x-dead-letter-routing-key`.
+///
+/// Returns `None` if the queue has no dead-letter exchange, or if the
+/// message has already died `max_depth` times: it is then likely going
+/// round a cycle and is dropped.
+fn dead_letter(queue: &Queue, mut message: Message, reason: &str, max_depth: u32) -> Option<Message> {
+    let exchange = queue.options.dead_letter_exchange.clone()?;
+    let mut headers = message.properties.headers.take().unwrap_or_default();
+    let mut deaths = match headers.get("x-death") {
+        Some(FieldValue::FieldArray(deaths)) => deaths.clone(),
+        _ => Vec::new(),
+    };
+    let count = |death: &FieldValue| match death {
+        FieldValue::FieldTable(death) => death.get("count").and_then(FieldValue::as_i64).unwrap_or(0),
+        _ => 0,
+    };
+    let depth: i64 = deaths.iter().map(count).sum();
+    if depth >= i64::from(max_depth) {
+        warn!(
+            "Dropping message from queue '{}' dead-lettered {} times, the most allowed",
+            queue.name, depth
+        );
+        return None;
+    }
+    let text = |s: &str| FieldValue::LongString(s.as_bytes().to_vec());
+    let same_death = |death: &FieldValue| match death {
+        FieldValue::FieldTable(death) => {
+            death.get("queue").and_then(FieldValue::as_str) == Some(queue.name.as_str())
+                && death.get("reason").and_then(FieldValue::as_str) == Some(reason)
+        }
+        _ => false,
+    };
+    // A repeated death moves its entry to the front with a higher count.
+    let death = match deaths.iter().position(same_death).map(|index| deaths.remove(index)) {
+        Some(FieldValue::FieldTable(mut death)) => {
+            let count = death.get("count").and_then(FieldValue::as_i64).unwrap_or(0);
+            death.insert("count", FieldValue::LongLongInt(count + 1));
+            death
+        }
+        _ => {
+            let mut death = FieldTable::new();
+            death.insert("count", FieldValue::LongLongInt(1));
+            death.insert("reason", text(reason));
+            death.insert("queue", text(&queue.name));
+            death.insert("exchange", text(&message.exchange));
+            death.insert("routing-keys", FieldValue::FieldArray(vec![text(&message.routing_key)]));
+            death
+        }
+    };
+    deaths.insert(0, FieldValue::FieldTable(death));
+    headers.insert("x-death", FieldValue::FieldArray(deaths));
+    if headers.get("x-first-death-reason").is_none() {
+        headers.insert("x-first-death-reason", text(reason));
+        headers.insert("x-first-death-queue", text(&queue.name));
+        headers.insert("x-first-death-exchange", text(&message.exchange));
+    }
+    message.properties.headers = Some(headers);
+    if let Some(routing_key) = &queue.options.dead_letter_routing_key {
+        message.routing_key = routing_key.clone();
+    }
+    message.exchange = exchange;
+    Some(message)
+}
+
+/// Deletes expired queues and returns expired pending lanes to their
+/// queues every `interval` until the broker is dropped.
+/// Records every event the broker emits in its event log, until the
+/// broker is dropped.
+pub fn spawn_event_log(broker: &Arc<Broker>) -> tokio::task::JoinHandle<()> {
+    let mut events = broker.subscribe_events();
+    let (log, clock) = (broker.event_log.clone(), broker.clock.clone());
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => log.push(RecordedEvent {
+                    at: clock.unix_time(),
+                    event,
+                }),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Event log fell behind and missed {} events", missed)
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    })
+}
+
+pub fn spawn_queue_expiry(broker: &Arc<Broker>, interval: Duration) -> tokio::task::JoinHandle<()> {
+    let broker = Arc::downgrade(broker);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let Some(broker) = broker.upgrade() else {
+                return;
+            };
+            broker.expire_queues();
+            broker.expire_pending_lanes();
+        }
+    })
+}
+
+/// Samples the message totals of every queue every `interval` until the
+/// broker is dropped.
+pub fn spawn_rate_sampling(broker: &Arc<Broker>, interval: Duration) -> tokio::task::JoinHandle<()> {
+    let broker = Arc::downgrade(broker);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let Some(broker) = broker.upgrade() else {
+                return;
+            };
+            broker.sample_rates();
+        }
+    })
+}
+
+/// Looks for slow consumers every `interval` until the broker is dropped,
+/// warning once about each consumer that becomes slow.
+pub fn spawn_slow_consumer_check(broker: &Arc<Broker>, interval: Duration) -> tokio::task::JoinHandle<()> {
+    let broker = Arc::downgrade(broker);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        let mut flagged = HashSet::new();
+        loop {
+            ticker.tick().await;
+            let Some(broker) = broker.upgrade() else {
+                return;
+            };
+            let slow = broker.slow_consumers();
+            for consumer in &slow {
+                if !flagged.contains(&consumer.id) {
+                    warn!(
+                        "Consumer '{}' on queue '{}' in vhost '{}' has kept its prefetch window full for over {:?}",
+                        consumer.consumer_tag,
+                        consumer.queue,
+                        consumer.vhost,
+                        broker.config().slow_consumer_threshold
+                    );
+                }
+            }
+            flagged = slow.into_iter().map(|consumer| consumer.id).collect();
+        }
+    })
+}
+
+/// Rewrites the topology file each time something is declared, deleted,
+/// bound or unbound, until the broker is dropped. Changes that arrive
+/// while a write is under way are saved together by the next one.
+pub fn spawn_topology_persistence(broker: &Arc<Broker>) -> tokio::task::JoinHandle<()> {
+    let mut events = broker.subscribe_events();
+    let broker = Arc::downgrade(broker);
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) if !event.changes_topology() => continue,
+                // Missed events may have changed the topology.
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+            while !matches!(
+                events.try_recv(),
+                Err(broadcast::error::TryRecvError::Empty | broadcast::error::TryRecvError::Closed)
+            ) {}
+            let Some(broker) = broker.upgrade() else {
+                return;
+            };
+            match tokio::task::spawn_blocking(move || broker.save_topology()).await {
+                Ok(Err(e)) => error!("Saving the topology failed: {}", e),
+                Err(e) => error!("Saving the topology panicked: {}", e),
+                Ok(Ok(())) => {}
+            }
+        }
+    })
+}
+
+/// Compacts the broker's message store every `interval` until the broker is dropped.
+pub fn spawn_compaction(broker: &Arc<Broker>, interval: Duration) -> tokio::task::JoinHandle<()> {
+    let broker = Arc::downgrade(broker);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let Some(broker) = broker.upgrade() else {
+                return;
+            };
+            match tokio::task::spawn_blocking(move || broker.compact_store()).await {
+                Ok(Ok(Some(stats))) if stats.bytes_reclaimed > 0 => {
+                    info!("Compacted message store: {:?}", stats)
+                }
+                Ok(Err(e)) => error!("Message store compaction failed: {}", e),
+                _ => {}
+            }
+        }
+    })
+}
+
+fn server_consumer_tag(id: u64) -> String {
+    format!("amq.ctag-{}", id)
+}
+
+/// Retries the message store every `interval` while the disk alarm is
+/// raised, until the broker is dropped.
+pub fn spawn_store_retry(broker: &Arc<Broker>, interval: Duration) -> tokio::task::JoinHandle<()> {
+    let broker = Arc::downgrade(broker);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let Some(broker) = broker.upgrade() else {
+                return;
+            };
+            if !broker.disk_alarm() {
+                continue;
+            }
+            match tokio::task::spawn_blocking(move || broker.retry_store()).await {
+                Ok(Err(e)) => error!("Message store still failing: {}", e),
+                Err(e) => error!("Message store retry panicked: {}", e),
+                Ok(Ok(_)) => {}
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::field_table::FieldValue;
+    use crate::properties::BasicProperties;
+    use crate::vhost::VHostSettings;
+
+    fn quorum_args() -> FieldTable {
+        let mut arguments = FieldTable::new();
+        arguments.insert("x-queue-type", FieldValue::LongString(b"quorum".to_vec()));
+        arguments
+    }
+
+    fn queue_type(broker: &Broker, name: &str) -> (QueueType, bool) {
+        let queue = broker.queue(DEFAULT_VHOST, name).unwrap();
+        let queue = queue.lock().unwrap();
+        (queue.queue_type, queue.durable)
+    }
+
+    #[test]
+    fn test_declare_classic_queue() {
+        let broker = Broker::new(Config::default());
+        let ok = broker
+            .declare_queue(
+                DEFAULT_VHOST,
+                QueueDeclare {
+                    queue: "jobs".into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(ok.queue, "jobs");
+        assert_eq!(queue_type(&broker, "jobs"), (QueueType::Classic, false));
+    }
+
+    #[test]
+    fn test_declare_quorum_queue_is_durable() {
+        let broker = Broker::new(Config::default());
+        broker
+            .declare_queue(
+                DEFAULT_VHOST,
+                QueueDeclare {
+                    queue: "jobs".into(),
+                    arguments: quorum_args(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(queue_type(&broker, "jobs"), (QueueType::Quorum, true));
+    }
+
+    #[test]
+    fn test_default_queue_type_is_configurable() {
+        let broker = Broker::new(Config {
+            default_queue_type: QueueType::Quorum,
+            ..Default::default()
+        });
+        broker
+            .declare_queue(
+                DEFAULT_VHOST,
+                QueueDeclare {
+                    queue: "jobs".into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(queue_type(&broker, "jobs"), (QueueType::Quorum, true));
+    }
+
+    #[test]
+    fn test_quorum_queue_rejects_exclusive() {
+        let broker = Broker::new(Config::default());
+        let err = broker
+            .declare_queue(
+                DEFAULT_VHOST,
+                QueueDeclare {
+                    queue: "jobs".into(),
+                    exclusive: true,
+                    arguments: quorum_args(),
+                    ..Default::default()
+                },
+            )
+            .unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::PRECONDITION_FAILED);
+    }
+
+    fn declare(broker: &Broker, queue: &str, exchange: &str, kind: &str) {
+        broker
+            .declare_queue(
+                DEFAULT_VHOST,
+                QueueDeclare {
+                    queue: queue.into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        broker
+            .declare_exchange(
+                DEFAULT_VHOST,
+                ExchangeDeclare {
+                    exchange: exchange.into(),
+                    kind: kind.into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+    }
+
+    fn message(exchange: &str, routing_key: &str, headers: Option<FieldTable>) -> Message {
+        Message {
+            exchange: exchange.into(),
+            routing_key: routing_key.into(),
+            properties: BasicProperties {
+                headers,
+                ..Default::default()
+            },
+            body: b"payload".to_vec(),
+        }
+    }
+
+    fn header_binding(format: &str) -> Binding {
+        let mut arguments = FieldTable::new();
+        arguments.insert("x-match", FieldValue::LongString(b"all".to_vec()));
+        arguments.insert("format", FieldValue::LongString(format.as_bytes().to_vec()));
+        Binding {
+            queue: "reports".into(),
+            routing_key: "".into(),
+            arguments,
+        }
+    }
+
+    #[test]
+    fn test_traced_messages_record_their_route() {
+        let broker = Broker::new(Config::default());
+        for (queue, pattern) in [
+            ("eu-orders", "orders.eu.*"),
+            ("all-orders", "orders.#"),
+            ("audit", "audit.#"),
+        ] {
+            declare(&broker, queue, "orders", "topic");
+            let binding = Binding {
+                queue: queue.into(),
+                routing_key: pattern.into(),
+                arguments: FieldTable::new(),
+            };
+            broker.bind_queue(DEFAULT_VHOST, binding, "orders").unwrap();
+        }
+        let mut events = broker.subscribe_events();
+        let traced = |value: FieldValue| {
+            let mut headers = FieldTable::new();
+            headers.insert("x-trace", value);
+            message("orders", "orders.eu.created", Some(headers))
+        };
+
+        broker.publish(DEFAULT_VHOST, message("orders", "orders.eu.created", None), false);
+        broker.publish(DEFAULT_VHOST, traced(FieldValue::Boolean(false)), false);
+        assert!(events.try_recv().is_err());
+
+        let outcome = broker.publish(DEFAULT_VHOST, traced(FieldValue::Boolean(true)), false);
+        assert_eq!(outcome.routed, 2);
+        let Ok(BrokerEvent::MessageRouted {
+            exchange,
+            routing_key,
+            mut bindings,
+            mut queues,
+            dead_lettered_from,
+            ..
+        }) = events.try_recv()
+        else {
+            panic!("expected the route of the traced message");
+        };
+        assert_eq!(
+            (exchange.as_str(), routing_key.as_str()),
+            ("orders", "orders.eu.created")
+        );
+        bindings.sort_by(|a, b| a.queue.cmp(&b.queue));
+        let matched = |queue: &str, routing_key: &str| TracedBinding {
+            queue: queue.into(),
+            routing_key: routing_key.into(),
+        };
+        assert_eq!(
+            bindings,
+            vec![matched("all-orders", "orders.#"), matched("eu-orders", "orders.eu.*")]
+        );
+        queues.sort();
+        assert_eq!(queues, vec!["all-orders", "eu-orders"]);
+        assert_eq!(dead_lettered_from, None);
+        // Tracing leaves delivery alone.
+        assert_eq!(
+            broker
+                .queue(DEFAULT_VHOST, "eu-orders")
+                .unwrap()
+                .lock()
+                .unwrap()
+                .messages
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_unbind_matches_binding_arguments_exactly() {
+        let broker = Broker::new(Config::default());
+        declare(&broker, "reports", "docs", "headers");
+        broker.bind_queue(DEFAULT_VHOST, header_binding("pdf"), "docs").unwrap();
+        broker.bind_queue(DEFAULT_VHOST, header_binding("csv"), "docs").unwrap();
+
+        broker
+            .unbind_queue(DEFAULT_VHOST, &header_binding("pdf"), "docs")
+            .unwrap();
+        let err = broker
+            .unbind_queue(DEFAULT_VHOST, &header_binding("pdf"), "docs")
+            .unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::NOT_FOUND);
+
+        let mut pdf = FieldTable::new();
+        pdf.insert("format", FieldValue::LongString(b"pdf".to_vec()));
+        let mut csv = FieldTable::new();
+        csv.insert("format", FieldValue::LongString(b"csv".to_vec()));
+        assert_eq!(
+            broker
+                .publish(DEFAULT_VHOST, message("docs", "", Some(pdf)), false)
+                .routed,
+            0
+        );
+        assert_eq!(
+            broker
+                .publish(DEFAULT_VHOST, message("docs", "", Some(csv)), false)
+                .routed,
+            1
+        );
+    }
+
+    #[test]
+    fn test_binding_twice_routes_each_message_once() {
+        let broker = Broker::new(Config::default());
+        declare(&broker, "jobs", "work", "direct");
+        let binding = Binding {
+            queue: "jobs".into(),
+            routing_key: "jobs".into(),
+            arguments: FieldTable::new(),
+        };
+        let mut events = broker.subscribe_events();
+        broker.bind_queue(DEFAULT_VHOST, binding.clone(), "work").unwrap();
+        broker.bind_queue(DEFAULT_VHOST, binding.clone(), "work").unwrap();
+        assert!(matches!(events.try_recv(), Ok(BrokerEvent::QueueBound { .. })));
+        assert!(events.try_recv().is_err());
+
+        assert_eq!(
+            broker
+                .publish(DEFAULT_VHOST, message("work", "jobs", None), false)
+                .routed,
+            1
+        );
+        assert_eq!(broker.peek(DEFAULT_VHOST, "jobs", 10).unwrap().len(), 1);
+        // One unbind removes the binding however many times it was made.
+        broker.unbind_queue(DEFAULT_VHOST, &binding, "work").unwrap();
+        assert_eq!(
+            broker
+                .publish(DEFAULT_VHOST, message("work", "jobs", None), false)
+                .routed,
+            0
+        );
+
+        // Arguments listed in another order make the same binding.
+        declare(&broker, "reports", "docs", "headers");
+        let binding = header_binding("pdf");
+        let mut reordered = FieldTable::new();
+        for (key, value) in binding.arguments.iter().collect::<Vec<_>>().into_iter().rev() {
+            reordered.insert(key, value.clone());
+        }
+        broker.bind_queue(DEFAULT_VHOST, binding.clone(), "docs").unwrap();
+        let reordered = Binding {
+            arguments: reordered,
+            ..binding
+        };
+        broker.bind_queue(DEFAULT_VHOST, reordered.clone(), "docs").unwrap();
+        broker.unbind_queue(DEFAULT_VHOST, &reordered, "docs").unwrap();
+        let mut pdf = FieldTable::new();
+        pdf.insert("format", FieldValue::LongString(b"pdf".to_vec()));
+        assert_eq!(
+            broker
+                .publish(DEFAULT_VHOST, message("docs", "", Some(pdf)), false)
+                .routed,
+            0
+        );
+    }
+
+    #[test]
+    fn test_unbind_unknown_binding_is_not_found() {
+        let broker = Broker::new(Config::default());
+        declare(&broker, "orders", "events", "topic");
+        let binding = Binding {
+            queue: "orders".into(),
+            routing_key: "orders.*".into(),
+            arguments: FieldTable::new(),
+        };
+        let err = broker.unbind_queue(DEFAULT_VHOST, &binding, "events").unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::NOT_FOUND);
+
+        broker.bind_queue(DEFAULT_VHOST, binding.clone(), "events").unwrap();
+        assert_eq!(
+            broker
+                .publish(DEFAULT_VHOST, message("events", "orders.created", None), false)
+                .routed,
+            1
+        );
+        broker.unbind_queue(DEFAULT_VHOST, &binding, "events").unwrap();
+        assert_eq!(
+            broker
+                .publish(DEFAULT_VHOST, message("events", "orders.created", None), false)
+                .routed,
+            0
+        );
+    }
+
+    #[test]
+    fn test_delete_queue_checks_preconditions_and_unbinds() {
+        let broker = Broker::new(Config::default());
+        declare(&broker, "orders", "events", "fanout");
+        let binding = Binding {
+            queue: "orders".into(),
+            routing_key: "".into(),
+            arguments: FieldTable::new(),
+        };
+        broker.bind_queue(DEFAULT_VHOST, binding, "events").unwrap();
+        broker.publish(DEFAULT_VHOST, message("events", "", None), false);
+
+        let err = broker.delete_queue(DEFAULT_VHOST, "orders", false, true).unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::PRECONDITION_FAILED);
+        assert_eq!(broker.delete_queue(DEFAULT_VHOST, "orders", true, false).unwrap(), 1);
+        let err = broker.delete_queue(DEFAULT_VHOST, "orders", false, false).unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::NOT_FOUND);
+        assert_eq!(
+            broker.publish(DEFAULT_VHOST, message("events", "", None), false).routed,
+            0
+        );
+    }
+
+    #[test]
+    fn test_routing_counters_distinguish_returned_and_dropped() {
+        let broker = Broker::new(Config::default());
+        declare(&broker, "orders", "events", "topic");
+        broker
+            .bind_queue(
+                DEFAULT_VHOST,
+                Binding {
+                    queue: "orders".into(),
+                    routing_key: "orders.*".into(),
+                    arguments: FieldTable::new(),
+                },
+                "events",
+            )
+            .unwrap();
+
+        broker.publish(DEFAULT_VHOST, message("events", "orders.created", None), false);
+        broker.publish(DEFAULT_VHOST, message("events", "orders.created", None), true);
+        broker.publish(DEFAULT_VHOST, message("events", "users.created", None), true);
+        broker.publish(DEFAULT_VHOST, message("events", "users.created", None), false);
+        broker.publish(DEFAULT_VHOST, message("events", "users.deleted", None), false);
+
+        let stats = broker.exchange_stats(DEFAULT_VHOST, "events").unwrap();
+        assert_eq!(stats.messages_routed, 2);
+        assert_eq!(stats.messages_unroutable_returned, 1);
+        assert_eq!(stats.messages_unroutable_dropped, 2);
+        assert_eq!(stats.messages_unroutable(), 3);
+    }
+
+    #[test]
+    fn test_invalid_queue_type_is_rejected() {
+        let broker = Broker::new(Config::default());
+        let mut arguments = FieldTable::new();
+        arguments.insert("x-queue-type", FieldValue::LongString(b"stream-ish".to_vec()));
+        let err = broker
+            .declare_queue(
+                DEFAULT_VHOST,
+                QueueDeclare {
+                    queue: "jobs".into(),
+                    arguments,
+                    ..Default::default()
+                },
+            )
+            .unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::PRECONDITION_FAILED);
+    }
+
+    /// A store that takes its time over every append and ack.
+    struct SlowStore(Duration);
+
+    impl MessageStore for SlowStore {
+        fn append(&mut self, _vhost: &str, _queue: &str, _message: &Message) -> io::Result<u64> {
+            std::thread::sleep(self.0);
+            Ok(1)
+        }
+
+        fn ack(&mut self, _id: u64) -> io::Result<()> {
+            std::thread::sleep(self.0);
+            Ok(())
+        }
+
+        fn recover(&mut self) -> io::Result<Vec<crate::store::StoredMessage>> {
+            Ok(Vec::new())
+        }
+
+        fn compact(&mut self) -> io::Result<CompactionStats> {
+            Ok(CompactionStats::default())
+        }
+    }
+
+    #[test]
+    fn test_slow_store_calls_are_reported() {
+        let config = Config {
+            slow_operation_threshold: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let broker = Broker::with_store(config, Box::new(SlowStore(Duration::from_millis(20))));
+        let declare = QueueDeclare {
+            queue: "jobs".into(),
+            durable: true,
+            ..Default::default()
+        };
+        broker.declare_queue(DEFAULT_VHOST, declare).unwrap();
+        let mut message = message("", "jobs", None);
+        message.properties.delivery_mode = Some(2);
+        broker.publish(DEFAULT_VHOST, message, false);
+
+        assert_eq!(broker.slow_operations(Operation::Store), 1);
+        // The publish waited for the store, so routing it was slow too.
+        assert_eq!(broker.slow_operations(Operation::Route), 1);
+        assert_eq!(broker.slow_operations(Operation::Deliver), 0);
+    }
+
+    #[test]
+    fn test_memory_storage_keeps_durable_resources_off_disk() {
+        let files = || {
+            let mut names: Vec<_> = std::fs::read_dir(".")
+                .unwrap()
+                .map(|e| e.unwrap().file_name())
+                .collect();
+            names.sort();
+            names
+        };
+        let before = files();
+        let broker = Broker::new(Config::from_toml("storage = \"memory\"").unwrap());
+        broker
+            .declare_exchange(
+                DEFAULT_VHOST,
+                ExchangeDeclare {
+                    exchange: "orders".into(),
+                    kind: "direct".into(),
+                    durable: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        broker
+            .declare_queue(
+                DEFAULT_VHOST,
+                QueueDeclare {
+                    queue: "jobs".into(),
+                    durable: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let binding = Binding {
+            queue: "jobs".into(),
+            routing_key: "new".into(),
+            arguments: FieldTable::new(),
+        };
+        broker.bind_queue(DEFAULT_VHOST, binding, "orders").unwrap();
+        let mut persistent = message("orders", "new", None);
+        persistent.properties.delivery_mode = Some(2);
+        assert_eq!(broker.publish(DEFAULT_VHOST, persistent, false).routed, 1);
+
+        // The message went to the in-memory store, and no file appeared.
+        let recovered = broker.store.lock().unwrap().as_mut().unwrap().recover().unwrap();
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(files(), before);
+        assert!(Config::from_toml("storage = \"memory\"\n[store]\ndata_dir = \"/tmp/x\"").is_err());
+    }
+
+    #[test]
+    fn test_persistent_messages_on_durable_queues_are_stored() {
+        use crate::store::{FileMessageStore, StoreConfig};
+
+        let dir = std::env::temp_dir().join(format!("haymq-broker-store-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = FileMessageStore::open(StoreConfig::new(&dir)).unwrap();
+        let broker = Broker::with_store(Config::default(), Box::new(store));
+        for (queue, durable) in [("durable", true), ("transient", false)] {
+            broker
+                .declare_queue(
+                    DEFAULT_VHOST,
+                    QueueDeclare {
+                        queue: queue.into(),
+                        durable,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+        }
+        broker
+            .declare_exchange(
+                DEFAULT_VHOST,
+                ExchangeDeclare {
+                    exchange: "all".into(),
+                    kind: "fanout".into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        for queue in ["durable", "transient"] {
+            broker
+                .bind_queue(
+                    DEFAULT_VHOST,
+                    Binding {
+                        queue: queue.into(),
+                        routing_key: "".into(),
+                        arguments: FieldTable::new(),
+                    },
+                    "all",
+                )
+                .unwrap();
+        }
+
+        let mut persistent = message("all", "", None);
+        persistent.properties.delivery_mode = Some(2);
+        assert_eq!(broker.publish(DEFAULT_VHOST, persistent, false).routed, 2);
+        assert_eq!(broker.publish(DEFAULT_VHOST, message("all", "", None), false).routed, 2);
+
+        let durable = broker.queue(DEFAULT_VHOST, "durable").unwrap();
+        let stored: Vec<Option<u64>> = durable.lock().unwrap().messages.iter().map(|m| m.store_id).collect();
+        assert!(matches!(stored[..], [Some(_), None]));
+        let transient = broker.queue(DEFAULT_VHOST, "transient").unwrap();
+        assert!(transient.lock().unwrap().messages.iter().all(|m| m.store_id.is_none()));
+        let recovered = broker.store.lock().unwrap().as_mut().unwrap().recover().unwrap();
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(recovered[0].queue, "durable");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compressed_bodies_are_delivered_intact_after_a_restart() {
+        use crate::store::{FileMessageStore, StoreConfig};
+
+        let dir = std::env::temp_dir().join(format!("haymq-broker-compressed-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store_config = StoreConfig {
+            compression_threshold: 1024,
+            ..StoreConfig::new(&dir)
+        };
+        let mut config = Config::default();
+        config.topology.queues.push(QueueDeclare {
+            queue: "jobs".into(),
+            durable: true,
+            ..Default::default()
+        });
+        let large: Vec<u8> = (0..64 * 1024).map(|n| b"compressible"[n % 12]).collect();
+        let bodies = [large.clone(), b"small".to_vec()];
+        let broker = Broker::with_store(
+            config.clone(),
+            Box::new(FileMessageStore::open(store_config.clone()).unwrap()),
+        );
+        broker.recover(&config.topology).unwrap();
+        for body in &bodies {
+            let mut persistent = message("", "jobs", None);
+            persistent.properties.delivery_mode = Some(2);
+            persistent.body = body.clone();
+            assert_eq!(broker.publish(DEFAULT_VHOST, persistent, false).routed, 1);
+        }
+        drop(broker);
+        let on_disk: u64 = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum();
+        assert!(on_disk < large.len() as u64 / 4, "{} bytes on disk", on_disk);
+
+        let broker = Broker::with_store(config.clone(), Box::new(FileMessageStore::open(store_config).unwrap()));
+        broker.recover(&config.topology).unwrap();
+        for body in &bodies {
+            let got = broker.get(DEFAULT_VHOST, "jobs", true).unwrap().unwrap();
+            assert!(got.message.body == *body, "body of {} bytes changed", body.len());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_durable_topology_survives_a_restart_without_messages() {
+        use crate::store::{FileMessageStore, StoreConfig};
+
+        let dir = std::env::temp_dir().join(format!("haymq-broker-topology-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = Config {
+            store: Some(StoreConfig::new(&dir)),
+            ..Default::default()
+        };
+        let start = || {
+            let store = FileMessageStore::open(config.store.clone().unwrap()).unwrap();
+            let broker = Arc::new(Broker::with_store(config.clone(), Box::new(store)));
+            broker.recover(&config.topology).unwrap();
+            broker
+        };
+        let broker = start();
+        spawn_topology_persistence(&broker);
+        let exchange = ExchangeDeclare {
+            exchange: "events".into(),
+            kind: "fanout".into(),
+            durable: true,
+            ..Default::default()
+        };
+        broker.declare_exchange(DEFAULT_VHOST, exchange).unwrap();
+        for (queue, durable) in [("dropped", false), ("kept", true)] {
+            let declare = QueueDeclare {
+                queue: queue.into(),
+                durable,
+                ..Default::default()
+            };
+            broker.declare_queue(DEFAULT_VHOST, declare).unwrap();
+            let binding = Binding {
+                queue: queue.into(),
+                routing_key: "".into(),
+                arguments: FieldTable::new(),
+            };
+            broker.bind_queue(DEFAULT_VHOST, binding, "events").unwrap();
+        }
+        // Transient messages leave nothing in the message store.
+        assert_eq!(
+            broker.publish(DEFAULT_VHOST, message("events", "", None), false).routed,
+            2
+        );
+        let saved = || std::fs::read_to_string(dir.join(TOPOLOGY_FILE)).unwrap_or_default();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !saved().contains(r#""destination":"kept""#) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        drop(broker);
+
+        let broker = start();
+        let names: Vec<String> = broker.queue_summaries().into_iter().map(|q| q.name).collect();
+        assert_eq!(names, ["kept"]);
+        assert_eq!(broker.queue_summaries()[0].messages, 0);
+        assert_eq!(
+            broker.publish(DEFAULT_VHOST, message("events", "", None), false).routed,
+            1
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_queues_tied_to_connections_or_consumers_are_not_recovered() {
+        use crate::store::{FileMessageStore, StoreConfig};
+
+        let dir = std::env::temp_dir().join(format!("haymq-broker-exclusive-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = Config {
+            store: Some(StoreConfig::new(&dir)),
+            ..Default::default()
+        };
+        let start = || {
+            let store = FileMessageStore::open(config.store.clone().unwrap()).unwrap();
+            let broker = Arc::new(Broker::with_store(config.clone(), Box::new(store)));
+            broker.recover(&config.topology).unwrap();
+            broker
+        };
+        let broker = start();
+        spawn_topology_persistence(&broker);
+        let queues = [
+            ("private", true, false),
+            ("temporary", false, true),
+            ("kept", false, false),
+        ];
+        for (queue, exclusive, auto_delete) in queues {
+            let declare = QueueDeclare {
+                queue: queue.into(),
+                durable: true,
+                exclusive,
+                auto_delete,
+                ..Default::default()
+            };
+            broker.declare_queue(DEFAULT_VHOST, declare).unwrap();
+            let (sender, _inbox) = tokio::sync::mpsc::unbounded_channel();
+            let consume = BasicConsume {
+                queue: queue.into(),
+                consumer_tag: format!("{}-consumer", queue),
+                no_ack: false,
+                exclusive: false,
+                channel: 1,
+                sender,
+                prefetch: Prefetch::new(1, 0),
+                priority: 0,
+            };
+            broker.consume(DEFAULT_VHOST, consume).unwrap();
+            let mut persistent = message("", queue, None);
+            persistent.properties.delivery_mode = Some(2);
+            broker.publish(DEFAULT_VHOST, persistent, false);
+        }
+        let saved = || std::fs::read_to_string(dir.join(TOPOLOGY_FILE)).unwrap_or_default();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !saved().contains(r#""name":"kept""#) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        drop(broker);
+
+        // Only the plain durable queue comes back, with its message but
+        // without its consumer; nothing else was stored.
+        let broker = start();
+        let summaries = broker.queue_summaries();
+        let recovered: Vec<(&str, usize, usize)> = summaries
+            .iter()
+            .map(|q| (q.name.as_str(), q.messages, q.consumers))
+            .collect();
+        assert_eq!(recovered, [("kept", 1, 0)]);
+        let stored = broker.store.lock().unwrap().as_mut().unwrap().recover().unwrap();
+        assert_eq!(stored.len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stored_messages_come_back_to_the_queue_of_their_vhost() {
+        use crate::store::{FileMessageStore, StoreConfig};
+
+        let dir = std::env::temp_dir().join(format!("haymq-broker-vhost-store-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = Config {
+            store: Some(StoreConfig::new(&dir)),
+            vhosts: vec!["staging".into()],
+            ..Default::default()
+        };
+        let start = || {
+            let store = FileMessageStore::open(config.store.clone().unwrap()).unwrap();
+            let broker = Broker::with_store(config.clone(), Box::new(store));
+            broker.recover(&config.topology).unwrap();
+            broker
+        };
+        let broker = start();
+        for vhost in [DEFAULT_VHOST, "staging"] {
+            let declare = QueueDeclare {
+                queue: "jobs".into(),
+                durable: true,
+                ..Default::default()
+            };
+            broker.declare_queue(vhost, declare).unwrap();
+        }
+        let mut persistent = message("", "jobs", None);
+        persistent.properties.delivery_mode = Some(2);
+        persistent.body = b"staged".to_vec();
+        assert_eq!(broker.publish("staging", persistent, false).routed, 1);
+        broker.save_topology().unwrap();
+        drop(broker);
+
+        let broker = start();
+        let counts: Vec<(String, usize)> = broker
+            .queue_summaries()
+            .into_iter()
+            .map(|q| (q.vhost, q.messages))
+            .collect();
+        assert_eq!(counts, [(DEFAULT_VHOST.to_string(), 0), ("staging".to_string(), 1)]);
+        let got = broker.get("staging", "jobs", true).unwrap().unwrap();
+        assert_eq!(got.message.body, b"staged");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_vhost_overrides_delivery_mode() {
+        use crate::store::{FileMessageStore, StoreConfig};
+        use crate::vhost::DeliveryModeOverride;
+
+        let dir = std::env::temp_dir().join(format!("haymq-broker-override-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = FileMessageStore::open(StoreConfig::new(&dir)).unwrap();
+        let mut config = Config {
+            vhosts: vec!["eternal".into(), "ephemeral".into()],
+            ..Default::default()
+        };
+        for (vhost, mode) in [
+            ("eternal", DeliveryModeOverride::Persistent),
+            ("ephemeral", DeliveryModeOverride::Transient),
+        ] {
+            let settings = VHostSettings {
+                delivery_mode: Some(mode),
+                ..Default::default()
+            };
+            config.vhost_settings.insert(vhost.into(), settings);
+        }
+        let broker = Broker::with_store(config, Box::new(store));
+
+        for (vhost, published, expected) in [("eternal", None, 2), ("ephemeral", Some(2), 1)] {
+            let declare = QueueDeclare {
+                queue: "jobs".into(),
+                durable: true,
+                ..Default::default()
+            };
+            broker.declare_queue(vhost, declare).unwrap();
+            let binding = Binding {
+                queue: "jobs".into(),
+                routing_key: "jobs".into(),
+                arguments: FieldTable::new(),
+            };
+            broker.bind_queue(vhost, binding, "amq.direct").unwrap();
+            let mut message = message("amq.direct", "jobs", None);
+            message.properties.delivery_mode = published;
+            assert_eq!(broker.publish(vhost, message, false).routed, 1);
+
+            let queue = broker.queue(vhost, "jobs").unwrap();
+            let queue = queue.lock().unwrap();
+            let queued = &queue.messages[0];
+            assert_eq!(queued.message.properties.delivery_mode, Some(expected));
+            assert_eq!(queued.store_id.is_some(), expected == 2, "in vhost {}", vhost);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    const TOPOLOGY: &str = r##"
+        [[topology.exchanges]]
+        name = "orders"
+        type = "topic"
+        durable = true
+
+        [[topology.queues]]
+        name = "orders.created"
+        durable = true
+
+        [[topology.queues]]
+        name = "audit"
+        arguments = { "x-queue-type" = "quorum" }
+
+        [[topology.bindings]]
+        exchange = "orders"
+        queue = "orders.created"
+        routing_key = "orders.created"
+
+        [[topology.bindings]]
+        exchange = "orders"
+        queue = "audit"
+        routing_key = "#"
+    "##;
+
+    #[test]
+    fn test_startup_topology_is_declared() {
+        let config = Config::from_toml(TOPOLOGY).unwrap();
+        let broker = Broker::new(config.clone());
+        broker.apply_topology(&config.topology);
+
+        assert_eq!(queue_type(&broker, "orders.created"), (QueueType::Classic, true));
+        assert_eq!(queue_type(&broker, "audit"), (QueueType::Quorum, true));
+        broker
+            .declare_exchange(
+                DEFAULT_VHOST,
+                ExchangeDeclare {
+                    exchange: "orders".into(),
+                    passive: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            broker
+                .publish(DEFAULT_VHOST, message("orders", "orders.created", None), false)
+                .routed,
+            2
+        );
+        assert_eq!(
+            broker
+                .publish(DEFAULT_VHOST, message("orders", "orders.deleted", None), false)
+                .routed,
+            1
+        );
+    }
+
+    #[test]
+    fn test_startup_topology_conflicts_are_skipped() {
+        let config = Config::from_toml(TOPOLOGY).unwrap();
+        let broker = Broker::new(config.clone());
+        // An existing transient definition wins over the configured durable one.
+        broker
+            .declare_queue(
+                DEFAULT_VHOST,
+                QueueDeclare {
+                    queue: "orders.created".into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        broker.apply_topology(&config.topology);
+
+        assert_eq!(queue_type(&broker, "orders.created"), (QueueType::Classic, false));
+        assert_eq!(
+            broker
+                .publish(DEFAULT_VHOST, message("orders", "orders.created", None), false)
+                .routed,
+            2
+        );
+    }
+
+    #[test]
+    fn test_recovery_restores_topology_then_stored_messages() {
+        let config = Config::from_toml(TOPOLOGY).unwrap();
+        let mut store = MemoryMessageStore::default();
+        let first = store
+            .append(DEFAULT_VHOST, "orders.created", &message("orders", "first", None))
+            .unwrap();
+        store
+            .append(DEFAULT_VHOST, "orders.created", &message("orders", "second", None))
+            .unwrap();
+        store.append(DEFAULT_VHOST, "gone", &message("", "gone", None)).unwrap();
+        let broker = Broker::with_store(config.clone(), Box::new(store));
+
+        broker.recover(&config.topology).unwrap();
+        assert!(!broker.recovering());
+        assert_eq!(broker.health().status, HealthStatus::Green);
+        let queue = broker.queue(DEFAULT_VHOST, "orders.created").unwrap();
+        let queue = queue.lock().unwrap();
+        let keys: Vec<&str> = queue.messages.iter().map(|m| m.message.routing_key.as_str()).collect();
+        assert_eq!(keys, ["first", "second"]);
+        assert_eq!(queue.messages[0].store_id, Some(first));
+        drop(queue);
+        // The message of a queue that is not configured stays stored.
+        assert_eq!(
+            broker.store.lock().unwrap().as_mut().unwrap().recover().unwrap().len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_recovery_stops_at_the_first_failed_phase() {
+        let mut config = Config::from_toml(TOPOLOGY).unwrap();
+        config.topology.bindings.push((
+            "orders".into(),
+            Binding {
+                queue: "missing".into(),
+                routing_key: "#".into(),
+                arguments: FieldTable::new(),
+            },
+        ));
+        let mut store = MemoryMessageStore::default();
+        store
+            .append(DEFAULT_VHOST, "orders.created", &message("orders", "first", None))
+            .unwrap();
+        let broker = Broker::with_store(config.clone(), Box::new(store));
+
+        let error = broker.recover(&config.topology).unwrap_err();
+        assert_eq!(error.phase, "bindings");
+        assert!(error.detail.contains("'missing'"), "{}", error.detail);
+        // Messages are not recovered, and the broker does not report ready.
+        let queue = broker.queue(DEFAULT_VHOST, "orders.created").unwrap();
+        assert!(queue.lock().unwrap().messages.is_empty());
+        assert!(broker.recovering());
+        let health = broker.health();
+        assert_eq!(health.status, HealthStatus::Red);
+        assert!(health.alarms.iter().any(|alarm| alarm.name == "recovery"));
+    }
+
+    #[test]
+    fn test_publish_to_builtin_fanout_without_declaring() {
+        let broker = Broker::new(Config::default());
+        broker
+            .declare_queue(
+                DEFAULT_VHOST,
+                QueueDeclare {
+                    queue: "logs".into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let binding = Binding {
+            queue: "logs".into(),
+            routing_key: "".into(),
+            arguments: FieldTable::new(),
+        };
+        broker.bind_queue(DEFAULT_VHOST, binding, "amq.fanout").unwrap();
+        assert_eq!(
+            broker
+                .publish(DEFAULT_VHOST, message("amq.fanout", "anything", None), false)
+                .routed,
+            1
+        );
+    }
+
+    #[test]
+    fn test_default_exchange_routes_by_queue_name() {
+        let broker = Broker::new(Config::default());
+        broker
+            .declare_queue(
+                DEFAULT_VHOST,
+                QueueDeclare {
+                    queue: "jobs".into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            broker.publish(DEFAULT_VHOST, message("", "jobs", None), false).routed,
+            1
+        );
+        assert_eq!(bodies(&broker, "jobs"), vec![b"payload".to_vec()]);
+        assert_eq!(
+            broker.publish(DEFAULT_VHOST, message("", "missing", None), true).routed,
+            0
+        );
+        assert_eq!(broker.publish(DEFAULT_VHOST, message("", "", None), false).routed, 0);
+
+        let binding = Binding {
+            queue: "jobs".into(),
+            routing_key: "other".into(),
+            arguments: FieldTable::new(),
+        };
+        let err = broker.bind_queue(DEFAULT_VHOST, binding.clone(), "").unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::ACCESS_REFUSED);
+        let err = broker.unbind_queue(DEFAULT_VHOST, &binding, "").unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::ACCESS_REFUSED);
+    }
+
+    #[test]
+    fn test_builtin_exchanges_keep_their_definition() {
+        let broker = Broker::new(Config::default());
+        let declare = |kind: &str, durable: bool| {
+            broker.declare_exchange(
+                DEFAULT_VHOST,
+                ExchangeDeclare {
+                    exchange: "amq.topic".into(),
+                    kind: kind.into(),
+                    durable,
+                    ..Default::default()
+                },
+            )
+        };
+        declare("topic", true).unwrap();
+        assert_eq!(declare("topic", false).unwrap_err().reply_code(), reply_codes::PRECONDITION_FAILED);
+        assert_eq!(declare("direct", true).unwrap_err().reply_code(), reply_codes::PRECONDITION_FAILED);
+
+        for name in ["", "amq.direct", "amq.fanout", "amq.topic", "amq.headers", "amq.match"] {
+            let err = broker.delete_exchange(DEFAULT_VHOST, name, false).unwrap_err();
+            assert_eq!(err.reply_code(), reply_codes::ACCESS_REFUSED, "deleting '{}'", name);
+        }
+    }
+
+    #[test]
+    fn test_vhosts_are_separate_namespaces() {
+        let broker = Broker::new(Config {
+            vhosts: vec!["staging".into()],
+            ..Default::default()
+        });
+        declare(&broker, "orders", "events", "fanout");
+        let binding = Binding {
+            queue: "orders".into(),
+            routing_key: "".into(),
+            arguments: FieldTable::new(),
+        };
+        broker.bind_queue(DEFAULT_VHOST, binding, "events").unwrap();
+
+        let err = broker
+            .declare_exchange(
+                "staging",
+                ExchangeDeclare {
+                    exchange: "events".into(),
+                    passive: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::NOT_FOUND);
+        assert!(broker.queue("staging", "orders").is_none());
+        assert_eq!(broker.publish("staging", message("events", "", None), false).routed, 0);
+        assert_eq!(
+            broker.publish("staging", message("amq.fanout", "", None), false).routed,
+            0
+        );
+        assert_eq!(
+            broker.publish(DEFAULT_VHOST, message("events", "", None), false).routed,
+            1
+        );
+
+        assert!(!broker.add_vhost("staging"));
+        assert!(broker.add_vhost("qa"));
+        assert!(broker.has_vhost("qa"));
+    }
+
+    fn expiring_queue(broker: &Broker, name: &str, millis: i32) {
+        let mut arguments = FieldTable::new();
+        arguments.insert("x-expires", FieldValue::LongInt(millis));
+        let declare = QueueDeclare {
+            queue: name.into(),
+            arguments,
+            ..Default::default()
+        };
+        broker.declare_queue(DEFAULT_VHOST, declare).unwrap();
+    }
+
+    #[test]
+    fn test_unused_queue_expires() {
+        let clock = Arc::new(ManualClock::new());
+        let broker = Broker::with_clock(Config::default(), clock.clone());
+        expiring_queue(&broker, "temp", 30);
+        declare(&broker, "kept", "events", "fanout");
+        for queue in ["temp", "kept"] {
+            let binding = Binding {
+                queue: queue.into(),
+                routing_key: "".into(),
+                arguments: FieldTable::new(),
+            };
+            broker.bind_queue(DEFAULT_VHOST, binding, "events").unwrap();
+        }
+        broker.publish(DEFAULT_VHOST, message("events", "", None), false);
+
+        assert!(broker.expire_queues().is_empty());
+        clock.advance(Duration::from_millis(29));
+        assert!(broker.expire_queues().is_empty());
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(
+            broker.expire_queues(),
+            vec![(DEFAULT_VHOST.to_string(), "temp".to_string())]
+        );
+        assert!(broker.queue(DEFAULT_VHOST, "temp").is_none());
+        assert_eq!(
+            broker.publish(DEFAULT_VHOST, message("events", "", None), false).routed,
+            1
+        );
+    }
+
+    #[test]
+    fn test_queue_activity_postpones_expiry() {
+        let clock = Arc::new(ManualClock::new());
+        let broker = Broker::with_clock(Config::default(), clock.clone());
+        expiring_queue(&broker, "temp", 100);
+        let (sender, _inbox) = tokio::sync::mpsc::unbounded_channel();
+        let consume = BasicConsume {
+            queue: "temp".into(),
+            consumer_tag: "".into(),
+            no_ack: true,
+            exclusive: false,
+            channel: 1,
+            sender,
+            prefetch: None,
+            priority: 0,
+        };
+        let (id, _) = broker.consume(DEFAULT_VHOST, consume).unwrap();
+        clock.advance(Duration::from_millis(150));
+        // A consumer keeps the queue alive however long it stays.
+        assert!(broker.expire_queues().is_empty());
+        broker.cancel(DEFAULT_VHOST, "temp", id);
+        clock.advance(Duration::from_millis(60));
+        broker.get(DEFAULT_VHOST, "temp", true).unwrap();
+        clock.advance(Duration::from_millis(99));
+        assert!(broker.expire_queues().is_empty());
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(broker.expire_queues().len(), 1);
+    }
+
+    /// Declares `queue` with `arguments`, fed by fanout exchange `exchange`.
+    #[test]
+    fn test_consumer_with_full_window_is_flagged_as_slow() {
+        let broker = Broker::new(Config {
+            slow_consumer_threshold: Duration::from_millis(50),
+            ..Default::default()
+        });
+        declare(&broker, "jobs", "work", "fanout");
+        let binding = Binding {
+            queue: "jobs".into(),
+            routing_key: "".into(),
+            arguments: FieldTable::new(),
+        };
+        broker.bind_queue(DEFAULT_VHOST, binding, "work").unwrap();
+        let (sender, mut inbox) = tokio::sync::mpsc::unbounded_channel();
+        let consume = BasicConsume {
+            queue: "jobs".into(),
+            consumer_tag: "never-acks".into(),
+            no_ack: false,
+            exclusive: false,
+            channel: 1,
+            sender,
+            prefetch: Prefetch::new(1, 0),
+            priority: 0,
+        };
+        broker.consume(DEFAULT_VHOST, consume).unwrap();
+
+        // Holding its one delivery is fine while nothing else is waiting.
+        broker.publish(DEFAULT_VHOST, message("work", "", None), false);
+        let delivery = inbox.try_recv().unwrap();
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(broker.slow_consumers().is_empty());
+
+        broker.publish(DEFAULT_VHOST, message("work", "", None), false);
+        assert!(broker.slow_consumers().is_empty());
+        std::thread::sleep(Duration::from_millis(60));
+        let slow = broker.slow_consumers();
+        assert_eq!(slow.len(), 1);
+        assert_eq!(
+            (slow[0].queue.as_str(), slow[0].consumer_tag.as_str()),
+            ("jobs", "never-acks")
+        );
+        assert!(crate::metrics::render(&broker).contains("haymq_slow_consumers 1\n"));
+
+        // Acking lets the waiting message through and resets the clock.
+        delivery.prefetch.unwrap().release(delivery.footprint);
+        broker.ack(DEFAULT_VHOST, "jobs", delivery.message_id);
+        assert!(inbox.try_recv().is_ok());
+        assert!(broker.slow_consumers().is_empty());
+    }
+
+    fn fanout_to(broker: &Broker, exchange: &str, queue: &str, arguments: &[(&str, FieldValue)]) {
+        let mut table = FieldTable::new();
+        for (key, value) in arguments {
+            table.insert(*key, value.clone());
+        }
+        let declare = QueueDeclare {
+            queue: queue.into(),
+            arguments: table,
+            ..Default::default()
+        };
+        broker.declare_queue(DEFAULT_VHOST, declare).unwrap();
+        let declare = ExchangeDeclare {
+            exchange: exchange.into(),
+            kind: "fanout".into(),
+            ..Default::default()
+        };
+        broker.declare_exchange(DEFAULT_VHOST, declare).unwrap();
+        let binding = Binding {
+            queue: queue.into(),
+            routing_key: "".into(),
+            arguments: FieldTable::new(),
+        };
+        broker.bind_queue(DEFAULT_VHOST, binding, exchange).unwrap();
+    }
+
+    #[test]
+    fn test_unacked_limit_caps_deliveries_across_consumers() {
+        let broker = Broker::new(Config {
+            max_unacked_messages: 12,
+            ..Default::default()
+        });
+        fanout_to(&broker, "work", "jobs", &[]);
+        fanout_to(&broker, "work", "audit", &[]);
+        // Six consumers with room for 30 deliveries between them.
+        let (sender, mut inbox) = tokio::sync::mpsc::unbounded_channel();
+        for (n, queue) in ["jobs", "audit"].iter().cycle().take(6).enumerate() {
+            let consume = BasicConsume {
+                queue: queue.to_string(),
+                consumer_tag: format!("consumer-{}", n),
+                no_ack: false,
+                exclusive: false,
+                channel: n as u16 + 1,
+                sender: sender.clone(),
+                prefetch: Prefetch::new(5, 0),
+                priority: 0,
+            };
+            broker.consume(DEFAULT_VHOST, consume).unwrap();
+        }
+        for _ in 0..20 {
+            broker.publish(DEFAULT_VHOST, message("work", "", None), false);
+        }
+        let mut delivered = Vec::new();
+        while let Ok(delivery) = inbox.try_recv() {
+            delivered.push(delivery);
+        }
+        assert_eq!(delivered.len(), 12);
+        assert_eq!(broker.unacked_messages(), 12);
+
+        // Every ack makes room for one more delivery.
+        let acked: Vec<_> = delivered.iter().filter(|d| d.queue == "jobs").take(4).collect();
+        for delivery in &acked {
+            delivery.prefetch.as_ref().unwrap().release(delivery.footprint);
+            assert!(broker.ack(DEFAULT_VHOST, "jobs", delivery.message_id));
+        }
+        let mut resumed = 0;
+        while inbox.try_recv().is_ok() {
+            resumed += 1;
+        }
+        assert_eq!(resumed, 4);
+        assert_eq!(broker.unacked_messages(), 12);
+        assert!(crate::metrics::render(&broker).contains("haymq_unacked_messages 12\n"));
+    }
+
+    #[test]
+    fn test_empty_binding_keys_per_exchange_type() {
+        let broker = Broker::new(Config::default());
+        let bind = |queue: &str, key: &str, exchange: &str| {
+            let binding = Binding {
+                queue: queue.into(),
+                routing_key: key.into(),
+                arguments: FieldTable::new(),
+            };
+            broker.bind_queue(DEFAULT_VHOST, binding, exchange)
+        };
+        for queue in ["empty", "keyed"] {
+            let declare = QueueDeclare {
+                queue: queue.into(),
+                ..Default::default()
+            };
+            broker.declare_queue(DEFAULT_VHOST, declare).unwrap();
+        }
+        for kind in ["direct", "topic", "fanout"] {
+            let declare = ExchangeDeclare {
+                exchange: kind.into(),
+                kind: kind.into(),
+                ..Default::default()
+            };
+            broker.declare_exchange(DEFAULT_VHOST, declare).unwrap();
+            bind("empty", "", kind).unwrap();
+            bind("keyed", "a", kind).unwrap();
+        }
+        let routed = |exchange: &str, key: &str| broker.route_queues(DEFAULT_VHOST, exchange, key, None).unwrap();
+
+        // Direct and topic: an empty key matches only an empty routing key.
+        for exchange in ["direct", "topic"] {
+            assert_eq!(routed(exchange, ""), vec!["empty"], "{}", exchange);
+            assert_eq!(routed(exchange, "a"), vec!["keyed"], "{}", exchange);
+            assert!(routed(exchange, "b").is_empty(), "{}", exchange);
+        }
+        assert!(routed("topic", "a.b").is_empty());
+
+        // Fanout: the key is ignored, when routing and as part of the binding.
+        for key in ["", "a", "b"] {
+            assert_eq!(routed("fanout", key).len(), 2);
+        }
+        let bindings = broker.vhost_summaries()[0].bindings;
+        bind("empty", "other", "fanout").unwrap();
+        assert_eq!(broker.vhost_summaries()[0].bindings, bindings);
+        let binding = Binding {
+            queue: "keyed".into(),
+            routing_key: "whatever".into(),
+            arguments: FieldTable::new(),
+        };
+        broker.unbind_queue(DEFAULT_VHOST, &binding, "fanout").unwrap();
+        assert_eq!(routed("fanout", "a"), vec!["empty"]);
+    }
+
+    fn text(s: &str) -> FieldValue {
+        FieldValue::LongString(s.as_bytes().to_vec())
+    }
+
+    fn bodies(broker: &Broker, queue: &str) -> Vec<Vec<u8>> {
+        let queue = broker.queue(DEFAULT_VHOST, queue).unwrap();
+        let queue = queue.lock().unwrap();
+        queue.messages.iter().map(|m| m.message.body.clone()).collect()
+    }
+
+    fn publish_body(broker: &Broker, exchange: &str, body: &[u8]) -> PublishOutcome {
+        let message = Message {
+            body: body.to_vec(),
+            ..message(exchange, "jobs", None)
+        };
+        broker.publish(DEFAULT_VHOST, message, false)
+    }
+
+    #[test]
+    fn test_drop_head_dead_letters_the_oldest_message() {
+        let broker = Broker::new(Config::default());
+        fanout_to(&broker, "dlx", "dead", &[]);
+        let arguments = [
+            ("x-max-length", FieldValue::LongInt(2)),
+            ("x-dead-letter-exchange", text("dlx")),
+        ];
+        fanout_to(&broker, "work", "jobs", &arguments);
+
+        for body in [b"a", b"b", b"c"] {
+            let outcome = publish_body(&broker, "work", body);
+            assert_eq!((outcome.routed, outcome.rejected), (1, false));
+        }
+        assert_eq!(bodies(&broker, "jobs"), vec![b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(bodies(&broker, "dead"), vec![b"a".to_vec()]);
+
+        let dead = broker.queue(DEFAULT_VHOST, "dead").unwrap();
+        let dead = dead.lock().unwrap();
+        let message = &dead.messages[0].message;
+        assert_eq!(
+            (message.exchange.as_str(), message.routing_key.as_str()),
+            ("dlx", "jobs")
+        );
+        let headers = message.properties.headers.as_ref().unwrap();
+        assert_eq!(headers.get("x-first-death-reason"), Some(&text("maxlen")));
+        assert_eq!(headers.get("x-first-death-queue"), Some(&text("jobs")));
+        let Some(FieldValue::FieldArray(deaths)) = headers.get("x-death") else {
+            panic!("no x-death in {:?}", headers);
+        };
+        assert_eq!(deaths.len(), 1);
+    }
+
+    #[test]
+    fn test_reject_publish_refuses_without_dead_lettering() {
+        let broker = Broker::new(Config::default());
+        fanout_to(&broker, "dlx", "dead", &[]);
+        let arguments = [
+            ("x-max-length", FieldValue::LongInt(1)),
+            ("x-overflow", text("reject-publish")),
+            ("x-dead-letter-exchange", text("dlx")),
+        ];
+        fanout_to(&broker, "work", "jobs", &arguments);
+
+        assert!(!publish_body(&broker, "work", b"a").rejected);
+        assert!(publish_body(&broker, "work", b"b").rejected);
+        assert_eq!(bodies(&broker, "jobs"), vec![b"a".to_vec()]);
+        assert!(bodies(&broker, "dead").is_empty());
+    }
+
+    #[test]
+    fn test_publish_rate_nack_policy_refuses_excess() {
+        let broker = Broker::new(Config {
+            publish_rate_policy: RatePolicy::Nack,
+            ..Default::default()
+        });
+        fanout_to(
+            &broker,
+            "work",
+            "jobs",
+            &[("x-max-publish-rate", FieldValue::LongInt(5))],
+        );
+        let rejected = (0..8).filter(|_| publish_body(&broker, "work", b"a").rejected).count();
+        assert_eq!(rejected, 3);
+        assert_eq!(bodies(&broker, "jobs").len(), 5);
+    }
+
+    fn quota_broker(settings: VHostSettings) -> Broker {
+        let mut config = Config::default();
+        config.vhost_settings.insert(DEFAULT_VHOST.into(), settings);
+        Broker::new(config)
+    }
+
+    #[test]
+    fn test_vhost_quota_refuses_publishes_once_reached() {
+        let broker = quota_broker(VHostSettings {
+            max_messages: Some(3),
+            quota_policy: RatePolicy::Nack,
+            ..Default::default()
+        });
+        fanout_to(&broker, "work", "jobs", &[]);
+        fanout_to(&broker, "audit", "log", &[]);
+
+        assert!(!publish_body(&broker, "work", b"a").rejected);
+        assert!(!publish_body(&broker, "audit", b"b").rejected);
+        // Unacked messages count as well as ready ones.
+        let (message_id, _) = broker
+            .queue(DEFAULT_VHOST, "jobs")
+            .unwrap()
+            .lock()
+            .unwrap()
+            .get(false)
+            .unwrap();
+        assert!(!publish_body(&broker, "work", b"c").rejected);
+        assert!(publish_body(&broker, "work", b"d").rejected);
+        assert_eq!(bodies(&broker, "jobs"), vec![b"c".to_vec()]);
+
+        assert!(broker.ack(DEFAULT_VHOST, "jobs", message_id));
+        assert!(!publish_body(&broker, "work", b"e").rejected);
+
+        // Deleting a queue gives back what it held.
+        broker.delete_queue(DEFAULT_VHOST, "jobs", false, false).unwrap();
+        assert!(!publish_body(&broker, "audit", b"f").rejected);
+    }
+
+    #[test]
+    fn test_vhost_resource_limits_refuse_declares_past_them() {
+        let broker = quota_broker(VHostSettings {
+            max_queues: Some(2),
+            max_exchanges: Some(1),
+            max_bindings: Some(1),
+            ..Default::default()
+        });
+        // The built-in exchanges leave room for one declared exchange.
+        fanout_to(&broker, "work", "jobs", &[]);
+        let declare = |queue: &str| {
+            broker.declare_queue(
+                DEFAULT_VHOST,
+                QueueDeclare {
+                    queue: queue.into(),
+                    ..Default::default()
+                },
+            )
+        };
+        declare("log").unwrap();
+        // Declaring an existing queue again is not a new queue.
+        declare("jobs").unwrap();
+        let err = declare("extra").unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::ACCESS_REFUSED);
+        assert!(broker.queue(DEFAULT_VHOST, "extra").is_none());
+
+        let err = broker
+            .declare_exchange(
+                DEFAULT_VHOST,
+                ExchangeDeclare {
+                    exchange: "audit".into(),
+                    kind: "fanout".into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::ACCESS_REFUSED);
+
+        let binding = |queue: &str| Binding {
+            queue: queue.into(),
+            routing_key: String::new(),
+            arguments: FieldTable::new(),
+        };
+        // `fanout_to` made the one binding allowed; repeating it is no new one.
+        broker.bind_queue(DEFAULT_VHOST, binding("jobs"), "work").unwrap();
+        let err = broker.bind_queue(DEFAULT_VHOST, binding("log"), "work").unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::ACCESS_REFUSED);
+
+        let summary = &broker.vhost_summaries()[0];
+        assert_eq!((summary.queues, summary.exchanges, summary.bindings), (2, 1, 1));
+    }
+
+    #[test]
+    fn test_vhost_byte_quota_counts_message_footprints() {
+        let footprint = |body: &[u8]| {
+            let message = Message {
+                body: body.to_vec(),
+                ..message("work", "jobs", None)
+            };
+            message.memory_footprint()
+        };
+        let broker = quota_broker(VHostSettings {
+            max_bytes: Some(footprint(b"a") + footprint(b"b")),
+            quota_policy: RatePolicy::Nack,
+            ..Default::default()
+        });
+        fanout_to(&broker, "work", "jobs", &[]);
+        assert!(!publish_body(&broker, "work", b"a").rejected);
+        assert!(publish_body(&broker, "work", b"too large").rejected);
+        assert!(!publish_body(&broker, "work", b"b").rejected);
+        assert!(publish_body(&broker, "work", b"c").rejected);
+
+        broker.purge_queues(DEFAULT_VHOST, |_| true).unwrap();
+        assert!(!publish_body(&broker, "work", b"c").rejected);
+    }
+
+    #[test]
+    fn test_vhost_quota_flow_policy_throttles_publishers() {
+        let broker = quota_broker(VHostSettings {
+            max_messages: Some(1),
+            ..Default::default()
+        });
+        fanout_to(&broker, "work", "jobs", &[]);
+        assert_eq!(publish_body(&broker, "work", b"a").throttle, None);
+        let outcome = publish_body(&broker, "work", b"b");
+        assert!(!outcome.rejected);
+        assert_eq!(outcome.throttle, Some(QUOTA_RETRY_INTERVAL));
+        assert_eq!(bodies(&broker, "jobs").len(), 2);
+    }
+
+    #[test]
+    fn test_dead_letter_cycle_is_dropped() {
+        let broker = Broker::new(Config::default());
+        // Dead letters go back to the queue they died in, which never has room.
+        let arguments = [
+            ("x-max-length", FieldValue::LongInt(0)),
+            ("x-dead-letter-exchange", text("work")),
+        ];
+        fanout_to(&broker, "work", "jobs", &arguments);
+        assert_eq!(publish_body(&broker, "work", b"a").routed, 1);
+        assert!(bodies(&broker, "jobs").is_empty());
+    }
+
+    #[test]
+    fn test_dead_letter_routing_key_override() {
+        let broker = Broker::new(Config::default());
+        fanout_to(&broker, "dlx", "dead", &[]);
+        let full = ("x-max-length", FieldValue::LongInt(0));
+        fanout_to(
+            &broker,
+            "keep",
+            "kept",
+            &[full.clone(), ("x-dead-letter-exchange", text("dlx"))],
+        );
+        let arguments = [
+            full,
+            ("x-dead-letter-exchange", text("dlx")),
+            ("x-dead-letter-routing-key", text("late")),
+        ];
+        fanout_to(&broker, "rename", "renamed", &arguments);
+
+        publish_body(&broker, "keep", b"a");
+        publish_body(&broker, "rename", b"b");
+        let dead = broker.queue(DEFAULT_VHOST, "dead").unwrap();
+        let keys: Vec<String> = dead
+            .lock()
+            .unwrap()
+            .messages
+            .iter()
+            .map(|m| m.message.routing_key.clone())
+            .collect();
+        assert_eq!(keys, vec!["jobs", "late"]);
+    }
+
+    #[test]
+    fn test_dead_letter_loop_ends_at_the_depth_limit() {
+        let broker = Broker::new(Config {
+            dead_letter_max_depth: 5,
+            ..Default::default()
+        });
+        // `ping` and `pong` never have room and dead-letter to each other;
+        // `watch` sees each copy on its way from `ping` to `pong`.
+        let full = ("x-max-length", FieldValue::LongInt(0));
+        fanout_to(
+            &broker,
+            "to-ping",
+            "ping",
+            &[full.clone(), ("x-dead-letter-exchange", text("to-pong"))],
+        );
+        fanout_to(
+            &broker,
+            "to-pong",
+            "pong",
+            &[full, ("x-dead-letter-exchange", text("to-ping"))],
+        );
+        fanout_to(&broker, "to-pong", "watch", &[]);
+
+        publish_body(&broker, "to-ping", b"a");
+        let watch = broker.queue(DEFAULT_VHOST, "watch").unwrap();
+        let watch = watch.lock().unwrap();
+        assert_eq!(watch.messages.len(), 3);
+        let headers = watch.messages[2].message.properties.headers.as_ref().unwrap();
+        let Some(FieldValue::FieldArray(deaths)) = headers.get("x-death") else {
+            panic!("no x-death in {:?}", headers);
+        };
+        let counts: Vec<(Option<&str>, Option<i64>)> = deaths
+            .iter()
+            .map(|death| {
+                let FieldValue::FieldTable(death) = death else {
+                    panic!("x-death entry {:?}", death)
+                };
+                (
+                    death.get("queue").and_then(FieldValue::as_str),
+                    death.get("count").and_then(FieldValue::as_i64),
+                )
+            })
+            .collect();
+        assert_eq!(counts, vec![(Some("ping"), Some(3)), (Some("pong"), Some(2))]);
+    }
+
+    /// Names queues `gen-1`, `gen-2` and so on.
+    struct Sequence(u32);
+
+    impl QueueNameGenerator for Sequence {
+        fn generate(&mut self) -> String {
+            self.0 += 1;
+            format!("gen-{}", self.0)
+        }
+    }
+
+    #[test]
+    fn test_generated_queue_names_skip_taken_ones() {
+        let broker = Broker::with_queue_names(Config::default(), Box::new(Sequence(0)));
+        let declare = |queue: &str, passive: bool| QueueDeclare {
+            queue: queue.into(),
+            passive,
+            ..Default::default()
+        };
+        broker.declare_queue(DEFAULT_VHOST, declare("gen-1", false)).unwrap();
+
+        let ok = broker.declare_queue(DEFAULT_VHOST, declare("", false)).unwrap();
+        assert_eq!(ok.queue, "gen-2");
+        let ok = broker.declare_queue(DEFAULT_VHOST, declare("", false)).unwrap();
+        assert_eq!(ok.queue, "gen-3");
+        let ok = broker.declare_queue(DEFAULT_VHOST, declare("gen-2", true)).unwrap();
+        assert_eq!((ok.queue.as_str(), ok.message_count), ("gen-2", 0));
+    }
+
+    #[test]
+    fn test_invalid_overflow_arguments_are_rejected() {
+        let broker = Broker::new(Config::default());
+        for (key, value) in [
+            ("x-overflow", text("drop-tail")),
+            ("x-max-length", FieldValue::LongInt(-1)),
+            ("x-delivery-limit", text("3")),
+        ] {
+            let mut arguments = FieldTable::new();
+            arguments.insert(key, value);
+            let declare = QueueDeclare {
+                queue: "jobs".into(),
+                arguments,
+                ..Default::default()
+            };
+            let err = broker.declare_queue(DEFAULT_VHOST, declare).unwrap_err();
+            assert_eq!(err.reply_code(), reply_codes::PRECONDITION_FAILED);
+        }
+    }
+    #[test]
+    fn test_locator_hints_are_validated_and_reported() {
+        let broker = Broker::new(Config::default());
+        let declare = |queue: &str, key: &str, locator: &str| {
+            let mut arguments = FieldTable::new();
+            arguments.insert(key, text(locator));
+            let declare = QueueDeclare {
+                queue: queue.into(),
+                arguments,
+                ..Default::default()
+            };
+            broker.declare_queue(DEFAULT_VHOST, declare)
+        };
+        declare("jobs", "x-queue-master-locator", "min-masters").unwrap();
+        declare("events", "x-queue-leader-locator", "balanced").unwrap();
+        let err = declare("other", "x-queue-master-locator", "nearest").unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::PRECONDITION_FAILED);
+        assert!(declare("other", "x-queue-leader-locator", "min-masters").is_err());
+
+        let summaries = broker.queue_summaries();
+        let locators: Vec<_> = summaries
+            .iter()
+            .map(|q| {
+                (
+                    q.name.as_str(),
+                    q.master_locator.as_deref(),
+                    q.leader_locator.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            locators,
+            vec![("events", None, Some("balanced")), ("jobs", Some("min-masters"), None)]
+        );
+    }
+
+    #[test]
+    fn test_topic_keys_over_the_limits_are_refused() {
+        let broker = Broker::new(Config {
+            topic_max_words: 8,
+            topic_max_length: 64,
+            ..Default::default()
+        });
+        broker
+            .declare_queue(
+                DEFAULT_VHOST,
+                QueueDeclare {
+                    queue: "q".into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let bind = |key: &str| {
+            let binding = Binding {
+                queue: "q".into(),
+                routing_key: key.into(),
+                arguments: FieldTable::new(),
+            };
+            broker.bind_queue(DEFAULT_VHOST, binding, "amq.topic")
+        };
+        // A pattern that would make naive matching backtrack for a long time.
+        let pathological = ["#.a"; 60].join(".");
+        let err = bind(&pathological).unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::PRECONDITION_FAILED);
+        assert!(bind(&"x".repeat(65)).is_err());
+        bind("a.*.#").unwrap();
+
+        let err = broker
+            .check_publish(DEFAULT_VHOST, "amq.topic", &["a"; 9].join("."))
+            .unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::PRECONDITION_FAILED);
+        broker
+            .check_publish(DEFAULT_VHOST, "amq.topic", &["a"; 8].join("."))
+            .unwrap();
+        // Other exchange types are not limited.
+        broker
+            .check_publish(DEFAULT_VHOST, "amq.direct", &["a"; 9].join("."))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_modulus_exchange_spreads_keys_over_its_shards() {
+        let broker = Broker::new(Config::default());
+        let declare = |shards: Option<i64>| {
+            let mut arguments = FieldTable::new();
+            if let Some(shards) = shards {
+                arguments.insert("x-shards", FieldValue::LongLongInt(shards));
+            }
+            let declare = ExchangeDeclare {
+                exchange: "events".into(),
+                kind: "x-modulus-hash".into(),
+                arguments,
+                ..Default::default()
+            };
+            broker.declare_exchange(DEFAULT_VHOST, declare)
+        };
+        for invalid in [None, Some(0), Some(-1)] {
+            let err = declare(invalid).unwrap_err();
+            assert_eq!(err.reply_code(), reply_codes::PRECONDITION_FAILED);
+        }
+        declare(Some(4)).unwrap();
+
+        for i in 0..10_000 {
+            assert_eq!(
+                broker
+                    .publish(DEFAULT_VHOST, message("events", &format!("user-{}", i), None), false)
+                    .routed,
+                1
+            );
+        }
+        let summaries = broker.queue_summaries();
+        let shards: Vec<&str> = summaries.iter().map(|q| q.name.as_str()).collect();
+        assert_eq!(
+            shards,
+            ["events.shard.0", "events.shard.1", "events.shard.2", "events.shard.3"]
+        );
+        for queue in &summaries {
+            assert!(
+                (2_250..=2_750).contains(&queue.messages),
+                "{} got {}",
+                queue.name,
+                queue.messages
+            );
+        }
+        // The same key always goes to the same shard.
+        broker.publish(DEFAULT_VHOST, message("events", "user-0", None), false);
+        broker.publish(DEFAULT_VHOST, message("events", "user-0", None), false);
+        let grown: usize = broker
+            .queue_summaries()
+            .iter()
+            .zip(&summaries)
+            .filter(|(a, b)| a.messages != b.messages)
+            .count();
+        assert_eq!(grown, 1);
+    }
+
+    #[test]
+    fn test_modulus_exchange_is_not_left_behind_when_a_shard_conflicts() {
+        let broker = Broker::new(Config::default());
+        let conflicting = QueueDeclare {
+            queue: "events.shard.1".into(),
+            durable: true,
+            ..Default::default()
+        };
+        broker.declare_queue(DEFAULT_VHOST, conflicting).unwrap();
+        let mut arguments = FieldTable::new();
+        arguments.insert("x-shards", FieldValue::LongLongInt(3));
+        let declare = ExchangeDeclare {
+            exchange: "events".into(),
+            kind: "x-modulus-hash".into(),
+            arguments,
+            ..Default::default()
+        };
+        let err = broker.declare_exchange(DEFAULT_VHOST, declare).unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::PRECONDITION_FAILED);
+
+        assert!(!broker.exchange_summaries().iter().any(|e| e.name == "events"));
+        // The shard declared before the conflict is gone; the queue it
+        // conflicted with is untouched.
+        let queues: Vec<String> = broker.queue_summaries().into_iter().map(|q| q.name).collect();
+        assert_eq!(queues, ["events.shard.1"]);
+    }
+}
@@ -0,0 +1,1851 @@
+// src/channel.rs
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::debug;
+use tokio::time::Instant;
+
+use crate::broker::{BasicConsume, Broker, ExchangeDeclare, PublishOutcome, QueueDeclare};
+use crate::error::AmqpError;
+use crate::exchange::Binding;
+use crate::field_table::FieldTable;
+use crate::intercept::InterceptAction;
+use crate::message::Message;
+use crate::methods::{Method, CLASS_BASIC, CLASS_CONFIRM, CLASS_TX};
+use crate::properties::{ContentHeader, ServerTimestamp};
+use crate::queue::{Delivery, DeliverySender, Prefetch};
+use crate::protocol::AmqpFrame;
+use crate::reply_codes;
+use crate::rewrite;
+
+/// Publishing mode of a channel.
+///
+/// AMQP forbids mixing transactions and publisher confirms on one channel,
+/// so once a channel leaves `Normal` it can never switch to the other mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChannelMode {
+    #[default]
+    Normal,
+    Transactional,
+    Confirm,
+}
+
+/// A `Basic.Publish` waiting for its content header and body frames.
+#[derive(Debug)]
+struct PendingPublish {
+    exchange: String,
+    routing_key: String,
+    mandatory: bool,
+    header: Option<ContentHeader>,
+    /// Body frame payloads as they arrived, joined once the body is
+    /// complete, and their total length.
+    body: Vec<Vec<u8>>,
+    received: u64,
+    /// When the content has to be complete; see `Config::publish_timeout`.
+    deadline: Option<Instant>,
+}
+
+impl PendingPublish {
+    /// The body, without copying it when it came in a single frame.
+    fn take_body(&mut self) -> Vec<u8> {
+        match self.body.len() {
+            1 => self.body.pop().unwrap(),
+            _ => std::mem::take(&mut self.body).concat(),
+        }
+    }
+}
+
+/// A complete publish held back while the broker's interceptors look at it.
+#[derive(Debug)]
+pub struct InterceptedPublish {
+    /// Publish sequence number, the delivery-tag of its confirm.
+    seq: u64,
+    mandatory: bool,
+    pub message: Message,
+    /// The message as the client published it, kept for the `Basic.Return`
+    /// of a mandatory publish when the server timestamp or an interceptor
+    /// may change it before it is routed.
+    original: Option<Message>,
+}
+
+/// A broker-initiated `Channel.Close` waiting for the client's `Close-Ok`.
+#[derive(Debug, Clone, Copy)]
+pub struct Closing {
+    /// When the channel is dropped even without a `Close-Ok`.
+    pub deadline: Instant,
+    pub reply_code: u16,
+}
+
+/// Where a channel is, as far as the frames it takes go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelState {
+    /// Takes methods; content frames are unexpected.
+    Open,
+    /// A `Basic.Publish` arrived and only its content header may follow.
+    AwaitingHeader,
+    /// The content header arrived and only body frames may follow, until
+    /// they add up to the size it announced.
+    AwaitingBody,
+    /// The broker sent `Channel.Close`; only `Close` and `Close-Ok` count,
+    /// everything else is discarded.
+    Closing,
+}
+
+/// A delivery awaiting a `Basic.Ack`.
+#[derive(Debug)]
+struct Outstanding {
+    queue: String,
+    /// Tag of the consumer the message was delivered to; `None` for
+    /// `Basic.Get`.
+    consumer_tag: Option<String>,
+    /// Queue-local id of the message.
+    message_id: u64,
+    /// Prefetch window the delivery was charged to, and its body size.
+    prefetch: Option<(Arc<Prefetch>, u64)>,
+}
+
+impl Outstanding {
+    /// Frees the delivery's room in its prefetch window, returning its
+    /// queue and queue-local id.
+    fn release(self) -> (String, u64) {
+        if let Some((prefetch, bytes)) = self.prefetch {
+            prefetch.release(bytes);
+        }
+        (self.queue, self.message_id)
+    }
+}
+
+/// Most deliveries the `x-delivery-batch` consume argument lets the
+/// connection coalesce into one write.
+pub const MAX_DELIVERY_BATCH: i64 = 1024;
+
+/// A consumer started on this channel.
+#[derive(Debug, Clone)]
+struct ChannelConsumer {
+    queue: String,
+    id: u64,
+    /// Deliveries written together when several are ready, from the
+    /// `x-delivery-batch` consume argument; 1 without it.
+    batch: usize,
+    /// The window the consumer's deliveries are charged to; `None` for a
+    /// no-ack consumer.
+    prefetch: Option<Arc<Prefetch>>,
+    /// How long after delivery an unsettled message counts as acked, from
+    /// the `x-auto-ack-after` consume argument.
+    auto_ack_after: Option<Duration>,
+}
+
+/// Reads the `x-delivery-batch` consume argument.
+fn delivery_batch(arguments: &FieldTable) -> Result<usize, AmqpError> {
+    let Some(value) = arguments.get("x-delivery-batch") else {
+        return Ok(1);
+    };
+    match value.as_i64().filter(|n| (1..=MAX_DELIVERY_BATCH).contains(n)) {
+        Some(batch) => Ok(batch as usize),
+        None => Err(AmqpError::channel(
+            reply_codes::PRECONDITION_FAILED,
+            format!("PRECONDITION_FAILED - invalid x-delivery-batch {:?}", value),
+            CLASS_BASIC,
+            20,
+        )),
+    }
+}
+
+/// Reads the `x-auto-ack-after` consume argument, in milliseconds. Only a
+/// consumer that acks may have it.
+fn auto_ack_after(arguments: &FieldTable, no_ack: bool) -> Result<Option<Duration>, AmqpError> {
+    let Some(value) = arguments.get("x-auto-ack-after") else {
+        return Ok(None);
+    };
+    let invalid = |problem: &str| {
+        AmqpError::channel(
+            reply_codes::PRECONDITION_FAILED,
+            format!("PRECONDITION_FAILED - {} x-auto-ack-after {:?}", problem, value),
+            CLASS_BASIC,
+            20,
+        )
+    };
+    if no_ack {
+        return Err(invalid("no-ack consumer cannot take"));
+    }
+    match value.as_i64().and_then(|n| u64::try_from(n).ok()).filter(|&n| n > 0) {
+        Some(millis) => Ok(Some(Duration::from_millis(millis))),
+        None => Err(invalid("invalid")),
+    }
+}
+
+/// Reads the `x-priority` consume argument, 0 to 255; 0 without it.
+fn consumer_priority(arguments: &FieldTable) -> Result<u8, AmqpError> {
+    let Some(value) = arguments.get("x-priority") else {
+        return Ok(0);
+    };
+    match value.as_i64().and_then(|n| u8::try_from(n).ok()) {
+        Some(priority) => Ok(priority),
+        None => Err(AmqpError::channel(
+            reply_codes::PRECONDITION_FAILED,
+            format!("PRECONDITION_FAILED - invalid x-priority {:?}", value),
+            CLASS_BASIC,
+            20,
+        )),
+    }
+}
+
+#[derive(Debug)]
+pub struct Channel {
+    pub id: u16,
+    pub mode: ChannelMode,
+    /// Whether the client currently allows deliveries (`Channel.Flow`).
+    pub flow_active: bool,
+    /// Sequence number of the last complete `Basic.Publish`. In confirm mode
+    /// this is the delivery-tag the broker uses to ack that message.
+    pub publish_seq: u64,
+    /// Set once the broker has sent `Channel.Close`; from then on only
+    /// `Close` and `Close-Ok` are processed.
+    pub closing: Option<Closing>,
+    /// Virtual host of the owning connection.
+    pub vhost: String,
+    /// Authenticated user of the owning connection.
+    pub user: Option<String>,
+    /// Negotiated `frame_max` of the connection; 0 means no limit. Outgoing
+    /// bodies are split so no frame exceeds it.
+    pub frame_max: u32,
+    /// Set while publishing is paused with `Channel.Flow` for going over a
+    /// queue's publish rate: when the connection may resume it.
+    pub throttled_until: Option<Instant>,
+    /// Publish sequence numbers whose `Basic.Ack` waits for the disk alarm
+    /// to clear, because the message is not in the store yet.
+    held_confirms: Vec<u64>,
+    pending: Option<PendingPublish>,
+    /// A complete publish for the connection to run through the broker's
+    /// interceptors before it is routed.
+    intercepted: Option<InterceptedPublish>,
+    /// Consumers started on this channel, by consumer tag.
+    consumers: HashMap<String, ChannelConsumer>,
+    /// Inbox of the owning connection, handed to the queues this channel consumes from.
+    deliveries: DeliverySender,
+    /// Delivery-tag of the last `Basic.Deliver` or `Basic.Get-Ok` sent on
+    /// this channel. Tags are shared by all consumers of the channel, so a
+    /// multiple ack covers deliveries from every queue it consumes from.
+    delivery_tag: u64,
+    /// Deliveries awaiting a `Basic.Ack`, by delivery-tag.
+    unacked: BTreeMap<u64, Outstanding>,
+    /// Delivery-tags of consumers with `x-auto-ack-after`, by when they
+    /// count as acked. Tags settled in the meantime are skipped when their
+    /// time comes.
+    auto_acks: BTreeSet<(Instant, u64)>,
+    /// Limits of the last non-global `Basic.Qos`, given to each consumer
+    /// started after it: prefetch count and prefetch size.
+    consumer_prefetch: (u16, u32),
+    /// Window of the first global `Basic.Qos`, shared by the consumers
+    /// started after it; later ones change its limits.
+    channel_prefetch: Option<Arc<Prefetch>>,
+}
+
+impl Channel {
+    pub fn new(id: u16, vhost: String, deliveries: DeliverySender) -> Self {
+        Channel {
+            id,
+            vhost,
+            user: None,
+            mode: ChannelMode::Normal,
+            flow_active: true,
+            publish_seq: 0,
+            closing: None,
+            frame_max: 0,
+            throttled_until: None,
+            held_confirms: Vec::new(),
+            pending: None,
+            intercepted: None,
+            consumers: HashMap::new(),
+            deliveries,
+            delivery_tag: 0,
+            unacked: BTreeMap::new(),
+            auto_acks: BTreeSet::new(),
+            consumer_prefetch: (0, 0),
+            channel_prefetch: None,
+        }
+    }
+
+    /// Cancels every consumer of this channel; called when the channel or
+    /// its connection goes away.
+    /// Unacked deliveries are requeued.
+    pub fn close(&mut self, broker: &Broker) {
+        for (_, consumer) in self.consumers.drain() {
+            broker.cancel(&self.vhost, &consumer.queue, consumer.id);
+        }
+        self.auto_acks.clear();
+        // Consumer deliveries go back per consumer, so a queue can hold
+        // them for the consumer to reconnect.
+        let mut by_consumer: HashMap<(String, String), Vec<u64>> = HashMap::new();
+        let mut fetched = Vec::new();
+        for outstanding in std::mem::take(&mut self.unacked).into_values() {
+            let tag = outstanding.consumer_tag.clone();
+            let (queue, message_id) = outstanding.release();
+            match tag {
+                Some(tag) => by_consumer.entry((queue, tag)).or_default().push(message_id),
+                None => fetched.push((queue, message_id)),
+            }
+        }
+        for ((queue, tag), message_ids) in by_consumer {
+            broker.requeue_for(&self.vhost, &queue, &tag, &message_ids);
+        }
+        self.requeue(broker, fetched);
+    }
+
+    /// Returns deliveries, given by queue and queue-local id, to their queues.
+    fn requeue(&self, broker: &Broker, deliveries: impl IntoIterator<Item = (String, u64)>) {
+        let mut by_queue: HashMap<String, Vec<u64>> = HashMap::new();
+        for (queue, message_id) in deliveries {
+            by_queue.entry(queue).or_default().push(message_id);
+        }
+        for (queue, message_ids) in by_queue {
+            broker.requeue(&self.vhost, &queue, &message_ids);
+        }
+    }
+
+    /// Lets every queue this channel consumes from use the room settling
+    /// freed in a window shared by the channel's consumers; the queues the
+    /// settled deliveries came from only dispatch to their own consumers.
+    fn dispatch_shared_window(&self, broker: &Broker) {
+        if self.channel_prefetch.is_none() {
+            return;
+        }
+        let queues: HashSet<&str> = self.consumers.values().map(|c| c.queue.as_str()).collect();
+        for queue in queues {
+            broker.dispatch(&self.vhost, queue);
+        }
+    }
+
+    /// Marks the channel as closed by the broker with `reply_code`, releasing
+    /// its consumers and unacked deliveries right away.
+    pub fn start_close(&mut self, broker: &Broker, reply_code: u16, timeout: Duration) {
+        self.close(broker);
+        self.pending = None;
+        self.closing = Some(Closing {
+            deadline: Instant::now() + timeout,
+            reply_code,
+        });
+    }
+
+    /// Turns a delivery from a queue into `Basic.Deliver` and content frames.
+    /// Deliveries for consumers cancelled in the meantime are requeued, and
+    /// the room they took in a shared window is offered to the channel's
+    /// other consumers.
+    pub fn deliver(&mut self, broker: &Broker, delivery: Delivery) -> Vec<AmqpFrame> {
+        let Some(consumer) = self.consumers.get(&delivery.consumer_tag) else {
+            if let Some(prefetch) = &delivery.prefetch {
+                prefetch.release(delivery.footprint);
+            }
+            if !delivery.no_ack {
+                broker.restore(&self.vhost, &delivery.queue, &[delivery.message_id]);
+            }
+            self.dispatch_shared_window(broker);
+            return vec![];
+        };
+        let auto_ack_at = consumer.auto_ack_after.map(|after| Instant::now() + after);
+        let outstanding = (!delivery.no_ack).then(|| Outstanding {
+            queue: delivery.queue,
+            consumer_tag: Some(delivery.consumer_tag.clone()),
+            message_id: delivery.message_id,
+            prefetch: delivery.prefetch.map(|prefetch| (prefetch, delivery.footprint)),
+        });
+        let delivery_tag = self.next_delivery_tag(outstanding);
+        if let Some(at) = auto_ack_at.filter(|_| !delivery.no_ack) {
+            self.auto_acks.insert((at, delivery_tag));
+        }
+        let message = delivery.message;
+        let method = Method::BasicDeliver {
+            consumer_tag: delivery.consumer_tag,
+            delivery_tag,
+            redelivered: delivery.redelivered,
+            exchange: message.exchange.clone(),
+            routing_key: message.routing_key.clone(),
+        };
+        self.content_frames(method, message)
+    }
+
+    /// Tag of the latest delivery on the channel; later ones get higher tags.
+    pub fn last_delivery_tag(&self) -> u64 {
+        self.delivery_tag
+    }
+
+    /// Returns the deliveries tagged after `after`, which the connection
+    /// failed to write in full, to their queues as redelivered. They go
+    /// straight back to the queue, unlike on a close, since the client
+    /// never saw them. No-ack deliveries were settled when sent and are
+    /// lost.
+    pub fn return_unwritten(&mut self, broker: &Broker, after: u64) {
+        let tags: Vec<u64> = self.unacked.range(after + 1..).map(|(tag, _)| *tag).collect();
+        let returned: Vec<(String, u64)> = tags
+            .into_iter()
+            .map(|tag| self.unacked.remove(&tag).unwrap().release())
+            .collect();
+        self.requeue(broker, returned);
+    }
+
+    /// How many deliveries to consumer `consumer_tag` may be written at once.
+    pub fn delivery_batch(&self, consumer_tag: &str) -> usize {
+        self.consumers.get(consumer_tag).map_or(1, |consumer| consumer.batch)
+    }
+
+    /// Forgets the consumers of `queue` the broker no longer has, as after
+    /// the queue was deleted, returning a `Basic.Cancel` telling the client
+    /// about each.
+    pub fn cancel_lost_consumers(&mut self, broker: &Broker, queue: &str) -> Vec<AmqpFrame> {
+        let lost: Vec<String> = self
+            .consumers
+            .iter()
+            .filter(|(_, consumer)| consumer.queue == queue && !broker.has_consumer(&self.vhost, queue, consumer.id))
+            .map(|(tag, _)| tag.clone())
+            .collect();
+        lost.into_iter()
+            .map(|consumer_tag| {
+                self.consumers.remove(&consumer_tag);
+                AmqpFrame::method(
+                    self.id,
+                    &Method::BasicCancel {
+                        consumer_tag,
+                        nowait: true,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Removes the deliveries a `Basic.Ack` or `Basic.Nack` (method
+    /// `method_id`) settles, returning their queue and queue-local id, and
+    /// frees their prefetch room. A multiple one with tag 0 settles
+    /// everything outstanding.
+    fn settle(&mut self, delivery_tag: u64, multiple: bool, method_id: u16) -> Result<Vec<(String, u64)>, AmqpError> {
+        let upto = if multiple && delivery_tag == 0 {
+            u64::MAX
+        } else {
+            delivery_tag
+        };
+        let tags: Vec<u64> = if multiple {
+            self.unacked.range(..=upto).map(|(tag, _)| *tag).collect()
+        } else if self.unacked.contains_key(&delivery_tag) {
+            vec![delivery_tag]
+        } else {
+            vec![]
+        };
+        if tags.is_empty() && !(multiple && delivery_tag == 0) {
+            return Err(AmqpError::channel(
+                reply_codes::PRECONDITION_FAILED,
+                format!("PRECONDITION_FAILED - unknown delivery tag {}", delivery_tag),
+                CLASS_BASIC,
+                method_id,
+            ));
+        }
+        Ok(tags
+            .into_iter()
+            .map(|tag| self.unacked.remove(&tag).unwrap().release())
+            .collect())
+    }
+
+    /// When the next delivery of an `x-auto-ack-after` consumer may count
+    /// as acked.
+    pub fn auto_ack_deadline(&self) -> Option<Instant> {
+        self.auto_acks.first().map(|&(at, _)| at)
+    }
+
+    /// Acks the deliveries of `x-auto-ack-after` consumers that are still
+    /// unsettled at their time, as if the client had acked them, freeing
+    /// their room in the prefetch window. A later ack or nack of one finds
+    /// its delivery-tag unknown.
+    pub fn auto_ack(&mut self, broker: &Broker, now: Instant) {
+        while let Some(&(at, tag)) = self.auto_acks.first() {
+            if at > now {
+                break;
+            }
+            self.auto_acks.pop_first();
+            if let Some(outstanding) = self.unacked.remove(&tag) {
+                debug!("Channel {}: delivery {} acked automatically", self.id, tag);
+                let (queue, message_id) = outstanding.release();
+                broker.ack(&self.vhost, &queue, message_id);
+            }
+        }
+        self.dispatch_shared_window(broker);
+    }
+
+    /// Assigns the next delivery-tag, remembering `outstanding` under it
+    /// until it is acked.
+    fn next_delivery_tag(&mut self, outstanding: Option<Outstanding>) -> u64 {
+        self.delivery_tag += 1;
+        if let Some(outstanding) = outstanding {
+            self.unacked.insert(self.delivery_tag, outstanding);
+        }
+        self.delivery_tag
+    }
+
+    /// Frames a content-carrying method: the method, the header and as many
+    /// body frames as `frame_max` requires (none for an empty body).
+    fn content_frames(&self, method: Method, message: Message) -> Vec<AmqpFrame> {
+        message.into_frames(self.id, &method, self.frame_max)
+    }
+
+    fn reply(&self, method: Method) -> Vec<AmqpFrame> {
+        vec![AmqpFrame::method(self.id, &method)]
+    }
+
+    /// Handles a method addressed to this channel, returning the frames to
+    /// send back or the exception to raise.
+    pub fn handle_method(&mut self, broker: &Broker, method: Method) -> Result<Vec<AmqpFrame>, AmqpError> {
+        match method {
+            Method::ChannelFlow { active } => {
+                self.flow_active = active;
+                Ok(self.reply(Method::ChannelFlowOk { active }))
+            }
+            // Answer to a broker-sent Channel.Flow, e.g. while the vhost is drained.
+            Method::ChannelFlowOk { .. } => Ok(vec![]),
+            Method::ExchangeDeclare {
+                exchange,
+                kind,
+                passive,
+                durable,
+                auto_delete,
+                internal,
+                nowait,
+                arguments,
+            } => {
+                broker.declare_exchange(
+                    &self.vhost,
+                    ExchangeDeclare {
+                    exchange,
+                    kind,
+                    passive,
+                    durable,
+                    auto_delete,
+                    internal,
+                    arguments,
+                })?;
+                if nowait {
+                    return Ok(vec![]);
+                }
+                Ok(self.reply(Method::ExchangeDeclareOk))
+            }
+            Method::ExchangeDelete {
+                exchange,
+                if_unused,
+                nowait,
+            } => {
+                broker.delete_exchange(&self.vhost, &exchange, if_unused)?;
+                if nowait {
+                    return Ok(vec![]);
+                }
+                Ok(self.reply(Method::ExchangeDeleteOk))
+            }
+            Method::QueueDeclare {
+                queue,
+                passive,
+                durable,
+                exclusive,
+                auto_delete,
+                nowait,
+                arguments,
+            } => {
+                let ok = broker.declare_queue(
+                    &self.vhost,
+                    QueueDeclare {
+                    queue,
+                    passive,
+                    durable,
+                    exclusive,
+                    auto_delete,
+                    arguments,
+                })?;
+                if nowait {
+                    return Ok(vec![]);
+                }
+                Ok(self.reply(Method::QueueDeclareOk {
+                    queue: ok.queue,
+                    message_count: ok.message_count,
+                    consumer_count: ok.consumer_count,
+                }))
+            }
+            Method::QueueBind {
+                queue,
+                exchange,
+                routing_key,
+                nowait,
+                arguments,
+            } => {
+                let binding = Binding {
+                    queue,
+                    routing_key,
+                    arguments,
+                };
+                broker.bind_queue(&self.vhost, binding, &exchange)?;
+                if nowait {
+                    return Ok(vec![]);
+                }
+                Ok(self.reply(Method::QueueBindOk))
+            }
+            Method::QueueUnbind {
+                queue,
+                exchange,
+                routing_key,
+                arguments,
+            } => {
+                let binding = Binding {
+                    queue,
+                    routing_key,
+                    arguments,
+                };
+                broker.unbind_queue(&self.vhost, &binding, &exchange)?;
+                Ok(self.reply(Method::QueueUnbindOk))
+            }
+            Method::QueueDelete {
+                queue,
+                if_unused,
+                if_empty,
+                nowait,
+            } => {
+                let message_count = broker.delete_queue(&self.vhost, &queue, if_unused, if_empty)?;
+                if nowait {
+                    return Ok(vec![]);
+                }
+                Ok(self.reply(Method::QueueDeleteOk { message_count }))
+            }
+            Method::BasicConsume {
+                queue,
+                consumer_tag,
+                no_ack,
+                exclusive,
+                nowait,
+                arguments,
+                ..
+            } => {
+                // Tags are unique within a channel; a generated one is
+                // drawn again should the client have picked it already.
+                let consumer_tag = if consumer_tag.is_empty() {
+                    loop {
+                        let tag = broker.generate_consumer_tag();
+                        if !self.consumers.contains_key(&tag) {
+                            break tag;
+                        }
+                    }
+                } else if self.consumers.contains_key(&consumer_tag) {
+                    return Err(AmqpError::connection(
+                        reply_codes::NOT_ALLOWED,
+                        format!("NOT_ALLOWED - attempt to reuse consumer tag '{}'", consumer_tag),
+                        CLASS_BASIC,
+                        20,
+                    ));
+                } else {
+                    consumer_tag
+                };
+                let batch = delivery_batch(&arguments)?;
+                let priority = consumer_priority(&arguments)?;
+                let auto_ack_after = auto_ack_after(&arguments, no_ack)?;
+                // Every consumer that acks gets a window, even an unlimited
+                // one, so a later `Basic.Qos` can limit it.
+                let prefetch = if no_ack {
+                    None
+                } else if self.channel_prefetch.is_some() {
+                    self.channel_prefetch.clone()
+                } else {
+                    Some(Prefetch::window(self.consumer_prefetch.0, self.consumer_prefetch.1))
+                };
+                // Register the tag before the queue can start delivering to it.
+                let (id, consumer_tag) = broker.consume(
+                    &self.vhost,
+                    BasicConsume {
+                        queue: queue.clone(),
+                        consumer_tag,
+                        no_ack,
+                        exclusive,
+                        channel: self.id,
+                        sender: self.deliveries.clone(),
+                        prefetch: prefetch.clone(),
+                        priority,
+                    },
+                )?;
+                let consumer = ChannelConsumer {
+                    queue,
+                    id,
+                    batch,
+                    prefetch,
+                    auto_ack_after,
+                };
+                self.consumers.insert(consumer_tag.clone(), consumer);
+                if nowait {
+                    return Ok(vec![]);
+                }
+                Ok(self.reply(Method::BasicConsumeOk { consumer_tag }))
+            }
+            Method::BasicCancel { consumer_tag, nowait } => {
+                if let Some(consumer) = self.consumers.remove(&consumer_tag) {
+                    broker.cancel(&self.vhost, &consumer.queue, consumer.id);
+                }
+                if nowait {
+                    return Ok(vec![]);
+                }
+                Ok(self.reply(Method::BasicCancelOk { consumer_tag }))
+            }
+            Method::BasicGet { queue, no_ack } => {
+                let Some(ok) = broker.get(&self.vhost, &queue, no_ack)? else {
+                    return Ok(self.reply(Method::BasicGetEmpty));
+                };
+                let outstanding = (!no_ack).then_some(Outstanding {
+                    queue,
+                    consumer_tag: None,
+                    message_id: ok.message_id,
+                    prefetch: None,
+                });
+                let delivery_tag = self.next_delivery_tag(outstanding);
+                let method = Method::BasicGetOk {
+                    delivery_tag,
+                    redelivered: ok.redelivered,
+                    exchange: ok.message.exchange.clone(),
+                    routing_key: ok.message.routing_key.clone(),
+                    message_count: ok.message_count,
+                };
+                Ok(self.content_frames(method, ok.message))
+            }
+            Method::BasicQos {
+                prefetch_size,
+                prefetch_count,
+                global,
+            } => {
+                // A non-global `Basic.Qos` limits each consumer on its own, as
+                // RabbitMQ has it, whatever the client announced. The limits
+                // apply to the consumers already started as well: lowered
+                // ones hold back deliveries until enough are settled, raised
+                // ones let the queues deliver at once.
+                if global {
+                    match &self.channel_prefetch {
+                        Some(window) => window.set_limits(prefetch_count, prefetch_size),
+                        None => self.channel_prefetch = Prefetch::new(prefetch_count, prefetch_size),
+                    }
+                } else {
+                    self.consumer_prefetch = (prefetch_count, prefetch_size);
+                }
+                // Consumers with windows of their own take non-global limits.
+                if !global {
+                    let shared_window = self.channel_prefetch.as_ref();
+                    let own_windows = self
+                        .consumers
+                        .values()
+                        .filter_map(|c| c.prefetch.as_ref())
+                        .filter(|window| shared_window.is_none_or(|shared| !Arc::ptr_eq(shared, window)));
+                    for window in own_windows {
+                        window.set_limits(prefetch_count, prefetch_size);
+                    }
+                }
+                let queues: HashSet<&str> = self.consumers.values().map(|c| c.queue.as_str()).collect();
+                for queue in queues {
+                    broker.dispatch(&self.vhost, queue);
+                }
+                Ok(self.reply(Method::BasicQosOk))
+            }
+            Method::BasicAck { delivery_tag, multiple } => {
+                for (queue, message_id) in self.settle(delivery_tag, multiple, 80)? {
+                    broker.ack(&self.vhost, &queue, message_id);
+                }
+                self.dispatch_shared_window(broker);
+                Ok(vec![])
+            }
+            Method::BasicNack {
+                delivery_tag,
+                multiple,
+                requeue,
+            } => {
+                let settled = self.settle(delivery_tag, multiple, 120)?;
+                if requeue {
+                    self.requeue(broker, settled);
+                } else {
+                    for (queue, message_id) in settled {
+                        broker.reject(&self.vhost, &queue, message_id);
+                    }
+                }
+                self.dispatch_shared_window(broker);
+                Ok(vec![])
+            }
+            Method::BasicRecover { .. } => {
+                // As in RabbitMQ, deliveries are requeued whatever `requeue`
+                // says, and redelivered to any consumer of their queue.
+                let settled = self.settle(0, true, 110)?;
+                self.requeue(broker, settled);
+                self.dispatch_shared_window(broker);
+                Ok(self.reply(Method::BasicRecoverOk))
+            }
+            Method::BasicPublish {
+                exchange,
+                routing_key,
+                mandatory,
+                ..
+            } => {
+                broker.check_publish(&self.vhost, &exchange, &routing_key)?;
+                let timeout = broker.config().publish_timeout;
+                self.pending = Some(PendingPublish {
+                    exchange,
+                    routing_key,
+                    mandatory,
+                    header: None,
+                    body: Vec::new(),
+                    received: 0,
+                    deadline: (!timeout.is_zero()).then(|| Instant::now() + timeout),
+                });
+                Ok(vec![])
+            }
+            Method::TxSelect => {
+                self.tx_select()?;
+                Ok(self.reply(Method::TxSelectOk))
+            }
+            Method::TxCommit => {
+                self.require_transactional(20)?;
+                Ok(self.reply(Method::TxCommitOk))
+            }
+            Method::TxRollback => {
+                self.require_transactional(30)?;
+                Ok(self.reply(Method::TxRollbackOk))
+            }
+            Method::ConfirmSelect { nowait } => {
+                self.confirm_select()?;
+                if nowait {
+                    return Ok(vec![]);
+                }
+                Ok(self.reply(Method::ConfirmSelectOk))
+            }
+            other => {
+                let (class_id, method_id) = other.id();
+                Err(AmqpError::connection(
+                    reply_codes::COMMAND_INVALID,
+                    format!("COMMAND_INVALID - unexpected method {}.{}", class_id, method_id),
+                    class_id,
+                    method_id,
+                ))
+            }
+        }
+    }
+
+    /// Handles the content header following a `Basic.Publish`.
+    pub fn handle_header(&mut self, broker: &Broker, header: ContentHeader) -> Result<Vec<AmqpFrame>, AmqpError> {
+        let Some(pending) = &self.pending else {
+            return Err(unexpected_frame(format!(
+                "content header on channel {} without Basic.Publish",
+                self.id
+            )));
+        };
+        if pending.header.is_some() {
+            return Err(unexpected_frame(format!(
+                "second content header for a publish on channel {}",
+                self.id
+            )));
+        }
+        // Refused before a body frame arrives, so a client cannot announce
+        // a huge body and keep the broker buffering it a trickle at a time.
+        self.check_message_size(broker, header.body_size)?;
+        if broker.config().validate_user_id {
+            if let Some(user_id) = &header.properties.user_id {
+                if self.user.as_ref() != Some(user_id) {
+                    self.pending = None;
+                    return Err(AmqpError::channel(
+                        reply_codes::PRECONDITION_FAILED,
+                        format!(
+                            "PRECONDITION_FAILED - user_id property set to '{}' but authenticated user was '{}'",
+                            user_id,
+                            self.user.as_deref().unwrap_or_default()
+                        ),
+                        CLASS_BASIC,
+                        40,
+                    ));
+                }
+            }
+        }
+        let body_size = header.body_size;
+        let Some(pending) = self.pending.as_mut() else {
+            return Ok(vec![]);
+        };
+        pending.header = Some(header);
+        if body_size == 0 {
+            return Ok(self.complete_publish(broker));
+        }
+        Ok(vec![])
+    }
+
+    /// Handles a content body frame, completing the publish once the
+    /// announced body size has been received.
+    pub fn handle_body(&mut self, broker: &Broker, body: Vec<u8>) -> Result<Vec<AmqpFrame>, AmqpError> {
+        let Some(header) = self.pending.as_ref().and_then(|pending| pending.header.as_ref()) else {
+            return Err(unexpected_frame(format!(
+                "content body on channel {} without content header",
+                self.id
+            )));
+        };
+        let body_size = header.body_size;
+        let pending = self.pending.as_mut().unwrap();
+        pending.received += body.len() as u64;
+        let received = pending.received;
+        if !body.is_empty() {
+            pending.body.push(body);
+        }
+        self.check_message_size(broker, received)?;
+        if received > body_size {
+            return Err(unexpected_frame(format!(
+                "content body on channel {} exceeds the announced {} bytes",
+                self.id, body_size
+            )));
+        }
+        if received >= body_size {
+            return Ok(self.complete_publish(broker));
+        }
+        Ok(vec![])
+    }
+
+    /// Rejects a publish whose body is, or is announced to be, larger than
+    /// `max_message_size`, discarding what was received of it.
+    fn check_message_size(&mut self, broker: &Broker, size: u64) -> Result<(), AmqpError> {
+        let max = broker.config().max_message_size;
+        if max == 0 || size <= max {
+            return Ok(());
+        }
+        self.pending = None;
+        Err(AmqpError::channel(
+            reply_codes::CONTENT_TOO_LARGE,
+            format!(
+                "CONTENT_TOO_LARGE - message size {} is larger than configured max size {}",
+                size, max
+            ),
+            CLASS_BASIC,
+            40,
+        ))
+    }
+
+    fn complete_publish(&mut self, broker: &Broker) -> Vec<AmqpFrame> {
+        let Some(mut pending) = self.pending.take() else {
+            return vec![];
+        };
+        let Some(header) = pending.header.take() else {
+            return vec![];
+        };
+        let body = pending.take_body();
+        broker.observe_message_size(body.len());
+        let PendingPublish {
+            exchange,
+            routing_key,
+            mandatory,
+            ..
+        } = pending;
+        self.publish_seq += 1;
+        let mut message = Message {
+            exchange,
+            routing_key,
+            properties: header.properties,
+            body,
+        };
+        let server_timestamp = broker.config().server_timestamp;
+        let rewritten = rewrite::rewrite(
+            &broker.config().routing_key_rewrites,
+            &message.exchange,
+            &message.routing_key,
+        );
+        let original = (mandatory
+            && (broker.has_interceptors() || server_timestamp != ServerTimestamp::Off || rewritten.is_some()))
+        .then(|| message.clone());
+        if let Some(routing_key) = rewritten {
+            message.routing_key = routing_key;
+        }
+        server_timestamp.apply(&mut message.properties, broker.clock().unix_time().as_secs());
+
+        let publish = InterceptedPublish {
+            seq: self.publish_seq,
+            mandatory,
+            message,
+            original,
+        };
+        if broker.has_interceptors() {
+            self.intercepted = Some(publish);
+            return vec![];
+        }
+        self.finish_publish(broker, publish, InterceptAction::Pass)
+    }
+
+    /// The frames the channel takes next.
+    pub fn state(&self) -> ChannelState {
+        match &self.pending {
+            _ if self.closing.is_some() => ChannelState::Closing,
+            Some(pending) if pending.header.is_some() => ChannelState::AwaitingBody,
+            Some(_) => ChannelState::AwaitingHeader,
+            None => ChannelState::Open,
+        }
+    }
+
+    /// When the publish whose content is arriving runs out of time.
+    pub fn publish_deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().and_then(|pending| pending.deadline)
+    }
+
+    /// Drops the publish whose content is arriving if its time ran out by
+    /// `now`, returning the exception to close the channel with.
+    pub fn expire_publish(&mut self, now: Instant) -> Option<AmqpError> {
+        self.publish_deadline().filter(|&deadline| deadline <= now)?;
+        let pending = self.pending.take()?;
+        Some(AmqpError::channel(
+            reply_codes::UNEXPECTED_FRAME,
+            format!(
+                "UNEXPECTED_FRAME - content of the publish to '{}' incomplete after {} of {} body bytes",
+                pending.exchange,
+                pending.received,
+                pending.header.map_or(0, |header| header.body_size)
+            ),
+            CLASS_BASIC,
+            40,
+        ))
+    }
+
+    /// Takes the publish waiting for the broker's interceptors, if any.
+    pub fn take_intercepted(&mut self) -> Option<InterceptedPublish> {
+        self.intercepted.take()
+    }
+
+    /// Routes a complete publish, unless an interceptor decided otherwise,
+    /// returning the `Basic.Return`, confirm and `Channel.Flow` it calls for.
+    pub fn finish_publish(
+        &mut self,
+        broker: &Broker,
+        publish: InterceptedPublish,
+        action: InterceptAction,
+    ) -> Vec<AmqpFrame> {
+        let InterceptedPublish {
+            seq,
+            mandatory,
+            message,
+            original,
+        } = publish;
+        let outcome = match action {
+            InterceptAction::Pass => broker.publish(&self.vhost, message.clone(), mandatory),
+            InterceptAction::Drop => {
+                debug!("Channel {}: message #{} dropped by an interceptor", self.id, seq);
+                return self.confirm(seq, &PublishOutcome::default()).into_iter().collect();
+            }
+            InterceptAction::Reject(reason) => {
+                debug!(
+                    "Channel {}: message #{} rejected by an interceptor: {}",
+                    self.id, seq, reason
+                );
+                let outcome = PublishOutcome {
+                    rejected: true,
+                    ..Default::default()
+                };
+                return self.confirm(seq, &outcome).into_iter().collect();
+            }
+        };
+
+        let mut frames = Vec::new();
+        if outcome.routed == 0 {
+            debug!(
+                "Channel {}: message #{} to '{}' with key '{}' is unroutable",
+                self.id, seq, message.exchange, message.routing_key
+            );
+        }
+        if outcome.routed == 0 && mandatory {
+            // The whole message goes back as it was published, split to
+            // the connection's frame size like a delivery.
+            let returned = original.unwrap_or(message);
+            let method = Method::BasicReturn {
+                reply_code: reply_codes::NO_ROUTE,
+                reply_text: "NO_ROUTE".into(),
+                exchange: returned.exchange.clone(),
+                routing_key: returned.routing_key.clone(),
+            };
+            frames.extend(self.content_frames(method, returned));
+        }
+        frames.extend(self.confirm(seq, &outcome));
+        if let Some(wait) = outcome.throttle {
+            if self.throttled_until.is_none() {
+                frames.push(AmqpFrame::method(self.id, &Method::ChannelFlow { active: false }));
+            }
+            self.throttled_until = self.throttled_until.max(Some(Instant::now() + wait));
+        }
+        frames
+    }
+
+    /// The `Basic.Ack` or `Basic.Nack` for publish `seq`, in confirm mode.
+    /// The ack of a message that is not stored yet is held back until
+    /// `release_confirms`.
+    fn confirm(&mut self, seq: u64, outcome: &PublishOutcome) -> Option<AmqpFrame> {
+        if self.mode != ChannelMode::Confirm {
+            return None;
+        }
+        if outcome.unstored && !outcome.rejected {
+            self.held_confirms.push(seq);
+            return None;
+        }
+        let confirm = if outcome.rejected {
+            Method::BasicNack {
+                delivery_tag: seq,
+                multiple: false,
+                requeue: false,
+            }
+        } else {
+            Method::BasicAck {
+                delivery_tag: seq,
+                multiple: false,
+            }
+        };
+        Some(AmqpFrame::method(self.id, &confirm))
+    }
+
+    /// Acks the publishes held back for the disk alarm, now that their
+    /// messages are stored.
+    pub fn release_confirms(&mut self) -> Vec<AmqpFrame> {
+        self.resolve_confirms(true)
+    }
+
+    /// Acks the held-back publishes if their messages are `stored` by now,
+    /// and nacks them otherwise, as when the broker shuts down.
+    pub fn resolve_confirms(&mut self, stored: bool) -> Vec<AmqpFrame> {
+        let id = self.id;
+        self.held_confirms
+            .drain(..)
+            .map(|seq| {
+                let confirm = if stored {
+                    Method::BasicAck {
+                        delivery_tag: seq,
+                        multiple: false,
+                    }
+                } else {
+                    Method::BasicNack {
+                        delivery_tag: seq,
+                        multiple: false,
+                        requeue: false,
+                    }
+                };
+                AmqpFrame::method(id, &confirm)
+            })
+            .collect()
+    }
+
+    fn tx_select(&mut self) -> Result<(), AmqpError> {
+        if self.mode == ChannelMode::Confirm {
+            return Err(AmqpError::channel(
+                reply_codes::PRECONDITION_FAILED,
+                "PRECONDITION_FAILED - cannot switch from confirm to tx mode",
+                CLASS_TX,
+                10,
+            ));
+        }
+        self.mode = ChannelMode::Transactional;
+        Ok(())
+    }
+
+    fn confirm_select(&mut self) -> Result<(), AmqpError> {
+        match self.mode {
+            ChannelMode::Transactional => {
+                return Err(AmqpError::channel(
+                    reply_codes::PRECONDITION_FAILED,
+                    "PRECONDITION_FAILED - cannot switch from tx to confirm mode",
+                    CLASS_CONFIRM,
+                    10,
+                ));
+            }
+            // Confirm delivery-tags count publishes from the first select.
+            ChannelMode::Normal => self.publish_seq = 0,
+            ChannelMode::Confirm => {}
+        }
+        self.mode = ChannelMode::Confirm;
+        Ok(())
+    }
+
+    fn require_transactional(&self, method_id: u16) -> Result<(), AmqpError> {
+        if self.mode != ChannelMode::Transactional {
+            return Err(AmqpError::channel(
+                reply_codes::PRECONDITION_FAILED,
+                "PRECONDITION_FAILED - channel is not transactional",
+                CLASS_TX,
+                method_id,
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The connection exception for a frame that breaks the content sequence
+/// of a publish.
+fn unexpected_frame(text: String) -> AmqpError {
+    AmqpError::connection(
+        reply_codes::UNEXPECTED_FRAME,
+        format!("UNEXPECTED_FRAME - {}", text),
+        CLASS_BASIC,
+        40,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::field_table::FieldTable;
+    use crate::properties::BasicProperties;
+    use crate::vhost::DEFAULT_VHOST;
+
+    /// A channel whose deliveries are discarded.
+    fn channel(id: u16) -> Channel {
+        Channel::new(id, DEFAULT_VHOST.into(), tokio::sync::mpsc::unbounded_channel().0)
+    }
+
+    fn decode(frame: &AmqpFrame) -> Method {
+        Method::decode(&frame.payload).unwrap()
+    }
+
+    fn publish(channel: &mut Channel, broker: &Broker, mandatory: bool, body: &[u8]) -> Vec<AmqpFrame> {
+        let method = Method::BasicPublish {
+            exchange: "".into(),
+            routing_key: "jobs".into(),
+            mandatory,
+            immediate: false,
+        };
+        assert!(channel.handle_method(broker, method).unwrap().is_empty());
+        let header = ContentHeader {
+            class_id: 60,
+            body_size: body.len() as u64,
+            properties: BasicProperties::default(),
+        };
+        let mut frames = channel.handle_header(broker, header).unwrap();
+        for chunk in body.chunks(2) {
+            frames.extend(channel.handle_body(broker, chunk.to_vec()).unwrap());
+        }
+        frames
+    }
+
+    #[test]
+    fn test_confirm_select_after_tx_select_is_rejected() {
+        let broker = Broker::new(Config::default());
+        let mut channel = channel(1);
+        channel.handle_method(&broker, Method::TxSelect).unwrap();
+
+        let err = channel
+            .handle_method(&broker, Method::ConfirmSelect { nowait: false })
+            .unwrap_err();
+        assert!(matches!(err, AmqpError::ChannelException { .. }));
+        assert_eq!(err.reply_code(), reply_codes::PRECONDITION_FAILED);
+        assert_eq!(channel.mode, ChannelMode::Transactional);
+    }
+
+    #[test]
+    fn test_tx_select_after_confirm_select_is_rejected() {
+        let broker = Broker::new(Config::default());
+        let mut channel = channel(1);
+        let frames = channel
+            .handle_method(&broker, Method::ConfirmSelect { nowait: false })
+            .unwrap();
+        assert_eq!(decode(&frames[0]), Method::ConfirmSelectOk);
+
+        let err = channel.handle_method(&broker, Method::TxSelect).unwrap_err();
+        assert!(matches!(err, AmqpError::ChannelException { .. }));
+        assert_eq!(err.reply_code(), reply_codes::PRECONDITION_FAILED);
+        assert_eq!(channel.mode, ChannelMode::Confirm);
+    }
+
+    #[test]
+    fn test_tx_commit_requires_tx_mode() {
+        let broker = Broker::new(Config::default());
+        let mut channel = channel(1);
+        let err = channel.handle_method(&broker, Method::TxCommit).unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::PRECONDITION_FAILED);
+    }
+
+    #[test]
+    fn test_nth_publish_is_confirmed_with_tag_n() {
+        let broker = Broker::new(Config::default());
+        let mut channel = channel(1);
+        channel
+            .handle_method(&broker, Method::ConfirmSelect { nowait: true })
+            .unwrap();
+
+        for n in 1..=3 {
+            if n == 2 {
+                // Pausing deliveries must not disturb the publish sequence.
+                channel
+                    .handle_method(&broker, Method::ChannelFlow { active: false })
+                    .unwrap();
+            }
+            let frames = publish(&mut channel, &broker, false, b"hello");
+            assert_eq!(
+                frames.iter().map(decode).collect::<Vec<_>>(),
+                vec![Method::BasicAck {
+                    delivery_tag: n,
+                    multiple: false
+                }]
+            );
+        }
+        assert_eq!(channel.publish_seq, 3);
+    }
+
+    #[test]
+    fn test_mandatory_return_precedes_confirm() {
+        let broker = Broker::new(Config::default());
+        let mut channel = channel(1);
+        channel
+            .handle_method(&broker, Method::ConfirmSelect { nowait: true })
+            .unwrap();
+
+        // An empty body is returned without body frames, as it is delivered.
+        let frames = publish(&mut channel, &broker, true, b"");
+        assert_eq!(frames.len(), 3);
+        assert!(matches!(decode(&frames[0]), Method::BasicReturn { reply_code: 312, .. }));
+        assert_eq!(
+            decode(&frames[2]),
+            Method::BasicAck {
+                delivery_tag: 1,
+                multiple: false
+            }
+        );
+    }
+
+    fn consume(channel: &mut Channel, broker: &Broker, exclusive: bool) -> Result<Vec<AmqpFrame>, AmqpError> {
+        channel.handle_method(
+            broker,
+            Method::BasicConsume {
+                queue: "jobs".into(),
+                consumer_tag: "".into(),
+                no_local: false,
+                no_ack: false,
+                exclusive,
+                nowait: false,
+                arguments: Default::default(),
+            },
+        )
+    }
+
+    fn declare_jobs(broker: &Broker) {
+        broker
+            .declare_queue(
+                DEFAULT_VHOST,
+                QueueDeclare {
+                queue: "jobs".into(),
+                ..Default::default()
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_exclusive_consume_on_consumed_queue_is_refused() {
+        let broker = Broker::new(Config::default());
+        declare_jobs(&broker);
+        let mut first = channel(1);
+        let mut second = channel(2);
+        consume(&mut first, &broker, false).unwrap();
+
+        let err = consume(&mut second, &broker, true).unwrap_err();
+        assert!(matches!(err, AmqpError::ChannelException { .. }));
+        assert_eq!(err.reply_code(), reply_codes::ACCESS_REFUSED);
+    }
+
+    #[test]
+    fn test_consumer_after_exclusive_consumer_is_refused_until_cancel() {
+        let broker = Broker::new(Config::default());
+        declare_jobs(&broker);
+        let mut first = channel(1);
+        let mut second = channel(2);
+        let frames = consume(&mut first, &broker, true).unwrap();
+        let Method::BasicConsumeOk { consumer_tag } = decode(&frames[0]) else {
+            panic!("expected Basic.Consume-Ok");
+        };
+
+        let err = consume(&mut second, &broker, false).unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::ACCESS_REFUSED);
+
+        first
+            .handle_method(
+                &broker,
+                Method::BasicCancel {
+                    consumer_tag,
+                    nowait: true,
+                },
+            )
+            .unwrap();
+        consume(&mut second, &broker, false).unwrap();
+    }
+
+    #[test]
+    fn test_queue_exclusive_flag_does_not_make_consumers_exclusive() {
+        let broker = Broker::new(Config::default());
+        broker
+            .declare_queue(
+                DEFAULT_VHOST,
+                QueueDeclare {
+                queue: "jobs".into(),
+                exclusive: true,
+                ..Default::default()
+            })
+            .unwrap();
+        let mut channel = channel(1);
+        consume(&mut channel, &broker, false).unwrap();
+        consume(&mut channel, &broker, false).unwrap();
+    }
+
+    /// Declares "jobs" bound to `amq.direct` and publishes `bodies` to it.
+    fn fill_jobs(broker: &Broker, bodies: &[&[u8]]) {
+        declare_jobs(broker);
+        let binding = Binding {
+            queue: "jobs".into(),
+            routing_key: "jobs".into(),
+            arguments: Default::default(),
+        };
+        broker.bind_queue(DEFAULT_VHOST, binding, "amq.direct").unwrap();
+        for body in bodies {
+            let message = Message {
+                exchange: "amq.direct".into(),
+                routing_key: "jobs".into(),
+                properties: BasicProperties::default(),
+                body: body.to_vec(),
+            };
+            assert_eq!(broker.publish(DEFAULT_VHOST, message, false).routed, 1);
+        }
+    }
+
+    fn get(channel: &mut Channel, broker: &Broker, no_ack: bool) -> Vec<AmqpFrame> {
+        let method = Method::BasicGet {
+            queue: "jobs".into(),
+            no_ack,
+        };
+        channel.handle_method(broker, method).unwrap()
+    }
+
+    fn ready(broker: &Broker) -> u32 {
+        let ok = broker
+            .declare_queue(
+                DEFAULT_VHOST,
+                QueueDeclare {
+                queue: "jobs".into(),
+                passive: true,
+                ..Default::default()
+            })
+            .unwrap();
+        ok.message_count
+    }
+
+    #[test]
+    fn test_no_ack_get_removes_the_message() {
+        let broker = Broker::new(Config::default());
+        fill_jobs(&broker, &[b"a", b"b"]);
+        let mut channel = channel(1);
+
+        let frames = get(&mut channel, &broker, true);
+        assert_eq!(frames.len(), 3);
+        assert_eq!(
+            decode(&frames[0]),
+            Method::BasicGetOk {
+                delivery_tag: 1,
+                redelivered: false,
+                exchange: "amq.direct".into(),
+                routing_key: "jobs".into(),
+                message_count: 1,
+            }
+        );
+        assert_eq!(frames[2].payload, b"a");
+        assert!(channel.unacked.is_empty());
+
+        // Nothing to requeue: the message is gone for good.
+        channel.close(&broker);
+        assert_eq!(ready(&broker), 1);
+    }
+
+    #[test]
+    fn test_manual_ack_get_stays_outstanding_until_acked() {
+        let broker = Broker::new(Config::default());
+        fill_jobs(&broker, &[b"a"]);
+        let mut channel = channel(1);
+
+        let frames = get(&mut channel, &broker, false);
+        assert!(matches!(decode(&frames[0]), Method::BasicGetOk { delivery_tag: 1, .. }));
+        assert_eq!(channel.unacked.len(), 1);
+        assert_eq!(ready(&broker), 0);
+
+        let ack = Method::BasicAck {
+            delivery_tag: 1,
+            multiple: false,
+        };
+        assert!(channel.handle_method(&broker, ack.clone()).unwrap().is_empty());
+        assert!(channel.unacked.is_empty());
+        channel.close(&broker);
+        assert_eq!(ready(&broker), 0);
+        assert_eq!(decode(&get(&mut channel, &broker, false)[0]), Method::BasicGetEmpty);
+
+        // The tag was settled by the first ack.
+        let err = channel.handle_method(&broker, ack).unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::PRECONDITION_FAILED);
+    }
+
+    #[test]
+    fn test_multiple_ack_settles_gets_and_deliveries_alike() {
+        let broker = Broker::new(Config::default());
+        fill_jobs(&broker, &[b"a", b"b", b"c", b"d"]);
+        let (deliveries, mut inbox) = tokio::sync::mpsc::unbounded_channel();
+        let mut channel = Channel::new(1, DEFAULT_VHOST.into(), deliveries);
+        let qos = Method::BasicQos {
+            prefetch_size: 0,
+            prefetch_count: 1,
+            global: false,
+        };
+        channel.handle_method(&broker, qos).unwrap();
+        let consume = Method::BasicConsume {
+            queue: "jobs".into(),
+            consumer_tag: "worker".into(),
+            no_local: false,
+            no_ack: false,
+            exclusive: false,
+            nowait: true,
+            arguments: FieldTable::new(),
+        };
+        channel.handle_method(&broker, consume).unwrap();
+        let mut deliver = |channel: &mut Channel| {
+            let delivery = inbox.try_recv().expect("no delivery");
+            match decode(&channel.deliver(&broker, delivery)[0]) {
+                Method::BasicDeliver { delivery_tag, .. } => delivery_tag,
+                other => panic!("expected Basic.Deliver, got {:?}", other),
+            }
+        };
+
+        // Tags 1 and 3 come from the consumer, 2 from a get in between.
+        assert_eq!(deliver(&mut channel), 1);
+        assert!(matches!(
+            decode(&get(&mut channel, &broker, false)[0]),
+            Method::BasicGetOk { delivery_tag: 2, .. }
+        ));
+        let ack = |delivery_tag| Method::BasicAck {
+            delivery_tag,
+            multiple: true,
+        };
+        channel.handle_method(&broker, ack(1)).unwrap();
+        assert_eq!(deliver(&mut channel), 3);
+        assert_eq!(channel.unacked.keys().copied().collect::<Vec<_>>(), [2, 3]);
+
+        // One multiple ack covers the get and the delivery under it.
+        channel.handle_method(&broker, ack(3)).unwrap();
+        assert!(channel.unacked.is_empty());
+        assert_eq!(deliver(&mut channel), 4);
+        channel.close(&broker);
+        assert_eq!(ready(&broker), 1);
+    }
+
+    #[test]
+    fn test_unacked_get_is_requeued_on_close() {
+        let broker = Broker::new(Config::default());
+        fill_jobs(&broker, &[b"a"]);
+        let mut first = channel(1);
+        get(&mut first, &broker, false);
+        first.close(&broker);
+        assert_eq!(ready(&broker), 1);
+
+        let mut second = channel(2);
+        let frames = get(&mut second, &broker, false);
+        assert!(matches!(
+            decode(&frames[0]),
+            Method::BasicGetOk {
+                redelivered: true,
+                message_count: 0,
+                ..
+            }
+        ));
+        assert_eq!(frames[2].payload, b"a");
+    }
+
+    #[test]
+    fn test_get_from_missing_queue_is_not_found() {
+        let broker = Broker::new(Config::default());
+        let method = Method::BasicGet {
+            queue: "nope".into(),
+            no_ack: true,
+        };
+        let err = channel(1).handle_method(&broker, method).unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_internal_exchange_takes_only_broker_routed_messages() {
+        use crate::broker::{ExchangeDeclare, QueueDeclare};
+        use crate::exchange::Binding;
+        use crate::field_table::FieldValue;
+        use crate::message::Message;
+
+        let broker = Broker::new(Config::default());
+        let declare = ExchangeDeclare {
+            exchange: "hidden".into(),
+            kind: "direct".into(),
+            internal: true,
+            ..Default::default()
+        };
+        broker.declare_exchange(DEFAULT_VHOST, declare).unwrap();
+        let mut arguments = FieldTable::new();
+        arguments.insert("x-max-length", FieldValue::LongInt(1));
+        arguments.insert("x-dead-letter-exchange", FieldValue::LongString(b"hidden".to_vec()));
+        for (queue, arguments) in [("work", arguments), ("dropped", FieldTable::new())] {
+            let declare = QueueDeclare {
+                queue: queue.into(),
+                arguments,
+                ..Default::default()
+            };
+            broker.declare_queue(DEFAULT_VHOST, declare).unwrap();
+        }
+        let binding = Binding {
+            queue: "dropped".into(),
+            routing_key: "work".into(),
+            arguments: FieldTable::new(),
+        };
+        broker.bind_queue(DEFAULT_VHOST, binding, "hidden").unwrap();
+
+        // Dead letters reach the internal exchange and route on.
+        for body in ["first", "second"] {
+            let message = Message {
+                exchange: "".into(),
+                routing_key: "work".into(),
+                properties: Default::default(),
+                body: body.as_bytes().to_vec(),
+            };
+            broker.publish(DEFAULT_VHOST, message, false);
+        }
+        let dropped = broker.peek(DEFAULT_VHOST, "dropped", 10).unwrap();
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].message.body, b"first");
+
+        let method = Method::BasicPublish {
+            exchange: "hidden".into(),
+            routing_key: "work".into(),
+            mandatory: false,
+            immediate: false,
+        };
+        let err = channel(1).handle_method(&broker, method).unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::ACCESS_REFUSED);
+    }
+
+    #[test]
+    fn test_names_are_checked_on_declare_and_publish() {
+        let broker = Broker::new(Config::default());
+        let mut channel = channel(1);
+        let declare = |queue: &str| Method::QueueDeclare {
+            queue: queue.into(),
+            passive: false,
+            durable: false,
+            exclusive: false,
+            auto_delete: false,
+            nowait: false,
+            arguments: FieldTable::new(),
+        };
+        assert!(channel.handle_method(&broker, declare("orders.created:v2")).is_ok());
+        let err = channel.handle_method(&broker, declare("orders created")).unwrap_err();
+        assert!(matches!(err, AmqpError::ChannelException { .. }));
+        assert_eq!(err.reply_code(), reply_codes::PRECONDITION_FAILED);
+        let err = channel.handle_method(&broker, declare(&"q".repeat(256))).unwrap_err();
+        assert!(matches!(err, AmqpError::ConnectionException { .. }));
+        assert_eq!(err.reply_code(), reply_codes::SYNTAX_ERROR);
+
+        let publish = Method::BasicPublish {
+            exchange: "".into(),
+            routing_key: "k".repeat(300),
+            mandatory: false,
+            immediate: false,
+        };
+        let err = channel.handle_method(&broker, publish).unwrap_err();
+        assert!(matches!(err, AmqpError::ConnectionException { .. }));
+        assert_eq!(err.reply_code(), reply_codes::SYNTAX_ERROR);
+    }
+
+    /// Publishes to the default exchange with `user_id` set, on a channel
+    /// owned by "guest".
+    fn publish_as(broker: &Broker, user_id: Option<&str>) -> Result<Vec<AmqpFrame>, AmqpError> {
+        let mut channel = channel(1);
+        channel.user = Some("guest".into());
+        let method = Method::BasicPublish {
+            exchange: "".into(),
+            routing_key: "jobs".into(),
+            mandatory: false,
+            immediate: false,
+        };
+        channel.handle_method(broker, method)?;
+        let header = ContentHeader {
+            class_id: 60,
+            body_size: 0,
+            properties: BasicProperties {
+                user_id: user_id.map(str::to_string),
+                ..Default::default()
+            },
+        };
+        channel.handle_header(broker, header)
+    }
+
+    fn validating_broker() -> Broker {
+        Broker::new(Config {
+            validate_user_id: true,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_matching_user_id_is_accepted() {
+        assert!(publish_as(&validating_broker(), Some("guest")).is_ok());
+    }
+
+    #[test]
+    fn test_mismatching_user_id_is_precondition_failed() {
+        let err = publish_as(&validating_broker(), Some("admin")).unwrap_err();
+        assert!(matches!(err, AmqpError::ChannelException { .. }));
+        assert_eq!(err.reply_code(), reply_codes::PRECONDITION_FAILED);
+
+        // Without the flag the property is not checked.
+        assert!(publish_as(&Broker::new(Config::default()), Some("admin")).is_ok());
+    }
+
+    #[test]
+    fn test_absent_user_id_is_accepted() {
+        assert!(publish_as(&validating_broker(), None).is_ok());
+    }
+
+    fn start_publish(channel: &mut Channel, broker: &Broker, body_size: u64) -> Result<Vec<AmqpFrame>, AmqpError> {
+        let method = Method::BasicPublish {
+            exchange: "".into(),
+            routing_key: "jobs".into(),
+            mandatory: false,
+            immediate: false,
+        };
+        channel.handle_method(broker, method)?;
+        let header = ContentHeader {
+            class_id: 60,
+            body_size,
+            properties: BasicProperties::default(),
+        };
+        channel.handle_header(broker, header)
+    }
+
+    #[test]
+    fn test_message_over_max_size_is_rejected() {
+        let broker = Broker::new(Config {
+            max_message_size: 16,
+            ..Default::default()
+        });
+        let mut channel = channel(1);
+
+        let err = start_publish(&mut channel, &broker, 17).unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::CONTENT_TOO_LARGE);
+        assert!(channel.pending.is_none());
+
+        // A header understating the size is caught once the body grows past the limit.
+        start_publish(&mut channel, &broker, 8).unwrap();
+        assert!(channel.handle_body(&broker, vec![0; 8]).is_ok());
+        start_publish(&mut channel, &broker, 16).unwrap();
+        assert!(channel.handle_body(&broker, vec![0; 10]).is_ok());
+        let err = channel.handle_body(&broker, vec![0; 10]).unwrap_err();
+        assert!(matches!(err, AmqpError::ChannelException { .. }));
+        assert!(channel.pending.is_none());
+    }
+
+    #[test]
+    fn test_body_is_reassembled_whatever_the_framing() {
+        let broker = Broker::new(Config::default());
+        declare_jobs(&broker);
+        let mut channel = channel(1);
+        let body: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let framings: [&[usize]; 4] = [&[1000], &[1; 1000], &[333, 0, 333, 334], &[999, 1]];
+        for framing in framings {
+            start_publish(&mut channel, &broker, body.len() as u64).unwrap();
+            let mut rest = &body[..];
+            for &len in framing {
+                let (frame, after) = rest.split_at(len);
+                channel.handle_body(&broker, frame.to_vec()).unwrap();
+                rest = after;
+            }
+            assert!(channel.pending.is_none());
+        }
+        let queued = broker.peek(DEFAULT_VHOST, "jobs", framings.len()).unwrap();
+        assert_eq!(queued.len(), framings.len());
+        for queued in queued {
+            assert_eq!(queued.message.body, body);
+        }
+    }
+
+    #[test]
+    fn test_channel_state_follows_a_publish() {
+        let broker = Broker::new(Config::default());
+        declare_jobs(&broker);
+        let mut channel = channel(1);
+        assert_eq!(channel.state(), ChannelState::Open);
+        let method = Method::BasicPublish {
+            exchange: "".into(),
+            routing_key: "jobs".into(),
+            mandatory: false,
+            immediate: false,
+        };
+        channel.handle_method(&broker, method).unwrap();
+        assert_eq!(channel.state(), ChannelState::AwaitingHeader);
+        let header = ContentHeader {
+            class_id: 60,
+            body_size: 6,
+            properties: BasicProperties::default(),
+        };
+        channel.handle_header(&broker, header).unwrap();
+        assert_eq!(channel.state(), ChannelState::AwaitingBody);
+        channel.handle_body(&broker, b"job".to_vec()).unwrap();
+        assert_eq!(channel.state(), ChannelState::AwaitingBody);
+        channel.handle_body(&broker, b"job".to_vec()).unwrap();
+        assert_eq!(channel.state(), ChannelState::Open);
+
+        start_publish(&mut channel, &broker, 6).unwrap();
+        channel.start_close(&broker, reply_codes::PRECONDITION_FAILED, Duration::from_secs(1));
+        assert_eq!(channel.state(), ChannelState::Closing);
+    }
+
+    /// Compares assembling bodies of many frames the way `handle_body` does,
+    /// keeping the payloads and joining them once, with copying each frame
+    /// onto a growing body as it used to. Run with `cargo test --release --
+    /// --ignored bench_body_accumulation --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_body_accumulation() {
+        const MESSAGES: usize = 32;
+        const FRAMES: usize = 256;
+        const FRAME_SIZE: usize = 4096;
+        let frames = || -> Vec<Vec<Vec<u8>>> {
+            (0..MESSAGES)
+                .map(|m| (0..FRAMES).map(|f| vec![(m + f) as u8; FRAME_SIZE]).collect())
+                .collect()
+        };
+
+        let messages = frames();
+        let started = std::time::Instant::now();
+        let copied: Vec<Vec<u8>> = messages
+            .iter()
+            .map(|frames| {
+                let mut body = Vec::new();
+                for payload in frames {
+                    body.extend_from_slice(payload);
+                }
+                body
+            })
+            .collect();
+        let copied_time = started.elapsed();
+
+        let messages = frames();
+        let started = std::time::Instant::now();
+        let joined: Vec<Vec<u8>> = messages
+            .into_iter()
+            .map(|frames| {
+                let mut pending = PendingPublish {
+                    exchange: String::new(),
+                    routing_key: String::new(),
+                    mandatory: false,
+                    header: None,
+                    body: Vec::new(),
+                    received: 0,
+                    deadline: None,
+                };
+                for payload in frames {
+                    pending.received += payload.len() as u64;
+                    pending.body.push(payload);
+                }
+                pending.take_body()
+            })
+            .collect();
+        let joined_time = started.elapsed();
+
+        println!(
+            "{} bodies of {} frames of {} bytes: copied per frame {:?}, joined once {:?}",
+            MESSAGES, FRAMES, FRAME_SIZE, copied_time, joined_time
+        );
+        assert_eq!(copied, joined);
+    }
+}
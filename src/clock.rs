@@ -0,0 +1,100 @@
+// src/clock.rs
+
+//! Where the broker reads the time.
+//!
+//! Queue expiry, consumer reconnect windows, ack timeouts, slow-consumer
+//! checks, server timestamps and publish throttling all ask the broker's
+//! `Clock` rather than the system. `TokioClock`, the default, reads
+//! `tokio::time`, so it also follows a paused Tokio runtime; a
+//! `ManualClock` only moves when it is advanced, which lets tests step past
+//! a deadline instead of sleeping until it.
+//!
+//! Timers a connection waits on, such as heartbeats and the close timeout,
+//! still sleep on Tokio; the clock decides what is due once they fire.
+//! Durations measured for slow-operation warnings use the real time.
+
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A source of the current time.
+pub trait Clock: Send + Sync + fmt::Debug {
+    /// The current instant, for deadlines and elapsed times.
+    fn now(&self) -> Instant;
+
+    /// Time since the Unix epoch, for timestamps put on messages.
+    fn unix_time(&self) -> Duration;
+}
+
+/// The real time, as `tokio::time` sees it.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn unix_time(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// A clock that stands still until `advance` moves it.
+#[derive(Debug)]
+pub struct ManualClock {
+    start: Instant,
+    unix_start: Duration,
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    /// A clock reading the current time until it is advanced.
+    pub fn new() -> Self {
+        ManualClock {
+            start: Instant::now(),
+            unix_start: TokioClock.unix_time(),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    /// Moves the clock forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+
+    fn unix_time(&self) -> Duration {
+        self.unix_start + *self.elapsed.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock_moves_only_when_advanced() {
+        let clock = ManualClock::new();
+        let (now, unix) = (clock.now(), clock.unix_time());
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(clock.now(), now);
+
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(clock.now() - now, Duration::from_secs(90));
+        assert_eq!(clock.unix_time() - unix, Duration::from_secs(90));
+    }
+}
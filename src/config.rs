@@ -0,0 +1,1195 @@
+// src/config.rs
+
+//! Broker settings, built from defaults or loaded from a TOML file.
+//!
+//! Every key of the file is optional:
+//!
+//! ```toml
+//! default_queue_type = "quorum"
+//! storage = "disk"
+//! self_test = true
+//! channel_max = 512
+//! channel_warning_threshold = 200
+//! frame_max = 131072
+//! frame_resync_limit = 8
+//! max_pending_handshakes = 128
+//! churn_limit = 20
+//! churn_window_ms = 10000
+//! max_message_size = 16777216
+//! max_unacked_messages = 100000
+//! max_frame_rate = 5000
+//! frame_rate_burst = 10000
+//! topic_max_words = 32
+//! topic_max_length = 255
+//! dead_letter_max_depth = 16
+//! write_timeout_ms = 30000
+//! connection_close_timeout_ms = 3000
+//! publish_timeout_ms = 60000
+//! slow_consumer_threshold_ms = 30000
+//! slow_operation_threshold_ms = 500
+//! memory_high_watermark = 2147483648
+//! consumer_utilization_window_ms = 60000
+//! rate_sample_interval_ms = 5000
+//! rate_samples = 60
+//! event_log_size = 1000
+//! publish_rate_policy = "nack"
+//! server_timestamp = "if_absent"
+//! unknown_method_close = "channel"
+//! vhosts = ["staging"]
+//! sasl_mechanisms = ["EXTERNAL"]
+//!
+//! [[listeners]]
+//! address = "[::]:5672"
+//!
+//! [[listeners]]
+//! path = "/run/haymq/amqp.sock"
+//!
+//! [socket]
+//! nodelay = true
+//! keepalive_ms = 60000
+//! send_buffer_size = 262144
+//! recv_buffer_size = 262144
+//!
+//! [limits]
+//! max_table_depth = 32
+//! max_table_bytes = 1048576
+//!
+//! [sni_vhosts]
+//! "staging.example.com" = "staging"
+//!
+//! [vhost_settings.staging]
+//! force_transient = true
+//! max_messages = 100000
+//! max_bytes = 1073741824
+//! quota_policy = "nack"
+//! max_queues = 1000
+//! max_exchanges = 100
+//! max_bindings = 10000
+//!
+//! [management]
+//! bind = "127.0.0.1:15673"
+//!
+//! [[users]]
+//! name = "admin"
+//! password = "change-me"
+//! tags = ["administrator"]
+//! vhosts = ["/", "staging"]
+//!
+//! [[topology.exchanges]]
+//! name = "orders"
+//! type = "topic"
+//! durable = true
+//!
+//! [[topology.queues]]
+//! name = "orders.created"
+//! durable = true
+//! arguments = { "x-queue-type" = "quorum" }
+//!
+//! [[topology.bindings]]
+//! exchange = "orders"
+//! queue = "orders.created"
+//! routing_key = "orders.created"
+//!
+//! [[routing_key_rewrites]]
+//! exchange = "orders"
+//! transform = "lowercase"
+//!
+//! [[routing_key_rewrites]]
+//! routing_key = "legacy.*"
+//! transform = "strip_prefix"
+//! prefix = "legacy."
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::auth::UserConfig;
+use crate::broker::{ExchangeDeclare, QueueDeclare};
+use crate::exchange::Binding;
+use crate::field_table::{FieldTable, FieldValue, TableLimits};
+use crate::listener::{ListenerConfig, SocketOptions};
+use crate::management::ManagementConfig;
+use crate::methods::UnknownMethodClose;
+use crate::properties::ServerTimestamp;
+use crate::protocol::FRAME_MIN_SIZE;
+use crate::queue::QueueType;
+use crate::rate_limit::RatePolicy;
+use crate::rewrite::{KeyTransform, RewriteRule};
+use crate::store::{ChecksumMismatch, Storage, StoreConfig};
+use crate::vhost::{self, DeliveryModeOverride, VHostSettings, DEFAULT_VHOST};
+use crate::websocket::WebSocketConfig;
+
+/// Broker-wide settings.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Queue type used when `Queue.Declare` carries no `x-queue-type`.
+    pub default_queue_type: QueueType,
+    /// Highest channel number offered in `Connection.Tune`; 0 means no limit.
+    pub channel_max: u16,
+    /// Open channels past which a connection is warned about in the log
+    /// and counted in the metrics, as a sign of a client leaking channels.
+    /// Nothing is closed; 0 turns the warning off.
+    pub channel_warning_threshold: u16,
+    /// Largest frame size offered in `Connection.Tune`, at least
+    /// `FRAME_MIN_SIZE`; 0 means no limit.
+    pub frame_max: u32,
+    /// After a frame with a bad frame-end, how many candidate frame
+    /// boundaries a connection tries when scanning forward to regain frame
+    /// alignment before it is closed. 0 turns resynchronizing off.
+    pub frame_resync_limit: u32,
+    /// Heartbeat interval in seconds offered in `Connection.Tune`. The
+    /// broker sends heartbeats at half the interval the client agrees to,
+    /// and drops a client it hears nothing from for two intervals; 0 means
+    /// none.
+    pub heartbeat: u16,
+    /// Open connections allowed per authenticated user; 0 means no limit.
+    pub max_connections_per_user: u32,
+    /// Open connections allowed per peer IP address; 0 means no limit.
+    pub max_connections_per_ip: u32,
+    /// Connections allowed between accept and `Connection.Open-Ok` at once;
+    /// listeners stop accepting while this many are pending. 0 means no limit.
+    pub max_pending_handshakes: usize,
+    /// How long a client may take from connecting to `Connection.Open`,
+    /// all handshake steps together; a connection still in its handshake
+    /// then is closed and its handshake slot given back.
+    pub handshake_timeout: Duration,
+    /// Failed handshakes in a row after which connections from the same
+    /// address are turned away for `handshake_failure_cooldown`; 0 disables it.
+    pub handshake_failure_limit: u32,
+    pub handshake_failure_cooldown: Duration,
+    /// Connections closed within `churn_min_lifetime` of being accepted
+    /// that, `churn_limit` of them from one address within `churn_window`,
+    /// get the address turned away for `churn_cooldown`; 0 disables it.
+    pub churn_limit: u32,
+    pub churn_min_lifetime: Duration,
+    pub churn_window: Duration,
+    pub churn_cooldown: Duration,
+    /// How long to wait for `Channel.Close-Ok` after the broker closes a channel.
+    pub channel_close_timeout: Duration,
+    /// How long a publish may take to send its content header and body
+    /// after its `Basic.Publish`; a channel that runs over is closed with
+    /// `UNEXPECTED_FRAME` and the partial message dropped. Zero waits
+    /// forever.
+    pub publish_timeout: Duration,
+    /// How long to wait for `Connection.Close-Ok` after the broker closes a
+    /// connection before dropping the socket anyway; zero does not wait.
+    pub connection_close_timeout: Duration,
+    /// How long a write to a client may stay blocked before the client is
+    /// taken for dead and its unacked messages requeued; zero waits forever.
+    pub write_timeout: Duration,
+    /// How long a consumer's full prefetch window may keep ready messages
+    /// waiting before the consumer is reported as slow; zero disables it.
+    pub slow_consumer_threshold: Duration,
+    /// How long routing a publish, writing a delivery, a message store call
+    /// or waiting for the broker's or a queue's lock may take before it is
+    /// logged as slow; zero disables it.
+    pub slow_operation_threshold: Duration,
+    /// Memory footprint of the messages queued in all virtual hosts above
+    /// which `GET /api/health` reports a memory alarm; 0 means no limit.
+    pub memory_high_watermark: u64,
+    /// Length of the windows over which each queue's consumer utilization
+    /// is measured; zero measures since the queue was declared.
+    pub consumer_utilization_window: Duration,
+    /// How often each queue's message totals are sampled for
+    /// `GET /api/queues/{vhost}/{name}/rates`; zero takes no samples.
+    pub rate_sample_interval: Duration,
+    /// Samples kept per queue, the oldest dropped first.
+    pub rate_samples: usize,
+    /// Most recent broker events kept for `GET /api/events`; 0 keeps none.
+    pub event_log_size: usize,
+    /// Largest message body accepted from publishers, however it is framed;
+    /// a content header announcing more is refused with `CONTENT_TOO_LARGE`
+    /// before any of the body arrives. 0 means no limit.
+    pub max_message_size: u64,
+    /// Most deliveries awaiting an ack across the whole broker. Once that
+    /// many are out, queues stop delivering to consumers that ack until
+    /// acks bring the count down; publishes, `Basic.Get` and no-ack
+    /// consumers carry on. 0 means no limit.
+    pub max_unacked_messages: u64,
+    /// Most frames per second an open connection may send, of any type;
+    /// beyond it the broker pauses reading from the socket until the rate
+    /// allows another frame. 0 means no limit.
+    pub max_frame_rate: u32,
+    /// Frames a connection may send at once before `max_frame_rate` paces
+    /// it; 0 allows one second's worth.
+    pub frame_rate_burst: u32,
+    /// Most dot-separated words a topic routing key or binding pattern may
+    /// have; 0 means no limit.
+    pub topic_max_words: usize,
+    /// Longest topic routing key or binding pattern accepted, in bytes; 0
+    /// means no limit beyond the short string's 255.
+    pub topic_max_length: usize,
+    /// Most times a message may be dead-lettered, counted over its `x-death`
+    /// header; a message due to die once more is dropped instead, which ends
+    /// cycles between queues dead-lettering to each other.
+    pub dead_letter_max_depth: u32,
+    /// What happens to publishes beyond a queue's `x-max-publish-rate`.
+    pub publish_rate_policy: RatePolicy,
+    /// Reject publishes whose `user-id` property differs from the
+    /// connection's authenticated user.
+    pub validate_user_id: bool,
+    /// Whether published messages get the broker's time as their
+    /// `timestamp` property: `off`, `if_absent` or `force`.
+    pub server_timestamp: ServerTimestamp,
+    /// What a method the broker does not implement closes: the
+    /// `connection`, as the spec has it, or only the `channel` it came on.
+    pub unknown_method_close: UnknownMethodClose,
+    /// Sockets AMQP clients connect to.
+    pub listeners: Vec<ListenerConfig>,
+    /// Options set on accepted TCP sockets.
+    pub socket: SocketOptions,
+    /// How deeply nested and how large the field tables clients send may
+    /// be; a table beyond them closes the connection.
+    pub table_limits: TableLimits,
+    /// Run the startup self-test before accepting clients; on by default in
+    /// debug builds only.
+    pub self_test: bool,
+    /// Whether persisted messages go to the `store` on disk or stay in memory.
+    pub storage: Storage,
+    /// Message store settings; without them no message, and none of the
+    /// topology clients declare, is persisted.
+    pub store: Option<StoreConfig>,
+    /// WebSocket listener settings; without them only plain TCP is served.
+    pub websocket: Option<WebSocketConfig>,
+    /// Management HTTP listener settings; without them there is no admin API.
+    pub management: Option<ManagementConfig>,
+    /// Virtual hosts created at startup besides the default `/`.
+    pub vhosts: Vec<String>,
+    /// Virtual host opened, by lowercase TLS SNI hostname, when a client that
+    /// sent the hostname asks `Connection.Open` for the default `/`.
+    pub sni_vhosts: BTreeMap<String, String>,
+    /// Settings of individual virtual hosts, by name.
+    pub vhost_settings: BTreeMap<String, VHostSettings>,
+    /// Exchanges, queues and bindings declared in `/` at startup.
+    pub topology: Topology,
+    /// Rules rewriting the routing keys of published messages, in the
+    /// order they apply; see `rewrite`.
+    pub routing_key_rewrites: Vec<RewriteRule>,
+    /// Users whose passwords clients must present; with none, any client
+    /// gets in under the name it gives.
+    pub users: Vec<UserConfig>,
+    /// SASL mechanisms clients may authenticate with. Of these, the ones
+    /// the auth provider supports are offered in `Connection.Start`, in the
+    /// provider's order; empty allows every mechanism the provider has.
+    pub sasl_mechanisms: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            default_queue_type: QueueType::Classic,
+            channel_max: 2047,
+            channel_warning_threshold: 0,
+            frame_max: 131072,
+            frame_resync_limit: 0,
+            heartbeat: 60,
+            max_connections_per_user: 0,
+            max_connections_per_ip: 0,
+            max_pending_handshakes: 256,
+            handshake_timeout: Duration::from_secs(10),
+            handshake_failure_limit: 10,
+            handshake_failure_cooldown: Duration::from_secs(5),
+            churn_limit: 0,
+            churn_min_lifetime: Duration::from_secs(1),
+            churn_window: Duration::from_secs(10),
+            churn_cooldown: Duration::from_secs(30),
+            channel_close_timeout: Duration::from_secs(30),
+            publish_timeout: Duration::from_secs(60),
+            connection_close_timeout: Duration::from_secs(3),
+            write_timeout: Duration::from_secs(30),
+            slow_consumer_threshold: Duration::from_secs(60),
+            slow_operation_threshold: Duration::from_millis(500),
+            memory_high_watermark: 0,
+            consumer_utilization_window: Duration::from_secs(60),
+            rate_sample_interval: Duration::from_secs(5),
+            rate_samples: 60,
+            event_log_size: 1000,
+            max_message_size: 128 * 1024 * 1024,
+            max_unacked_messages: 0,
+            max_frame_rate: 0,
+            frame_rate_burst: 0,
+            topic_max_words: 64,
+            topic_max_length: 0,
+            dead_letter_max_depth: 16,
+            publish_rate_policy: RatePolicy::Flow,
+            validate_user_id: false,
+            server_timestamp: ServerTimestamp::Off,
+            unknown_method_close: UnknownMethodClose::Connection,
+            listeners: vec![ListenerConfig::default()],
+            socket: SocketOptions::default(),
+            table_limits: TableLimits::default(),
+            self_test: cfg!(debug_assertions),
+            storage: Storage::Disk,
+            store: None,
+            websocket: None,
+            management: None,
+            vhosts: Vec::new(),
+            sni_vhosts: BTreeMap::new(),
+            vhost_settings: BTreeMap::new(),
+            topology: Topology::default(),
+            routing_key_rewrites: Vec::new(),
+            users: Vec::new(),
+            sasl_mechanisms: Vec::new(),
+        }
+    }
+}
+
+/// Resources declared when the broker starts.
+#[derive(Debug, Clone, Default)]
+pub struct Topology {
+    pub exchanges: Vec<ExchangeDeclare>,
+    pub queues: Vec<QueueDeclare>,
+    /// Bindings, each with the exchange it binds to.
+    pub bindings: Vec<(String, Binding)>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            ConfigError::Parse(e) => write!(f, "invalid config: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Loads settings from a TOML file, using defaults for missing keys.
+    pub fn load(path: impl AsRef<Path>) -> Result<Config, ConfigError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        Config::from_toml(&text)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Config, ConfigError> {
+        let raw: RawConfig = toml::from_str(text).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        let defaults = Config::default();
+        let default_queue_type = match raw.default_queue_type.as_deref() {
+            None => defaults.default_queue_type,
+            Some("classic") => QueueType::Classic,
+            Some("quorum") => QueueType::Quorum,
+            Some(other) => return Err(ConfigError::Parse(format!("unknown queue type '{}'", other))),
+        };
+        let publish_rate_policy = match raw.publish_rate_policy.as_deref() {
+            None => defaults.publish_rate_policy,
+            Some(policy) => rate_policy(policy, "publish rate")?,
+        };
+        let server_timestamp = match raw.server_timestamp.as_deref() {
+            None => defaults.server_timestamp,
+            Some("off") => ServerTimestamp::Off,
+            Some("if_absent") => ServerTimestamp::IfAbsent,
+            Some("force") => ServerTimestamp::Force,
+            Some(other) => return Err(ConfigError::Parse(format!("unknown server timestamp '{}'", other))),
+        };
+        let unknown_method_close = match raw.unknown_method_close.as_deref() {
+            None => defaults.unknown_method_close,
+            Some("connection") => UnknownMethodClose::Connection,
+            Some("channel") => UnknownMethodClose::Channel,
+            Some(other) => return Err(ConfigError::Parse(format!("invalid unknown_method_close '{}'", other))),
+        };
+        let storage = match raw.storage.as_deref() {
+            None | Some("disk") => Storage::Disk,
+            Some("memory") if raw.store.is_some() => {
+                return Err(ConfigError::Parse(
+                    "storage = \"memory\" cannot be combined with a [store] section".into(),
+                ))
+            }
+            Some("memory") => Storage::Memory,
+            Some(other) => return Err(ConfigError::Parse(format!("unknown storage '{}'", other))),
+        };
+        let frame_max = raw.frame_max.unwrap_or(defaults.frame_max);
+        if frame_max != 0 && frame_max < FRAME_MIN_SIZE {
+            return Err(ConfigError::Parse(format!(
+                "frame_max {} is below the minimum of {}",
+                frame_max, FRAME_MIN_SIZE
+            )));
+        }
+        let listeners = match raw.listeners {
+            Some(listeners) => listeners
+                .into_iter()
+                .map(RawListener::into_listener)
+                .collect::<Result<_, _>>()?,
+            None => defaults.listeners,
+        };
+        let mut vhost_settings = BTreeMap::new();
+        for (name, settings) in raw.vhost_settings {
+            let delivery_mode = match (settings.force_persistent, settings.force_transient) {
+                (true, true) => {
+                    return Err(ConfigError::Parse(format!(
+                        "vhost '{}' cannot force both persistent and transient messages",
+                        name
+                    )))
+                }
+                (true, false) => Some(DeliveryModeOverride::Persistent),
+                (false, true) => Some(DeliveryModeOverride::Transient),
+                (false, false) => None,
+            };
+            let quota_policy = match settings.quota_policy.as_deref() {
+                None => RatePolicy::default(),
+                Some(policy) => rate_policy(policy, "quota")?,
+            };
+            let settings = VHostSettings {
+                delivery_mode,
+                max_messages: settings.max_messages,
+                max_bytes: settings.max_bytes,
+                quota_policy,
+                max_queues: settings.max_queues,
+                max_exchanges: settings.max_exchanges,
+                max_bindings: settings.max_bindings,
+            };
+            vhost_settings.insert(vhost_name(name)?, settings);
+        }
+        let vhosts = raw.vhosts.into_iter().map(vhost_name).collect::<Result<_, _>>()?;
+        // Mechanisms go out space-separated in `Connection.Start`.
+        if let Some(mechanism) = raw
+            .sasl_mechanisms
+            .iter()
+            .find(|mechanism| mechanism.is_empty() || mechanism.contains(' '))
+        {
+            return Err(ConfigError::Parse(format!(
+                "invalid SASL mechanism name '{}'",
+                mechanism
+            )));
+        }
+        let sni_vhosts = raw
+            .sni_vhosts
+            .into_iter()
+            .map(|(hostname, vhost)| Ok((hostname.to_ascii_lowercase(), vhost_name(vhost)?)))
+            .collect::<Result<_, ConfigError>>()?;
+        let routing_key_rewrites = raw
+            .routing_key_rewrites
+            .into_iter()
+            .map(RawRewrite::into_rule)
+            .collect::<Result<_, _>>()?;
+        let users = raw
+            .users
+            .into_iter()
+            .map(RawUser::into_user)
+            .collect::<Result<_, _>>()?;
+        Ok(Config {
+            default_queue_type,
+            channel_max: raw.channel_max.unwrap_or(defaults.channel_max),
+            channel_warning_threshold: raw
+                .channel_warning_threshold
+                .unwrap_or(defaults.channel_warning_threshold),
+            frame_max,
+            frame_resync_limit: raw.frame_resync_limit.unwrap_or(defaults.frame_resync_limit),
+            heartbeat: raw.heartbeat.unwrap_or(defaults.heartbeat),
+            max_connections_per_user: raw.max_connections_per_user.unwrap_or(defaults.max_connections_per_user),
+            max_connections_per_ip: raw.max_connections_per_ip.unwrap_or(defaults.max_connections_per_ip),
+            max_pending_handshakes: raw.max_pending_handshakes.unwrap_or(defaults.max_pending_handshakes),
+            handshake_timeout: raw
+                .handshake_timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.handshake_timeout),
+            handshake_failure_limit: raw.handshake_failure_limit.unwrap_or(defaults.handshake_failure_limit),
+            handshake_failure_cooldown: raw
+                .handshake_failure_cooldown_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.handshake_failure_cooldown),
+            churn_limit: raw.churn_limit.unwrap_or(defaults.churn_limit),
+            churn_min_lifetime: raw
+                .churn_min_lifetime_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.churn_min_lifetime),
+            churn_window: raw
+                .churn_window_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.churn_window),
+            churn_cooldown: raw
+                .churn_cooldown_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.churn_cooldown),
+            channel_close_timeout: raw
+                .channel_close_timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.channel_close_timeout),
+            publish_timeout: raw
+                .publish_timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.publish_timeout),
+            connection_close_timeout: raw
+                .connection_close_timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.connection_close_timeout),
+            write_timeout: raw
+                .write_timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.write_timeout),
+            slow_consumer_threshold: raw
+                .slow_consumer_threshold_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.slow_consumer_threshold),
+            slow_operation_threshold: raw
+                .slow_operation_threshold_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.slow_operation_threshold),
+            memory_high_watermark: raw.memory_high_watermark.unwrap_or(defaults.memory_high_watermark),
+            consumer_utilization_window: raw
+                .consumer_utilization_window_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.consumer_utilization_window),
+            rate_sample_interval: raw
+                .rate_sample_interval_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.rate_sample_interval),
+            rate_samples: raw.rate_samples.unwrap_or(defaults.rate_samples),
+            event_log_size: raw.event_log_size.unwrap_or(defaults.event_log_size),
+            max_message_size: raw.max_message_size.unwrap_or(defaults.max_message_size),
+            max_unacked_messages: raw.max_unacked_messages.unwrap_or(defaults.max_unacked_messages),
+            max_frame_rate: raw.max_frame_rate.unwrap_or(defaults.max_frame_rate),
+            frame_rate_burst: raw.frame_rate_burst.unwrap_or(defaults.frame_rate_burst),
+            topic_max_words: raw.topic_max_words.unwrap_or(defaults.topic_max_words),
+            topic_max_length: raw.topic_max_length.unwrap_or(defaults.topic_max_length),
+            dead_letter_max_depth: raw.dead_letter_max_depth.unwrap_or(defaults.dead_letter_max_depth),
+            publish_rate_policy,
+            validate_user_id: raw.validate_user_id.unwrap_or(defaults.validate_user_id),
+            server_timestamp,
+            unknown_method_close,
+            listeners,
+            socket: raw.socket.map_or(defaults.socket, RawSocket::into_options),
+            table_limits: raw.limits.map_or(Ok(defaults.table_limits), RawLimits::into_limits)?,
+            self_test: raw.self_test.unwrap_or(defaults.self_test),
+            storage,
+            store: raw.store.map(RawStore::into_config).transpose()?,
+            websocket: raw.websocket.map(|ws| {
+                let defaults = WebSocketConfig::default();
+                WebSocketConfig {
+                    bind: ws.bind.unwrap_or(defaults.bind),
+                    path: ws.path.unwrap_or(defaults.path),
+                }
+            }),
+            management: raw.management.map(|management| ManagementConfig {
+                bind: management.bind.unwrap_or_else(|| ManagementConfig::default().bind),
+            }),
+            vhosts,
+            sni_vhosts,
+            vhost_settings,
+            topology: raw.topology.into_topology(),
+            routing_key_rewrites,
+            users,
+            sasl_mechanisms: raw.sasl_mechanisms,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    default_queue_type: Option<String>,
+    channel_max: Option<u16>,
+    channel_warning_threshold: Option<u16>,
+    frame_max: Option<u32>,
+    frame_resync_limit: Option<u32>,
+    heartbeat: Option<u16>,
+    max_connections_per_user: Option<u32>,
+    max_connections_per_ip: Option<u32>,
+    max_pending_handshakes: Option<usize>,
+    handshake_timeout_ms: Option<u64>,
+    handshake_failure_limit: Option<u32>,
+    handshake_failure_cooldown_ms: Option<u64>,
+    churn_limit: Option<u32>,
+    churn_min_lifetime_ms: Option<u64>,
+    churn_window_ms: Option<u64>,
+    churn_cooldown_ms: Option<u64>,
+    channel_close_timeout_ms: Option<u64>,
+    publish_timeout_ms: Option<u64>,
+    connection_close_timeout_ms: Option<u64>,
+    write_timeout_ms: Option<u64>,
+    slow_consumer_threshold_ms: Option<u64>,
+    slow_operation_threshold_ms: Option<u64>,
+    memory_high_watermark: Option<u64>,
+    consumer_utilization_window_ms: Option<u64>,
+    rate_sample_interval_ms: Option<u64>,
+    rate_samples: Option<usize>,
+    event_log_size: Option<usize>,
+    max_message_size: Option<u64>,
+    max_unacked_messages: Option<u64>,
+    max_frame_rate: Option<u32>,
+    frame_rate_burst: Option<u32>,
+    topic_max_words: Option<usize>,
+    topic_max_length: Option<usize>,
+    dead_letter_max_depth: Option<u32>,
+    publish_rate_policy: Option<String>,
+    validate_user_id: Option<bool>,
+    server_timestamp: Option<String>,
+    unknown_method_close: Option<String>,
+    listeners: Option<Vec<RawListener>>,
+    socket: Option<RawSocket>,
+    limits: Option<RawLimits>,
+    self_test: Option<bool>,
+    storage: Option<String>,
+    store: Option<RawStore>,
+    websocket: Option<RawWebSocket>,
+    management: Option<RawManagement>,
+    #[serde(default)]
+    vhosts: Vec<String>,
+    #[serde(default)]
+    sasl_mechanisms: Vec<String>,
+    #[serde(default)]
+    sni_vhosts: BTreeMap<String, String>,
+    #[serde(default)]
+    vhost_settings: BTreeMap<String, RawVHostSettings>,
+    #[serde(default)]
+    topology: RawTopology,
+    #[serde(default)]
+    routing_key_rewrites: Vec<RawRewrite>,
+    #[serde(default)]
+    users: Vec<RawUser>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawVHostSettings {
+    #[serde(default)]
+    force_persistent: bool,
+    #[serde(default)]
+    force_transient: bool,
+    max_messages: Option<u64>,
+    max_bytes: Option<u64>,
+    quota_policy: Option<String>,
+    max_queues: Option<usize>,
+    max_exchanges: Option<usize>,
+    max_bindings: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawListener {
+    address: Option<String>,
+    path: Option<PathBuf>,
+    #[serde(default)]
+    tls: bool,
+}
+
+impl RawListener {
+    fn into_listener(self) -> Result<ListenerConfig, ConfigError> {
+        if self.tls {
+            // TLS is set up by whoever calls `handle_tls_connection`.
+            return Err(ConfigError::Parse(
+                "listeners cannot terminate TLS themselves; put a TLS proxy in front".into(),
+            ));
+        }
+        match (self.address, self.path) {
+            (Some(address), None) => Ok(ListenerConfig::Tcp(address)),
+            (None, Some(path)) => Ok(ListenerConfig::Unix(path)),
+            _ => Err(ConfigError::Parse(
+                "each listener needs exactly one of address and path".into(),
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSocket {
+    nodelay: Option<bool>,
+    /// 0 leaves keepalive off.
+    keepalive_ms: Option<u64>,
+    send_buffer_size: Option<usize>,
+    recv_buffer_size: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLimits {
+    max_table_depth: Option<usize>,
+    max_table_bytes: Option<u32>,
+}
+
+impl RawLimits {
+    fn into_limits(self) -> Result<TableLimits, ConfigError> {
+        let defaults = TableLimits::default();
+        let limits = TableLimits {
+            max_depth: self.max_table_depth.unwrap_or(defaults.max_depth),
+            max_bytes: self.max_table_bytes.unwrap_or(defaults.max_bytes),
+        };
+        // Connection.Start-Ok always carries a table, if an empty one.
+        if limits.max_depth == 0 {
+            return Err(ConfigError::Parse("max_table_depth must be at least 1".into()));
+        }
+        Ok(limits)
+    }
+}
+
+impl RawSocket {
+    fn into_options(self) -> SocketOptions {
+        SocketOptions {
+            nodelay: self.nodelay.unwrap_or(SocketOptions::default().nodelay),
+            keepalive: self.keepalive_ms.filter(|&ms| ms > 0).map(Duration::from_millis),
+            send_buffer_size: self.send_buffer_size,
+            recv_buffer_size: self.recv_buffer_size,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawStore {
+    data_dir: PathBuf,
+    segment_max_bytes: Option<u64>,
+    compaction_interval_secs: Option<u64>,
+    compaction_garbage_ratio: Option<f64>,
+    compression_threshold: Option<u64>,
+    on_checksum_mismatch: Option<String>,
+}
+
+impl RawStore {
+    fn into_config(self) -> Result<StoreConfig, ConfigError> {
+        let mut config = StoreConfig::new(self.data_dir);
+        if let Some(bytes) = self.segment_max_bytes {
+            config.segment_max_bytes = bytes;
+        }
+        if let Some(secs) = self.compaction_interval_secs {
+            config.compaction_interval = Duration::from_secs(secs);
+        }
+        if let Some(ratio) = self.compaction_garbage_ratio {
+            config.compaction_garbage_ratio = ratio;
+        }
+        if let Some(threshold) = self.compression_threshold {
+            config.compression_threshold = threshold;
+        }
+        if let Some(policy) = self.on_checksum_mismatch {
+            config.on_checksum_mismatch = ChecksumMismatch::parse(&policy)
+                .ok_or_else(|| ConfigError::Parse(format!("invalid on_checksum_mismatch '{}'", policy)))?;
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawWebSocket {
+    bind: Option<String>,
+    path: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawManagement {
+    bind: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawUser {
+    name: String,
+    password: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    vhosts: Option<Vec<String>>,
+}
+
+impl RawUser {
+    fn into_user(self) -> Result<UserConfig, ConfigError> {
+        let vhosts = match self.vhosts {
+            Some(vhosts) => vhosts.into_iter().map(vhost_name).collect::<Result<_, _>>()?,
+            None => vec![DEFAULT_VHOST.into()],
+        };
+        Ok(UserConfig {
+            name: self.name,
+            password: self.password,
+            tags: self.tags,
+            vhosts,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRewrite {
+    exchange: Option<String>,
+    routing_key: Option<String>,
+    transform: String,
+    prefix: Option<String>,
+}
+
+impl RawRewrite {
+    fn into_rule(self) -> Result<RewriteRule, ConfigError> {
+        let transform = match (self.transform.as_str(), self.prefix) {
+            ("lowercase", None) => KeyTransform::Lowercase,
+            ("uppercase", None) => KeyTransform::Uppercase,
+            ("strip_prefix", Some(prefix)) => KeyTransform::StripPrefix(prefix),
+            ("strip_prefix", None) => return Err(ConfigError::Parse("strip_prefix rewrite needs a prefix".into())),
+            ("lowercase" | "uppercase", Some(_)) => {
+                return Err(ConfigError::Parse(format!(
+                    "{} rewrite takes no prefix",
+                    self.transform
+                )))
+            }
+            (other, _) => return Err(ConfigError::Parse(format!("invalid rewrite transform '{}'", other))),
+        };
+        Ok(RewriteRule {
+            exchange: self.exchange.unwrap_or_else(|| "*".into()),
+            routing_key: self.routing_key.unwrap_or_else(|| "*".into()),
+            transform,
+        })
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RawTopology {
+    #[serde(default)]
+    exchanges: Vec<RawExchange>,
+    #[serde(default)]
+    queues: Vec<RawQueue>,
+    #[serde(default)]
+    bindings: Vec<RawBinding>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawExchange {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    durable: bool,
+    #[serde(default)]
+    auto_delete: bool,
+    #[serde(default)]
+    internal: bool,
+    #[serde(default)]
+    arguments: BTreeMap<String, toml::Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawQueue {
+    name: String,
+    #[serde(default)]
+    durable: bool,
+    #[serde(default)]
+    auto_delete: bool,
+    #[serde(default)]
+    arguments: BTreeMap<String, toml::Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawBinding {
+    exchange: String,
+    queue: String,
+    #[serde(default)]
+    routing_key: String,
+    #[serde(default)]
+    arguments: BTreeMap<String, toml::Value>,
+}
+
+impl RawTopology {
+    fn into_topology(self) -> Topology {
+        Topology {
+            exchanges: self
+                .exchanges
+                .into_iter()
+                .map(|e| ExchangeDeclare {
+                    exchange: e.name,
+                    kind: e.kind,
+                    passive: false,
+                    durable: e.durable,
+                    auto_delete: e.auto_delete,
+                    internal: e.internal,
+                    arguments: field_table(e.arguments),
+                })
+                .collect(),
+            queues: self
+                .queues
+                .into_iter()
+                .map(|q| QueueDeclare {
+                    queue: q.name,
+                    durable: q.durable,
+                    auto_delete: q.auto_delete,
+                    arguments: field_table(q.arguments),
+                    ..Default::default()
+                })
+                .collect(),
+            bindings: self
+                .bindings
+                .into_iter()
+                .map(|b| {
+                    let binding = Binding {
+                        queue: b.queue,
+                        routing_key: b.routing_key,
+                        arguments: field_table(b.arguments),
+                    };
+                    (b.exchange, binding)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Virtual host `name` from the file, normalized as clients' names are.
+fn vhost_name(name: String) -> Result<String, ConfigError> {
+    vhost::normalize_name(&name).ok_or_else(|| ConfigError::Parse(format!("invalid vhost name '{}'", name)))
+}
+
+fn rate_policy(policy: &str, what: &str) -> Result<RatePolicy, ConfigError> {
+    match policy {
+        "flow" => Ok(RatePolicy::Flow),
+        "nack" => Ok(RatePolicy::Nack),
+        other => Err(ConfigError::Parse(format!("unknown {} policy '{}'", what, other))),
+    }
+}
+
+fn field_table(values: BTreeMap<String, toml::Value>) -> FieldTable {
+    let mut table = FieldTable::new();
+    for (key, value) in values {
+        table.insert(key, field_value(value));
+    }
+    table
+}
+
+fn field_value(value: toml::Value) -> FieldValue {
+    match value {
+        toml::Value::String(s) => FieldValue::LongString(s.into_bytes()),
+        toml::Value::Integer(i) => FieldValue::LongLongInt(i),
+        toml::Value::Float(f) => FieldValue::Double(f),
+        toml::Value::Boolean(b) => FieldValue::Boolean(b),
+        toml::Value::Datetime(d) => FieldValue::LongString(d.to_string().into_bytes()),
+        toml::Value::Array(values) => FieldValue::FieldArray(values.into_iter().map(field_value).collect()),
+        toml::Value::Table(values) => FieldValue::FieldTable(field_table(values.into_iter().collect())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_file_uses_defaults() {
+        let config = Config::from_toml("").unwrap();
+        assert_eq!(config.channel_max, Config::default().channel_max);
+        assert!(config.store.is_none());
+        assert!(config.topology.queues.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_key_is_rejected() {
+        assert!(matches!(Config::from_toml("chanel_max = 1"), Err(ConfigError::Parse(_))));
+    }
+
+    #[test]
+    fn test_frame_max_below_minimum_is_rejected() {
+        assert!(matches!(Config::from_toml("frame_max = 1024"), Err(ConfigError::Parse(_))));
+        assert_eq!(Config::from_toml("frame_max = 4096").unwrap().frame_max, 4096);
+    }
+
+    #[test]
+    fn test_vhost_cannot_force_both_delivery_modes() {
+        let text = "[vhost_settings.staging]\nforce_persistent = true\nforce_transient = true";
+        assert!(matches!(Config::from_toml(text), Err(ConfigError::Parse(_))));
+        let config = Config::from_toml("[vhost_settings.staging]\nforce_persistent = true").unwrap();
+        assert_eq!(
+            config.vhost_settings["staging"].delivery_mode,
+            Some(DeliveryModeOverride::Persistent)
+        );
+    }
+
+    #[test]
+    fn test_sasl_mechanisms_are_names_without_spaces() {
+        let config = Config::from_toml("sasl_mechanisms = [\"EXTERNAL\", \"AMQPLAIN\"]").unwrap();
+        assert_eq!(
+            config.sasl_mechanisms,
+            vec!["EXTERNAL".to_string(), "AMQPLAIN".to_string()]
+        );
+        assert!(Config::default().sasl_mechanisms.is_empty());
+        for text in ["sasl_mechanisms = [\"\"]", "sasl_mechanisms = [\"SCRAM SHA-256\"]"] {
+            assert!(
+                matches!(Config::from_toml(text), Err(ConfigError::Parse(_))),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_vhost_names_are_normalized() {
+        let text = "vhosts = [\"stag%69ng\"]\n[vhost_settings.\"%2F\"]\nmax_messages = 1";
+        let config = Config::from_toml(text).unwrap();
+        assert_eq!(config.vhosts, vec!["staging".to_string()]);
+        assert!(config.vhost_settings.contains_key(DEFAULT_VHOST));
+        for text in [
+            "vhosts = [\"\"]",
+            "vhosts = [\"two words\"]",
+            "[sni_vhosts]\n\"a.example.com\" = \"%zz\"",
+            "[[users]]\nname = \"app\"\npassword = \"pw\"\nvhosts = [\"%\"]",
+        ] {
+            assert!(
+                matches!(Config::from_toml(text), Err(ConfigError::Parse(_))),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_store_compression_threshold() {
+        let text = "[store]\ndata_dir = \"/var/lib/haymq\"\ncompression_threshold = 65536";
+        let store = Config::from_toml(text).unwrap().store.unwrap();
+        assert_eq!(store.compression_threshold, 65536);
+        let store = Config::from_toml("[store]\ndata_dir = \"/var/lib/haymq\"")
+            .unwrap()
+            .store
+            .unwrap();
+        assert_eq!(store.compression_threshold, 0);
+    }
+
+    #[test]
+    fn test_store_checksum_mismatch_policy() {
+        let store = Config::from_toml("[store]\ndata_dir = \"/var/lib/haymq\"")
+            .unwrap()
+            .store
+            .unwrap();
+        assert_eq!(store.on_checksum_mismatch, ChecksumMismatch::Fail);
+        let text = "[store]\ndata_dir = \"/var/lib/haymq\"\non_checksum_mismatch = \"skip\"";
+        let store = Config::from_toml(text).unwrap().store.unwrap();
+        assert_eq!(store.on_checksum_mismatch, ChecksumMismatch::Skip);
+        let text = "[store]\ndata_dir = \"/var/lib/haymq\"\non_checksum_mismatch = \"ignore\"";
+        assert!(Config::from_toml(text).is_err());
+    }
+
+    #[test]
+    fn test_routing_key_rewrites() {
+        let text = "[[routing_key_rewrites]]\nexchange = \"orders\"\ntransform = \"lowercase\"\n\n\
+                    [[routing_key_rewrites]]\nrouting_key = \"legacy.*\"\ntransform = \"strip_prefix\"\n\
+                    prefix = \"legacy.\"";
+        let rules = Config::from_toml(text).unwrap().routing_key_rewrites;
+        assert_eq!(
+            rules,
+            [
+                RewriteRule {
+                    exchange: "orders".into(),
+                    routing_key: "*".into(),
+                    transform: KeyTransform::Lowercase,
+                },
+                RewriteRule {
+                    exchange: "*".into(),
+                    routing_key: "legacy.*".into(),
+                    transform: KeyTransform::StripPrefix("legacy.".into()),
+                },
+            ]
+        );
+        assert!(Config::from_toml("[[routing_key_rewrites]]\ntransform = \"strip_prefix\"").is_err());
+        assert!(Config::from_toml("[[routing_key_rewrites]]\ntransform = \"reverse\"").is_err());
+    }
+
+    #[test]
+    fn test_unknown_method_close() {
+        assert_eq!(Config::default().unknown_method_close, UnknownMethodClose::Connection);
+        let config = Config::from_toml("unknown_method_close = \"channel\"").unwrap();
+        assert_eq!(config.unknown_method_close, UnknownMethodClose::Channel);
+        assert!(Config::from_toml("unknown_method_close = \"ignore\"").is_err());
+    }
+
+    #[test]
+    fn test_server_timestamp() {
+        assert_eq!(Config::default().server_timestamp, ServerTimestamp::Off);
+        let config = Config::from_toml("server_timestamp = \"force\"").unwrap();
+        assert_eq!(config.server_timestamp, ServerTimestamp::Force);
+        assert!(Config::from_toml("server_timestamp = \"always\"").is_err());
+    }
+
+    #[test]
+    fn test_vhost_quota() {
+        let text = "[vhost_settings.staging]\nmax_messages = 10\nquota_policy = \"nack\"\nmax_queues = 5";
+        let settings = &Config::from_toml(text).unwrap().vhost_settings["staging"];
+        assert_eq!(settings.max_messages, Some(10));
+        assert_eq!((settings.max_queues, settings.max_exchanges), (Some(5), None));
+        assert_eq!(settings.max_bytes, None);
+        assert_eq!(settings.quota_policy, RatePolicy::Nack);
+        let text = "[vhost_settings.staging]\nquota_policy = \"drop\"";
+        assert!(matches!(Config::from_toml(text), Err(ConfigError::Parse(_))));
+    }
+
+    #[test]
+    fn test_users() {
+        let text = "[[users]]\nname = \"admin\"\npassword = \"pw\"\ntags = [\"administrator\"]\n\n\
+                    [[users]]\nname = \"app\"\npassword = \"pw\"\nvhosts = []";
+        let users = Config::from_toml(text).unwrap().users;
+        assert_eq!(users[0].tags, vec!["administrator".to_string()]);
+        assert_eq!(users[0].vhosts, vec![DEFAULT_VHOST.to_string()]);
+        assert!(users[1].vhosts.is_empty());
+    }
+
+    #[test]
+    fn test_listeners() {
+        assert_eq!(
+            Config::from_toml("").unwrap().listeners,
+            vec![ListenerConfig::default()]
+        );
+        let text = "[[listeners]]\naddress = \"[::1]:5673\"\n[[listeners]]\npath = \"/tmp/amqp.sock\"";
+        assert_eq!(
+            Config::from_toml(text).unwrap().listeners,
+            vec![
+                ListenerConfig::Tcp("[::1]:5673".into()),
+                ListenerConfig::Unix("/tmp/amqp.sock".into())
+            ]
+        );
+        for text in [
+            "[[listeners]]",
+            "[[listeners]]\naddress = \"[::1]:5673\"\npath = \"/tmp/amqp.sock\"",
+            "[[listeners]]\naddress = \"[::1]:5671\"\ntls = true",
+        ] {
+            assert!(
+                matches!(Config::from_toml(text), Err(ConfigError::Parse(_))),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_socket_options() {
+        assert_eq!(Config::from_toml("").unwrap().socket, SocketOptions::default());
+        assert!(Config::default().socket.nodelay);
+        let text = "[socket]\nnodelay = false\nkeepalive_ms = 45000\nrecv_buffer_size = 65536";
+        assert_eq!(
+            Config::from_toml(text).unwrap().socket,
+            SocketOptions {
+                nodelay: false,
+                keepalive: Some(Duration::from_secs(45)),
+                send_buffer_size: None,
+                recv_buffer_size: Some(65536),
+            }
+        );
+        let off = Config::from_toml("[socket]\nkeepalive_ms = 0").unwrap();
+        assert_eq!(off.socket.keepalive, None);
+    }
+
+    #[test]
+    fn test_table_limits() {
+        assert_eq!(Config::from_toml("").unwrap().table_limits, TableLimits::default());
+        let limits = Config::from_toml("[limits]\nmax_table_depth = 4").unwrap().table_limits;
+        assert_eq!(
+            limits,
+            TableLimits {
+                max_depth: 4,
+                max_bytes: TableLimits::default().max_bytes,
+            }
+        );
+        assert!(Config::from_toml("[limits]\nmax_table_depth = 0").is_err());
+        assert!(Config::from_toml("[limits]\nmax_table_bytes = -1").is_err());
+    }
+
+    #[test]
+    fn test_sni_hostnames_are_lowercased() {
+        let config = Config::from_toml("[sni_vhosts]\n\"Staging.Example.com\" = \"staging\"").unwrap();
+        assert_eq!(
+            config.sni_vhosts.get("staging.example.com").map(String::as_str),
+            Some("staging")
+        );
+    }
+}
@@ -1,46 +1,5075 @@
-use tokio::net::TcpStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use log::{info, warn};
-use crate::protocol::{parse_amqp_header, parse_amqp_frame};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
-pub async fn handle_connection(mut socket: TcpStream) -> Result<(), Box<dyn std::error::Error>> {
+use crate::auth::SaslStep;
+use crate::broker::Broker;
+use crate::channel::{Channel, ChannelState, InterceptedPublish};
+use crate::error::AmqpError;
+use crate::events::BrokerEvent;
+use crate::field_table::{FieldTable, FieldValue};
+use crate::intercept::PublishCtx;
+use crate::latency::Operation;
+use crate::locale;
+use crate::methods::{self, Method, UnknownMethodClose, CLASS_CHANNEL, CLASS_CONNECTION};
+use crate::properties::ContentHeader;
+use crate::queue::{Delivery, DeliverySender};
+use crate::registry::{
+    ConnectionInfo, ConnectionKey, ConnectionPermit, FrameCounters, FrameCounts, HandshakeSlot, Negotiated,
+};
+use crate::protocol::{
+    frame_len, parse_amqp_header, parse_amqp_frame, AmqpFrame, HeaderError, FRAME_BODY, FRAME_END, FRAME_HEADER,
+    FRAME_HEARTBEAT, FRAME_METHOD, FRAME_MIN_SIZE, PROTOCOL_HEADER,
+};
+use crate::rate_limit::TokenBucket;
+use crate::reply_codes;
+use crate::trace::{Direction, FrameTrace};
+use crate::vhost::{self, DEFAULT_VHOST};
+use log::{debug, info, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+/// Optional protocol extensions the broker announces in `Connection.Start`.
+const SERVER_CAPABILITIES: &[&str] = &[
+    "publisher_confirms",
+    "basic.nack",
+    "per_consumer_qos",
+    "consumer_cancel_notify",
+    "connection.blocked",
+];
+
+pub async fn handle_connection<S>(socket: S, broker: Arc<Broker>) -> Result<(), ConnectionFailure>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    handle_connection_from(socket, None, broker).await
+}
+
+/// Serves a connection from `peer`, counting it against the per-IP limit.
+pub async fn handle_connection_from<S>(
+    socket: S,
+    peer: Option<IpAddr>,
+    broker: Arc<Broker>,
+) -> Result<(), ConnectionFailure>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    handle_tls_connection(socket, peer, None, broker).await
+}
+
+/// Serves a connection a listener accepted holding `slot`, which is given
+/// back once the handshake is over.
+pub async fn handle_accepted_connection<S>(
+    socket: S,
+    peer: Option<IpAddr>,
+    slot: HandshakeSlot,
+    broker: Arc<Broker>,
+) -> Result<(), ConnectionFailure>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    serve_connection(socket, peer, None, slot, broker).await
+}
+
+/// Serves a connection whose TLS session, set up by the caller, carried
+/// `server_name` in its SNI extension. The name picks the virtual host from
+/// `Config::sni_vhosts` for clients opening the default `/`.
+pub async fn handle_tls_connection<S>(
+    socket: S,
+    peer: Option<IpAddr>,
+    server_name: Option<String>,
+    broker: Arc<Broker>,
+) -> Result<(), ConnectionFailure>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    serve_connection(socket, peer, server_name, HandshakeSlot::default(), broker).await
+}
+
+async fn serve_connection<S>(
+    socket: S,
+    peer: Option<IpAddr>,
+    server_name: Option<String>,
+    slot: HandshakeSlot,
+    broker: Arc<Broker>,
+) -> Result<(), ConnectionFailure>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let accepted = Instant::now();
+    let result = run_connection(socket, peer, server_name, slot, broker.clone()).await;
+    if let Some(ip) = peer {
+        broker.connections().record_close(ip, accepted.elapsed());
+    }
+    result
+}
+
+async fn run_connection<S>(
+    mut socket: S,
+    peer: Option<IpAddr>,
+    server_name: Option<String>,
+    slot: HandshakeSlot,
+    broker: Arc<Broker>,
+) -> Result<(), ConnectionFailure>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // One deadline covers the header and every handshake method after it.
+    let handshake_timeout = broker.config().handshake_timeout;
+    let handshake_deadline = Instant::now() + handshake_timeout;
+    let handshake_failed = |broker: &Broker| {
+        if let Some(ip) = peer {
+            broker.connections().record_handshake(ip, false);
+        }
+    };
     let mut header_buf = [0u8; 8];
-    socket.read_exact(&mut header_buf).await?;
+    match tokio::time::timeout_at(handshake_deadline, socket.read_exact(&mut header_buf)).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => {
+            handshake_failed(&broker);
+            return Err(ConnectionFailure::before_open(e));
+        }
+        Err(_) => {
+            handshake_failed(&broker);
+            warn!("No AMQP header within {:?}, closing the connection", handshake_timeout);
+            return Err(ConnectionFailure::before_open(timed_out()));
+        }
+    }
     match parse_amqp_header(&header_buf) {
-        Ok(_) => info!("AMQP header received and validated"),
+        Ok(version) => info!("AMQP {} header received and validated", version),
         Err(e) => {
-            warn!("Invalid AMQP header: {:?}", e);
-            // You might want to drop the connection or send an error
-            socket.write_all(b"Invalid AMQP header").await?;
-            return Err(e.into());
+            handshake_failed(&broker);
+            warn!("Invalid AMQP header: {}", e);
+            // As the spec asks, answer with the header of the version the
+            // broker does speak, then close.
+            socket
+                .write_all(&PROTOCOL_HEADER)
+                .await
+                .map_err(ConnectionFailure::before_open)?;
+            return Err(ConnectionFailure::before_open(ConnectionError::InvalidHeader(e)));
+        }
+    }
+
+    let mut permits = Vec::new();
+    if let Some(ip) = peer {
+        match broker.connections().acquire(ConnectionKey::Ip(ip)) {
+            Some(permit) => permits.push(permit),
+            None => {
+                let e = AmqpError::connection(
+                    reply_codes::CONNECTION_FORCED,
+                    format!("CONNECTION_FORCED - too many connections from {}", ip),
+                    0,
+                    0,
+                );
+                warn!("Refusing connection: {}", e.reply_text());
+                broker.connection_exception(None, e.reply_code(), e.reply_text());
+                if let Some(close) = connection_close(&e) {
+                    let refused = async {
+                        socket.write_all(&AmqpFrame::method(0, &close).encode()).await?;
+                        socket.flush().await
+                    };
+                    refused.await.map_err(ConnectionFailure::before_open)?;
+                }
+                return Err(ConnectionFailure::before_open(e));
+            }
+        }
+    }
+
+    let (deliveries, mut inbox) = mpsc::unbounded_channel();
+    // Until Tune-Ok arrives, frames are held to the size the broker offers.
+    let tuning = Tuning {
+        channel_max: 0,
+        frame_max: broker.config().frame_max,
+        heartbeat: 0,
+    };
+    let mut conn = Connection {
+        id: broker.next_connection_id(),
+        permits,
+        socket,
+        broker,
+        deliveries,
+        channels: HashMap::new(),
+        too_many_channels: false,
+        state: ConnectionState::AwaitingStartOk,
+        tuning,
+        vhost: DEFAULT_VHOST.into(),
+        user: None,
+        peer,
+        server_name,
+        client_properties: FieldTable::new(),
+        name: None,
+        capabilities: Vec::new(),
+        locale: locale::DEFAULT_LOCALE.into(),
+        mechanism: String::new(),
+        trace: Arc::default(),
+        frames: Arc::default(),
+        buf: vec![0u8; 4096],
+        pending: Vec::new(),
+        resync: None,
+    };
+    let mut opened = false;
+    let handshake = match tokio::time::timeout_at(handshake_deadline, conn.handshake()).await {
+        Ok(handshake) => handshake,
+        Err(_) => {
+            warn!(
+                "Handshake did not finish within {:?}, closing the connection",
+                handshake_timeout
+            );
+            Err(ConnectionError::Io(timed_out()))
+        }
+    };
+    if let Some(ip) = peer {
+        conn.broker
+            .connections()
+            .record_handshake(ip, matches!(handshake, Ok(true)));
+    }
+    drop(slot);
+    // The connection stays listed until its socket is dropped, so a
+    // shutdown can wait for it to finish closing.
+    let mut _listed = None;
+    let result = match handshake {
+        Ok(true) => {
+            opened = true;
+            conn.broker.emit(BrokerEvent::ConnectionOpened {
+                connection: conn.id,
+                user: conn.user.clone(),
+                vhost: conn.vhost.clone(),
+            });
+            _listed = Some(conn.broker.connections().list(
+                ConnectionInfo {
+                    id: conn.id,
+                    user: conn.user.clone(),
+                    vhost: conn.vhost.clone(),
+                    peer: conn.peer,
+                    client_properties: conn.client_properties.clone(),
+                    name: conn.name.clone(),
+                    capabilities: conn.capabilities.clone(),
+                    negotiated: Negotiated {
+                        channel_max: conn.tuning.channel_max,
+                        frame_max: conn.tuning.frame_max,
+                        heartbeat: conn.tuning.heartbeat,
+                        mechanism: conn.mechanism.clone(),
+                        locale: conn.locale.clone(),
+                    },
+                    frames: FrameCounts::default(),
+                    channels: 0,
+                },
+                conn.trace.clone(),
+                conn.frames.clone(),
+            ));
+            let drained = conn.broker.watch_vhost(&conn.vhost).expect("opened vhost exists");
+            conn.run(&mut inbox, drained).await
+        }
+        Ok(false) => return Ok(()),
+        Err(e) => Err(e),
+    };
+    for channel in conn.channels.values_mut() {
+        channel.close(&conn.broker);
+        conn.broker.emit(BrokerEvent::ChannelClosed {
+            connection: conn.id,
+            channel: channel.id,
+        });
+    }
+    // Deliveries the connection never got to write go back to their queues.
+    inbox.close();
+    while let Ok(delivery) = inbox.try_recv() {
+        conn.return_delivery(delivery);
+    }
+    if opened {
+        conn.broker.emit(BrokerEvent::ConnectionClosed { connection: conn.id });
+    }
+    let error = match result {
+        Ok(()) => return Ok(()),
+        Err(ConnectionError::Amqp(e)) => {
+            if let Some(close) = connection_close(&e) {
+                conn.broker
+                    .connection_exception(Some(conn.id), e.reply_code(), e.reply_text());
+                conn.enter(ConnectionState::Closing);
+                let closed = async {
+                    conn.write_frame(&AmqpFrame::method(0, &close)).await?;
+                    conn.flush().await
+                };
+                match closed.await {
+                    Ok(()) => conn.await_close_ok().await,
+                    Err(io) => return Err(conn.failure(opened, io.into())),
+                }
+            }
+            ConnectionError::Amqp(e)
+        }
+        Err(e) => e,
+    };
+    Err(conn.failure(opened, error))
+}
+
+/// Runs `write`, failing it if the peer has not taken the bytes within
+/// `limit`; a zero `limit` waits for as long as it takes.
+async fn within_write_timeout(
+    limit: Duration,
+    write: impl Future<Output = Result<(), std::io::Error>>,
+) -> Result<(), std::io::Error> {
+    if limit.is_zero() {
+        return write.await;
+    }
+    match tokio::time::timeout(limit, write).await {
+        Ok(result) => result,
+        Err(_) => {
+            warn!("Peer took no data for {:?}, closing the connection", limit);
+            Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "write timed out"))
+        }
+    }
+}
+
+fn timed_out() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::TimedOut, "handshake timed out")
+}
+
+/// Waits for the next broker event. A subscriber that fell behind gets
+/// `None` and picks up with the oldest event still buffered.
+async fn next_event(events: Option<&mut broadcast::Receiver<BrokerEvent>>) -> Option<BrokerEvent> {
+    match events?.recv().await {
+        Ok(event) => Some(event),
+        Err(broadcast::error::RecvError::Lagged(missed)) => {
+            warn!(
+                "Missed {} broker events, consumers of deleted queues may not be cancelled",
+                missed
+            );
+            None
+        }
+        Err(broadcast::error::RecvError::Closed) => None,
+    }
+}
+
+/// Waits for the next tick of `interval`, or forever without one.
+async fn next_tick(interval: Option<&mut Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits until the broker starts shutting down; `false` if it never will.
+async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) -> bool {
+    shutdown.wait_for(|&shutdown| shutdown).await.is_ok()
+}
+
+/// The `Connection.Close` announcing a connection exception.
+fn connection_close(e: &AmqpError) -> Option<Method> {
+    match e {
+        AmqpError::ConnectionException {
+            reply_code,
+            reply_text,
+            class_id,
+            method_id,
+        } => Some(reply_codes::connection_close(*reply_code, reply_text.clone(), *class_id, *method_id)),
+        AmqpError::ChannelException { .. } => None,
+    }
+}
+
+#[derive(Debug)]
+pub enum ConnectionError {
+    Amqp(AmqpError),
+    Io(std::io::Error),
+    /// The client did not open with the AMQP 0-9-1 protocol header.
+    InvalidHeader(HeaderError),
+}
+
+/// Why serving a connection failed, with what was known about the
+/// connection by then.
+#[derive(Debug)]
+pub struct ConnectionFailure {
+    /// `None` if the connection failed before its handshake began.
+    pub connection: Option<u64>,
+    /// Set once the connection was opened.
+    pub vhost: Option<String>,
+    pub user: Option<String>,
+    /// The `connection_name` the client gave in `Connection.Start-Ok`.
+    pub name: Option<String>,
+    pub error: ConnectionError,
+}
+
+impl ConnectionFailure {
+    fn before_open(error: impl Into<ConnectionError>) -> Self {
+        ConnectionFailure {
+            connection: None,
+            vhost: None,
+            user: None,
+            name: None,
+            error: error.into(),
+        }
+    }
+
+    /// Reply code of the `Connection.Close` the broker sent, if any.
+    pub fn reply_code(&self) -> Option<u16> {
+        match &self.error {
+            ConnectionError::Amqp(e) => Some(e.reply_code()),
+            _ => None,
+        }
+    }
+
+    /// How much the failure matters: a client that went away or a broker
+    /// shutdown is routine, a protocol error is the client's doing, and
+    /// anything else is the broker's problem.
+    pub fn level(&self) -> log::Level {
+        match &self.error {
+            ConnectionError::Io(e) if is_disconnect(e) => log::Level::Info,
+            ConnectionError::Amqp(e) if e.reply_code() == reply_codes::CONNECTION_FORCED => log::Level::Info,
+            ConnectionError::Amqp(_) | ConnectionError::InvalidHeader(_) => log::Level::Warn,
+            ConnectionError::Io(e) if e.kind() == std::io::ErrorKind::TimedOut => log::Level::Warn,
+            ConnectionError::Io(_) => log::Level::Error,
+        }
+    }
+
+    /// The known context as `key=value` pairs, leaving out what is unknown.
+    pub fn fields(&self) -> String {
+        let mut fields = Vec::new();
+        if let Some(id) = self.connection {
+            fields.push(format!("connection={}", id));
+        }
+        if let Some(vhost) = &self.vhost {
+            fields.push(format!("vhost={}", vhost));
+        }
+        if let Some(user) = &self.user {
+            fields.push(format!("user={}", user));
+        }
+        if let Some(code) = self.reply_code() {
+            fields.push(format!("reply_code={}", code));
+        }
+        fields.join(" ")
+    }
+
+    /// Logs the failure of the connection from `peer` at its `level`,
+    /// naming it by its connection name if the client gave one.
+    pub fn log(&self, peer: &str) {
+        let connection = match &self.name {
+            Some(name) => format!("'{}' from {}", name, peer),
+            None => format!("from {}", peer),
+        };
+        log::log!(
+            self.level(),
+            "Connection {} ended: {} [{}]",
+            connection,
+            self,
+            self.fields()
+        );
+    }
+}
+
+impl std::fmt::Display for ConnectionFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.error {
+            ConnectionError::Amqp(e) => write!(f, "{}", e.reply_text()),
+            ConnectionError::Io(e) => write!(f, "{}", e),
+            ConnectionError::InvalidHeader(reason) => write!(f, "invalid protocol header: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionFailure {}
+
+/// Whether `e` means the peer closed or dropped the connection.
+fn is_disconnect(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        e.kind(),
+        ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe
+    )
+}
+
+impl From<AmqpError> for ConnectionError {
+    fn from(e: AmqpError) -> Self {
+        ConnectionError::Amqp(e)
+    }
+}
+
+impl From<std::io::Error> for ConnectionError {
+    fn from(e: std::io::Error) -> Self {
+        ConnectionError::Io(e)
+    }
+}
+
+/// Negotiates a tuning limit where 0 means "no limit" on either side.
+fn negotiate<T: Ord + Default + Copy>(server: T, client: T) -> T {
+    let unlimited = T::default();
+    match (server == unlimited, client == unlimited) {
+        (true, _) => client,
+        (_, true) => server,
+        _ => server.min(client),
+    }
+}
+
+/// Limits negotiated in `Connection.Tune` / `Tune-Ok`; 0 means no limit.
+#[derive(Debug, Clone, Copy, Default)]
+struct Tuning {
+    channel_max: u16,
+    frame_max: u32,
+    /// Heartbeat interval in seconds.
+    heartbeat: u16,
+}
+
+impl Tuning {
+    /// Highest channel number the client may open: `channel_max`, or the
+    /// whole 16-bit channel space when it is 0.
+    fn max_channel(&self) -> u16 {
+        match self.channel_max {
+            0 => u16::MAX,
+            channel_max => channel_max,
+        }
+    }
+}
+
+/// Where a connection is in its life. Each state takes only the methods on
+/// channel 0 the spec allows there: a method out of turn, or one on another
+/// channel before `Connection.Open-Ok`, is a `COMMAND_INVALID` connection
+/// exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionState {
+    /// `Connection.Start` went out; waiting for `Start-Ok`.
+    AwaitingStartOk,
+    /// A SASL challenge went out in `Connection.Secure`; waiting for `Secure-Ok`.
+    AwaitingSecureOk,
+    /// `Connection.Tune` went out; waiting for `Tune-Ok`.
+    AwaitingTuneOk,
+    /// Tuned; waiting for `Connection.Open`.
+    AwaitingOpen,
+    /// `Open-Ok` went out; channels may be used.
+    Open,
+    /// The broker sent `Connection.Close`; waiting for `Close-Ok`.
+    Closing,
+}
+
+impl ConnectionState {
+    /// Whether `method`, on channel 0, is legal in this state.
+    fn allows(self, method: &Method) -> bool {
+        use ConnectionState::*;
+        matches!(
+            (self, method),
+            (AwaitingStartOk, Method::ConnectionStartOk { .. })
+                | (AwaitingSecureOk, Method::ConnectionSecureOk { .. })
+                | (AwaitingTuneOk, Method::ConnectionTuneOk { .. })
+                | (AwaitingOpen, Method::ConnectionOpen { .. })
+                | (Open, Method::ConnectionClose { .. })
+                | (Closing, Method::ConnectionClose { .. } | Method::ConnectionCloseOk)
+        )
+    }
+
+    /// Whether the connection may move on from this state to `next`. The
+    /// broker may close the connection in any state.
+    fn can_become(self, next: ConnectionState) -> bool {
+        use ConnectionState::*;
+        matches!(
+            (self, next),
+            (AwaitingStartOk | AwaitingSecureOk, AwaitingSecureOk | AwaitingTuneOk)
+                | (AwaitingTuneOk, AwaitingOpen)
+                | (AwaitingOpen, Open)
+                | (_, Closing)
+        )
+    }
+
+    /// Heartbeats may only be sent once the heartbeat interval is tuned.
+    fn takes_heartbeats(self) -> bool {
+        use ConnectionState::*;
+        !matches!(self, AwaitingStartOk | AwaitingSecureOk | AwaitingTuneOk)
+    }
+
+    /// What the connection waits for in this state, for reply texts.
+    fn describe(self) -> &'static str {
+        match self {
+            ConnectionState::AwaitingStartOk => "awaiting Connection.Start-Ok",
+            ConnectionState::AwaitingSecureOk => "awaiting Connection.Secure-Ok",
+            ConnectionState::AwaitingTuneOk => "awaiting Connection.Tune-Ok",
+            ConnectionState::AwaitingOpen => "awaiting Connection.Open",
+            ConnectionState::Open => "open",
+            ConnectionState::Closing => "closing",
+        }
+    }
+}
+
+struct Connection<S> {
+    /// Identifies the connection in broker events.
+    id: u64,
+    socket: S,
+    /// Slots held in the broker's connection registry, released on drop.
+    permits: Vec<ConnectionPermit>,
+    broker: Arc<Broker>,
+    /// Handed to every channel; queues push deliveries for this connection here.
+    deliveries: DeliverySender,
+    channels: HashMap<u16, Channel>,
+    /// Whether the open channels are past `Config::channel_warning_threshold`;
+    /// the warning fires again only after they drop back.
+    too_many_channels: bool,
+    state: ConnectionState,
+    tuning: Tuning,
+    /// Virtual host opened in `Connection.Open`.
+    vhost: String,
+    /// User authenticated in `Connection.Start-Ok` and `Secure-Ok`.
+    user: Option<String>,
+    peer: Option<IpAddr>,
+    /// Hostname the client sent via TLS SNI.
+    server_name: Option<String>,
+    /// Table the client sent in `Connection.Start-Ok`.
+    client_properties: FieldTable,
+    /// `connection_name` from the client properties, which logs and the
+    /// management API know the connection by.
+    name: Option<String>,
+    /// Capabilities the client properties announce as supported.
+    capabilities: Vec<String>,
+    /// Locale the client chose in `Connection.Start-Ok`, used for reply texts.
+    locale: String,
+    /// SASL mechanism the client authenticated with.
+    mechanism: String,
+    /// Records frames while the management API traces this connection.
+    trace: Arc<FrameTrace>,
+    /// Counts frames received and heartbeats sent.
+    frames: Arc<FrameCounters>,
+    buf: Vec<u8>,
+    /// Bytes read from the socket that do not yet form a complete frame.
+    pending: Vec<u8>,
+    /// Set while scanning for frame alignment after a bad frame.
+    resync: Option<Resync>,
+}
+
+/// Progress of regaining frame alignment, see `Config::frame_resync_limit`.
+#[derive(Debug, Default)]
+struct Resync {
+    /// Bytes thrown away since the bad frame began.
+    skipped: usize,
+    /// Candidate boundaries that did not start a valid frame.
+    failures: u32,
+    /// Whether `pending` starts right after a frame-end marker.
+    at_boundary: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    /// `error` with what is known about this connection; the vhost only
+    /// counts once the connection was `opened`.
+    fn failure(&self, opened: bool, error: ConnectionError) -> ConnectionFailure {
+        ConnectionFailure {
+            connection: Some(self.id),
+            vhost: opened.then(|| self.vhost.clone()),
+            user: self.user.clone(),
+            name: self.name.clone(),
+            error,
+        }
+    }
+
+    /// Reads the next complete frame, or `None` once the client closes the
+    /// socket at a frame boundary. Closing it in the middle of a frame, or
+    /// sending a frame larger than the negotiated `frame_max`, is an error.
+    ///
+    /// With `Config::frame_resync_limit` set, a frame that fails to parse
+    /// starts a resync: the bytes up to the next frame-end marker are
+    /// skipped and the byte after it is tried as the start of a frame,
+    /// until a frame parses or too many candidates have failed. Without it,
+    /// such a frame closes the connection with `FRAME_ERROR`.
+    async fn read_frame(&mut self) -> Result<Option<AmqpFrame>, ConnectionError> {
+        loop {
+            self.skip_to_boundary();
+            let frame_max = self.tuning.frame_max;
+            if let Some(len) = frame_len(&self.pending).filter(|&len| frame_max != 0 && len > frame_max as usize) {
+                if self.resync.is_some() {
+                    self.reject_candidate()?;
+                    continue;
+                }
+                return Err(AmqpError::connection(
+                    reply_codes::FRAME_ERROR,
+                    format!("FRAME_ERROR - frame of {} bytes exceeds frame_max {}", len, frame_max),
+                    0,
+                    0,
+                )
+                .into());
+            }
+            if let Some(len) = frame_len(&self.pending).filter(|&len| self.pending.len() >= len) {
+                match parse_amqp_frame(&self.pending[..len]) {
+                    Ok(frame) => {
+                        if let Some(resync) = self.resync.take() {
+                            warn!("Regained frame alignment after skipping {} bytes", resync.skipped);
+                        }
+                        self.trace.record(Direction::In, &self.pending[..len]);
+                        self.frames.count_received(frame.frame_type);
+                        self.pending.drain(..len);
+                        info!("Received frame: {:?}", frame);
+                        return Ok(Some(frame));
+                    }
+                    Err(e) if self.broker.config().frame_resync_limit > 0 => {
+                        if self.resync.is_none() {
+                            warn!("Frame parse error: {:?}; scanning for the next frame boundary", e);
+                        }
+                        self.reject_candidate()?;
+                    }
+                    Err(e) => {
+                        warn!("Frame parse error: {:?}", e);
+                        return Err(AmqpError::connection(
+                            reply_codes::FRAME_ERROR,
+                            format!("FRAME_ERROR - {}", e),
+                            0,
+                            0,
+                        )
+                        .into());
+                    }
+                }
+                continue;
+            }
+
+            self.frames.set_partial_bytes(self.partial_frame_bytes());
+            let n = match self.socket.read(&mut self.buf).await {
+                Ok(0) if !self.pending.is_empty() => {
+                    warn!(
+                        "Connection closed by client with a truncated frame ({} bytes buffered)",
+                        self.partial_frame_bytes()
+                    );
+                    let e = std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "connection closed mid-frame");
+                    return Err(e.into());
+                }
+                Ok(0) => {
+                    info!("Connection closed by client");
+                    return Ok(None);
+                }
+                Ok(n) => n,
+                Err(e) => {
+                    warn!("Failed to read from socket: {:?}", e);
+                    return Err(e.into());
+                }
+            };
+            self.pending.extend_from_slice(&self.buf[..n]);
+        }
+    }
+
+    /// Bytes buffered towards a frame that has not fully arrived. Once
+    /// `read_frame` goes back to the socket every complete frame has been
+    /// taken off `pending`, so all that is left is partial.
+    fn partial_frame_bytes(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Gives up on the frame at the start of `pending`, starting a resync or
+    /// counting a failed candidate, and moves on to the next boundary.
+    fn reject_candidate(&mut self) -> Result<(), ConnectionError> {
+        let limit = self.broker.config().frame_resync_limit;
+        let resync = match &mut self.resync {
+            Some(resync) => {
+                resync.failures += 1;
+                if resync.failures >= limit {
+                    return Err(AmqpError::connection(
+                        reply_codes::FRAME_ERROR,
+                        format!(
+                            "FRAME_ERROR - lost frame alignment, skipped {} bytes over {} candidate frames",
+                            resync.skipped, resync.failures
+                        ),
+                        0,
+                        0,
+                    )
+                    .into());
+                }
+                resync
+            }
+            None => self.resync.insert(Resync::default()),
+        };
+        resync.at_boundary = false;
+        self.skip_to_boundary();
+        Ok(())
+    }
+
+    /// While resyncing, drops buffered bytes up to and including the next
+    /// frame-end marker, or all of them if none has arrived yet.
+    fn skip_to_boundary(&mut self) {
+        let Some(resync) = self.resync.as_mut().filter(|resync| !resync.at_boundary) else {
+            return;
+        };
+        let skip = match self.pending.iter().position(|&b| b == FRAME_END) {
+            Some(end) => {
+                resync.at_boundary = true;
+                end + 1
+            }
+            None => self.pending.len(),
+        };
+        self.pending.drain(..skip);
+        resync.skipped += skip;
+    }
+
+    async fn send_method(&mut self, channel: u16, method: &Method) -> Result<(), std::io::Error> {
+        self.write_frame(&AmqpFrame::method(channel, method)).await?;
+        self.flush().await
+    }
+
+    /// Writes `frame` without flushing, recording it if a trace is running.
+    async fn write_frame(&mut self, frame: &AmqpFrame) -> Result<(), std::io::Error> {
+        let bytes = frame.encode();
+        self.trace.record(Direction::Out, &bytes);
+        let limit = self.broker.config().write_timeout;
+        within_write_timeout(limit, self.socket.write_all(&bytes)).await
+    }
+
+    /// Writes `frames` with a single write, without flushing.
+    async fn write_frames(&mut self, frames: &[AmqpFrame]) -> Result<(), std::io::Error> {
+        let mut bytes = Vec::new();
+        for frame in frames {
+            let encoded = frame.encode();
+            self.trace.record(Direction::Out, &encoded);
+            bytes.extend(encoded);
+        }
+        let limit = self.broker.config().write_timeout;
+        within_write_timeout(limit, self.socket.write_all(&bytes)).await
+    }
+
+    async fn flush(&mut self) -> Result<(), std::io::Error> {
+        let limit = self.broker.config().write_timeout;
+        within_write_timeout(limit, self.socket.flush()).await
+    }
+
+    /// Reads the next handshake method, which must be one the connection's
+    /// state allows. Heartbeats are skipped once the connection is tuned.
+    async fn expect_method(&mut self) -> Result<Option<Method>, ConnectionError> {
+        loop {
+            let Some(frame) = self.read_frame().await? else {
+                return Ok(None);
+            };
+            if frame.frame_type == FRAME_HEARTBEAT && frame.channel == 0 && self.state.takes_heartbeats() {
+                continue;
+            }
+            let (class_id, method_id) = match frame.frame_type {
+                FRAME_METHOD => methods::method_id(&frame.payload).unwrap_or_default(),
+                _ => (0, 0),
+            };
+            if frame.frame_type == FRAME_METHOD && frame.channel == 0 {
+                let method = Method::decode_within(&frame.payload, &self.broker.config().table_limits)?;
+                if self.state.allows(&method) {
+                    return Ok(Some(method));
+                }
+            }
+            return Err(out_of_state(self.state, frame.channel, class_id, method_id).into());
+        }
+    }
+
+    /// Moves the connection to `next`.
+    fn enter(&mut self, next: ConnectionState) {
+        debug_assert!(
+            self.state.can_become(next),
+            "connection {} cannot go from {:?} to {:?}",
+            self.id,
+            self.state,
+            next
+        );
+        debug!("Connection {} is now {}", self.id, next.describe());
+        self.state = next;
+    }
+
+    /// Runs `Connection.Start` through `Connection.Open-Ok`. Returns `false`
+    /// if the client went away before the connection was opened.
+    async fn handshake(&mut self) -> Result<bool, ConnectionError> {
+        let config = self.broker.config().clone();
+        let mut server_properties = FieldTable::new();
+        server_properties.insert("product", FieldValue::LongString(b"HayMQ".to_vec()));
+        server_properties.insert(
+            "version",
+            FieldValue::LongString(env!("CARGO_PKG_VERSION").as_bytes().to_vec()),
+        );
+        let mut capabilities = FieldTable::new();
+        for capability in SERVER_CAPABILITIES {
+            capabilities.insert(*capability, FieldValue::Boolean(true));
+        }
+        server_properties.insert("capabilities", FieldValue::FieldTable(capabilities));
+        self.send_method(
+            0,
+            &Method::ConnectionStart {
+                version_major: 0,
+                version_minor: 9,
+                server_properties,
+                mechanisms: self.broker.mechanisms().join(" ").into_bytes(),
+                locales: locale::advertised(),
+            },
+        )
+        .await?;
+
+        match self.expect_method().await? {
+            Some(Method::ConnectionStartOk {
+                client_properties,
+                mechanism,
+                response,
+                locale,
+            }) => {
+                if !locale::is_supported(&locale) {
+                    return Err(AmqpError::connection(
+                        reply_codes::NOT_ALLOWED,
+                        self.text("locale_unsupported", &[&locale]),
+                        CLASS_CONNECTION,
+                        11,
+                    )
+                    .into());
+                }
+                self.locale = locale;
+                self.name = client_properties
+                    .get("connection_name")
+                    .and_then(FieldValue::as_str)
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_string);
+                self.capabilities = match client_properties.get("capabilities") {
+                    Some(FieldValue::FieldTable(capabilities)) => capabilities
+                        .iter()
+                        .filter(|(_, value)| **value == FieldValue::Boolean(true))
+                        .map(|(name, _)| name.to_string())
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                self.client_properties = client_properties;
+                info!("Connection {} authenticating with {}", self.label(), mechanism);
+                if !self.authenticate(&mechanism, response).await? {
+                    return Ok(false);
+                }
+                self.mechanism = mechanism;
+            }
+            Some(other) => return Err(unexpected(self.state, &other).into()),
+            None => return Ok(false),
+        }
+
+        self.send_method(
+            0,
+            &Method::ConnectionTune {
+                channel_max: config.channel_max,
+                frame_max: config.frame_max,
+                heartbeat: config.heartbeat,
+            },
+        )
+        .await?;
+        self.enter(ConnectionState::AwaitingTuneOk);
+
+        match self.expect_method().await? {
+            Some(Method::ConnectionTuneOk {
+                channel_max,
+                frame_max,
+                heartbeat,
+            }) => {
+                if frame_max != 0 && frame_max < FRAME_MIN_SIZE {
+                    return Err(AmqpError::connection(
+                        reply_codes::NOT_ALLOWED,
+                        self.text("frame_max_too_small", &[&frame_max, &FRAME_MIN_SIZE]),
+                        CLASS_CONNECTION,
+                        31,
+                    )
+                    .into());
+                }
+                self.tuning = Tuning {
+                    channel_max: negotiate(config.channel_max, channel_max),
+                    frame_max: negotiate(config.frame_max, frame_max),
+                    heartbeat: negotiate(config.heartbeat, heartbeat),
+                };
+                self.enter(ConnectionState::AwaitingOpen);
+            }
+            Some(other) => return Err(unexpected(self.state, &other).into()),
+            None => return Ok(false),
+        }
+
+        match self.expect_method().await? {
+            Some(Method::ConnectionOpen { virtual_host }) => {
+                let Some(normalized) = vhost::normalize_name(&virtual_host) else {
+                    return Err(AmqpError::connection(
+                        reply_codes::NOT_ALLOWED,
+                        self.text("vhost_invalid", &[&virtual_host]),
+                        CLASS_CONNECTION,
+                        40,
+                    )
+                    .into());
+                };
+                let virtual_host = self.resolve_vhost(normalized);
+                if !self.broker.has_vhost(&virtual_host) {
+                    return Err(AmqpError::connection(
+                        reply_codes::NOT_ALLOWED,
+                        self.text("vhost_not_found", &[&virtual_host]),
+                        CLASS_CONNECTION,
+                        40,
+                    )
+                    .into());
+                }
+                if let (Some(users), Some(user)) = (self.broker.auth().users(), &self.user) {
+                    if users.permission(&virtual_host, user).is_none() {
+                        return Err(AmqpError::connection(
+                            reply_codes::ACCESS_REFUSED,
+                            self.text("vhost_access_refused", &[&virtual_host, user]),
+                            CLASS_CONNECTION,
+                            40,
+                        )
+                        .into());
+                    }
+                }
+                info!("Connection {} opening virtual host {}", self.label(), virtual_host);
+                self.vhost = virtual_host;
+                self.send_method(0, &Method::ConnectionOpenOk).await?;
+                self.enter(ConnectionState::Open);
+            }
+            Some(other) => return Err(unexpected(self.state, &other).into()),
+            None => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Reply text `key` in the connection's locale.
+    fn text(&self, key: &str, args: &[&dyn std::fmt::Display]) -> String {
+        locale::text(&self.locale, key, args)
+    }
+
+    /// Whether the client listed `name` as a capability it supports in
+    /// `Connection.Start-Ok`.
+    fn has_capability(&self, name: &str) -> bool {
+        self.capabilities.iter().any(|capability| capability == name)
+    }
+
+    /// How log lines name the connection: by its connection name, or else
+    /// by the client's address.
+    fn label(&self) -> String {
+        match (&self.name, self.peer) {
+            (Some(name), _) => format!("'{}'", name),
+            (None, Some(peer)) => peer.to_string(),
+            (None, None) => self.id.to_string(),
+        }
+    }
+
+    /// The virtual host to open for a `Connection.Open` asking for
+    /// `requested`: an explicit vhost is taken as is, while the default `/`
+    /// gives way to the vhost mapped to the client's SNI hostname, if any.
+    fn resolve_vhost(&self, requested: String) -> String {
+        if requested != DEFAULT_VHOST {
+            return requested;
+        }
+        self.server_name
+            .as_ref()
+            .and_then(|name| self.broker.config().sni_vhosts.get(&name.to_ascii_lowercase()))
+            .cloned()
+            .unwrap_or(requested)
+    }
+
+    /// Runs the SASL exchange that `Start-Ok` began with `response`, sending
+    /// `Connection.Secure` for every challenge. Returns `false` if the client
+    /// went away in the middle of it.
+    async fn authenticate(&mut self, mechanism: &str, mut response: Vec<u8>) -> Result<bool, ConnectionError> {
+        if !self.broker.allows_mechanism(mechanism) {
+            return Err(AmqpError::connection(
+                reply_codes::NOT_ALLOWED,
+                self.text("mechanism_not_allowed", &[&mechanism]),
+                CLASS_CONNECTION,
+                11,
+            )
+            .into());
+        }
+        let Some(mut session) = self.broker.auth().start(mechanism) else {
+            return Err(AmqpError::connection(
+                reply_codes::ACCESS_REFUSED,
+                self.text("mechanism_unsupported", &[&mechanism]),
+                CLASS_CONNECTION,
+                11,
+            )
+            .into());
+        };
+        // The method whose response is being checked, for error reports.
+        let mut method_id = 11;
+        let user = loop {
+            match session.step(&response) {
+                Ok(SaslStep::Authenticated(user)) => break user,
+                Ok(SaslStep::Challenge(challenge)) => {
+                    self.send_method(0, &Method::ConnectionSecure { challenge }).await?;
+                    self.enter(ConnectionState::AwaitingSecureOk);
+                    match self.expect_method().await? {
+                        Some(Method::ConnectionSecureOk { response: next }) => response = next,
+                        Some(other) => return Err(unexpected(self.state, &other).into()),
+                        None => return Ok(false),
+                    }
+                    method_id = 21;
+                }
+                Err(failure) => {
+                    return Err(AmqpError::connection(
+                        reply_codes::ACCESS_REFUSED,
+                        format!("ACCESS_REFUSED - {}", failure),
+                        CLASS_CONNECTION,
+                        method_id,
+                    )
+                    .into())
+                }
+            }
+        };
+        if let Some(user) = user {
+            let Some(permit) = self.broker.connections().acquire(ConnectionKey::User(user.clone())) else {
+                return Err(AmqpError::connection(
+                    reply_codes::CONNECTION_FORCED,
+                    self.text("user_connection_limit", &[&user]),
+                    CLASS_CONNECTION,
+                    method_id,
+                )
+                .into());
+            };
+            self.permits.push(permit);
+            self.user = Some(user);
+        }
+        Ok(true)
+    }
+
+    /// Serves the open connection: frames from the client, deliveries from
+    /// queues arriving on `inbox`, and drain/resume of the vhost seen on
+    /// `drained`.
+    async fn run(
+        &mut self,
+        inbox: &mut mpsc::UnboundedReceiver<Delivery>,
+        mut drained: watch::Receiver<bool>,
+    ) -> Result<(), ConnectionError> {
+        // Only clients that handle a broker-sent `Basic.Cancel` hear about
+        // the queues they consume from going away.
+        let mut events = self
+            .has_capability("consumer_cancel_notify")
+            .then(|| self.broker.subscribe_events());
+        // While the disk alarm is raised, frames are no longer read from a
+        // connection once it has published.
+        let mut disk_alarm = self.broker.watch_disk_alarm();
+        let mut blocked = *disk_alarm.borrow_and_update();
+        let mut publishing = false;
+        let mut shutdown = self.broker.watch_shutdown();
+        // Paces the frames read from a client sending faster than
+        // `max_frame_rate`: frames stay in the socket until it allows more.
+        let config = self.broker.config();
+        let burst = match config.frame_rate_burst {
+            0 => config.max_frame_rate,
+            burst => burst,
+        };
+        let mut frame_rate =
+            (config.max_frame_rate != 0).then(|| TokenBucket::with_burst(config.max_frame_rate, burst));
+        let mut paced_until: Option<Instant> = None;
+        // Heartbeats go out at half the negotiated interval, so the client
+        // hears from the broker in time even if one is late.
+        let mut heartbeats = (self.tuning.heartbeat != 0).then(|| {
+            let period = Duration::from_secs(self.tuning.heartbeat.into()) / 2;
+            let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        // A client that sends nothing, not even a heartbeat, for two
+        // intervals is taken to be gone.
+        let peer_window = Duration::from_secs(self.tuning.heartbeat.into()) * 2;
+        let mut peer_deadline = (self.tuning.heartbeat != 0).then(|| Instant::now() + peer_window);
+        if blocked {
+            self.send_blocked(true).await?;
+        }
+        loop {
+            let close_deadline = self.channels.values().filter_map(|c| c.closing).map(|c| c.deadline).min();
+            let throttle_deadline = self.channels.values().filter_map(|c| c.throttled_until).min();
+            let auto_ack_deadline = self.channels.values().filter_map(Channel::auto_ack_deadline).min();
+            let publish_deadline = self.channels.values().filter_map(Channel::publish_deadline).min();
+            let frame = tokio::select! {
+                frame = self.read_frame(), if !(blocked && publishing) && paced_until.is_none() => frame?,
+                _ = tokio::time::sleep_until(paced_until.unwrap_or_else(Instant::now)), if paced_until.is_some() => {
+                    paced_until = None;
+                    continue;
+                }
+                Some(delivery) = inbox.recv() => {
+                    self.deliver(delivery, inbox).await?;
+                    continue;
+                }
+                Some(event) = next_event(events.as_mut()), if events.is_some() => {
+                    if let BrokerEvent::QueueDeleted { vhost, queue } = event {
+                        if vhost == self.vhost {
+                            self.cancel_lost_consumers(&queue).await?;
+                        }
+                    }
+                    continue;
+                }
+                Ok(()) = drained.changed() => {
+                    let active = !*drained.borrow_and_update();
+                    self.send_flow(active).await?;
+                    continue;
+                }
+                Ok(()) = disk_alarm.changed() => {
+                    let raised = *disk_alarm.borrow_and_update();
+                    if raised != blocked {
+                        blocked = raised;
+                        self.send_blocked(blocked).await?;
+                    }
+                    continue;
+                }
+                true = shutdown_requested(&mut shutdown) => {
+                    self.resolve_confirms().await?;
+                    return Err(AmqpError::connection(
+                        reply_codes::CONNECTION_FORCED,
+                        "CONNECTION_FORCED - broker shutdown",
+                        0,
+                        0,
+                    )
+                    .into());
+                }
+                _ = tokio::time::sleep_until(close_deadline.unwrap_or_else(Instant::now)), if close_deadline.is_some() => {
+                    self.expire_closing_channels()?;
+                    continue;
+                }
+                _ = tokio::time::sleep_until(throttle_deadline.unwrap_or_else(Instant::now)), if throttle_deadline.is_some() => {
+                    let drained = *drained.borrow();
+                    self.lift_throttles(!drained).await?;
+                    continue;
+                }
+                _ = tokio::time::sleep_until(peer_deadline.unwrap_or_else(Instant::now)), if peer_deadline.is_some() => {
+                    if (blocked && publishing) || paced_until.is_some() {
+                        // Frames wait in the socket while reads are paused.
+                        peer_deadline = Some(Instant::now() + peer_window);
+                        continue;
+                    }
+                    warn!(
+                        "Connection {} sent nothing for {:?}, closing it",
+                        self.label(),
+                        peer_window
+                    );
+                    let e = std::io::Error::new(std::io::ErrorKind::TimedOut, "missed heartbeats");
+                    return Err(e.into());
+                }
+                _ = next_tick(heartbeats.as_mut()), if heartbeats.is_some() => {
+                    self.frames.count_heartbeat_sent();
+                    self.write_frame(&AmqpFrame::heartbeat()).await?;
+                    self.flush().await?;
+                    continue;
+                }
+                _ = tokio::time::sleep_until(auto_ack_deadline.unwrap_or_else(Instant::now)), if auto_ack_deadline.is_some() => {
+                    let now = Instant::now();
+                    for channel in self.channels.values_mut() {
+                        channel.auto_ack(&self.broker, now);
+                    }
+                    continue;
+                }
+                _ = tokio::time::sleep_until(publish_deadline.unwrap_or_else(Instant::now)), if publish_deadline.is_some() => {
+                    self.expire_publishes().await?;
+                    continue;
+                }
+            };
+            let Some(frame) = frame else {
+                break;
+            };
+            if let Some(deadline) = peer_deadline.as_mut() {
+                *deadline = Instant::now() + peer_window;
+            }
+            if let Some(wait) = frame_rate.as_mut().and_then(|bucket| bucket.take(Instant::now())) {
+                debug!(
+                    "Connection {} is over max_frame_rate, pausing reads for {:?}",
+                    self.id, wait
+                );
+                paced_until = Some(Instant::now() + wait);
+            }
+            if frame.channel == 0 && frame.frame_type == FRAME_METHOD {
+                match Method::decode_within(&frame.payload, &self.broker.config().table_limits)? {
+                    Method::ConnectionClose { .. } => {
+                        self.send_method(0, &Method::ConnectionCloseOk).await?;
+                        info!("Connection {} closed by client", self.label());
+                        return Ok(());
+                    }
+                    other => return Err(unexpected(self.state, &other).into()),
+                }
+            }
+            if frame.frame_type == FRAME_METHOD && frame.payload.starts_with(&[0, 60, 0, 40]) {
+                publishing = true;
+            }
+            let channel_id = frame.channel;
+            let was_open = self.channels.contains_key(&channel_id);
+            let replies = handle_frame(
+                &self.broker,
+                self.id,
+                self.tuning,
+                &self.vhost,
+                self.user.as_deref(),
+                &self.deliveries,
+                &mut self.channels,
+                frame,
+            )?;
+            match (was_open, self.channels.contains_key(&channel_id)) {
+                (false, true) => self.broker.emit(BrokerEvent::ChannelOpened {
+                    connection: self.id,
+                    channel: channel_id,
+                }),
+                (true, false) => self.broker.emit(BrokerEvent::ChannelClosed {
+                    connection: self.id,
+                    channel: channel_id,
+                }),
+                _ => {}
+            }
+            if was_open != self.channels.contains_key(&channel_id) {
+                self.count_channels();
+            }
+            for reply in replies {
+                self.write_frame(&reply).await?;
+            }
+            // Awaited before the next frame is read, which keeps a channel's
+            // publishes in order on their queues.
+            if let Some(publish) = self.channels.get_mut(&channel_id).and_then(Channel::take_intercepted) {
+                self.intercept(channel_id, publish).await?;
+            }
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Waits, for at most `Config::connection_close_timeout`, for the client
+    /// to answer the `Connection.Close` the broker sent, discarding every
+    /// other frame. A `Connection.Close` the client sent at the same time
+    /// counts as an answer.
+    async fn await_close_ok(&mut self) {
+        let limit = self.broker.config().connection_close_timeout;
+        if limit.is_zero() {
+            return;
+        }
+        let answer = async {
+            loop {
+                let frame = match self.read_frame().await {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => return "the client hung up",
+                    Err(_) => return "the client sent an invalid frame",
+                };
+                let replied = frame.channel == 0
+                    && frame.frame_type == FRAME_METHOD
+                    && Method::decode(&frame.payload).is_ok_and(|method| self.state.allows(&method));
+                if replied {
+                    return "the client replied";
+                }
+                debug!("Connection is closing, discarding {:?}", frame);
+            }
+        };
+        match tokio::time::timeout(limit, answer).await {
+            Ok(outcome) => info!("Connection closed by the broker; {}", outcome),
+            Err(_) => warn!("No Connection.Close-Ok within {:?}, dropping the connection", limit),
+        }
+    }
+
+    /// Sends `Basic.Cancel` for every consumer of the deleted `queue`.
+    async fn cancel_lost_consumers(&mut self, queue: &str) -> Result<(), std::io::Error> {
+        let mut frames = Vec::new();
+        for channel in self.channels.values_mut() {
+            frames.extend(channel.cancel_lost_consumers(&self.broker, queue));
+        }
+        for frame in frames {
+            self.write_frame(&frame).await?;
+        }
+        self.flush().await
+    }
+
+    /// Runs a publish completed on `channel_id` through the broker's
+    /// interceptors, then routes it and sends what the channel replies.
+    async fn intercept(&mut self, channel_id: u16, mut publish: InterceptedPublish) -> Result<(), std::io::Error> {
+        let ctx = PublishCtx {
+            vhost: self.vhost.clone(),
+            user: self.user.clone(),
+            connection: self.id,
+            channel: channel_id,
+        };
+        let action = self.broker.intercept(&mut publish.message, &ctx).await;
+        let Some(channel) = self.channels.get_mut(&channel_id) else {
+            return Ok(());
+        };
+        for frame in channel.finish_publish(&self.broker, publish, action) {
+            self.write_frame(&frame).await?;
+        }
+        Ok(())
+    }
+
+    /// Updates the channel count the registry lists, warning when it goes
+    /// past `Config::channel_warning_threshold`.
+    fn count_channels(&mut self) {
+        let open = self.channels.len();
+        self.broker.connections().set_channels(self.id, open);
+        let threshold = self.broker.config().channel_warning_threshold;
+        let too_many = threshold != 0 && open > threshold.into();
+        if too_many && !self.too_many_channels {
+            self.broker.channel_warning(self.id, open);
+        }
+        self.too_many_channels = too_many;
+    }
+
+    /// Drops channels whose `Channel.Close-Ok` did not arrive in time. A
+    /// channel closed for a hard error takes the connection down with it.
+    fn expire_closing_channels(&mut self) -> Result<(), AmqpError> {
+        let now = Instant::now();
+        let expired: Vec<(u16, u16)> = self
+            .channels
+            .values()
+            .filter_map(|c| c.closing.filter(|closing| closing.deadline <= now).map(|closing| (c.id, closing.reply_code)))
+            .collect();
+        for (channel_id, reply_code) in expired {
+            warn!("Channel {} did not confirm Channel.Close in time, dropping it", channel_id);
+            self.channels.remove(&channel_id);
+            self.broker.emit(BrokerEvent::ChannelClosed {
+                connection: self.id,
+                channel: channel_id,
+            });
+            self.count_channels();
+            if reply_codes::is_hard_error(reply_code) {
+                return Err(AmqpError::connection(
+                    reply_code,
+                    self.text("channel_close_unconfirmed", &[&channel_id]),
+                    CLASS_CHANNEL,
+                    40,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Closes the channels whose publish did not send all its content
+    /// within `Config::publish_timeout`.
+    async fn expire_publishes(&mut self) -> Result<(), ConnectionError> {
+        let now = Instant::now();
+        let mut frames = Vec::new();
+        for channel in self.channels.values_mut() {
+            if let Some(e) = channel.expire_publish(now) {
+                frames.extend(channel_result(&self.broker, self.id, channel, Err(e))?);
+            }
+        }
+        if frames.is_empty() {
+            return Ok(());
+        }
+        self.write_frames(&frames).await?;
+        self.flush().await?;
+        Ok(())
+    }
+
+    /// Ends the publish throttling of channels whose pause is over,
+    /// resuming them with `Channel.Flow` if `resume` is set.
+    async fn lift_throttles(&mut self, resume: bool) -> Result<(), std::io::Error> {
+        let now = Instant::now();
+        let mut ids = Vec::new();
+        for channel in self.channels.values_mut() {
+            if channel.throttled_until.is_some_and(|until| until <= now) {
+                channel.throttled_until = None;
+                if resume && channel.closing.is_none() {
+                    ids.push(channel.id);
+                }
+            }
+        }
+        ids.sort_unstable();
+        for id in ids {
+            let flow = Method::ChannelFlow { active: true };
+            self.write_frame(&AmqpFrame::method(id, &flow)).await?;
+        }
+        self.flush().await
+    }
+
+    /// Tells the client its publishes are blocked for the disk alarm, if it
+    /// announced the `connection.blocked` capability, or that they are not
+    /// any more, acking the publishes held back meanwhile.
+    async fn send_blocked(&mut self, blocked: bool) -> Result<(), std::io::Error> {
+        if self.has_capability("connection.blocked") {
+            let method = if blocked {
+                Method::ConnectionBlocked {
+                    reason: "low on disk".into(),
+                }
+            } else {
+                Method::ConnectionUnblocked
+            };
+            self.write_frame(&AmqpFrame::method(0, &method)).await?;
+        }
+        if !blocked {
+            let mut ids: Vec<u16> = self.channels.keys().copied().collect();
+            ids.sort_unstable();
+            for id in ids {
+                for frame in self.channels.get_mut(&id).unwrap().release_confirms() {
+                    self.write_frame(&frame).await?;
+                }
+            }
+        }
+        self.flush().await
+    }
+
+    /// Sends the confirms of every publish held back for the disk alarm:
+    /// acks if the store has taken the messages since, nacks otherwise.
+    async fn resolve_confirms(&mut self) -> Result<(), std::io::Error> {
+        let stored = !self.broker.disk_alarm();
+        let mut ids: Vec<u16> = self.channels.keys().copied().collect();
+        ids.sort_unstable();
+        for id in ids {
+            for frame in self.channels.get_mut(&id).unwrap().resolve_confirms(stored) {
+                self.write_frame(&frame).await?;
+            }
         }
+        self.flush().await
     }
 
-    let mut buf = vec![0u8; 4096];
-    loop {
-        let n = match socket.read(&mut buf).await {
-            Ok(0) => {
-                // EOF - connection closed by client
-                info!("Connection closed by client");
-                return Ok(());
+    /// Asks the client to pause or resume publishing on every open channel.
+    /// Channels throttled for their publish rate resume on their own.
+    async fn send_flow(&mut self, active: bool) -> Result<(), std::io::Error> {
+        let mut ids: Vec<u16> = self
+            .channels
+            .values()
+            .filter(|c| c.closing.is_none() && (!active || c.throttled_until.is_none()))
+            .map(|c| c.id)
+            .collect();
+        ids.sort_unstable();
+        for id in ids {
+            self.write_frame(&AmqpFrame::method(id, &Method::ChannelFlow { active })).await?;
+        }
+        self.flush().await
+    }
+
+    /// Writes `delivery` to the client. If its consumer asked for batches
+    /// with `x-delivery-batch`, deliveries already waiting in `inbox` are
+    /// written along with it, up to the batch size, in a single write; the
+    /// queues only sent what the consumers' prefetch windows allow.
+    ///
+    /// A delivery's method, header and body frames are written as a unit:
+    /// if the write fails partway, every delivery in it goes back to its
+    /// queue whole, as redelivered, before the connection is torn down.
+    async fn deliver(
+        &mut self,
+        delivery: Delivery,
+        inbox: &mut mpsc::UnboundedReceiver<Delivery>,
+    ) -> Result<(), std::io::Error> {
+        let batch = self
+            .channels
+            .get(&delivery.channel)
+            .map_or(1, |channel| channel.delivery_batch(&delivery.consumer_tag));
+        let started = std::time::Instant::now();
+        let mut frames = Vec::new();
+        // Each channel written to, with the last tag it had given before.
+        let mut tagged_after: Vec<(u16, u64)> = Vec::new();
+        let mut next = Some(delivery);
+        let mut count = 0;
+        while let Some(delivery) = next.take() {
+            let Some(channel) = self.channels.get_mut(&delivery.channel) else {
+                // The channel closed after the queue sent this; its close
+                // already requeued what it had, so put this one back too.
+                self.return_delivery(delivery);
+                continue;
+            };
+            if tagged_after.iter().all(|&(id, _)| id != delivery.channel) {
+                tagged_after.push((delivery.channel, channel.last_delivery_tag()));
             }
-            Ok(n) => n,
-            Err(e) => {
-                warn!("Failed to read from socket: {:?}", e);
-                return Err(e.into());
+            frames.extend(channel.deliver(&self.broker, delivery));
+            count += 1;
+            if count < batch {
+                next = inbox.try_recv().ok();
             }
+        }
+        if frames.is_empty() {
+            return Ok(());
+        }
+        let written = match self.write_frames(&frames).await {
+            Ok(()) => self.flush().await,
+            Err(e) => Err(e),
         };
+        if let Err(e) = written {
+            debug!(
+                "Connection {}: returning {} deliveries it failed to write: {}",
+                self.id, count, e
+            );
+            for (id, after) in tagged_after {
+                if let Some(channel) = self.channels.get_mut(&id) {
+                    channel.return_unwritten(&self.broker, after);
+                }
+            }
+            return Err(e);
+        }
+        self.broker.check_latency(Operation::Deliver, started);
+        Ok(())
+    }
 
-        let incoming = &buf[..n];
-        match parse_amqp_frame(incoming) {
-            Ok(frame) => {
-                info!("Received frame: {:?}", frame);
-                socket.write_all(b"Frame received").await?;
+    /// Puts back a delivery that never reached the client.
+    fn return_delivery(&self, delivery: Delivery) {
+        if let Some(prefetch) = &delivery.prefetch {
+            prefetch.release(delivery.footprint);
+        }
+        if !delivery.no_ack {
+            self.broker
+                .restore(&self.vhost, &delivery.queue, &[delivery.message_id]);
+        }
+    }
+}
+
+fn unexpected(state: ConnectionState, method: &Method) -> AmqpError {
+    let (class_id, method_id) = method.id();
+    out_of_state(state, 0, class_id, method_id)
+}
+
+/// The exception for method `class_id.method_id` arriving on `channel` when
+/// the connection's `state` does not allow it; content and heartbeat frames
+/// come as method 0.0.
+fn out_of_state(state: ConnectionState, channel: u16, class_id: u16, method_id: u16) -> AmqpError {
+    let what = match (class_id, method_id) {
+        (0, 0) => "frame".to_string(),
+        _ => format!("method {}.{}", class_id, method_id),
+    };
+    AmqpError::connection(
+        reply_codes::COMMAND_INVALID,
+        format!(
+            "COMMAND_INVALID - unexpected {} on channel {} while the connection is {}",
+            what,
+            channel,
+            state.describe()
+        ),
+        class_id,
+        method_id,
+    )
+}
+
+fn channel_error(text: String, class_id: u16, method_id: u16) -> AmqpError {
+    AmqpError::connection(reply_codes::CHANNEL_ERROR, text, class_id, method_id)
+}
+
+/// Dispatches a parsed frame to its channel and returns the frames to send back.
+///
+/// Channel exceptions are turned into a `Channel.Close` reply; connection
+/// exceptions are returned as errors.
+#[allow(clippy::too_many_arguments)]
+fn handle_frame(
+    broker: &Broker,
+    connection: u64,
+    tuning: Tuning,
+    vhost: &str,
+    user: Option<&str>,
+    deliveries: &DeliverySender,
+    channels: &mut HashMap<u16, Channel>,
+    frame: AmqpFrame,
+) -> Result<Vec<AmqpFrame>, AmqpError> {
+    let channel_id = frame.channel;
+    let state = channels.get(&channel_id).map(Channel::state);
+    if state == Some(ChannelState::Closing) {
+        return Ok(handle_closing_frame(channels, frame));
+    }
+    let method = match frame.frame_type {
+        FRAME_METHOD => {
+            if let Some((class_id, method_id)) = methods::method_id(&frame.payload)
+                .filter(|&(class_id, method_id)| !methods::is_implemented(class_id, method_id))
+            {
+                let close = broker.config().unknown_method_close;
+                return match channels.get_mut(&channel_id) {
+                    Some(channel) if close == UnknownMethodClose::Channel => channel_result(
+                        broker,
+                        connection,
+                        channel,
+                        Err(methods::not_implemented(class_id, method_id, close)),
+                    ),
+                    _ => Err(methods::not_implemented(
+                        class_id,
+                        method_id,
+                        UnknownMethodClose::Connection,
+                    )),
+                };
             }
-            Err(e) => {
-                warn!("Frame parse error: {:?}", e);
-                socket.write_all(b"Invalid frame").await?;
+            let method = Method::decode_within(&frame.payload, &broker.config().table_limits)?;
+            let (class_id, method_id) = method.id();
+            // Connection methods belong on channel 0, and only they do.
+            if (class_id == CLASS_CONNECTION) != (channel_id == 0) {
+                return Err(AmqpError::connection(
+                    reply_codes::COMMAND_INVALID,
+                    format!(
+                        "COMMAND_INVALID - method {}.{} is not allowed on channel {}",
+                        class_id, method_id, channel_id
+                    ),
+                    class_id,
+                    method_id,
+                ));
+            }
+            if matches!(state, Some(ChannelState::AwaitingHeader | ChannelState::AwaitingBody)) {
+                return Err(AmqpError::connection(
+                    reply_codes::UNEXPECTED_FRAME,
+                    format!(
+                        "UNEXPECTED_FRAME - method {}.{} on channel {} in the middle of a publish's content",
+                        class_id, method_id, channel_id
+                    ),
+                    class_id,
+                    method_id,
+                ));
+            }
+            method
+        }
+        FRAME_HEADER | FRAME_BODY => {
+            let Some(channel) = channels.get_mut(&channel_id) else {
+                return Err(channel_error(
+                    format!("CHANNEL_ERROR - content frame on unopened channel {}", channel_id),
+                    0,
+                    0,
+                ));
+            };
+            let result = if frame.frame_type == FRAME_HEADER {
+                channel.handle_header(
+                    broker,
+                    ContentHeader::decode_within(&frame.payload, &broker.config().table_limits)?,
+                )
+            } else {
+                channel.handle_body(broker, frame.payload)
+            };
+            return channel_result(broker, connection, channel, result);
+        }
+        FRAME_HEARTBEAT => {
+            if channel_id != 0 || !frame.payload.is_empty() {
+                return Err(AmqpError::connection(
+                    reply_codes::FRAME_ERROR,
+                    format!(
+                        "FRAME_ERROR - heartbeat on channel {} with {} payload bytes, expected channel 0 and none",
+                        channel_id,
+                        frame.payload.len()
+                    ),
+                    0,
+                    0,
+                ));
+            }
+            return Ok(vec![]);
+        }
+        _ => return Ok(vec![]),
+    };
+
+    match method {
+        Method::ChannelOpen => {
+            let channel_max = tuning.channel_max;
+            if channel_id > tuning.max_channel() {
+                return Err(channel_error(
+                    format!(
+                        "CHANNEL_ERROR - channel {} exceeds negotiated channel_max {}",
+                        channel_id, channel_max
+                    ),
+                    CLASS_CHANNEL,
+                    10,
+                ));
+            }
+            if channels.contains_key(&channel_id) {
+                return Err(channel_error(
+                    format!("CHANNEL_ERROR - channel {} is already open", channel_id),
+                    CLASS_CHANNEL,
+                    10,
+                ));
+            }
+            let mut channel = Channel::new(channel_id, vhost.to_string(), deliveries.clone());
+            channel.frame_max = tuning.frame_max;
+            channel.user = user.map(str::to_string);
+            channels.insert(channel_id, channel);
+            let mut replies = vec![AmqpFrame::method(channel_id, &Method::ChannelOpenOk)];
+            if broker.is_drained(vhost) {
+                replies.push(AmqpFrame::method(channel_id, &Method::ChannelFlow { active: false }));
+            }
+            Ok(replies)
+        }
+        Method::ChannelClose { .. } => {
+            if let Some(mut channel) = channels.remove(&channel_id) {
+                channel.close(broker);
+            }
+            Ok(vec![AmqpFrame::method(channel_id, &Method::ChannelCloseOk)])
+        }
+        Method::ChannelCloseOk => {
+            if let Some(mut channel) = channels.remove(&channel_id) {
+                channel.close(broker);
+            }
+            Ok(vec![])
+        }
+        method => {
+            let Some(channel) = channels.get_mut(&channel_id) else {
+                let (class_id, method_id) = method.id();
+                return Err(channel_error(
+                    format!("CHANNEL_ERROR - channel {} is not open", channel_id),
+                    class_id,
+                    method_id,
+                ));
+            };
+            let result = channel.handle_method(broker, method);
+            channel_result(broker, connection, channel, result)
+        }
+    }
+}
+
+/// Handles a frame on a channel the broker is closing: everything but
+/// `Channel.Close` and `Channel.Close-Ok` is discarded.
+fn handle_closing_frame(channels: &mut HashMap<u16, Channel>, frame: AmqpFrame) -> Vec<AmqpFrame> {
+    let channel_id = frame.channel;
+    let method = match frame.frame_type {
+        FRAME_METHOD => Method::decode(&frame.payload).ok(),
+        _ => None,
+    };
+    match method {
+        Some(Method::ChannelCloseOk) => {
+            channels.remove(&channel_id);
+            vec![]
+        }
+        // Both sides closed at once; the client's Close still gets its Close-Ok.
+        Some(Method::ChannelClose { .. }) => {
+            channels.remove(&channel_id);
+            vec![AmqpFrame::method(channel_id, &Method::ChannelCloseOk)]
+        }
+        _ => {
+            debug!("Channel {} is closing, discarding {:?}", channel_id, frame);
+            vec![]
+        }
+    }
+}
+
+/// Turns a channel exception into the `Channel.Close` the broker sends,
+/// counting it against `connection`.
+fn channel_result(
+    broker: &Broker,
+    connection: u64,
+    channel: &mut Channel,
+    result: Result<Vec<AmqpFrame>, AmqpError>,
+) -> Result<Vec<AmqpFrame>, AmqpError> {
+    let channel_id = channel.id;
+    match result {
+        Err(AmqpError::ChannelException {
+            reply_code,
+            reply_text,
+            class_id,
+            method_id,
+        }) => {
+            warn!("Channel {} exception {}: {}", channel_id, reply_code, reply_text);
+            broker.channel_exception(connection, channel_id, reply_code, &reply_text);
+            channel.start_close(broker, reply_code, broker.config().channel_close_timeout);
+            let close = reply_codes::channel_close(reply_code, reply_text, class_id, method_id);
+            Ok(vec![AmqpFrame::method(channel_id, &close)])
+        }
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::*;
+    use crate::auth::{AuthFailure, AuthProvider, SaslSession};
+    use crate::broker::{ExchangeDeclare, QueueDeclare};
+    use crate::config::Config;
+    use crate::exchange::Binding;
+    use crate::field_table::TableLimits;
+    use crate::intercept::{InterceptAction, Interceptor};
+    use crate::message::Message;
+    use crate::properties::{BasicProperties, ServerTimestamp};
+    use crate::rewrite::{KeyTransform, RewriteRule};
+    use crate::store::{CompactionStats, MessageStore, StoredMessage};
+    use crate::test_support::TestClient;
+
+    fn method_frame(channel: u16, method: Method) -> AmqpFrame {
+        AmqpFrame::method(channel, &method)
+    }
+
+    fn decode_reply(frames: Vec<AmqpFrame>) -> Method {
+        assert_eq!(frames.len(), 1);
+        Method::decode(&frames[0].payload).unwrap()
+    }
+
+    #[test]
+    fn test_mixing_tx_and_confirm_closes_channel() {
+        let broker = Broker::new(Config::default());
+        let (deliveries, _inbox) = mpsc::unbounded_channel();
+        let mut channels = HashMap::new();
+        handle_frame(
+            &broker,
+            1,
+            Tuning::default(),
+            DEFAULT_VHOST,
+            None,
+            &deliveries,
+            &mut channels,
+            method_frame(1, Method::ChannelOpen),
+        )
+        .unwrap();
+        handle_frame(
+            &broker,
+            1,
+            Tuning::default(),
+            DEFAULT_VHOST,
+            None,
+            &deliveries,
+            &mut channels,
+            method_frame(1, Method::TxSelect),
+        )
+        .unwrap();
+
+        let reply = decode_reply(
+            handle_frame(
+                &broker,
+                1,
+                Tuning::default(),
+                DEFAULT_VHOST,
+                None,
+                &deliveries,
+                &mut channels,
+                method_frame(1, Method::ConfirmSelect { nowait: false }),
+            )
+            .unwrap(),
+        );
+        match reply {
+            Method::ChannelClose { reply_code, .. } => {
+                assert_eq!(reply_code, reply_codes::PRECONDITION_FAILED)
+            }
+            other => panic!("expected Channel.Close, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(2047u16, 0), 2047);
+        assert_eq!(negotiate(0u16, 10), 10);
+        assert_eq!(negotiate(2047u16, 10), 10);
+        assert_eq!(negotiate(0u16, 0), 0);
+    }
+
+    #[tokio::test]
+    async fn test_channel_above_channel_max_is_rejected() {
+        let mut client = TestClient::connect(Config::default()).await;
+        client.handshake_with(10).await;
+        client.send_method(11, &Method::ChannelOpen).await;
+        client.expect_connection_close(reply_codes::CHANNEL_ERROR).await;
+    }
+
+    #[tokio::test]
+    async fn test_channel_max_of_zero_allows_every_channel() {
+        let unlimited = || Config {
+            channel_max: 0,
+            ..Default::default()
+        };
+        let mut client = TestClient::connect(unlimited()).await;
+        client.handshake_with(0).await;
+        client.open_channel(50000).await;
+        client.open_channel(u16::MAX).await;
+
+        // One below the top of the channel space, the last channel is out.
+        let mut client = TestClient::connect(unlimited()).await;
+        client.handshake_with(u16::MAX - 1).await;
+        client.open_channel(u16::MAX - 1).await;
+        client.send_method(u16::MAX, &Method::ChannelOpen).await;
+        client.expect_connection_close(reply_codes::CHANNEL_ERROR).await;
+    }
+
+    #[tokio::test]
+    async fn test_reopening_open_channel_is_rejected() {
+        let mut client = TestClient::connect(Config::default()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client.send_method(1, &Method::ChannelOpen).await;
+        client.expect_connection_close(reply_codes::CHANNEL_ERROR).await;
+    }
+
+    #[tokio::test]
+    async fn test_short_method_frame_closes_with_syntax_error() {
+        for payload in [&[][..], &[0, 60], &[0, 60, 0, 40, 0]] {
+            let mut client = TestClient::connect(Config::default()).await;
+            client.handshake().await;
+            client.open_channel(1).await;
+            let frame = AmqpFrame {
+                frame_type: FRAME_METHOD,
+                channel: 1,
+                payload: payload.to_vec(),
+            };
+            client.send_frame(&frame).await;
+            client.expect_connection_close(reply_codes::SYNTAX_ERROR).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_with_over_length_routing_key_closes_with_syntax_error() {
+        let mut client = TestClient::connect(Config::default()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        // 255 bytes of invalid UTF-8 decode to three times as many.
+        let mut payload = vec![0, 60, 0, 40, 0, 0, 0, 255];
+        payload.extend([0xff; 255]);
+        payload.push(0);
+        let frame = AmqpFrame {
+            frame_type: FRAME_METHOD,
+            channel: 1,
+            payload,
+        };
+        client.send_frame(&frame).await;
+        client.expect_connection_close(reply_codes::SYNTAX_ERROR).await;
+    }
+
+    #[tokio::test]
+    async fn test_configured_table_limits_apply_to_methods_and_headers() {
+        let config = Config {
+            table_limits: TableLimits {
+                max_depth: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        // Within the default limits, but one table too deep for these.
+        let mut nested = FieldTable::new();
+        nested.insert("x", FieldValue::FieldTable(FieldTable::new()));
+        let mut deep = FieldTable::new();
+        deep.insert("nested", FieldValue::FieldTable(nested.clone()));
+        let declare = |arguments| Method::QueueDeclare {
+            queue: "jobs".into(),
+            passive: false,
+            durable: false,
+            exclusive: false,
+            auto_delete: false,
+            nowait: false,
+            arguments,
+        };
+
+        let mut client = TestClient::connect(config.clone()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client.send_method(1, &declare(nested)).await;
+        assert!(matches!(client.recv_method().await, (1, Method::QueueDeclareOk { .. })));
+        client.send_method(1, &declare(deep.clone())).await;
+        client.expect_connection_close(reply_codes::SYNTAX_ERROR).await;
+
+        let mut client = TestClient::connect(config).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        let properties = BasicProperties {
+            headers: Some(deep),
+            ..Default::default()
+        };
+        client.publish(1, "", "jobs", properties, b"body").await;
+        client.expect_connection_close(reply_codes::FRAME_ERROR).await;
+    }
+
+    #[tokio::test]
+    async fn test_method_on_unopened_channel_is_rejected() {
+        let mut client = TestClient::connect(Config::default()).await;
+        client.handshake().await;
+        client.send_method(3, &Method::TxSelect).await;
+        client.expect_connection_close(reply_codes::CHANNEL_ERROR).await;
+    }
+
+    /// Waits for a `Connection.Close` and returns its reply code and the
+    /// method it names.
+    async fn connection_close_of(client: &mut TestClient) -> (u16, u16, u16) {
+        match client.recv_method().await {
+            (
+                0,
+                Method::ConnectionClose {
+                    reply_code,
+                    class_id,
+                    method_id,
+                    ..
+                },
+            ) => (reply_code, class_id, method_id),
+            other => panic!("expected Connection.Close, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_methods_out_of_handshake_order_are_command_invalid() {
+        // Tune-Ok in answer to Connection.Start.
+        let mut client = TestClient::connect(Config::default()).await;
+        client.recv_method().await;
+        let tune_ok = Method::ConnectionTuneOk {
+            channel_max: 0,
+            frame_max: 0,
+            heartbeat: 0,
+        };
+        client.send_method(0, &tune_ok).await;
+        assert_eq!(
+            connection_close_of(&mut client).await,
+            (reply_codes::COMMAND_INVALID, CLASS_CONNECTION, 31)
+        );
+
+        // A queue declared on a channel before Connection.Open-Ok.
+        let mut client = TestClient::connect(Config::default()).await;
+        client.tune(0, None).await;
+        let declare = Method::QueueDeclare {
+            queue: "jobs".into(),
+            passive: false,
+            durable: false,
+            exclusive: false,
+            auto_delete: false,
+            nowait: false,
+            arguments: FieldTable::new(),
+        };
+        client.send_method(1, &declare).await;
+        assert_eq!(
+            connection_close_of(&mut client).await,
+            (reply_codes::COMMAND_INVALID, 50, 10)
+        );
+
+        // Connection.Open on a connection that is already open.
+        let mut client = TestClient::connect(Config::default()).await;
+        client.handshake().await;
+        client.send_vhost_open("/").await;
+        assert_eq!(
+            connection_close_of(&mut client).await,
+            (reply_codes::COMMAND_INVALID, CLASS_CONNECTION, 40)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_heartbeats_count_only_once_the_connection_is_tuned() {
+        let mut client = TestClient::connect(Config::default()).await;
+        client.tune(0, None).await;
+        client.send_frame(&AmqpFrame::heartbeat()).await;
+        client.open().await;
+        client.open_channel(1).await;
+
+        let mut client = TestClient::connect(Config::default()).await;
+        client.recv_method().await;
+        client.send_frame(&AmqpFrame::heartbeat()).await;
+        assert_eq!(
+            connection_close_of(&mut client).await,
+            (reply_codes::COMMAND_INVALID, 0, 0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_silent_peer_is_dropped_after_two_heartbeat_intervals() {
+        let mut client = TestClient::connect(Config {
+            heartbeat: 1,
+            ..Default::default()
+        })
+        .await;
+        client.handshake().await;
+        // Heartbeats keep the connection alive past the window.
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(800)).await;
+            client.send_frame(&AmqpFrame::heartbeat()).await;
+        }
+
+        // Then the client goes quiet, and only the broker's heartbeats arrive.
+        let paused = Instant::now();
+        while let Some(frame) = client.try_recv_frame(Duration::from_secs(4)).await {
+            assert_eq!(frame, AmqpFrame::heartbeat());
+        }
+        let waited = paused.elapsed();
+        assert!(waited >= Duration::from_millis(1900), "dropped after {:?}", waited);
+        assert!(waited < Duration::from_secs(3), "still open after {:?}", waited);
+        tokio::time::timeout(Duration::from_secs(1), &mut client.server)
+            .await
+            .expect("the broker kept the connection")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_method_in_the_middle_of_content_is_unexpected() {
+        let mut client = TestClient::connect(Config::default()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        let publish = Method::BasicPublish {
+            exchange: "amq.direct".into(),
+            routing_key: "jobs".into(),
+            mandatory: false,
+            immediate: false,
+        };
+        client.send_method(1, &publish).await;
+        let header = ContentHeader {
+            class_id: 60,
+            body_size: 10,
+            properties: Default::default(),
+        };
+        client.send_frame(&AmqpFrame::header(1, &header)).await;
+        client.send_frame(&AmqpFrame::body(1, b"hello")).await;
+        client.send_method(1, &Method::TxSelect).await;
+        client.expect_connection_close(reply_codes::UNEXPECTED_FRAME).await;
+    }
+
+    #[tokio::test]
+    async fn test_publish_without_its_body_times_out() {
+        let broker = work_queue_with(Config {
+            publish_timeout: Duration::from_millis(200),
+            ..Default::default()
+        });
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        let publish = Method::BasicPublish {
+            exchange: "work".into(),
+            routing_key: "jobs".into(),
+            mandatory: false,
+            immediate: false,
+        };
+        client.send_method(1, &publish).await;
+        let header = ContentHeader {
+            class_id: 60,
+            body_size: 10,
+            properties: Default::default(),
+        };
+        client.send_frame(&AmqpFrame::header(1, &header)).await;
+        let started = Instant::now();
+        client.expect_channel_close(1, reply_codes::UNEXPECTED_FRAME).await;
+        assert!(
+            started.elapsed() >= Duration::from_millis(150),
+            "closed after {:?}",
+            started.elapsed()
+        );
+        client.send_method(1, &Method::ChannelCloseOk).await;
+
+        // The partial message is gone; the reopened channel publishes anew.
+        client.open_channel(1).await;
+        client.publish(1, "work", "jobs", Default::default(), b"complete").await;
+        client.open_channel(2).await;
+        let jobs = &broker.queue_summaries()[0];
+        assert_eq!(jobs.messages, 1);
+        assert_eq!(
+            broker.get(DEFAULT_VHOST, "jobs", true).unwrap().unwrap().message.body,
+            b"complete"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_an_announced_body_over_max_message_size_is_refused_at_once() {
+        let broker = work_queue_with(Config {
+            max_message_size: 1024,
+            publish_timeout: Duration::from_secs(30),
+            ..Default::default()
+        });
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        let publish = Method::BasicPublish {
+            exchange: "work".into(),
+            routing_key: "jobs".into(),
+            mandatory: false,
+            immediate: false,
+        };
+        client.send_method(1, &publish).await;
+        let header = ContentHeader {
+            class_id: 60,
+            body_size: 1 << 40,
+            properties: Default::default(),
+        };
+        // No body follows; the header alone closes the channel.
+        client.send_frame(&AmqpFrame::header(1, &header)).await;
+        client.expect_channel_close(1, reply_codes::CONTENT_TOO_LARGE).await;
+        client.send_method(1, &Method::ChannelCloseOk).await;
+
+        client.open_channel(1).await;
+        client.publish(1, "work", "jobs", Default::default(), b"small").await;
+        client.open_channel(2).await;
+        assert_eq!(broker.queue_summaries()[0].messages, 1);
+    }
+
+    #[tokio::test]
+    async fn test_publish_to_a_missing_exchange_closes_the_channel() {
+        let mut client = TestClient::connect(Config::default()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client.open_channel(2).await;
+        client.send_method(1, &Method::TxSelect).await;
+        assert_eq!(client.recv_method().await, (1, Method::TxSelectOk));
+        client.publish(1, "nope", "jobs", Default::default(), b"lost").await;
+        client.expect_channel_close(1, reply_codes::NOT_FOUND).await;
+        client.send_method(1, &Method::ChannelCloseOk).await;
+
+        // The transaction went with the channel; the other channel is fine.
+        client.open_channel(1).await;
+        client.send_method(1, &Method::TxCommit).await;
+        client.expect_channel_close(1, reply_codes::PRECONDITION_FAILED).await;
+        client.publish(2, "", "jobs", Default::default(), b"kept").await;
+        client
+            .send_method(
+                2,
+                &Method::ChannelClose {
+                    reply_code: reply_codes::REPLY_SUCCESS,
+                    reply_text: "bye".into(),
+                    class_id: 0,
+                    method_id: 0,
+                },
+            )
+            .await;
+        assert_eq!(client.recv_method().await, (2, Method::ChannelCloseOk));
+    }
+
+    #[tokio::test]
+    async fn test_zero_body_messages_complete_at_their_header() {
+        let mut client = TestClient::connect_to(work_queue()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client.consume(1, "jobs", true).await;
+        let mut headers = FieldTable::new();
+        headers.insert("kind", FieldValue::LongString(b"ping".to_vec()));
+        let properties = BasicProperties {
+            content_type: Some("application/json".into()),
+            headers: Some(headers),
+            correlation_id: Some("c-1".into()),
+            ..Default::default()
+        };
+        // Header only: nothing follows it for the broker to wait on.
+        client.publish(1, "work", "jobs", properties.clone(), b"").await;
+        client.publish(1, "work", "jobs", Default::default(), b"next").await;
+
+        let (_, deliver) = client.recv_method().await;
+        assert!(matches!(deliver, Method::BasicDeliver { .. }), "got {:?}", deliver);
+        let header = ContentHeader::decode(&client.recv_frame().await.payload).unwrap();
+        assert_eq!((header.body_size, header.properties), (0, properties));
+        // No body frame is sent; the next delivery follows at once.
+        assert_eq!(client.recv_delivery().await.1, b"next");
+    }
+
+    #[tokio::test]
+    async fn test_malformed_heartbeats_are_frame_errors() {
+        for (channel, payload) in [(1, vec![]), (0, vec![0])] {
+            let mut client = TestClient::connect(Config::default()).await;
+            client.handshake().await;
+            client.send_frame(&AmqpFrame::heartbeat()).await;
+            let heartbeat = AmqpFrame {
+                channel,
+                payload,
+                ..AmqpFrame::heartbeat()
+            };
+            client.send_frame(&heartbeat).await;
+            client.expect_connection_close(reply_codes::FRAME_ERROR).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_content_without_publish_is_unexpected() {
+        let mut client = TestClient::connect(Config::default()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client.send_frame(&AmqpFrame::body(1, b"hello")).await;
+        client.expect_connection_close(reply_codes::UNEXPECTED_FRAME).await;
+    }
+
+    #[tokio::test]
+    async fn test_channel_within_limit_opens() {
+        let mut client = TestClient::connect(Config::default()).await;
+        client.handshake_with(10).await;
+        client.open_channel(10).await;
+    }
+
+    #[tokio::test]
+    async fn test_channels_past_the_warning_threshold_are_counted_not_closed() {
+        let broker = Arc::new(Broker::new(Config {
+            channel_warning_threshold: 3,
+            ..Config::default()
+        }));
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.handshake().await;
+        for channel in 1..=5 {
+            client.open_channel(channel).await;
+        }
+        let listed = |broker: &Broker| broker.connections().open_connections()[0].channels;
+        assert_eq!(listed(&broker), 5);
+        assert_eq!(broker.channel_warnings(), 1);
+
+        // Staying over the threshold does not warn again; dropping back to it
+        // and crossing it once more does.
+        client.open_channel(6).await;
+        for channel in 4..=6 {
+            let close = Method::ChannelClose {
+                reply_code: reply_codes::REPLY_SUCCESS,
+                reply_text: String::new(),
+                class_id: 0,
+                method_id: 0,
+            };
+            client.send_method(channel, &close).await;
+            assert_eq!(client.recv_method().await, (channel, Method::ChannelCloseOk));
+        }
+        assert_eq!(listed(&broker), 3);
+        assert_eq!(broker.channel_warnings(), 1);
+        client.open_channel(4).await;
+        assert_eq!(broker.channel_warnings(), 2);
+        let metrics = crate::metrics::render(&broker);
+        assert!(metrics.contains("haymq_channel_count_warnings_total 2"), "{}", metrics);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_vhost_is_not_allowed() {
+        let mut client = TestClient::connect(Config::default()).await;
+        client.tune(0, None).await;
+        client.send_vhost_open("nope").await;
+        client.expect_connection_close(reply_codes::NOT_ALLOWED).await;
+    }
+
+    #[tokio::test]
+    async fn test_vhost_names_are_normalized() {
+        let broker = Arc::new(Broker::new(Config {
+            vhosts: vec!["staging".into()],
+            ..Config::default()
+        }));
+        let mut events = broker.subscribe_events();
+        for requested in ["/", "%2F", "staging", "%73tag%69ng"] {
+            let mut client = TestClient::connect_to(broker.clone()).await;
+            client.tune(0, None).await;
+            client.open_vhost(requested).await;
+        }
+        let opened: Vec<String> = std::iter::from_fn(|| events.try_recv().ok())
+            .filter_map(|event| match event {
+                BrokerEvent::ConnectionOpened { vhost, .. } => Some(vhost),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(opened, vec!["/", "/", "staging", "staging"]);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_vhost_name_is_not_allowed() {
+        for requested in ["", "%", "%2", "%zz", "bad name", "%00", "%FF"] {
+            let mut client = TestClient::connect(Config::default()).await;
+            client.tune(0, None).await;
+            client.send_vhost_open(requested).await;
+            let (_, close) = client.recv_method().await;
+            let Method::ConnectionClose {
+                reply_code, reply_text, ..
+            } = close
+            else {
+                panic!("expected Connection.Close for {:?}, got {:?}", requested, close);
+            };
+            assert_eq!(reply_code, reply_codes::NOT_ALLOWED, "{:?}", requested);
+            assert!(
+                reply_text.starts_with("NOT_ALLOWED - invalid vhost name"),
+                "{}",
+                reply_text
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sni_hostname_picks_the_default_vhost() {
+        let config = Config {
+            vhosts: vec!["alpha".into(), "beta".into()],
+            sni_vhosts: [("alpha.example.com", "alpha"), ("beta.example.com", "beta")]
+                .into_iter()
+                .map(|(hostname, vhost)| (hostname.to_string(), vhost.to_string()))
+                .collect(),
+            ..Config::default()
+        };
+        let broker = Arc::new(Broker::new(config));
+        let mut events = broker.subscribe_events();
+
+        for (server_name, requested) in [
+            (Some("alpha.example.com"), "/"),
+            (Some("BETA.example.com"), "/"),
+            (Some("alpha.example.com"), "beta"),
+            (Some("other.example.com"), "/"),
+            (None, "/"),
+        ] {
+            let mut client = TestClient::connect_tls(broker.clone(), None, server_name).await;
+            client.tune(0, None).await;
+            client.open_vhost(requested).await;
+        }
+        let opened: Vec<String> = std::iter::from_fn(|| events.try_recv().ok())
+            .filter_map(|event| match event {
+                BrokerEvent::ConnectionOpened { vhost, .. } => Some(vhost),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(opened, vec!["alpha", "beta", "beta", "/", "/"]);
+    }
+
+    #[tokio::test]
+    async fn test_frame_max_below_minimum_is_not_allowed() {
+        let mut client = TestClient::connect(Config::default()).await;
+        client.tune(0, Some(FRAME_MIN_SIZE - 1)).await;
+        client.expect_connection_close(reply_codes::NOT_ALLOWED).await;
+    }
+
+    #[tokio::test]
+    async fn test_negotiated_frame_max_splits_deliveries_and_limits_frames() {
+        let mut client = TestClient::connect_to(work_queue()).await;
+        client.tune(0, Some(FRAME_MIN_SIZE)).await;
+        client.open().await;
+        client.open_channel(1).await;
+        client.consume(1, "jobs", true).await;
+
+        let body = vec![7u8; 10_000];
+        client.broker.publish(
+            DEFAULT_VHOST,
+            Message {
+                exchange: "work".into(),
+                routing_key: "jobs".into(),
+                properties: BasicProperties::default(),
+                body: body.clone(),
+            },
+            false,
+        );
+        client.recv_frame().await;
+        client.recv_frame().await;
+        let mut received = Vec::new();
+        while received.len() < body.len() {
+            let frame = client.recv_frame().await;
+            assert_eq!(frame.frame_type, FRAME_BODY);
+            assert!(frame.encode().len() <= FRAME_MIN_SIZE as usize);
+            received.extend(frame.payload);
+        }
+        assert_eq!(received, body);
+
+        // A frame above the negotiated size is a connection error.
+        client.send_frame(&AmqpFrame::body(1, &vec![0u8; FRAME_MIN_SIZE as usize])).await;
+        client.expect_connection_close(reply_codes::FRAME_ERROR).await;
+    }
+
+    fn work_queue() -> Arc<Broker> {
+        work_queue_with(Config::default())
+    }
+
+    fn work_queue_with(config: Config) -> Arc<Broker> {
+        let broker = Arc::new(Broker::new(config));
+        broker
+            .declare_queue(
+                DEFAULT_VHOST,
+                QueueDeclare {
+                    queue: "jobs".into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        broker
+            .declare_exchange(
+                DEFAULT_VHOST,
+                ExchangeDeclare {
+                    exchange: "work".into(),
+                    kind: "direct".into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let binding = Binding {
+            queue: "jobs".into(),
+            routing_key: "jobs".into(),
+            arguments: FieldTable::new(),
+        };
+        broker.bind_queue(DEFAULT_VHOST, binding, "work").unwrap();
+        broker
+    }
+
+    /// Opens a connection whose server task result the test can inspect.
+    async fn connect_with_result() -> (TestClient, tokio::task::JoinHandle<Result<(), ConnectionFailure>>) {
+        let broker = Arc::new(Broker::new(Config::default()));
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let handle = tokio::spawn(handle_connection(server, broker.clone()));
+        let mut client = TestClient::from_stream(broker, client, tokio::spawn(async {}));
+        client.send_raw(b"AMQP\x00\x00\x09\x01").await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        (client, handle)
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_versions_get_the_supported_header_back() {
+        let broker = Arc::new(Broker::new(Config::default()));
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        let handle = tokio::spawn(handle_connection(server, broker));
+        client.write_all(b"AMQP\x01\x01\x00\x09").await.unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"AMQP\x00\x00\x09\x01");
+
+        let failure = handle.await.unwrap().unwrap_err();
+        let ConnectionError::InvalidHeader(HeaderError::Unsupported(version)) = failure.error else {
+            panic!("{:?}", failure);
+        };
+        assert_eq!(version.to_string(), "0-9-0");
+        assert_eq!(
+            failure.to_string(),
+            "invalid protocol header: unsupported AMQP version 0-9-0"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stalled_handshake_is_closed_at_the_timeout() {
+        let broker = Arc::new(Broker::new(Config {
+            handshake_timeout: Duration::from_millis(300),
+            ..Default::default()
+        }));
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let slot = broker.connections().reserve_handshake().await;
+        let started = Instant::now();
+        let handle = tokio::spawn(handle_accepted_connection(server, None, slot, broker.clone()));
+        let mut client = TestClient::from_stream(broker.clone(), client, tokio::spawn(async {}));
+        assert_eq!(broker.health().pending_handshakes.map(|(pending, _)| pending), Some(1));
+        // The header comes late and Start-Ok never does; one timeout covers both.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        client.send_raw(b"AMQP\x00\x00\x09\x01").await;
+        assert!(matches!(
+            client.recv_method().await,
+            (0, Method::ConnectionStart { .. })
+        ));
+
+        let failure = handle.await.unwrap().unwrap_err();
+        let elapsed = started.elapsed();
+        assert!(
+            matches!(&failure.error, ConnectionError::Io(e) if e.kind() == std::io::ErrorKind::TimedOut),
+            "{:?}",
+            failure
+        );
+        assert!(elapsed >= Duration::from_millis(250), "closed after {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(450), "closed after {:?}", elapsed);
+        assert_eq!(broker.health().pending_handshakes.map(|(pending, _)| pending), Some(0));
+        assert!(client.try_recv_frame(Duration::from_secs(1)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_eof_at_frame_boundary_is_a_clean_close() {
+        let (mut client, server) = connect_with_result().await;
+        client.shutdown().await;
+        assert!(server.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_eof_mid_frame_is_an_error() {
+        let (mut client, server) = connect_with_result().await;
+        let frame = AmqpFrame::method(
+            1,
+            &Method::ChannelClose {
+                reply_code: 200,
+                reply_text: "bye".into(),
+                class_id: 0,
+                method_id: 0,
+            },
+        )
+        .encode();
+        client.send_raw(&frame[..frame.len() - 3]).await;
+        client.shutdown().await;
+        let failure = server.await.unwrap().unwrap_err();
+        assert!(
+            matches!(&failure.error, ConnectionError::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof),
+            "{:?}",
+            failure
+        );
+        assert_eq!(failure.level(), log::Level::Info);
+    }
+
+    #[tokio::test]
+    async fn test_methods_must_match_their_channel() {
+        let mut client = TestClient::connect(Config::default()).await;
+        client.handshake().await;
+        let declare = Method::QueueDeclare {
+            queue: "jobs".into(),
+            passive: false,
+            durable: false,
+            exclusive: false,
+            auto_delete: false,
+            nowait: false,
+            arguments: FieldTable::new(),
+        };
+        client.send_method(0, &declare).await;
+        client.expect_connection_close(reply_codes::COMMAND_INVALID).await;
+
+        let mut client = TestClient::connect(Config::default()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        let close = Method::ConnectionClose {
+            reply_code: reply_codes::REPLY_SUCCESS,
+            reply_text: "bye".into(),
+            class_id: 0,
+            method_id: 0,
+        };
+        client.send_method(1, &close).await;
+        let (channel, method) = client.recv_method().await;
+        assert_eq!(channel, 0);
+        assert!(
+            matches!(&method, Method::ConnectionClose { reply_code, reply_text, .. }
+                if *reply_code == reply_codes::COMMAND_INVALID && reply_text.ends_with("on channel 1")),
+            "{:?}",
+            method
+        );
+    }
+
+    #[tokio::test]
+    async fn test_protocol_error_failure_carries_the_connection_context() {
+        let (mut client, server) = connect_with_result().await;
+        client.send_method(0, &Method::ChannelOpen).await;
+        client.expect_connection_close(reply_codes::COMMAND_INVALID).await;
+        client.send_method(0, &Method::ConnectionCloseOk).await;
+        let failure = server.await.unwrap().unwrap_err();
+        let id = failure.connection.expect("failure without a connection id");
+        assert_eq!(failure.vhost.as_deref(), Some("/"));
+        assert_eq!(failure.user.as_deref(), Some("guest"));
+        assert_eq!(failure.reply_code(), Some(reply_codes::COMMAND_INVALID));
+        assert_eq!(failure.level(), log::Level::Warn);
+        assert_eq!(
+            failure.fields(),
+            format!(
+                "connection={} vhost=/ user=guest reply_code={}",
+                id,
+                reply_codes::COMMAND_INVALID
+            )
+        );
+        assert!(failure.to_string().starts_with("COMMAND_INVALID"), "{}", failure);
+    }
+
+    #[tokio::test]
+    async fn test_garbage_byte_is_skipped_by_resyncing() {
+        let mut client = TestClient::connect(Config {
+            frame_resync_limit: 4,
+            ..Default::default()
+        })
+        .await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        // A stray byte before its frame-end loses the frame it lands in.
+        let mut corrupt = AmqpFrame::method(2, &Method::ChannelOpen).encode();
+        corrupt.insert(corrupt.len() - 1, 0x42);
+        client.send_raw(&corrupt).await;
+        client.send_method(3, &Method::ChannelOpen).await;
+        assert_eq!(client.recv_method().await, (3, Method::ChannelOpenOk));
+
+        // The connection is aligned again: channel 2 was never opened.
+        client.send_method(2, &Method::ChannelOpen).await;
+        assert_eq!(client.recv_method().await, (2, Method::ChannelOpenOk));
+    }
+
+    #[tokio::test]
+    async fn test_failed_resyncs_close_the_connection() {
+        let mut client = TestClient::connect(Config {
+            frame_resync_limit: 2,
+            ..Default::default()
+        })
+        .await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        let mut corrupt = AmqpFrame::method(1, &Method::ChannelFlowOk { active: true }).encode();
+        *corrupt.last_mut().unwrap() = 0;
+        // Every marker that follows starts a frame too large to be one.
+        corrupt.extend([FRAME_END; 16]);
+        client.send_raw(&corrupt).await;
+        client.expect_connection_close(reply_codes::FRAME_ERROR).await;
+    }
+
+    #[tokio::test]
+    async fn test_malformed_frame_closes_with_frame_error() {
+        let mut client = TestClient::connect(Config::default()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        let mut corrupt = AmqpFrame::method(1, &Method::ChannelFlowOk { active: true }).encode();
+        *corrupt.last_mut().unwrap() = 0;
+        client.send_raw(&corrupt).await;
+        client.expect_connection_close(reply_codes::FRAME_ERROR).await;
+    }
+
+    /// Connects with `connection_close_timeout` and makes the broker close
+    /// the connection for a method it does not expect on channel 0.
+    async fn closed_by_broker(timeout: Duration) -> TestClient {
+        let mut client = TestClient::connect(Config {
+            connection_close_timeout: timeout,
+            ..Default::default()
+        })
+        .await;
+        client.handshake().await;
+        client.send_method(0, &Method::ChannelOpen).await;
+        client.expect_connection_close(reply_codes::COMMAND_INVALID).await;
+        client
+    }
+
+    #[tokio::test]
+    async fn test_connection_without_close_ok_drops_after_the_timeout() {
+        let mut client = closed_by_broker(Duration::from_millis(300)).await;
+        let closed = Instant::now();
+        // Frames other than Close-Ok go unanswered while the broker waits.
+        client.send_method(1, &Method::ChannelOpen).await;
+        assert!(client.try_recv_frame(Duration::from_secs(2)).await.is_none());
+        let waited = closed.elapsed();
+        assert!(waited >= Duration::from_millis(250), "dropped after {:?}", waited);
+        assert!(waited < Duration::from_secs(2), "still open after {:?}", waited);
+    }
+
+    #[tokio::test]
+    async fn test_close_ok_ends_the_connection_at_once() {
+        let mut client = closed_by_broker(Duration::from_secs(30)).await;
+        client.send_method(0, &Method::ConnectionCloseOk).await;
+        tokio::time::timeout(Duration::from_secs(1), &mut client.server)
+            .await
+            .expect("the broker kept the connection after Close-Ok")
+            .unwrap();
+    }
+
+    /// Publishes to "jobs" with `timestamp` set, or not, under `policy`
+    /// and returns the timestamp the consumer sees.
+    async fn delivered_timestamp(policy: ServerTimestamp, timestamp: Option<u64>) -> Option<u64> {
+        let broker = work_queue_with(Config {
+            server_timestamp: policy,
+            ..Default::default()
+        });
+        let mut client = TestClient::connect_to(broker).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client.consume(1, "jobs", true).await;
+        let properties = BasicProperties {
+            timestamp,
+            ..Default::default()
+        };
+        client.publish(1, "work", "jobs", properties, b"stamped").await;
+        assert!(matches!(client.recv_method().await, (1, Method::BasicDeliver { .. })));
+        let header = ContentHeader::decode(&client.recv_frame().await.payload).unwrap();
+        header.properties.timestamp
+    }
+
+    #[tokio::test]
+    async fn test_server_timestamp_is_set_on_publish() {
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let stamped = delivered_timestamp(ServerTimestamp::IfAbsent, None).await.unwrap();
+        let after = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert!(
+            (before..=after).contains(&stamped),
+            "{} not in {}..={}",
+            stamped,
+            before,
+            after
+        );
+
+        assert_eq!(delivered_timestamp(ServerTimestamp::IfAbsent, Some(42)).await, Some(42));
+        assert_ne!(delivered_timestamp(ServerTimestamp::Force, Some(42)).await, Some(42));
+        assert_eq!(delivered_timestamp(ServerTimestamp::Off, None).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_frame_flood_is_read_at_the_frame_rate() {
+        let config = Config {
+            max_frame_rate: 100,
+            frame_rate_burst: 10,
+            ..Default::default()
+        };
+        let mut client = TestClient::connect_to(Arc::new(Broker::new(config))).await;
+        client.handshake().await;
+        // 60 heartbeats, then a channel open behind them: past the burst
+        // of 10 they are read at 100 a second.
+        let mut flood = b"\x08\x00\x00\x00\x00\x00\x00\xce".repeat(60);
+        flood.extend(AmqpFrame::method(1, &Method::ChannelOpen).encode());
+        let started = Instant::now();
+        client.send_raw(&flood).await;
+        let (channel, open_ok) = tokio::time::timeout(Duration::from_secs(5), client.recv_method())
+            .await
+            .unwrap();
+        assert_eq!((channel, open_ok), (1, Method::ChannelOpenOk));
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(400), "read the flood in {:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_mandatory_return_carries_the_published_message() {
+        let broker = work_queue_with(Config {
+            frame_max: 4096,
+            server_timestamp: ServerTimestamp::Force,
+            ..Default::default()
+        });
+        let mut client = TestClient::connect_to(broker).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        let mut headers = FieldTable::new();
+        headers.insert("attempt", FieldValue::LongInt(3));
+        headers.insert("source", FieldValue::LongString(b"billing".to_vec()));
+        let message = Message {
+            exchange: "work".into(),
+            routing_key: "nowhere".into(),
+            properties: BasicProperties {
+                content_type: Some("application/json".into()),
+                content_encoding: Some("gzip".into()),
+                headers: Some(headers),
+                delivery_mode: Some(2),
+                priority: Some(7),
+                correlation_id: Some("c-1".into()),
+                reply_to: Some("replies".into()),
+                expiration: Some("60000".into()),
+                message_id: Some("m-1".into()),
+                timestamp: Some(1_700_000_000),
+                kind: Some("invoice".into()),
+                app_id: Some("billing".into()),
+                ..Default::default()
+            },
+            body: (0..10_000u32).map(|i| i as u8).collect(),
+        };
+        let publish = Method::BasicPublish {
+            exchange: message.exchange.clone(),
+            routing_key: message.routing_key.clone(),
+            mandatory: true,
+            immediate: false,
+        };
+        for frame in message.clone().into_frames(1, &publish, 4096) {
+            client.send_frame(&frame).await;
+        }
+
+        // The return comes as a method, a header and body frames within the
+        // frame size, reassembling into the very message published.
+        let mut frames = vec![client.recv_frame().await, client.recv_frame().await];
+        let mut received = 0;
+        while received < message.body.len() {
+            let frame = client.recv_frame().await;
+            assert!(frame.encode().len() <= 4096);
+            received += frame.payload.len();
+            frames.push(frame);
+        }
+        assert_eq!(
+            Method::decode(&frames[0].payload).unwrap(),
+            Method::BasicReturn {
+                reply_code: reply_codes::NO_ROUTE,
+                reply_text: "NO_ROUTE".into(),
+                exchange: "work".into(),
+                routing_key: "nowhere".into(),
+            }
+        );
+        assert_eq!(Message::from_frames(&frames).unwrap(), message);
+    }
+
+    #[tokio::test]
+    async fn test_routing_keys_are_rewritten_before_routing() {
+        let broker = work_queue_with(Config {
+            routing_key_rewrites: vec![RewriteRule {
+                exchange: "work".into(),
+                routing_key: "*".into(),
+                transform: KeyTransform::Lowercase,
+            }],
+            ..Default::default()
+        });
+        let mut client = TestClient::connect_to(broker).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client.consume(1, "jobs", true).await;
+        client
+            .publish(1, "work", "JoBs", BasicProperties::default(), b"mixed")
+            .await;
+        let (deliver, body) = client.recv_delivery().await;
+        assert!(matches!(deliver, Method::BasicDeliver { routing_key, .. } if routing_key == "jobs"));
+        assert_eq!(body, b"mixed");
+
+        // A returned message keeps the key it was published with.
+        let message = Message {
+            exchange: "work".into(),
+            routing_key: "NoWhere".into(),
+            properties: BasicProperties::default(),
+            body: b"lost".to_vec(),
+        };
+        let publish = Method::BasicPublish {
+            exchange: "work".into(),
+            routing_key: "NoWhere".into(),
+            mandatory: true,
+            immediate: false,
+        };
+        for frame in message.into_frames(1, &publish, 4096) {
+            client.send_frame(&frame).await;
+        }
+        assert!(matches!(
+            client.recv_method().await,
+            (1, Method::BasicReturn { routing_key, .. }) if routing_key == "NoWhere"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_exceptions_are_counted_and_emitted() {
+        let broker = Arc::new(Broker::new(Config::default()));
+        let mut events = broker.subscribe_events();
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client
+            .send_method(
+                1,
+                &Method::QueueDeclare {
+                    queue: "missing".into(),
+                    passive: true,
+                    durable: false,
+                    exclusive: false,
+                    auto_delete: false,
+                    nowait: false,
+                    arguments: FieldTable::new(),
+                },
+            )
+            .await;
+        client.expect_channel_close(1, reply_codes::NOT_FOUND).await;
+        // A method on a channel that was never opened is a connection exception.
+        client
+            .send_method(
+                2,
+                &Method::BasicQos {
+                    prefetch_size: 0,
+                    prefetch_count: 1,
+                    global: false,
+                },
+            )
+            .await;
+        client.expect_connection_close(reply_codes::CHANNEL_ERROR).await;
+
+        let raised: Vec<(Option<u16>, u16)> = std::iter::from_fn(|| events.try_recv().ok())
+            .filter_map(|event| match event {
+                BrokerEvent::ChannelException {
+                    channel, reply_code, ..
+                } => Some((Some(channel), reply_code)),
+                BrokerEvent::ConnectionException { reply_code, .. } => Some((None, reply_code)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            raised,
+            [(Some(1), reply_codes::NOT_FOUND), (None, reply_codes::CHANNEL_ERROR)]
+        );
+        let metrics = crate::metrics::render(&broker);
+        assert!(
+            metrics.contains("haymq_channel_exceptions_total{code=\"404\"} 1\n"),
+            "{}",
+            metrics
+        );
+        assert!(
+            metrics.contains("haymq_connection_exceptions_total{code=\"504\"} 1\n"),
+            "{}",
+            metrics
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connection_lifecycle_emits_events() {
+        let broker = Arc::new(Broker::new(Config::default()));
+        let mut events = broker.subscribe_events();
+        let mut client = TestClient::connect_to(broker).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client
+            .send_method(
+                1,
+                &Method::QueueDeclare {
+                    queue: "jobs".into(),
+                    passive: false,
+                    durable: false,
+                    exclusive: false,
+                    auto_delete: false,
+                    nowait: false,
+                    arguments: FieldTable::new(),
+                },
+            )
+            .await;
+        client.recv_method().await;
+        client
+            .send_method(
+                1,
+                &Method::ChannelClose {
+                    reply_code: reply_codes::REPLY_SUCCESS,
+                    reply_text: "bye".into(),
+                    class_id: 0,
+                    method_id: 0,
+                },
+            )
+            .await;
+        assert_eq!(client.recv_method().await, (1, Method::ChannelCloseOk));
+        // Channel 2 is still open when the connection goes away.
+        client.open_channel(2).await;
+        client
+            .send_method(
+                0,
+                &Method::ConnectionClose {
+                    reply_code: reply_codes::REPLY_SUCCESS,
+                    reply_text: "bye".into(),
+                    class_id: 0,
+                    method_id: 0,
+                },
+            )
+            .await;
+        assert_eq!(client.recv_method().await, (0, Method::ConnectionCloseOk));
+        (&mut client.server).await.unwrap();
+
+        let mut seen = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            seen.push(event);
+        }
+        assert_eq!(
+            seen,
+            vec![
+                BrokerEvent::ConnectionOpened {
+                    connection: 1,
+                    user: Some("guest".into()),
+                    vhost: "/".into(),
+                },
+                BrokerEvent::ChannelOpened {
+                    connection: 1,
+                    channel: 1
+                },
+                BrokerEvent::QueueDeclared {
+                    vhost: "/".into(),
+                    queue: "jobs".into(),
+                },
+                BrokerEvent::ChannelClosed {
+                    connection: 1,
+                    channel: 1
+                },
+                BrokerEvent::ChannelOpened {
+                    connection: 1,
+                    channel: 2
+                },
+                BrokerEvent::ChannelClosed {
+                    connection: 1,
+                    channel: 2
+                },
+                BrokerEvent::ConnectionClosed { connection: 1 },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publishing_over_rate_pauses_the_channel() {
+        let broker = Arc::new(Broker::new(Config::default()));
+        let mut arguments = FieldTable::new();
+        arguments.insert("x-max-publish-rate", FieldValue::LongInt(10));
+        let declare = QueueDeclare {
+            queue: "jobs".into(),
+            arguments,
+            ..Default::default()
+        };
+        broker.declare_queue(DEFAULT_VHOST, declare).unwrap();
+        let binding = Binding {
+            queue: "jobs".into(),
+            routing_key: "jobs".into(),
+            arguments: FieldTable::new(),
+        };
+        broker.bind_queue(DEFAULT_VHOST, binding, "amq.direct").unwrap();
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+
+        for _ in 0..11 {
+            client
+                .publish(1, "amq.direct", "jobs", BasicProperties::default(), b"fast")
+                .await;
+        }
+        assert_eq!(client.recv_method().await, (1, Method::ChannelFlow { active: false }));
+        // The bucket refills at 10 per second, so the pause lasts about 200ms.
+        let started = Instant::now();
+        assert_eq!(client.recv_method().await, (1, Method::ChannelFlow { active: true }));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        // Throttling never loses messages.
+        assert_eq!(broker.peek(DEFAULT_VHOST, "jobs", 100).unwrap().len(), 11);
+    }
+
+    /// A broker where fanout exchange "work" feeds queue "jobs", declared
+    /// with `arguments` and dead-lettering to fanout "dlx", which feeds "dead".
+    fn dead_lettering_jobs(mut arguments: FieldTable) -> Arc<Broker> {
+        let broker = Arc::new(Broker::new(Config::default()));
+        arguments.insert("x-dead-letter-exchange", FieldValue::LongString(b"dlx".to_vec()));
+        for (queue, exchange, arguments) in [("jobs", "work", arguments), ("dead", "dlx", FieldTable::new())] {
+            let declare = QueueDeclare {
+                queue: queue.into(),
+                arguments,
+                ..Default::default()
+            };
+            broker.declare_queue(DEFAULT_VHOST, declare).unwrap();
+            let declare = ExchangeDeclare {
+                exchange: exchange.into(),
+                kind: "fanout".into(),
+                ..Default::default()
+            };
+            broker.declare_exchange(DEFAULT_VHOST, declare).unwrap();
+            let binding = Binding {
+                queue: queue.into(),
+                routing_key: "".into(),
+                arguments: FieldTable::new(),
+            };
+            broker.bind_queue(DEFAULT_VHOST, binding, exchange).unwrap();
+        }
+        broker
+    }
+
+    /// Headers exercising every field value type, nested tables and arrays,
+    /// and keys in an order no sorting would produce.
+    fn rich_headers() -> FieldTable {
+        let mut inner = FieldTable::new();
+        inner.insert("zeta", FieldValue::ShortShortInt(-8));
+        inner.insert("alpha", FieldValue::Decimal(2, 31415));
+        inner.insert("deeper", FieldValue::FieldTable(inner.clone()));
+        let mut headers = FieldTable::new();
+        headers.insert("trace-id", FieldValue::LongString("ümlaut ✓".as_bytes().to_vec()));
+        headers.insert("flag", FieldValue::Boolean(true));
+        headers.insert("octet", FieldValue::ShortShortUint(200));
+        headers.insert("short", FieldValue::ShortInt(-300));
+        headers.insert("ushort", FieldValue::ShortUint(60000));
+        headers.insert("long", FieldValue::LongInt(-70000));
+        headers.insert("ulong", FieldValue::LongUint(4_000_000_000));
+        headers.insert("longlong", FieldValue::LongLongInt(i64::MIN));
+        headers.insert("ulonglong", FieldValue::LongLongUint(u64::MAX));
+        headers.insert("float", FieldValue::Float(1.5));
+        headers.insert("double", FieldValue::Double(-2.25e300));
+        headers.insert("when", FieldValue::Timestamp(1_700_000_000));
+        headers.insert("nothing", FieldValue::Void);
+        headers.insert("raw", FieldValue::ByteArray(vec![0, 255, 1]));
+        headers.insert(
+            "list",
+            FieldValue::FieldArray(vec![
+                FieldValue::LongInt(1),
+                FieldValue::FieldTable(inner.clone()),
+                FieldValue::FieldArray(vec![]),
+            ]),
+        );
+        headers.insert("nested", FieldValue::FieldTable(inner));
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_headers_survive_delivery_and_dead_lettering() {
+        let broker = dead_lettering_jobs(FieldTable::new());
+        let mut client = TestClient::connect_to(broker).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        let properties = BasicProperties {
+            headers: Some(rich_headers()),
+            ..Default::default()
+        };
+        let published = AmqpFrame::header(
+            1,
+            &ContentHeader {
+                class_id: 60,
+                body_size: 4,
+                properties: properties.clone(),
+            },
+        );
+        client.publish(1, "work", "", properties, b"body").await;
+        client.consume(1, "jobs", false).await;
+
+        let deliver = client.recv_frame().await;
+        assert!(matches!(
+            Method::decode(&deliver.payload),
+            Ok(Method::BasicDeliver { delivery_tag: 1, .. })
+        ));
+        // The content header comes back exactly as it was published.
+        assert_eq!(client.recv_frame().await.payload, published.payload);
+        assert_eq!(client.recv_frame().await.payload, b"body");
+
+        let nack = Method::BasicNack {
+            delivery_tag: 1,
+            multiple: false,
+            requeue: false,
+        };
+        client.send_method(1, &nack).await;
+        client.consume(1, "dead", true).await;
+        client.recv_frame().await;
+        let header = ContentHeader::decode(&client.recv_frame().await.payload).unwrap();
+        client.recv_frame().await;
+        let headers = header.properties.headers.unwrap();
+        // Dead-lettering adds keys after the publisher's, which stay as they were.
+        let published: Vec<_> = rich_headers().iter().map(|(k, v)| (k.to_string(), v.clone())).collect();
+        let received: Vec<_> = headers.iter().map(|(k, v)| (k.to_string(), v.clone())).collect();
+        assert_eq!(received[..published.len()], published[..]);
+        let added: Vec<&str> = received[published.len()..].iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(
+            added,
+            [
+                "x-death",
+                "x-first-death-reason",
+                "x-first-death-queue",
+                "x-first-death-exchange"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reject_publish_dlx_nacks_and_dead_letters() {
+        let mut arguments = FieldTable::new();
+        arguments.insert("x-max-length", FieldValue::LongInt(1));
+        arguments.insert("x-overflow", FieldValue::LongString(b"reject-publish-dlx".to_vec()));
+        let broker = dead_lettering_jobs(arguments);
+
+        let mut consumer = TestClient::connect_to(broker.clone()).await;
+        consumer.handshake().await;
+        consumer.open_channel(1).await;
+        consumer.consume(1, "dead", true).await;
+
+        let mut publisher = TestClient::connect_to(broker).await;
+        publisher.handshake().await;
+        publisher.open_channel(1).await;
+        publisher.send_method(1, &Method::ConfirmSelect { nowait: false }).await;
+        assert_eq!(publisher.recv_method().await, (1, Method::ConfirmSelectOk));
+
+        publisher
+            .publish(1, "work", "jobs", BasicProperties::default(), b"fits")
+            .await;
+        let ack = Method::BasicAck {
+            delivery_tag: 1,
+            multiple: false,
+        };
+        assert_eq!(publisher.recv_method().await, (1, ack));
+        publisher
+            .publish(1, "work", "jobs", BasicProperties::default(), b"overflows")
+            .await;
+        let nack = Method::BasicNack {
+            delivery_tag: 2,
+            multiple: false,
+            requeue: false,
+        };
+        assert_eq!(publisher.recv_method().await, (1, nack));
+
+        let (deliver, body) = consumer.recv_delivery().await;
+        assert_eq!(body, b"overflows");
+        assert!(matches!(deliver, Method::BasicDeliver { exchange, .. } if exchange == "dlx"));
+    }
+
+    #[tokio::test]
+    async fn test_message_nacked_past_delivery_limit_is_dead_lettered() {
+        let mut arguments = FieldTable::new();
+        arguments.insert("x-delivery-limit", FieldValue::LongInt(2));
+        let broker = dead_lettering_jobs(arguments);
+        let mut client = TestClient::connect_to(broker).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        let jobs = client.consume(1, "jobs", false).await;
+        let dead = client.consume(1, "dead", true).await;
+        client
+            .publish(1, "work", "", BasicProperties::default(), b"poison")
+            .await;
+
+        // Delivered once, then redelivered up to the limit of two times.
+        for redelivered in [false, true, true] {
+            let (deliver, body) = client.recv_delivery().await;
+            let Method::BasicDeliver {
+                consumer_tag,
+                delivery_tag,
+                redelivered: flag,
+                ..
+            } = deliver
+            else {
+                unreachable!()
+            };
+            assert_eq!(
+                (consumer_tag.as_str(), flag, &body[..]),
+                (jobs.as_str(), redelivered, &b"poison"[..])
+            );
+            let nack = Method::BasicNack {
+                delivery_tag,
+                multiple: false,
+                requeue: true,
+            };
+            client.send_method(1, &nack).await;
+        }
+        let (deliver, body) = client.recv_delivery().await;
+        assert_eq!(body, b"poison");
+        assert!(matches!(deliver, Method::BasicDeliver { consumer_tag, exchange, .. }
+            if consumer_tag == dead && exchange == "dlx"));
+    }
+
+    #[tokio::test]
+    async fn test_reconnecting_consumer_gets_its_unacked_messages_first() {
+        let broker = Arc::new(Broker::new(Config::default()));
+        let mut arguments = FieldTable::new();
+        arguments.insert("x-consumer-reconnect-window", FieldValue::LongInt(5000));
+        let declare = QueueDeclare {
+            queue: "jobs".into(),
+            arguments,
+            ..Default::default()
+        };
+        broker.declare_queue(DEFAULT_VHOST, declare).unwrap();
+        let consume = Method::BasicConsume {
+            queue: "jobs".into(),
+            consumer_tag: "worker".into(),
+            no_local: false,
+            no_ack: false,
+            exclusive: false,
+            nowait: false,
+            arguments: FieldTable::new(),
+        };
+        let mut publisher = TestClient::connect_to(broker.clone()).await;
+        publisher.handshake().await;
+        publisher.open_channel(1).await;
+        for body in [b"one", b"two", b"six"] {
+            publisher.publish(1, "", "jobs", BasicProperties::default(), body).await;
+        }
+
+        let mut worker = TestClient::connect_to(broker.clone()).await;
+        worker.handshake().await;
+        worker.open_channel(1).await;
+        worker.send_method(1, &consume).await;
+        assert!(matches!(worker.recv_method().await, (1, Method::BasicConsumeOk { .. })));
+        for _ in 0..3 {
+            worker.recv_delivery().await;
+        }
+        // Only the first delivery is acked before the worker goes away.
+        let ack = Method::BasicAck {
+            delivery_tag: 1,
+            multiple: false,
+        };
+        worker.send_method(1, &ack).await;
+        worker.shutdown().await;
+        while broker.queue_summaries()[0].consumers > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        publisher
+            .publish(1, "", "jobs", BasicProperties::default(), b"ten")
+            .await;
+
+        let mut worker = TestClient::connect_to(broker).await;
+        worker.handshake().await;
+        worker.open_channel(1).await;
+        worker.send_method(1, &consume).await;
+        assert!(matches!(worker.recv_method().await, (1, Method::BasicConsumeOk { .. })));
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            let (deliver, body) = worker.recv_delivery().await;
+            let Method::BasicDeliver { redelivered, .. } = deliver else {
+                unreachable!()
+            };
+            received.push((body, redelivered));
+        }
+        assert_eq!(
+            received,
+            vec![
+                (b"two".to_vec(), true),
+                (b"six".to_vec(), true),
+                (b"ten".to_vec(), false)
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_many_publishers_and_consumers_lose_nothing() {
+        const PUBLISHERS: usize = 8;
+        const CONSUMERS: usize = 4;
+        const MESSAGES: usize = 250;
+        let broker = work_queue();
+
+        let mut consumers = Vec::new();
+        for _ in 0..CONSUMERS {
+            let mut client = TestClient::connect_to(broker.clone()).await;
+            client.handshake().await;
+            client.open_channel(1).await;
+            client.consume(1, "jobs", false).await;
+            consumers.push(tokio::spawn(async move {
+                let mut bodies = Vec::new();
+                while let Some((deliver, body)) = client.try_recv_delivery(Duration::from_millis(500)).await {
+                    let Method::BasicDeliver { delivery_tag, .. } = deliver else {
+                        unreachable!()
+                    };
+                    client
+                        .send_method(
+                            1,
+                            &Method::BasicAck {
+                                delivery_tag,
+                                multiple: false,
+                            },
+                        )
+                        .await;
+                    bodies.push(body);
+                }
+                bodies
+            }));
+        }
+
+        let mut publishers = Vec::new();
+        for p in 0..PUBLISHERS {
+            let broker = broker.clone();
+            publishers.push(tokio::spawn(async move {
+                let mut client = TestClient::connect_to(broker).await;
+                client.handshake().await;
+                client.open_channel(1).await;
+                for n in 0..MESSAGES {
+                    let body = format!("{}-{}", p, n);
+                    client
+                        .publish(1, "work", "jobs", BasicProperties::default(), body.as_bytes())
+                        .await;
+                }
+            }));
+        }
+        for publisher in publishers {
+            publisher.await.unwrap();
+        }
+
+        let mut received = HashSet::new();
+        for consumer in consumers {
+            for body in consumer.await.unwrap() {
+                assert!(received.insert(body), "message delivered twice");
+            }
+        }
+        assert_eq!(received.len(), PUBLISHERS * MESSAGES);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_one_channel_publishes_arrive_in_order_among_other_publishers() {
+        const MESSAGES: usize = 2000;
+        let broker = work_queue();
+        let mut consumer = TestClient::connect_to(broker.clone()).await;
+        consumer.handshake().await;
+        consumer.open_channel(1).await;
+        consumer.consume(1, "jobs", true).await;
+
+        // Other connections publish to the same queue at the same time.
+        let mut others = Vec::new();
+        for p in 0..3 {
+            let broker = broker.clone();
+            others.push(tokio::spawn(async move {
+                let mut client = TestClient::connect_to(broker).await;
+                client.handshake().await;
+                client.open_channel(1).await;
+                for n in 0..MESSAGES / 4 {
+                    let body = format!("other-{}-{}", p, n);
+                    client
+                        .publish(1, "work", "jobs", BasicProperties::default(), body.as_bytes())
+                        .await;
+                }
+            }));
+        }
+        let mut publisher = TestClient::connect_to(broker.clone()).await;
+        publisher.handshake().await;
+        publisher.open_channel(1).await;
+        for n in 0..MESSAGES {
+            let body = format!("{}", n);
+            publisher
+                .publish(1, "work", "jobs", BasicProperties::default(), body.as_bytes())
+                .await;
+        }
+
+        let mut next = 0;
+        while next < MESSAGES {
+            let (_, body) = consumer.recv_delivery().await;
+            let body = String::from_utf8(body).unwrap();
+            if body.starts_with("other-") {
+                continue;
+            }
+            assert_eq!(body, next.to_string(), "delivered out of publish order");
+            next += 1;
+        }
+        for other in others {
+            other.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unacked_deliveries_move_to_surviving_consumer() {
+        let broker = work_queue();
+        let mut first = TestClient::connect_to(broker.clone()).await;
+        first.handshake().await;
+        first.open_channel(1).await;
+        first.consume(1, "jobs", false).await;
+        first
+            .publish(1, "work", "jobs", BasicProperties::default(), b"hello")
+            .await;
+        let (deliver, _) = first.recv_delivery().await;
+        assert!(matches!(deliver, Method::BasicDeliver { redelivered: false, .. }));
+
+        let mut second = TestClient::connect_to(broker.clone()).await;
+        second.handshake().await;
+        second.open_channel(1).await;
+        second.consume(1, "jobs", false).await;
+
+        // The first consumer's connection dies without acking.
+        drop(first);
+        let (deliver, body) = second.recv_delivery().await;
+        assert!(matches!(deliver, Method::BasicDeliver { redelivered: true, .. }));
+        assert_eq!(body, b"hello");
+    }
+
+    /// Adds a queue "other" to `work_queue`'s broker, bound with key "other".
+    fn other_queue(broker: &Broker) {
+        broker
+            .declare_queue(
+                DEFAULT_VHOST,
+                QueueDeclare {
+                    queue: "other".into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let binding = Binding {
+            queue: "other".into(),
+            routing_key: "other".into(),
+            arguments: FieldTable::new(),
+        };
+        broker.bind_queue(DEFAULT_VHOST, binding, "work").unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_multiple_ack_spans_consumers_on_a_channel() {
+        let broker = work_queue();
+        other_queue(&broker);
+
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        let jobs = client.consume(1, "jobs", false).await;
+        let other = client.consume(1, "other", false).await;
+        for (n, (queue, tag)) in [("jobs", &jobs), ("other", &other), ("jobs", &jobs), ("other", &other)]
+            .into_iter()
+            .enumerate()
+        {
+            client.publish(1, "work", queue, BasicProperties::default(), b"x").await;
+            let (deliver, _) = client.recv_delivery().await;
+            let Method::BasicDeliver {
+                consumer_tag,
+                delivery_tag,
+                ..
+            } = deliver
+            else {
+                unreachable!()
+            };
+            assert_eq!((consumer_tag.as_str(), delivery_tag), (tag.as_str(), n as u64 + 1));
+        }
+
+        // Acks tags 1 to 3, two from "jobs" and one from "other".
+        client
+            .send_method(
+                1,
+                &Method::BasicAck {
+                    delivery_tag: 3,
+                    multiple: true,
+                },
+            )
+            .await;
+        client.shutdown().await;
+        (&mut client.server).await.unwrap();
+        let ready = |queue: &str| {
+            let declare = QueueDeclare {
+                queue: queue.into(),
+                passive: true,
+                ..Default::default()
+            };
+            broker.declare_queue(DEFAULT_VHOST, declare).unwrap().message_count
+        };
+        assert_eq!((ready("jobs"), ready("other")), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn test_nacked_message_is_redelivered_before_later_ones() {
+        let broker = work_queue();
+        let mut client = TestClient::connect_to(broker).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        let qos = Method::BasicQos {
+            prefetch_size: 0,
+            prefetch_count: 1,
+            global: false,
+        };
+        client.send_method(1, &qos).await;
+        assert_eq!(client.recv_method().await, (1, Method::BasicQosOk));
+        client.consume(1, "jobs", false).await;
+        for body in [b"1", b"2", b"3"] {
+            client
+                .publish(1, "work", "jobs", BasicProperties::default(), body)
+                .await;
+        }
+
+        let (first, body) = client.recv_delivery().await;
+        assert_eq!(body, b"1");
+        let Method::BasicDeliver { delivery_tag, .. } = first else {
+            panic!("expected Basic.Deliver, got {:?}", first);
+        };
+        let nack = Method::BasicNack {
+            delivery_tag,
+            multiple: false,
+            requeue: true,
+        };
+        client.send_method(1, &nack).await;
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            let (deliver, body) = client.recv_delivery().await;
+            let Method::BasicDeliver {
+                delivery_tag,
+                redelivered,
+                ..
+            } = deliver
+            else {
+                panic!("expected Basic.Deliver, got {:?}", deliver);
+            };
+            received.push((body, redelivered));
+            let ack = Method::BasicAck {
+                delivery_tag,
+                multiple: false,
+            };
+            client.send_method(1, &ack).await;
+        }
+        assert_eq!(
+            received,
+            vec![(b"1".to_vec(), true), (b"2".to_vec(), false), (b"3".to_vec(), false)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_consumer_that_stops_reading_is_closed_and_requeued() {
+        let broker = work_queue_with(Config {
+            write_timeout: Duration::from_millis(100),
+            ..Default::default()
+        });
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client.consume(1, "jobs", false).await;
+        // Far more than the connection buffers; the client reads none of it.
+        for _ in 0..200 {
+            let message = Message {
+                exchange: "work".into(),
+                routing_key: "jobs".into(),
+                properties: BasicProperties::default(),
+                body: vec![0; 4096],
+            };
+            broker.publish(DEFAULT_VHOST, message, false);
+        }
+
+        tokio::time::timeout(Duration::from_secs(5), &mut client.server)
+            .await
+            .expect("the stuck connection was never closed")
+            .unwrap();
+        let jobs = &broker.queue_summaries()[0];
+        assert_eq!((jobs.messages, jobs.unacked), (200, 0));
+    }
+
+    /// A message store whose appends fail while `failing` is set, counting
+    /// those that succeed in `appended`.
+    struct FailingStore {
+        failing: Arc<AtomicBool>,
+        appended: Arc<AtomicUsize>,
+    }
+
+    impl MessageStore for FailingStore {
+        fn append(&mut self, _vhost: &str, _queue: &str, _message: &Message) -> std::io::Result<u64> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(std::io::Error::other("no space left on device"));
+            }
+            Ok(self.appended.fetch_add(1, Ordering::SeqCst) as u64 + 1)
+        }
+
+        fn ack(&mut self, _id: u64) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn recover(&mut self) -> std::io::Result<Vec<StoredMessage>> {
+            Ok(Vec::new())
+        }
+
+        fn compact(&mut self) -> std::io::Result<CompactionStats> {
+            Ok(CompactionStats::default())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failing_store_raises_disk_alarm_and_blocks_publishers() {
+        let failing = Arc::new(AtomicBool::new(true));
+        let store = FailingStore {
+            failing: failing.clone(),
+            appended: Default::default(),
+        };
+        let broker = Arc::new(Broker::with_store(Config::default(), Box::new(store)));
+        let declare = QueueDeclare {
+            queue: "jobs".into(),
+            durable: true,
+            ..Default::default()
+        };
+        broker.declare_queue(DEFAULT_VHOST, declare).unwrap();
+        let mut capabilities = FieldTable::new();
+        capabilities.insert("connection.blocked", FieldValue::Boolean(true));
+        let mut client_properties = FieldTable::new();
+        client_properties.insert("capabilities", FieldValue::FieldTable(capabilities));
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.handshake_with_properties(client_properties).await;
+        client.open_channel(1).await;
+        client.send_method(1, &Method::ConfirmSelect { nowait: false }).await;
+        assert_eq!(client.recv_method().await, (1, Method::ConfirmSelectOk));
+        let persistent = BasicProperties {
+            delivery_mode: Some(2),
+            ..Default::default()
+        };
+
+        // The failed write raises the alarm; the message is kept in memory
+        // but not confirmed, and the next publish is not even read.
+        client.publish(1, "", "jobs", persistent.clone(), b"first").await;
+        let blocked = Method::ConnectionBlocked {
+            reason: "low on disk".into(),
+        };
+        assert_eq!(client.recv_method().await, (0, blocked));
+        assert!(broker.disk_alarm());
+        client.publish(1, "", "jobs", persistent, b"second").await;
+        assert!(client.try_recv_frame(Duration::from_millis(100)).await.is_none());
+        assert_eq!(broker.queue_summaries()[0].messages, 1);
+        assert!(broker.retry_store().is_err());
+
+        // Once the store takes writes again the alarm clears, the held
+        // confirm goes out and the blocked publish goes through.
+        failing.store(false, Ordering::SeqCst);
+        assert_eq!(broker.retry_store().unwrap(), 1);
+        assert!(!broker.disk_alarm());
+        assert_eq!(client.recv_method().await, (0, Method::ConnectionUnblocked));
+        for seq in [1, 2] {
+            let ack = Method::BasicAck {
+                delivery_tag: seq,
+                multiple: false,
+            };
+            assert_eq!(client.recv_method().await, (1, ack));
+        }
+        assert_eq!(broker.queue_summaries()[0].messages, 2);
+    }
+
+    #[tokio::test]
+    async fn test_clearing_the_disk_alarm_stores_delivered_messages_first() {
+        let failing = Arc::new(AtomicBool::new(true));
+        let store = FailingStore {
+            failing: failing.clone(),
+            appended: Default::default(),
+        };
+        let broker = Arc::new(Broker::with_store(Config::default(), Box::new(store)));
+        let declare = QueueDeclare {
+            queue: "jobs".into(),
+            durable: true,
+            ..Default::default()
+        };
+        broker.declare_queue(DEFAULT_VHOST, declare).unwrap();
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client.send_method(1, &Method::ConfirmSelect { nowait: false }).await;
+        assert_eq!(client.recv_method().await, (1, Method::ConfirmSelectOk));
+        client.consume(1, "jobs", false).await;
+        let persistent = BasicProperties {
+            delivery_mode: Some(2),
+            ..Default::default()
+        };
+        client.publish(1, "", "jobs", persistent, b"delivered").await;
+        assert_eq!(client.recv_delivery().await.1, b"delivered");
+        assert!(broker.disk_alarm());
+
+        // The message waits for its ack, not in the ready list, and is
+        // still stored before its publisher is told it is safe.
+        let jobs = &broker.queue_summaries()[0];
+        assert_eq!((jobs.messages, jobs.unacked), (0, 1));
+        failing.store(false, Ordering::SeqCst);
+        assert_eq!(broker.retry_store().unwrap(), 1);
+        let ack = Method::BasicAck {
+            delivery_tag: 1,
+            multiple: false,
+        };
+        assert_eq!(client.recv_method().await, (1, ack));
+    }
+
+    /// Publishes a persistent message in confirm mode while the store fails,
+    /// delivering it to a consumer that does not ack it if `delivered`,
+    /// shuts the broker down, with the store working again if
+    /// `store_recovers`, and returns the confirm the publisher gets and how
+    /// many messages the store took.
+    async fn confirm_at_shutdown(store_recovers: bool, delivered: bool) -> (Method, usize) {
+        let failing = Arc::new(AtomicBool::new(true));
+        let appended = Arc::new(AtomicUsize::new(0));
+        let store = FailingStore {
+            failing: failing.clone(),
+            appended: appended.clone(),
+        };
+        let broker = Arc::new(Broker::with_store(Config::default(), Box::new(store)));
+        let declare = QueueDeclare {
+            queue: "jobs".into(),
+            durable: true,
+            ..Default::default()
+        };
+        broker.declare_queue(DEFAULT_VHOST, declare).unwrap();
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client.send_method(1, &Method::ConfirmSelect { nowait: false }).await;
+        assert_eq!(client.recv_method().await, (1, Method::ConfirmSelectOk));
+        if delivered {
+            client.consume(1, "jobs", false).await;
+        }
+        let persistent = BasicProperties {
+            delivery_mode: Some(2),
+            ..Default::default()
+        };
+        client.publish(1, "", "jobs", persistent, b"held").await;
+        if delivered {
+            assert_eq!(client.recv_delivery().await.1, b"held");
+        }
+        while !broker.disk_alarm() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        failing.store(!store_recovers, Ordering::SeqCst);
+        broker.shut_down();
+        let (channel, confirm) = client.recv_method().await;
+        assert_eq!(channel, 1);
+        client.expect_connection_close(reply_codes::CONNECTION_FORCED).await;
+        client.send_method(0, &Method::ConnectionCloseOk).await;
+        (confirm, appended.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_resolves_outstanding_confirms() {
+        let nack = Method::BasicNack {
+            delivery_tag: 1,
+            multiple: false,
+            requeue: false,
+        };
+        assert_eq!(confirm_at_shutdown(false, false).await, (nack, 0));
+        let ack = Method::BasicAck {
+            delivery_tag: 1,
+            multiple: false,
+        };
+        assert_eq!(confirm_at_shutdown(true, false).await, (ack, 1));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_acks_delivered_messages_only_once_stored() {
+        let nack = Method::BasicNack {
+            delivery_tag: 1,
+            multiple: false,
+            requeue: false,
+        };
+        assert_eq!(confirm_at_shutdown(false, true).await, (nack, 0));
+        let ack = Method::BasicAck {
+            delivery_tag: 1,
+            multiple: false,
+        };
+        assert_eq!(confirm_at_shutdown(true, true).await, (ack, 1));
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_size_holds_back_deliveries_until_acked() {
+        let broker = work_queue();
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        let footprint = Message {
+            exchange: "work".into(),
+            routing_key: "jobs".into(),
+            properties: BasicProperties::default(),
+            body: vec![0; 1000],
+        }
+        .memory_footprint();
+        // Room for three of the messages below.
+        let qos = Method::BasicQos {
+            prefetch_size: (footprint * 7 / 2) as u32,
+            prefetch_count: 0,
+            global: false,
+        };
+        client.send_method(1, &qos).await;
+        assert_eq!(client.recv_method().await, (1, Method::BasicQosOk));
+        client.consume(1, "jobs", false).await;
+        for _ in 0..5 {
+            client
+                .publish(1, "work", "jobs", BasicProperties::default(), &[0; 1000])
+                .await;
+        }
+
+        for tag in 1..=3 {
+            let (deliver, _) = client.recv_delivery().await;
+            assert!(matches!(deliver, Method::BasicDeliver { delivery_tag, .. } if delivery_tag == tag));
+        }
+        assert!(client.try_recv_delivery(Duration::from_millis(50)).await.is_none());
+
+        let ack = Method::BasicAck {
+            delivery_tag: 1,
+            multiple: false,
+        };
+        client.send_method(1, &ack).await;
+        let (deliver, _) = client.recv_delivery().await;
+        assert!(matches!(deliver, Method::BasicDeliver { delivery_tag: 4, .. }));
+        assert!(client.try_recv_delivery(Duration::from_millis(50)).await.is_none());
+    }
+
+    /// How many deliveries a channel with `Basic.Qos` prefetch count 1
+    /// receives from two consumers, for a client announcing `capabilities`.
+    async fn deliveries_under_qos_of_one(capabilities: FieldTable) -> usize {
+        let broker = work_queue();
+        other_queue(&broker);
+
+        let mut client_properties = FieldTable::new();
+        client_properties.insert("capabilities", FieldValue::FieldTable(capabilities));
+        let mut client = TestClient::connect_to(broker).await;
+        client.handshake_with_properties(client_properties).await;
+        client.open_channel(1).await;
+        let qos = Method::BasicQos {
+            prefetch_size: 0,
+            prefetch_count: 1,
+            global: false,
+        };
+        client.send_method(1, &qos).await;
+        assert_eq!(client.recv_method().await, (1, Method::BasicQosOk));
+        client.consume(1, "jobs", false).await;
+        client.consume(1, "other", false).await;
+        client
+            .publish(1, "work", "jobs", BasicProperties::default(), b"a")
+            .await;
+        client
+            .publish(1, "work", "other", BasicProperties::default(), b"b")
+            .await;
+        let mut received = 0;
+        while client.try_recv_delivery(Duration::from_millis(50)).await.is_some() {
+            received += 1;
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn test_room_in_a_channel_window_goes_to_any_of_its_queues() {
+        let broker = work_queue();
+        other_queue(&broker);
+        let mut client = TestClient::connect_to(broker).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        let qos = Method::BasicQos {
+            prefetch_size: 0,
+            prefetch_count: 1,
+            global: true,
+        };
+        client.send_method(1, &qos).await;
+        assert_eq!(client.recv_method().await, (1, Method::BasicQosOk));
+        client.consume(1, "jobs", false).await;
+        client.consume(1, "other", false).await;
+        client
+            .publish(1, "work", "jobs", BasicProperties::default(), b"a")
+            .await;
+        assert_eq!(client.recv_delivery().await.1, b"a");
+        client
+            .publish(1, "work", "other", BasicProperties::default(), b"b")
+            .await;
+        assert!(client.try_recv_delivery(Duration::from_millis(50)).await.is_none());
+
+        let ack = Method::BasicAck {
+            delivery_tag: 1,
+            multiple: false,
+        };
+        client.send_method(1, &ack).await;
+        assert_eq!(client.recv_delivery().await.1, b"b");
+    }
+
+    #[tokio::test]
+    async fn test_non_global_qos_limits_each_consumer_whatever_the_capabilities() {
+        let mut capabilities = FieldTable::new();
+        assert_eq!(deliveries_under_qos_of_one(capabilities.clone()).await, 2);
+        capabilities.insert("per_consumer_qos", FieldValue::Boolean(true));
+        assert_eq!(deliveries_under_qos_of_one(capabilities).await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_recover_frees_a_full_prefetch_window() {
+        let broker = work_queue();
+        let mut client = TestClient::connect_to(broker).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        let qos = Method::BasicQos {
+            prefetch_size: 0,
+            prefetch_count: 2,
+            global: false,
+        };
+        client.send_method(1, &qos).await;
+        assert_eq!(client.recv_method().await, (1, Method::BasicQosOk));
+        client.consume(1, "jobs", false).await;
+        for body in [b"a", b"b", b"c"] {
+            client
+                .publish(1, "work", "jobs", BasicProperties::default(), body)
+                .await;
+        }
+        assert_eq!(client.recv_delivery().await.1, b"a");
+        assert_eq!(client.recv_delivery().await.1, b"b");
+        assert!(client.try_recv_delivery(Duration::from_millis(50)).await.is_none());
+
+        client.send_method(1, &Method::BasicRecover { requeue: true }).await;
+        assert_eq!(client.recv_method().await, (1, Method::BasicRecoverOk));
+        for (tag, body) in [(3, b"a"), (4, b"b")] {
+            let (deliver, content) = client.recv_delivery().await;
+            assert!(matches!(
+                deliver,
+                Method::BasicDeliver { delivery_tag, redelivered: true, .. } if delivery_tag == tag
+            ));
+            assert_eq!(content, body);
+        }
+        assert!(client.try_recv_delivery(Duration::from_millis(50)).await.is_none());
+        let ack = Method::BasicAck {
+            delivery_tag: 4,
+            multiple: true,
+        };
+        client.send_method(1, &ack).await;
+        assert_eq!(client.recv_delivery().await.1, b"c");
+    }
+
+    #[tokio::test]
+    async fn test_deliveries_resume_after_cancel_and_nack() {
+        let broker = work_queue();
+        let mut client = TestClient::connect_to(broker).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        let qos = Method::BasicQos {
+            prefetch_size: 0,
+            prefetch_count: 1,
+            global: true,
+        };
+        client.send_method(1, &qos).await;
+        assert_eq!(client.recv_method().await, (1, Method::BasicQosOk));
+        let consumer_tag = client.consume(1, "jobs", false).await;
+        for body in [b"a", b"b"] {
+            client
+                .publish(1, "work", "jobs", BasicProperties::default(), body)
+                .await;
+        }
+        assert_eq!(client.recv_delivery().await.1, b"a");
+        let cancel = Method::BasicCancel {
+            consumer_tag: consumer_tag.clone(),
+            nowait: false,
+        };
+        client.send_method(1, &cancel).await;
+        assert_eq!(client.recv_method().await, (1, Method::BasicCancelOk { consumer_tag }));
+
+        // The cancelled consumer's delivery still fills the channel window
+        // until it is settled; a nack with requeue frees it.
+        client.consume(1, "jobs", false).await;
+        assert!(client.try_recv_delivery(Duration::from_millis(50)).await.is_none());
+        let nack = Method::BasicNack {
+            delivery_tag: 1,
+            multiple: false,
+            requeue: true,
+        };
+        client.send_method(1, &nack).await;
+        let (deliver, body) = client.recv_delivery().await;
+        assert!(matches!(
+            deliver,
+            Method::BasicDeliver {
+                delivery_tag: 2,
+                redelivered: true,
+                ..
+            }
+        ));
+        assert_eq!(body, b"a");
+        let ack = Method::BasicAck {
+            delivery_tag: 2,
+            multiple: false,
+        };
+        client.send_method(1, &ack).await;
+        assert_eq!(client.recv_delivery().await.1, b"b");
+    }
+
+    async fn set_qos(client: &mut TestClient, prefetch_count: u16, global: bool) {
+        let qos = Method::BasicQos {
+            prefetch_size: 0,
+            prefetch_count,
+            global,
+        };
+        client.send_method(1, &qos).await;
+        assert_eq!(client.recv_method().await, (1, Method::BasicQosOk));
+    }
+
+    #[tokio::test]
+    async fn test_lowering_qos_mid_stream_pauses_deliveries() {
+        let broker = work_queue();
+        let mut client = TestClient::connect_to(broker).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        set_qos(&mut client, 3, false).await;
+        client.consume(1, "jobs", false).await;
+        for body in [b"a", b"b", b"c", b"d"] {
+            client
+                .publish(1, "work", "jobs", BasicProperties::default(), body)
+                .await;
+        }
+        for body in [b"a", b"b", b"c"] {
+            assert_eq!(client.recv_delivery().await.1, body);
+        }
+
+        // Three are outstanding against the new limit of one: nothing more
+        // comes until all three are settled.
+        set_qos(&mut client, 1, false).await;
+        for delivery_tag in [1, 2] {
+            let ack = Method::BasicAck {
+                delivery_tag,
+                multiple: false,
+            };
+            client.send_method(1, &ack).await;
+            assert!(client.try_recv_delivery(Duration::from_millis(50)).await.is_none());
+        }
+        let ack = Method::BasicAck {
+            delivery_tag: 3,
+            multiple: false,
+        };
+        client.send_method(1, &ack).await;
+        assert_eq!(client.recv_delivery().await.1, b"d");
+    }
+
+    #[tokio::test]
+    async fn test_raising_qos_mid_stream_resumes_deliveries() {
+        for global in [false, true] {
+            let broker = work_queue();
+            let mut client = TestClient::connect_to(broker).await;
+            client.handshake().await;
+            client.open_channel(1).await;
+            set_qos(&mut client, 1, global).await;
+            client.consume(1, "jobs", false).await;
+            for body in [b"a", b"b", b"c"] {
+                client
+                    .publish(1, "work", "jobs", BasicProperties::default(), body)
+                    .await;
+            }
+            assert_eq!(client.recv_delivery().await.1, b"a");
+            assert!(client.try_recv_delivery(Duration::from_millis(50)).await.is_none());
+
+            set_qos(&mut client, 3, global).await;
+            assert_eq!(client.recv_delivery().await.1, b"b");
+            assert_eq!(client.recv_delivery().await.1, b"c");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_qos_limits_a_consumer_started_without_one() {
+        let broker = work_queue();
+        let mut client = TestClient::connect_to(broker).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client.consume(1, "jobs", false).await;
+        set_qos(&mut client, 1, false).await;
+        for body in [b"a", b"b"] {
+            client
+                .publish(1, "work", "jobs", BasicProperties::default(), body)
+                .await;
+        }
+        assert_eq!(client.recv_delivery().await.1, b"a");
+        assert!(client.try_recv_delivery(Duration::from_millis(50)).await.is_none());
+    }
+
+    fn consume_as(consumer_tag: &str) -> Method {
+        Method::BasicConsume {
+            queue: "jobs".into(),
+            consumer_tag: consumer_tag.into(),
+            no_local: false,
+            no_ack: false,
+            exclusive: false,
+            nowait: false,
+            arguments: FieldTable::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reused_consumer_tag_is_not_allowed() {
+        let mut client = TestClient::connect_to(work_queue()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client.open_channel(2).await;
+        for channel in [1, 2] {
+            client.send_method(channel, &consume_as("worker")).await;
+            let ok = Method::BasicConsumeOk {
+                consumer_tag: "worker".into(),
+            };
+            assert_eq!(client.recv_method().await, (channel, ok));
+        }
+
+        client.send_method(1, &consume_as("worker")).await;
+        client.expect_connection_close(reply_codes::NOT_ALLOWED).await;
+    }
+
+    #[tokio::test]
+    async fn test_generated_consumer_tags_avoid_client_tags() {
+        let broker = work_queue();
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        // The tag the broker would generate after the consume below, which
+        // takes the next consumer id.
+        let last: u64 = broker.generate_consumer_tag()["amq.ctag-".len()..].parse().unwrap();
+        let next = format!("amq.ctag-{}", last + 2);
+        client.send_method(1, &consume_as(&next)).await;
+        assert!(matches!(client.recv_method().await, (1, Method::BasicConsumeOk { .. })));
+        let generated = client.consume(1, "jobs", false).await;
+        assert_ne!(generated, next);
+    }
+
+    /// Consumes from "jobs" as a client announcing `capabilities`, deletes
+    /// the queue and returns the frame that follows its `Delete-Ok`, if any.
+    async fn frame_after_deleting_consumed_queue(capabilities: FieldTable) -> Option<Method> {
+        let mut client_properties = FieldTable::new();
+        client_properties.insert("capabilities", FieldValue::FieldTable(capabilities));
+        let mut client = TestClient::connect_to(work_queue()).await;
+        client.handshake_with_properties(client_properties).await;
+        client.open_channel(1).await;
+        let consumer_tag = client.consume(1, "jobs", false).await;
+        let delete = Method::QueueDelete {
+            queue: "jobs".into(),
+            if_unused: false,
+            if_empty: false,
+            nowait: false,
+        };
+        client.send_method(1, &delete).await;
+        assert_eq!(
+            client.recv_method().await,
+            (1, Method::QueueDeleteOk { message_count: 0 })
+        );
+        let frame = client.try_recv_frame(Duration::from_millis(50)).await?;
+        let method = Method::decode(&frame.payload).unwrap();
+        assert!(matches!(&method, Method::BasicCancel { consumer_tag: tag, .. } if *tag == consumer_tag));
+        Some(method)
+    }
+
+    #[tokio::test]
+    async fn test_deleted_queue_cancels_consumers_of_notified_clients() {
+        let mut capabilities = FieldTable::new();
+        assert_eq!(frame_after_deleting_consumed_queue(capabilities.clone()).await, None);
+        capabilities.insert("consumer_cancel_notify", FieldValue::Boolean(true));
+        let cancel = frame_after_deleting_consumed_queue(capabilities).await;
+        assert!(matches!(cancel, Some(Method::BasicCancel { nowait: true, .. })));
+    }
+
+    /// Drops messages with bodies over the given number of bytes.
+    struct DropLarge(usize);
+
+    #[async_trait::async_trait]
+    impl Interceptor for DropLarge {
+        async fn on_publish(&self, message: &mut Message, _ctx: &PublishCtx) -> InterceptAction {
+            if message.body.len() > self.0 {
+                InterceptAction::Drop
+            } else {
+                InterceptAction::Pass
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_interceptor_drops_large_messages() {
+        let broker = Arc::new(Broker::with_interceptors(
+            Config::default(),
+            vec![Box::new(DropLarge(5))],
+        ));
+        let declare = QueueDeclare {
+            queue: "jobs".into(),
+            ..Default::default()
+        };
+        broker.declare_queue(DEFAULT_VHOST, declare).unwrap();
+        let binding = Binding {
+            queue: "jobs".into(),
+            routing_key: "jobs".into(),
+            arguments: FieldTable::new(),
+        };
+        broker.bind_queue(DEFAULT_VHOST, binding, "amq.direct").unwrap();
+
+        let mut client = TestClient::connect_to(broker).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client.send_method(1, &Method::ConfirmSelect { nowait: false }).await;
+        assert_eq!(client.recv_method().await, (1, Method::ConfirmSelectOk));
+        for (n, body) in [&b"much too large"[..], b"small"].into_iter().enumerate() {
+            client
+                .publish(1, "amq.direct", "jobs", BasicProperties::default(), body)
+                .await;
+            // The dropped message is confirmed all the same.
+            let ack = Method::BasicAck {
+                delivery_tag: n as u64 + 1,
+                multiple: false,
+            };
+            assert_eq!(client.recv_method().await, (1, ack));
+        }
+
+        client.consume(1, "jobs", true).await;
+        let (_, body) = client.recv_delivery().await;
+        assert_eq!(body, b"small");
+        assert!(client.try_recv_delivery(Duration::from_millis(50)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_connections_past_ip_limit_are_closed() {
+        let broker = Arc::new(Broker::new(Config {
+            max_connections_per_ip: 2,
+            ..Default::default()
+        }));
+        let ip: IpAddr = "10.0.0.7".parse().unwrap();
+        let mut open = Vec::new();
+        for _ in 0..2 {
+            let mut client = TestClient::connect_from(broker.clone(), Some(ip)).await;
+            client.handshake().await;
+            open.push(client);
+        }
+
+        let mut refused = TestClient::connect_from(broker.clone(), Some(ip)).await;
+        refused.expect_connection_close(reply_codes::CONNECTION_FORCED).await;
+        let mut other = TestClient::connect_from(broker.clone(), Some("10.0.0.8".parse().unwrap())).await;
+        other.handshake().await;
+
+        // Closing one connection frees its slot.
+        let closed = open.pop().unwrap();
+        drop(closed);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut client = TestClient::connect_from(broker.clone(), Some(ip)).await;
+        client.handshake().await;
+    }
+
+    #[tokio::test]
+    async fn test_connections_past_user_limit_are_closed() {
+        let broker = Arc::new(Broker::new(Config {
+            max_connections_per_user: 1,
+            ..Default::default()
+        }));
+        let mut first = TestClient::connect_to(broker.clone()).await;
+        first.handshake().await;
+
+        let mut second = TestClient::connect_to(broker.clone()).await;
+        let (_, start) = second.recv_method().await;
+        assert!(matches!(start, Method::ConnectionStart { .. }));
+        second
+            .send_method(
+                0,
+                &Method::ConnectionStartOk {
+                    client_properties: FieldTable::new(),
+                    mechanism: "PLAIN".into(),
+                    response: b"\0guest\0guest".to_vec(),
+                    locale: "en_US".into(),
+                },
+            )
+            .await;
+        second.expect_connection_close(reply_codes::CONNECTION_FORCED).await;
+    }
+
+    async fn start_with_locale(broker: Arc<Broker>, locale: &str) -> TestClient {
+        let mut client = TestClient::connect_to(broker).await;
+        let (_, start) = client.recv_method().await;
+        let Method::ConnectionStart { locales, .. } = start else {
+            panic!("expected Connection.Start, got {:?}", start);
+        };
+        assert_eq!(locales, b"en_US de_DE");
+        client
+            .send_method(
+                0,
+                &Method::ConnectionStartOk {
+                    client_properties: FieldTable::new(),
+                    mechanism: "PLAIN".into(),
+                    response: b"\0guest\0guest".to_vec(),
+                    locale: locale.into(),
+                },
+            )
+            .await;
+        client
+    }
+
+    #[tokio::test]
+    async fn test_reply_texts_use_the_negotiated_locale() {
+        let broker = Arc::new(Broker::new(Config::default()));
+        let mut client = start_with_locale(broker.clone(), "de_DE").await;
+        let (_, tune) = client.recv_method().await;
+        let Method::ConnectionTune { frame_max, .. } = tune else {
+            panic!("expected Connection.Tune, got {:?}", tune);
+        };
+        client
+            .send_method(
+                0,
+                &Method::ConnectionTuneOk {
+                    channel_max: 0,
+                    frame_max,
+                    heartbeat: 0,
+                },
+            )
+            .await;
+        client.send_vhost_open("missing").await;
+        let (_, close) = client.recv_method().await;
+        let Method::ConnectionClose {
+            reply_code, reply_text, ..
+        } = close
+        else {
+            panic!("expected Connection.Close, got {:?}", close);
+        };
+        assert_eq!(reply_code, reply_codes::NOT_ALLOWED);
+        assert_eq!(reply_text, "NOT_ALLOWED - virtueller Host 'missing' nicht gefunden");
+
+        let mut client = start_with_locale(broker, "fr_FR").await;
+        client.expect_connection_close(reply_codes::NOT_ALLOWED).await;
+    }
+
+    /// Asks who the client is, then accepts anyone but "mallory".
+    struct TwoStepAuth;
+
+    struct TwoStepSession {
+        greeted: bool,
+    }
+
+    impl AuthProvider for TwoStepAuth {
+        fn mechanisms(&self) -> Vec<String> {
+            vec!["X-TWO-STEP".into()]
+        }
+
+        fn start(&self, mechanism: &str) -> Option<Box<dyn SaslSession>> {
+            (mechanism == "X-TWO-STEP").then(|| Box::new(TwoStepSession { greeted: false }) as Box<dyn SaslSession>)
+        }
+    }
+
+    impl SaslSession for TwoStepSession {
+        fn step(&mut self, response: &[u8]) -> Result<SaslStep, AuthFailure> {
+            if !self.greeted {
+                self.greeted = true;
+                return Ok(SaslStep::Challenge(b"who?".to_vec()));
+            }
+            match std::str::from_utf8(response) {
+                Ok("mallory") | Err(_) => Err(AuthFailure("go away".into())),
+                Ok(user) => Ok(SaslStep::Authenticated(Some(user.into()))),
+            }
+        }
+    }
+
+    async fn start_two_step(user: &str) -> TestClient {
+        let broker = Arc::new(Broker::with_auth(Config::default(), Box::new(TwoStepAuth)));
+        let mut client = TestClient::connect_to(broker).await;
+        let (_, start) = client.recv_method().await;
+        let Method::ConnectionStart { mechanisms, .. } = start else {
+            panic!("expected Connection.Start, got {:?}", start);
+        };
+        assert_eq!(mechanisms, b"X-TWO-STEP");
+        client
+            .send_method(
+                0,
+                &Method::ConnectionStartOk {
+                    client_properties: FieldTable::new(),
+                    mechanism: "X-TWO-STEP".into(),
+                    response: b"hello".to_vec(),
+                    locale: "en_US".into(),
+                },
+            )
+            .await;
+        let challenge = b"who?".to_vec();
+        assert_eq!(client.recv_method().await, (0, Method::ConnectionSecure { challenge }));
+        let response = user.as_bytes().to_vec();
+        client.send_method(0, &Method::ConnectionSecureOk { response }).await;
+        client
+    }
+
+    #[tokio::test]
+    async fn test_two_step_mechanism_uses_secure() {
+        let mut client = start_two_step("alice").await;
+        let (_, tune) = client.recv_method().await;
+        assert!(matches!(tune, Method::ConnectionTune { .. }), "got {:?}", tune);
+        assert_eq!(
+            client.broker.connections().count(&ConnectionKey::User("alice".into())),
+            1
+        );
+
+        let mut refused = start_two_step("mallory").await;
+        refused.expect_connection_close(reply_codes::ACCESS_REFUSED).await;
+    }
+
+    /// Offers PLAIN and the two-step mechanism.
+    struct PlainOrTwoStep;
+
+    impl AuthProvider for PlainOrTwoStep {
+        fn mechanisms(&self) -> Vec<String> {
+            vec!["PLAIN".into(), "X-TWO-STEP".into()]
+        }
+
+        fn start(&self, mechanism: &str) -> Option<Box<dyn SaslSession>> {
+            crate::auth::PlainAuth
+                .start(mechanism)
+                .or_else(|| TwoStepAuth.start(mechanism))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mechanisms_outside_the_allow_list_are_not_allowed() {
+        let config = Config {
+            sasl_mechanisms: vec!["EXTERNAL".into(), "X-TWO-STEP".into()],
+            ..Config::default()
+        };
+        let broker = Arc::new(Broker::with_auth(config, Box::new(PlainOrTwoStep)));
+        let mut client = TestClient::connect_to(broker).await;
+        let (_, start) = client.recv_method().await;
+        let Method::ConnectionStart { mechanisms, .. } = start else {
+            panic!("expected Connection.Start, got {:?}", start);
+        };
+        // EXTERNAL is allowed but the provider lacks it; PLAIN is not allowed.
+        assert_eq!(mechanisms, b"X-TWO-STEP");
+        client
+            .send_method(
+                0,
+                &Method::ConnectionStartOk {
+                    client_properties: FieldTable::new(),
+                    mechanism: "PLAIN".into(),
+                    response: b"\0guest\0guest".to_vec(),
+                    locale: "en_US".into(),
+                },
+            )
+            .await;
+        let (_, close) = client.recv_method().await;
+        let Method::ConnectionClose {
+            reply_code, reply_text, ..
+        } = close
+        else {
+            panic!("expected Connection.Close, got {:?}", close);
+        };
+        assert_eq!(reply_code, reply_codes::NOT_ALLOWED);
+        assert_eq!(reply_text, "NOT_ALLOWED - mechanism 'PLAIN' is not allowed");
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_mechanism_is_refused() {
+        let broker = Arc::new(Broker::with_auth(Config::default(), Box::new(TwoStepAuth)));
+        let mut client = TestClient::connect_to(broker).await;
+        client.recv_method().await;
+        client
+            .send_method(
+                0,
+                &Method::ConnectionStartOk {
+                    client_properties: FieldTable::new(),
+                    mechanism: "PLAIN".into(),
+                    response: b"\0guest\0guest".to_vec(),
+                    locale: "en_US".into(),
+                },
+            )
+            .await;
+        client.expect_connection_close(reply_codes::ACCESS_REFUSED).await;
+    }
+
+    /// A method frame for the made-up method 60.200, with one argument byte.
+    fn unimplemented_method(channel: u16) -> AmqpFrame {
+        AmqpFrame {
+            frame_type: FRAME_METHOD,
+            channel,
+            payload: vec![0, 60, 0, 200, 1],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unimplemented_method_closes_the_connection() {
+        let mut client = TestClient::connect(Config::default()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client.send_frame(&unimplemented_method(1)).await;
+        match client.recv_method().await {
+            (
+                0,
+                Method::ConnectionClose {
+                    reply_code,
+                    reply_text,
+                    class_id: 60,
+                    method_id: 200,
+                },
+            ) => {
+                assert_eq!(reply_code, reply_codes::NOT_IMPLEMENTED);
+                assert!(reply_text.contains("60.200"), "{}", reply_text);
+            }
+            other => panic!("expected Connection.Close, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unimplemented_method_can_close_only_its_channel() {
+        let mut client = TestClient::connect(Config {
+            unknown_method_close: UnknownMethodClose::Channel,
+            ..Default::default()
+        })
+        .await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client.open_channel(2).await;
+        client.send_frame(&unimplemented_method(1)).await;
+        client.expect_channel_close(1, reply_codes::NOT_IMPLEMENTED).await;
+        client.send_method(1, &Method::ChannelCloseOk).await;
+        client.send_method(2, &Method::ChannelFlow { active: true }).await;
+        assert_eq!(client.recv_method().await, (2, Method::ChannelFlowOk { active: true }));
+
+        // Channel 0 has no channel to close.
+        client.send_frame(&unimplemented_method(0)).await;
+        client.expect_connection_close(reply_codes::NOT_IMPLEMENTED).await;
+    }
+
+    #[tokio::test]
+    async fn test_clients_cannot_declare_reserved_names() {
+        let mut client = TestClient::connect(Config::default()).await;
+        client.handshake().await;
+        let declare_exchange = |exchange: &str, passive: bool| Method::ExchangeDeclare {
+            exchange: exchange.into(),
+            kind: "direct".into(),
+            passive,
+            durable: true,
+            auto_delete: false,
+            internal: false,
+            nowait: false,
+            arguments: FieldTable::new(),
+        };
+        client.open_channel(1).await;
+        client.send_method(1, &declare_exchange("amq.myexchange", false)).await;
+        client.expect_channel_close(1, reply_codes::ACCESS_REFUSED).await;
+
+        client.open_channel(2).await;
+        let declare_queue = Method::QueueDeclare {
+            queue: "amq.myqueue".into(),
+            passive: false,
+            durable: false,
+            exclusive: false,
+            auto_delete: false,
+            nowait: false,
+            arguments: FieldTable::new(),
+        };
+        client.send_method(2, &declare_queue).await;
+        client.expect_channel_close(2, reply_codes::ACCESS_REFUSED).await;
+
+        // Built-in exchanges can still be declared, passively or not.
+        client.open_channel(3).await;
+        for passive in [true, false] {
+            client.send_method(3, &declare_exchange("amq.direct", passive)).await;
+            assert_eq!(client.recv_method().await, (3, Method::ExchangeDeclareOk));
+        }
+        assert!(client
+            .broker
+            .route_queues(DEFAULT_VHOST, "amq.myexchange", "", None)
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unconfirmed_channel_close_is_cleaned_up_after_timeout() {
+        let mut client = TestClient::connect(Config {
+            channel_close_timeout: Duration::from_millis(100),
+            ..Default::default()
+        })
+        .await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client.send_method(1, &Method::TxSelect).await;
+        client.recv_method().await;
+        client.send_method(1, &Method::ConfirmSelect { nowait: false }).await;
+        client.expect_channel_close(1, reply_codes::PRECONDITION_FAILED).await;
+
+        // Until the timeout the channel is closing and ignores everything else.
+        client.send_method(1, &Method::ChannelOpen).await;
+        client.send_method(1, &Method::TxSelect).await;
+        assert!(client.try_recv_frame(Duration::from_millis(50)).await.is_none());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        client.open_channel(1).await;
+    }
+
+    #[test]
+    fn test_closing_channel_accepts_close_ok_only() {
+        let broker = Broker::new(Config::default());
+        let (deliveries, _inbox) = mpsc::unbounded_channel();
+        let mut channels = HashMap::new();
+        handle_frame(
+            &broker,
+            1,
+            Tuning::default(),
+            DEFAULT_VHOST,
+            None,
+            &deliveries,
+            &mut channels,
+            method_frame(1, Method::ChannelOpen),
+        )
+        .unwrap();
+        handle_frame(
+            &broker,
+            1,
+            Tuning::default(),
+            DEFAULT_VHOST,
+            None,
+            &deliveries,
+            &mut channels,
+            method_frame(1, Method::TxCommit),
+        )
+        .unwrap();
+        assert!(channels[&1].closing.is_some());
+
+        let replies = handle_frame(
+            &broker,
+            1,
+            Tuning::default(),
+            DEFAULT_VHOST,
+            None,
+            &deliveries,
+            &mut channels,
+            method_frame(1, Method::TxSelect),
+        )
+        .unwrap();
+        assert!(replies.is_empty());
+        handle_frame(
+            &broker,
+            1,
+            Tuning::default(),
+            DEFAULT_VHOST,
+            None,
+            &deliveries,
+            &mut channels,
+            method_frame(1, Method::ChannelCloseOk),
+        )
+        .unwrap();
+        assert!(channels.is_empty());
+    }
+
+    /// Counts the writes the server makes to its end of a duplex stream.
+    struct CountingStream {
+        inner: tokio::io::DuplexStream,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl AsyncRead for CountingStream {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for CountingStream {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            std::pin::Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    /// Connects to `broker` through a `CountingStream`, returning the client
+    /// and the server's write count.
+    async fn connect_counting_writes(broker: Arc<Broker>) -> (TestClient, Arc<AtomicUsize>) {
+        let (client, server) = tokio::io::duplex(1 << 20);
+        let writes = Arc::new(AtomicUsize::new(0));
+        let server = CountingStream {
+            inner: server,
+            writes: writes.clone(),
+        };
+        let handle = tokio::spawn({
+            let broker = broker.clone();
+            async move {
+                let _ = handle_connection(server, broker).await;
+            }
+        });
+        let mut client = TestClient::from_stream(broker, client, handle);
+        client.send_raw(b"AMQP\x00\x00\x09\x01").await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        (client, writes)
+    }
+
+    /// The server's end of a duplex stream that takes only `budget` more
+    /// bytes once one is set, and fails every write after that.
+    struct FailingStream {
+        inner: tokio::io::DuplexStream,
+        budget: Arc<std::sync::Mutex<Option<usize>>>,
+    }
+
+    impl AsyncRead for FailingStream {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for FailingStream {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let budget = *self.budget.lock().unwrap();
+            let buf = match budget {
+                None => buf,
+                Some(0) => return std::task::Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into())),
+                Some(left) => &buf[..left.min(buf.len())],
+            };
+            let written = std::pin::Pin::new(&mut self.inner).poll_write(cx, buf);
+            if let std::task::Poll::Ready(Ok(n)) = written {
+                if let Some(left) = self.budget.lock().unwrap().as_mut() {
+                    *left -= n;
+                }
+            }
+            written
+        }
+
+        fn poll_flush(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    fn batch_consume(batch: Option<i32>) -> Method {
+        let mut arguments = FieldTable::new();
+        if let Some(batch) = batch {
+            arguments.insert("x-delivery-batch", FieldValue::LongInt(batch));
+        }
+        Method::BasicConsume {
+            queue: "jobs".into(),
+            consumer_tag: "batched".into(),
+            no_local: false,
+            no_ack: false,
+            exclusive: false,
+            nowait: false,
+            arguments,
+        }
+    }
+
+    fn publish_jobs(broker: &Broker, count: usize) {
+        for n in 0..count {
+            let message = Message {
+                exchange: "work".into(),
+                routing_key: "jobs".into(),
+                properties: BasicProperties::default(),
+                body: format!("job-{}", n).into_bytes(),
+            };
+            broker.publish(DEFAULT_VHOST, message, false);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auto_ack_after_settles_deliveries_and_frees_prefetch() {
+        let broker = work_queue();
+        publish_jobs(&broker, 2);
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        let qos = Method::BasicQos {
+            prefetch_size: 0,
+            prefetch_count: 1,
+            global: false,
+        };
+        client.send_method(1, &qos).await;
+        assert_eq!(client.recv_method().await, (1, Method::BasicQosOk));
+        let consume = |no_ack| {
+            let mut arguments = FieldTable::new();
+            arguments.insert("x-auto-ack-after", FieldValue::LongInt(100));
+            Method::BasicConsume {
+                queue: "jobs".into(),
+                consumer_tag: "auto".into(),
+                no_local: false,
+                no_ack,
+                exclusive: false,
+                nowait: false,
+                arguments,
+            }
+        };
+        client.send_method(1, &consume(false)).await;
+        assert!(matches!(client.recv_method().await, (1, Method::BasicConsumeOk { .. })));
+
+        let started = Instant::now();
+        assert_eq!(client.recv_delivery().await.1, b"job-0");
+        // The window is full until the first delivery counts as acked.
+        let (_, body) = client.recv_delivery().await;
+        assert_eq!(body, b"job-1");
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let jobs = &broker.queue_summaries()[0];
+        assert_eq!((jobs.messages, jobs.unacked), (0, 0));
+
+        // Acking an auto-acked delivery is acking an unknown tag.
+        let ack = Method::BasicAck {
+            delivery_tag: 1,
+            multiple: false,
+        };
+        client.send_method(1, &ack).await;
+        client.expect_channel_close(1, reply_codes::PRECONDITION_FAILED).await;
+        client.send_method(1, &Method::ChannelCloseOk).await;
+
+        client.open_channel(2).await;
+        client.send_method(2, &consume(true)).await;
+        client.expect_channel_close(2, reply_codes::PRECONDITION_FAILED).await;
+    }
+
+    #[tokio::test]
+    async fn test_batched_deliveries_are_one_write_of_discrete_deliveries() {
+        let broker = work_queue();
+        publish_jobs(&broker, 10);
+        let (mut client, writes) = connect_counting_writes(broker).await;
+        let qos = Method::BasicQos {
+            prefetch_size: 0,
+            prefetch_count: 4,
+            global: false,
+        };
+        client.send_method(1, &qos).await;
+        assert_eq!(client.recv_method().await, (1, Method::BasicQosOk));
+
+        let before = writes.load(Ordering::SeqCst);
+        client.send_method(1, &batch_consume(Some(8))).await;
+        assert!(matches!(client.recv_method().await, (1, Method::BasicConsumeOk { .. })));
+        for n in 0..4u64 {
+            let (deliver, body) = client.recv_delivery().await;
+            let Method::BasicDeliver { delivery_tag, .. } = deliver else {
+                unreachable!()
+            };
+            assert_eq!((delivery_tag, body), (n + 1, format!("job-{}", n).into_bytes()));
+        }
+        // The prefetch window holds the rest back, batch or not.
+        assert!(client.try_recv_delivery(Duration::from_millis(50)).await.is_none());
+        // One write for Basic.Consume-Ok, one for all four deliveries.
+        assert_eq!(writes.load(Ordering::SeqCst), before + 2);
+
+        let ack = Method::BasicAck {
+            delivery_tag: 4,
+            multiple: true,
+        };
+        client.send_method(1, &ack).await;
+        for n in 4..8 {
+            let (_, body) = client.recv_delivery().await;
+            assert_eq!(body, format!("job-{}", n).into_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_a_delivery_failing_mid_write_is_requeued_whole() {
+        let broker = Arc::new(Broker::new(Config::default()));
+        // Deliveries to a consumer that goes away would wait out the window.
+        let mut arguments = FieldTable::new();
+        arguments.insert("x-consumer-reconnect-window", FieldValue::LongInt(60_000));
+        let declare = QueueDeclare {
+            queue: "jobs".into(),
+            arguments,
+            ..Default::default()
+        };
+        broker.declare_queue(DEFAULT_VHOST, declare).unwrap();
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let budget = Arc::new(std::sync::Mutex::new(None));
+        let server = FailingStream {
+            inner: server,
+            budget: budget.clone(),
+        };
+        let handle = tokio::spawn({
+            let broker = broker.clone();
+            async move {
+                let _ = handle_connection(server, broker).await;
+            }
+        });
+        let mut client = TestClient::from_stream(broker.clone(), client, handle);
+        client.send_raw(b"AMQP\x00\x00\x09\x01").await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        let consumer_tag = client.consume(1, "jobs", false).await;
+
+        let deliver = Method::BasicDeliver {
+            consumer_tag,
+            delivery_tag: 1,
+            redelivered: false,
+            exchange: "".into(),
+            routing_key: "jobs".into(),
+        };
+        // Room for the method frame only: the header and body fail.
+        *budget.lock().unwrap() = Some(AmqpFrame::method(1, &deliver).encode().len());
+        let message = Message {
+            exchange: "".into(),
+            routing_key: "jobs".into(),
+            properties: BasicProperties::default(),
+            body: b"intact".to_vec(),
+        };
+        broker.publish(DEFAULT_VHOST, message, false);
+        assert_eq!(client.recv_method().await, (1, deliver));
+        tokio::time::timeout(Duration::from_secs(1), &mut client.server)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let jobs = &broker.queue_summaries()[0];
+        assert_eq!((jobs.messages, jobs.unacked), (1, 0));
+        let requeued = broker.get(DEFAULT_VHOST, "jobs", true).unwrap().unwrap();
+        assert!(requeued.redelivered);
+        assert_eq!(requeued.message.body, b"intact");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_delivery_batch_is_refused() {
+        let mut client = TestClient::connect_to(work_queue()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client.send_method(1, &batch_consume(Some(0))).await;
+        client.expect_channel_close(1, reply_codes::PRECONDITION_FAILED).await;
+    }
+
+    /// Compares consuming with and without `x-delivery-batch`; run with
+    /// `cargo test --release -- --ignored bench_delivery_batching --nocapture`.
+    #[tokio::test]
+    #[ignore]
+    async fn bench_delivery_batching() {
+        const MESSAGES: usize = 20_000;
+        for batch in [None, Some(64)] {
+            let broker = work_queue();
+            publish_jobs(&broker, MESSAGES);
+            let (mut client, writes) = connect_counting_writes(broker).await;
+            let started = std::time::Instant::now();
+            client.send_method(1, &batch_consume(batch)).await;
+            client.recv_method().await;
+            for _ in 0..MESSAGES {
+                let (deliver, _) = client.recv_delivery().await;
+                let Method::BasicDeliver { delivery_tag, .. } = deliver else {
+                    unreachable!()
+                };
+                let ack = Method::BasicAck {
+                    delivery_tag,
+                    multiple: false,
+                };
+                client.send_method(1, &ack).await;
             }
+            let elapsed = started.elapsed();
+            println!(
+                "x-delivery-batch {:?}: {} messages in {:?} ({:.0}/s), {} writes",
+                batch,
+                MESSAGES,
+                elapsed,
+                MESSAGES as f64 / elapsed.as_secs_f64(),
+                writes.load(Ordering::SeqCst)
+            );
         }
     }
 }
@@ -0,0 +1,585 @@
+// src/definitions.rs
+
+//! Export and import of broker definitions, the JSON form of `Definitions`
+//! used to back up and restore topology. Messages are not part of it.
+//!
+//! The document has the shape of a RabbitMQ definitions file:
+//!
+//! ```json
+//! {
+//!   "vhosts": [{ "name": "/" }],
+//!   "users": [{ "name": "admin", "password_hash": "kI3GCqW5JLMJa4iX1lo7X4D6XbYqlLgxIs30+P6tENUV2POR",
+//!               "hashing_algorithm": "rabbit_password_hashing_sha256", "tags": ["administrator"] }],
+//!   "permissions": [{ "user": "admin", "vhost": "/", "configure": "*", "write": "*", "read": "*" }],
+//!   "exchanges": [{ "vhost": "/", "name": "orders", "type": "topic", "durable": true,
+//!                   "auto_delete": false, "internal": false, "arguments": {} }],
+//!   "queues": [{ "vhost": "/", "name": "orders.created", "durable": true,
+//!                "auto_delete": false, "arguments": { "x-queue-type": "quorum" } }],
+//!   "bindings": [{ "vhost": "/", "source": "orders", "destination": "orders.created",
+//!                  "destination_type": "queue", "routing_key": "orders.created", "arguments": {} }]
+//! }
+//! ```
+//!
+//! Users carry the salted hash of their password, never the password
+//! itself, so a document read back from an export logs in with the same
+//! passwords. They and their permissions are only exported and imported
+//! by a broker whose users live in a `UserStore`.
+//!
+//! Importing checks the whole document before applying any of it, then
+//! declares virtual hosts, users, permissions, exchanges, queues and
+//! bindings in that order. An imported user replaces one of the same name.
+//! A resource that already exists as described is left alone, so importing
+//! the same document twice changes nothing. Argument values keep only their
+//! JSON type: integers come back as long-long integers and strings as long
+//! strings.
+
+use serde_json::{json, Map, Value};
+
+use crate::auth::{PasswordHash, Permission, User, PASSWORD_HASHING};
+use crate::broker::{Broker, Definitions, ExchangeDeclare, QueueDeclare};
+use crate::exchange::Binding;
+use crate::field_table::{FieldTable, FieldValue};
+use crate::management::table_json;
+use crate::vhost;
+
+/// What importing one resource did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportResult {
+    /// `vhost`, `user`, `permission`, `exchange`, `queue` or `binding`.
+    pub kind: &'static str,
+    /// The resource as it appeared in the imported document.
+    pub resource: Value,
+    pub outcome: ImportOutcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImportOutcome {
+    Created,
+    /// The resource already existed exactly as described.
+    Unchanged,
+    /// Declaring the resource failed, for the given reason.
+    Failed(String),
+}
+
+impl ImportOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImportOutcome::Created => "created",
+            ImportOutcome::Unchanged => "unchanged",
+            ImportOutcome::Failed(_) => "failed",
+        }
+    }
+}
+
+/// The JSON document of `definitions`.
+pub fn to_json(definitions: &Definitions) -> Value {
+    json!({
+        "vhosts": definitions.vhosts.iter().map(|name| json!({ "name": name })).collect::<Vec<_>>(),
+        "users": definitions
+            .users
+            .iter()
+            .map(|(name, user)| user_json(name, user))
+            .collect::<Vec<_>>(),
+        "permissions": definitions
+            .permissions
+            .iter()
+            .map(|(vhost, user, permission)| permission_json(vhost, user, permission))
+            .collect::<Vec<_>>(),
+        "exchanges": definitions
+            .exchanges
+            .iter()
+            .map(|(vhost, exchange)| exchange_json(vhost, exchange))
+            .collect::<Vec<_>>(),
+        "queues": definitions
+            .queues
+            .iter()
+            .map(|(vhost, queue)| queue_json(vhost, queue))
+            .collect::<Vec<_>>(),
+        "bindings": definitions
+            .bindings
+            .iter()
+            .map(|(vhost, exchange, binding)| binding_json(vhost, exchange, binding))
+            .collect::<Vec<_>>(),
+    })
+}
+
+/// Reads a definitions document, naming the first invalid entry if any.
+pub fn from_json(document: &Value) -> Result<Definitions, String> {
+    let Some(document) = document.as_object() else {
+        return Err("definitions must be a JSON object".into());
+    };
+    let mut definitions = Definitions::default();
+    for (i, entry) in entries(document, "vhosts")?.iter().enumerate() {
+        let entry = Entry::new(entry, "vhosts", i)?;
+        definitions.vhosts.push(entry.string("name")?);
+    }
+    for (i, entry) in entries(document, "users")?.iter().enumerate() {
+        let entry = Entry::new(entry, "users", i)?;
+        if entry
+            .0
+            .get("hashing_algorithm")
+            .is_some_and(|scheme| scheme != PASSWORD_HASHING)
+        {
+            return Err(format!(
+                "{}: only {} password hashes are supported",
+                entry.1, PASSWORD_HASHING
+            ));
+        }
+        let Some(password) = PasswordHash::decode(&entry.string("password_hash")?) else {
+            return Err(format!("{}.password_hash is not a valid hash", entry.1));
+        };
+        let user = User {
+            password,
+            tags: entry.tags()?,
+        };
+        definitions.users.push((entry.string("name")?, user));
+    }
+    for (i, entry) in entries(document, "permissions")?.iter().enumerate() {
+        let entry = Entry::new(entry, "permissions", i)?;
+        let permission = Permission {
+            configure: entry.string("configure")?,
+            write: entry.string("write")?,
+            read: entry.string("read")?,
+        };
+        definitions
+            .permissions
+            .push((entry.string("vhost")?, entry.string("user")?, permission));
+    }
+    for (i, entry) in entries(document, "exchanges")?.iter().enumerate() {
+        let entry = Entry::new(entry, "exchanges", i)?;
+        let exchange = ExchangeDeclare {
+            exchange: entry.string("name")?,
+            kind: entry.string("type")?,
+            durable: entry.flag("durable")?,
+            auto_delete: entry.flag("auto_delete")?,
+            internal: entry.flag("internal")?,
+            arguments: entry.arguments()?,
+            ..Default::default()
+        };
+        definitions.exchanges.push((entry.string("vhost")?, exchange));
+    }
+    for (i, entry) in entries(document, "queues")?.iter().enumerate() {
+        let entry = Entry::new(entry, "queues", i)?;
+        let queue = QueueDeclare {
+            queue: entry.string("name")?,
+            durable: entry.flag("durable")?,
+            auto_delete: entry.flag("auto_delete")?,
+            arguments: entry.arguments()?,
+            ..Default::default()
+        };
+        definitions.queues.push((entry.string("vhost")?, queue));
+    }
+    for (i, entry) in entries(document, "bindings")?.iter().enumerate() {
+        let entry = Entry::new(entry, "bindings", i)?;
+        if entry.0.get("destination_type").is_some_and(|kind| kind != "queue") {
+            return Err(format!("{}: only queue destinations are supported", entry.1));
+        }
+        let binding = Binding {
+            queue: entry.string("destination")?,
+            routing_key: entry.optional_string("routing_key")?,
+            arguments: entry.arguments()?,
+        };
+        definitions
+            .bindings
+            .push((entry.string("vhost")?, entry.string("source")?, binding));
+    }
+    Ok(definitions)
+}
+
+/// Declares everything in `definitions` on `broker` that is not there yet.
+pub fn import(broker: &Broker, definitions: &Definitions) -> Vec<ImportResult> {
+    let existing = to_json(&broker.definitions());
+    let exists = |section: &str, resource: &Value| {
+        existing[section]
+            .as_array()
+            .is_some_and(|entries| entries.contains(resource))
+    };
+    let mut results = Vec::new();
+    let mut record = |kind, resource: Value, outcome: Result<bool, String>| {
+        let outcome = match outcome {
+            Ok(true) => ImportOutcome::Created,
+            Ok(false) => ImportOutcome::Unchanged,
+            Err(reason) => ImportOutcome::Failed(reason),
+        };
+        results.push(ImportResult {
+            kind,
+            resource,
+            outcome,
+        });
+    };
+
+    for name in &definitions.vhosts {
+        let outcome = match vhost::normalize_name(name) {
+            Some(name) => Ok(broker.add_vhost(&name)),
+            None => Err(format!("invalid vhost name '{}'", name)),
+        };
+        record("vhost", json!({ "name": name }), outcome);
+    }
+    let users = broker.auth().users();
+    let no_users = || Err("the broker's users cannot be changed while it runs".to_string());
+    for (name, user) in &definitions.users {
+        let resource = user_json(name, user);
+        let outcome = match users {
+            None => no_users(),
+            Some(_) if exists("users", &resource) => Ok(false),
+            Some(users) => {
+                users.put_user(name, user.clone());
+                Ok(true)
+            }
+        };
+        record("user", resource, outcome);
+    }
+    for (vhost, user, permission) in &definitions.permissions {
+        let resource = permission_json(vhost, user, permission);
+        let outcome = match users {
+            None => no_users(),
+            Some(_) if exists("permissions", &resource) => Ok(false),
+            Some(_) if !broker.has_vhost(vhost) => Err(format!("no vhost '{}'", vhost)),
+            Some(users) if users.user(user).is_none() => Err(format!("no user '{}'", user)),
+            Some(users) => {
+                users.set_permission(vhost, user, permission.clone());
+                Ok(true)
+            }
+        };
+        record("permission", resource, outcome);
+    }
+    for (vhost, exchange) in &definitions.exchanges {
+        let resource = exchange_json(vhost, exchange);
+        let outcome = if exists("exchanges", &resource) {
+            Ok(false)
+        } else {
+            broker
+                .declare_exchange(vhost, exchange.clone())
+                .map(|()| true)
+                .map_err(|e| e.to_string())
+        };
+        record("exchange", resource, outcome);
+    }
+    for (vhost, queue) in &definitions.queues {
+        let resource = queue_json(vhost, queue);
+        let outcome = if exists("queues", &resource) {
+            Ok(false)
+        } else {
+            broker
+                .declare_queue(vhost, queue.clone())
+                .map(|_| true)
+                .map_err(|e| e.to_string())
+        };
+        record("queue", resource, outcome);
+    }
+    for (vhost, exchange, binding) in &definitions.bindings {
+        let resource = binding_json(vhost, exchange, binding);
+        let outcome = if exists("bindings", &resource) {
+            Ok(false)
+        } else {
+            broker
+                .bind_queue(vhost, binding.clone(), exchange)
+                .map(|()| true)
+                .map_err(|e| e.to_string())
+        };
+        record("binding", resource, outcome);
+    }
+    results
+}
+
+fn user_json(name: &str, user: &User) -> Value {
+    json!({
+        "name": name,
+        "password_hash": user.password.encode(),
+        "hashing_algorithm": PASSWORD_HASHING,
+        "tags": user.tags,
+    })
+}
+
+fn permission_json(vhost: &str, user: &str, permission: &Permission) -> Value {
+    json!({
+        "user": user,
+        "vhost": vhost,
+        "configure": permission.configure,
+        "write": permission.write,
+        "read": permission.read,
+    })
+}
+
+fn exchange_json(vhost: &str, exchange: &ExchangeDeclare) -> Value {
+    json!({
+        "vhost": vhost,
+        "name": exchange.exchange,
+        "type": exchange.kind,
+        "durable": exchange.durable,
+        "auto_delete": exchange.auto_delete,
+        "internal": exchange.internal,
+        "arguments": table_json(&exchange.arguments),
+    })
+}
+
+fn queue_json(vhost: &str, queue: &QueueDeclare) -> Value {
+    json!({
+        "vhost": vhost,
+        "name": queue.queue,
+        "durable": queue.durable,
+        "auto_delete": queue.auto_delete,
+        "arguments": table_json(&queue.arguments),
+    })
+}
+
+fn binding_json(vhost: &str, exchange: &str, binding: &Binding) -> Value {
+    json!({
+        "vhost": vhost,
+        "source": exchange,
+        "destination": binding.queue,
+        "destination_type": "queue",
+        "routing_key": binding.routing_key,
+        "arguments": table_json(&binding.arguments),
+    })
+}
+
+/// The array `section` of the document; a missing one is empty.
+fn entries<'a>(document: &'a Map<String, Value>, section: &str) -> Result<&'a [Value], String> {
+    match document.get(section) {
+        None => Ok(&[]),
+        Some(Value::Array(entries)) => Ok(entries),
+        Some(_) => Err(format!("{} must be an array", section)),
+    }
+}
+
+/// One entry of a section, with its position for error messages.
+struct Entry<'a>(&'a Map<String, Value>, String);
+
+impl<'a> Entry<'a> {
+    fn new(entry: &'a Value, section: &str, index: usize) -> Result<Self, String> {
+        let at = format!("{}[{}]", section, index);
+        match entry.as_object() {
+            Some(entry) => Ok(Entry(entry, at)),
+            None => Err(format!("{} must be an object", at)),
+        }
+    }
+
+    fn string(&self, field: &str) -> Result<String, String> {
+        match self.0.get(field) {
+            Some(Value::String(value)) => Ok(value.clone()),
+            Some(_) => Err(format!("{}.{} must be a string", self.1, field)),
+            None => Err(format!("{} needs a {}", self.1, field)),
+        }
+    }
+
+    fn optional_string(&self, field: &str) -> Result<String, String> {
+        match self.0.get(field) {
+            None => Ok(String::new()),
+            Some(_) => self.string(field),
+        }
+    }
+
+    fn flag(&self, field: &str) -> Result<bool, String> {
+        match self.0.get(field) {
+            None => Ok(false),
+            Some(Value::Bool(value)) => Ok(*value),
+            Some(_) => Err(format!("{}.{} must be true or false", self.1, field)),
+        }
+    }
+
+    /// Tags as a list or, as older RabbitMQ versions write them,
+    /// comma-separated.
+    fn tags(&self) -> Result<Vec<String>, String> {
+        match self.0.get("tags") {
+            None => Ok(Vec::new()),
+            Some(Value::String(tags)) => Ok(tags
+                .split(',')
+                .map(str::trim)
+                .filter(|t| !t.is_empty())
+                .map(String::from)
+                .collect()),
+            Some(Value::Array(tags)) => tags
+                .iter()
+                .map(|t| t.as_str().map(String::from))
+                .collect::<Option<_>>()
+                .ok_or_else(|| format!("{}.tags must be strings", self.1)),
+            Some(_) => Err(format!("{}.tags must be a list or a string", self.1)),
+        }
+    }
+
+    fn arguments(&self) -> Result<FieldTable, String> {
+        match self.0.get("arguments") {
+            None => Ok(FieldTable::new()),
+            Some(Value::Object(arguments)) => Ok(field_table(arguments)),
+            Some(_) => Err(format!("{}.arguments must be an object", self.1)),
+        }
+    }
+}
+
+pub(crate) fn field_table(values: &Map<String, Value>) -> FieldTable {
+    let mut table = FieldTable::new();
+    for (key, value) in values {
+        table.insert(key.clone(), field_value(value));
+    }
+    table
+}
+
+fn field_value(value: &Value) -> FieldValue {
+    match value {
+        Value::Null => FieldValue::Void,
+        Value::Bool(b) => FieldValue::Boolean(*b),
+        Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => FieldValue::LongLongInt(i),
+            (None, Some(u)) => FieldValue::LongLongUint(u),
+            _ => FieldValue::Double(n.as_f64().unwrap_or_default()),
+        },
+        Value::String(s) => FieldValue::LongString(s.clone().into_bytes()),
+        Value::Array(values) => FieldValue::FieldArray(values.iter().map(field_value).collect()),
+        Value::Object(values) => FieldValue::FieldTable(field_table(values)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::UserConfig;
+    use crate::config::Config;
+    use crate::vhost::DEFAULT_VHOST;
+
+    fn populated_broker() -> Broker {
+        let broker = Broker::new(Config {
+            vhosts: vec!["staging".into()],
+            ..Default::default()
+        });
+        let orders = ExchangeDeclare {
+            exchange: "orders".into(),
+            kind: "topic".into(),
+            durable: true,
+            ..Default::default()
+        };
+        broker.declare_exchange("staging", orders).unwrap();
+        let mut arguments = FieldTable::new();
+        arguments.insert("x-max-length", FieldValue::LongInt(10));
+        let created = QueueDeclare {
+            queue: "orders.created".into(),
+            durable: true,
+            arguments,
+            ..Default::default()
+        };
+        broker.declare_queue("staging", created).unwrap();
+        let private = QueueDeclare {
+            queue: "private".into(),
+            exclusive: true,
+            ..Default::default()
+        };
+        broker.declare_queue(DEFAULT_VHOST, private).unwrap();
+        let binding = Binding {
+            queue: "orders.created".into(),
+            routing_key: "orders.*".into(),
+            arguments: FieldTable::new(),
+        };
+        broker.bind_queue("staging", binding, "orders").unwrap();
+        broker
+    }
+
+    #[test]
+    fn test_export_and_import_copy_topology() {
+        let exported = to_json(&populated_broker().definitions());
+        assert_eq!(exported["vhosts"], json!([{ "name": "/" }, { "name": "staging" }]));
+        // The exclusive queue belongs to a connection and stays behind.
+        assert_eq!(exported["queues"].as_array().unwrap().len(), 1);
+
+        let fresh = Broker::new(Config::default());
+        let results = import(&fresh, &from_json(&exported).unwrap());
+        let outcomes: Vec<_> = results.iter().map(|r| (r.kind, r.outcome.as_str())).collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                ("vhost", "unchanged"),
+                ("vhost", "created"),
+                ("exchange", "created"),
+                ("queue", "created"),
+                ("binding", "created"),
+            ]
+        );
+        assert_eq!(to_json(&fresh.definitions()), exported);
+
+        // A second import finds everything in place.
+        let again = import(&fresh, &from_json(&exported).unwrap());
+        assert!(again.iter().all(|r| r.outcome == ImportOutcome::Unchanged));
+    }
+
+    fn broker_with_users(users: &[(&str, &str)]) -> Broker {
+        let users = users
+            .iter()
+            .map(|(name, password)| UserConfig {
+                name: name.to_string(),
+                password: password.to_string(),
+                tags: vec!["administrator".into()],
+                vhosts: vec![DEFAULT_VHOST.into()],
+            })
+            .collect();
+        Broker::new(Config {
+            users,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_users_and_permissions_travel_with_their_password_hashes() {
+        let exported = to_json(&broker_with_users(&[("admin", "s3cret")]).definitions());
+        let user = &exported["users"][0];
+        assert_eq!(user["name"], "admin");
+        assert_eq!(user["hashing_algorithm"], PASSWORD_HASHING);
+        assert_eq!(user["tags"], json!(["administrator"]));
+        assert!(!exported.to_string().contains("s3cret"));
+        assert_eq!(
+            exported["permissions"],
+            json!([{ "user": "admin", "vhost": "/", "configure": "*", "write": "*", "read": "*" }])
+        );
+
+        let fresh = broker_with_users(&[("other", "pw")]);
+        let results = import(&fresh, &from_json(&exported).unwrap());
+        let outcomes: Vec<_> = results.iter().map(|r| (r.kind, r.outcome.as_str())).collect();
+        assert_eq!(
+            outcomes,
+            vec![("vhost", "unchanged"), ("user", "created"), ("permission", "created")]
+        );
+        let users = fresh.auth().users().unwrap();
+        assert!(users.check_password("admin", "s3cret").is_some());
+        assert!(users.check_password("admin", "pw").is_none());
+        assert!(import(&fresh, &from_json(&exported).unwrap())
+            .iter()
+            .all(|r| r.outcome == ImportOutcome::Unchanged));
+
+        // Without a user store there is nowhere to put them.
+        let plain = Broker::new(Config::default());
+        let results = import(&plain, &from_json(&exported).unwrap());
+        assert!(matches!(&results[1].outcome, ImportOutcome::Failed(reason) if reason.contains("users")));
+        assert_eq!(
+            from_json(&json!({ "users": [{ "name": "admin", "password": "s3cret" }] })).unwrap_err(),
+            "users[0] needs a password_hash"
+        );
+    }
+
+    #[test]
+    fn test_import_reports_each_failure() {
+        let broker = populated_broker();
+        let document = json!({
+            "queues": [
+                { "vhost": "staging", "name": "orders.created", "durable": false },
+                { "vhost": "staging", "name": "orders.shipped" },
+            ],
+            "bindings": [{ "vhost": "staging", "source": "missing", "destination": "orders.shipped" }],
+        });
+        let results = import(&broker, &from_json(&document).unwrap());
+        let outcomes: Vec<_> = results.iter().map(|r| r.outcome.as_str()).collect();
+        assert_eq!(outcomes, vec!["failed", "created", "failed"]);
+        assert!(matches!(&results[2].outcome, ImportOutcome::Failed(reason) if reason.contains("missing")));
+    }
+
+    #[test]
+    fn test_invalid_documents_are_rejected() {
+        assert!(from_json(&json!([])).is_err());
+        assert!(from_json(&json!({ "queues": {} })).is_err());
+        assert_eq!(
+            from_json(&json!({ "queues": [{ "vhost": "/" }] })).unwrap_err(),
+            "queues[0] needs a name"
+        );
+        assert_eq!(
+            from_json(&json!({ "exchanges": [{ "vhost": "/", "name": "x", "type": 1 }] })).unwrap_err(),
+            "exchanges[0].type must be a string"
+        );
+    }
+}
@@ -0,0 +1,72 @@
+// src/error.rs
+
+use std::fmt;
+
+/// An AMQP exception raised while handling a method.
+///
+/// Channel exceptions close only the offending channel, connection
+/// exceptions tear down the whole connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AmqpError {
+    ChannelException {
+        reply_code: u16,
+        reply_text: String,
+        class_id: u16,
+        method_id: u16,
+    },
+    ConnectionException {
+        reply_code: u16,
+        reply_text: String,
+        class_id: u16,
+        method_id: u16,
+    },
+}
+
+impl AmqpError {
+    pub fn channel(reply_code: u16, reply_text: impl Into<String>, class_id: u16, method_id: u16) -> Self {
+        AmqpError::ChannelException {
+            reply_code,
+            reply_text: reply_text.into(),
+            class_id,
+            method_id,
+        }
+    }
+
+    pub fn connection(reply_code: u16, reply_text: impl Into<String>, class_id: u16, method_id: u16) -> Self {
+        AmqpError::ConnectionException {
+            reply_code,
+            reply_text: reply_text.into(),
+            class_id,
+            method_id,
+        }
+    }
+
+    pub fn reply_code(&self) -> u16 {
+        match self {
+            AmqpError::ChannelException { reply_code, .. } => *reply_code,
+            AmqpError::ConnectionException { reply_code, .. } => *reply_code,
+        }
+    }
+
+    pub fn reply_text(&self) -> &str {
+        match self {
+            AmqpError::ChannelException { reply_text, .. } => reply_text,
+            AmqpError::ConnectionException { reply_text, .. } => reply_text,
+        }
+    }
+}
+
+impl fmt::Display for AmqpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AmqpError::ChannelException { reply_code, reply_text, .. } => {
+                write!(f, "channel exception {}: {}", reply_code, reply_text)
+            }
+            AmqpError::ConnectionException { reply_code, reply_text, .. } => {
+                write!(f, "connection exception {}: {}", reply_code, reply_text)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AmqpError {}
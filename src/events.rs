@@ -0,0 +1,209 @@
+// src/events.rs
+
+//! Structured events describing what happens inside a broker.
+//!
+//! The broker publishes a `BrokerEvent` on a broadcast channel whenever a
+//! connection or channel opens or closes, whenever it closes one with an
+//! exception, and whenever a queue, exchange or binding is created or
+//! removed. Embedders and tests observe them through
+//! `Broker::subscribe_events`. Events are sent without waiting for
+//! subscribers: one that falls more than `EVENT_CAPACITY` events behind
+//! sees a `Lagged` error and misses the overflow.
+//!
+//! A publisher traces how one message is routed by giving it an `x-trace`
+//! header, with any value but `false`; the broker then emits a
+//! `MessageRouted` event for it, and logs the same at info level.
+//!
+//! An `EventLog` keeps the most recent events for `GET /api/events`;
+//! `broker::spawn_event_log` fills it from the broadcast channel.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+
+/// How many events a subscriber may fall behind before it starts to miss them.
+pub const EVENT_CAPACITY: usize = 1024;
+
+/// Something that happened in the broker. Connections are identified by a
+/// number the broker assigns when the client's AMQP header arrives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BrokerEvent {
+    /// The handshake completed and `Connection.Open-Ok` was sent.
+    ConnectionOpened {
+        connection: u64,
+        user: Option<String>,
+        vhost: String,
+    },
+    /// An opened connection ended, for whatever reason.
+    ConnectionClosed {
+        connection: u64,
+    },
+    ChannelOpened {
+        connection: u64,
+        channel: u16,
+    },
+    /// The channel was closed by either peer, or went down with its connection.
+    ChannelClosed {
+        connection: u64,
+        channel: u16,
+    },
+    /// The broker sent `Channel.Close` for a channel exception.
+    ChannelException {
+        connection: u64,
+        channel: u16,
+        reply_code: u16,
+        reply_text: String,
+    },
+    /// The broker sent `Connection.Close` for a connection exception.
+    /// `connection` is `None` for one refused before it was numbered.
+    ConnectionException {
+        connection: Option<u64>,
+        reply_code: u16,
+        reply_text: String,
+    },
+    QueueDeclared {
+        vhost: String,
+        queue: String,
+    },
+    QueueDeleted {
+        vhost: String,
+        queue: String,
+    },
+    ExchangeDeclared {
+        vhost: String,
+        exchange: String,
+    },
+    ExchangeDeleted {
+        vhost: String,
+        exchange: String,
+    },
+    QueueBound {
+        vhost: String,
+        exchange: String,
+        queue: String,
+        routing_key: String,
+    },
+    QueueUnbound {
+        vhost: String,
+        exchange: String,
+        queue: String,
+        routing_key: String,
+    },
+    /// The disk alarm was raised, or cleared once the store took writes again.
+    DiskAlarm {
+        raised: bool,
+    },
+    /// A message published with an `x-trace` header was routed: the
+    /// bindings of `exchange` that matched it and the queues it went to.
+    /// A message dead-lettered from a queue is traced again on its way to
+    /// the dead-letter exchange, with that queue as `dead_lettered_from`.
+    MessageRouted {
+        vhost: String,
+        exchange: String,
+        routing_key: String,
+        bindings: Vec<TracedBinding>,
+        queues: Vec<String>,
+        dead_lettered_from: Option<String>,
+    },
+}
+
+impl BrokerEvent {
+    /// Whether the event declares, deletes, binds or unbinds something.
+    pub fn changes_topology(&self) -> bool {
+        matches!(
+            self,
+            BrokerEvent::QueueDeclared { .. }
+                | BrokerEvent::QueueDeleted { .. }
+                | BrokerEvent::ExchangeDeclared { .. }
+                | BrokerEvent::ExchangeDeleted { .. }
+                | BrokerEvent::QueueBound { .. }
+                | BrokerEvent::QueueUnbound { .. }
+        )
+    }
+}
+
+/// A binding that matched a traced message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TracedBinding {
+    pub queue: String,
+    pub routing_key: String,
+}
+
+pub(crate) fn channel() -> broadcast::Sender<BrokerEvent> {
+    broadcast::channel(EVENT_CAPACITY).0
+}
+
+/// An event, with when it was recorded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedEvent {
+    /// Time since the Unix epoch.
+    pub at: Duration,
+    pub event: BrokerEvent,
+}
+
+/// The most recent events, up to a fixed number; older ones are evicted.
+#[derive(Debug)]
+pub struct EventLog {
+    capacity: usize,
+    events: Mutex<VecDeque<RecordedEvent>>,
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> Self {
+        EventLog {
+            capacity,
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Keeps `event`, evicting the oldest one if the log is full.
+    pub fn push(&self, event: RecordedEvent) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut events = self.events.lock().unwrap();
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    /// The `limit` most recent events, oldest first.
+    pub fn recent(&self, limit: usize) -> Vec<RecordedEvent> {
+        let events = self.events.lock().unwrap();
+        events
+            .iter()
+            .skip(events.len().saturating_sub(limit))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn closed(connection: u64) -> RecordedEvent {
+        RecordedEvent {
+            at: Duration::from_secs(connection),
+            event: BrokerEvent::ConnectionClosed { connection },
+        }
+    }
+
+    #[test]
+    fn test_event_log_keeps_the_most_recent_events() {
+        let log = EventLog::new(3);
+        for connection in 1..=5 {
+            log.push(closed(connection));
+        }
+        assert_eq!(log.recent(10), [closed(3), closed(4), closed(5)]);
+        assert_eq!(log.recent(2), [closed(4), closed(5)]);
+        assert!(log.recent(0).is_empty());
+
+        let disabled = EventLog::new(0);
+        disabled.push(closed(1));
+        assert!(disabled.recent(10).is_empty());
+    }
+}
@@ -0,0 +1,797 @@
+// src/exchange.rs
+
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+
+use crate::clock::{Clock, TokioClock};
+use crate::error::AmqpError;
+use crate::field_table::{FieldTable, FieldValue};
+use crate::methods::{CLASS_EXCHANGE, CLASS_QUEUE};
+use crate::reply_codes;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExchangeType {
+    Direct,
+    Fanout,
+    Topic,
+    Headers,
+    /// Routes each message to one bound queue picked from a weighted hash
+    /// ring; the binding key is the weight.
+    ConsistentHash,
+    /// Routes each message to one of a fixed number of shard queues, picked
+    /// by the hash of the routing key modulo the `x-shards` argument. The
+    /// shard queues are declared along with the exchange; see `shard_queue`.
+    Modulus,
+}
+
+impl ExchangeType {
+    pub fn parse(kind: &str) -> Result<ExchangeType, AmqpError> {
+        match kind {
+            "direct" => Ok(ExchangeType::Direct),
+            "fanout" => Ok(ExchangeType::Fanout),
+            "topic" => Ok(ExchangeType::Topic),
+            "headers" => Ok(ExchangeType::Headers),
+            "x-consistent-hash" => Ok(ExchangeType::ConsistentHash),
+            "x-modulus-hash" => Ok(ExchangeType::Modulus),
+            _ => Err(AmqpError::connection(
+                reply_codes::COMMAND_INVALID,
+                format!("COMMAND_INVALID - unknown exchange type '{}'", kind),
+                CLASS_EXCHANGE,
+                10,
+            )),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExchangeType::Direct => "direct",
+            ExchangeType::Fanout => "fanout",
+            ExchangeType::Topic => "topic",
+            ExchangeType::Headers => "headers",
+            ExchangeType::ConsistentHash => "x-consistent-hash",
+            ExchangeType::Modulus => "x-modulus-hash",
+        }
+    }
+}
+
+/// A queue binding. Two bindings are the same binding only if the queue,
+/// the binding key and the arguments all match.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Binding {
+    pub queue: String,
+    pub routing_key: String,
+    pub arguments: FieldTable,
+}
+
+/// Routing counters of an exchange.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExchangeStats {
+    /// Messages that reached at least one queue.
+    pub messages_routed: u64,
+    /// Unroutable mandatory messages, returned to the publisher.
+    pub messages_unroutable_returned: u64,
+    /// Unroutable messages that were silently dropped.
+    pub messages_unroutable_dropped: u64,
+}
+
+impl ExchangeStats {
+    pub fn messages_unroutable(&self) -> u64 {
+        self.messages_unroutable_returned + self.messages_unroutable_dropped
+    }
+}
+
+#[derive(Debug)]
+pub struct Exchange {
+    pub name: String,
+    pub kind: ExchangeType,
+    pub durable: bool,
+    pub auto_delete: bool,
+    pub internal: bool,
+    pub arguments: FieldTable,
+    /// Name of the policy that applies to the exchange.
+    pub policy: Option<String>,
+    /// The definition of `policy`, which fills in arguments the exchange
+    /// was declared without.
+    pub policy_arguments: FieldTable,
+    pub bindings: Vec<Binding>,
+    pub stats: ExchangeStats,
+    /// Unix time the exchange was declared.
+    pub created_at: Duration,
+    /// Unix time of the last declare of or publish to the exchange.
+    pub last_active: Duration,
+    /// Predeclared by the broker (`""` and `amq.*`); cannot be deleted.
+    pub builtin: bool,
+    /// Hash ring of a consistent-hash exchange, kept in step with `bindings`.
+    ring: HashRing,
+    /// Compiled binding patterns of a topic exchange, kept in step with `bindings`.
+    topics: TopicTrie,
+}
+
+impl Exchange {
+    /// A transient, non-internal exchange without bindings.
+    pub fn new(name: String, kind: ExchangeType) -> Self {
+        Exchange {
+            name,
+            kind,
+            durable: false,
+            auto_delete: false,
+            internal: false,
+            arguments: FieldTable::new(),
+            policy: None,
+            policy_arguments: FieldTable::new(),
+            bindings: Vec::new(),
+            stats: ExchangeStats::default(),
+            created_at: TokioClock.unix_time(),
+            last_active: TokioClock.unix_time(),
+            builtin: false,
+            ring: HashRing::default(),
+            topics: TopicTrie::default(),
+        }
+    }
+
+    /// Whether this is the nameless default exchange, which routes every
+    /// message to the queue named by its routing key.
+    pub fn is_default(&self) -> bool {
+        self.builtin && self.name.is_empty()
+    }
+
+    /// The declared argument `key`, or the policy's.
+    fn argument(&self, key: &str) -> Option<&FieldValue> {
+        self.arguments.get(key).or_else(|| self.policy_arguments.get(key))
+    }
+
+    /// Checks that `binding` makes sense for this exchange's type.
+    pub fn check_binding(&self, binding: &Binding) -> Result<(), AmqpError> {
+        if self.is_default() {
+            return Err(AmqpError::channel(
+                reply_codes::ACCESS_REFUSED,
+                format!(
+                    "ACCESS_REFUSED - operation not permitted on the default exchange (queue '{}')",
+                    binding.queue
+                ),
+                CLASS_QUEUE,
+                20,
+            ));
+        }
+        if self.kind == ExchangeType::ConsistentHash && binding_weight(binding).is_none() {
+            return Err(AmqpError::channel(
+                reply_codes::PRECONDITION_FAILED,
+                format!(
+                    "PRECONDITION_FAILED - binding key '{}' of consistent-hash exchange '{}' is not a weight between 1 and {}",
+                    binding.routing_key, self.name, MAX_WEIGHT
+                ),
+                CLASS_QUEUE,
+                20,
+            ));
+        }
+        Ok(())
+    }
+
+    /// `binding` as this exchange keeps it. What its key means, empty ones
+    /// included, depends on the exchange type:
+    /// - fanout: nothing. The key is dropped, so binding a queue again with
+    ///   another key is the same binding.
+    /// - direct: the routing key to match exactly, so an empty key matches
+    ///   only messages published with an empty routing key.
+    /// - topic: a pattern, in which an empty key is one empty word matching
+    ///   only empty routing keys.
+    /// - headers: nothing for matching, which goes by the arguments, but it
+    ///   is kept as part of the binding.
+    pub fn canonical_binding(&self, mut binding: Binding) -> Binding {
+        if self.kind == ExchangeType::Fanout {
+            binding.routing_key.clear();
+        }
+        binding
+    }
+
+    /// Adds a binding, returning `false` if an identical one already exists.
+    pub fn bind(&mut self, binding: Binding) -> bool {
+        if self.bindings.contains(&binding) {
+            return false;
+        }
+        match self.kind {
+            ExchangeType::ConsistentHash => self.ring.add(&binding),
+            ExchangeType::Topic => self.topics.add(&binding.routing_key, self.bindings.len()),
+            _ => {}
+        }
+        self.bindings.push(binding);
+        true
+    }
+
+    /// Removes the binding exactly matching `binding`, returning whether it existed.
+    pub fn unbind(&mut self, binding: &Binding) -> bool {
+        let before = self.bindings.len();
+        self.bindings.retain(|b| b != binding);
+        let removed = self.bindings.len() != before;
+        if removed {
+            match self.kind {
+                ExchangeType::ConsistentHash => self.ring.remove(binding),
+                // Binding indexes after the removed one have shifted.
+                ExchangeType::Topic => self.topics = TopicTrie::new(&self.bindings),
+                _ => {}
+            }
+        }
+        removed
+    }
+
+    /// Removes every binding to `queue`, returning how many there were.
+    /// A topic exchange recompiles its trie once, however many went.
+    pub fn unbind_queue(&mut self, queue: &str) -> usize {
+        let (removed, kept): (Vec<Binding>, Vec<Binding>) = std::mem::take(&mut self.bindings)
+            .into_iter()
+            .partition(|b| b.queue == queue);
+        self.bindings = kept;
+        if !removed.is_empty() {
+            match self.kind {
+                ExchangeType::ConsistentHash => removed.iter().for_each(|binding| self.ring.remove(binding)),
+                ExchangeType::Topic => self.topics = TopicTrie::new(&self.bindings),
+                _ => {}
+            }
+        }
+        removed.len()
+    }
+
+    /// Returns the names of the queues a message should be routed to, each at most once.
+    pub fn route(&self, routing_key: &str, headers: Option<&FieldTable>) -> Vec<String> {
+        if self.is_default() {
+            // Every queue is implicitly bound by its own name.
+            return vec![routing_key.to_string()];
+        }
+        let mut queues: Vec<String> = Vec::new();
+        for binding in self.matched_bindings(routing_key, headers) {
+            if !queues.contains(&binding.queue) {
+                queues.push(binding.queue.clone());
+            }
+        }
+        queues
+    }
+
+    /// The bindings that match a message, which `route` takes its queues
+    /// from. A hashing exchange matches the bindings of the one queue it
+    /// picks; the default exchange has no bindings to match.
+    pub fn matched_bindings(&self, routing_key: &str, headers: Option<&FieldTable>) -> Vec<&Binding> {
+        let picked = match self.kind {
+            ExchangeType::Direct => return self.bindings.iter().filter(|b| b.routing_key == routing_key).collect(),
+            ExchangeType::Fanout => return self.bindings.iter().collect(),
+            ExchangeType::Headers => {
+                return self
+                    .bindings
+                    .iter()
+                    .filter(|b| headers_match(&b.arguments, headers))
+                    .collect()
+            }
+            ExchangeType::Topic => {
+                return self
+                    .topics
+                    .matches(routing_key)
+                    .into_iter()
+                    .map(|index| &self.bindings[index])
+                    .collect()
+            }
+            ExchangeType::ConsistentHash => self.route_by_hash(routing_key, headers),
+            ExchangeType::Modulus => self.route_by_modulus(routing_key),
+        };
+        match picked {
+            Some(queue) => self.bindings.iter().filter(|b| b.queue == queue).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Picks the queue owning the hash of the routing key, or of the header
+    /// named by the `hash-header` argument. A message without that header
+    /// is not routed.
+    fn route_by_hash(&self, routing_key: &str, headers: Option<&FieldTable>) -> Option<String> {
+        let hash = match self.argument("hash-header").and_then(FieldValue::as_str) {
+            Some(name) => {
+                let value = headers?.get(name)?;
+                match value.as_str() {
+                    Some(s) => hash_bytes(s.as_bytes()),
+                    None => hash_bytes(format!("{:?}", value).as_bytes()),
+                }
+            }
+            None => hash_bytes(routing_key.as_bytes()),
+        };
+        self.ring.lookup(hash)
+    }
+
+    /// Picks the shard queue owning the routing key, as long as it is still
+    /// bound.
+    fn route_by_modulus(&self, routing_key: &str) -> Option<String> {
+        let shards = shard_count(&self.arguments).ok()?;
+        let queue = shard_queue(
+            &self.name,
+            (hash_bytes(routing_key.as_bytes()) % u64::from(shards)) as u32,
+        );
+        self.bindings.iter().any(|b| b.queue == queue).then_some(queue)
+    }
+}
+
+/// Most shards a modulus exchange may have.
+pub const MAX_SHARDS: u32 = 1024;
+
+/// Number of shards of a modulus exchange, given as its `x-shards`
+/// argument.
+pub fn shard_count(arguments: &FieldTable) -> Result<u32, AmqpError> {
+    let value = arguments.get("x-shards");
+    value
+        .and_then(FieldValue::as_i64)
+        .and_then(|n| u32::try_from(n).ok())
+        .filter(|n| (1..=MAX_SHARDS).contains(n))
+        .ok_or_else(|| {
+            AmqpError::channel(
+                reply_codes::PRECONDITION_FAILED,
+                format!(
+                    "PRECONDITION_FAILED - invalid x-shards {:?}, expected a shard count between 1 and {}",
+                    value, MAX_SHARDS
+                ),
+                CLASS_EXCHANGE,
+                10,
+            )
+        })
+}
+
+/// Name of shard queue `shard`, counted from 0, of modulus exchange `exchange`.
+pub fn shard_queue(exchange: &str, shard: u32) -> String {
+    format!("{}.shard.{}", exchange, shard)
+}
+
+/// Ring points each unit of binding weight contributes, so that even small
+/// weights spread evenly around the ring.
+const POINTS_PER_WEIGHT: u32 = 64;
+/// Largest binding weight accepted, which bounds the size of the ring.
+const MAX_WEIGHT: u32 = 1024;
+
+/// Weighted consistent-hash ring: every binding owns `weight *
+/// POINTS_PER_WEIGHT` points, and a hash belongs to the first point at or
+/// after it. Binding or unbinding only adds or removes that binding's own
+/// points, so keys hashed to other queues keep their queue.
+#[derive(Debug, Default)]
+struct HashRing {
+    points: BTreeSet<(u64, String)>,
+}
+
+impl HashRing {
+    fn binding_points(binding: &Binding) -> impl Iterator<Item = (u64, String)> + '_ {
+        let weight = binding_weight(binding).unwrap_or(0);
+        (0..weight * POINTS_PER_WEIGHT).map(move |i| {
+            let point = format!("{}\0{}\0{}", binding.queue, binding.routing_key, i);
+            (hash_bytes(point.as_bytes()), binding.queue.clone())
+        })
+    }
+
+    fn add(&mut self, binding: &Binding) {
+        self.points.extend(HashRing::binding_points(binding));
+    }
+
+    fn remove(&mut self, binding: &Binding) {
+        for point in HashRing::binding_points(binding) {
+            self.points.remove(&point);
+        }
+    }
+
+    fn lookup(&self, hash: u64) -> Option<String> {
+        self.points
+            .range((hash, String::new())..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, queue)| queue.clone())
+    }
+}
+
+/// Weight of a consistent-hash binding, given as its binding key.
+fn binding_weight(binding: &Binding) -> Option<u32> {
+    binding
+        .routing_key
+        .trim()
+        .parse()
+        .ok()
+        .filter(|w| (1..=MAX_WEIGHT).contains(w))
+}
+
+/// FNV-1a followed by a 64-bit finalizer; stable across builds and
+/// platforms so routing survives restarts.
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Binding patterns of a topic exchange compiled into a trie of their
+/// words. Matching walks the trie a word of the routing key at a time,
+/// tracking the set of nodes reached so far, so a publish costs at most
+/// words times nodes steps however many `#` the patterns hold, where
+/// matching each pattern on its own can backtrack exponentially.
+#[derive(Debug)]
+struct TopicTrie {
+    /// Node 0 is the root, standing for the empty pattern.
+    nodes: Vec<TopicNode>,
+}
+
+#[derive(Debug, Default)]
+struct TopicNode {
+    words: HashMap<String, usize>,
+    star: Option<usize>,
+    hash: Option<usize>,
+    /// Whether this node was reached by a `#`, which may repeat.
+    is_hash: bool,
+    /// Indexes into `Exchange::bindings` of the patterns ending here.
+    bindings: Vec<usize>,
+}
+
+impl Default for TopicTrie {
+    fn default() -> Self {
+        TopicTrie {
+            nodes: vec![TopicNode::default()],
+        }
+    }
+}
+
+impl TopicTrie {
+    fn new(bindings: &[Binding]) -> Self {
+        let mut trie = TopicTrie::default();
+        for (index, binding) in bindings.iter().enumerate() {
+            trie.add(&binding.routing_key, index);
+        }
+        trie
+    }
+
+    fn add(&mut self, pattern: &str, index: usize) {
+        let mut node = 0;
+        for word in pattern.split('.') {
+            let existing = match word {
+                "*" => self.nodes[node].star,
+                "#" => self.nodes[node].hash,
+                _ => self.nodes[node].words.get(word).copied(),
+            };
+            node = match existing {
+                Some(next) => next,
+                None => {
+                    let next = self.nodes.len();
+                    self.nodes.push(TopicNode {
+                        is_hash: word == "#",
+                        ..TopicNode::default()
+                    });
+                    match word {
+                        "*" => self.nodes[node].star = Some(next),
+                        "#" => self.nodes[node].hash = Some(next),
+                        _ => {
+                            self.nodes[node].words.insert(word.to_string(), next);
+                        }
+                    }
+                    next
+                }
+            };
+        }
+        self.nodes[node].bindings.push(index);
+    }
+
+    /// Adds `node` to `reached`, with the `#` nodes below it that match no
+    /// words.
+    fn reach(&self, node: usize, reached: &mut Vec<usize>, seen: &mut [bool]) {
+        let mut node = Some(node);
+        while let Some(n) = node {
+            if seen[n] {
+                break;
+            }
+            seen[n] = true;
+            reached.push(n);
+            node = self.nodes[n].hash;
+        }
+    }
+
+    /// Indexes of the bindings whose pattern matches `routing_key`, in
+    /// binding order.
+    fn matches(&self, routing_key: &str) -> Vec<usize> {
+        let mut seen = vec![false; self.nodes.len()];
+        let mut current = Vec::new();
+        self.reach(0, &mut current, &mut seen);
+        for word in routing_key.split('.') {
+            seen.iter_mut().for_each(|s| *s = false);
+            let mut next = Vec::new();
+            for &n in &current {
+                let node = &self.nodes[n];
+                if node.is_hash {
+                    self.reach(n, &mut next, &mut seen);
+                }
+                if let Some(&child) = node.words.get(word) {
+                    self.reach(child, &mut next, &mut seen);
+                }
+                if let Some(child) = node.star {
+                    self.reach(child, &mut next, &mut seen);
+                }
+            }
+            if next.is_empty() {
+                return Vec::new();
+            }
+            current = next;
+        }
+        let mut indexes: Vec<usize> = current
+            .iter()
+            .flat_map(|&n| self.nodes[n].bindings.iter().copied())
+            .collect();
+        indexes.sort_unstable();
+        indexes
+    }
+}
+
+/// Matches a routing key against a topic binding pattern, where `*`
+/// matches exactly one word and `#` matches zero or more words.
+///
+/// Topic exchanges route through their compiled `TopicTrie`; this matches
+/// one pattern on its own.
+pub fn topic_matches(pattern: &str, routing_key: &str) -> bool {
+    fn matches(pattern: &[&str], key: &[&str]) -> bool {
+        match pattern.split_first() {
+            None => key.is_empty(),
+            Some((&"#", rest)) => (0..=key.len()).any(|skip| matches(rest, &key[skip..])),
+            Some((&word, rest)) => match key.split_first() {
+                Some((&k, key_rest)) => (word == "*" || word == k) && matches(rest, key_rest),
+                None => false,
+            },
+        }
+    }
+    let pattern: Vec<&str> = pattern.split('.').collect();
+    let key: Vec<&str> = routing_key.split('.').collect();
+    matches(&pattern, &key)
+}
+
+/// Matches message headers against headers-exchange binding arguments.
+///
+/// `x-match` selects `all` (the default) or `any`; other `x-` prefixed
+/// arguments are not used for matching.
+fn headers_match(arguments: &FieldTable, headers: Option<&FieldTable>) -> bool {
+    let match_any = matches!(
+        arguments.get("x-match").and_then(FieldValue::as_str),
+        Some("any")
+    );
+    let mut criteria = arguments.iter().filter(|(k, _)| !k.starts_with("x-")).peekable();
+    if criteria.peek().is_none() {
+        return true;
+    }
+    let mut matched = criteria.map(|(key, expected)| {
+        let actual = headers.and_then(|h| h.get(key));
+        match expected {
+            FieldValue::Void => actual.is_some(),
+            _ => actual == Some(expected),
+        }
+    });
+    if match_any {
+        matched.any(|m| m)
+    } else {
+        matched.all(|m| m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn table(entries: &[(&str, &str)]) -> FieldTable {
+        let mut table = FieldTable::new();
+        for (k, v) in entries {
+            table.insert(*k, FieldValue::LongString(v.as_bytes().to_vec()));
+        }
+        table
+    }
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches("orders.*", "orders.created"));
+        assert!(!topic_matches("orders.*", "orders.created.eu"));
+        assert!(topic_matches("orders.#", "orders"));
+        assert!(topic_matches("#.eu", "orders.created.eu"));
+        assert!(topic_matches("#", ""));
+        assert!(!topic_matches("orders", "invoices"));
+    }
+
+    fn topic_exchange(patterns: &[&str]) -> Exchange {
+        let mut exchange = Exchange::new("events".into(), ExchangeType::Topic);
+        for (i, pattern) in patterns.iter().enumerate() {
+            exchange.bind(Binding {
+                queue: format!("q{}", i),
+                routing_key: pattern.to_string(),
+                arguments: FieldTable::new(),
+            });
+        }
+        exchange
+    }
+
+    #[test]
+    fn test_compiled_topic_routing_agrees_with_patterns() {
+        let patterns = [
+            "orders.*",
+            "orders.#",
+            "#.eu",
+            "#",
+            "*.created.*",
+            "orders.#.eu",
+            "#.#",
+            "*",
+            "orders",
+            "#.*.#",
+        ];
+        let mut exchange = topic_exchange(&patterns);
+        let keys = [
+            "",
+            "orders",
+            "orders.created",
+            "orders.created.eu",
+            "invoices.eu",
+            "a.b.c.d",
+            "eu",
+        ];
+        for key in keys {
+            let expected: Vec<String> = (0..patterns.len())
+                .filter(|&i| topic_matches(patterns[i], key))
+                .map(|i| format!("q{}", i))
+                .collect();
+            assert_eq!(exchange.route(key, None), expected, "key '{}'", key);
+        }
+        // Unbinding recompiles the remaining patterns.
+        exchange.unbind(&Binding {
+            queue: "q3".into(),
+            routing_key: "#".into(),
+            arguments: FieldTable::new(),
+        });
+        assert_eq!(exchange.route("invoices", None), ["q6", "q7", "q9"]);
+    }
+
+    #[test]
+    fn test_compiled_topic_routing_does_not_backtrack() {
+        let pattern = ["#.a"; 30].join(".") + ".b";
+        let exchange = topic_exchange(&[&pattern]);
+        let key = ["a"; 60].join(".");
+        assert!(exchange.route(&key, None).is_empty());
+    }
+
+    #[test]
+    fn test_unbinding_a_queue_removes_all_its_bindings() {
+        let mut exchange = topic_exchange(&["orders.*", "#.eu", "orders.#"]);
+        for pattern in ["invoices.*", "*.us"] {
+            exchange.bind(Binding {
+                queue: "q1".into(),
+                routing_key: pattern.into(),
+                arguments: FieldTable::new(),
+            });
+        }
+        assert_eq!(exchange.unbind_queue("q1"), 3);
+        assert_eq!(exchange.unbind_queue("q1"), 0);
+        assert_eq!(exchange.route("orders.created.eu", None), ["q2"]);
+        assert_eq!(exchange.route("orders.created", None), ["q0", "q2"]);
+        assert!(exchange.route("invoices.us", None).is_empty());
+    }
+
+    /// Compares routing through the compiled trie with matching every
+    /// binding pattern in turn. Run with `cargo test --release -- --ignored
+    /// bench_topic_routing --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_topic_routing() {
+        let patterns: Vec<String> = (0..2_000)
+            .map(|i| match i % 4 {
+                0 => format!("orders.{}.*", i),
+                1 => format!("#.region{}", i),
+                2 => format!("*.{}.#", i),
+                _ => format!("events.{}.created", i),
+            })
+            .collect();
+        let refs: Vec<&str> = patterns.iter().map(String::as_str).collect();
+        let exchange = topic_exchange(&refs);
+        let keys: Vec<String> = (0..1_000)
+            .map(|i| format!("orders.{}.created.region{}", i, i))
+            .collect();
+
+        let started = std::time::Instant::now();
+        let compiled: usize = keys.iter().map(|key| exchange.route(key, None).len()).sum();
+        let compiled_time = started.elapsed();
+        let started = std::time::Instant::now();
+        let naive: usize = keys
+            .iter()
+            .map(|key| patterns.iter().filter(|p| topic_matches(p, key)).count())
+            .sum();
+        let naive_time = started.elapsed();
+
+        println!("compiled: {:?}, naive: {:?}", compiled_time, naive_time);
+        assert_eq!(compiled, naive);
+        assert!(compiled_time < naive_time);
+    }
+
+    #[test]
+    fn test_headers_match() {
+        let all = table(&[("x-match", "all"), ("format", "pdf"), ("type", "report")]);
+        let any = table(&[("x-match", "any"), ("format", "pdf"), ("type", "report")]);
+        let headers = table(&[("format", "pdf")]);
+        assert!(!headers_match(&all, Some(&headers)));
+        assert!(headers_match(&any, Some(&headers)));
+        assert!(!headers_match(&any, None));
+    }
+
+    fn hash_exchange(queues: &[(&str, &str)]) -> Exchange {
+        let mut exchange = Exchange::new("sharded".into(), ExchangeType::ConsistentHash);
+        for (queue, weight) in queues {
+            exchange.bind(weighted(queue, weight));
+        }
+        exchange
+    }
+
+    fn weighted(queue: &str, weight: &str) -> Binding {
+        Binding {
+            queue: queue.into(),
+            routing_key: weight.into(),
+            arguments: FieldTable::new(),
+        }
+    }
+
+    fn shares(exchange: &Exchange) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for i in 0..10_000 {
+            let routed = exchange.route(&format!("order-{}", i), None);
+            assert_eq!(routed.len(), 1);
+            *counts.entry(routed[0].clone()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_consistent_hash_spreads_by_weight() {
+        let even = shares(&hash_exchange(&[("q1", "1"), ("q2", "1"), ("q3", "1"), ("q4", "1")]));
+        for queue in ["q1", "q2", "q3", "q4"] {
+            let count = even[queue];
+            assert!((1_750..=3_250).contains(&count), "{} got {}", queue, count);
+        }
+        let weighted = shares(&hash_exchange(&[("light", "1"), ("heavy", "3")]));
+        assert!(weighted["heavy"] > 2 * weighted["light"], "{:?}", weighted);
+    }
+
+    #[test]
+    fn test_consistent_hash_routing_is_stable() {
+        let mut exchange = hash_exchange(&[("q1", "1"), ("q2", "1"), ("q3", "1")]);
+        let before: Vec<Vec<String>> = (0..1_000).map(|i| exchange.route(&format!("k{}", i), None)).collect();
+        assert_eq!(
+            before,
+            (0..1_000)
+                .map(|i| exchange.route(&format!("k{}", i), None))
+                .collect::<Vec<_>>()
+        );
+
+        // Adding a queue only takes keys over; it never moves them between old queues.
+        exchange.bind(weighted("q4", "1"));
+        for (i, old) in before.iter().enumerate() {
+            let new = exchange.route(&format!("k{}", i), None);
+            assert!(new == *old || new == ["q4"], "k{} moved from {:?} to {:?}", i, old, new);
+        }
+        // Removing it again restores the original routing.
+        exchange.unbind(&weighted("q4", "1"));
+        assert_eq!(
+            before,
+            (0..1_000)
+                .map(|i| exchange.route(&format!("k{}", i), None))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_consistent_hash_by_header_and_weight_check() {
+        let mut exchange = hash_exchange(&[("q1", "1"), ("q2", "1")]);
+        exchange.arguments = table(&[("hash-header", "customer")]);
+        let headers = table(&[("customer", "acme")]);
+        let expected = exchange.route("ignored", Some(&headers));
+        assert_eq!(expected.len(), 1);
+        for i in 0..20 {
+            assert_eq!(exchange.route(&format!("key-{}", i), Some(&headers)), expected);
+        }
+        assert!(exchange.route("key", None).is_empty());
+        assert!(exchange.check_binding(&weighted("q3", "heavy")).is_err());
+        assert!(exchange.check_binding(&weighted("q3", "0")).is_err());
+        assert!(exchange.check_binding(&weighted("q3", "2")).is_ok());
+    }
+}
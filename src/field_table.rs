@@ -0,0 +1,435 @@
+// src/field_table.rs
+
+//! AMQP field tables, used for method arguments, message headers and
+//! client properties.
+//!
+//! Tables keep their entries in wire order so that re-encoding a decoded
+//! table yields the same bytes. Decoding is bounded by `TableLimits`, so a
+//! peer cannot exhaust the stack with deeply nested tables and arrays.
+
+use std::collections::HashSet;
+
+use bytes::BufMut;
+use nom::bytes::complete::take;
+
+use crate::wire::{long, longlong, longstr, octet, put_longstr, put_shortstr, short, shortstr, ParseResult};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Boolean(bool),
+    ShortShortInt(i8),
+    ShortShortUint(u8),
+    ShortInt(i16),
+    ShortUint(u16),
+    LongInt(i32),
+    LongUint(u32),
+    LongLongInt(i64),
+    LongLongUint(u64),
+    Float(f32),
+    Double(f64),
+    Decimal(u8, u32),
+    LongString(Vec<u8>),
+    FieldArray(Vec<FieldValue>),
+    Timestamp(u64),
+    FieldTable(FieldTable),
+    Void,
+    ByteArray(Vec<u8>),
+}
+
+impl FieldValue {
+    /// Returns the value as a string if it is a UTF-8 long string.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            FieldValue::LongString(bytes) => std::str::from_utf8(bytes).ok(),
+            _ => None,
+        }
+    }
+
+    /// Returns the value as an integer if it is of any integer type.
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            FieldValue::ShortShortInt(v) => Some(v.into()),
+            FieldValue::ShortShortUint(v) => Some(v.into()),
+            FieldValue::ShortInt(v) => Some(v.into()),
+            FieldValue::ShortUint(v) => Some(v.into()),
+            FieldValue::LongInt(v) => Some(v.into()),
+            FieldValue::LongUint(v) => Some(v.into()),
+            FieldValue::LongLongInt(v) => Some(v),
+            FieldValue::LongLongUint(v) => i64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    /// Bytes the value keeps on the heap: string and byte contents, and
+    /// the entries of nested arrays and tables.
+    pub fn heap_size(&self) -> usize {
+        match self {
+            FieldValue::LongString(bytes) | FieldValue::ByteArray(bytes) => bytes.len(),
+            FieldValue::FieldArray(values) => values
+                .iter()
+                .map(|value| std::mem::size_of::<FieldValue>() + value.heap_size())
+                .sum(),
+            FieldValue::FieldTable(table) => table.heap_size(),
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FieldTable {
+    entries: Vec<(String, FieldValue)>,
+}
+
+/// Tables are maps: two are equal when they hold the same keys with equal
+/// values, in whatever order. Declares and bindings compare arguments this
+/// way, so a client listing them in another order still matches. A decoded
+/// table may repeat a key; as for `get`, its first value is the one that
+/// counts.
+impl PartialEq for FieldTable {
+    fn eq(&self, other: &Self) -> bool {
+        let keys: HashSet<&str> = self.entries.iter().map(|(k, _)| k.as_str()).collect();
+        let other_keys: HashSet<&str> = other.entries.iter().map(|(k, _)| k.as_str()).collect();
+        keys == other_keys && keys.into_iter().all(|key| self.get(key) == other.get(key))
+    }
+}
+
+impl FieldTable {
+    pub fn new() -> Self {
+        FieldTable::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&FieldValue> {
+        self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    /// Sets `key` to `value`, replacing an existing entry in place.
+    pub fn insert(&mut self, key: impl Into<String>, value: FieldValue) {
+        let key = key.into();
+        match self.entries.iter_mut().find(|(k, _)| *k == key) {
+            Some(entry) => entry.1 = value,
+            None => self.entries.push((key, value)),
+        }
+    }
+
+    /// Bytes the table keeps on the heap: each entry, its key and whatever
+    /// its value holds. Spare capacity is not counted.
+    pub fn heap_size(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|(key, value)| std::mem::size_of::<(String, FieldValue)>() + key.len() + value.heap_size())
+            .sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &FieldValue)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v))
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let mut body = Vec::new();
+        for (key, value) in &self.entries {
+            put_shortstr(&mut body, key);
+            encode_value(&mut body, value);
+        }
+        put_longstr(buf, &body);
+    }
+}
+
+/// Bounds on what the decoder accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableLimits {
+    /// Most tables and arrays nested in one another, counting the outer table.
+    pub max_depth: usize,
+    /// Largest encoded table, in bytes.
+    pub max_bytes: u32,
+}
+
+impl Default for TableLimits {
+    fn default() -> Self {
+        TableLimits {
+            max_depth: 32,
+            max_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// Parses a field table within the default `TableLimits`.
+pub fn parse_field_table(input: &[u8]) -> ParseResult<'_, FieldTable> {
+    parse_field_table_within(input, &TableLimits::default())
+}
+
+/// Parses a field table, failing with `ErrorKind::TooLarge` if it is
+/// nested or sized beyond `limits`.
+pub fn parse_field_table_within<'a>(input: &'a [u8], limits: &TableLimits) -> ParseResult<'a, FieldTable> {
+    let (input, len) = long(input)?;
+    if len > limits.max_bytes {
+        return Err(too_large(input));
+    }
+    parse_table_body(input, len, limits, 1)
+}
+
+fn parse_table_body<'a>(input: &'a [u8], len: u32, limits: &TableLimits, depth: usize) -> ParseResult<'a, FieldTable> {
+    if depth > limits.max_depth {
+        return Err(too_large(input));
+    }
+    let (rest, mut body) = take(len)(input)?;
+    let mut table = FieldTable::new();
+    while !body.is_empty() {
+        let (i, key) = shortstr(body)?;
+        let (i, value) = parse_field_value(i, limits, depth)?;
+        table.entries.push((key, value));
+        body = i;
+    }
+    Ok((rest, table))
+}
+
+fn too_large(input: &[u8]) -> nom::Err<nom::error::Error<&[u8]>> {
+    nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::TooLarge))
+}
+
+/// Parses one value of a table or array nested `depth` deep.
+fn parse_field_value<'a>(input: &'a [u8], limits: &TableLimits, depth: usize) -> ParseResult<'a, FieldValue> {
+    let (input, tag) = octet(input)?;
+    match tag {
+        b't' => octet(input).map(|(i, v)| (i, FieldValue::Boolean(v != 0))),
+        b'b' => octet(input).map(|(i, v)| (i, FieldValue::ShortShortInt(v as i8))),
+        b'B' => octet(input).map(|(i, v)| (i, FieldValue::ShortShortUint(v))),
+        b's' => short(input).map(|(i, v)| (i, FieldValue::ShortInt(v as i16))),
+        b'u' => short(input).map(|(i, v)| (i, FieldValue::ShortUint(v))),
+        b'I' => long(input).map(|(i, v)| (i, FieldValue::LongInt(v as i32))),
+        b'i' => long(input).map(|(i, v)| (i, FieldValue::LongUint(v))),
+        b'l' => longlong(input).map(|(i, v)| (i, FieldValue::LongLongInt(v as i64))),
+        b'L' => longlong(input).map(|(i, v)| (i, FieldValue::LongLongUint(v))),
+        b'f' => long(input).map(|(i, v)| (i, FieldValue::Float(f32::from_bits(v)))),
+        b'd' => longlong(input).map(|(i, v)| (i, FieldValue::Double(f64::from_bits(v)))),
+        b'D' => {
+            let (input, scale) = octet(input)?;
+            let (input, value) = long(input)?;
+            Ok((input, FieldValue::Decimal(scale, value)))
+        }
+        b'S' => longstr(input).map(|(i, v)| (i, FieldValue::LongString(v))),
+        b'A' => {
+            if depth + 1 > limits.max_depth {
+                return Err(too_large(input));
+            }
+            let (input, len) = long(input)?;
+            let (rest, mut body) = take(len)(input)?;
+            let mut values = Vec::new();
+            while !body.is_empty() {
+                let (i, value) = parse_field_value(body, limits, depth + 1)?;
+                values.push(value);
+                body = i;
+            }
+            Ok((rest, FieldValue::FieldArray(values)))
+        }
+        b'T' => longlong(input).map(|(i, v)| (i, FieldValue::Timestamp(v))),
+        b'F' => {
+            let (input, len) = long(input)?;
+            parse_table_body(input, len, limits, depth + 1).map(|(i, t)| (i, FieldValue::FieldTable(t)))
+        }
+        b'V' => Ok((input, FieldValue::Void)),
+        b'x' => longstr(input).map(|(i, v)| (i, FieldValue::ByteArray(v))),
+        _ => Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Tag,
+        ))),
+    }
+}
+
+fn encode_value(buf: &mut Vec<u8>, value: &FieldValue) {
+    match value {
+        FieldValue::Boolean(v) => {
+            buf.put_u8(b't');
+            buf.put_u8(*v as u8);
+        }
+        FieldValue::ShortShortInt(v) => {
+            buf.put_u8(b'b');
+            buf.put_i8(*v);
+        }
+        FieldValue::ShortShortUint(v) => {
+            buf.put_u8(b'B');
+            buf.put_u8(*v);
+        }
+        FieldValue::ShortInt(v) => {
+            buf.put_u8(b's');
+            buf.put_i16(*v);
+        }
+        FieldValue::ShortUint(v) => {
+            buf.put_u8(b'u');
+            buf.put_u16(*v);
+        }
+        FieldValue::LongInt(v) => {
+            buf.put_u8(b'I');
+            buf.put_i32(*v);
+        }
+        FieldValue::LongUint(v) => {
+            buf.put_u8(b'i');
+            buf.put_u32(*v);
+        }
+        FieldValue::LongLongInt(v) => {
+            buf.put_u8(b'l');
+            buf.put_i64(*v);
+        }
+        FieldValue::LongLongUint(v) => {
+            buf.put_u8(b'L');
+            buf.put_u64(*v);
+        }
+        FieldValue::Float(v) => {
+            buf.put_u8(b'f');
+            buf.put_f32(*v);
+        }
+        FieldValue::Double(v) => {
+            buf.put_u8(b'd');
+            buf.put_f64(*v);
+        }
+        FieldValue::Decimal(scale, v) => {
+            buf.put_u8(b'D');
+            buf.put_u8(*scale);
+            buf.put_u32(*v);
+        }
+        FieldValue::LongString(bytes) => {
+            buf.put_u8(b'S');
+            put_longstr(buf, bytes);
+        }
+        FieldValue::FieldArray(values) => {
+            buf.put_u8(b'A');
+            let mut body = Vec::new();
+            for v in values {
+                encode_value(&mut body, v);
+            }
+            put_longstr(buf, &body);
+        }
+        FieldValue::Timestamp(v) => {
+            buf.put_u8(b'T');
+            buf.put_u64(*v);
+        }
+        FieldValue::FieldTable(table) => {
+            buf.put_u8(b'F');
+            table.encode(buf);
+        }
+        FieldValue::Void => buf.put_u8(b'V'),
+        FieldValue::ByteArray(bytes) => {
+            buf.put_u8(b'x');
+            put_longstr(buf, bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field_table_roundtrip() {
+        let mut nested = FieldTable::new();
+        nested.insert("flag", FieldValue::Boolean(true));
+        let mut table = FieldTable::new();
+        table.insert("x-queue-type", FieldValue::LongString(b"quorum".to_vec()));
+        table.insert("x-max-length", FieldValue::LongInt(10));
+        table.insert("nested", FieldValue::FieldTable(nested));
+        table.insert(
+            "list",
+            FieldValue::FieldArray(vec![FieldValue::LongLongInt(-1), FieldValue::Void]),
+        );
+
+        let mut buf = Vec::new();
+        table.encode(&mut buf);
+        let (rest, decoded) = parse_field_table(&buf).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded, table);
+    }
+
+    /// Decodes a table of long ints encoded as given, repeated keys and all.
+    fn decode_wire(entries: &[(&str, i32)]) -> FieldTable {
+        let mut body = Vec::new();
+        for (key, value) in entries {
+            put_shortstr(&mut body, key);
+            encode_value(&mut body, &FieldValue::LongInt(*value));
+        }
+        let mut buf = Vec::new();
+        put_longstr(&mut buf, &body);
+        parse_field_table(&buf).unwrap().1
+    }
+
+    #[test]
+    fn test_equality_with_repeated_keys_is_symmetric() {
+        let repeated = decode_wire(&[("a", 1), ("a", 1)]);
+        let other = decode_wire(&[("a", 1), ("b", 2)]);
+        assert_ne!(repeated, other);
+        assert_ne!(other, repeated);
+        let single = decode_wire(&[("a", 1)]);
+        assert_eq!(repeated, single);
+        assert_eq!(single, repeated);
+        let reordered = decode_wire(&[("b", 2), ("a", 1)]);
+        assert_eq!(other, reordered);
+        assert_eq!(reordered, other);
+    }
+
+    /// A table holding `depth` tables nested in one another, counting itself.
+    fn nested(depth: usize) -> FieldTable {
+        let mut table = FieldTable::new();
+        table.insert("leaf", FieldValue::Boolean(true));
+        for _ in 1..depth {
+            let mut outer = FieldTable::new();
+            outer.insert("inner", FieldValue::FieldTable(table));
+            table = outer;
+        }
+        table
+    }
+
+    #[test]
+    fn test_tables_compare_regardless_of_order() {
+        let mut table = FieldTable::new();
+        table.insert("x-match", FieldValue::LongString(b"all".to_vec()));
+        table.insert("format", FieldValue::LongString(b"pdf".to_vec()));
+        let mut reordered = FieldTable::new();
+        reordered.insert("format", FieldValue::LongString(b"pdf".to_vec()));
+        reordered.insert("x-match", FieldValue::LongString(b"all".to_vec()));
+        assert_eq!(table, reordered);
+        reordered.insert("format", FieldValue::LongString(b"csv".to_vec()));
+        assert_ne!(table, reordered);
+        reordered.insert("x-extra", FieldValue::Boolean(true));
+        assert_ne!(FieldTable::new(), reordered);
+    }
+
+    #[test]
+    fn test_nesting_up_to_the_limit_decodes() {
+        let mut table = nested(32);
+        table.insert("list", FieldValue::FieldArray(vec![FieldValue::LongInt(7)]));
+        let mut buf = Vec::new();
+        table.encode(&mut buf);
+        assert_eq!(parse_field_table(&buf).unwrap().1, table);
+    }
+
+    #[test]
+    fn test_nesting_past_the_limit_is_rejected() {
+        let mut buf = Vec::new();
+        nested(33).encode(&mut buf);
+        assert!(matches!(parse_field_table(&buf), Err(nom::Err::Failure(_))));
+
+        // Arrays count toward the depth too, and the limit is adjustable.
+        let mut table = FieldTable::new();
+        table.insert("list", FieldValue::FieldArray(vec![FieldValue::FieldArray(vec![])]));
+        let mut buf = Vec::new();
+        table.encode(&mut buf);
+        let limits = TableLimits {
+            max_depth: 2,
+            ..Default::default()
+        };
+        assert!(parse_field_table_within(&buf, &limits).is_err());
+
+        // A header claiming an enormous table dies before it is read.
+        let mut huge = (2 * 1024 * 1024u32).to_be_bytes().to_vec();
+        huge.extend_from_slice(&[0; 16]);
+        assert!(matches!(parse_field_table(&huge), Err(nom::Err::Failure(_))));
+    }
+
+    #[test]
+    fn test_field_table_truncated() {
+        let mut buf = Vec::new();
+        let mut table = FieldTable::new();
+        table.insert("key", FieldValue::LongInt(1));
+        table.encode(&mut buf);
+        buf.truncate(buf.len() - 1);
+        assert!(parse_field_table(&buf).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+// src/histogram.rs
+
+//! Histograms of observed values, rendered as Prometheus histograms.
+//!
+//! A histogram has fixed upper bounds and counts each observation in the
+//! first bucket whose bound is at least the value, or in the `+Inf` bucket
+//! past the last one. Observing is a search over the bounds and two atomic
+//! adds, cheap enough for the publish path. Snapshots hold cumulative
+//! counts, as the exposition format wants them.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Bounds of `haymq_message_size_bytes`: powers of four from 64 bytes to
+/// 16 MiB, the default `max_message_size`.
+pub const MESSAGE_SIZE_BUCKETS: &[u64] = &[64, 256, 1024, 4096, 16384, 65536, 262144, 1048576, 4194304, 16777216];
+
+#[derive(Debug)]
+pub struct Histogram {
+    /// Upper bounds of the buckets, ascending.
+    bounds: &'static [u64],
+    /// Observations per bucket, the last one for values past every bound.
+    counts: Vec<AtomicU64>,
+    sum: AtomicU64,
+}
+
+/// What a `Histogram` held at one point.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    /// Each bound with how many observations were at most that much.
+    pub buckets: Vec<(u64, u64)>,
+    /// All observations, the `+Inf` bucket.
+    pub count: u64,
+    pub sum: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [u64]) -> Self {
+        Histogram {
+            bounds,
+            counts: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        let bucket = self.bounds.partition_point(|&bound| bound < value);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut count = 0;
+        let mut buckets = Vec::with_capacity(self.bounds.len());
+        for (bound, observed) in self.bounds.iter().zip(&self.counts) {
+            count += observed.load(Ordering::Relaxed);
+            buckets.push((*bound, count));
+        }
+        count += self.counts[self.bounds.len()].load(Ordering::Relaxed);
+        HistogramSnapshot {
+            buckets,
+            count,
+            sum: self.sum.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_observations_count_in_cumulative_buckets() {
+        let histogram = Histogram::new(&[10, 100]);
+        for value in [0, 10, 11, 100, 5000] {
+            histogram.observe(value);
+        }
+        assert_eq!(
+            histogram.snapshot(),
+            HistogramSnapshot {
+                buckets: vec![(10, 2), (100, 4)],
+                count: 5,
+                sum: 5121,
+            }
+        );
+    }
+}
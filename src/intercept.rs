@@ -0,0 +1,53 @@
+// src/intercept.rs
+
+//! Hooks run on every published message before it is routed.
+//!
+//! A broker holds an ordered chain of `Interceptor`s. Each sees the message
+//! in turn and may change it in place; the first one to drop or reject the
+//! message ends the chain and the message is never routed. Publishers in
+//! confirm mode get an ack for a dropped message and a nack for a rejected
+//! one.
+
+use async_trait::async_trait;
+
+use crate::message::Message;
+
+/// Where a message is being published from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublishCtx {
+    pub vhost: String,
+    /// User the publishing connection authenticated as.
+    pub user: Option<String>,
+    /// Connection id, as in `BrokerEvent`s.
+    pub connection: u64,
+    pub channel: u16,
+}
+
+/// What an interceptor decided about a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterceptAction {
+    /// Hand the message, as the interceptor left it, to the next
+    /// interceptor and then to routing.
+    Pass,
+    /// Discard the message as if it had been routed.
+    Drop,
+    /// Refuse the message for the given reason.
+    Reject(String),
+}
+
+#[async_trait]
+pub trait Interceptor: Send + Sync {
+    /// Inspects, and possibly modifies, a message about to be routed.
+    async fn on_publish(&self, message: &mut Message, ctx: &PublishCtx) -> InterceptAction;
+}
+
+/// An interceptor that passes every message through untouched.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopInterceptor;
+
+#[async_trait]
+impl Interceptor for NoopInterceptor {
+    async fn on_publish(&self, _message: &mut Message, _ctx: &PublishCtx) -> InterceptAction {
+        InterceptAction::Pass
+    }
+}
@@ -0,0 +1,133 @@
+// src/latency.rs
+
+//! Warnings about slow broker operations.
+//!
+//! Routing a publish to its queues, writing a delivery to a client and each
+//! message store call are timed, as are waits for the broker's state lock
+//! and for the lock of a queue when another thread holds it. One that takes
+//! longer than `Config::slow_operation_threshold` is logged as a warning
+//! naming the operation and its duration, and counted per operation for the
+//! metrics. Timing is a plain `Instant` around the operation and the warning
+//! goes through `log`, like the rest of the broker's logging.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+/// A kind of timed operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// Routing a published message and enqueueing it on its queues.
+    Route,
+    /// Writing a delivery to a client's socket.
+    Deliver,
+    /// A message store append or ack.
+    Store,
+    /// Waiting for the lock on the broker's virtual hosts.
+    StateLock,
+    /// Waiting for the lock on one queue.
+    QueueLock,
+}
+
+impl Operation {
+    pub const ALL: [Operation; 5] = [
+        Operation::Route,
+        Operation::Deliver,
+        Operation::Store,
+        Operation::StateLock,
+        Operation::QueueLock,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Route => "route",
+            Operation::Deliver => "deliver",
+            Operation::Store => "store",
+            Operation::StateLock => "state_lock",
+            Operation::QueueLock => "queue_lock",
+        }
+    }
+}
+
+/// How many operations of each kind were slow.
+#[derive(Debug, Default)]
+pub struct SlowOperations {
+    counts: [AtomicU64; 5],
+}
+
+impl SlowOperations {
+    /// Warns about and counts `operation`, begun at `started`, if it took
+    /// longer than `threshold`. A zero `threshold` turns this off.
+    pub fn check(&self, threshold: Duration, operation: Operation, started: Instant) {
+        if threshold.is_zero() {
+            return;
+        }
+        let elapsed = started.elapsed();
+        if elapsed > threshold {
+            warn!(
+                "Slow {} operation took {:?}, over the threshold of {:?}",
+                operation.as_str(),
+                elapsed,
+                threshold
+            );
+            self.counts[operation as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Locks `mutex`, timing the wait as `operation` only when it is held
+    /// elsewhere, so an uncontended lock costs no clock reads.
+    pub fn lock<'a, T>(&self, threshold: Duration, operation: Operation, mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+        if let Ok(guard) = mutex.try_lock() {
+            return guard;
+        }
+        let started = Instant::now();
+        let guard = mutex.lock().unwrap();
+        self.check(threshold, operation, started);
+        guard
+    }
+
+    pub fn count(&self, operation: Operation) -> u64 {
+        self.counts[operation as usize].load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_operations_over_the_threshold_count() {
+        let slow = SlowOperations::default();
+        let long_ago = Instant::now() - Duration::from_millis(50);
+        slow.check(Duration::from_millis(10), Operation::Store, long_ago);
+        slow.check(Duration::from_secs(10), Operation::Route, long_ago);
+        slow.check(Duration::ZERO, Operation::Deliver, long_ago);
+        let counts: Vec<u64> = Operation::ALL.iter().map(|&op| slow.count(op)).collect();
+        assert_eq!(counts, vec![0, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_lock_waits_count_only_when_contended() {
+        let slow = SlowOperations::default();
+        let threshold = Duration::from_millis(10);
+        let mutex = Mutex::new(0);
+        *slow.lock(threshold, Operation::QueueLock, &mutex) += 1;
+        assert_eq!(slow.count(Operation::QueueLock), 0);
+
+        let (locked, wait) = std::sync::mpsc::channel();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let _guard = mutex.lock().unwrap();
+                locked.send(()).unwrap();
+                std::thread::sleep(Duration::from_millis(50));
+            });
+            wait.recv().unwrap();
+            *slow.lock(threshold, Operation::QueueLock, &mutex) += 1;
+        });
+        assert_eq!(*mutex.lock().unwrap(), 2);
+        assert_eq!(slow.count(Operation::QueueLock), 1);
+        assert_eq!(slow.count(Operation::StateLock), 0);
+    }
+}
@@ -0,0 +1,39 @@
+pub mod auth;
+pub mod broker;
+pub mod channel;
+pub mod clock;
+pub mod config;
+pub mod connection;
+pub mod definitions;
+pub mod error;
+pub mod events;
+pub mod exchange;
+pub mod field_table;
+pub mod histogram;
+pub mod intercept;
+pub mod latency;
+pub mod listener;
+pub mod locale;
+pub mod management;
+pub mod message;
+pub mod metrics;
+pub mod methods;
+pub mod policy;
+pub mod properties;
+pub mod protocol;
+pub mod queue;
+pub mod rate_limit;
+pub mod registry;
+#[cfg(test)]
+mod replay;
+pub mod reply_codes;
+pub mod rewrite;
+pub mod selftest;
+pub mod store;
+pub mod vhost;
+#[cfg(test)]
+mod test_support;
+pub mod trace;
+pub mod websocket;
+pub mod wire;
+pub mod wirelog;
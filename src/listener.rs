@@ -0,0 +1,480 @@
+// src/listener.rs
+
+//! Sockets the broker accepts AMQP clients on.
+//!
+//! Each configured listener is either a TCP address, IPv4 or IPv6, or on
+//! Unix a filesystem path for a Unix domain socket. All of them hand their
+//! connections to the same `handle_connection` code; only TCP clients have
+//! a peer address to count against `max_connections_per_ip`.
+//!
+//! Every listener is a `Transport`, something that yields client streams,
+//! and `serve` runs the accept loop over any of them. An embedder can feed
+//! the broker clients from another source by implementing `Transport`;
+//! `MemoryTransport` does so with in-memory pipes, for tests and for
+//! clients living in the same process.
+//!
+//! A listener only accepts while fewer than `max_pending_handshakes`
+//! connections are in their handshake, and turns away TCP clients whose
+//! address is cooling down after repeated failed handshakes or, with
+//! `churn_limit` set, after opening and closing connections too quickly.
+//! While `Broker::pause_listeners` is on, every listener closes the
+//! sockets it accepts at once; connections already open carry on.
+//!
+//! Accepted TCP sockets, WebSocket ones included, get the `SocketOptions`
+//! of `Config::socket`: `TCP_NODELAY` is on by default, since AMQP
+//! exchanges many small frames, while keepalive and the buffer sizes keep
+//! the system defaults unless configured.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+
+use crate::broker::Broker;
+use crate::connection;
+
+/// Where a listener accepts clients.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenerConfig {
+    /// A TCP address such as `127.0.0.1:5672` or `[::1]:5672`.
+    Tcp(String),
+    /// A Unix domain socket path.
+    Unix(PathBuf),
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        ListenerConfig::Tcp("127.0.0.1:5672".into())
+    }
+}
+
+/// Options set on every accepted TCP socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// `TCP_NODELAY`: send frames at once rather than coalescing them.
+    pub nodelay: bool,
+    /// Turns on `SO_KEEPALIVE`, with probes starting after the connection
+    /// has been idle this long. `None` leaves keepalive off.
+    pub keepalive: Option<Duration>,
+    /// `SO_SNDBUF` in bytes; `None` keeps the system default.
+    pub send_buffer_size: Option<usize>,
+    /// `SO_RCVBUF` in bytes; `None` keeps the system default.
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            nodelay: true,
+            keepalive: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Sets the options on `stream`.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        let socket = SockRef::from(stream);
+        if let Some(idle) = self.keepalive {
+            socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Who is at the other end of an accepted stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Peer {
+    /// A client with a network address, which the per-address limits and
+    /// cooldowns apply to.
+    Address(SocketAddr),
+    /// A client without one, such as on a Unix domain socket, described as
+    /// it should read in the log.
+    Local(String),
+}
+
+impl Peer {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Address(addr) => Some(addr.ip()),
+            Peer::Local(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Address(addr) => write!(f, "{}", addr),
+            Peer::Local(description) => f.write_str(description),
+        }
+    }
+}
+
+/// A source of client connections for `serve`.
+#[async_trait]
+pub trait Transport: Send {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// Waits for the next client. An error ends `serve`.
+    async fn accept(&mut self) -> io::Result<(Self::Stream, Peer)>;
+}
+
+/// TCP clients of a bound `TcpListener`, each socket set up with
+/// `options` as it is accepted.
+#[derive(Debug)]
+pub struct TcpTransport {
+    listener: TcpListener,
+    options: SocketOptions,
+}
+
+impl TcpTransport {
+    pub fn new(listener: TcpListener, options: SocketOptions) -> Self {
+        TcpTransport { listener, options }
+    }
+}
+
+#[async_trait]
+impl Transport for TcpTransport {
+    type Stream = TcpStream;
+
+    async fn accept(&mut self) -> io::Result<(TcpStream, Peer)> {
+        let (socket, addr) = self.listener.accept().await?;
+        if let Err(e) = self.options.apply(&socket) {
+            warn!("Failed to set socket options for {:?}: {}", addr, e);
+        }
+        Ok((socket, Peer::Address(addr)))
+    }
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl Transport for UnixListener {
+    type Stream = UnixStream;
+
+    async fn accept(&mut self) -> io::Result<(UnixStream, Peer)> {
+        let (socket, _) = UnixListener::accept(self).await?;
+        Ok((socket, Peer::Local("a Unix socket".into())))
+    }
+}
+
+/// Clients connected through a `MemoryConnector`, each over an in-memory
+/// pipe.
+#[derive(Debug)]
+pub struct MemoryTransport {
+    incoming: mpsc::UnboundedReceiver<DuplexStream>,
+}
+
+/// Connects clients to the `MemoryTransport` it was made with.
+#[derive(Debug, Clone)]
+pub struct MemoryConnector {
+    outgoing: mpsc::UnboundedSender<DuplexStream>,
+}
+
+/// Bytes either end of a memory pipe buffers before writes wait.
+const MEMORY_PIPE_CAPACITY: usize = 64 * 1024;
+
+impl MemoryTransport {
+    /// A transport with no clients yet, and the connector that adds them.
+    /// Accepting fails once every connector has been dropped.
+    pub fn new() -> (MemoryTransport, MemoryConnector) {
+        let (outgoing, incoming) = mpsc::unbounded_channel();
+        (MemoryTransport { incoming }, MemoryConnector { outgoing })
+    }
+}
+
+impl MemoryConnector {
+    /// Opens a pipe to the transport, returning the client's end.
+    pub fn connect(&self) -> io::Result<DuplexStream> {
+        let (client, server) = tokio::io::duplex(MEMORY_PIPE_CAPACITY);
+        self.outgoing
+            .send(server)
+            .map_err(|_| io::Error::new(io::ErrorKind::ConnectionRefused, "memory transport is gone"))?;
+        Ok(client)
+    }
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    type Stream = DuplexStream;
+
+    async fn accept(&mut self) -> io::Result<(DuplexStream, Peer)> {
+        match self.incoming.recv().await {
+            Some(stream) => Ok((stream, Peer::Local("an in-memory pipe".into()))),
+            None => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "every memory connector was dropped",
+            )),
+        }
+    }
+}
+
+/// Accepts clients from `transport` until accepting fails, serving each on
+/// its own task.
+pub async fn serve<T: Transport>(mut transport: T, broker: Arc<Broker>) -> io::Result<()> {
+    loop {
+        let slot = broker.connections().reserve_handshake().await;
+        let (stream, peer) = transport.accept().await?;
+        if broker.listeners_paused() || broker.recovering() {
+            debug!("Refusing connection from {} while listeners are paused", peer);
+            continue;
+        }
+        if peer.ip().is_some_and(|ip| broker.connections().is_cooling_down(ip)) {
+            debug!("Refusing connection from {} during its cooldown", peer);
+            continue;
+        }
+        info!("New connection from {}", peer);
+        let broker = broker.clone();
+        tokio::spawn(async move {
+            if let Err(e) = connection::handle_accepted_connection(stream, peer.ip(), slot, broker).await {
+                e.log(&peer.to_string());
+            }
+        });
+    }
+}
+
+/// A bound listener.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Binds the socket `config` describes. A Unix socket left behind by an
+    /// earlier run is replaced; any other file at the path is an error.
+    pub async fn bind(config: &ListenerConfig) -> io::Result<Listener> {
+        match config {
+            ListenerConfig::Tcp(address) => Ok(Listener::Tcp(TcpListener::bind(address).await?)),
+            #[cfg(unix)]
+            ListenerConfig::Unix(path) => {
+                remove_stale_socket(path)?;
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            }
+            #[cfg(not(unix))]
+            ListenerConfig::Unix(path) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "cannot listen on {}: Unix domain sockets are not supported here",
+                    path.display()
+                ),
+            )),
+        }
+    }
+
+    /// Accepts clients until accepting fails, serving each on its own task.
+    pub async fn serve(self, broker: Arc<Broker>) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => {
+                let options = broker.config().socket.clone();
+                serve(TcpTransport::new(listener, options), broker).await
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => serve(listener, broker).await,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use crate::config::Config;
+    use crate::methods::Method;
+    use crate::test_support::TestClient;
+
+    #[tokio::test]
+    async fn test_handshake_over_unix_socket() {
+        let dir = std::env::temp_dir().join(format!("haymq-listener-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("amqp.sock");
+        // A socket file left over from an earlier run is taken over.
+        drop(std::os::unix::net::UnixListener::bind(&path));
+        let config = ListenerConfig::Unix(path.clone());
+        let broker = Arc::new(Broker::new(Config::default()));
+        let listener = Listener::bind(&config).await.unwrap();
+        tokio::spawn(listener.serve(broker.clone()));
+
+        let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let mut client = TestClient::from_stream(broker, stream, tokio::spawn(async {}));
+        client.send_raw(b"AMQP\x00\x00\x09\x01").await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client
+            .send_method(
+                1,
+                &Method::ChannelClose {
+                    reply_code: 200,
+                    reply_text: "bye".into(),
+                    class_id: 0,
+                    method_id: 0,
+                },
+            )
+            .await;
+        assert_eq!(client.recv_method().await, (1, Method::ChannelCloseOk));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_half_open_connections_hold_back_accepts() {
+        let broker = Arc::new(Broker::new(Config {
+            max_pending_handshakes: 2,
+            ..Default::default()
+        }));
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        tokio::spawn(Listener::Tcp(tcp).serve(broker.clone()));
+
+        // Two clients connect and never send the protocol header.
+        let half_open = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let _other = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut client = TestClient::from_stream(broker, stream, tokio::spawn(async {}));
+        client.send_raw(b"AMQP\x00\x00\x09\x01").await;
+        assert!(client.try_recv_frame(Duration::from_millis(100)).await.is_none());
+
+        drop(half_open);
+        client.handshake().await;
+    }
+
+    #[tokio::test]
+    async fn test_connection_churn_gets_an_address_refused() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let broker = Arc::new(Broker::new(Config {
+            churn_limit: 5,
+            churn_cooldown: Duration::from_secs(60),
+            handshake_failure_limit: 0,
+            ..Default::default()
+        }));
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        tokio::spawn(Listener::Tcp(tcp).serve(broker));
+
+        // Connect, wait for Connection.Start and hang up, until the broker
+        // stops answering.
+        let mut answered = 0;
+        loop {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let mut buf = [0u8; 1];
+            let exchange = async {
+                stream.write_all(b"AMQP\x00\x00\x09\x01").await?;
+                stream.read(&mut buf).await
+            };
+            let read = tokio::time::timeout(Duration::from_secs(5), exchange).await;
+            if !matches!(read, Ok(Ok(1))) {
+                break;
+            }
+            answered += 1;
+            assert!(answered < 50, "churning address was never refused");
+        }
+        assert!(answered >= 5);
+    }
+
+    #[tokio::test]
+    async fn test_socket_options_are_applied() {
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(tcp.local_addr().unwrap()).await.unwrap();
+        let (accepted, _) = tcp.accept().await.unwrap();
+        let options = SocketOptions {
+            keepalive: Some(Duration::from_secs(30)),
+            send_buffer_size: Some(64 * 1024),
+            recv_buffer_size: Some(32 * 1024),
+            ..Default::default()
+        };
+        options.apply(&accepted).unwrap();
+        let socket = SockRef::from(&accepted);
+        assert!(accepted.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        // The kernel may round the buffer sizes up, as Linux doubles them.
+        assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+        assert!(socket.recv_buffer_size().unwrap() >= 32 * 1024);
+
+        SocketOptions {
+            nodelay: false,
+            ..Default::default()
+        }
+        .apply(&accepted)
+        .unwrap();
+        assert!(!accepted.nodelay().unwrap());
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn test_publish_and_consume_over_a_memory_transport() {
+        let broker = Arc::new(Broker::new(Config::default()));
+        let (transport, connector) = MemoryTransport::new();
+        tokio::spawn(serve(transport, broker.clone()));
+
+        let stream = connector.connect().unwrap();
+        let mut client = TestClient::from_stream(broker, stream, tokio::spawn(async {}));
+        client.send_raw(b"AMQP\x00\x00\x09\x01").await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        let declare = Method::QueueDeclare {
+            queue: "jobs".into(),
+            passive: false,
+            durable: false,
+            exclusive: false,
+            auto_delete: false,
+            nowait: false,
+            arguments: Default::default(),
+        };
+        client.send_method(1, &declare).await;
+        assert!(matches!(client.recv_method().await, (1, Method::QueueDeclareOk { .. })));
+        client.consume(1, "jobs", true).await;
+        client.publish(1, "", "jobs", Default::default(), b"in memory").await;
+        let (_, body) = client.recv_delivery().await;
+        assert_eq!(body, b"in memory");
+    }
+
+    #[tokio::test]
+    async fn test_unix_listener_keeps_other_files() {
+        let dir = std::env::temp_dir().join(format!("haymq-listener-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("not-a-socket");
+        std::fs::write(&path, b"data").unwrap();
+        let err = Listener::bind(&ListenerConfig::Unix(path.clone())).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
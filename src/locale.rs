@@ -0,0 +1,91 @@
+// src/locale.rs
+
+//! Locales the broker offers in `Connection.Start` and the catalog of
+//! reply texts it sends in each.
+//!
+//! A reply text is looked up by key and has its `{}` placeholders filled
+//! in order. Keys missing from a locale fall back to `DEFAULT_LOCALE`. The
+//! reply code name at the start of each text stays untranslated, since
+//! clients match on it.
+
+/// Locale used until the client picks one, and for keys other locales lack.
+pub const DEFAULT_LOCALE: &str = "en_US";
+/// Locales advertised in `Connection.Start`, default first.
+pub const LOCALES: &[&str] = &[DEFAULT_LOCALE, "de_DE"];
+
+/// The catalog: the template of reply text `key` in `locale`, if any.
+fn template(locale: &str, key: &str) -> Option<&'static str> {
+    Some(match (locale, key) {
+        ("en_US", "locale_unsupported") => "NOT_ALLOWED - locale '{}' is not supported",
+        ("en_US", "mechanism_unsupported") => "ACCESS_REFUSED - mechanism '{}' is not supported",
+        ("en_US", "mechanism_not_allowed") => "NOT_ALLOWED - mechanism '{}' is not allowed",
+        ("en_US", "user_connection_limit") => "CONNECTION_FORCED - too many connections for user '{}'",
+        ("en_US", "frame_max_too_small") => "NOT_ALLOWED - frame_max {} is below the minimum of {}",
+        ("en_US", "vhost_not_found") => "NOT_ALLOWED - vhost '{}' not found",
+        ("en_US", "vhost_invalid") => "NOT_ALLOWED - invalid vhost name '{}'",
+        ("en_US", "vhost_access_refused") => "ACCESS_REFUSED - access to vhost '{}' refused for user '{}'",
+        ("en_US", "channel_close_unconfirmed") => "channel {} did not confirm Channel.Close",
+        ("de_DE", "mechanism_unsupported") => "ACCESS_REFUSED - Mechanismus '{}' wird nicht unterstützt",
+        ("de_DE", "mechanism_not_allowed") => "NOT_ALLOWED - Mechanismus '{}' ist nicht erlaubt",
+        ("de_DE", "user_connection_limit") => "CONNECTION_FORCED - zu viele Verbindungen für Benutzer '{}'",
+        ("de_DE", "frame_max_too_small") => "NOT_ALLOWED - frame_max {} liegt unter dem Minimum von {}",
+        ("de_DE", "vhost_not_found") => "NOT_ALLOWED - virtueller Host '{}' nicht gefunden",
+        ("de_DE", "vhost_invalid") => "NOT_ALLOWED - ungültiger Name eines virtuellen Hosts '{}'",
+        ("de_DE", "vhost_access_refused") => {
+            "ACCESS_REFUSED - Zugriff auf virtuellen Host '{}' für Benutzer '{}' verweigert"
+        }
+        _ => return None,
+    })
+}
+
+/// Whether `locale` is one of `LOCALES`.
+pub fn is_supported(locale: &str) -> bool {
+    LOCALES.contains(&locale)
+}
+
+/// The `locales` field of `Connection.Start`: `LOCALES`, space-separated.
+pub fn advertised() -> Vec<u8> {
+    LOCALES.join(" ").into_bytes()
+}
+
+/// The reply text `key` in `locale`, with `args` filling its placeholders.
+///
+/// # Panics
+///
+/// If `key` is missing from `DEFAULT_LOCALE` too.
+pub fn text(locale: &str, key: &str, args: &[&dyn std::fmt::Display]) -> String {
+    let Some(template) = template(locale, key).or_else(|| template(DEFAULT_LOCALE, key)) else {
+        panic!("no reply text '{}'", key);
+    };
+    let mut parts = template.split("{}");
+    let mut out = parts.next().unwrap_or_default().to_string();
+    for (i, part) in parts.enumerate() {
+        if let Some(arg) = args.get(i) {
+            out.push_str(&arg.to_string());
+        }
+        out.push_str(part);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_falls_back_to_default_locale() {
+        assert_eq!(
+            text("de_DE", "vhost_not_found", &[&"staging"]),
+            "NOT_ALLOWED - virtueller Host 'staging' nicht gefunden"
+        );
+        assert_eq!(
+            text("de_DE", "channel_close_unconfirmed", &[&3]),
+            "channel 3 did not confirm Channel.Close"
+        );
+        assert_eq!(
+            text("en_US", "frame_max_too_small", &[&1024, &4096]),
+            "NOT_ALLOWED - frame_max 1024 is below the minimum of 4096"
+        );
+        assert_eq!(advertised(), b"en_US de_DE");
+    }
+}
@@ -1,25 +1,136 @@
-mod connection;
-mod protocol;
+use std::sync::Arc;
+use std::time::Duration;
 
 use tokio::net::TcpListener;
-use log::info;
-use env_logger;
+use tokio::task::JoinSet;
+use log::{info, warn};
+
+use haymq::broker::{self, Broker};
+use haymq::config::Config;
+use haymq::listener::Listener;
+use haymq::management;
+use haymq::selftest;
+use haymq::store::{FileMessageStore, Storage};
+use haymq::websocket;
+use haymq::wirelog;
+
+/// How often queues declared with `x-expires` are checked for expiry.
+const QUEUE_EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+/// How often a failing message store is retried while the disk alarm is raised.
+const STORE_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// How often consumers are checked against the slow-consumer threshold.
+const SLOW_CONSUMER_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How often a shutdown checks whether every connection has closed.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init(); // Initialize logger
 
-    let listener = TcpListener::bind("127.0.0.1:5672").await?;
-    info!("AMQP service listening on 127.0.0.1:5672");
-
-    loop {
-        let (socket, addr) = listener.accept().await?;
-        info!("New connection from {:?}", addr);
+    let args: Vec<String> = std::env::args().collect();
+    // `haymq decode LOG` prints a trace's wire log as hex; `haymq encode
+    // HEX LOG` turns the hex form back into a wire log.
+    match args.get(1).map(String::as_str) {
+        Some("decode") if args.len() == 3 => {
+            print!("{}", wirelog::to_hex(&std::fs::read(&args[2])?)?);
+            return Ok(());
+        }
+        Some("encode") if args.len() == 4 => {
+            std::fs::write(&args[3], wirelog::from_hex(&std::fs::read_to_string(&args[2])?)?)?;
+            return Ok(());
+        }
+        Some("decode" | "encode") => return Err("usage: haymq decode LOG | haymq encode HEX LOG".into()),
+        _ => {}
+    }
 
+    let config = match args.get(1) {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let topology = config.topology.clone();
+    let broker = match config.store.clone() {
+        _ if config.storage == Storage::Memory => {
+            warn!("Storage is in memory only: durable queues and persistent messages will not survive a restart");
+            Arc::new(Broker::new(config))
+        }
+        Some(store_config) => {
+            let interval = store_config.compaction_interval;
+            let store = FileMessageStore::open(store_config)?;
+            let broker = Arc::new(Broker::with_store(config, Box::new(store)));
+            broker::spawn_compaction(&broker, interval);
+            broker::spawn_store_retry(&broker, STORE_RETRY_INTERVAL);
+            broker::spawn_topology_persistence(&broker);
+            broker
+        }
+        None => Arc::new(Broker::new(config)),
+    };
+    if broker.config().event_log_size > 0 {
+        broker::spawn_event_log(&broker);
+    }
+    broker.recover(&topology)?;
+    if broker.mechanisms().is_empty() {
+        warn!("No SASL mechanism is both supported and allowed by sasl_mechanisms: no client can log in");
+    }
+    if broker.config().self_test {
+        // The self-test client logs in with PLAIN.
+        if broker.allows_mechanism("PLAIN") {
+            selftest::run(broker.clone()).await?;
+            info!("Startup self-test passed");
+        } else {
+            warn!("Skipping the startup self-test: sasl_mechanisms does not allow PLAIN");
+        }
+    }
+    broker::spawn_queue_expiry(&broker, QUEUE_EXPIRY_INTERVAL);
+    if !broker.config().rate_sample_interval.is_zero() {
+        broker::spawn_rate_sampling(&broker, broker.config().rate_sample_interval);
+    }
+    if !broker.config().slow_consumer_threshold.is_zero() {
+        broker::spawn_slow_consumer_check(&broker, SLOW_CONSUMER_CHECK_INTERVAL);
+    }
+    if let Some(ws) = broker.config().websocket.clone() {
+        let listener = TcpListener::bind(&ws.bind).await?;
+        info!("AMQP over WebSocket listening on ws://{}{}", ws.bind, ws.path);
+        let broker = broker.clone();
         tokio::spawn(async move {
-            if let Err(e) = connection::handle_connection(socket).await {
-                eprintln!("Error handling connection from {:?}: {:?}", addr, e);
+            if let Err(e) = websocket::serve(listener, ws.path, broker.clone()).await {
+                broker.record_listener_failure(&format!("ws://{}", ws.bind), &e);
             }
         });
     }
+    if let Some(management) = broker.config().management.clone() {
+        let listener = TcpListener::bind(&management.bind).await?;
+        info!("Management API listening on http://{}", management.bind);
+        let broker = broker.clone();
+        tokio::spawn(async move {
+            if let Err(e) = management::serve(listener, broker.clone()).await {
+                broker.record_listener_failure(&format!("http://{}", management.bind), &e);
+            }
+        });
+    }
+
+    let mut listeners = JoinSet::new();
+    for config in &broker.config().listeners {
+        let listener = Listener::bind(config).await?;
+        info!("AMQP service listening on {:?}", config);
+        listeners.spawn(listener.serve(broker.clone()));
+    }
+    // Serve until a listener fails or the broker is interrupted.
+    loop {
+        tokio::select! {
+            served = listeners.join_next() => match served {
+                Some(served) => served??,
+                None => return Ok(()),
+            },
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+    broker.shut_down();
+    // Connections resolve their confirms and wait for the client's
+    // Close-Ok for at most the close timeout.
+    let deadline = tokio::time::Instant::now() + broker.config().connection_close_timeout + Duration::from_secs(1);
+    while !broker.connections().open_connections().is_empty() && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+    }
+    info!("Shut down");
+    Ok(())
 }
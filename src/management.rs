@@ -0,0 +1,2551 @@
+// src/management.rs
+
+//! HTTP management API.
+//!
+//! A deliberately small HTTP/1.1 server: one request per connection, JSON
+//! responses. Virtual host names are percent-encoded in paths, so the
+//! default virtual host is `/api/vhosts/%2F`.
+//!
+//! - `GET /api/health` sums up operational health: the disk, memory,
+//!   vhost quota, handshake and listener alarms, connection and message
+//!   counts, whether listeners are paused or startup recovery is still
+//!   running, and an overall `green`, `yellow` or `red` status. It
+//!   answers 200 whatever the status, so it reflects health, not whether
+//!   the process is up.
+//! - `POST /api/listeners/pause` makes every listener close new sockets
+//!   as it accepts them, for maintenance; open connections keep working.
+//!   `POST /api/listeners/resume` accepts connections again.
+//! - `GET /api/vhosts` lists virtual hosts with how many queues,
+//!   exchanges and bindings each has, and the most it may have.
+//! - `POST /api/vhosts/{name}/drain` stops message flow in a virtual host.
+//! - `POST /api/vhosts/{name}/resume` starts it again.
+//! - `GET /api/queues` lists queues with their message and consumer
+//!   counts, consumer utilization and placement hints, and when each was
+//!   declared and last active in `created_at_ms` and `last_active_ms`. A
+//!   queue is active when it is declared, consumed from, published to,
+//!   delivers or answers a `Basic.Get`.
+//! - `POST /api/queues/purge` with a body like
+//!   `{"vhost": "/", "pattern": "deploy-*"}` removes the ready messages of
+//!   every queue whose name matches the glob pattern, where `*` matches any
+//!   run of characters and `?` any one character. Unacked deliveries stay.
+//!   With users in a `UserStore` the caller needs configure and write
+//!   permission on every matching queue, or nothing is purged.
+//! - `GET /api/queues/{vhost}/{name}/messages?count=N&peek=true` shows up
+//!   to N messages at the head of a queue without removing them, with how
+//!   long each has been queued and how often it was delivered.
+//! - `GET /api/queues/{vhost}/{name}/rates?window=S` reports the messages
+//!   per second a queue took in, delivered and saw acked over the last S
+//!   seconds, or over every sample kept, along with the rate between each
+//!   two samples. Rates come from totals sampled every
+//!   `rate_sample_interval`; the totals since the queue was declared are
+//!   included.
+//! - `GET /api/exchanges` lists exchanges with their type and policy, and
+//!   when each was declared and last declared or published to, likewise.
+//! - `GET /api/exchanges/{vhost}/{name}/route?routing_key=K&headers=H`
+//!   names the queues a message published to the exchange with routing key
+//!   K, and optionally the headers of the JSON object H, would be routed to.
+//!   Nothing is published.
+//! - `GET /api/connections` lists open connections with the client
+//!   properties each sent and how many frames and heartbeats it received
+//!   and heartbeats it sent, and in `partial_frame_bytes` how much of a
+//!   frame it has buffered while waiting for the rest; a count that stays
+//!   high points at a slow or stuck sender. Each is named by the
+//!   `connection_name` of its client properties, or else by the client's
+//!   address. `GET /api/connections/{id}` shows one, along with what its
+//!   handshake negotiated: frame and channel limits, heartbeat, SASL
+//!   mechanism and locale.
+//! - `GET /api/events?limit=N` returns the N most recent broker events,
+//!   oldest first, each with its `type` and when it happened in
+//!   `timestamp_ms`: connections and channels opening, closing and failing
+//!   with exceptions, declares and deletes, bindings, the disk alarm and
+//!   the routes of messages published with an `x-trace` header.
+//!   The broker keeps `event_log_size` of them; N defaults to all.
+//! - `POST /api/connections/{id}/trace?seconds=N` starts capturing the
+//!   frames of one connection for N seconds; `GET` on the same path returns
+//!   what was captured, each frame base64-encoded, or with
+//!   `?format=wirelog` the whole capture as one base64-encoded wire log.
+//! - `GET /api/definitions` exports the topology, users and permissions as
+//!   a definitions document; `POST` on the same path imports one and
+//!   reports, per resource, whether it was created, already there, or
+//!   failed. Both need an administrator.
+//! - `GET /api/policies` lists the policies of every virtual host.
+//!   `PUT /api/policies/{vhost}/{name}` with a body like
+//!   `{"pattern": "jobs.*", "apply-to": "queues", "priority": 1,
+//!   "definition": {"x-max-length": 1000}}` creates or replaces a policy
+//!   and applies it at once; `GET` on the same path shows it and `DELETE`
+//!   removes it. `apply-to` defaults to `all` and `priority` to 0.
+//! - `PUT /api/users/{name}` with a body like
+//!   `{"password": "secret", "tags": ["administrator"]}` creates or
+//!   replaces a user; `DELETE` on the same path removes it.
+//! - `PUT /api/permissions/{vhost}/{user}` with a body like
+//!   `{"configure": "*", "write": "*", "read": "orders.*"}` sets what a user
+//!   may do in a virtual host.
+//!
+//! With the broker's users in a `UserStore`, every request but a `GET`
+//! needs HTTP Basic credentials of a user tagged `administrator`; others
+//! are refused with 401, or 403 for a user without the tag. A broker that
+//! takes clients at their word, with no users to check, does the same for
+//! the API. The user and permission endpoints need a `UserStore` to change.
+//! Changes apply to authentications from then on; open connections keep
+//! their session.
+
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use log::{info, warn};
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use crate::auth::{PasswordHash, Permission, User, UserStore, ADMIN_TAG};
+use crate::broker::{Broker, ExchangeSummary, HealthSummary, QueueSummary, VHostSummary};
+use crate::definitions::{self, ImportOutcome};
+use crate::events::{BrokerEvent, RecordedEvent};
+use crate::field_table::{FieldTable, FieldValue};
+use crate::policy::{ApplyTo, Policy};
+use crate::properties::BasicProperties;
+use crate::queue::{QueuedMessage, Rates};
+use crate::registry::ConnectionInfo;
+use crate::rewrite::glob_matches;
+use crate::trace::{FrameTrace, MAX_TRACE_DURATION};
+use crate::vhost::percent_decode;
+
+/// Largest request head (request line and headers) the server accepts.
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+/// Largest request body the server accepts.
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+/// Most messages one peek returns.
+const MAX_PEEK_COUNT: usize = 100;
+/// Bytes of each message body included in a peek.
+const PEEK_PREVIEW_BYTES: usize = 4096;
+/// How long a trace runs when the request does not say.
+const DEFAULT_TRACE_SECONDS: u64 = 60;
+
+/// Settings for the optional management listener.
+#[derive(Debug, Clone)]
+pub struct ManagementConfig {
+    /// Address the listener binds to.
+    pub bind: String,
+}
+
+impl Default for ManagementConfig {
+    fn default() -> Self {
+        ManagementConfig {
+            bind: "127.0.0.1:15673".into(),
+        }
+    }
+}
+
+/// A parsed management request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// The query string, without the leading `?`.
+    pub query: String,
+    /// User name and password from an HTTP Basic `Authorization` header.
+    pub credentials: Option<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Returns the decoded value of query parameter `name`.
+    fn param(&self, name: &str) -> Option<String> {
+        self.query
+            .split('&')
+            .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+            .find(|(key, _)| *key == name)
+            .and_then(|(_, value)| percent_decode(value))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    /// JSON document sent as the response body.
+    pub body: String,
+}
+
+impl Response {
+    fn ok(body: serde_json::Value) -> Self {
+        Response {
+            status: 200,
+            body: body.to_string(),
+        }
+    }
+
+    fn error(status: u16, error: &str, reason: String) -> Self {
+        Response {
+            status,
+            body: json!({ "error": error, "reason": reason }).to_string(),
+        }
+    }
+
+    fn not_found(path: &str) -> Self {
+        Response::error(404, "not_found", format!("no endpoint at {}", path))
+    }
+}
+
+/// Serves one management request against `broker`.
+pub fn handle(broker: &Broker, request: &Request) -> Response {
+    // The definitions carry the users' password hashes.
+    if request.method != "GET" || request.path == "/api/definitions" {
+        if let Some(users) = broker.auth().users() {
+            if let Err(response) = authenticate_admin(users, request) {
+                return response;
+            }
+        }
+    }
+    let segments: Vec<&str> = request.path.trim_start_matches('/').split('/').collect();
+    match segments[..] {
+        ["api", "health"] => {
+            if request.method != "GET" {
+                return Response::error(405, "method_not_allowed", format!("use GET for {}", request.path));
+            }
+            Response::ok(health_json(&broker.health()))
+        }
+        ["api", "listeners", action @ ("pause" | "resume")] => {
+            if request.method != "POST" {
+                return Response::error(405, "method_not_allowed", format!("use POST for {}", request.path));
+            }
+            broker.pause_listeners(action == "pause");
+            Response::ok(json!({ "paused": broker.listeners_paused() }))
+        }
+        ["api", "vhosts"] => {
+            if request.method != "GET" {
+                return Response::error(405, "method_not_allowed", format!("use GET for {}", request.path));
+            }
+            Response::ok(Value::Array(broker.vhost_summaries().iter().map(vhost_json).collect()))
+        }
+        ["api", "vhosts", name, action @ ("drain" | "resume")] => {
+            if request.method != "POST" {
+                return Response::error(405, "method_not_allowed", format!("use POST for {}", request.path));
+            }
+            let Some(vhost) = percent_decode(name) else {
+                return Response::error(400, "bad_request", format!("invalid vhost name '{}'", name));
+            };
+            let drain = action == "drain";
+            let found = if drain {
+                broker.drain_vhost(&vhost)
+            } else {
+                broker.resume_vhost(&vhost)
+            };
+            if !found {
+                return Response::error(404, "not_found", format!("no vhost '{}'", vhost));
+            }
+            Response::ok(json!({ "vhost": vhost, "drained": drain }))
+        }
+        ["api", "queues"] => {
+            if request.method != "GET" {
+                return Response::error(405, "method_not_allowed", format!("use GET for {}", request.path));
+            }
+            Response::ok(Value::Array(broker.queue_summaries().iter().map(queue_json).collect()))
+        }
+        ["api", "queues", "purge"] => {
+            if request.method != "POST" {
+                return Response::error(405, "method_not_allowed", format!("use POST for {}", request.path));
+            }
+            purge_queues(broker, request)
+        }
+        ["api", "queues", vhost, name, "messages"] => {
+            if request.method != "GET" {
+                return Response::error(405, "method_not_allowed", format!("use GET for {}", request.path));
+            }
+            let (Some(vhost), Some(name)) = (percent_decode(vhost), percent_decode(name)) else {
+                return Response::error(400, "bad_request", format!("invalid queue path {}", request.path));
+            };
+            peek_messages(broker, request, &vhost, &name)
+        }
+        ["api", "queues", vhost, name, "rates"] => {
+            if request.method != "GET" {
+                return Response::error(405, "method_not_allowed", format!("use GET for {}", request.path));
+            }
+            let (Some(vhost), Some(name)) = (percent_decode(vhost), percent_decode(name)) else {
+                return Response::error(400, "bad_request", format!("invalid queue path {}", request.path));
+            };
+            queue_rates(broker, request, &vhost, &name)
+        }
+        ["api", "exchanges"] => {
+            if request.method != "GET" {
+                return Response::error(405, "method_not_allowed", format!("use GET for {}", request.path));
+            }
+            Response::ok(Value::Array(
+                broker.exchange_summaries().iter().map(exchange_json).collect(),
+            ))
+        }
+        ["api", "exchanges", vhost, name, "route"] => {
+            if request.method != "GET" {
+                return Response::error(405, "method_not_allowed", format!("use GET for {}", request.path));
+            }
+            let (Some(vhost), Some(name)) = (percent_decode(vhost), percent_decode(name)) else {
+                return Response::error(400, "bad_request", format!("invalid exchange path {}", request.path));
+            };
+            simulate_route(broker, request, &vhost, &name)
+        }
+        ["api", "connections"] => {
+            if request.method != "GET" {
+                return Response::error(405, "method_not_allowed", format!("use GET for {}", request.path));
+            }
+            let connections = broker.connections().open_connections();
+            Response::ok(Value::Array(connections.iter().map(connection_json).collect()))
+        }
+        ["api", "events"] => {
+            if request.method != "GET" {
+                return Response::error(405, "method_not_allowed", format!("use GET for {}", request.path));
+            }
+            let limit = match request.param("limit") {
+                None => usize::MAX,
+                Some(limit) => match limit.parse::<usize>() {
+                    Ok(limit) => limit,
+                    Err(_) => return Response::error(400, "bad_request", format!("invalid limit '{}'", limit)),
+                },
+            };
+            Response::ok(Value::Array(
+                broker.recent_events(limit).iter().map(event_json).collect(),
+            ))
+        }
+        ["api", "connections", id] => {
+            if request.method != "GET" {
+                return Response::error(405, "method_not_allowed", format!("use GET for {}", request.path));
+            }
+            let Ok(id) = id.parse::<u64>() else {
+                return Response::error(400, "bad_request", format!("invalid connection id '{}'", id));
+            };
+            match broker.connections().connection(id) {
+                Some(connection) => Response::ok(connection_json(&connection)),
+                None => Response::error(404, "not_found", format!("no connection {}", id)),
+            }
+        }
+        ["api", "connections", id, "trace"] => {
+            let Ok(id) = id.parse::<u64>() else {
+                return Response::error(400, "bad_request", format!("invalid connection id '{}'", id));
+            };
+            let Some(trace) = broker.connections().trace(id) else {
+                return Response::error(404, "not_found", format!("no connection {}", id));
+            };
+            match request.method.as_str() {
+                "POST" => start_trace(request, id, &trace),
+                "GET" => match request.param("format").as_deref() {
+                    None | Some("json") => Response::ok(trace_json(&trace)),
+                    Some("wirelog") => Response::ok(json!({
+                        "active": trace.is_active(),
+                        "wirelog": base64::engine::general_purpose::STANDARD.encode(trace.wire_log()),
+                    })),
+                    Some(other) => Response::error(400, "bad_request", format!("unknown trace format '{}'", other)),
+                },
+                _ => Response::error(
+                    405,
+                    "method_not_allowed",
+                    format!("use GET or POST for {}", request.path),
+                ),
+            }
+        }
+        ["api", "definitions"] => match request.method.as_str() {
+            "GET" => Response::ok(definitions::to_json(&broker.definitions())),
+            "POST" => import_definitions(broker, request),
+            _ => Response::error(
+                405,
+                "method_not_allowed",
+                format!("use GET or POST for {}", request.path),
+            ),
+        },
+        ["api", "policies"] => {
+            if request.method != "GET" {
+                return Response::error(405, "method_not_allowed", format!("use GET for {}", request.path));
+            }
+            let policies = broker.policies();
+            Response::ok(Value::Array(
+                policies
+                    .iter()
+                    .map(|(vhost, policy)| policy_json(vhost, policy))
+                    .collect(),
+            ))
+        }
+        ["api", "policies", vhost, name] => {
+            let (Some(vhost), Some(name)) = (percent_decode(vhost), percent_decode(name)) else {
+                return Response::error(400, "bad_request", format!("invalid policy path {}", request.path));
+            };
+            match request.method.as_str() {
+                "GET" => match broker
+                    .policies()
+                    .iter()
+                    .find(|(v, policy)| *v == vhost && policy.name == name)
+                {
+                    Some((vhost, policy)) => Response::ok(policy_json(vhost, policy)),
+                    None => Response::error(404, "not_found", format!("no policy '{}' in vhost '{}'", name, vhost)),
+                },
+                "PUT" => put_policy(broker, request, &vhost, &name),
+                "DELETE" => {
+                    if !broker.delete_policy(&vhost, &name) {
+                        return Response::error(404, "not_found", format!("no policy '{}' in vhost '{}'", name, vhost));
+                    }
+                    info!("Deleted policy '{}' in vhost '{}'", name, vhost);
+                    Response::ok(json!({ "vhost": vhost, "policy": name, "deleted": true }))
+                }
+                _ => Response::error(
+                    405,
+                    "method_not_allowed",
+                    format!("use GET, PUT or DELETE for {}", request.path),
+                ),
+            }
+        }
+        ["api", "users", name] => {
+            let Some(name) = percent_decode(name) else {
+                return Response::error(400, "bad_request", format!("invalid user name '{}'", name));
+            };
+            let users = match admin_users(broker, request) {
+                Ok(users) => users,
+                Err(response) => return response,
+            };
+            match request.method.as_str() {
+                "PUT" => put_user(users, request, &name),
+                "DELETE" => {
+                    if !users.delete_user(&name) {
+                        return Response::error(404, "not_found", format!("no user '{}'", name));
+                    }
+                    info!("Deleted user '{}'", name);
+                    Response::ok(json!({ "user": name, "deleted": true }))
+                }
+                _ => Response::error(
+                    405,
+                    "method_not_allowed",
+                    format!("use PUT or DELETE for {}", request.path),
+                ),
+            }
+        }
+        ["api", "permissions", vhost, user] => {
+            if request.method != "PUT" {
+                return Response::error(405, "method_not_allowed", format!("use PUT for {}", request.path));
+            }
+            let (Some(vhost), Some(user)) = (percent_decode(vhost), percent_decode(user)) else {
+                return Response::error(400, "bad_request", format!("invalid permission path {}", request.path));
+            };
+            match admin_users(broker, request) {
+                Ok(users) => put_permission(broker, users, request, &vhost, &user),
+                Err(response) => response,
+            }
+        }
+        _ => Response::not_found(&request.path),
+    }
+}
+
+fn health_json(health: &HealthSummary) -> Value {
+    let alarms: Vec<Value> = health
+        .alarms
+        .iter()
+        .map(|alarm| {
+            json!({
+                "name": alarm.name,
+                "status": alarm.status.as_str(),
+                "detail": alarm.detail,
+            })
+        })
+        .collect();
+    json!({
+        "status": health.status.as_str(),
+        "alarms": alarms,
+        "connections": health.connections,
+        "pending_handshakes": health.pending_handshakes.map(|(pending, _)| pending),
+        "max_pending_handshakes": health.pending_handshakes.map(|(_, max)| max),
+        "listeners_paused": health.listeners_paused,
+        "recovering": health.recovering,
+        "messages": health.messages,
+        "message_bytes": health.message_bytes,
+    })
+}
+
+fn vhost_json(vhost: &VHostSummary) -> Value {
+    json!({
+        "name": vhost.name,
+        "queues": vhost.queues,
+        "exchanges": vhost.exchanges,
+        "bindings": vhost.bindings,
+        "limits": {
+            "max_queues": vhost.max_queues,
+            "max_exchanges": vhost.max_exchanges,
+            "max_bindings": vhost.max_bindings,
+        },
+    })
+}
+
+fn queue_json(queue: &QueueSummary) -> Value {
+    json!({
+        "vhost": queue.vhost,
+        "name": queue.name,
+        "messages_ready": queue.messages,
+        "messages_unacknowledged": queue.unacked,
+        "messages": queue.total,
+        "consumers": queue.consumers,
+        "consumer_utilization": queue.consumer_utilization,
+        "master_locator": queue.master_locator,
+        "leader_locator": queue.leader_locator,
+        "policy": queue.policy,
+        "created_at_ms": queue.created_at.as_millis() as u64,
+        "last_active_ms": queue.last_active.as_millis() as u64,
+    })
+}
+
+fn exchange_json(exchange: &ExchangeSummary) -> Value {
+    json!({
+        "vhost": exchange.vhost,
+        "name": exchange.name,
+        "type": exchange.kind.as_str(),
+        "durable": exchange.durable,
+        "builtin": exchange.builtin,
+        "policy": exchange.policy,
+        "created_at_ms": exchange.created_at.as_millis() as u64,
+        "last_active_ms": exchange.last_active.as_millis() as u64,
+    })
+}
+
+fn connection_json(connection: &ConnectionInfo) -> Value {
+    json!({
+        "id": connection.id,
+        "user": connection.user,
+        "vhost": connection.vhost,
+        "name": connection.name.clone().or_else(|| connection.peer.map(|ip| ip.to_string())),
+        "connection_name": connection.name,
+        "peer_host": connection.peer.map(|ip| ip.to_string()),
+        "client_properties": table_json(&connection.client_properties),
+        "capabilities": connection.capabilities,
+        "frame_max": connection.negotiated.frame_max,
+        "channel_max": connection.negotiated.channel_max,
+        "heartbeat": connection.negotiated.heartbeat,
+        "auth_mechanism": connection.negotiated.mechanism,
+        "locale": connection.negotiated.locale,
+        "frames_received": connection.frames.received,
+        "heartbeats_received": connection.frames.heartbeats_received,
+        "heartbeats_sent": connection.frames.heartbeats_sent,
+        "partial_frame_bytes": connection.frames.partial_bytes,
+        "channels": connection.channels,
+    })
+}
+
+fn event_json(recorded: &RecordedEvent) -> Value {
+    let mut event = match &recorded.event {
+        BrokerEvent::ConnectionOpened {
+            connection,
+            user,
+            vhost,
+        } => json!({
+            "type": "connection_opened",
+            "connection": connection,
+            "user": user,
+            "vhost": vhost,
+        }),
+        BrokerEvent::ConnectionClosed { connection } => json!({
+            "type": "connection_closed",
+            "connection": connection,
+        }),
+        BrokerEvent::ConnectionException {
+            connection,
+            reply_code,
+            reply_text,
+        } => json!({
+            "type": "connection_exception",
+            "connection": connection,
+            "reply_code": reply_code,
+            "reply_text": reply_text,
+        }),
+        BrokerEvent::ChannelOpened { connection, channel } => json!({
+            "type": "channel_opened",
+            "connection": connection,
+            "channel": channel,
+        }),
+        BrokerEvent::ChannelClosed { connection, channel } => json!({
+            "type": "channel_closed",
+            "connection": connection,
+            "channel": channel,
+        }),
+        BrokerEvent::ChannelException {
+            connection,
+            channel,
+            reply_code,
+            reply_text,
+        } => json!({
+            "type": "channel_exception",
+            "connection": connection,
+            "channel": channel,
+            "reply_code": reply_code,
+            "reply_text": reply_text,
+        }),
+        BrokerEvent::QueueDeclared { vhost, queue } => json!({
+            "type": "queue_declared",
+            "vhost": vhost,
+            "queue": queue,
+        }),
+        BrokerEvent::QueueDeleted { vhost, queue } => json!({
+            "type": "queue_deleted",
+            "vhost": vhost,
+            "queue": queue,
+        }),
+        BrokerEvent::ExchangeDeclared { vhost, exchange } => json!({
+            "type": "exchange_declared",
+            "vhost": vhost,
+            "exchange": exchange,
+        }),
+        BrokerEvent::ExchangeDeleted { vhost, exchange } => json!({
+            "type": "exchange_deleted",
+            "vhost": vhost,
+            "exchange": exchange,
+        }),
+        BrokerEvent::QueueBound {
+            vhost,
+            exchange,
+            queue,
+            routing_key,
+        } => json!({
+            "type": "queue_bound",
+            "vhost": vhost,
+            "exchange": exchange,
+            "queue": queue,
+            "routing_key": routing_key,
+        }),
+        BrokerEvent::QueueUnbound {
+            vhost,
+            exchange,
+            queue,
+            routing_key,
+        } => json!({
+            "type": "queue_unbound",
+            "vhost": vhost,
+            "exchange": exchange,
+            "queue": queue,
+            "routing_key": routing_key,
+        }),
+        BrokerEvent::MessageRouted {
+            vhost,
+            exchange,
+            routing_key,
+            bindings,
+            queues,
+            dead_lettered_from,
+        } => json!({
+            "type": "message_routed",
+            "vhost": vhost,
+            "exchange": exchange,
+            "routing_key": routing_key,
+            "bindings": bindings
+                .iter()
+                .map(|b| json!({ "queue": b.queue, "routing_key": b.routing_key }))
+                .collect::<Vec<_>>(),
+            "queues": queues,
+            "dead_lettered_from": dead_lettered_from,
+        }),
+        BrokerEvent::DiskAlarm { raised } => json!({
+            "type": "disk_alarm",
+            "raised": raised,
+        }),
+    };
+    event["timestamp_ms"] = json!(recorded.at.as_millis() as u64);
+    event
+}
+
+fn start_trace(request: &Request, id: u64, trace: &FrameTrace) -> Response {
+    let seconds = match request.param("seconds") {
+        None => DEFAULT_TRACE_SECONDS,
+        Some(seconds) => match seconds.parse::<u64>() {
+            Ok(seconds) => seconds.min(MAX_TRACE_DURATION.as_secs()),
+            Err(_) => return Response::error(400, "bad_request", format!("invalid seconds '{}'", seconds)),
+        },
+    };
+    trace.start(Duration::from_secs(seconds));
+    Response::ok(json!({ "connection": id, "seconds": seconds }))
+}
+
+fn trace_json(trace: &FrameTrace) -> Value {
+    let frames: Vec<Value> = trace
+        .frames()
+        .iter()
+        .map(|frame| {
+            json!({
+                "direction": frame.direction.as_str(),
+                "timestamp": frame.timestamp_ms,
+                "frame": base64::engine::general_purpose::STANDARD.encode(&frame.bytes),
+            })
+        })
+        .collect();
+    json!({ "active": trace.is_active(), "frames": frames })
+}
+
+fn purge_queues(broker: &Broker, request: &Request) -> Response {
+    let body: Value = match serde_json::from_slice(&request.body) {
+        Ok(body) => body,
+        Err(e) => return Response::error(400, "bad_request", format!("invalid JSON body: {}", e)),
+    };
+    let (Some(vhost), Some(pattern)) = (body["vhost"].as_str(), body["pattern"].as_str()) else {
+        return Response::error(
+            400,
+            "bad_request",
+            "the body needs string fields vhost and pattern".into(),
+        );
+    };
+    if !broker.has_vhost(vhost) {
+        return Response::error(404, "not_found", format!("no vhost '{}'", vhost));
+    }
+    if let Some(users) = broker.auth().users() {
+        let user = request.credentials.as_ref().map_or("", |(name, _)| name.as_str());
+        let Some(permission) = users.permission(vhost, user) else {
+            return Response::error(
+                403,
+                "forbidden",
+                format!("user '{}' has no permissions in vhost '{}'", user, vhost),
+            );
+        };
+        let denied = broker
+            .queue_summaries()
+            .into_iter()
+            .filter(|queue| queue.vhost == vhost && glob_matches(pattern, &queue.name))
+            .find(|queue| {
+                !glob_matches(&permission.configure, &queue.name) || !glob_matches(&permission.write, &queue.name)
+            });
+        if let Some(queue) = denied {
+            return Response::error(
+                403,
+                "forbidden",
+                format!(
+                    "user '{}' may not purge queue '{}' in vhost '{}'",
+                    user, queue.name, vhost
+                ),
+            );
+        }
+    }
+    let Some(purged) = broker.purge_queues(vhost, |name| glob_matches(pattern, name)) else {
+        return Response::error(404, "not_found", format!("no vhost '{}'", vhost));
+    };
+    let total: usize = purged.iter().map(|(_, count)| count).sum();
+    let queues: Vec<Value> = purged
+        .iter()
+        .map(|(name, count)| json!({ "name": name, "messages_purged": count }))
+        .collect();
+    info!(
+        "Purged {} messages from {} queues matching '{}' in vhost '{}'",
+        total,
+        queues.len(),
+        pattern,
+        vhost
+    );
+    Response::ok(json!({ "vhost": vhost, "messages_purged": total, "queues": queues }))
+}
+
+fn import_definitions(broker: &Broker, request: &Request) -> Response {
+    let document: Value = match serde_json::from_slice(&request.body) {
+        Ok(document) => document,
+        Err(e) => return Response::error(400, "bad_request", format!("invalid JSON body: {}", e)),
+    };
+    let definitions = match definitions::from_json(&document) {
+        Ok(definitions) => definitions,
+        Err(e) => return Response::error(400, "bad_request", format!("invalid definitions: {}", e)),
+    };
+    let results = definitions::import(broker, &definitions);
+    let failed = results
+        .iter()
+        .filter(|r| matches!(r.outcome, ImportOutcome::Failed(_)))
+        .count();
+    info!("Imported definitions: {} resources, {} failed", results.len(), failed);
+    let results: Vec<Value> = results
+        .iter()
+        .map(|result| {
+            let mut json = json!({
+                "kind": result.kind,
+                "resource": result.resource,
+                "result": result.outcome.as_str(),
+            });
+            if let ImportOutcome::Failed(reason) = &result.outcome {
+                json["reason"] = json!(reason);
+            }
+            json
+        })
+        .collect();
+    Response::ok(json!({ "failed": failed, "results": results }))
+}
+
+/// The broker's changeable users, if the request comes from an
+/// administrator; otherwise the response refusing it.
+fn admin_users<'a>(broker: &'a Broker, request: &Request) -> Result<&'a UserStore, Response> {
+    let Some(users) = broker.auth().users() else {
+        return Err(Response::error(
+            501,
+            "not_supported",
+            "the broker's users cannot be changed while it runs".into(),
+        ));
+    };
+    authenticate_admin(users, request)?;
+    Ok(users)
+}
+
+/// Checks that the request carries the credentials of one of `users`
+/// tagged `administrator`.
+fn authenticate_admin(users: &UserStore, request: &Request) -> Result<(), Response> {
+    let Some((name, password)) = &request.credentials else {
+        return Err(Response::error(401, "unauthorized", "credentials are required".into()));
+    };
+    let Some(user) = users.check_password(name, password) else {
+        return Err(Response::error(
+            401,
+            "unauthorized",
+            "invalid username or password".into(),
+        ));
+    };
+    if !user.has_tag(ADMIN_TAG) {
+        return Err(Response::error(
+            403,
+            "forbidden",
+            format!("user '{}' is not tagged {}", name, ADMIN_TAG),
+        ));
+    }
+    Ok(())
+}
+
+fn put_user(users: &UserStore, request: &Request, name: &str) -> Response {
+    let body: Value = match serde_json::from_slice(&request.body) {
+        Ok(body) => body,
+        Err(e) => return Response::error(400, "bad_request", format!("invalid JSON body: {}", e)),
+    };
+    let Some(password) = body["password"].as_str() else {
+        return Response::error(400, "bad_request", "the body needs a string field password".into());
+    };
+    // Tags come as a list or, as other brokers take them, comma-separated.
+    let tags = match &body["tags"] {
+        Value::Null => Vec::new(),
+        Value::String(tags) => tags
+            .split(',')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(String::from)
+            .collect(),
+        Value::Array(tags) => match tags.iter().map(|t| t.as_str().map(String::from)).collect() {
+            Some(tags) => tags,
+            None => return Response::error(400, "bad_request", "tags must be strings".into()),
+        },
+        _ => return Response::error(400, "bad_request", "tags must be a list or a string".into()),
+    };
+    let user = User {
+        password: PasswordHash::new(password),
+        tags,
+    };
+    let created = users.put_user(name, user);
+    info!("{} user '{}'", if created { "Created" } else { "Updated" }, name);
+    Response::ok(json!({ "user": name, "created": created }))
+}
+
+fn put_permission(broker: &Broker, users: &UserStore, request: &Request, vhost: &str, user: &str) -> Response {
+    let body: Value = match serde_json::from_slice(&request.body) {
+        Ok(body) => body,
+        Err(e) => return Response::error(400, "bad_request", format!("invalid JSON body: {}", e)),
+    };
+    let (Some(configure), Some(write), Some(read)) = (
+        body["configure"].as_str(),
+        body["write"].as_str(),
+        body["read"].as_str(),
+    ) else {
+        return Response::error(
+            400,
+            "bad_request",
+            "the body needs string fields configure, write and read".into(),
+        );
+    };
+    if !broker.has_vhost(vhost) {
+        return Response::error(404, "not_found", format!("no vhost '{}'", vhost));
+    }
+    if users.user(user).is_none() {
+        return Response::error(404, "not_found", format!("no user '{}'", user));
+    }
+    let permission = Permission {
+        configure: configure.into(),
+        write: write.into(),
+        read: read.into(),
+    };
+    let created = users.set_permission(vhost, user, permission);
+    info!("Set permissions of user '{}' in vhost '{}'", user, vhost);
+    Response::ok(json!({ "vhost": vhost, "user": user, "created": created }))
+}
+
+fn policy_json(vhost: &str, policy: &Policy) -> Value {
+    json!({
+        "vhost": vhost,
+        "name": policy.name,
+        "pattern": policy.pattern,
+        "apply-to": policy.apply_to.as_str(),
+        "priority": policy.priority,
+        "definition": table_json(&policy.definition),
+    })
+}
+
+fn put_policy(broker: &Broker, request: &Request, vhost: &str, name: &str) -> Response {
+    let body: Value = match serde_json::from_slice(&request.body) {
+        Ok(body) => body,
+        Err(e) => return Response::error(400, "bad_request", format!("invalid JSON body: {}", e)),
+    };
+    let (Some(pattern), Some(definition)) = (body["pattern"].as_str(), body["definition"].as_object()) else {
+        return Response::error(
+            400,
+            "bad_request",
+            "the body needs a string field pattern and an object field definition".into(),
+        );
+    };
+    let apply_to = match &body["apply-to"] {
+        Value::Null => ApplyTo::default(),
+        value => match value.as_str().and_then(ApplyTo::parse) {
+            Some(apply_to) => apply_to,
+            None => return Response::error(400, "bad_request", format!("invalid apply-to {}", value)),
+        },
+    };
+    let priority = match &body["priority"] {
+        Value::Null => 0,
+        value => match value.as_i64().and_then(|priority| i32::try_from(priority).ok()) {
+            Some(priority) => priority,
+            None => return Response::error(400, "bad_request", format!("invalid priority {}", value)),
+        },
+    };
+    let policy = Policy {
+        name: name.to_string(),
+        pattern: pattern.to_string(),
+        apply_to,
+        priority,
+        definition: definitions::field_table(definition),
+    };
+    if let Err(reason) = policy.validate() {
+        return Response::error(400, "bad_request", format!("invalid definition: {}", reason));
+    }
+    let Some(created) = broker.set_policy(vhost, policy) else {
+        return Response::error(404, "not_found", format!("no vhost '{}'", vhost));
+    };
+    info!(
+        "{} policy '{}' in vhost '{}'",
+        if created { "Created" } else { "Updated" },
+        name,
+        vhost
+    );
+    Response::ok(json!({ "vhost": vhost, "policy": name, "created": created }))
+}
+
+fn peek_messages(broker: &Broker, request: &Request, vhost: &str, queue: &str) -> Response {
+    // Fetching by consuming is deliberately not offered here.
+    if request.param("peek").as_deref() != Some("true") {
+        return Response::error(400, "bad_request", "only peek=true is supported".into());
+    }
+    let count = match request.param("count") {
+        None => 1,
+        Some(count) => match count.parse::<usize>() {
+            Ok(count) => count.min(MAX_PEEK_COUNT),
+            Err(_) => return Response::error(400, "bad_request", format!("invalid count '{}'", count)),
+        },
+    };
+    let Some(messages) = broker.peek(vhost, queue, count) else {
+        return Response::error(404, "not_found", format!("no queue '{}' in vhost '{}'", queue, vhost));
+    };
+    let now = broker.clock().now();
+    Response::ok(Value::Array(
+        messages.iter().map(|queued| message_json(queued, now)).collect(),
+    ))
+}
+
+fn queue_rates(broker: &Broker, request: &Request, vhost: &str, queue: &str) -> Response {
+    let window = match request.param("window") {
+        None => None,
+        Some(window) => match window.parse::<u64>() {
+            Ok(seconds) => Some(Duration::from_secs(seconds)),
+            Err(_) => return Response::error(400, "bad_request", format!("invalid window '{}'", window)),
+        },
+    };
+    let Some(rates) = broker.queue_rates(vhost, queue, window) else {
+        return Response::error(404, "not_found", format!("no queue '{}' in vhost '{}'", queue, vhost));
+    };
+    let now = broker.clock().now();
+    let samples: Vec<Value> = rates
+        .series
+        .iter()
+        .map(|(at, rates)| {
+            let mut json = rates_json(rates);
+            json["age_ms"] = json!(now.saturating_duration_since(*at).as_millis() as u64);
+            json
+        })
+        .collect();
+    Response::ok(json!({
+        "vhost": vhost,
+        "name": queue,
+        "interval_ms": broker.config().rate_sample_interval.as_millis() as u64,
+        "window_ms": window.map(|window| window.as_millis() as u64),
+        "totals": {
+            "published": rates.totals.published,
+            "delivered": rates.totals.delivered,
+            "acked": rates.totals.acked,
+        },
+        "rates": rates.average.as_ref().map(rates_json),
+        "samples": samples,
+    }))
+}
+
+fn rates_json(rates: &Rates) -> Value {
+    json!({ "publish": rates.publish, "deliver": rates.deliver, "ack": rates.ack })
+}
+
+fn simulate_route(broker: &Broker, request: &Request, vhost: &str, exchange: &str) -> Response {
+    let routing_key = request.param("routing_key").unwrap_or_default();
+    let headers = match request.param("headers") {
+        None => None,
+        Some(headers) => match serde_json::from_str(&headers) {
+            Ok(Value::Object(headers)) => Some(definitions::field_table(&headers)),
+            _ => return Response::error(400, "bad_request", "headers must be a JSON object".into()),
+        },
+    };
+    let Some(queues) = broker.route_queues(vhost, exchange, &routing_key, headers.as_ref()) else {
+        return Response::error(
+            404,
+            "not_found",
+            format!("no exchange '{}' in vhost '{}'", exchange, vhost),
+        );
+    };
+    Response::ok(json!({
+        "vhost": vhost,
+        "exchange": exchange,
+        "routing_key": routing_key,
+        "queues": queues,
+    }))
+}
+
+fn message_json(queued: &QueuedMessage, now: Instant) -> Value {
+    let message = &queued.message;
+    let preview = &message.body[..message.body.len().min(PEEK_PREVIEW_BYTES)];
+    json!({
+        "exchange": message.exchange,
+        "routing_key": message.routing_key,
+        "redelivered": queued.redelivered,
+        "delivery_count": queued.delivery_count,
+        "queued_ms": now.saturating_duration_since(queued.enqueued_at).as_millis() as u64,
+        "properties": properties_json(&message.properties),
+        "payload": base64::engine::general_purpose::STANDARD.encode(preview),
+        "payload_bytes": message.body.len(),
+        "payload_truncated": preview.len() < message.body.len(),
+    })
+}
+
+/// The properties that are set, keyed by their AMQP names.
+fn properties_json(properties: &BasicProperties) -> Value {
+    let mut json = serde_json::Map::new();
+    let strings = [
+        ("content_type", &properties.content_type),
+        ("content_encoding", &properties.content_encoding),
+        ("correlation_id", &properties.correlation_id),
+        ("reply_to", &properties.reply_to),
+        ("expiration", &properties.expiration),
+        ("message_id", &properties.message_id),
+        ("type", &properties.kind),
+        ("user_id", &properties.user_id),
+        ("app_id", &properties.app_id),
+        ("cluster_id", &properties.cluster_id),
+    ];
+    for (key, value) in strings {
+        if let Some(value) = value {
+            json.insert(key.into(), json!(value));
+        }
+    }
+    if let Some(mode) = properties.delivery_mode {
+        json.insert("delivery_mode".into(), json!(mode));
+    }
+    if let Some(priority) = properties.priority {
+        json.insert("priority".into(), json!(priority));
+    }
+    if let Some(timestamp) = properties.timestamp {
+        json.insert("timestamp".into(), json!(timestamp));
+    }
+    if let Some(headers) = &properties.headers {
+        json.insert("headers".into(), table_json(headers));
+    }
+    Value::Object(json)
+}
+
+pub(crate) fn table_json(table: &FieldTable) -> Value {
+    Value::Object(
+        table
+            .iter()
+            .map(|(key, value)| (key.to_string(), value_json(value)))
+            .collect(),
+    )
+}
+
+fn value_json(value: &FieldValue) -> Value {
+    match value {
+        FieldValue::Boolean(b) => json!(b),
+        FieldValue::Float(f) => json!(f),
+        FieldValue::Double(f) => json!(f),
+        FieldValue::Decimal(scale, value) => json!(*value as f64 / 10f64.powi(*scale as i32)),
+        FieldValue::LongString(bytes) => match std::str::from_utf8(bytes) {
+            Ok(s) => json!(s),
+            Err(_) => json!(base64::engine::general_purpose::STANDARD.encode(bytes)),
+        },
+        FieldValue::ByteArray(bytes) => json!(base64::engine::general_purpose::STANDARD.encode(bytes)),
+        FieldValue::FieldArray(values) => Value::Array(values.iter().map(value_json).collect()),
+        FieldValue::Timestamp(t) => json!(t),
+        FieldValue::FieldTable(table) => table_json(table),
+        FieldValue::Void => Value::Null,
+        FieldValue::LongLongUint(v) => json!(v),
+        other => json!(other.as_i64()),
+    }
+}
+
+/// Reads one request from `stream`. Returns `Ok(None)` if the peer closes
+/// the connection before sending a complete request.
+async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<Request>> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let (head_len, method, path, content_length, credentials) = loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut parsed = httparse::Request::new(&mut headers);
+        match parsed.parse(&buf) {
+            Ok(httparse::Status::Complete(head_len)) => {
+                let content_length = parsed
+                    .headers
+                    .iter()
+                    .find(|h| h.name.eq_ignore_ascii_case("content-length"))
+                    .and_then(|h| std::str::from_utf8(h.value).ok()?.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                let credentials = parsed
+                    .headers
+                    .iter()
+                    .find(|h| h.name.eq_ignore_ascii_case("authorization"))
+                    .and_then(|h| basic_credentials(h.value));
+                let method = parsed.method.unwrap_or_default().to_string();
+                let path = parsed.path.unwrap_or_default().to_string();
+                break (head_len, method, path, content_length, credentials);
+            }
+            Ok(httparse::Status::Partial) if buf.len() < MAX_HEAD_BYTES => {}
+            Ok(httparse::Status::Partial) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "request head too large"))
+            }
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    };
+    if content_length > MAX_BODY_BYTES {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "request body too large"));
+    }
+    let mut body = buf.split_off(head_len);
+    while body.len() < content_length {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+    body.truncate(content_length);
+    let (path, query) = match path.split_once('?') {
+        Some((path, query)) => (path.to_string(), query.to_string()),
+        None => (path, String::new()),
+    };
+    Ok(Some(Request {
+        method,
+        path,
+        query,
+        credentials,
+        body,
+    }))
+}
+
+/// Decodes the user name and password of a `Basic` authorization header.
+fn basic_credentials(header: &[u8]) -> Option<(String, String)> {
+    let encoded = std::str::from_utf8(header).ok()?.trim().strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+    let (name, password) = String::from_utf8(decoded)
+        .ok()?
+        .split_once(':')
+        .map(|(n, p)| (n.into(), p.into()))?;
+    Some((name, password))
+}
+
+async fn write_response<S: AsyncWrite + Unpin>(stream: &mut S, response: &Response) -> io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        501 => "Not Implemented",
+        _ => "Error",
+    };
+    // Lets clients know credentials are taken as HTTP Basic.
+    let challenge = if response.status == 401 {
+        "WWW-Authenticate: Basic realm=\"haymq\"\r\n"
+    } else {
+        ""
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+        response.status,
+        reason,
+        response.body.len(),
+        challenge
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.flush().await
+}
+
+/// Accepts management clients on `listener`, one request per connection.
+pub async fn serve(listener: TcpListener, broker: Arc<Broker>) -> io::Result<()> {
+    loop {
+        let (mut socket, addr) = listener.accept().await?;
+        let broker = broker.clone();
+        tokio::spawn(async move {
+            let request = match read_request(&mut socket).await {
+                Ok(Some(request)) => request,
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("Bad management request from {:?}: {}", addr, e);
+                    let _ = write_response(&mut socket, &Response::error(400, "bad_request", e.to_string())).await;
+                    return;
+                }
+            };
+            let response = handle(&broker, &request);
+            info!("{} {} -> {}", request.method, request.path, response.status);
+            if let Err(e) = write_response(&mut socket, &response).await {
+                warn!("Failed to answer management request from {:?}: {}", addr, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::auth::{PasswordAuth, UserConfig};
+    use crate::broker::{ExchangeDeclare, QueueDeclare};
+    use crate::config::Config;
+    use crate::exchange::Binding;
+    use crate::listener::Listener;
+    use crate::message::Message;
+    use crate::methods::Method;
+    use crate::properties::BasicProperties;
+    use crate::protocol::{AmqpFrame, FRAME_HEADER};
+    use crate::reply_codes;
+    use crate::test_support::TestClient;
+    use crate::vhost::DEFAULT_VHOST;
+
+    fn post(path: &str) -> Request {
+        Request {
+            method: "POST".into(),
+            path: path.into(),
+            query: String::new(),
+            credentials: None,
+            body: Vec::new(),
+        }
+    }
+
+    fn work_queue(broker: &Broker) {
+        broker
+            .declare_exchange(
+                DEFAULT_VHOST,
+                ExchangeDeclare {
+                    exchange: "work".into(),
+                    kind: "direct".into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        broker
+            .declare_queue(
+                DEFAULT_VHOST,
+                QueueDeclare {
+                    queue: "jobs".into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let binding = Binding {
+            queue: "jobs".into(),
+            routing_key: "jobs".into(),
+            arguments: FieldTable::new(),
+        };
+        broker.bind_queue(DEFAULT_VHOST, binding, "work").unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drained_vhost_holds_publishes_until_resumed() {
+        let broker = Arc::new(Broker::new(Config {
+            vhosts: vec!["other".into()],
+            ..Default::default()
+        }));
+        work_queue(&broker);
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client.consume(1, "jobs", true).await;
+
+        let response = handle(&broker, &post("/api/vhosts/%2F/drain"));
+        assert_eq!(response.status, 200, "{}", response.body);
+        assert!(!broker.is_drained("other"));
+        assert_eq!(client.recv_method().await, (1, Method::ChannelFlow { active: false }));
+        client.send_method(1, &Method::ChannelFlowOk { active: false }).await;
+
+        client
+            .publish(1, "work", "jobs", BasicProperties::default(), b"held")
+            .await;
+        assert!(client.try_recv_frame(Duration::from_millis(100)).await.is_none());
+
+        assert_eq!(handle(&broker, &post("/api/vhosts/%2F/resume")).status, 200);
+        // The resume notice and the delivery race each other to the client.
+        let mut resumed = false;
+        loop {
+            let frame = client.recv_frame().await;
+            match Method::decode(&frame.payload).unwrap() {
+                Method::ChannelFlow { active: true } => resumed = true,
+                Method::BasicDeliver { .. } => break,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert_eq!(client.recv_frame().await.frame_type, FRAME_HEADER);
+        assert_eq!(client.recv_frame().await.payload, b"held");
+        if !resumed {
+            assert_eq!(client.recv_method().await, (1, Method::ChannelFlow { active: true }));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_channel_opened_while_drained_starts_paused() {
+        let broker = Arc::new(Broker::new(Config::default()));
+        assert_eq!(handle(&broker, &post("/api/vhosts/%2F/drain")).status, 200);
+        let mut client = TestClient::connect_to(broker).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        assert_eq!(client.recv_method().await, (1, Method::ChannelFlow { active: false }));
+    }
+
+    #[test]
+    fn test_unknown_vhost_and_route() {
+        let broker = Broker::new(Config::default());
+        assert_eq!(handle(&broker, &post("/api/vhosts/nope/drain")).status, 404);
+        assert_eq!(handle(&broker, &post("/api/nothing")).status, 404);
+        let get = Request {
+            method: "GET".into(),
+            ..post("/api/vhosts/%2F/drain")
+        };
+        assert_eq!(handle(&broker, &get).status, 405);
+        assert!(!broker.is_drained(DEFAULT_VHOST));
+    }
+
+    #[test]
+    fn test_vhosts_report_resource_counts() {
+        let mut config = Config::default();
+        let settings = crate::vhost::VHostSettings {
+            max_queues: Some(10),
+            ..Default::default()
+        };
+        config.vhost_settings.insert(DEFAULT_VHOST.into(), settings);
+        let broker = Broker::new(config);
+        work_queue(&broker);
+        let request = Request {
+            method: "GET".into(),
+            ..post("/api/vhosts")
+        };
+        let response = handle(&broker, &request);
+        assert_eq!(response.status, 200, "{}", response.body);
+        let vhosts: Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(
+            vhosts,
+            json!([{
+                "name": "/",
+                "queues": 1,
+                "exchanges": 1,
+                "bindings": 1,
+                "limits": { "max_queues": 10, "max_exchanges": null, "max_bindings": null },
+            }])
+        );
+    }
+
+    fn health(broker: &Broker) -> Value {
+        let request = Request {
+            method: "GET".into(),
+            ..post("/api/health")
+        };
+        let response = handle(broker, &request);
+        assert_eq!(response.status, 200, "{}", response.body);
+        serde_json::from_str(&response.body).unwrap()
+    }
+
+    #[test]
+    fn test_health_turns_with_memory_pressure() {
+        let message = Message {
+            exchange: "work".into(),
+            routing_key: "jobs".into(),
+            properties: BasicProperties::default(),
+            body: vec![0; 1000],
+        };
+        let broker = Broker::new(Config {
+            memory_high_watermark: 10 * message.memory_footprint(),
+            ..Default::default()
+        });
+        work_queue(&broker);
+        let report = health(&broker);
+        assert_eq!(report["status"], "green");
+        assert_eq!(report["alarms"], json!([]));
+
+        // Past MEMORY_WARNING_FRACTION of the watermark, then past all of it.
+        for (more, status) in [(8, "yellow"), (2, "red")] {
+            for _ in 0..more {
+                broker.publish(DEFAULT_VHOST, message.clone(), false);
+            }
+            let report = health(&broker);
+            assert_eq!(report["status"], status, "{}", report);
+            assert_eq!(report["alarms"][0]["name"], "memory");
+            assert_eq!(report["alarms"][0]["status"], status);
+        }
+        assert_eq!(health(&broker)["messages"], 10);
+        assert_eq!(health(&broker)["message_bytes"], 10 * message.memory_footprint());
+
+        let fresh = broker_with_jobs();
+        fresh.record_listener_failure("127.0.0.1:5672", &io::Error::other("address in use"));
+        let report = health(&fresh);
+        assert_eq!(report["status"], "red");
+        assert_eq!(report["alarms"][0]["name"], "listener");
+    }
+
+    #[tokio::test]
+    async fn test_paused_listeners_refuse_new_connections() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let broker = Arc::new(Broker::new(Config::default()));
+        let tcp = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        tokio::spawn(Listener::Tcp(tcp).serve(broker.clone()));
+        let connect = || async {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let mut client = TestClient::from_stream(broker.clone(), stream, tokio::spawn(async {}));
+            client.send_raw(b"AMQP\x00\x00\x09\x01").await;
+            client.handshake().await;
+            client
+        };
+        let mut open = connect().await;
+
+        assert_eq!(handle(&broker, &post("/api/listeners/pause")).status, 200);
+        let report = health(&broker);
+        assert_eq!(report["listeners_paused"], true);
+        assert_eq!(report["status"], "yellow");
+        // The socket is closed before the broker sends anything.
+        let mut refused = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let _ = refused.write_all(b"AMQP\x00\x00\x09\x01").await;
+        let mut buf = [0; 8];
+        let read = tokio::time::timeout(Duration::from_secs(1), refused.read(&mut buf))
+            .await
+            .unwrap();
+        assert!(matches!(read, Ok(0) | Err(_)), "{:?}", read);
+        // The connection opened before the pause keeps working.
+        open.open_channel(1).await;
+
+        assert_eq!(handle(&broker, &post("/api/listeners/resume")).status, 200);
+        assert_eq!(health(&broker)["listeners_paused"], false);
+        let mut resumed = connect().await;
+        resumed.open_channel(1).await;
+    }
+
+    fn peek(path: &str, query: &str) -> Response {
+        let request = Request {
+            method: "GET".into(),
+            query: query.into(),
+            ..post(path)
+        };
+        handle(&broker_with_jobs(), &request)
+    }
+
+    fn broker_with_jobs() -> Broker {
+        let broker = Broker::new(Config::default());
+        work_queue(&broker);
+        broker
+    }
+
+    #[tokio::test]
+    async fn test_peek_leaves_messages_deliverable() {
+        let broker = Arc::new(broker_with_jobs());
+        for body in ["first", "second", "third"] {
+            let message = Message {
+                exchange: "work".into(),
+                routing_key: "jobs".into(),
+                properties: BasicProperties {
+                    content_type: Some("text/plain".into()),
+                    ..Default::default()
+                },
+                body: body.as_bytes().to_vec(),
+            };
+            broker.publish(DEFAULT_VHOST, message, false);
+        }
+        let request = Request {
+            method: "GET".into(),
+            query: "count=2&peek=true".into(),
+            ..post("/api/queues/%2F/jobs/messages")
+        };
+        let response = handle(&broker, &request);
+        assert_eq!(response.status, 200, "{}", response.body);
+        let peeked: Value = serde_json::from_str(&response.body).unwrap();
+        let peeked = peeked.as_array().unwrap();
+        assert_eq!(peeked.len(), 2);
+        assert_eq!(peeked[0]["payload"], "Zmlyc3Q=");
+        assert_eq!(peeked[0]["payload_bytes"], 5);
+        assert_eq!(peeked[0]["properties"]["content_type"], "text/plain");
+        assert_eq!(peeked[0]["delivery_count"], 0);
+        assert!(peeked[0]["queued_ms"].is_u64());
+        assert_eq!(peeked[1]["payload"], "c2Vjb25k");
+
+        let mut client = TestClient::connect_to(broker).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client.consume(1, "jobs", true).await;
+        for body in ["first", "second", "third"] {
+            assert_eq!(client.recv_delivery().await.1, body.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_peek_errors() {
+        assert_eq!(peek("/api/queues/%2F/missing/messages", "peek=true").status, 404);
+        assert_eq!(peek("/api/queues/%2F/jobs/messages", "count=2").status, 400);
+        assert_eq!(
+            peek("/api/queues/%2F/jobs/messages", "count=many&peek=true").status,
+            400
+        );
+        assert_eq!(peek("/api/queues/%2F/jobs/messages", "peek=true").body, "[]");
+    }
+
+    fn route(broker: &Broker, path: &str, query: &str) -> Response {
+        let request = Request {
+            method: "GET".into(),
+            query: query.into(),
+            ..post(path)
+        };
+        handle(broker, &request)
+    }
+
+    #[test]
+    fn test_simulated_route_matches_delivery() {
+        let broker = broker_with_jobs();
+        for (exchange, kind) in [("events", "topic"), ("documents", "headers")] {
+            let declare = ExchangeDeclare {
+                exchange: exchange.into(),
+                kind: kind.into(),
+                ..Default::default()
+            };
+            broker.declare_exchange(DEFAULT_VHOST, declare).unwrap();
+        }
+        let mut pdf = FieldTable::new();
+        pdf.insert("format", FieldValue::LongString(b"pdf".to_vec()));
+        let bindings = [
+            ("events", "audit", "#", FieldTable::new()),
+            ("events", "orders", "order.*", FieldTable::new()),
+            ("events", "jobs", "order.created", FieldTable::new()),
+            ("documents", "audit", "", pdf),
+        ];
+        for (exchange, queue, routing_key, arguments) in bindings {
+            let declare = QueueDeclare {
+                queue: queue.into(),
+                ..Default::default()
+            };
+            broker.declare_queue(DEFAULT_VHOST, declare).unwrap();
+            let binding = Binding {
+                queue: queue.into(),
+                routing_key: routing_key.into(),
+                arguments,
+            };
+            broker.bind_queue(DEFAULT_VHOST, binding, exchange).unwrap();
+        }
+
+        let cases = [
+            ("events", "order.created", None, vec!["audit", "orders", "jobs"]),
+            ("events", "order.shipped.late", None, vec!["audit"]),
+            ("work", "jobs", None, vec!["jobs"]),
+            ("work", "other", None, vec![]),
+            ("documents", "", Some("pdf"), vec!["audit"]),
+            ("documents", "", Some("txt"), vec![]),
+        ];
+        for (exchange, routing_key, format, expected) in cases {
+            let mut query = format!("routing_key={}", routing_key);
+            if let Some(format) = format {
+                query.push_str(&format!("&headers=%7B%22format%22:%22{}%22%7D", format));
+            }
+            let path = format!("/api/exchanges/%2F/{}/route", exchange);
+            let response = route(&broker, &path, &query);
+            assert_eq!(response.status, 200, "{}", response.body);
+            let routed: Value = serde_json::from_str(&response.body).unwrap();
+            let mut simulated: Vec<String> = serde_json::from_value(routed["queues"].clone()).unwrap();
+            simulated.sort();
+            // Nothing was enqueued by the simulation.
+            assert!(broker.queue_summaries().iter().all(|queue| queue.messages == 0));
+
+            let message = Message {
+                exchange: exchange.into(),
+                routing_key: routing_key.into(),
+                properties: BasicProperties {
+                    headers: format.map(|format| {
+                        let mut headers = FieldTable::new();
+                        headers.insert("format", FieldValue::LongString(format.as_bytes().to_vec()));
+                        headers
+                    }),
+                    ..Default::default()
+                },
+                body: b"routed".to_vec(),
+            };
+            broker.publish(DEFAULT_VHOST, message, false);
+            let mut delivered: Vec<String> = broker
+                .queue_summaries()
+                .into_iter()
+                .filter(|queue| queue.messages > 0)
+                .map(|queue| queue.name)
+                .collect();
+            delivered.sort();
+            let mut expected: Vec<String> = expected.into_iter().map(String::from).collect();
+            expected.sort();
+            assert_eq!(simulated, expected, "{} {}", exchange, routing_key);
+            assert_eq!(delivered, expected, "{} {}", exchange, routing_key);
+            broker.purge_queues(DEFAULT_VHOST, |_| true).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_simulated_route_errors() {
+        let broker = broker_with_jobs();
+        assert_eq!(
+            route(&broker, "/api/exchanges/%2F/missing/route", "routing_key=jobs").status,
+            404
+        );
+        assert_eq!(
+            route(&broker, "/api/exchanges/nope/work/route", "routing_key=jobs").status,
+            404
+        );
+        assert_eq!(
+            route(&broker, "/api/exchanges/%2F/work/route", "headers=%5B1%5D").status,
+            400
+        );
+    }
+
+    #[tokio::test]
+    async fn test_events_return_the_most_recent_in_order() {
+        let broker = Arc::new(Broker::new(Config {
+            event_log_size: 3,
+            ..Default::default()
+        }));
+        crate::broker::spawn_event_log(&broker);
+        for queue in ["q1", "q2", "q3", "q4", "q5"] {
+            let declare = QueueDeclare {
+                queue: queue.into(),
+                ..Default::default()
+            };
+            broker.declare_queue(DEFAULT_VHOST, declare).unwrap();
+        }
+        while broker.recent_events(1).first().map(|recorded| &recorded.event)
+            != Some(&BrokerEvent::QueueDeclared {
+                vhost: DEFAULT_VHOST.into(),
+                queue: "q5".into(),
+            })
+        {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let events = |query: &str| {
+            let request = Request {
+                method: "GET".into(),
+                query: query.into(),
+                ..post("/api/events")
+            };
+            let response = handle(&broker, &request);
+            assert_eq!(response.status, 200, "{}", response.body);
+            let events: Value = serde_json::from_str(&response.body).unwrap();
+            let queues: Vec<String> = events
+                .as_array()
+                .unwrap()
+                .iter()
+                .inspect(|event| {
+                    assert_eq!(event["type"], "queue_declared");
+                    assert!(event["timestamp_ms"].is_u64());
+                })
+                .map(|event| event["queue"].as_str().unwrap().to_string())
+                .collect();
+            queues
+        };
+        assert_eq!(events("limit=2"), ["q4", "q5"]);
+        // Only the last three were kept.
+        assert_eq!(events(""), ["q3", "q4", "q5"]);
+        let request = Request {
+            method: "GET".into(),
+            query: "limit=few".into(),
+            ..post("/api/events")
+        };
+        assert_eq!(handle(&broker, &request).status, 400);
+    }
+
+    #[tokio::test]
+    async fn test_connections_show_client_properties() {
+        let broker = Arc::new(Broker::new(Config::default()));
+        let mut capabilities = FieldTable::new();
+        capabilities.insert("publisher_confirms", FieldValue::Boolean(true));
+        let mut client_properties = FieldTable::new();
+        client_properties.insert("product", FieldValue::LongString(b"test-client".to_vec()));
+        client_properties.insert("version", FieldValue::LongString(b"1.2.3".to_vec()));
+        client_properties.insert("platform", FieldValue::LongString(b"Rust".to_vec()));
+        client_properties.insert("capabilities", FieldValue::FieldTable(capabilities));
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.handshake_with_properties(client_properties).await;
+
+        let get = Request {
+            method: "GET".into(),
+            ..post("/api/connections")
+        };
+        let response = handle(&broker, &get);
+        assert_eq!(response.status, 200, "{}", response.body);
+        let connections: Value = serde_json::from_str(&response.body).unwrap();
+        let [connection] = &connections.as_array().unwrap()[..] else {
+            panic!("expected one connection, got {}", connections);
+        };
+        assert_eq!(connection["user"], "guest");
+        assert_eq!(connection["vhost"], "/");
+        let properties = &connection["client_properties"];
+        assert_eq!(properties["product"], "test-client");
+        assert_eq!(properties["version"], "1.2.3");
+        assert_eq!(properties["platform"], "Rust");
+        assert_eq!(properties["capabilities"]["publisher_confirms"], true);
+
+        client.shutdown().await;
+        (&mut client.server).await.unwrap();
+        assert_eq!(handle(&broker, &get).body, "[]");
+        assert_eq!(handle(&broker, &post("/api/connections")).status, 405);
+    }
+
+    #[tokio::test]
+    async fn test_connection_reports_negotiated_parameters() {
+        let broker = Arc::new(Broker::new(Config {
+            frame_max: 131072,
+            channel_max: 2047,
+            heartbeat: 30,
+            ..Config::default()
+        }));
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.tune(100, Some(8192)).await;
+        client.open().await;
+
+        let id = broker.connections().open_connections()[0].id;
+        let get = |path: &str| Request {
+            method: "GET".into(),
+            ..post(path)
+        };
+        let response = handle(&broker, &get(&format!("/api/connections/{}", id)));
+        assert_eq!(response.status, 200, "{}", response.body);
+        let connection: Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(connection["id"], id);
+        assert_eq!(connection["frame_max"], 8192);
+        assert_eq!(connection["channel_max"], 100);
+        assert_eq!(connection["heartbeat"], 30);
+        assert_eq!(connection["auth_mechanism"], "PLAIN");
+        assert_eq!(connection["locale"], "en_US");
+        assert_eq!(connection["channels"], 0);
+
+        assert_eq!(
+            handle(&broker, &get(&format!("/api/connections/{}", id + 1))).status,
+            404
+        );
+        assert_eq!(handle(&broker, &get("/api/connections/abc")).status, 400);
+        client.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_connections_are_named_by_their_connection_name() {
+        let broker = Arc::new(Broker::new(Config::default()));
+        let peer: std::net::IpAddr = "10.0.0.7".parse().unwrap();
+        let mut capabilities = FieldTable::new();
+        capabilities.insert("consumer_cancel_notify", FieldValue::Boolean(true));
+        capabilities.insert("basic.nack", FieldValue::Boolean(false));
+        let mut client_properties = FieldTable::new();
+        client_properties.insert("connection_name", FieldValue::LongString(b"billing-worker-3".to_vec()));
+        client_properties.insert("capabilities", FieldValue::FieldTable(capabilities));
+        let mut named = TestClient::connect_from(broker.clone(), Some(peer)).await;
+        named.handshake_with_properties(client_properties).await;
+        let mut unnamed = TestClient::connect_from(broker.clone(), Some(peer)).await;
+        unnamed.handshake().await;
+
+        let get = Request {
+            method: "GET".into(),
+            ..post("/api/connections")
+        };
+        let connections: Value = serde_json::from_str(&handle(&broker, &get).body).unwrap();
+        let [named, unnamed] = &connections.as_array().unwrap()[..] else {
+            panic!("expected two connections, got {}", connections);
+        };
+        assert_eq!(named["name"], "billing-worker-3");
+        assert_eq!(named["connection_name"], "billing-worker-3");
+        assert_eq!(named["peer_host"], "10.0.0.7");
+        assert_eq!(named["capabilities"], json!(["consumer_cancel_notify"]));
+        assert_eq!(unnamed["name"], "10.0.0.7");
+        assert_eq!(unnamed["connection_name"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_connections_report_bytes_of_a_partial_frame() {
+        let broker = Arc::new(Broker::new(Config::default()));
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.handshake().await;
+        let id = broker.connections().open_connections()[0].id;
+        let get = Request {
+            method: "GET".into(),
+            ..post(&format!("/api/connections/{}", id))
+        };
+        let partial_bytes = || {
+            let connection: Value = serde_json::from_str(&handle(&broker, &get).body).unwrap();
+            connection["partial_frame_bytes"].as_u64().unwrap()
+        };
+        let wait_for = |expected: u64| async move {
+            for _ in 0..100 {
+                if partial_bytes() == expected {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            panic!(
+                "partial_frame_bytes stayed at {}, expected {}",
+                partial_bytes(),
+                expected
+            );
+        };
+
+        let frame = AmqpFrame::heartbeat().encode();
+        for sent in 1..frame.len() {
+            client.send_raw(&frame[sent - 1..sent]).await;
+            wait_for(sent as u64).await;
+        }
+        // The last byte completes the frame, and nothing is left over.
+        client.send_raw(&frame[frame.len() - 1..]).await;
+        wait_for(0).await;
+    }
+
+    #[tokio::test]
+    async fn test_connections_count_heartbeats_apart_from_other_frames() {
+        let broker = Arc::new(Broker::new(Config {
+            heartbeat: 1,
+            ..Config::default()
+        }));
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.handshake().await;
+        client.send_frame(&AmqpFrame::heartbeat()).await;
+        client.send_frame(&AmqpFrame::heartbeat()).await;
+        // Sent every half interval.
+        assert_eq!(client.recv_frame().await, AmqpFrame::heartbeat());
+
+        let id = broker.connections().open_connections()[0].id;
+        let get = Request {
+            method: "GET".into(),
+            ..post(&format!("/api/connections/{}", id))
+        };
+        let connection: Value = serde_json::from_str(&handle(&broker, &get).body).unwrap();
+        // Start-Ok, Tune-Ok and Open.
+        assert_eq!(connection["frames_received"], 3);
+        assert_eq!(connection["heartbeats_received"], 2);
+        assert_eq!(connection["heartbeats_sent"], 1);
+        let metrics = crate::metrics::render(&broker);
+        let line = format!("haymq_connection_heartbeats_received_total{{connection=\"{}\"}} 2", id);
+        assert!(metrics.contains(&line), "{}", metrics);
+        client.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_queues_report_consumer_utilization() {
+        let broker = Arc::new(broker_with_jobs());
+        let get = Request {
+            method: "GET".into(),
+            ..post("/api/queues")
+        };
+        let queues: Value = serde_json::from_str(&handle(&broker, &get).body).unwrap();
+        assert_eq!(queues[0]["name"], "jobs");
+        assert_eq!(queues[0]["consumer_utilization"], Value::Null);
+
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        let qos = Method::BasicQos {
+            prefetch_size: 0,
+            prefetch_count: 1,
+            global: false,
+        };
+        client.send_method(1, &qos).await;
+        assert_eq!(client.recv_method().await, (1, Method::BasicQosOk));
+        client.consume(1, "jobs", false).await;
+        for body in [b"a", b"b"] {
+            client
+                .publish(1, "work", "jobs", BasicProperties::default(), body)
+                .await;
+        }
+        client.recv_delivery().await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let queues: Value = serde_json::from_str(&handle(&broker, &get).body).unwrap();
+        assert_eq!(queues[0]["messages_ready"], 1);
+        assert_eq!(queues[0]["messages_unacknowledged"], 1);
+        assert_eq!(queues[0]["consumers"], 1);
+        let utilization = queues[0]["consumer_utilization"].as_f64().unwrap();
+        assert!(utilization < 1.0, "{}", utilization);
+        assert_eq!(handle(&broker, &post("/api/queues")).status, 405);
+    }
+
+    #[tokio::test]
+    async fn test_queues_report_ready_unacked_and_total_messages() {
+        let broker = Arc::new(broker_with_jobs());
+        let get = Request {
+            method: "GET".into(),
+            ..post("/api/queues")
+        };
+        let counts = || {
+            let queues: Value = serde_json::from_str(&handle(&broker, &get).body).unwrap();
+            let jobs = &queues[0];
+            (
+                jobs["messages_ready"].clone(),
+                jobs["messages_unacknowledged"].clone(),
+                jobs["messages"].clone(),
+            )
+        };
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        client
+            .publish(1, "work", "jobs", BasicProperties::default(), b"a")
+            .await;
+        client.consume(1, "jobs", false).await;
+        let (deliver, _) = client.recv_delivery().await;
+        assert_eq!(counts(), (json!(0), json!(1), json!(1)));
+
+        let Method::BasicDeliver { delivery_tag, .. } = deliver else {
+            panic!("expected Basic.Deliver, got {:?}", deliver);
+        };
+        let ack = Method::BasicAck {
+            delivery_tag,
+            multiple: false,
+        };
+        client.send_method(1, &ack).await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(counts(), (json!(0), json!(0), json!(0)));
+    }
+
+    #[test]
+    fn test_purge_clears_only_matching_queues() {
+        let broker = Broker::new(Config::default());
+        for name in ["deploy-1", "deploy-2", "billing"] {
+            broker
+                .declare_queue(
+                    DEFAULT_VHOST,
+                    QueueDeclare {
+                        queue: name.into(),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            for body in ["a", "b"] {
+                let message = Message {
+                    exchange: "".into(),
+                    routing_key: name.into(),
+                    properties: Default::default(),
+                    body: body.as_bytes().to_vec(),
+                };
+                broker.publish(DEFAULT_VHOST, message, false);
+            }
+        }
+        // An unacked delivery survives the purge.
+        broker.get(DEFAULT_VHOST, "deploy-1", false).unwrap().unwrap();
+
+        let purge = Request {
+            body: br#"{"vhost": "/", "pattern": "deploy-*"}"#.to_vec(),
+            ..post("/api/queues/purge")
+        };
+        let response = handle(&broker, &purge);
+        assert_eq!(response.status, 200, "{}", response.body);
+        let purged: Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(purged["messages_purged"], 3);
+        assert_eq!(
+            purged["queues"],
+            json!([
+                { "name": "deploy-1", "messages_purged": 1 },
+                { "name": "deploy-2", "messages_purged": 2 },
+            ])
+        );
+        let counts: Vec<(String, usize, usize)> = broker
+            .queue_summaries()
+            .into_iter()
+            .map(|queue| (queue.name, queue.messages, queue.unacked))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                ("billing".to_string(), 2, 0),
+                ("deploy-1".to_string(), 0, 1),
+                ("deploy-2".to_string(), 0, 0),
+            ]
+        );
+
+        let unknown = Request {
+            body: br#"{"vhost": "nope", "pattern": "*"}"#.to_vec(),
+            ..post("/api/queues/purge")
+        };
+        assert_eq!(handle(&broker, &unknown).status, 404);
+        let invalid = Request {
+            body: b"{}".to_vec(),
+            ..post("/api/queues/purge")
+        };
+        assert_eq!(handle(&broker, &invalid).status, 400);
+    }
+
+    #[test]
+    fn test_purge_needs_permission_on_every_matching_queue() {
+        let permission = |pattern: &str| Permission {
+            configure: pattern.into(),
+            write: pattern.into(),
+            read: "*".into(),
+        };
+        let users = UserStore::new();
+        for name in ["admin", "ops", "outsider"] {
+            let user = User {
+                password: PasswordHash::new(&format!("{}-pw", name)),
+                tags: vec![ADMIN_TAG.into()],
+            };
+            users.put_user(name, user);
+        }
+        users.set_permission(DEFAULT_VHOST, "admin", Permission::full());
+        users.set_permission(DEFAULT_VHOST, "ops", permission("deploy-*"));
+        let broker = Broker::with_auth(Config::default(), Box::new(PasswordAuth::new(Arc::new(users))));
+        for name in ["deploy-1", "billing"] {
+            broker
+                .declare_queue(
+                    DEFAULT_VHOST,
+                    QueueDeclare {
+                        queue: name.into(),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            let message = Message {
+                exchange: "".into(),
+                routing_key: name.into(),
+                properties: Default::default(),
+                body: b"a".to_vec(),
+            };
+            broker.publish(DEFAULT_VHOST, message, false);
+        }
+        let purge = |user: &str, pattern: &str| {
+            let request = Request {
+                credentials: Some((user.into(), format!("{}-pw", user))),
+                body: json!({ "vhost": "/", "pattern": pattern }).to_string().into_bytes(),
+                ..post("/api/queues/purge")
+            };
+            handle(&broker, &request).status
+        };
+        let counts = || broker.queue_summaries().iter().map(|q| q.messages).collect::<Vec<_>>();
+
+        assert_eq!(purge("outsider", "deploy-*"), 403);
+        // One queue out of reach refuses the whole purge.
+        assert_eq!(purge("ops", "*"), 403);
+        assert_eq!(counts(), [1, 1]);
+        assert_eq!(purge("ops", "deploy-*"), 200);
+        assert_eq!(counts(), [1, 0]);
+        assert_eq!(purge("admin", "*"), 200);
+        assert_eq!(counts(), [0, 0]);
+    }
+
+    #[test]
+    fn test_policies_apply_to_queues_declared_before_and_after() {
+        let broker = Broker::new(Config::default());
+        let declare = |name: &str| {
+            broker
+                .declare_queue(
+                    DEFAULT_VHOST,
+                    QueueDeclare {
+                        queue: name.into(),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+        };
+        let publish = |name: &str, count: usize| {
+            for _ in 0..count {
+                let message = Message {
+                    exchange: "".into(),
+                    routing_key: name.into(),
+                    properties: Default::default(),
+                    body: b"job".to_vec(),
+                };
+                broker.publish(DEFAULT_VHOST, message, false);
+            }
+        };
+        let summaries = |broker: &Broker| -> Vec<(String, usize, Option<String>)> {
+            broker
+                .queue_summaries()
+                .into_iter()
+                .map(|queue| (queue.name, queue.messages, queue.policy))
+                .collect()
+        };
+        declare("jobs.early");
+
+        let put = Request {
+            method: "PUT".into(),
+            body: br#"{"pattern": "jobs.*", "apply-to": "queues", "priority": 1,
+                       "definition": {"x-max-length": 2, "x-overflow": "drop-head"}}"#
+                .to_vec(),
+            ..post("/api/policies/%2F/jobs-limit")
+        };
+        let response = handle(&broker, &put);
+        assert_eq!(response.status, 200, "{}", response.body);
+        assert_eq!(serde_json::from_str::<Value>(&response.body).unwrap()["created"], true);
+        declare("jobs.late");
+        declare("audit");
+        publish("jobs.early", 3);
+        publish("jobs.late", 3);
+        publish("audit", 3);
+        let limited = Some("jobs-limit".to_string());
+        assert_eq!(
+            summaries(&broker),
+            vec![
+                ("audit".to_string(), 3, None),
+                ("jobs.early".to_string(), 2, limited.clone()),
+                ("jobs.late".to_string(), 2, limited),
+            ]
+        );
+        let listed: Value = serde_json::from_str(
+            &handle(
+                &broker,
+                &Request {
+                    method: "GET".into(),
+                    ..post("/api/policies")
+                },
+            )
+            .body,
+        )
+        .unwrap();
+        assert_eq!(listed[0]["name"], "jobs-limit");
+        assert_eq!(listed[0]["definition"]["x-max-length"], 2);
+
+        let delete = Request {
+            method: "DELETE".into(),
+            ..post("/api/policies/%2F/jobs-limit")
+        };
+        assert_eq!(handle(&broker, &delete).status, 200);
+        assert_eq!(handle(&broker, &delete).status, 404);
+        publish("jobs.late", 1);
+        assert_eq!(summaries(&broker)[2], ("jobs.late".to_string(), 3, None));
+
+        let invalid = Request {
+            body: br#"{"pattern": "*", "definition": {"x-max-length": "lots"}}"#.to_vec(),
+            ..put.clone()
+        };
+        assert_eq!(handle(&broker, &invalid).status, 400);
+        let unknown = Request {
+            path: "/api/policies/nope/jobs-limit".into(),
+            ..put
+        };
+        assert_eq!(handle(&broker, &unknown).status, 404);
+    }
+
+    #[test]
+    fn test_queue_rates_come_from_sampled_totals() {
+        use crate::clock::ManualClock;
+
+        let clock = Arc::new(ManualClock::new());
+        let broker = Broker::with_clock(Config::default(), clock.clone());
+        let declare = QueueDeclare {
+            queue: "jobs".into(),
+            ..Default::default()
+        };
+        broker.declare_queue(DEFAULT_VHOST, declare).unwrap();
+        broker.sample_rates();
+        // Each second: 10 messages in, 4 fetched, 2 of those acked.
+        for _ in 0..5 {
+            for _ in 0..10 {
+                let message = Message {
+                    exchange: "".into(),
+                    routing_key: "jobs".into(),
+                    properties: Default::default(),
+                    body: b"job".to_vec(),
+                };
+                broker.publish(DEFAULT_VHOST, message, false);
+            }
+            for n in 0..4 {
+                let fetched = broker.get(DEFAULT_VHOST, "jobs", false).unwrap().unwrap();
+                if n % 2 == 0 {
+                    assert!(broker.ack(DEFAULT_VHOST, "jobs", fetched.message_id));
+                }
+            }
+            clock.advance(Duration::from_secs(1));
+            broker.sample_rates();
+        }
+
+        let rates = |path: &str, query: &str| {
+            handle(
+                &broker,
+                &Request {
+                    method: "GET".into(),
+                    query: query.into(),
+                    ..post(path)
+                },
+            )
+        };
+        let get = |query: &str| {
+            let response = rates("/api/queues/%2F/jobs/rates", query);
+            assert_eq!(response.status, 200, "{}", response.body);
+            serde_json::from_str::<Value>(&response.body).unwrap()
+        };
+        let near = |value: &Value, expected: f64| (value.as_f64().unwrap() - expected).abs() < 0.01;
+        let all = get("");
+        assert_eq!(all["totals"], json!({ "published": 50, "delivered": 20, "acked": 10 }));
+        assert!(near(&all["rates"]["publish"], 10.0), "{}", all);
+        assert!(near(&all["rates"]["deliver"], 4.0), "{}", all);
+        assert!(near(&all["rates"]["ack"], 2.0), "{}", all);
+        assert_eq!(all["samples"].as_array().unwrap().len(), 5);
+        assert_eq!(all["samples"][4]["age_ms"], 0);
+        let recent = get("window=2");
+        assert_eq!(recent["samples"].as_array().unwrap().len(), 2);
+        assert!(near(&recent["rates"]["publish"], 10.0), "{}", recent);
+
+        assert_eq!(rates("/api/queues/%2F/nope/rates", "").status, 404);
+        assert_eq!(rates("/api/queues/%2F/jobs/rates", "window=soon").status, 400);
+    }
+
+    #[test]
+    fn test_queues_and_exchanges_report_when_they_were_last_active() {
+        use crate::clock::ManualClock;
+
+        let clock = Arc::new(ManualClock::new());
+        let broker = Broker::with_clock(Config::default(), clock.clone());
+        work_queue(&broker);
+        let list = |path: &str, name: &str| {
+            let response = handle(
+                &broker,
+                &Request {
+                    method: "GET".into(),
+                    ..post(path)
+                },
+            );
+            assert_eq!(response.status, 200, "{}", response.body);
+            let items: Vec<Value> = serde_json::from_str(&response.body).unwrap();
+            let item = items.into_iter().find(|item| item["name"] == name).unwrap();
+            (
+                item["created_at_ms"].as_u64().unwrap(),
+                item["last_active_ms"].as_u64().unwrap(),
+            )
+        };
+        let (created, last_active) = list("/api/queues", "jobs");
+        assert_eq!(created, last_active);
+        assert_eq!(list("/api/exchanges", "work"), (created, created));
+
+        clock.advance(Duration::from_secs(5));
+        let message = Message {
+            exchange: "work".into(),
+            routing_key: "jobs".into(),
+            properties: Default::default(),
+            body: b"job".to_vec(),
+        };
+        broker.publish(DEFAULT_VHOST, message, false);
+        assert_eq!(list("/api/queues", "jobs"), (created, created + 5000));
+        assert_eq!(list("/api/exchanges", "work"), (created, created + 5000));
+
+        clock.advance(Duration::from_secs(3));
+        broker.get(DEFAULT_VHOST, "jobs", true).unwrap().unwrap();
+        assert_eq!(list("/api/queues", "jobs"), (created, created + 8000));
+        assert_eq!(list("/api/exchanges", "work"), (created, created + 5000));
+    }
+
+    #[test]
+    fn test_definitions_round_trip_between_brokers() {
+        let source = Broker::new(Config::default());
+        work_queue(&source);
+        let exported = handle(
+            &source,
+            &Request {
+                method: "GET".into(),
+                ..post("/api/definitions")
+            },
+        );
+        assert_eq!(exported.status, 200);
+
+        let target = Broker::new(Config::default());
+        let imported = handle(
+            &target,
+            &Request {
+                body: exported.body.clone().into_bytes(),
+                ..post("/api/definitions")
+            },
+        );
+        assert_eq!(imported.status, 200);
+        let imported: Value = serde_json::from_str(&imported.body).unwrap();
+        assert_eq!(imported["failed"], 0);
+        assert_eq!(imported["results"].as_array().unwrap().len(), 4);
+        assert_eq!(definitions::to_json(&target.definitions()).to_string(), exported.body);
+
+        let invalid = handle(
+            &target,
+            &Request {
+                body: br#"{"queues": [{"name": "jobs"}]}"#.to_vec(),
+                ..post("/api/definitions")
+            },
+        );
+        assert_eq!(invalid.status, 400);
+    }
+
+    #[tokio::test]
+    async fn test_trace_captures_frames_both_ways() {
+        let broker = Arc::new(Broker::new(Config::default()));
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.handshake().await;
+        let id = broker.connections().open_connections()[0].id;
+        let path = format!("/api/connections/{}/trace", id);
+        let start = Request {
+            query: "seconds=30".into(),
+            ..post(&path)
+        };
+        assert_eq!(handle(&broker, &start).status, 200);
+        client.open_channel(1).await;
+
+        let get = Request {
+            method: "GET".into(),
+            ..post(&path)
+        };
+        let response = handle(&broker, &get);
+        assert_eq!(response.status, 200, "{}", response.body);
+        let trace: Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(trace["active"], true);
+        let frames: Vec<(String, Vec<u8>)> = trace["frames"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|frame| {
+                let bytes = base64::engine::general_purpose::STANDARD
+                    .decode(frame["frame"].as_str().unwrap())
+                    .unwrap();
+                (frame["direction"].as_str().unwrap().to_string(), bytes)
+            })
+            .collect();
+        let open = AmqpFrame::method(1, &Method::ChannelOpen).encode();
+        let open_ok = AmqpFrame::method(1, &Method::ChannelOpenOk).encode();
+        assert_eq!(frames, vec![("in".to_string(), open), ("out".to_string(), open_ok)]);
+
+        let as_log = Request {
+            query: "format=wirelog".into(),
+            ..get.clone()
+        };
+        let response = handle(&broker, &as_log);
+        let trace: Value = serde_json::from_str(&response.body).unwrap();
+        let log = base64::engine::general_purpose::STANDARD
+            .decode(trace["wirelog"].as_str().unwrap())
+            .unwrap();
+        let logged: Vec<(String, Vec<u8>)> = crate::wirelog::decode(&log)
+            .unwrap()
+            .into_iter()
+            .map(|frame| (frame.direction.as_str().to_string(), frame.bytes))
+            .collect();
+        assert_eq!(logged, frames);
+
+        assert_eq!(handle(&broker, &post("/api/connections/999/trace")).status, 404);
+        assert_eq!(handle(&broker, &post("/api/connections/abc/trace")).status, 400);
+        let delete = Request {
+            method: "DELETE".into(),
+            ..post(&path)
+        };
+        assert_eq!(handle(&broker, &delete).status, 405);
+    }
+
+    fn put(path: &str, credentials: (&str, &str), body: Value) -> Request {
+        Request {
+            method: "PUT".into(),
+            credentials: Some((credentials.0.into(), credentials.1.into())),
+            body: body.to_string().into_bytes(),
+            ..post(path)
+        }
+    }
+
+    /// Answers `Connection.Start` with PLAIN credentials and, if the broker
+    /// takes them, `Connection.Tune`. Otherwise returns the reply code the
+    /// broker closed the connection with.
+    async fn login(broker: &Arc<Broker>, name: &str, password: &str) -> Result<TestClient, u16> {
+        let mut client = TestClient::connect_to(broker.clone()).await;
+        client.recv_method().await;
+        let start_ok = Method::ConnectionStartOk {
+            client_properties: FieldTable::new(),
+            mechanism: "PLAIN".into(),
+            response: format!("\0{}\0{}", name, password).into_bytes(),
+            locale: "en_US".into(),
+        };
+        client.send_method(0, &start_ok).await;
+        match client.recv_method().await.1 {
+            Method::ConnectionTune {
+                frame_max, heartbeat, ..
+            } => {
+                let tune_ok = Method::ConnectionTuneOk {
+                    channel_max: 0,
+                    frame_max,
+                    heartbeat,
+                };
+                client.send_method(0, &tune_ok).await;
+                Ok(client)
+            }
+            Method::ConnectionClose { reply_code, .. } => Err(reply_code),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_users_changed_at_runtime() {
+        let broker = Arc::new(Broker::new(Config {
+            users: vec![UserConfig {
+                name: "admin".into(),
+                password: "admin-pw".into(),
+                tags: vec![ADMIN_TAG.into()],
+                vhosts: vec![DEFAULT_VHOST.into()],
+            }],
+            ..Default::default()
+        }));
+        let admin = ("admin", "admin-pw");
+        let alice = json!({ "password": "alice-pw", "tags": "monitoring" });
+        let anonymous = Request {
+            credentials: None,
+            ..put("/api/users/alice", admin, alice.clone())
+        };
+        assert_eq!(handle(&broker, &anonymous).status, 401);
+        assert_eq!(
+            handle(&broker, &put("/api/users/alice", ("admin", "wrong"), alice.clone())).status,
+            401
+        );
+        let response = handle(&broker, &put("/api/users/alice", admin, alice));
+        assert_eq!(response.status, 200, "{}", response.body);
+        assert_eq!(
+            login(&broker, "alice", "wrong").await.err(),
+            Some(reply_codes::ACCESS_REFUSED)
+        );
+        // Alice may not manage users herself.
+        let bob = json!({ "password": "bob-pw" });
+        assert_eq!(
+            handle(&broker, &put("/api/users/bob", ("alice", "alice-pw"), bob)).status,
+            403
+        );
+
+        // Without permissions she gets in but cannot open the vhost.
+        let mut client = login(&broker, "alice", "alice-pw").await.unwrap();
+        client.send_vhost_open(DEFAULT_VHOST).await;
+        client.expect_connection_close(reply_codes::ACCESS_REFUSED).await;
+        let permission = json!({ "configure": "", "write": "*", "read": "*" });
+        let response = handle(&broker, &put("/api/permissions/%2F/alice", admin, permission.clone()));
+        assert_eq!(response.status, 200, "{}", response.body);
+        assert_eq!(
+            handle(&broker, &put("/api/permissions/%2F/carol", admin, permission)).status,
+            404
+        );
+        let mut client = login(&broker, "alice", "alice-pw").await.unwrap();
+        client.open().await;
+
+        let delete = Request {
+            method: "DELETE".into(),
+            ..put("/api/users/alice", admin, Value::Null)
+        };
+        assert_eq!(handle(&broker, &delete).status, 200);
+        assert_eq!(handle(&broker, &delete).status, 404);
+        assert_eq!(
+            login(&broker, "alice", "alice-pw").await.err(),
+            Some(reply_codes::ACCESS_REFUSED)
+        );
+        // The connection she already had stays open.
+        client.open_channel(1).await;
+    }
+
+    #[test]
+    fn test_every_change_needs_an_administrator() {
+        let broker = Broker::new(Config {
+            users: ["admin", "alice"]
+                .map(|name| UserConfig {
+                    name: name.into(),
+                    password: format!("{}-pw", name),
+                    tags: if name == "admin" {
+                        vec![ADMIN_TAG.into()]
+                    } else {
+                        Vec::new()
+                    },
+                    vhosts: vec![DEFAULT_VHOST.into()],
+                })
+                .to_vec(),
+            ..Default::default()
+        });
+        work_queue(&broker);
+        let changes = [
+            ("POST", "/api/listeners/pause"),
+            ("POST", "/api/listeners/resume"),
+            ("POST", "/api/vhosts/%2F/drain"),
+            ("POST", "/api/vhosts/%2F/resume"),
+            ("POST", "/api/queues/purge"),
+            ("POST", "/api/definitions"),
+            ("PUT", "/api/policies/%2F/limit"),
+            ("DELETE", "/api/policies/%2F/limit"),
+            ("POST", "/api/connections/1/trace"),
+            ("PUT", "/api/users/bob"),
+            ("DELETE", "/api/users/bob"),
+            ("PUT", "/api/permissions/%2F/alice"),
+            // Not a change, but the export holds the password hashes.
+            ("GET", "/api/definitions"),
+        ];
+        for (method, path) in changes {
+            let anonymous = Request {
+                method: method.into(),
+                ..post(path)
+            };
+            assert_eq!(handle(&broker, &anonymous).status, 401, "{} {}", method, path);
+            let alice = Request {
+                credentials: Some(("alice".into(), "alice-pw".into())),
+                ..anonymous
+            };
+            assert_eq!(handle(&broker, &alice).status, 403, "{} {}", method, path);
+        }
+        assert!(!broker.listeners_paused());
+        // Reading needs no credentials, and the administrator gets through.
+        let queues = Request {
+            method: "GET".into(),
+            ..post("/api/queues")
+        };
+        assert_eq!(handle(&broker, &queues).status, 200);
+        let pause = Request {
+            credentials: Some(("admin".into(), "admin-pw".into())),
+            ..post("/api/listeners/pause")
+        };
+        assert_eq!(handle(&broker, &pause).status, 200);
+        assert!(broker.listeners_paused());
+    }
+
+    #[test]
+    fn test_users_need_a_changeable_store() {
+        let broker = Broker::new(Config::default());
+        let request = put("/api/users/alice", ("admin", "pw"), json!({ "password": "pw" }));
+        assert_eq!(handle(&broker, &request).status, 501);
+    }
+
+    #[test]
+    fn test_basic_credentials() {
+        assert_eq!(
+            basic_credentials(b"Basic YWRtaW46cGE6c3M="),
+            Some(("admin".into(), "pa:ss".into()))
+        );
+        assert_eq!(basic_credentials(b"Bearer abc"), None);
+        assert_eq!(basic_credentials(b"Basic !!"), None);
+    }
+
+    #[tokio::test]
+    async fn test_serve_answers_over_http() {
+        let broker = Arc::new(Broker::new(Config::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, broker.clone()));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"POST /api/vhosts/%2F/drain HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\n\r\n{}")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with(r#"{"drained":true,"vhost":"/"}"#), "{}", response);
+        assert!(broker.is_drained(DEFAULT_VHOST));
+    }
+}
@@ -0,0 +1,203 @@
+// src/message.rs
+
+//! The message as the broker routes, stores and delivers it.
+//!
+//! A publish arrives as a `Basic.Publish` frame, a content header and body
+//! frames; once assembled it travels through exchanges, queues and the store
+//! as a `Message`, and is split into frames again only when it is written
+//! to a client. What a queue knows about its copy, such as when it was
+//! enqueued and how often it was delivered, lives in `QueuedMessage`.
+
+use crate::error::AmqpError;
+use crate::methods::{Method, CLASS_BASIC};
+use crate::properties::{BasicProperties, ContentHeader};
+use crate::protocol::{AmqpFrame, FRAME_BODY, FRAME_HEADER, FRAME_METHOD, FRAME_OVERHEAD};
+use crate::reply_codes;
+
+/// A fully assembled published message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub exchange: String,
+    pub routing_key: String,
+    pub properties: BasicProperties,
+    pub body: Vec<u8>,
+}
+
+impl Message {
+    /// Estimated bytes the message takes in memory, which is what memory
+    /// limits, virtual host quotas and `Basic.Qos` prefetch sizes count:
+    /// the `Message` itself plus everything it keeps on the heap, the body,
+    /// names, properties and headers. It is summed from lengths without
+    /// encoding anything; allocator overhead and spare capacity are left out.
+    pub fn memory_footprint(&self) -> u64 {
+        let heap = self.exchange.len() + self.routing_key.len() + self.properties.heap_size() + self.body.len();
+        (std::mem::size_of::<Message>() + heap) as u64
+    }
+
+    /// The frames carrying the message on `channel` after `method`: the
+    /// method, a content header and the body, split so no frame is larger
+    /// than `frame_max`, where 0 means no limit.
+    pub fn into_frames(self, channel: u16, method: &Method, frame_max: u32) -> Vec<AmqpFrame> {
+        let header = ContentHeader {
+            class_id: CLASS_BASIC,
+            body_size: self.body.len() as u64,
+            properties: self.properties,
+        };
+        let mut frames = vec![AmqpFrame::method(channel, method), AmqpFrame::header(channel, &header)];
+        let chunk = match frame_max {
+            0 => self.body.len().max(1),
+            frame_max => (frame_max - FRAME_OVERHEAD) as usize,
+        };
+        frames.extend(self.body.chunks(chunk).map(|part| AmqpFrame::body(channel, part)));
+        frames
+    }
+
+    /// Assembles a message from the frames of one content-carrying method,
+    /// a `Basic.Publish`, `Return`, `Deliver` or `Get-Ok`, followed by its
+    /// content header and body frames. The inverse of `into_frames`.
+    pub fn from_frames(frames: &[AmqpFrame]) -> Result<Message, AmqpError> {
+        let malformed = |text: &str| {
+            AmqpError::connection(
+                reply_codes::UNEXPECTED_FRAME,
+                format!("UNEXPECTED_FRAME - {}", text),
+                0,
+                0,
+            )
+        };
+        let [method, header, bodies @ ..] = frames else {
+            return Err(malformed("a message needs a method and a content header"));
+        };
+        if method.frame_type != FRAME_METHOD || header.frame_type != FRAME_HEADER {
+            return Err(malformed("a message starts with a method and a content header"));
+        }
+        let (exchange, routing_key) = match Method::decode(&method.payload)? {
+            Method::BasicPublish {
+                exchange, routing_key, ..
+            }
+            | Method::BasicReturn {
+                exchange, routing_key, ..
+            }
+            | Method::BasicDeliver {
+                exchange, routing_key, ..
+            }
+            | Method::BasicGetOk {
+                exchange, routing_key, ..
+            } => (exchange, routing_key),
+            _ => return Err(malformed("the method carries no content")),
+        };
+        let header = ContentHeader::decode(&header.payload)?;
+        let mut body = Vec::new();
+        for frame in bodies {
+            if frame.frame_type != FRAME_BODY {
+                return Err(malformed("only body frames may follow the content header"));
+            }
+            body.extend_from_slice(&frame.payload);
+        }
+        if body.len() as u64 != header.body_size {
+            return Err(malformed("the body frames do not add up to the header's body size"));
+        }
+        Ok(Message {
+            exchange,
+            routing_key,
+            properties: header.properties,
+            body,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::field_table::{FieldTable, FieldValue};
+
+    fn sample() -> Message {
+        let mut headers = FieldTable::new();
+        headers.insert("attempt", FieldValue::LongInt(3));
+        Message {
+            exchange: "orders".into(),
+            routing_key: "orders.created".into(),
+            properties: BasicProperties {
+                content_type: Some("application/json".into()),
+                delivery_mode: Some(2),
+                headers: Some(headers),
+                ..Default::default()
+            },
+            body: (0..=255).collect(),
+        }
+    }
+
+    #[test]
+    fn test_frames_round_trip() {
+        let publish = Method::BasicPublish {
+            exchange: "orders".into(),
+            routing_key: "orders.created".into(),
+            mandatory: true,
+            immediate: false,
+        };
+        let frames = sample().into_frames(1, &publish, 4096);
+        assert_eq!(frames.len(), 3);
+        assert_eq!(Message::from_frames(&frames).unwrap(), sample());
+
+        // Split over several body frames, and as a delivery.
+        let deliver = Method::BasicDeliver {
+            consumer_tag: "ctag".into(),
+            delivery_tag: 1,
+            redelivered: false,
+            exchange: "orders".into(),
+            routing_key: "orders.created".into(),
+        };
+        let frames = sample().into_frames(1, &deliver, FRAME_OVERHEAD + 100);
+        assert_eq!(frames.len(), 5);
+        assert_eq!(Message::from_frames(&frames).unwrap(), sample());
+
+        let empty = Message {
+            body: Vec::new(),
+            ..sample()
+        };
+        let frames = empty.clone().into_frames(1, &publish, 0);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(Message::from_frames(&frames).unwrap(), empty);
+    }
+
+    #[test]
+    fn test_from_frames_rejects_incomplete_messages() {
+        let publish = Method::BasicPublish {
+            exchange: "orders".into(),
+            routing_key: "orders.created".into(),
+            mandatory: false,
+            immediate: false,
+        };
+        let mut frames = sample().into_frames(1, &publish, 0);
+        frames.pop();
+        let err = Message::from_frames(&frames).unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::UNEXPECTED_FRAME);
+        assert!(Message::from_frames(&frames[..1]).is_err());
+        let mut frames = sample().into_frames(1, &Method::BasicGetEmpty, 0);
+        frames.truncate(2);
+        assert!(Message::from_frames(&frames).is_err());
+    }
+
+    #[test]
+    fn test_footprint_grows_with_headers() {
+        let mut message = Message {
+            exchange: "amq.topic".into(),
+            routing_key: "orders.created".into(),
+            properties: BasicProperties::default(),
+            body: vec![0; 100],
+        };
+        let plain = message.memory_footprint();
+        assert!(plain > 100 + 9 + 14);
+
+        let mut headers = FieldTable::new();
+        headers.insert("trace-id", FieldValue::LongString(vec![b'x'; 32]));
+        message.properties.headers = Some(headers.clone());
+        let one_header = message.memory_footprint();
+        assert!(one_header >= plain + 8 + 32);
+
+        let mut nested = FieldTable::new();
+        nested.insert("hops", FieldValue::FieldArray(vec![FieldValue::LongLongInt(1); 10]));
+        headers.insert("route", FieldValue::FieldTable(nested));
+        message.properties.headers = Some(headers);
+        assert!(message.memory_footprint() > one_header + 10 * std::mem::size_of::<FieldValue>() as u64);
+    }
+}
@@ -0,0 +1,1306 @@
+// src/methods.rs
+
+//! Decoding and encoding of AMQP 0.9.1 method frame payloads.
+//!
+//! A method payload starts with a 2-byte class id and a 2-byte method id,
+//! followed by the method arguments.
+
+use bytes::BufMut;
+
+use crate::error::AmqpError;
+use crate::field_table::{parse_field_table_within, FieldTable, TableLimits};
+use crate::reply_codes;
+use crate::wire::{long, longlong, longstr, octet, put_longstr, put_shortstr, short, shortstr};
+
+pub const CLASS_CONNECTION: u16 = 10;
+pub const CLASS_CHANNEL: u16 = 20;
+pub const CLASS_EXCHANGE: u16 = 40;
+pub const CLASS_QUEUE: u16 = 50;
+pub const CLASS_BASIC: u16 = 60;
+pub const CLASS_CONFIRM: u16 = 85;
+pub const CLASS_TX: u16 = 90;
+
+/// Every method the broker implements, as (class id, method id). A method
+/// frame naming any other pair is answered with `NOT_IMPLEMENTED`.
+pub const IMPLEMENTED_METHODS: &[(u16, u16)] = &[
+    (CLASS_CONNECTION, 10),
+    (CLASS_CONNECTION, 11),
+    (CLASS_CONNECTION, 20),
+    (CLASS_CONNECTION, 21),
+    (CLASS_CONNECTION, 30),
+    (CLASS_CONNECTION, 31),
+    (CLASS_CONNECTION, 40),
+    (CLASS_CONNECTION, 41),
+    (CLASS_CONNECTION, 50),
+    (CLASS_CONNECTION, 51),
+    (CLASS_CONNECTION, 60),
+    (CLASS_CONNECTION, 61),
+    (CLASS_CHANNEL, 10),
+    (CLASS_CHANNEL, 11),
+    (CLASS_CHANNEL, 20),
+    (CLASS_CHANNEL, 21),
+    (CLASS_CHANNEL, 40),
+    (CLASS_CHANNEL, 41),
+    (CLASS_EXCHANGE, 10),
+    (CLASS_EXCHANGE, 11),
+    (CLASS_EXCHANGE, 20),
+    (CLASS_EXCHANGE, 21),
+    (CLASS_QUEUE, 10),
+    (CLASS_QUEUE, 11),
+    (CLASS_QUEUE, 20),
+    (CLASS_QUEUE, 21),
+    (CLASS_QUEUE, 40),
+    (CLASS_QUEUE, 41),
+    (CLASS_QUEUE, 50),
+    (CLASS_QUEUE, 51),
+    (CLASS_BASIC, 10),
+    (CLASS_BASIC, 11),
+    (CLASS_BASIC, 20),
+    (CLASS_BASIC, 21),
+    (CLASS_BASIC, 30),
+    (CLASS_BASIC, 31),
+    (CLASS_BASIC, 40),
+    (CLASS_BASIC, 50),
+    (CLASS_BASIC, 60),
+    (CLASS_BASIC, 70),
+    (CLASS_BASIC, 71),
+    (CLASS_BASIC, 72),
+    (CLASS_BASIC, 80),
+    (CLASS_BASIC, 110),
+    (CLASS_BASIC, 111),
+    (CLASS_BASIC, 120),
+    (CLASS_CONFIRM, 10),
+    (CLASS_CONFIRM, 11),
+    (CLASS_TX, 10),
+    (CLASS_TX, 11),
+    (CLASS_TX, 20),
+    (CLASS_TX, 21),
+    (CLASS_TX, 30),
+    (CLASS_TX, 31),
+];
+
+/// Whether `class_id.method_id` is in `IMPLEMENTED_METHODS`.
+pub fn is_implemented(class_id: u16, method_id: u16) -> bool {
+    IMPLEMENTED_METHODS.contains(&(class_id, method_id))
+}
+
+/// The class and method id a method frame payload starts with.
+pub fn method_id(payload: &[u8]) -> Option<(u16, u16)> {
+    match payload {
+        [c1, c2, m1, m2, ..] => Some((u16::from_be_bytes([*c1, *c2]), u16::from_be_bytes([*m1, *m2]))),
+        _ => None,
+    }
+}
+
+/// What the broker closes when a client sends a method it does not
+/// implement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownMethodClose {
+    /// The connection, as AMQP 0.9.1 has it for `NOT_IMPLEMENTED`.
+    #[default]
+    Connection,
+    /// Only the channel the method arrived on; methods on channel 0 still
+    /// close the connection.
+    Channel,
+}
+
+/// The `NOT_IMPLEMENTED` error for a method frame naming
+/// `class_id.method_id`, closing what `close` says.
+pub fn not_implemented(class_id: u16, method_id: u16, close: UnknownMethodClose) -> AmqpError {
+    let text = format!("NOT_IMPLEMENTED - method {}.{} is not supported", class_id, method_id);
+    match close {
+        UnknownMethodClose::Connection => {
+            AmqpError::connection(reply_codes::NOT_IMPLEMENTED, text, class_id, method_id)
+        }
+        UnknownMethodClose::Channel => AmqpError::channel(reply_codes::NOT_IMPLEMENTED, text, class_id, method_id),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Method {
+    ConnectionStart {
+        version_major: u8,
+        version_minor: u8,
+        server_properties: FieldTable,
+        mechanisms: Vec<u8>,
+        locales: Vec<u8>,
+    },
+    ConnectionStartOk {
+        client_properties: FieldTable,
+        mechanism: String,
+        response: Vec<u8>,
+        locale: String,
+    },
+    ConnectionSecure {
+        challenge: Vec<u8>,
+    },
+    ConnectionSecureOk {
+        response: Vec<u8>,
+    },
+    ConnectionTune {
+        channel_max: u16,
+        frame_max: u32,
+        heartbeat: u16,
+    },
+    ConnectionTuneOk {
+        channel_max: u16,
+        frame_max: u32,
+        heartbeat: u16,
+    },
+    ConnectionOpen {
+        virtual_host: String,
+    },
+    ConnectionOpenOk,
+    ConnectionClose {
+        reply_code: u16,
+        reply_text: String,
+        class_id: u16,
+        method_id: u16,
+    },
+    ConnectionCloseOk,
+    /// Tells a client that announced the `connection.blocked` capability
+    /// that the broker stopped reading its publishes.
+    ConnectionBlocked {
+        reason: String,
+    },
+    ConnectionUnblocked,
+    ChannelOpen,
+    ChannelOpenOk,
+    ChannelClose {
+        reply_code: u16,
+        reply_text: String,
+        class_id: u16,
+        method_id: u16,
+    },
+    ChannelCloseOk,
+    ChannelFlow {
+        active: bool,
+    },
+    ChannelFlowOk {
+        active: bool,
+    },
+    ExchangeDeclare {
+        exchange: String,
+        kind: String,
+        passive: bool,
+        durable: bool,
+        auto_delete: bool,
+        internal: bool,
+        nowait: bool,
+        arguments: FieldTable,
+    },
+    ExchangeDeclareOk,
+    ExchangeDelete {
+        exchange: String,
+        if_unused: bool,
+        nowait: bool,
+    },
+    ExchangeDeleteOk,
+    QueueDeclare {
+        queue: String,
+        passive: bool,
+        durable: bool,
+        exclusive: bool,
+        auto_delete: bool,
+        nowait: bool,
+        arguments: FieldTable,
+    },
+    QueueDeclareOk {
+        queue: String,
+        message_count: u32,
+        consumer_count: u32,
+    },
+    QueueBind {
+        queue: String,
+        exchange: String,
+        routing_key: String,
+        nowait: bool,
+        arguments: FieldTable,
+    },
+    QueueBindOk,
+    QueueUnbind {
+        queue: String,
+        exchange: String,
+        routing_key: String,
+        arguments: FieldTable,
+    },
+    QueueUnbindOk,
+    QueueDelete {
+        queue: String,
+        if_unused: bool,
+        if_empty: bool,
+        nowait: bool,
+    },
+    QueueDeleteOk {
+        message_count: u32,
+    },
+    BasicQos {
+        prefetch_size: u32,
+        prefetch_count: u16,
+        global: bool,
+    },
+    BasicQosOk,
+    BasicConsume {
+        queue: String,
+        consumer_tag: String,
+        no_local: bool,
+        no_ack: bool,
+        exclusive: bool,
+        nowait: bool,
+        arguments: FieldTable,
+    },
+    BasicConsumeOk {
+        consumer_tag: String,
+    },
+    BasicCancel {
+        consumer_tag: String,
+        nowait: bool,
+    },
+    BasicCancelOk {
+        consumer_tag: String,
+    },
+    BasicPublish {
+        exchange: String,
+        routing_key: String,
+        mandatory: bool,
+        immediate: bool,
+    },
+    BasicReturn {
+        reply_code: u16,
+        reply_text: String,
+        exchange: String,
+        routing_key: String,
+    },
+    BasicDeliver {
+        consumer_tag: String,
+        delivery_tag: u64,
+        redelivered: bool,
+        exchange: String,
+        routing_key: String,
+    },
+    BasicGet {
+        queue: String,
+        no_ack: bool,
+    },
+    BasicGetOk {
+        delivery_tag: u64,
+        redelivered: bool,
+        exchange: String,
+        routing_key: String,
+        message_count: u32,
+    },
+    BasicGetEmpty,
+    BasicAck {
+        delivery_tag: u64,
+        multiple: bool,
+    },
+    BasicNack {
+        delivery_tag: u64,
+        multiple: bool,
+        requeue: bool,
+    },
+    BasicRecover {
+        requeue: bool,
+    },
+    BasicRecoverOk,
+    ConfirmSelect {
+        nowait: bool,
+    },
+    ConfirmSelectOk,
+    TxSelect,
+    TxSelectOk,
+    TxCommit,
+    TxCommitOk,
+    TxRollback,
+    TxRollbackOk,
+}
+
+fn syntax_error(class_id: u16, method_id: u16) -> AmqpError {
+    AmqpError::connection(
+        reply_codes::SYNTAX_ERROR,
+        "SYNTAX_ERROR - malformed method arguments",
+        class_id,
+        method_id,
+    )
+}
+
+impl Method {
+    /// Returns the `(class_id, method_id)` pair identifying this method.
+    pub fn id(&self) -> (u16, u16) {
+        match self {
+            Method::ConnectionStart { .. } => (CLASS_CONNECTION, 10),
+            Method::ConnectionStartOk { .. } => (CLASS_CONNECTION, 11),
+            Method::ConnectionSecure { .. } => (CLASS_CONNECTION, 20),
+            Method::ConnectionSecureOk { .. } => (CLASS_CONNECTION, 21),
+            Method::ConnectionTune { .. } => (CLASS_CONNECTION, 30),
+            Method::ConnectionTuneOk { .. } => (CLASS_CONNECTION, 31),
+            Method::ConnectionOpen { .. } => (CLASS_CONNECTION, 40),
+            Method::ConnectionOpenOk => (CLASS_CONNECTION, 41),
+            Method::ConnectionClose { .. } => (CLASS_CONNECTION, 50),
+            Method::ConnectionCloseOk => (CLASS_CONNECTION, 51),
+            Method::ConnectionBlocked { .. } => (CLASS_CONNECTION, 60),
+            Method::ConnectionUnblocked => (CLASS_CONNECTION, 61),
+            Method::ChannelOpen => (CLASS_CHANNEL, 10),
+            Method::ChannelOpenOk => (CLASS_CHANNEL, 11),
+            Method::ChannelClose { .. } => (CLASS_CHANNEL, 40),
+            Method::ChannelCloseOk => (CLASS_CHANNEL, 41),
+            Method::ChannelFlow { .. } => (CLASS_CHANNEL, 20),
+            Method::ChannelFlowOk { .. } => (CLASS_CHANNEL, 21),
+            Method::ExchangeDeclare { .. } => (CLASS_EXCHANGE, 10),
+            Method::ExchangeDeclareOk => (CLASS_EXCHANGE, 11),
+            Method::ExchangeDelete { .. } => (CLASS_EXCHANGE, 20),
+            Method::ExchangeDeleteOk => (CLASS_EXCHANGE, 21),
+            Method::QueueDeclare { .. } => (CLASS_QUEUE, 10),
+            Method::QueueDeclareOk { .. } => (CLASS_QUEUE, 11),
+            Method::QueueBind { .. } => (CLASS_QUEUE, 20),
+            Method::QueueBindOk => (CLASS_QUEUE, 21),
+            Method::QueueUnbind { .. } => (CLASS_QUEUE, 50),
+            Method::QueueUnbindOk => (CLASS_QUEUE, 51),
+            Method::QueueDelete { .. } => (CLASS_QUEUE, 40),
+            Method::QueueDeleteOk { .. } => (CLASS_QUEUE, 41),
+            Method::BasicQos { .. } => (CLASS_BASIC, 10),
+            Method::BasicQosOk => (CLASS_BASIC, 11),
+            Method::BasicConsume { .. } => (CLASS_BASIC, 20),
+            Method::BasicConsumeOk { .. } => (CLASS_BASIC, 21),
+            Method::BasicCancel { .. } => (CLASS_BASIC, 30),
+            Method::BasicCancelOk { .. } => (CLASS_BASIC, 31),
+            Method::BasicPublish { .. } => (CLASS_BASIC, 40),
+            Method::BasicReturn { .. } => (CLASS_BASIC, 50),
+            Method::BasicDeliver { .. } => (CLASS_BASIC, 60),
+            Method::BasicGet { .. } => (CLASS_BASIC, 70),
+            Method::BasicGetOk { .. } => (CLASS_BASIC, 71),
+            Method::BasicGetEmpty => (CLASS_BASIC, 72),
+            Method::BasicAck { .. } => (CLASS_BASIC, 80),
+            Method::BasicRecover { .. } => (CLASS_BASIC, 110),
+            Method::BasicRecoverOk => (CLASS_BASIC, 111),
+            Method::BasicNack { .. } => (CLASS_BASIC, 120),
+            Method::ConfirmSelect { .. } => (CLASS_CONFIRM, 10),
+            Method::ConfirmSelectOk => (CLASS_CONFIRM, 11),
+            Method::TxSelect => (CLASS_TX, 10),
+            Method::TxSelectOk => (CLASS_TX, 11),
+            Method::TxCommit => (CLASS_TX, 20),
+            Method::TxCommitOk => (CLASS_TX, 21),
+            Method::TxRollback => (CLASS_TX, 30),
+            Method::TxRollbackOk => (CLASS_TX, 31),
+        }
+    }
+
+    /// Decodes a method frame payload.
+    pub fn decode(payload: &[u8]) -> Result<Method, AmqpError> {
+        Self::decode_within(payload, &TableLimits::default())
+    }
+
+    /// Decodes a method frame payload whose field tables must stay within
+    /// `limits`.
+    pub fn decode_within(payload: &[u8], limits: &TableLimits) -> Result<Method, AmqpError> {
+        let (args, (class_id, method_id)) = match nom::sequence::tuple((short, short))(payload) {
+            Ok(res) => res,
+            Err(_) => return Err(syntax_error(0, 0)),
+        };
+        Self::decode_args(class_id, method_id, args, limits).map_err(|e| match e {
+            DecodeError::Unknown => not_implemented(class_id, method_id, UnknownMethodClose::Connection),
+            DecodeError::Malformed => syntax_error(class_id, method_id),
+        })
+    }
+
+    fn decode_args(class_id: u16, method_id: u16, args: &[u8], limits: &TableLimits) -> Result<Method, DecodeError> {
+        let method = match (class_id, method_id) {
+            (CLASS_CONNECTION, 10) => {
+                let (args, version_major) = octet(args)?;
+                let (args, version_minor) = octet(args)?;
+                let (args, server_properties) = parse_field_table_within(args, limits)?;
+                let (args, mechanisms) = longstr(args)?;
+                let (_, locales) = longstr(args)?;
+                Method::ConnectionStart {
+                    version_major,
+                    version_minor,
+                    server_properties,
+                    mechanisms,
+                    locales,
+                }
+            }
+            (CLASS_CONNECTION, 11) => {
+                let (args, client_properties) = parse_field_table_within(args, limits)?;
+                let (args, mechanism) = shortstr(args)?;
+                let (args, response) = longstr(args)?;
+                let (_, locale) = shortstr(args)?;
+                Method::ConnectionStartOk {
+                    client_properties,
+                    mechanism,
+                    response,
+                    locale,
+                }
+            }
+            (CLASS_CONNECTION, 20) => {
+                let (_, challenge) = longstr(args)?;
+                Method::ConnectionSecure { challenge }
+            }
+            (CLASS_CONNECTION, 21) => {
+                let (_, response) = longstr(args)?;
+                Method::ConnectionSecureOk { response }
+            }
+            (CLASS_CONNECTION, 30) | (CLASS_CONNECTION, 31) => {
+                let (args, channel_max) = short(args)?;
+                let (args, frame_max) = long(args)?;
+                let (_, heartbeat) = short(args)?;
+                if method_id == 30 {
+                    Method::ConnectionTune {
+                        channel_max,
+                        frame_max,
+                        heartbeat,
+                    }
+                } else {
+                    Method::ConnectionTuneOk {
+                        channel_max,
+                        frame_max,
+                        heartbeat,
+                    }
+                }
+            }
+            (CLASS_CONNECTION, 40) => {
+                let (args, virtual_host) = shortstr(args)?;
+                // reserved-1 (capabilities) and reserved-2 (insist)
+                let (args, _) = shortstr(args)?;
+                octet(args)?;
+                Method::ConnectionOpen { virtual_host }
+            }
+            (CLASS_CONNECTION, 41) => Method::ConnectionOpenOk,
+            (CLASS_CONNECTION, 50) => {
+                let (args, reply_code) = short(args)?;
+                let (args, reply_text) = shortstr(args)?;
+                let (args, class_id) = short(args)?;
+                let (_, method_id) = short(args)?;
+                Method::ConnectionClose {
+                    reply_code,
+                    reply_text,
+                    class_id,
+                    method_id,
+                }
+            }
+            (CLASS_CONNECTION, 51) => Method::ConnectionCloseOk,
+            (CLASS_CONNECTION, 60) => {
+                let (_, reason) = shortstr(args)?;
+                Method::ConnectionBlocked { reason }
+            }
+            (CLASS_CONNECTION, 61) => Method::ConnectionUnblocked,
+            (CLASS_CHANNEL, 10) => {
+                // reserved-1 (out-of-band), deprecated and ignored
+                shortstr(args)?;
+                Method::ChannelOpen
+            }
+            (CLASS_CHANNEL, 11) => Method::ChannelOpenOk,
+            (CLASS_CHANNEL, 40) => {
+                let (args, reply_code) = short(args)?;
+                let (args, reply_text) = shortstr(args)?;
+                let (args, class_id) = short(args)?;
+                let (_, method_id) = short(args)?;
+                Method::ChannelClose {
+                    reply_code,
+                    reply_text,
+                    class_id,
+                    method_id,
+                }
+            }
+            (CLASS_CHANNEL, 41) => Method::ChannelCloseOk,
+            (CLASS_CHANNEL, 20) => {
+                let (_, bits) = octet(args)?;
+                Method::ChannelFlow { active: bits & 1 != 0 }
+            }
+            (CLASS_CHANNEL, 21) => {
+                let (_, bits) = octet(args)?;
+                Method::ChannelFlowOk { active: bits & 1 != 0 }
+            }
+            (CLASS_EXCHANGE, 10) => {
+                let (args, _reserved) = short(args)?;
+                let (args, exchange) = shortstr(args)?;
+                let (args, kind) = shortstr(args)?;
+                let (args, bits) = octet(args)?;
+                let (_, arguments) = parse_field_table_within(args, limits)?;
+                Method::ExchangeDeclare {
+                    exchange,
+                    kind,
+                    passive: bits & 0x01 != 0,
+                    durable: bits & 0x02 != 0,
+                    auto_delete: bits & 0x04 != 0,
+                    internal: bits & 0x08 != 0,
+                    nowait: bits & 0x10 != 0,
+                    arguments,
+                }
+            }
+            (CLASS_EXCHANGE, 11) => Method::ExchangeDeclareOk,
+            (CLASS_EXCHANGE, 20) => {
+                let (args, _reserved) = short(args)?;
+                let (args, exchange) = shortstr(args)?;
+                let (_, bits) = octet(args)?;
+                Method::ExchangeDelete {
+                    exchange,
+                    if_unused: bits & 0x01 != 0,
+                    nowait: bits & 0x02 != 0,
+                }
+            }
+            (CLASS_EXCHANGE, 21) => Method::ExchangeDeleteOk,
+            (CLASS_QUEUE, 10) => {
+                let (args, _reserved) = short(args)?;
+                let (args, queue) = shortstr(args)?;
+                let (args, bits) = octet(args)?;
+                let (_, arguments) = parse_field_table_within(args, limits)?;
+                Method::QueueDeclare {
+                    queue,
+                    passive: bits & 0x01 != 0,
+                    durable: bits & 0x02 != 0,
+                    exclusive: bits & 0x04 != 0,
+                    auto_delete: bits & 0x08 != 0,
+                    nowait: bits & 0x10 != 0,
+                    arguments,
+                }
+            }
+            (CLASS_QUEUE, 11) => {
+                let (args, queue) = shortstr(args)?;
+                let (args, message_count) = long(args)?;
+                let (_, consumer_count) = long(args)?;
+                Method::QueueDeclareOk {
+                    queue,
+                    message_count,
+                    consumer_count,
+                }
+            }
+            (CLASS_QUEUE, 20) => {
+                let (args, _reserved) = short(args)?;
+                let (args, queue) = shortstr(args)?;
+                let (args, exchange) = shortstr(args)?;
+                let (args, routing_key) = shortstr(args)?;
+                let (args, bits) = octet(args)?;
+                let (_, arguments) = parse_field_table_within(args, limits)?;
+                Method::QueueBind {
+                    queue,
+                    exchange,
+                    routing_key,
+                    nowait: bits & 0x01 != 0,
+                    arguments,
+                }
+            }
+            (CLASS_QUEUE, 21) => Method::QueueBindOk,
+            (CLASS_QUEUE, 50) => {
+                let (args, _reserved) = short(args)?;
+                let (args, queue) = shortstr(args)?;
+                let (args, exchange) = shortstr(args)?;
+                let (args, routing_key) = shortstr(args)?;
+                let (_, arguments) = parse_field_table_within(args, limits)?;
+                Method::QueueUnbind {
+                    queue,
+                    exchange,
+                    routing_key,
+                    arguments,
+                }
+            }
+            (CLASS_QUEUE, 51) => Method::QueueUnbindOk,
+            (CLASS_QUEUE, 40) => {
+                let (args, _reserved) = short(args)?;
+                let (args, queue) = shortstr(args)?;
+                let (_, bits) = octet(args)?;
+                Method::QueueDelete {
+                    queue,
+                    if_unused: bits & 0x01 != 0,
+                    if_empty: bits & 0x02 != 0,
+                    nowait: bits & 0x04 != 0,
+                }
+            }
+            (CLASS_QUEUE, 41) => {
+                let (_, message_count) = long(args)?;
+                Method::QueueDeleteOk { message_count }
+            }
+            (CLASS_BASIC, 10) => {
+                let (args, prefetch_size) = long(args)?;
+                let (args, prefetch_count) = short(args)?;
+                let (_, bits) = octet(args)?;
+                Method::BasicQos {
+                    prefetch_size,
+                    prefetch_count,
+                    global: bits & 1 != 0,
+                }
+            }
+            (CLASS_BASIC, 11) => Method::BasicQosOk,
+            (CLASS_BASIC, 20) => {
+                let (args, _reserved) = short(args)?;
+                let (args, queue) = shortstr(args)?;
+                let (args, consumer_tag) = shortstr(args)?;
+                let (args, bits) = octet(args)?;
+                let (_, arguments) = parse_field_table_within(args, limits)?;
+                Method::BasicConsume {
+                    queue,
+                    consumer_tag,
+                    no_local: bits & 0x01 != 0,
+                    no_ack: bits & 0x02 != 0,
+                    exclusive: bits & 0x04 != 0,
+                    nowait: bits & 0x08 != 0,
+                    arguments,
+                }
+            }
+            (CLASS_BASIC, 21) => {
+                let (_, consumer_tag) = shortstr(args)?;
+                Method::BasicConsumeOk { consumer_tag }
+            }
+            (CLASS_BASIC, 30) => {
+                let (args, consumer_tag) = shortstr(args)?;
+                let (_, bits) = octet(args)?;
+                Method::BasicCancel {
+                    consumer_tag,
+                    nowait: bits & 1 != 0,
+                }
+            }
+            (CLASS_BASIC, 31) => {
+                let (_, consumer_tag) = shortstr(args)?;
+                Method::BasicCancelOk { consumer_tag }
+            }
+            (CLASS_BASIC, 40) => {
+                let (args, _reserved) = short(args)?;
+                let (args, exchange) = shortstr(args)?;
+                let (args, routing_key) = shortstr(args)?;
+                let (_, bits) = octet(args)?;
+                Method::BasicPublish {
+                    exchange,
+                    routing_key,
+                    mandatory: bits & 0x01 != 0,
+                    immediate: bits & 0x02 != 0,
+                }
+            }
+            (CLASS_BASIC, 50) => {
+                let (args, reply_code) = short(args)?;
+                let (args, reply_text) = shortstr(args)?;
+                let (args, exchange) = shortstr(args)?;
+                let (_, routing_key) = shortstr(args)?;
+                Method::BasicReturn {
+                    reply_code,
+                    reply_text,
+                    exchange,
+                    routing_key,
+                }
+            }
+            (CLASS_BASIC, 60) => {
+                let (args, consumer_tag) = shortstr(args)?;
+                let (args, delivery_tag) = longlong(args)?;
+                let (args, bits) = octet(args)?;
+                let (args, exchange) = shortstr(args)?;
+                let (_, routing_key) = shortstr(args)?;
+                Method::BasicDeliver {
+                    consumer_tag,
+                    delivery_tag,
+                    redelivered: bits & 1 != 0,
+                    exchange,
+                    routing_key,
+                }
+            }
+            (CLASS_BASIC, 70) => {
+                let (args, _reserved) = short(args)?;
+                let (args, queue) = shortstr(args)?;
+                let (_, bits) = octet(args)?;
+                Method::BasicGet {
+                    queue,
+                    no_ack: bits & 1 != 0,
+                }
+            }
+            (CLASS_BASIC, 71) => {
+                let (args, delivery_tag) = longlong(args)?;
+                let (args, bits) = octet(args)?;
+                let (args, exchange) = shortstr(args)?;
+                let (args, routing_key) = shortstr(args)?;
+                let (_, message_count) = long(args)?;
+                Method::BasicGetOk {
+                    delivery_tag,
+                    redelivered: bits & 1 != 0,
+                    exchange,
+                    routing_key,
+                    message_count,
+                }
+            }
+            (CLASS_BASIC, 72) => {
+                // reserved-1 (cluster-id)
+                shortstr(args)?;
+                Method::BasicGetEmpty
+            }
+            (CLASS_BASIC, 80) => {
+                let (args, delivery_tag) = longlong(args)?;
+                let (_, bits) = octet(args)?;
+                Method::BasicAck {
+                    delivery_tag,
+                    multiple: bits & 1 != 0,
+                }
+            }
+            (CLASS_BASIC, 110) => {
+                let (_, bits) = octet(args)?;
+                Method::BasicRecover { requeue: bits & 1 != 0 }
+            }
+            (CLASS_BASIC, 111) => Method::BasicRecoverOk,
+            (CLASS_BASIC, 120) => {
+                let (args, delivery_tag) = longlong(args)?;
+                let (_, bits) = octet(args)?;
+                Method::BasicNack {
+                    delivery_tag,
+                    multiple: bits & 1 != 0,
+                    requeue: bits & 2 != 0,
+                }
+            }
+            (CLASS_CONFIRM, 10) => {
+                let (_, bits) = octet(args)?;
+                Method::ConfirmSelect { nowait: bits & 1 != 0 }
+            }
+            (CLASS_CONFIRM, 11) => Method::ConfirmSelectOk,
+            (CLASS_TX, 10) => Method::TxSelect,
+            (CLASS_TX, 11) => Method::TxSelectOk,
+            (CLASS_TX, 20) => Method::TxCommit,
+            (CLASS_TX, 21) => Method::TxCommitOk,
+            (CLASS_TX, 30) => Method::TxRollback,
+            (CLASS_TX, 31) => Method::TxRollbackOk,
+            _ => return Err(DecodeError::Unknown),
+        };
+        Ok(method)
+    }
+
+    /// Encodes this method into a method frame payload.
+    pub fn encode(&self) -> Vec<u8> {
+        let (class_id, method_id) = self.id();
+        let mut buf = Vec::new();
+        buf.put_u16(class_id);
+        buf.put_u16(method_id);
+        match self {
+            Method::ConnectionStart {
+                version_major,
+                version_minor,
+                server_properties,
+                mechanisms,
+                locales,
+            } => {
+                buf.put_u8(*version_major);
+                buf.put_u8(*version_minor);
+                server_properties.encode(&mut buf);
+                put_longstr(&mut buf, mechanisms);
+                put_longstr(&mut buf, locales);
+            }
+            Method::ConnectionStartOk {
+                client_properties,
+                mechanism,
+                response,
+                locale,
+            } => {
+                client_properties.encode(&mut buf);
+                put_shortstr(&mut buf, mechanism);
+                put_longstr(&mut buf, response);
+                put_shortstr(&mut buf, locale);
+            }
+            Method::ConnectionSecure { challenge } => put_longstr(&mut buf, challenge),
+            Method::ConnectionSecureOk { response } => put_longstr(&mut buf, response),
+            Method::ConnectionTune {
+                channel_max,
+                frame_max,
+                heartbeat,
+            }
+            | Method::ConnectionTuneOk {
+                channel_max,
+                frame_max,
+                heartbeat,
+            } => {
+                buf.put_u16(*channel_max);
+                buf.put_u32(*frame_max);
+                buf.put_u16(*heartbeat);
+            }
+            Method::ConnectionOpen { virtual_host } => {
+                put_shortstr(&mut buf, virtual_host);
+                put_shortstr(&mut buf, "");
+                buf.put_u8(0);
+            }
+            Method::ConnectionOpenOk => put_shortstr(&mut buf, ""),
+            Method::ConnectionClose {
+                reply_code,
+                reply_text,
+                class_id,
+                method_id,
+            } => {
+                buf.put_u16(*reply_code);
+                put_shortstr(&mut buf, reply_text);
+                buf.put_u16(*class_id);
+                buf.put_u16(*method_id);
+            }
+            Method::ConnectionBlocked { reason } => put_shortstr(&mut buf, reason),
+            Method::ChannelOpen => put_shortstr(&mut buf, ""),
+            Method::ChannelOpenOk => put_longstr(&mut buf, b""),
+            Method::ChannelClose {
+                reply_code,
+                reply_text,
+                class_id,
+                method_id,
+            } => {
+                buf.put_u16(*reply_code);
+                put_shortstr(&mut buf, reply_text);
+                buf.put_u16(*class_id);
+                buf.put_u16(*method_id);
+            }
+            Method::ExchangeDeclare {
+                exchange,
+                kind,
+                passive,
+                durable,
+                auto_delete,
+                internal,
+                nowait,
+                arguments,
+            } => {
+                buf.put_u16(0);
+                put_shortstr(&mut buf, exchange);
+                put_shortstr(&mut buf, kind);
+                buf.put_u8(bits(&[*passive, *durable, *auto_delete, *internal, *nowait]));
+                arguments.encode(&mut buf);
+            }
+            Method::ExchangeDelete {
+                exchange,
+                if_unused,
+                nowait,
+            } => {
+                buf.put_u16(0);
+                put_shortstr(&mut buf, exchange);
+                buf.put_u8(bits(&[*if_unused, *nowait]));
+            }
+            Method::QueueBind {
+                queue,
+                exchange,
+                routing_key,
+                nowait,
+                arguments,
+            } => {
+                buf.put_u16(0);
+                put_shortstr(&mut buf, queue);
+                put_shortstr(&mut buf, exchange);
+                put_shortstr(&mut buf, routing_key);
+                buf.put_u8(*nowait as u8);
+                arguments.encode(&mut buf);
+            }
+            Method::QueueUnbind {
+                queue,
+                exchange,
+                routing_key,
+                arguments,
+            } => {
+                buf.put_u16(0);
+                put_shortstr(&mut buf, queue);
+                put_shortstr(&mut buf, exchange);
+                put_shortstr(&mut buf, routing_key);
+                arguments.encode(&mut buf);
+            }
+            Method::QueueDeclare {
+                queue,
+                passive,
+                durable,
+                exclusive,
+                auto_delete,
+                nowait,
+                arguments,
+            } => {
+                buf.put_u16(0);
+                put_shortstr(&mut buf, queue);
+                buf.put_u8(bits(&[*passive, *durable, *exclusive, *auto_delete, *nowait]));
+                arguments.encode(&mut buf);
+            }
+            Method::QueueDeclareOk {
+                queue,
+                message_count,
+                consumer_count,
+            } => {
+                put_shortstr(&mut buf, queue);
+                buf.put_u32(*message_count);
+                buf.put_u32(*consumer_count);
+            }
+            Method::QueueDelete {
+                queue,
+                if_unused,
+                if_empty,
+                nowait,
+            } => {
+                buf.put_u16(0);
+                put_shortstr(&mut buf, queue);
+                buf.put_u8(bits(&[*if_unused, *if_empty, *nowait]));
+            }
+            Method::QueueDeleteOk { message_count } => buf.put_u32(*message_count),
+            Method::ChannelFlow { active } | Method::ChannelFlowOk { active } => buf.put_u8(*active as u8),
+            Method::BasicQos {
+                prefetch_size,
+                prefetch_count,
+                global,
+            } => {
+                buf.put_u32(*prefetch_size);
+                buf.put_u16(*prefetch_count);
+                buf.put_u8(*global as u8);
+            }
+            Method::BasicConsume {
+                queue,
+                consumer_tag,
+                no_local,
+                no_ack,
+                exclusive,
+                nowait,
+                arguments,
+            } => {
+                buf.put_u16(0);
+                put_shortstr(&mut buf, queue);
+                put_shortstr(&mut buf, consumer_tag);
+                buf.put_u8(bits(&[*no_local, *no_ack, *exclusive, *nowait]));
+                arguments.encode(&mut buf);
+            }
+            Method::BasicConsumeOk { consumer_tag } | Method::BasicCancelOk { consumer_tag } => {
+                put_shortstr(&mut buf, consumer_tag)
+            }
+            Method::BasicCancel { consumer_tag, nowait } => {
+                put_shortstr(&mut buf, consumer_tag);
+                buf.put_u8(*nowait as u8);
+            }
+            Method::BasicPublish {
+                exchange,
+                routing_key,
+                mandatory,
+                immediate,
+            } => {
+                buf.put_u16(0);
+                put_shortstr(&mut buf, exchange);
+                put_shortstr(&mut buf, routing_key);
+                buf.put_u8(bits(&[*mandatory, *immediate]));
+            }
+            Method::BasicReturn {
+                reply_code,
+                reply_text,
+                exchange,
+                routing_key,
+            } => {
+                buf.put_u16(*reply_code);
+                put_shortstr(&mut buf, reply_text);
+                put_shortstr(&mut buf, exchange);
+                put_shortstr(&mut buf, routing_key);
+            }
+            Method::BasicDeliver {
+                consumer_tag,
+                delivery_tag,
+                redelivered,
+                exchange,
+                routing_key,
+            } => {
+                put_shortstr(&mut buf, consumer_tag);
+                buf.put_u64(*delivery_tag);
+                buf.put_u8(*redelivered as u8);
+                put_shortstr(&mut buf, exchange);
+                put_shortstr(&mut buf, routing_key);
+            }
+            Method::BasicGet { queue, no_ack } => {
+                buf.put_u16(0);
+                put_shortstr(&mut buf, queue);
+                buf.put_u8(*no_ack as u8);
+            }
+            Method::BasicGetOk {
+                delivery_tag,
+                redelivered,
+                exchange,
+                routing_key,
+                message_count,
+            } => {
+                buf.put_u64(*delivery_tag);
+                buf.put_u8(*redelivered as u8);
+                put_shortstr(&mut buf, exchange);
+                put_shortstr(&mut buf, routing_key);
+                buf.put_u32(*message_count);
+            }
+            Method::BasicGetEmpty => put_shortstr(&mut buf, ""),
+            Method::BasicAck { delivery_tag, multiple } => {
+                buf.put_u64(*delivery_tag);
+                buf.put_u8(*multiple as u8);
+            }
+            Method::BasicNack {
+                delivery_tag,
+                multiple,
+                requeue,
+            } => {
+                buf.put_u64(*delivery_tag);
+                buf.put_u8(*multiple as u8 | (*requeue as u8) << 1);
+            }
+            Method::BasicRecover { requeue } => buf.put_u8(*requeue as u8),
+            Method::ConfirmSelect { nowait } => buf.put_u8(*nowait as u8),
+            Method::ConnectionCloseOk
+            | Method::ConnectionUnblocked
+            | Method::ChannelCloseOk
+            | Method::ExchangeDeclareOk
+            | Method::ExchangeDeleteOk
+            | Method::QueueBindOk
+            | Method::QueueUnbindOk
+            | Method::ConfirmSelectOk
+            | Method::BasicQosOk
+            | Method::BasicRecoverOk
+            | Method::TxSelect
+            | Method::TxSelectOk
+            | Method::TxCommit
+            | Method::TxCommitOk
+            | Method::TxRollback
+            | Method::TxRollbackOk => {}
+        }
+        buf
+    }
+}
+
+/// Packs consecutive bit arguments into a single octet, least significant first.
+fn bits(flags: &[bool]) -> u8 {
+    flags
+        .iter()
+        .enumerate()
+        .fold(0, |acc, (i, &flag)| acc | ((flag as u8) << i))
+}
+
+enum DecodeError {
+    Unknown,
+    Malformed,
+}
+
+impl<E> From<nom::Err<E>> for DecodeError {
+    fn from(_: nom::Err<E>) -> Self {
+        DecodeError::Malformed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_roundtrip() {
+        let methods = vec![
+            Method::ConnectionStartOk {
+                client_properties: FieldTable::new(),
+                mechanism: "PLAIN".into(),
+                response: b"\0guest\0guest".to_vec(),
+                locale: "en_US".into(),
+            },
+            Method::ConnectionSecure {
+                challenge: b"r=nonce".to_vec(),
+            },
+            Method::ConnectionSecureOk {
+                response: b"c=biws".to_vec(),
+            },
+            Method::ConnectionTuneOk {
+                channel_max: 2047,
+                frame_max: 131072,
+                heartbeat: 60,
+            },
+            Method::ConnectionOpen {
+                virtual_host: "/".into(),
+            },
+            Method::ConnectionBlocked {
+                reason: "low on disk".into(),
+            },
+            Method::ConnectionUnblocked,
+            Method::ChannelOpen,
+            Method::ChannelClose {
+                reply_code: 406,
+                reply_text: "PRECONDITION_FAILED".into(),
+                class_id: CLASS_TX,
+                method_id: 10,
+            },
+            Method::QueueDeclare {
+                queue: "orders".into(),
+                passive: false,
+                durable: true,
+                exclusive: false,
+                auto_delete: true,
+                nowait: false,
+                arguments: FieldTable::new(),
+            },
+            Method::ExchangeDelete {
+                exchange: "orders".into(),
+                if_unused: true,
+                nowait: false,
+            },
+            Method::QueueDelete {
+                queue: "orders".into(),
+                if_unused: false,
+                if_empty: true,
+                nowait: false,
+            },
+            Method::QueueDeleteOk { message_count: 3 },
+            Method::QueueUnbind {
+                queue: "orders".into(),
+                exchange: "amq.headers".into(),
+                routing_key: "".into(),
+                arguments: FieldTable::new(),
+            },
+            Method::BasicConsume {
+                queue: "orders".into(),
+                consumer_tag: "ctag-1".into(),
+                no_local: false,
+                no_ack: true,
+                exclusive: true,
+                nowait: false,
+                arguments: FieldTable::new(),
+            },
+            Method::BasicCancel {
+                consumer_tag: "ctag-1".into(),
+                nowait: true,
+            },
+            Method::BasicPublish {
+                exchange: "amq.topic".into(),
+                routing_key: "orders.created".into(),
+                mandatory: true,
+                immediate: false,
+            },
+            Method::BasicDeliver {
+                consumer_tag: "ctag-1".into(),
+                delivery_tag: 3,
+                redelivered: true,
+                exchange: "amq.direct".into(),
+                routing_key: "orders".into(),
+            },
+            Method::BasicGet {
+                queue: "orders".into(),
+                no_ack: true,
+            },
+            Method::BasicGetOk {
+                delivery_tag: 4,
+                redelivered: false,
+                exchange: "".into(),
+                routing_key: "orders".into(),
+                message_count: 9,
+            },
+            Method::BasicGetEmpty,
+            Method::BasicAck {
+                delivery_tag: 7,
+                multiple: true,
+            },
+            Method::BasicNack {
+                delivery_tag: 8,
+                multiple: false,
+                requeue: true,
+            },
+            Method::BasicRecover { requeue: true },
+            Method::BasicRecoverOk,
+            Method::ConfirmSelect { nowait: true },
+            Method::TxSelect,
+            Method::BasicQos {
+                prefetch_size: 4096,
+                prefetch_count: 10,
+                global: true,
+            },
+            Method::BasicQosOk,
+        ];
+        for method in methods {
+            assert_eq!(Method::decode(&method.encode()).unwrap(), method);
+        }
+    }
+
+    #[test]
+    fn test_channel_open_reserved_fields() {
+        // Channel.Open-Ok carries an empty reserved long string: four zero
+        // length bytes, which strict clients wait for.
+        let frame = crate::protocol::AmqpFrame::method(1, &Method::ChannelOpenOk).encode();
+        assert_eq!(frame, [1, 0, 1, 0, 0, 0, 8, 0, 20, 0, 11, 0, 0, 0, 0, 0xce]);
+        // The deprecated out-of-band argument of Channel.Open is skipped,
+        // whatever it holds.
+        assert_eq!(Method::decode(&[0, 20, 0, 10, 0]).unwrap(), Method::ChannelOpen);
+        assert_eq!(
+            Method::decode(&[0, 20, 0, 10, 3, b'o', b'o', b'b']).unwrap(),
+            Method::ChannelOpen
+        );
+    }
+
+    #[test]
+    fn test_decode_unknown_method() {
+        let err = Method::decode(&[0, 99, 0, 1]).unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::NOT_IMPLEMENTED);
+    }
+
+    #[test]
+    fn test_implemented_methods_are_the_decodable_ones() {
+        for class_id in 0..=100 {
+            for method_id in 0..=130 {
+                let unknown = matches!(
+                    Method::decode_args(class_id, method_id, &[], &TableLimits::default()),
+                    Err(DecodeError::Unknown)
+                );
+                assert_eq!(
+                    is_implemented(class_id, method_id),
+                    !unknown,
+                    "{}.{}",
+                    class_id,
+                    method_id
+                );
+            }
+        }
+        assert_eq!(method_id(&[0, 60, 0, 40, 0]), Some((60, 40)));
+        assert_eq!(method_id(&[0, 60, 0]), None);
+    }
+
+    #[test]
+    fn test_decode_truncated_method() {
+        let err = Method::decode(&[0, 20, 0, 40, 1]).unwrap_err();
+        assert_eq!(err.reply_code(), reply_codes::SYNTAX_ERROR);
+    }
+
+    #[test]
+    fn test_decode_every_truncation_is_a_syntax_error() {
+        let methods = vec![
+            Method::ConnectionStartOk {
+                client_properties: FieldTable::new(),
+                mechanism: "PLAIN".into(),
+                response: b"\0guest\0guest".to_vec(),
+                locale: "en_US".into(),
+            },
+            Method::ConnectionTuneOk {
+                channel_max: 2047,
+                frame_max: 131072,
+                heartbeat: 60,
+            },
+            Method::ConnectionOpen {
+                virtual_host: "/".into(),
+            },
+            Method::ChannelOpen,
+            Method::ChannelFlow { active: false },
+            Method::ExchangeDeclare {
+                exchange: "orders".into(),
+                kind: "topic".into(),
+                passive: false,
+                durable: true,
+                auto_delete: false,
+                internal: false,
+                nowait: false,
+                arguments: FieldTable::new(),
+            },
+            Method::QueueBind {
+                queue: "orders".into(),
+                exchange: "amq.topic".into(),
+                routing_key: "orders.*".into(),
+                nowait: false,
+                arguments: FieldTable::new(),
+            },
+            Method::BasicQos {
+                prefetch_size: 0,
+                prefetch_count: 10,
+                global: false,
+            },
+            Method::BasicPublish {
+                exchange: "amq.topic".into(),
+                routing_key: "orders.created".into(),
+                mandatory: true,
+                immediate: false,
+            },
+            Method::BasicAck {
+                delivery_tag: 7,
+                multiple: true,
+            },
+            Method::ConfirmSelect { nowait: false },
+        ];
+        for method in methods {
+            let (class_id, method_id) = method.id();
+            let payload = method.encode();
+            for len in 0..payload.len() {
+                let err = Method::decode(&payload[..len]).unwrap_err();
+                // Without its four id bytes the method cannot be named.
+                let expected = if len < 4 {
+                    syntax_error(0, 0)
+                } else {
+                    syntax_error(class_id, method_id)
+                };
+                assert_eq!(err, expected, "{:?} cut to {} bytes", method, len);
+            }
+        }
+    }
+}
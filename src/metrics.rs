@@ -0,0 +1,250 @@
+// src/metrics.rs
+
+//! Broker metrics in the Prometheus text exposition format.
+
+use std::fmt::Write;
+
+use crate::broker::Broker;
+use crate::latency::Operation;
+use crate::registry::FrameCounts;
+
+/// Renders the broker's counters as Prometheus text.
+pub fn render(broker: &Broker) -> String {
+    let stats = broker.all_exchange_stats();
+    let mut out = String::new();
+
+    writeln!(out, "# HELP haymq_exchange_messages_routed_total Messages routed to at least one queue.").unwrap();
+    writeln!(out, "# TYPE haymq_exchange_messages_routed_total counter").unwrap();
+    for (vhost, name, s) in &stats {
+        writeln!(
+            out,
+            "haymq_exchange_messages_routed_total{{vhost=\"{}\",exchange=\"{}\"}} {}",
+            escape(vhost),
+            escape(name),
+            s.messages_routed
+        )
+        .unwrap();
+    }
+
+    writeln!(out, "# HELP haymq_exchange_messages_unroutable_total Messages that matched no binding.").unwrap();
+    writeln!(out, "# TYPE haymq_exchange_messages_unroutable_total counter").unwrap();
+    for (vhost, name, s) in &stats {
+        let (vhost, name) = (escape(vhost), escape(name));
+        writeln!(
+            out,
+            "haymq_exchange_messages_unroutable_total{{vhost=\"{}\",exchange=\"{}\",outcome=\"returned\"}} {}",
+            vhost, name, s.messages_unroutable_returned
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "haymq_exchange_messages_unroutable_total{{vhost=\"{}\",exchange=\"{}\",outcome=\"dropped\"}} {}",
+            vhost, name, s.messages_unroutable_dropped
+        )
+        .unwrap();
+    }
+
+    writeln!(
+        out,
+        "# HELP haymq_slow_consumers Consumers whose full prefetch window is holding up their queue."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE haymq_slow_consumers gauge").unwrap();
+    writeln!(out, "haymq_slow_consumers {}", broker.slow_consumers().len()).unwrap();
+
+    writeln!(
+        out,
+        "# HELP haymq_queue_consumer_utilization Share of time consumers could take a queue's messages at once."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE haymq_queue_consumer_utilization gauge").unwrap();
+    for queue in broker.queue_summaries() {
+        if let Some(utilization) = queue.consumer_utilization {
+            writeln!(
+                out,
+                "haymq_queue_consumer_utilization{{vhost=\"{}\",queue=\"{}\"}} {}",
+                escape(&queue.vhost),
+                escape(&queue.name),
+                utilization
+            )
+            .unwrap();
+        }
+    }
+
+    writeln!(
+        out,
+        "# HELP haymq_slow_operations_total Operations that took longer than the slow operation threshold."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE haymq_slow_operations_total counter").unwrap();
+    for operation in Operation::ALL {
+        writeln!(
+            out,
+            "haymq_slow_operations_total{{operation=\"{}\"}} {}",
+            operation.as_str(),
+            broker.slow_operations(operation)
+        )
+        .unwrap();
+    }
+
+    let sizes = broker.message_sizes();
+    writeln!(
+        out,
+        "# HELP haymq_message_size_bytes Body sizes of the messages clients published."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE haymq_message_size_bytes histogram").unwrap();
+    for (bound, count) in &sizes.buckets {
+        writeln!(out, "haymq_message_size_bytes_bucket{{le=\"{}\"}} {}", bound, count).unwrap();
+    }
+    writeln!(out, "haymq_message_size_bytes_bucket{{le=\"+Inf\"}} {}", sizes.count).unwrap();
+    writeln!(out, "haymq_message_size_bytes_sum {}", sizes.sum).unwrap();
+    writeln!(out, "haymq_message_size_bytes_count {}", sizes.count).unwrap();
+
+    let connections = broker.connections().open_connections();
+    for (name, help, count) in [
+        (
+            "connection_frames_received_total",
+            "Method, content header and body frames received on a connection.",
+            (|frames: &FrameCounts| frames.received) as fn(&FrameCounts) -> u64,
+        ),
+        (
+            "connection_heartbeats_received_total",
+            "Heartbeat frames received on a connection.",
+            |frames| frames.heartbeats_received,
+        ),
+        (
+            "connection_heartbeats_sent_total",
+            "Heartbeat frames sent on a connection.",
+            |frames| frames.heartbeats_sent,
+        ),
+    ] {
+        writeln!(out, "# HELP haymq_{} {}", name, help).unwrap();
+        writeln!(out, "# TYPE haymq_{} counter", name).unwrap();
+        for connection in &connections {
+            writeln!(
+                out,
+                "haymq_{}{{connection=\"{}\"}} {}",
+                name,
+                connection.id,
+                count(&connection.frames)
+            )
+            .unwrap();
+        }
+    }
+
+    writeln!(out, "# HELP haymq_connection_channels Channels open on a connection.").unwrap();
+    writeln!(out, "# TYPE haymq_connection_channels gauge").unwrap();
+    for connection in &connections {
+        writeln!(
+            out,
+            "haymq_connection_channels{{connection=\"{}\"}} {}",
+            connection.id, connection.channels
+        )
+        .unwrap();
+    }
+    writeln!(
+        out,
+        "# HELP haymq_channel_count_warnings_total Times a connection opened channels past the warning threshold."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE haymq_channel_count_warnings_total counter").unwrap();
+    writeln!(out, "haymq_channel_count_warnings_total {}", broker.channel_warnings()).unwrap();
+    writeln!(
+        out,
+        "# HELP haymq_unacked_messages Deliveries awaiting an ack across every queue."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE haymq_unacked_messages gauge").unwrap();
+    writeln!(out, "haymq_unacked_messages {}", broker.unacked_messages()).unwrap();
+
+    let exceptions = broker.exception_counts();
+    for (scope, kind, counts) in [
+        ("channel", "Channel", &exceptions.channel),
+        ("connection", "Connection", &exceptions.connection),
+    ] {
+        writeln!(
+            out,
+            "# HELP haymq_{}_exceptions_total {} exceptions the broker raised, by reply code.",
+            scope, kind
+        )
+        .unwrap();
+        writeln!(out, "# TYPE haymq_{}_exceptions_total counter", scope).unwrap();
+        for (code, count) in counts {
+            writeln!(out, "haymq_{}_exceptions_total{{code=\"{}\"}} {}", scope, code, count).unwrap();
+        }
+    }
+    out
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker::ExchangeDeclare;
+    use crate::config::Config;
+    use crate::message::Message;
+    use crate::vhost::DEFAULT_VHOST;
+
+    #[test]
+    fn test_render_unroutable_counters() {
+        let broker = Broker::new(Config::default());
+        broker
+            .declare_exchange(
+                DEFAULT_VHOST,
+                ExchangeDeclare {
+                exchange: "events".into(),
+                kind: "direct".into(),
+                ..Default::default()
+            })
+            .unwrap();
+        let message = Message {
+            exchange: "events".into(),
+            routing_key: "nowhere".into(),
+            properties: Default::default(),
+            body: vec![],
+        };
+        broker.publish(DEFAULT_VHOST, message, true);
+
+        let text = render(&broker);
+        assert!(text.contains("haymq_exchange_messages_routed_total{vhost=\"/\",exchange=\"events\"} 0\n"));
+        assert!(text.contains(
+            "haymq_exchange_messages_unroutable_total{vhost=\"/\",exchange=\"events\",outcome=\"returned\"} 1\n"
+        ));
+        assert!(text.contains(
+            "haymq_exchange_messages_unroutable_total{vhost=\"/\",exchange=\"events\",outcome=\"dropped\"} 0\n"
+        ));
+        assert!(text.contains("haymq_slow_operations_total{operation=\"store\"} 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_render_published_message_sizes() {
+        let broker = std::sync::Arc::new(Broker::new(Config::default()));
+        let mut client = crate::test_support::TestClient::connect_to(broker.clone()).await;
+        client.handshake().await;
+        client.open_channel(1).await;
+        for size in [10, 100, 5000] {
+            client
+                .publish(1, "", "nowhere", Default::default(), &vec![0; size])
+                .await;
+        }
+        // Channel 1's publishes are handled before channel 2 opens.
+        client.open_channel(2).await;
+
+        let text = render(&broker);
+        for line in [
+            "haymq_message_size_bytes_bucket{le=\"64\"} 1\n",
+            "haymq_message_size_bytes_bucket{le=\"256\"} 2\n",
+            "haymq_message_size_bytes_bucket{le=\"4096\"} 2\n",
+            "haymq_message_size_bytes_bucket{le=\"16384\"} 3\n",
+            "haymq_message_size_bytes_bucket{le=\"+Inf\"} 3\n",
+            "haymq_message_size_bytes_sum 5110\n",
+            "haymq_message_size_bytes_count 3\n",
+        ] {
+            assert!(text.contains(line), "{} missing from {}", line, text);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+// src/policy.rs
+
+//! Policies: arguments an operator applies to queues and exchanges by name.
+//!
+//! A policy belongs to one virtual host. It matches queue names, exchange
+//! names or both against a glob pattern, where `*` matches any run of
+//! characters and `?` any one, and carries a definition: arguments as a
+//! declare would give them, such as `x-max-length` or
+//! `x-dead-letter-exchange` for queues and `hash-header` for exchanges.
+//!
+//! Of the policies matching a queue or exchange only one applies: the one
+//! with the highest priority, or on a tie the first by name. Its arguments
+//! fill in those the client left out of the declare; an argument the client
+//! declared always wins over the policy's. Declares still compare only the
+//! client's arguments when a queue or exchange is redeclared.
+//!
+//! Policies apply when a queue or exchange is declared, and again to every
+//! queue and exchange of the virtual host whenever one of its policies is
+//! set or removed. Built-in exchanges are left alone.
+
+use crate::field_table::FieldTable;
+use crate::queue::QueueOptions;
+use crate::rewrite::glob_matches;
+
+/// What a policy applies to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ApplyTo {
+    Queues,
+    Exchanges,
+    #[default]
+    All,
+}
+
+impl ApplyTo {
+    pub fn parse(apply_to: &str) -> Option<ApplyTo> {
+        match apply_to {
+            "queues" => Some(ApplyTo::Queues),
+            "exchanges" => Some(ApplyTo::Exchanges),
+            "all" => Some(ApplyTo::All),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApplyTo::Queues => "queues",
+            ApplyTo::Exchanges => "exchanges",
+            ApplyTo::All => "all",
+        }
+    }
+
+    fn includes(&self, target: ApplyTo) -> bool {
+        *self == ApplyTo::All || *self == target
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    pub name: String,
+    /// Glob of the names the policy applies to.
+    pub pattern: String,
+    pub apply_to: ApplyTo,
+    /// Decides between policies matching the same name; higher wins.
+    pub priority: i32,
+    /// Arguments applied to matching queues or exchanges.
+    pub definition: FieldTable,
+}
+
+impl Policy {
+    /// Checks the definition the way a queue declare would check its
+    /// arguments, for a policy that applies to queues.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.apply_to.includes(ApplyTo::Queues) {
+            QueueOptions::from_arguments(&self.definition).map_err(|e| e.reply_text().to_string())?;
+        }
+        Ok(())
+    }
+
+    fn matches(&self, target: ApplyTo, name: &str) -> bool {
+        self.apply_to.includes(target) && glob_matches(&self.pattern, name)
+    }
+}
+
+/// The policy that applies to the queue or exchange called `name`, given
+/// as `ApplyTo::Queues` or `ApplyTo::Exchanges`. `policies` come by name.
+pub fn select<'a>(policies: impl IntoIterator<Item = &'a Policy>, target: ApplyTo, name: &str) -> Option<&'a Policy> {
+    let mut chosen: Option<&Policy> = None;
+    for policy in policies.into_iter().filter(|p| p.matches(target, name)) {
+        if chosen.is_none_or(|chosen| policy.priority > chosen.priority) {
+            chosen = Some(policy);
+        }
+    }
+    chosen
+}
+
+/// `arguments` with those of `policy`'s definition they lack added after
+/// them.
+pub fn merge(arguments: &FieldTable, policy: Option<&Policy>) -> FieldTable {
+    let mut merged = arguments.clone();
+    for (key, value) in policy.iter().flat_map(|policy| policy.definition.iter()) {
+        if arguments.get(key).is_none() {
+            merged.insert(key, value.clone());
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::field_table::FieldValue;
+
+    fn policy(name: &str, pattern: &str, apply_to: ApplyTo, priority: i32, max_length: i32) -> Policy {
+        let mut definition = FieldTable::new();
+        definition.insert("x-max-length", FieldValue::LongInt(max_length));
+        definition.insert("x-overflow", FieldValue::LongString(b"drop-head".to_vec()));
+        Policy {
+            name: name.into(),
+            pattern: pattern.into(),
+            apply_to,
+            priority,
+            definition,
+        }
+    }
+
+    #[test]
+    fn test_the_highest_priority_matching_policy_applies() {
+        let policies = [
+            policy("a-jobs", "jobs.*", ApplyTo::Queues, 1, 10),
+            policy("b-all", "*", ApplyTo::All, 0, 20),
+            policy("c-exchanges", "jobs.*", ApplyTo::Exchanges, 5, 30),
+            policy("d-jobs", "jobs.*", ApplyTo::Queues, 1, 40),
+        ];
+        let chosen = |target, name| select(&policies, target, name).map(|p| p.name.as_str());
+        assert_eq!(chosen(ApplyTo::Queues, "jobs.eu"), Some("a-jobs"));
+        assert_eq!(chosen(ApplyTo::Exchanges, "jobs.eu"), Some("c-exchanges"));
+        assert_eq!(chosen(ApplyTo::Queues, "audit"), Some("b-all"));
+        assert_eq!(select(&policies[..1], ApplyTo::Queues, "audit"), None);
+    }
+
+    #[test]
+    fn test_client_arguments_win_over_the_policy() {
+        let mut arguments = FieldTable::new();
+        arguments.insert("x-max-length", FieldValue::LongInt(5));
+        let policy = policy("limit", "*", ApplyTo::All, 0, 100);
+        let merged = merge(&arguments, Some(&policy));
+        assert_eq!(merged.get("x-max-length"), Some(&FieldValue::LongInt(5)));
+        assert_eq!(merged.get("x-overflow").and_then(FieldValue::as_str), Some("drop-head"));
+        assert_eq!(merge(&arguments, None), arguments);
+    }
+}
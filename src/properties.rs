@@ -0,0 +1,293 @@
+// src/properties.rs
+
+//! Content header frames and the `basic` class properties they carry.
+//!
+//! The header payload is laid out as:
+//! - 2 bytes: class id
+//! - 2 bytes: weight (unused, always 0)
+//! - 8 bytes: body size
+//! - 2 bytes: property flags, one bit per present property
+//! - the present properties, in flag order
+
+use bytes::BufMut;
+
+use crate::error::AmqpError;
+use crate::field_table::{parse_field_table_within, FieldTable, TableLimits};
+use crate::reply_codes;
+use crate::wire::{longlong, octet, put_shortstr, short, shortstr, ParseResult};
+
+const FLAG_CONTENT_TYPE: u16 = 1 << 15;
+const FLAG_CONTENT_ENCODING: u16 = 1 << 14;
+const FLAG_HEADERS: u16 = 1 << 13;
+const FLAG_DELIVERY_MODE: u16 = 1 << 12;
+const FLAG_PRIORITY: u16 = 1 << 11;
+const FLAG_CORRELATION_ID: u16 = 1 << 10;
+const FLAG_REPLY_TO: u16 = 1 << 9;
+const FLAG_EXPIRATION: u16 = 1 << 8;
+const FLAG_MESSAGE_ID: u16 = 1 << 7;
+const FLAG_TIMESTAMP: u16 = 1 << 6;
+const FLAG_TYPE: u16 = 1 << 5;
+const FLAG_USER_ID: u16 = 1 << 4;
+const FLAG_APP_ID: u16 = 1 << 3;
+const FLAG_CLUSTER_ID: u16 = 1 << 2;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BasicProperties {
+    pub content_type: Option<String>,
+    pub content_encoding: Option<String>,
+    pub headers: Option<FieldTable>,
+    pub delivery_mode: Option<u8>,
+    pub priority: Option<u8>,
+    pub correlation_id: Option<String>,
+    pub reply_to: Option<String>,
+    pub expiration: Option<String>,
+    pub message_id: Option<String>,
+    pub timestamp: Option<u64>,
+    pub kind: Option<String>,
+    pub user_id: Option<String>,
+    pub app_id: Option<String>,
+    pub cluster_id: Option<String>,
+}
+
+impl BasicProperties {
+    /// Bytes the properties keep on the heap: their strings and the
+    /// headers table.
+    pub fn heap_size(&self) -> usize {
+        let strings = [
+            &self.content_type,
+            &self.content_encoding,
+            &self.correlation_id,
+            &self.reply_to,
+            &self.expiration,
+            &self.message_id,
+            &self.kind,
+            &self.user_id,
+            &self.app_id,
+            &self.cluster_id,
+        ];
+        let strings: usize = strings.iter().filter_map(|s| s.as_ref()).map(String::len).sum();
+        strings + self.headers.as_ref().map_or(0, FieldTable::heap_size)
+    }
+}
+
+/// Whether the broker sets the `timestamp` property of published messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ServerTimestamp {
+    /// Messages keep the timestamp the publisher set, if any.
+    #[default]
+    Off,
+    /// Messages published without a timestamp get the broker's time.
+    IfAbsent,
+    /// Every message gets the broker's time, replacing the publisher's.
+    Force,
+}
+
+impl ServerTimestamp {
+    /// Sets `properties.timestamp` to `now`, in seconds since the Unix
+    /// epoch, where the policy calls for it.
+    pub fn apply(self, properties: &mut BasicProperties, now: u64) {
+        match self {
+            ServerTimestamp::Off => {}
+            ServerTimestamp::IfAbsent => {
+                properties.timestamp.get_or_insert(now);
+            }
+            ServerTimestamp::Force => properties.timestamp = Some(now),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContentHeader {
+    pub class_id: u16,
+    pub body_size: u64,
+    pub properties: BasicProperties,
+}
+
+fn optional<'a, T>(
+    input: &'a [u8],
+    flags: u16,
+    flag: u16,
+    parser: impl FnOnce(&'a [u8]) -> ParseResult<'a, T>,
+) -> ParseResult<'a, Option<T>> {
+    if flags & flag == 0 {
+        return Ok((input, None));
+    }
+    let (input, value) = parser(input)?;
+    Ok((input, Some(value)))
+}
+
+fn parse_content_header<'a>(input: &'a [u8], limits: &TableLimits) -> ParseResult<'a, ContentHeader> {
+    let (i, class_id) = short(input)?;
+    let (i, _weight) = short(i)?;
+    let (i, body_size) = longlong(i)?;
+    let (i, flags) = short(i)?;
+    let (i, content_type) = optional(i, flags, FLAG_CONTENT_TYPE, shortstr)?;
+    let (i, content_encoding) = optional(i, flags, FLAG_CONTENT_ENCODING, shortstr)?;
+    let (i, headers) = optional(i, flags, FLAG_HEADERS, |i| parse_field_table_within(i, limits))?;
+    let (i, delivery_mode) = optional(i, flags, FLAG_DELIVERY_MODE, octet)?;
+    let (i, priority) = optional(i, flags, FLAG_PRIORITY, octet)?;
+    let (i, correlation_id) = optional(i, flags, FLAG_CORRELATION_ID, shortstr)?;
+    let (i, reply_to) = optional(i, flags, FLAG_REPLY_TO, shortstr)?;
+    let (i, expiration) = optional(i, flags, FLAG_EXPIRATION, shortstr)?;
+    let (i, message_id) = optional(i, flags, FLAG_MESSAGE_ID, shortstr)?;
+    let (i, timestamp) = optional(i, flags, FLAG_TIMESTAMP, longlong)?;
+    let (i, kind) = optional(i, flags, FLAG_TYPE, shortstr)?;
+    let (i, user_id) = optional(i, flags, FLAG_USER_ID, shortstr)?;
+    let (i, app_id) = optional(i, flags, FLAG_APP_ID, shortstr)?;
+    let (i, cluster_id) = optional(i, flags, FLAG_CLUSTER_ID, shortstr)?;
+    Ok((
+        i,
+        ContentHeader {
+            class_id,
+            body_size,
+            properties: BasicProperties {
+                content_type,
+                content_encoding,
+                headers,
+                delivery_mode,
+                priority,
+                correlation_id,
+                reply_to,
+                expiration,
+                message_id,
+                timestamp,
+                kind,
+                user_id,
+                app_id,
+                cluster_id,
+            },
+        },
+    ))
+}
+
+impl ContentHeader {
+    /// Decodes a content header frame payload.
+    pub fn decode(payload: &[u8]) -> Result<ContentHeader, AmqpError> {
+        Self::decode_within(payload, &TableLimits::default())
+    }
+
+    /// Decodes a content header frame payload whose headers table must stay
+    /// within `limits`.
+    pub fn decode_within(payload: &[u8], limits: &TableLimits) -> Result<ContentHeader, AmqpError> {
+        match parse_content_header(payload, limits) {
+            Ok((_, header)) => Ok(header),
+            Err(_) => Err(AmqpError::connection(
+                reply_codes::FRAME_ERROR,
+                "FRAME_ERROR - malformed content header",
+                0,
+                0,
+            )),
+        }
+    }
+
+    /// Encodes this header into a content header frame payload.
+    pub fn encode(&self) -> Vec<u8> {
+        let p = &self.properties;
+        let mut flags = 0u16;
+        let mut props = Vec::new();
+        if let Some(v) = &p.content_type {
+            flags |= FLAG_CONTENT_TYPE;
+            put_shortstr(&mut props, v);
+        }
+        if let Some(v) = &p.content_encoding {
+            flags |= FLAG_CONTENT_ENCODING;
+            put_shortstr(&mut props, v);
+        }
+        if let Some(v) = &p.headers {
+            flags |= FLAG_HEADERS;
+            v.encode(&mut props);
+        }
+        if let Some(v) = p.delivery_mode {
+            flags |= FLAG_DELIVERY_MODE;
+            props.put_u8(v);
+        }
+        if let Some(v) = p.priority {
+            flags |= FLAG_PRIORITY;
+            props.put_u8(v);
+        }
+        if let Some(v) = &p.correlation_id {
+            flags |= FLAG_CORRELATION_ID;
+            put_shortstr(&mut props, v);
+        }
+        if let Some(v) = &p.reply_to {
+            flags |= FLAG_REPLY_TO;
+            put_shortstr(&mut props, v);
+        }
+        if let Some(v) = &p.expiration {
+            flags |= FLAG_EXPIRATION;
+            put_shortstr(&mut props, v);
+        }
+        if let Some(v) = &p.message_id {
+            flags |= FLAG_MESSAGE_ID;
+            put_shortstr(&mut props, v);
+        }
+        if let Some(v) = p.timestamp {
+            flags |= FLAG_TIMESTAMP;
+            props.put_u64(v);
+        }
+        if let Some(v) = &p.kind {
+            flags |= FLAG_TYPE;
+            put_shortstr(&mut props, v);
+        }
+        if let Some(v) = &p.user_id {
+            flags |= FLAG_USER_ID;
+            put_shortstr(&mut props, v);
+        }
+        if let Some(v) = &p.app_id {
+            flags |= FLAG_APP_ID;
+            put_shortstr(&mut props, v);
+        }
+        if let Some(v) = &p.cluster_id {
+            flags |= FLAG_CLUSTER_ID;
+            put_shortstr(&mut props, v);
+        }
+
+        let mut buf = Vec::with_capacity(14 + props.len());
+        buf.put_u16(self.class_id);
+        buf.put_u16(0);
+        buf.put_u64(self.body_size);
+        buf.put_u16(flags);
+        buf.extend_from_slice(&props);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::field_table::FieldValue;
+
+    #[test]
+    fn test_content_header_roundtrip() {
+        let mut headers = FieldTable::new();
+        headers.insert("tenant", FieldValue::LongString(b"acme".to_vec()));
+        let header = ContentHeader {
+            class_id: 60,
+            body_size: 42,
+            properties: BasicProperties {
+                content_type: Some("application/json".into()),
+                headers: Some(headers),
+                delivery_mode: Some(2),
+                timestamp: Some(1_700_000_000),
+                user_id: Some("guest".into()),
+                ..Default::default()
+            },
+        };
+        assert_eq!(ContentHeader::decode(&header.encode()).unwrap(), header);
+    }
+
+    #[test]
+    fn test_content_header_truncated() {
+        let header = ContentHeader {
+            class_id: 60,
+            body_size: 1,
+            properties: BasicProperties {
+                message_id: Some("m-1".into()),
+                ..Default::default()
+            },
+        };
+        let mut payload = header.encode();
+        payload.pop();
+        assert!(ContentHeader::decode(&payload).is_err());
+    }
+}
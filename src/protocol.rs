@@ -6,26 +6,162 @@ use nom::{
   error::Error as NomError,
 };
 
-#[derive(Debug)]
+use crate::methods::Method;
+use crate::properties::ContentHeader;
+
+pub const FRAME_METHOD: u8 = 1;
+pub const FRAME_HEADER: u8 = 2;
+pub const FRAME_BODY: u8 = 3;
+pub const FRAME_HEARTBEAT: u8 = 8;
+pub const FRAME_END: u8 = 0xCE;
+
+/// Protocol name a connection's header starts with.
+pub const PROTOCOL_ID: &[u8; 4] = b"AMQP";
+/// The protocol version the broker speaks: AMQP 0-9-1.
+pub const PROTOCOL_MAJOR: u8 = 0;
+pub const PROTOCOL_MINOR: u8 = 9;
+pub const PROTOCOL_REVISION: u8 = 1;
+/// The header a client opens with, and the one the broker answers an
+/// unsupported header with before closing.
+pub const PROTOCOL_HEADER: [u8; 8] = [
+  PROTOCOL_ID[0], PROTOCOL_ID[1], PROTOCOL_ID[2], PROTOCOL_ID[3],
+  0, PROTOCOL_MAJOR, PROTOCOL_MINOR, PROTOCOL_REVISION,
+];
+
+/// Smallest `frame_max` a peer may negotiate.
+pub const FRAME_MIN_SIZE: u32 = 4096;
+/// Bytes a frame spends outside its payload: type, channel, size and frame-end.
+pub const FRAME_OVERHEAD: u32 = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct AmqpFrame {
   pub frame_type: u8,
   pub channel: u16,
   pub payload: Vec<u8>,
 }
 
-/// Parses the AMQP header for AMQP 0.9.1.
-/// 
-/// The header is exactly 8 bytes: "AMQP\0\0\9\1"
-pub fn parse_amqp_header(input: &[u8]) -> Result<(), &'static str> {
-  let expected = b"AMQP\x00\x00\x09\x01";
-  if input.len() < expected.len() {
-      return Err("AMQP header too short");
-  }
-  if input.starts_with(expected) {
-      Ok(())
+impl AmqpFrame {
+  pub fn method(channel: u16, method: &Method) -> Self {
+      AmqpFrame {
+          frame_type: FRAME_METHOD,
+          channel,
+          payload: method.encode(),
+      }
+  }
+
+  pub fn header(channel: u16, header: &ContentHeader) -> Self {
+      AmqpFrame {
+          frame_type: FRAME_HEADER,
+          channel,
+          payload: header.encode(),
+      }
+  }
+
+  pub fn body(channel: u16, body: &[u8]) -> Self {
+      AmqpFrame {
+          frame_type: FRAME_BODY,
+          channel,
+          payload: body.to_vec(),
+      }
+  }
+
+  /// A heartbeat: always on channel 0, with no payload.
+  pub fn heartbeat() -> Self {
+      AmqpFrame {
+          frame_type: FRAME_HEARTBEAT,
+          channel: 0,
+          payload: Vec::new(),
+      }
+  }
+
+  /// Serializes the frame into its wire representation.
+  pub fn encode(&self) -> Vec<u8> {
+      let mut buf = Vec::with_capacity(self.payload.len() + 8);
+      buf.push(self.frame_type);
+      buf.extend_from_slice(&self.channel.to_be_bytes());
+      buf.extend_from_slice(&(self.payload.len() as u32).to_be_bytes());
+      buf.extend_from_slice(&self.payload);
+      buf.push(FRAME_END);
+      buf
+  }
+}
+
+/// A protocol version, as a connection header names it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolVersion {
+  pub major: u8,
+  pub minor: u8,
+  pub revision: u8,
+}
+
+impl ProtocolVersion {
+  /// AMQP 0-9-1, the only version the broker speaks.
+  pub const SUPPORTED: ProtocolVersion = ProtocolVersion {
+      major: PROTOCOL_MAJOR,
+      minor: PROTOCOL_MINOR,
+      revision: PROTOCOL_REVISION,
+  };
+}
+
+impl std::fmt::Display for ProtocolVersion {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+      write!(f, "{}-{}-{}", self.major, self.minor, self.revision)
+  }
+}
+
+/// Why a connection header was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderError {
+  TooShort,
+  /// The header does not start with `AMQP`.
+  NotAmqp,
+  /// An AMQP header, for a version other than 0-9-1.
+  Unsupported(ProtocolVersion),
+}
+
+impl std::fmt::Display for HeaderError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+      match self {
+          HeaderError::TooShort => write!(f, "AMQP header too short"),
+          HeaderError::NotAmqp => write!(f, "not an AMQP header"),
+          HeaderError::Unsupported(version) => write!(f, "unsupported AMQP version {}", version),
+      }
+  }
+}
+
+/// Parses the 8-byte header a connection opens with, returning the
+/// version it asks for if that is AMQP 0-9-1.
+///
+/// 0-9-1 headers are `AMQP`, a zero byte, then major, minor and revision.
+/// Older ones are `AMQP`, a protocol class and instance, then major and
+/// minor, so 0-9 reads `AMQP\x01\x01\x00\x09` and is refused as 0-9-0.
+pub fn parse_amqp_header(input: &[u8]) -> Result<ProtocolVersion, HeaderError> {
+  if input.len() < PROTOCOL_HEADER.len() {
+      return Err(HeaderError::TooShort);
+  }
+  if !input.starts_with(PROTOCOL_ID) {
+      return Err(HeaderError::NotAmqp);
+  }
+  let version = match input[4] {
+      0 => ProtocolVersion { major: input[5], minor: input[6], revision: input[7] },
+      _ => ProtocolVersion { major: input[6], minor: input[7], revision: 0 },
+  };
+  if version == ProtocolVersion::SUPPORTED {
+      Ok(version)
   } else {
-      Err("Invalid AMQP header")
+      Err(HeaderError::Unsupported(version))
+  }
+}
+
+/// Returns the total encoded length of the frame at the start of `input`
+/// (header, payload and frame-end marker), or `None` while fewer than the
+/// 7 header bytes are available.
+pub fn frame_len(input: &[u8]) -> Option<usize> {
+  if input.len() < 7 {
+      return None;
   }
+  let payload_len = u32::from_be_bytes([input[3], input[4], input[5], input[6]]);
+  Some(7 + payload_len as usize + 1)
 }
 
 /// Parses an AMQP 0.9.1-like frame.
@@ -55,7 +191,7 @@ pub fn parse_amqp_frame(input: &[u8]) -> Result<AmqpFrame, &'static str> {
 
   let (payload, last_byte) = remainder.split_at(payload_len as usize);
   let frame_end = last_byte[0];
-  if frame_end != 0xCE {
+  if frame_end != FRAME_END {
       return Err("Invalid frame-end marker, expected 0xCE");
   }
 
@@ -73,13 +209,27 @@ mod tests {
   #[test]
   fn test_parse_amqp_header_valid() {
       let header = b"AMQP\x00\x00\x09\x01";
-      assert!(parse_amqp_header(header).is_ok());
+      assert_eq!(&PROTOCOL_HEADER, header);
+      let version = parse_amqp_header(header).unwrap();
+      assert_eq!(version, ProtocolVersion::SUPPORTED);
+      assert_eq!(version.to_string(), "0-9-1");
   }
 
   #[test]
   fn test_parse_amqp_header_invalid() {
       let header = b"XYZ\x00\x00\x09\x01";
-      assert!(parse_amqp_header(header).is_err());
+      assert_eq!(parse_amqp_header(header), Err(HeaderError::TooShort));
+      assert_eq!(parse_amqp_header(b"HTTP/1.1"), Err(HeaderError::NotAmqp));
+  }
+
+  #[test]
+  fn test_parse_amqp_header_names_unsupported_versions() {
+      let v0_9 = ProtocolVersion { major: 0, minor: 9, revision: 0 };
+      // 0-9 in its own header layout, and in the 0-9-1 one.
+      assert_eq!(parse_amqp_header(b"AMQP\x01\x01\x00\x09"), Err(HeaderError::Unsupported(v0_9)));
+      assert_eq!(parse_amqp_header(b"AMQP\x00\x00\x09\x00"), Err(HeaderError::Unsupported(v0_9)));
+      let v0_8 = ProtocolVersion { major: 8, minor: 0, revision: 0 };
+      assert_eq!(parse_amqp_header(b"AMQP\x01\x01\x08\x00"), Err(HeaderError::Unsupported(v0_8)));
   }
 
   #[test]
@@ -116,4 +266,25 @@ mod tests {
       let parsed = parse_amqp_frame(&frame_data);
       assert!(parsed.is_err());
   }
+
+  #[test]
+  fn test_frame_len() {
+      let frame = AmqpFrame::body(1, &[0xA, 0xB]);
+      let encoded = frame.encode();
+      assert_eq!(frame_len(&encoded[..6]), None);
+      assert_eq!(frame_len(&encoded[..7]), Some(encoded.len()));
+  }
+
+  #[test]
+  fn test_encode_frame_roundtrip() {
+      let frame = AmqpFrame {
+          frame_type: FRAME_BODY,
+          channel: 7,
+          payload: vec![1, 2, 3],
+      };
+      let parsed = parse_amqp_frame(&frame.encode()).expect("Should parse successfully");
+      assert_eq!(parsed.frame_type, FRAME_BODY);
+      assert_eq!(parsed.channel, 7);
+      assert_eq!(parsed.payload, vec![1, 2, 3]);
+  }
 }
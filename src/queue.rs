@@ -0,0 +1,1508 @@
+// src/queue.rs
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use tokio::sync::mpsc;
+
+use crate::clock::{Clock, TokioClock};
+use crate::error::AmqpError;
+use crate::field_table::{FieldTable, FieldValue};
+use crate::message::Message;
+use crate::methods::CLASS_QUEUE;
+use crate::rate_limit::TokenBucket;
+use crate::reply_codes;
+use crate::vhost::VHostUsage;
+
+/// Storage flavour of a queue, selected with the `x-queue-type` argument.
+///
+/// `Quorum` queues currently share the classic implementation; the type only
+/// changes declare-time defaults so the storage layer can specialize later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueType {
+    Classic,
+    Quorum,
+}
+
+impl QueueType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QueueType::Classic => "classic",
+            QueueType::Quorum => "quorum",
+        }
+    }
+
+    /// Reads `x-queue-type` from declare arguments, falling back to `default`.
+    pub fn from_arguments(arguments: &FieldTable, default: QueueType) -> Result<QueueType, AmqpError> {
+        let Some(value) = arguments.get("x-queue-type") else {
+            return Ok(default);
+        };
+        match value.as_str() {
+            Some("classic") => Ok(QueueType::Classic),
+            Some("quorum") => Ok(QueueType::Quorum),
+            _ => Err(AmqpError::channel(
+                reply_codes::PRECONDITION_FAILED,
+                format!("PRECONDITION_FAILED - invalid x-queue-type {:?}", value),
+                CLASS_QUEUE,
+                10,
+            )),
+        }
+    }
+}
+
+/// What a queue at its `x-max-length` does with a new message, selected
+/// with the `x-overflow` argument.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Drop (and dead-letter) the oldest ready message to make room.
+    #[default]
+    DropHead,
+    /// Refuse the new message; publishers in confirm mode get a nack.
+    RejectPublish,
+    /// Refuse the new message like `RejectPublish`, but dead-letter it.
+    RejectPublishDlx,
+}
+
+impl Overflow {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Overflow::DropHead => "drop-head",
+            Overflow::RejectPublish => "reject-publish",
+            Overflow::RejectPublishDlx => "reject-publish-dlx",
+        }
+    }
+}
+
+/// Length limit and dead-lettering settings read from declare arguments.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueueOptions {
+    /// `x-max-length`: most ready messages the queue holds.
+    pub max_length: Option<usize>,
+    /// `x-overflow`: what happens to a publish once `max_length` is reached.
+    pub overflow: Overflow,
+    /// `x-dead-letter-exchange`: where dropped or rejected messages are
+    /// republished.
+    pub dead_letter_exchange: Option<String>,
+    /// `x-dead-letter-routing-key`: replaces the routing key of dead letters.
+    pub dead_letter_routing_key: Option<String>,
+    /// `x-expires`: how long the queue itself may go unused before it is
+    /// deleted. Unlike a message TTL, it never removes single messages.
+    pub expires: Option<Duration>,
+    /// `x-max-publish-rate`: most messages per second the queue accepts.
+    pub max_publish_rate: Option<u32>,
+    /// `x-delivery-limit`: how often a message may be returned to the queue
+    /// after reaching a consumer before it is dead-lettered instead.
+    pub delivery_limit: Option<u32>,
+    /// `x-queue-master-locator`: where a clustered broker would place the
+    /// queue. One of `MASTER_LOCATORS`; kept for reporting only, since this
+    /// broker is a single node.
+    pub master_locator: Option<String>,
+    /// `x-queue-leader-locator`: the newer name of the same hint, one of
+    /// `LEADER_LOCATORS`, and as much a no-op.
+    pub leader_locator: Option<String>,
+    /// `x-consumer-reconnect-window`: how long the unacked messages of a
+    /// consumer that went away are kept for a consumer with the same tag
+    /// before they return to the queue.
+    pub reconnect_window: Option<Duration>,
+}
+
+/// Values `x-queue-master-locator` accepts.
+pub const MASTER_LOCATORS: &[&str] = &["min-masters", "client-local", "random"];
+/// Values `x-queue-leader-locator` accepts.
+pub const LEADER_LOCATORS: &[&str] = &["client-local", "balanced"];
+
+impl QueueOptions {
+    pub fn from_arguments(arguments: &FieldTable) -> Result<QueueOptions, AmqpError> {
+        let invalid = |key: &str, value: &FieldValue| {
+            AmqpError::channel(
+                reply_codes::PRECONDITION_FAILED,
+                format!("PRECONDITION_FAILED - invalid {} {:?}", key, value),
+                CLASS_QUEUE,
+                10,
+            )
+        };
+        let mut options = QueueOptions::default();
+        if let Some(value) = arguments.get("x-max-length") {
+            let length = value.as_i64().and_then(|n| usize::try_from(n).ok());
+            options.max_length = Some(length.ok_or_else(|| invalid("x-max-length", value))?);
+        }
+        if let Some(value) = arguments.get("x-overflow") {
+            options.overflow = match value.as_str() {
+                Some("drop-head") => Overflow::DropHead,
+                Some("reject-publish") => Overflow::RejectPublish,
+                Some("reject-publish-dlx") => Overflow::RejectPublishDlx,
+                _ => return Err(invalid("x-overflow", value)),
+            };
+        }
+        if let Some(value) = arguments.get("x-expires") {
+            let millis = value.as_i64().and_then(|n| u64::try_from(n).ok()).filter(|&n| n > 0);
+            options.expires = Some(Duration::from_millis(
+                millis.ok_or_else(|| invalid("x-expires", value))?,
+            ));
+        }
+        if let Some(value) = arguments.get("x-consumer-reconnect-window") {
+            let millis = value.as_i64().and_then(|n| u64::try_from(n).ok()).filter(|&n| n > 0);
+            options.reconnect_window = Some(Duration::from_millis(
+                millis.ok_or_else(|| invalid("x-consumer-reconnect-window", value))?,
+            ));
+        }
+        if let Some(value) = arguments.get("x-max-publish-rate") {
+            let rate = value.as_i64().and_then(|n| u32::try_from(n).ok()).filter(|&n| n > 0);
+            options.max_publish_rate = Some(rate.ok_or_else(|| invalid("x-max-publish-rate", value))?);
+        }
+        if let Some(value) = arguments.get("x-delivery-limit") {
+            let limit = value.as_i64().and_then(|n| u32::try_from(n).ok());
+            options.delivery_limit = Some(limit.ok_or_else(|| invalid("x-delivery-limit", value))?);
+        }
+        for (key, allowed, option) in [
+            ("x-queue-master-locator", MASTER_LOCATORS, &mut options.master_locator),
+            ("x-queue-leader-locator", LEADER_LOCATORS, &mut options.leader_locator),
+        ] {
+            if let Some(value) = arguments.get(key) {
+                let locator = value.as_str().filter(|locator| allowed.contains(locator));
+                *option = Some(locator.ok_or_else(|| invalid(key, value))?.to_string());
+            }
+        }
+        for (key, option) in [
+            ("x-dead-letter-exchange", &mut options.dead_letter_exchange),
+            ("x-dead-letter-routing-key", &mut options.dead_letter_routing_key),
+        ] {
+            if let Some(value) = arguments.get(key) {
+                *option = Some(value.as_str().ok_or_else(|| invalid(key, value))?.to_string());
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Source of the names given to queues declared with an empty name.
+pub trait QueueNameGenerator: Send {
+    /// Proposes a name. The broker asks again while the name is taken, so
+    /// a generator must not repeat itself forever.
+    fn generate(&mut self) -> String;
+}
+
+/// The default generator: `amq.gen-` followed by 128 random bits in
+/// URL-safe base64, like RabbitMQ's server-named queues.
+pub struct RandomQueueNames {
+    keys: RandomState,
+    counter: u64,
+}
+
+impl RandomQueueNames {
+    pub fn new() -> Self {
+        RandomQueueNames {
+            keys: RandomState::new(),
+            counter: 0,
+        }
+    }
+}
+
+impl Default for RandomQueueNames {
+    fn default() -> Self {
+        RandomQueueNames::new()
+    }
+}
+
+impl QueueNameGenerator for RandomQueueNames {
+    fn generate(&mut self) -> String {
+        // SipHash under random keys turns the counter into unpredictable bits.
+        let mut bytes = [0u8; 16];
+        for half in bytes.chunks_mut(8) {
+            self.counter += 1;
+            let mut hasher = self.keys.build_hasher();
+            hasher.write_u64(self.counter);
+            half.copy_from_slice(&hasher.finish().to_le_bytes());
+        }
+        format!(
+            "amq.gen-{}",
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+        )
+    }
+}
+
+/// How many messages a queue holds. Taken under the queue's lock, so a
+/// delivery moving between ready and unacked is counted exactly once.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MessageCounts {
+    /// Messages waiting for delivery.
+    pub ready: usize,
+    /// Messages delivered and not yet acked.
+    pub unacked: usize,
+}
+
+impl MessageCounts {
+    pub fn total(&self) -> usize {
+        self.ready + self.unacked
+    }
+}
+
+/// A message waiting in a queue.
+#[derive(Debug, Clone)]
+pub struct QueuedMessage {
+    pub message: Message,
+    /// Id of the persisted copy, if the message was written to the store.
+    pub store_id: Option<u64>,
+    /// Whether the message was delivered before and requeued.
+    pub redelivered: bool,
+    /// How often the message reached a consumer and came back unacked.
+    pub delivery_count: u32,
+    /// Place in the order the queue received its messages, set when the
+    /// message is enqueued.
+    pub position: u64,
+    /// When the message was first enqueued; requeueing keeps it.
+    pub enqueued_at: Instant,
+}
+
+impl QueuedMessage {
+    pub fn new(message: Message, store_id: Option<u64>) -> Self {
+        QueuedMessage {
+            message,
+            store_id,
+            redelivered: false,
+            delivery_count: 0,
+            position: 0,
+            enqueued_at: Instant::now(),
+        }
+    }
+
+    fn bytes(&self) -> u64 {
+        self.message.memory_footprint()
+    }
+
+    /// The message as handed to a consumer: once it has come back from an
+    /// earlier delivery, it carries the `x-delivery-count` header.
+    pub fn for_delivery(&self) -> Message {
+        let mut message = self.message.clone();
+        if self.delivery_count > 0 {
+            message
+                .properties
+                .headers
+                .get_or_insert_with(FieldTable::new)
+                .insert("x-delivery-count", FieldValue::LongLongInt(self.delivery_count.into()));
+        }
+        message
+    }
+}
+
+/// Limits that `Basic.Qos` puts on deliveries awaiting an ack, shared by
+/// the consumers they cover: one consumer, or all consumers of a channel.
+/// A later `Basic.Qos` may change the limits of a window in use.
+#[derive(Debug, Default)]
+pub struct Prefetch {
+    /// Most unacked deliveries; 0 means no limit.
+    count: AtomicU16,
+    /// Largest total memory footprint of unacked messages; 0 means no limit.
+    size: AtomicU32,
+    unacked: Mutex<Unacked>,
+}
+
+/// What a `Prefetch` window currently holds.
+#[derive(Debug, Default)]
+struct Unacked {
+    count: u32,
+    bytes: u64,
+    /// Since when a ready message has been waiting for room in the window.
+    full_since: Option<Instant>,
+}
+
+impl Prefetch {
+    /// The limits of `Basic.Qos`, or `None` if it sets none.
+    pub fn new(count: u16, size: u32) -> Option<Arc<Prefetch>> {
+        (count != 0 || size != 0).then(|| Prefetch::window(count, size))
+    }
+
+    /// A window with the limits of `Basic.Qos`, which may be none yet.
+    pub fn window(count: u16, size: u32) -> Arc<Prefetch> {
+        Arc::new(Prefetch {
+            count: AtomicU16::new(count),
+            size: AtomicU32::new(size),
+            ..Default::default()
+        })
+    }
+
+    /// Changes the limits. Deliveries already over lower limits stay out;
+    /// no more are made until enough of them are settled.
+    pub fn set_limits(&self, count: u16, size: u32) {
+        self.count.store(count, Ordering::Relaxed);
+        self.size.store(size, Ordering::Relaxed);
+    }
+
+    /// Whether a message with a footprint of `bytes` may be delivered without
+    /// going over either limit. With nothing unacked any message fits, so
+    /// one larger than the size limit cannot stall the consumer forever.
+    pub fn has_room(&self, bytes: u64) -> bool {
+        let unacked = self.unacked.lock().unwrap();
+        if unacked.count == 0 {
+            return true;
+        }
+        let (count, size) = (self.count.load(Ordering::Relaxed), self.size.load(Ordering::Relaxed));
+        (count == 0 || unacked.count < u32::from(count)) && (size == 0 || unacked.bytes + bytes <= u64::from(size))
+    }
+
+    pub fn add(&self, bytes: u64) {
+        let mut unacked = self.unacked.lock().unwrap();
+        unacked.count += 1;
+        unacked.bytes += bytes;
+    }
+
+    /// Frees the room of a delivery that was acked, rejected or requeued.
+    pub fn release(&self, bytes: u64) {
+        let mut unacked = self.unacked.lock().unwrap();
+        unacked.count = unacked.count.saturating_sub(1);
+        unacked.bytes = unacked.bytes.saturating_sub(bytes);
+        unacked.full_since = None;
+    }
+
+    /// Notes that a ready message is waiting for room in the window.
+    fn mark_full(&self, now: Instant) {
+        self.unacked.lock().unwrap().full_since.get_or_insert(now);
+    }
+
+    /// Since when the window has kept a ready message waiting, if it has.
+    pub fn full_since(&self) -> Option<Instant> {
+        self.unacked.lock().unwrap().full_since
+    }
+}
+
+/// Deliveries awaiting an ack across every queue, against
+/// `Config::max_unacked_messages`. Queues check it like a prefetch window
+/// shared by all consumers that ack; since each checks under its own lock,
+/// queues dispatching at the same moment may go over by a delivery each.
+#[derive(Debug, Default)]
+pub struct UnackedLimit {
+    /// 0 means no limit.
+    max: u64,
+    count: AtomicU64,
+    /// Set when a delivery was held back at the limit, so the broker knows
+    /// to dispatch every queue once there is room.
+    stalled: AtomicBool,
+}
+
+impl UnackedLimit {
+    pub fn new(max: u64) -> Self {
+        UnackedLimit {
+            max,
+            ..Default::default()
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    fn below_max(&self) -> bool {
+        self.max == 0 || self.count() < self.max
+    }
+
+    /// Whether another delivery may go out, noting a stall when not.
+    fn has_room(&self) -> bool {
+        if self.below_max() {
+            return true;
+        }
+        self.stalled.store(true, Ordering::SeqCst);
+        // An ack between the check and the store saw no stall to resume.
+        self.below_max()
+    }
+
+    fn add(&self, count: u64) {
+        self.count.fetch_add(count, Ordering::SeqCst);
+    }
+
+    fn remove(&self, count: u64) {
+        self.count.fetch_sub(count, Ordering::SeqCst);
+    }
+
+    /// Whether deliveries stalled at the limit and may go on now, clearing
+    /// the stall if so.
+    pub fn take_resumed(&self) -> bool {
+        self.below_max() && self.stalled.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// How much of the time a queue's consumers were able to take its messages
+/// at once, measured over fixed windows. The queue is held up while it has
+/// ready messages that no consumer has prefetch room for.
+#[derive(Debug, Clone)]
+pub struct Utilization {
+    /// Length of a window; zero makes the first window last forever.
+    window: Duration,
+    started: Instant,
+    /// Time held up in the current window, up to `held_since`.
+    held: Duration,
+    held_since: Option<Instant>,
+    /// Utilization over the last window that ran to its end.
+    last: Option<f64>,
+}
+
+impl Utilization {
+    pub fn new(window: Duration, now: Instant) -> Self {
+        Utilization {
+            window,
+            started: now,
+            held: Duration::ZERO,
+            held_since: None,
+            last: None,
+        }
+    }
+
+    /// Records whether the queue is held up from `now` on.
+    pub fn set_held(&mut self, held: bool, now: Instant) {
+        self.roll(now);
+        match (held, self.held_since) {
+            (true, None) => self.held_since = Some(now),
+            (false, Some(since)) => {
+                self.held += now.saturating_duration_since(since);
+                self.held_since = None;
+            }
+            _ => {}
+        }
+    }
+
+    /// The fraction of time, from 0.0 to 1.0, the queue was not held up:
+    /// over the last full window, or the current one until a window ends.
+    pub fn value(&mut self, now: Instant) -> f64 {
+        self.roll(now);
+        if let Some(last) = self.last {
+            return last;
+        }
+        let elapsed = now.saturating_duration_since(self.started);
+        if elapsed.is_zero() {
+            return 1.0;
+        }
+        let held = self.held
+            + self
+                .held_since
+                .map_or(Duration::ZERO, |since| now.saturating_duration_since(since));
+        1.0 - (held.as_secs_f64() / elapsed.as_secs_f64()).min(1.0)
+    }
+
+    /// Closes the windows that have ended by `now`.
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.started);
+        if self.window.is_zero() || elapsed < self.window {
+            return;
+        }
+        let windows = elapsed.as_nanos() / self.window.as_nanos();
+        let held = if windows > 1 {
+            // Nothing changed during the windows since the first ended.
+            if self.held_since.is_some() {
+                self.window
+            } else {
+                Duration::ZERO
+            }
+        } else {
+            let end = self.started + self.window;
+            self.held
+                + self
+                    .held_since
+                    .map_or(Duration::ZERO, |since| end.saturating_duration_since(since))
+        };
+        self.last = Some(1.0 - (held.as_secs_f64() / self.window.as_secs_f64()).min(1.0));
+        let into_window = (elapsed.as_nanos() % self.window.as_nanos()) as u64;
+        self.started = now - Duration::from_nanos(into_window);
+        self.held = Duration::ZERO;
+        self.held_since = self.held_since.map(|_| self.started);
+    }
+}
+
+/// How many messages a queue has taken in, delivered and seen acked since
+/// it was declared.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MessageTotals {
+    pub published: u64,
+    /// Deliveries to consumers and `Basic.Get`s, redeliveries included.
+    pub delivered: u64,
+    /// Explicit acks; no-ack deliveries are not counted.
+    pub acked: u64,
+}
+
+/// Messages per second over an interval.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Rates {
+    pub publish: f64,
+    pub deliver: f64,
+    pub ack: f64,
+}
+
+impl Rates {
+    /// The rates at which `from` became `to` over `elapsed`.
+    fn between(from: MessageTotals, to: MessageTotals, elapsed: Duration) -> Rates {
+        let per_second = |from: u64, to: u64| to.saturating_sub(from) as f64 / elapsed.as_secs_f64();
+        Rates {
+            publish: per_second(from.published, to.published),
+            deliver: per_second(from.delivered, to.delivered),
+            ack: per_second(from.acked, to.acked),
+        }
+    }
+}
+
+/// The most recent samples of a queue's `MessageTotals`, up to a fixed
+/// number, from which its rates are worked out by differencing.
+#[derive(Debug, Clone, Default)]
+pub struct RateHistory {
+    capacity: usize,
+    samples: VecDeque<(Instant, MessageTotals)>,
+}
+
+impl RateHistory {
+    pub fn new(capacity: usize) -> Self {
+        RateHistory {
+            capacity,
+            samples: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Records the totals as they were at `at`, forgetting the oldest
+    /// sample once the history is full.
+    pub fn record(&mut self, at: Instant, totals: MessageTotals) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((at, totals));
+    }
+
+    /// The rates between consecutive samples taken at or after `since`, or
+    /// between all of them, oldest first, each with when its interval
+    /// ended.
+    pub fn series(&self, since: Option<Instant>) -> Vec<(Instant, Rates)> {
+        let samples: Vec<&(Instant, MessageTotals)> = self.since(since).collect();
+        samples
+            .windows(2)
+            .filter(|pair| pair[1].0 > pair[0].0)
+            .map(|pair| (pair[1].0, Rates::between(pair[0].1, pair[1].1, pair[1].0 - pair[0].0)))
+            .collect()
+    }
+
+    /// The rates from the first sample taken at or after `since`, or the
+    /// first of all, to the last one; `None` without two samples apart in
+    /// time.
+    pub fn average(&self, since: Option<Instant>) -> Option<Rates> {
+        let first = self.since(since).next()?;
+        let last = self.samples.back()?;
+        (last.0 > first.0).then(|| Rates::between(first.1, last.1, last.0 - first.0))
+    }
+
+    fn since(&self, since: Option<Instant>) -> impl Iterator<Item = &(Instant, MessageTotals)> {
+        self.samples
+            .iter()
+            .filter(move |(at, _)| since.is_none_or(|since| *at >= since))
+    }
+}
+
+/// A message handed to a consumer, sent to the task owning its connection.
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub channel: u16,
+    pub consumer_tag: String,
+    pub queue: String,
+    /// Queue-local id of this delivery, used to ack or requeue it.
+    pub message_id: u64,
+    pub no_ack: bool,
+    pub redelivered: bool,
+    pub message: Message,
+    /// Prefetch window of the consumer, charged for this delivery until it
+    /// is settled.
+    pub prefetch: Option<Arc<Prefetch>>,
+    /// What `prefetch` is charged: the memory footprint of the message as
+    /// it was queued.
+    pub footprint: u64,
+}
+
+/// Sending half of a connection's delivery inbox.
+pub type DeliverySender = mpsc::UnboundedSender<Delivery>;
+
+/// A subscription created by `Basic.Consume`.
+#[derive(Debug, Clone)]
+pub struct Consumer {
+    /// Broker-wide id, used to cancel the consumer.
+    pub id: u64,
+    pub tag: String,
+    pub channel: u16,
+    pub no_ack: bool,
+    /// Consumer-level exclusivity: no other consumer may subscribe while
+    /// this one is attached. Unrelated to the queue's own `exclusive` flag,
+    /// which ties the queue to the declaring connection.
+    pub exclusive: bool,
+    pub sender: DeliverySender,
+    /// Set by `Basic.Qos`; ignored for no-ack consumers.
+    pub prefetch: Option<Arc<Prefetch>>,
+    /// Share of the deliveries the consumer gets while it has room, relative
+    /// to the other consumers: its `x-priority` plus one.
+    pub weight: u32,
+    /// Credit in the queue's weighted round-robin; see `Queue::next_consumer`.
+    pub credit: i64,
+}
+
+impl Consumer {
+    /// Whether the consumer may take a message with a footprint of `bytes`
+    /// now, within its prefetch window and the broker-wide `limit`.
+    fn has_room(&self, bytes: u64, limit: &UnackedLimit) -> bool {
+        self.no_ack || (self.prefetch.as_ref().is_none_or(|prefetch| prefetch.has_room(bytes)) && limit.has_room())
+    }
+}
+
+/// Unacked messages of a consumer that went away, set aside under the
+/// queue's `x-consumer-reconnect-window` for a consumer with its tag.
+#[derive(Debug)]
+pub struct PendingLane {
+    /// In queue order.
+    messages: VecDeque<QueuedMessage>,
+    /// When the messages return to the queue if no consumer with the tag
+    /// has taken them.
+    expires: Instant,
+}
+
+/// A queue's contents and subscriptions.
+///
+/// Each queue sits behind its own lock in the broker, so publishers and
+/// consumers of different queues never contend. Messages are pushed to
+/// consumers as `Delivery` values over the consumer's connection inbox; the
+/// connection task turns them into frames. A consumer whose inbox is gone
+/// (its connection died) is dropped on the next delivery attempt and the
+/// message stays in the queue for the remaining consumers.
+///
+/// Ready messages are kept in the order the queue received them. A message
+/// that is requeued, whether by a nack, a recover or its channel closing,
+/// goes back to its original place: ahead of every message received after
+/// it, and behind any older message that was requeued as well. A requeued
+/// message can still arrive after newer ones that were delivered while it
+/// was out, and with several consumers deliveries may be processed out of
+/// order anyway.
+///
+/// Ready messages live in a `VecDeque` ordered by position, which keeps
+/// the operations on them cheap however long the queue grows: enqueueing
+/// at the back, delivering or fetching from the head and dropping the head
+/// for `x-max-length` are O(1). Messages returned together, the usual case
+/// of a channel closing, are put back ahead of the head in O(1) each; one
+/// that has to go behind a message returned earlier is placed by binary
+/// search and costs a shift of the messages in front of it. `peek` reaches
+/// any offset in O(1) and then costs O(1) per message it returns.
+#[derive(Debug)]
+pub struct Queue {
+    pub name: String,
+    pub durable: bool,
+    pub exclusive: bool,
+    pub auto_delete: bool,
+    pub arguments: FieldTable,
+    pub queue_type: QueueType,
+    /// Read from the declare arguments together with those of `policy`.
+    pub options: QueueOptions,
+    /// Name of the policy that applies to the queue.
+    pub policy: Option<String>,
+    /// Messages ready for delivery, oldest first: in `position` order.
+    pub messages: VecDeque<QueuedMessage>,
+    /// Attached consumers, in subscription order.
+    pub consumers: Vec<Consumer>,
+    /// Messages delivered to manual-ack consumers and not yet acked.
+    pub unacked: HashMap<u64, QueuedMessage>,
+    /// Messages held for consumers that may reconnect, by consumer tag.
+    pub pending_lanes: HashMap<String, PendingLane>,
+    /// Set while the queue's virtual host is drained: nothing is delivered
+    /// or fetched until it is cleared.
+    pub paused: bool,
+    /// Last declare, consume, cancel or `Basic.Get`, for `x-expires`.
+    pub last_used: Instant,
+    /// Unix time the queue was declared.
+    pub created_at: Duration,
+    /// Unix time of the last declare, consume, cancel, `Basic.Get`, publish
+    /// or delivery, for spotting idle queues.
+    pub last_active: Duration,
+    /// Enforces `x-max-publish-rate`.
+    pub rate_limiter: Option<TokenBucket>,
+    /// Time consumers spent unable to keep up with the queue.
+    pub utilization: Utilization,
+    /// Messages through the queue so far, sampled into `rates`.
+    pub totals: MessageTotals,
+    pub rates: RateHistory,
+    /// Totals of the queue's virtual host, which count this queue's ready
+    /// and unacked messages.
+    pub usage: Arc<VHostUsage>,
+    /// The broker's count of unacked deliveries, which counts this queue's.
+    pub unacked_limit: Arc<UnackedLimit>,
+    /// The broker's clock, which `last_used`, `last_active`, `utilization`
+    /// and `QueuedMessage::enqueued_at` are read from.
+    pub clock: Arc<dyn Clock>,
+    next_message_id: u64,
+    /// `QueuedMessage::position` of the next message enqueued.
+    next_position: u64,
+}
+
+impl Queue {
+    pub fn new(name: String, queue_type: QueueType) -> Self {
+        Queue {
+            name,
+            durable: false,
+            exclusive: false,
+            auto_delete: false,
+            arguments: FieldTable::new(),
+            queue_type,
+            options: QueueOptions::default(),
+            policy: None,
+            messages: VecDeque::new(),
+            consumers: Vec::new(),
+            unacked: HashMap::new(),
+            pending_lanes: HashMap::new(),
+            paused: false,
+            last_used: Instant::now(),
+            created_at: TokioClock.unix_time(),
+            last_active: TokioClock.unix_time(),
+            rate_limiter: None,
+            utilization: Utilization::new(Duration::ZERO, Instant::now()),
+            totals: MessageTotals::default(),
+            rates: RateHistory::default(),
+            usage: Arc::default(),
+            unacked_limit: Arc::default(),
+            clock: Arc::new(TokioClock),
+            next_message_id: 0,
+            next_position: 0,
+        }
+    }
+
+    /// Adds a message to the back of the queue and delivers what it can.
+    /// Returns the store ids of messages that no longer need to be kept.
+    pub fn enqueue(&mut self, mut message: QueuedMessage) -> Vec<u64> {
+        message.position = self.next_position;
+        message.enqueued_at = self.clock.now();
+        self.last_active = self.clock.unix_time();
+        self.next_position += 1;
+        self.usage.add(message.bytes());
+        self.totals.published += 1;
+        self.messages.push_back(message);
+        self.dispatch()
+    }
+
+    /// The ready messages from `offset` places behind the head on, at most
+    /// `count` of them, leaving the queue untouched.
+    pub fn peek(&self, offset: usize, count: usize) -> impl Iterator<Item = &QueuedMessage> {
+        self.messages.range(offset.min(self.messages.len())..).take(count)
+    }
+
+    /// Records activity that keeps an `x-expires` queue alive, and moves
+    /// `last_active` along.
+    pub fn touch(&mut self) {
+        self.last_used = self.clock.now();
+        self.last_active = self.clock.unix_time();
+    }
+
+    /// Whether the queue has gone unused past its `x-expires`. A queue with
+    /// consumers is in use however long ago it was last touched.
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.consumers.is_empty()
+            && self
+                .options
+                .expires
+                .is_some_and(|expires| now.saturating_duration_since(self.last_used) >= expires)
+    }
+
+    /// Whether the queue comes back after a restart: durable, and neither
+    /// exclusive to a connection nor deleted with its last consumer, since
+    /// no connection or consumer outlives the broker.
+    pub fn survives_restart(&self) -> bool {
+        self.durable && !self.exclusive && !self.auto_delete
+    }
+
+    /// Every message the queue still holds, ready, delivered and unacked,
+    /// or in a pending lane, in the order the queue received them.
+    pub fn held_messages_mut(&mut self) -> Vec<&mut QueuedMessage> {
+        let mut held: Vec<&mut QueuedMessage> = self
+            .messages
+            .iter_mut()
+            .chain(self.unacked.values_mut())
+            .chain(
+                self.pending_lanes
+                    .values_mut()
+                    .flat_map(|lane| lane.messages.iter_mut()),
+            )
+            .collect();
+        held.sort_by_key(|queued| queued.position);
+        held
+    }
+
+    pub fn message_counts(&self) -> MessageCounts {
+        MessageCounts {
+            ready: self.messages.len(),
+            unacked: self.unacked.len()
+                + self
+                    .pending_lanes
+                    .values()
+                    .map(|lane| lane.messages.len())
+                    .sum::<usize>(),
+        }
+    }
+
+    /// Whether the queue holds `x-max-length` ready messages.
+    pub fn is_full(&self) -> bool {
+        self.options.max_length.is_some_and(|max| self.messages.len() >= max)
+    }
+
+    /// Drops the oldest ready messages until there is room for one more,
+    /// returning them. A queue limited to no messages at all stays full.
+    pub fn drop_head(&mut self) -> Vec<QueuedMessage> {
+        let mut dropped = Vec::new();
+        while self.is_full() {
+            let Some(queued) = self.messages.pop_front() else {
+                break;
+            };
+            self.usage.remove(queued.bytes());
+            dropped.push(queued);
+        }
+        dropped
+    }
+
+    /// Hands ready messages to consumers in weighted round-robin, skipping
+    /// consumers whose prefetch window is full, until either runs out. A consumer
+    /// with a pending lane gets the lane's messages before any other.
+    /// Returns the store ids of messages delivered to no-ack consumers.
+    pub fn dispatch(&mut self) -> Vec<u64> {
+        let mut settled = Vec::new();
+        if !self.paused {
+            self.dispatch_lanes(&mut settled);
+        }
+        while !self.paused && !self.consumers.is_empty() {
+            let Some(bytes) = self.messages.front().map(QueuedMessage::bytes) else {
+                break;
+            };
+            let Some(index) = self.next_consumer(bytes) else {
+                break;
+            };
+            let queued = self.messages.pop_front().unwrap();
+            if let Some(queued) = self.deliver(index, queued, &mut settled) {
+                self.messages.push_front(queued);
+            }
+        }
+        let now = self.clock.now();
+        let held = !self.paused && !self.messages.is_empty() && !self.consumers.is_empty();
+        if held {
+            // Every consumer is out of room; start the slow-consumer clocks.
+            for prefetch in self.consumers.iter().filter_map(|c| c.prefetch.as_ref()) {
+                prefetch.mark_full(now);
+            }
+        }
+        self.utilization.set_held(held, now);
+        settled
+    }
+
+    /// Picks the consumer for a message with a footprint of `bytes`, by
+    /// smooth weighted round-robin: every consumer earns its weight in
+    /// credit, and the one with the most among those with room, the earliest
+    /// subscribed on a tie, pays back the total of all weights. With equal
+    /// weights this is a plain rotation; otherwise consumers get deliveries
+    /// in proportion to their weight, spread out rather than in runs. A
+    /// consumer whose window is full saves credit up to that total, so it
+    /// is first in line once it has room again, but only for one turn.
+    fn next_consumer(&mut self, bytes: u64) -> Option<usize> {
+        let room: Vec<bool> = self
+            .consumers
+            .iter()
+            .map(|c| c.has_room(bytes, &self.unacked_limit))
+            .collect();
+        if !room.contains(&true) {
+            return None;
+        }
+        let total: i64 = self.consumers.iter().map(|c| i64::from(c.weight)).sum();
+        let mut chosen = None;
+        for (index, consumer) in self.consumers.iter_mut().enumerate() {
+            consumer.credit = (consumer.credit + i64::from(consumer.weight)).min(total);
+            if room[index] && chosen.is_none_or(|(_, credit)| consumer.credit > credit) {
+                chosen = Some((index, consumer.credit));
+            }
+        }
+        let (index, _) = chosen?;
+        self.consumers[index].credit -= total;
+        Some(index)
+    }
+
+    /// Hands the messages of pending lanes to the consumers with their tags.
+    fn dispatch_lanes(&mut self, settled: &mut Vec<u64>) {
+        let mut index = 0;
+        while index < self.consumers.len() && !self.pending_lanes.is_empty() {
+            let tag = self.consumers[index].tag.clone();
+            let Some(lane) = self.pending_lanes.get_mut(&tag) else {
+                index += 1;
+                continue;
+            };
+            let expires = lane.expires;
+            let bytes = lane.messages.front().map_or(0, QueuedMessage::bytes);
+            if !self.consumers[index].has_room(bytes, &self.unacked_limit) {
+                index += 1;
+                continue;
+            }
+            let queued = lane.messages.pop_front().unwrap();
+            if lane.messages.is_empty() {
+                self.pending_lanes.remove(&tag);
+            }
+            if let Some(queued) = self.deliver(index, queued, settled) {
+                let lane = self.pending_lanes.entry(tag).or_insert_with(|| PendingLane {
+                    messages: VecDeque::new(),
+                    expires,
+                });
+                lane.messages.push_front(queued);
+            }
+        }
+    }
+
+    /// Sends `queued` to the consumer at `index`. A consumer whose
+    /// connection is gone is detached and the message handed back.
+    fn deliver(&mut self, index: usize, queued: QueuedMessage, settled: &mut Vec<u64>) -> Option<QueuedMessage> {
+        let bytes = queued.bytes();
+        let consumer = &self.consumers[index];
+        let prefetch = consumer.prefetch.clone().filter(|_| !consumer.no_ack);
+        self.next_message_id += 1;
+        let delivery = Delivery {
+            channel: consumer.channel,
+            consumer_tag: consumer.tag.clone(),
+            queue: self.name.clone(),
+            message_id: self.next_message_id,
+            no_ack: consumer.no_ack,
+            redelivered: queued.redelivered,
+            message: queued.for_delivery(),
+            prefetch: prefetch.clone(),
+            footprint: bytes,
+        };
+        // Charged before sending, so an ack can never release it first.
+        if let Some(prefetch) = &prefetch {
+            prefetch.add(bytes);
+        }
+        if consumer.sender.send(delivery).is_err() {
+            if let Some(prefetch) = &prefetch {
+                prefetch.release(bytes);
+            }
+            self.consumers.remove(index);
+            return Some(queued);
+        }
+        self.totals.delivered += 1;
+        self.last_active = self.clock.unix_time();
+        if consumer.no_ack {
+            self.usage.remove(queued.bytes());
+            settled.extend(queued.store_id);
+        } else {
+            self.unacked.insert(self.next_message_id, queued);
+            self.unacked_limit.add(1);
+        }
+        None
+    }
+
+    /// The share of time consumers could take the queue's messages at once,
+    /// or `None` without consumers.
+    pub fn consumer_utilization(&mut self, now: Instant) -> Option<f64> {
+        (!self.consumers.is_empty()).then(|| self.utilization.value(now))
+    }
+
+    /// Consumers that have kept a ready message waiting since before
+    /// `cutoff` because their prefetch window is full.
+    pub fn slow_consumers(&self, cutoff: Instant) -> Vec<&Consumer> {
+        if self.messages.is_empty() {
+            return Vec::new();
+        }
+        self.consumers
+            .iter()
+            .filter(|c| {
+                !c.no_ack
+                    && c.prefetch
+                        .as_ref()
+                        .and_then(|prefetch| prefetch.full_since())
+                        .is_some_and(|since| since <= cutoff)
+            })
+            .collect()
+    }
+
+    /// Removes every ready message, returning them. Deliveries awaiting an
+    /// ack are left alone.
+    pub fn purge(&mut self) -> Vec<QueuedMessage> {
+        self.utilization.set_held(false, self.clock.now());
+        let purged: Vec<QueuedMessage> = self.messages.drain(..).collect();
+        for queued in &purged {
+            self.usage.remove(queued.bytes());
+        }
+        purged
+    }
+
+    /// Removes every message, ready or unacked, returning them; for
+    /// deleting the queue.
+    pub fn clear(&mut self) -> Vec<QueuedMessage> {
+        let mut cleared = self.purge();
+        self.unacked_limit.remove(self.unacked.len() as u64);
+        for (_, queued) in self.unacked.drain() {
+            self.usage.remove(queued.bytes());
+            cleared.push(queued);
+        }
+        for queued in self.pending_lanes.drain().flat_map(|(_, lane)| lane.messages) {
+            self.usage.remove(queued.bytes());
+            cleared.push(queued);
+        }
+        cleared
+    }
+
+    /// Takes the head message for `Basic.Get`, returning its delivery id and
+    /// the message. Unless `no_ack` is set the message stays in `unacked`
+    /// until it is acked or requeued.
+    pub fn get(&mut self, no_ack: bool) -> Option<(u64, QueuedMessage)> {
+        if self.paused {
+            return None;
+        }
+        let queued = self.messages.pop_front()?;
+        if self.messages.is_empty() {
+            self.utilization.set_held(false, self.clock.now());
+        }
+        self.next_message_id += 1;
+        self.totals.delivered += 1;
+        if no_ack {
+            self.usage.remove(queued.bytes());
+        } else {
+            self.unacked.insert(self.next_message_id, queued.clone());
+            self.unacked_limit.add(1);
+        }
+        Some((self.next_message_id, queued))
+    }
+
+    /// Removes an acked delivery, returning the message it carried.
+    pub fn ack(&mut self, message_id: u64) -> Option<QueuedMessage> {
+        let queued = self.unacked.remove(&message_id)?;
+        self.unacked_limit.remove(1);
+        self.usage.remove(queued.bytes());
+        self.totals.acked += 1;
+        Some(queued)
+    }
+
+    /// Puts unacked deliveries back in their original places, which are at
+    /// or near the head of the queue, and redelivers them. Returns the store
+    /// ids of messages that no longer need to be kept.
+    ///
+    /// If `delivered` is set the deliveries reached the consumer, and each
+    /// counts toward the queue's `x-delivery-limit`: messages past the limit
+    /// are not requeued but pushed to `poisoned`.
+    pub fn requeue(&mut self, message_ids: &[u64], delivered: bool, poisoned: &mut Vec<QueuedMessage>) -> Vec<u64> {
+        let returned = self.take_unacked(message_ids, delivered, poisoned);
+        restore(&mut self.messages, returned);
+        self.dispatch()
+    }
+
+    /// Returns the unacked deliveries of consumer `tag`, which went away,
+    /// like `requeue` does. With `x-consumer-reconnect-window` set they are
+    /// held in a pending lane instead, for a consumer with the same tag to
+    /// get first, and only return to the queue once the window has passed.
+    pub fn requeue_for(
+        &mut self,
+        tag: &str,
+        message_ids: &[u64],
+        poisoned: &mut Vec<QueuedMessage>,
+        now: Instant,
+    ) -> Vec<u64> {
+        let Some(window) = self.options.reconnect_window else {
+            return self.requeue(message_ids, true, poisoned);
+        };
+        let held = self.take_unacked(message_ids, true, poisoned);
+        if !held.is_empty() {
+            let lane = self
+                .pending_lanes
+                .entry(tag.to_string())
+                .or_insert_with(|| PendingLane {
+                    messages: VecDeque::new(),
+                    expires: now,
+                });
+            lane.expires = now + window;
+            restore(&mut lane.messages, held);
+        }
+        self.dispatch()
+    }
+
+    /// Returns the messages of pending lanes whose window has passed by
+    /// `now` to the queue, unless a consumer with the lane's tag is attached.
+    pub fn expire_lanes(&mut self, now: Instant) -> Vec<u64> {
+        let expired: Vec<String> = self
+            .pending_lanes
+            .iter()
+            .filter(|(tag, lane)| lane.expires <= now && !self.consumers.iter().any(|c| &c.tag == *tag))
+            .map(|(tag, _)| tag.clone())
+            .collect();
+        if expired.is_empty() {
+            return Vec::new();
+        }
+        for tag in expired {
+            let lane = self.pending_lanes.remove(&tag).unwrap();
+            restore(&mut self.messages, lane.messages.into());
+        }
+        self.dispatch()
+    }
+
+    /// Takes the given unacked deliveries, in queue order, marked as
+    /// redelivered. With `delivered` set each counts as a delivery, and
+    /// those past the queue's `x-delivery-limit` go to `poisoned` instead.
+    fn take_unacked(
+        &mut self,
+        message_ids: &[u64],
+        delivered: bool,
+        poisoned: &mut Vec<QueuedMessage>,
+    ) -> Vec<QueuedMessage> {
+        let mut ids = message_ids.to_vec();
+        ids.sort_unstable();
+        let mut taken = Vec::new();
+        for id in ids {
+            if let Some(mut queued) = self.unacked.remove(&id) {
+                self.unacked_limit.remove(1);
+                if delivered {
+                    queued.delivery_count += 1;
+                    if self
+                        .options
+                        .delivery_limit
+                        .is_some_and(|limit| queued.delivery_count > limit)
+                    {
+                        self.usage.remove(queued.bytes());
+                        poisoned.push(queued);
+                        continue;
+                    }
+                }
+                queued.redelivered = true;
+                taken.push(queued);
+            }
+        }
+        taken
+    }
+}
+
+/// Puts `returned` back into `messages` at their positions. Going from the
+/// last to the first, each one older than the current head is pushed in
+/// front of it, so a batch returning ahead of the queue costs O(1) per
+/// message rather than a shift of the whole batch for each.
+fn restore(messages: &mut VecDeque<QueuedMessage>, mut returned: Vec<QueuedMessage>) {
+    returned.sort_by_key(|m| m.position);
+    for queued in returned.into_iter().rev() {
+        if messages.front().is_none_or(|head| queued.position < head.position) {
+            messages.push_front(queued);
+        } else {
+            let index = messages.partition_point(|m| m.position < queued.position);
+            messages.insert(index, queued);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queued(body: &[u8]) -> QueuedMessage {
+        QueuedMessage::new(
+            Message {
+                exchange: "".into(),
+                routing_key: "jobs".into(),
+                properties: Default::default(),
+                body: body.to_vec(),
+            },
+            None,
+        )
+    }
+
+    fn consumer(id: u64, sender: DeliverySender) -> Consumer {
+        Consumer {
+            id,
+            tag: format!("ctag-{}", id),
+            channel: 1,
+            no_ack: false,
+            exclusive: false,
+            sender,
+            prefetch: None,
+            weight: 1,
+            credit: 0,
+        }
+    }
+
+    #[test]
+    fn test_random_queue_names_differ() {
+        let mut names = RandomQueueNames::new();
+        let (first, second) = (names.generate(), names.generate());
+        assert!(first.starts_with("amq.gen-"));
+        assert_eq!(first.len(), "amq.gen-".len() + 22);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_dead_consumer_is_dropped_without_losing_the_message() {
+        let mut queue = Queue::new("jobs".into(), QueueType::Classic);
+        let (dead, _) = mpsc::unbounded_channel();
+        let (live, mut inbox) = mpsc::unbounded_channel();
+        queue.consumers.push(consumer(1, dead));
+        queue.consumers.push(consumer(2, live));
+
+        queue.enqueue(queued(b"a"));
+        assert_eq!(queue.consumers.len(), 1);
+        let delivery = inbox.try_recv().unwrap();
+        assert_eq!((delivery.consumer_tag.as_str(), &delivery.message.body[..]), ("ctag-2", &b"a"[..]));
+        assert!(queue.messages.is_empty());
+    }
+
+    #[test]
+    fn test_full_prefetch_window_skips_the_consumer() {
+        let mut queue = Queue::new("jobs".into(), QueueType::Classic);
+        let (limited, mut limited_inbox) = mpsc::unbounded_channel();
+        let (open, mut open_inbox) = mpsc::unbounded_channel();
+        let window = Prefetch::new(1, 0);
+        queue.consumers.push(Consumer {
+            prefetch: window.clone(),
+            ..consumer(1, limited)
+        });
+        queue.consumers.push(consumer(2, open));
+        for body in [b"a", b"b", b"c"] {
+            queue.enqueue(queued(body));
+        }
+        assert_eq!(&limited_inbox.try_recv().unwrap().message.body[..], b"a");
+        assert!(limited_inbox.try_recv().is_err());
+        assert_eq!(&open_inbox.try_recv().unwrap().message.body[..], b"b");
+        assert_eq!(&open_inbox.try_recv().unwrap().message.body[..], b"c");
+
+        window.unwrap().release(1);
+        queue.enqueue(queued(b"d"));
+        assert_eq!(&limited_inbox.try_recv().unwrap().message.body[..], b"d");
+    }
+
+    #[test]
+    fn test_consumers_with_different_prefetch_share_deliveries_fairly() {
+        let mut queue = Queue::new("jobs".into(), QueueType::Classic);
+        let mut consumers = Vec::new();
+        for (id, count) in [(1, 1), (2, 2), (3, 3)] {
+            let (sender, inbox) = mpsc::unbounded_channel();
+            let window = Prefetch::new(count, 0);
+            queue.consumers.push(Consumer {
+                prefetch: window.clone(),
+                ..consumer(id, sender)
+            });
+            consumers.push((inbox, window.unwrap(), 0));
+        }
+        for _ in 0..30 {
+            queue.enqueue(queued(b"job"));
+        }
+        // The consumers work at the same pace, acking one delivery each in turn.
+        let mut received = 0;
+        while received < 30 {
+            for (inbox, window, count) in &mut consumers {
+                if inbox.try_recv().is_ok() {
+                    *count += 1;
+                    received += 1;
+                    window.release(1);
+                    queue.dispatch();
+                }
+            }
+        }
+        // Each gets a third, give or take what its window holds at the end.
+        let counts: Vec<usize> = consumers.iter().map(|(_, _, count)| *count).collect();
+        assert!(counts.iter().all(|count| (9..=11).contains(count)), "{:?}", counts);
+    }
+
+    #[test]
+    fn test_deliveries_follow_consumer_weights() {
+        let mut queue = Queue::new("jobs".into(), QueueType::Classic);
+        let (light, mut light_inbox) = mpsc::unbounded_channel();
+        let (heavy, mut heavy_inbox) = mpsc::unbounded_channel();
+        queue.consumers.push(consumer(1, light));
+        queue.consumers.push(Consumer {
+            weight: 2,
+            ..consumer(2, heavy)
+        });
+        for i in 0..30u8 {
+            queue.enqueue(queued(&[i]));
+        }
+        let bodies = |inbox: &mut mpsc::UnboundedReceiver<Delivery>| {
+            std::iter::from_fn(|| inbox.try_recv().ok())
+                .map(|d| d.message.body[0])
+                .collect::<Vec<u8>>()
+        };
+        let light = bodies(&mut light_inbox);
+        assert_eq!((light.len(), bodies(&mut heavy_inbox).len()), (10, 20));
+        // Spread out: the light consumer gets every third message.
+        assert!(light.windows(2).all(|pair| pair[1] - pair[0] == 3), "{:?}", light);
+    }
+
+    #[test]
+    fn test_utilization_is_measured_per_window() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut utilization = Utilization::new(Duration::from_secs(10), start);
+        utilization.set_held(true, at(2));
+        utilization.set_held(false, at(6));
+        assert_eq!(utilization.value(at(8)), 0.5);
+        // The finished window is reported until the next one ends.
+        utilization.set_held(true, at(13));
+        assert_eq!(utilization.value(at(12)), 0.6);
+        assert_eq!(utilization.value(at(19)), 0.6);
+        assert!((utilization.value(at(25)) - 0.3).abs() < 1e-9);
+        assert_eq!(utilization.value(at(45)), 0.0);
+    }
+
+    #[test]
+    fn test_full_prefetch_window_lowers_utilization() {
+        let mut queue = Queue::new("jobs".into(), QueueType::Classic);
+        let (sender, mut inbox) = mpsc::unbounded_channel();
+        let window = Prefetch::new(1, 0);
+        queue.consumers.push(Consumer {
+            prefetch: window.clone(),
+            ..consumer(1, sender)
+        });
+        queue.enqueue(queued(b"a"));
+        let later = Instant::now() + Duration::from_secs(1);
+        assert_eq!(queue.consumer_utilization(later), Some(1.0));
+
+        queue.enqueue(queued(b"b"));
+        let utilization = queue.consumer_utilization(later + Duration::from_secs(1)).unwrap();
+        assert!(utilization < 1.0, "{}", utilization);
+        inbox.try_recv().unwrap();
+
+        queue.consumers.clear();
+        assert_eq!(queue.consumer_utilization(later), None);
+    }
+
+    #[test]
+    fn test_requeue_restores_order_and_marks_redelivered() {
+        let mut queue = Queue::new("jobs".into(), QueueType::Classic);
+        let (sender, mut inbox) = mpsc::unbounded_channel();
+        queue.consumers.push(consumer(1, sender));
+        for body in [b"a", b"b", b"c"] {
+            queue.enqueue(queued(body));
+        }
+        let ids: Vec<u64> = (0..3).map(|_| inbox.try_recv().unwrap().message_id).collect();
+        queue.consumers.clear();
+
+        queue.requeue(&[ids[2], ids[0]], true, &mut Vec::new());
+        let bodies: Vec<&[u8]> = queue.messages.iter().map(|m| &m.message.body[..]).collect();
+        assert_eq!(bodies, vec![&b"a"[..], &b"c"[..]]);
+        assert!(queue.messages.iter().all(|m| m.redelivered));
+        assert_eq!(queue.unacked.len(), 1);
+    }
+
+    #[test]
+    fn test_requeues_return_messages_to_their_original_places() {
+        let mut queue = Queue::new("jobs".into(), QueueType::Classic);
+        let (sender, mut inbox) = mpsc::unbounded_channel();
+        queue.consumers.push(consumer(1, sender));
+        for body in [b"1", b"2", b"3", b"4"] {
+            queue.enqueue(queued(body));
+        }
+        let ids: Vec<u64> = (0..4).map(|_| inbox.try_recv().unwrap().message_id).collect();
+        queue.consumers.clear();
+
+        // Returned one at a time and out of order, they still line up.
+        for id in [ids[2], ids[0], ids[1]] {
+            queue.requeue(&[id], true, &mut Vec::new());
+        }
+        queue.enqueue(queued(b"5"));
+        let bodies: Vec<&[u8]> = queue.messages.iter().map(|m| &m.message.body[..]).collect();
+        assert_eq!(bodies, vec![&b"1"[..], &b"2"[..], &b"3"[..], &b"5"[..]]);
+    }
+
+    #[test]
+    fn test_peek_reads_from_an_offset_without_taking() {
+        let mut queue = Queue::new("jobs".into(), QueueType::Classic);
+        for body in [b"a", b"b", b"c", b"d"] {
+            queue.enqueue(queued(body));
+        }
+        let bodies =
+            |offset, count| -> Vec<Vec<u8>> { queue.peek(offset, count).map(|m| m.message.body.clone()).collect() };
+        assert_eq!(bodies(1, 2), vec![b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(bodies(3, 10), vec![b"d".to_vec()]);
+        assert!(bodies(9, 1).is_empty());
+        assert_eq!(queue.messages.len(), 4);
+    }
+
+    /// Times enqueueing a million messages, popping and peeking at the
+    /// head, peeking deep into the queue and requeueing a batch. Run with
+    /// `cargo test --release -- --ignored bench_queue_operations --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_queue_operations() {
+        const MESSAGES: usize = 1_000_000;
+        const ROUNDS: u32 = 10_000;
+        let mut queue = Queue::new("jobs".into(), QueueType::Classic);
+        let started = std::time::Instant::now();
+        for _ in 0..MESSAGES {
+            queue.enqueue(queued(b"x"));
+        }
+        println!("enqueue: {:?} per message", started.elapsed() / MESSAGES as u32);
+
+        for offset in [0, MESSAGES / 2, MESSAGES - 10] {
+            let started = std::time::Instant::now();
+            for _ in 0..ROUNDS {
+                let peeked: usize = queue.peek(offset, 10).map(|m| m.message.body.len()).sum();
+                assert_eq!(std::hint::black_box(peeked), 10);
+            }
+            println!("peek 10 at {}: {:?}", offset, started.elapsed() / ROUNDS);
+        }
+
+        let started = std::time::Instant::now();
+        let taken: Vec<u64> = (0..ROUNDS).map(|_| queue.get(false).unwrap().0).collect();
+        println!("head pop: {:?}", started.elapsed() / ROUNDS);
+
+        let started = std::time::Instant::now();
+        queue.requeue(&taken, false, &mut Vec::new());
+        println!("requeue: {:?} per message", started.elapsed() / ROUNDS);
+        assert_eq!(queue.messages.len(), MESSAGES);
+        assert!(queue
+            .messages
+            .iter()
+            .zip(queue.messages.iter().skip(1))
+            .all(|(a, b)| a.position < b.position));
+    }
+
+    #[test]
+    fn test_pending_lane_returns_to_the_queue_after_its_window() {
+        let mut queue = Queue::new("jobs".into(), QueueType::Classic);
+        queue.options.reconnect_window = Some(Duration::from_secs(5));
+        let (sender, mut inbox) = mpsc::unbounded_channel();
+        queue.consumers.push(consumer(1, sender));
+        for body in [b"a", b"b"] {
+            queue.enqueue(queued(body));
+        }
+        let ids: Vec<u64> = (0..2).map(|_| inbox.try_recv().unwrap().message_id).collect();
+        queue.consumers.clear();
+
+        let now = Instant::now();
+        queue.requeue_for("ctag-1", &ids, &mut Vec::new(), now);
+        // Another consumer does not get the held messages.
+        let (other, mut other_inbox) = mpsc::unbounded_channel();
+        queue.consumers.push(consumer(2, other));
+        queue.dispatch();
+        assert!(other_inbox.try_recv().is_err());
+        assert_eq!(queue.message_counts().unacked, 2);
+
+        queue.expire_lanes(now + Duration::from_secs(4));
+        assert!(other_inbox.try_recv().is_err());
+        queue.expire_lanes(now + Duration::from_secs(5));
+        let bodies: Vec<Vec<u8>> = (0..2).map(|_| other_inbox.try_recv().unwrap().message.body).collect();
+        assert_eq!(bodies, vec![b"a".to_vec(), b"b".to_vec()]);
+        assert!(queue.pending_lanes.is_empty());
+    }
+
+    #[test]
+    fn test_delivery_limit_counts_only_delivered_returns() {
+        let mut queue = Queue::new("jobs".into(), QueueType::Classic);
+        queue.options.delivery_limit = Some(1);
+        let (sender, mut inbox) = mpsc::unbounded_channel();
+        queue.consumers.push(consumer(1, sender));
+        queue.enqueue(queued(b"a"));
+        let mut poisoned = Vec::new();
+
+        // Handing it back undelivered leaves the count alone.
+        let first = inbox.try_recv().unwrap();
+        assert_eq!(first.message.properties.headers, None);
+        queue.requeue(&[first.message_id], false, &mut poisoned);
+        let second = inbox.try_recv().unwrap();
+        assert_eq!(second.message.properties.headers, None);
+
+        queue.requeue(&[second.message_id], true, &mut poisoned);
+        let third = inbox.try_recv().unwrap();
+        let headers = third.message.properties.headers.unwrap();
+        assert_eq!(headers.get("x-delivery-count"), Some(&FieldValue::LongLongInt(1)));
+
+        queue.requeue(&[third.message_id], true, &mut poisoned);
+        assert!(inbox.try_recv().is_err());
+        assert_eq!(poisoned.len(), 1);
+        assert_eq!(poisoned[0].delivery_count, 2);
+        assert!(queue.messages.is_empty() && queue.unacked.is_empty());
+    }
+}
@@ -0,0 +1,135 @@
+// src/rate_limit.rs
+
+//! Publish rate limiting for queues declared with `x-max-publish-rate`.
+//!
+//! Each such queue owns a token bucket holding up to one second's worth of
+//! messages. What happens to a publish that finds the bucket empty is
+//! broker-wide: see `RatePolicy`.
+//!
+//! Connections use the same bucket for `Config::max_frame_rate`, which
+//! counts every frame a client sends rather than its publishes, and pauses
+//! reading from the socket instead of refusing anything.
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// How publishes beyond a limit are treated: a queue's
+/// `x-max-publish-rate`, or a virtual host's quota.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RatePolicy {
+    /// Accept the message, but pause the publishing channel with
+    /// `Channel.Flow` until the bucket has refilled, or for
+    /// `QUOTA_RETRY_INTERVAL` over a quota.
+    #[default]
+    Flow,
+    /// Refuse the message; publishers in confirm mode get a nack.
+    Nack,
+}
+
+impl RatePolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RatePolicy::Flow => "flow",
+            RatePolicy::Nack => "nack",
+        }
+    }
+}
+
+/// A token bucket refilled continuously at `rate` tokens per second.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    /// Tokens left; negative while the bucket is in debt.
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket allowing `rate` messages per second, in bursts of up
+    /// to one second's worth.
+    pub fn new(rate: u32) -> Self {
+        TokenBucket::with_burst(rate, rate)
+    }
+
+    /// A full bucket allowing `rate` tokens per second, in bursts of up to
+    /// `burst`.
+    pub fn with_burst(rate: u32, burst: u32) -> Self {
+        let capacity = f64::from(burst.max(1));
+        TokenBucket {
+            rate: f64::from(rate.max(1)),
+            capacity,
+            tokens: capacity,
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.refilled = now;
+    }
+
+    /// Takes a token if one is left.
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Takes a token even if none is left, going into debt. Returns how long
+    /// until a token is available again, or `None` if one was taken without
+    /// exceeding the rate.
+    pub fn take(&mut self, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_allows_a_burst_then_the_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10);
+        assert!((0..10).all(|_| bucket.try_take(start)));
+        assert!(!bucket.try_take(start));
+        assert!(bucket.try_take(start + Duration::from_millis(100)));
+        assert!(!bucket.try_take(start + Duration::from_millis(150)));
+        // Idle time refills no more than the one-second capacity.
+        let later = start + Duration::from_secs(60);
+        assert_eq!((0..20).filter(|_| bucket.try_take(later)).count(), 10);
+    }
+
+    #[test]
+    fn test_take_goes_into_debt() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10);
+        for _ in 0..10 {
+            assert_eq!(bucket.take(start), None);
+        }
+        assert_eq!(bucket.take(start), Some(Duration::from_millis(200)));
+        assert!(!bucket.try_take(start + Duration::from_millis(150)));
+        assert!(bucket.try_take(start + Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn test_burst_is_separate_from_the_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::with_burst(100, 5);
+        assert_eq!((0..10).filter(|_| bucket.try_take(start)).count(), 5);
+        let later = start + Duration::from_secs(1);
+        assert_eq!((0..10).filter(|_| bucket.try_take(later)).count(), 5);
+    }
+}
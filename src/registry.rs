@@ -0,0 +1,554 @@
+// src/registry.rs
+
+//! Live connection counts, used to enforce per-user and per-IP limits, the
+//! accept-side limits on connections still in their handshake and on
+//! connection churn, and the list of open connections shown by the
+//! management API.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::warn;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::field_table::FieldTable;
+use crate::protocol::FRAME_HEARTBEAT;
+use crate::trace::FrameTrace;
+
+/// An opened connection, as the registry lists it.
+#[derive(Debug)]
+struct OpenConnection {
+    info: ConnectionInfo,
+    trace: Arc<FrameTrace>,
+    frames: Arc<FrameCounters>,
+}
+
+impl OpenConnection {
+    fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            frames: self.frames.counts(),
+            ..self.info.clone()
+        }
+    }
+}
+
+/// What a connection is counted against.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ConnectionKey {
+    User(String),
+    Ip(IpAddr),
+}
+
+/// Connection counts per user and per peer address.
+#[derive(Debug)]
+pub struct ConnectionRegistry {
+    /// Limit per authenticated user; 0 means no limit.
+    max_per_user: u32,
+    /// Limit per peer IP address; 0 means no limit.
+    max_per_ip: u32,
+    counts: Mutex<HashMap<ConnectionKey, u32>>,
+    /// Opened connections, by connection id.
+    open: Mutex<BTreeMap<u64, OpenConnection>>,
+    limits: HandshakeLimits,
+    /// One permit per connection in its handshake; `None` without a limit.
+    handshakes: Option<Arc<Semaphore>>,
+    /// Recent handshake failures per peer address.
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+    churn_limits: ChurnLimits,
+    /// Recent short-lived connections per peer address.
+    churn: Mutex<HashMap<IpAddr, Churn>>,
+}
+
+/// Limits on connections that have not finished their handshake.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HandshakeLimits {
+    /// Most connections between accept and `Connection.Open-Ok`; 0 means
+    /// no limit.
+    pub max_pending: usize,
+    /// Failed handshakes in a row that put an address on cooldown; 0
+    /// disables cooldowns.
+    pub max_failures: u32,
+    /// How long an address on cooldown is turned away.
+    pub cooldown: Duration,
+}
+
+/// Limits on addresses that keep opening connections and closing them
+/// right away.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChurnLimits {
+    /// Short-lived connections within `window` that put an address on
+    /// cooldown; 0 disables churn tracking.
+    pub max_short_lived: u32,
+    /// Connections closed sooner than this after accept are short-lived.
+    pub min_lifetime: Duration,
+    /// Length of the sliding window short-lived connections are counted in.
+    pub window: Duration,
+    /// How long an address on cooldown is turned away.
+    pub cooldown: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Churn {
+    /// When each short-lived connection in the window closed, oldest first.
+    closed: VecDeque<Instant>,
+    cooling_until: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct Failures {
+    in_a_row: u32,
+    cooling_until: Option<Instant>,
+}
+
+/// Held from accept until the connection's handshake ends either way.
+#[derive(Debug, Default)]
+pub struct HandshakeSlot {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// What is known about an opened connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionInfo {
+    /// Connection id, as in `BrokerEvent`s.
+    pub id: u64,
+    pub user: Option<String>,
+    pub vhost: String,
+    pub peer: Option<IpAddr>,
+    /// Table the client sent in `Connection.Start-Ok`: product, version,
+    /// platform, capabilities and so on.
+    pub client_properties: FieldTable,
+    /// `connection_name` from the client properties, if the client set one.
+    pub name: Option<String>,
+    /// Capabilities the client properties announce as supported.
+    pub capabilities: Vec<String>,
+    pub negotiated: Negotiated,
+    /// Frames exchanged so far, as of when the info was read.
+    pub frames: FrameCounts,
+    /// Channels open on the connection, closing ones included.
+    pub channels: usize,
+}
+
+/// Frames a connection received and heartbeats it sent, counted as it
+/// runs, so a quiet connection that is still alive can be told from a busy
+/// one, and how much of a frame it is still waiting for the rest of, so a
+/// slow or stuck sender can be told from an idle one.
+#[derive(Debug, Default)]
+pub struct FrameCounters {
+    received: AtomicU64,
+    heartbeats_received: AtomicU64,
+    heartbeats_sent: AtomicU64,
+    partial_bytes: AtomicU64,
+}
+
+impl FrameCounters {
+    /// Counts a frame of `frame_type` read from the client.
+    pub fn count_received(&self, frame_type: u8) {
+        let counter = match frame_type {
+            FRAME_HEARTBEAT => &self.heartbeats_received,
+            _ => &self.received,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_heartbeat_sent(&self) {
+        self.heartbeats_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records how many bytes of an incomplete frame are buffered.
+    pub fn set_partial_bytes(&self, bytes: usize) {
+        self.partial_bytes.store(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> FrameCounts {
+        FrameCounts {
+            received: self.received.load(Ordering::Relaxed),
+            heartbeats_received: self.heartbeats_received.load(Ordering::Relaxed),
+            heartbeats_sent: self.heartbeats_sent.load(Ordering::Relaxed),
+            partial_bytes: self.partial_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What `FrameCounters` held at one point.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameCounts {
+    /// Method, content header and body frames.
+    pub received: u64,
+    pub heartbeats_received: u64,
+    pub heartbeats_sent: u64,
+    /// Bytes of a frame that has not fully arrived yet, buffered while the
+    /// connection waits for the rest.
+    pub partial_bytes: u64,
+}
+
+/// What the handshake of a connection settled on.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Negotiated {
+    /// Limits agreed in `Connection.Tune` / `Tune-Ok`; 0 means no limit.
+    pub channel_max: u16,
+    pub frame_max: u32,
+    /// Heartbeat interval in seconds; 0 means none.
+    pub heartbeat: u16,
+    /// SASL mechanism the client authenticated with.
+    pub mechanism: String,
+    /// Locale of the broker's reply texts.
+    pub locale: String,
+}
+
+impl ConnectionRegistry {
+    pub fn new(max_per_user: u32, max_per_ip: u32) -> Self {
+        ConnectionRegistry {
+            max_per_user,
+            max_per_ip,
+            counts: Mutex::new(HashMap::new()),
+            open: Mutex::new(BTreeMap::new()),
+            limits: HandshakeLimits::default(),
+            handshakes: None,
+            failures: Mutex::new(HashMap::new()),
+            churn_limits: ChurnLimits::default(),
+            churn: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_handshake_limits(mut self, limits: HandshakeLimits) -> Self {
+        self.handshakes = (limits.max_pending != 0).then(|| Arc::new(Semaphore::new(limits.max_pending)));
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_churn_limits(mut self, limits: ChurnLimits) -> Self {
+        self.churn_limits = limits;
+        self
+    }
+
+    /// Waits until another connection may start its handshake. Listeners
+    /// call this before accepting, so a flood of half-open connections
+    /// leaves the rest waiting in the kernel's backlog.
+    pub async fn reserve_handshake(&self) -> HandshakeSlot {
+        let Some(handshakes) = &self.handshakes else {
+            return HandshakeSlot::default();
+        };
+        if let Ok(permit) = handshakes.clone().try_acquire_owned() {
+            return HandshakeSlot { _permit: Some(permit) };
+        }
+        warn!(
+            "{} connections are in their handshake, pausing accepts",
+            self.limits.max_pending
+        );
+        let permit = handshakes
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed");
+        HandshakeSlot { _permit: Some(permit) }
+    }
+
+    /// How many connections are in their handshake, and the most that may
+    /// be; `None` without a limit.
+    pub fn pending_handshakes(&self) -> Option<(usize, usize)> {
+        let handshakes = self.handshakes.as_ref()?;
+        let max = self.limits.max_pending;
+        Some((max - handshakes.available_permits(), max))
+    }
+
+    /// Whether connections from `ip` are turned away after too many failed
+    /// handshakes or too many short-lived connections.
+    pub fn is_cooling_down(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        if let Some(until) = failures.get(&ip).and_then(|f| f.cooling_until) {
+            if now < until {
+                return true;
+            }
+            failures.remove(&ip);
+        }
+        drop(failures);
+        let mut churn = self.churn.lock().unwrap();
+        if let Some(until) = churn.get(&ip).and_then(|c| c.cooling_until) {
+            if now < until {
+                return true;
+            }
+            churn.remove(&ip);
+        }
+        false
+    }
+
+    /// Records that a connection from `ip` closed `lifetime` after it was
+    /// accepted. Enough short-lived ones within the churn window start the
+    /// address's cooldown.
+    pub fn record_close(&self, ip: IpAddr, lifetime: Duration) {
+        let limits = &self.churn_limits;
+        if limits.max_short_lived == 0 || lifetime >= limits.min_lifetime {
+            return;
+        }
+        let now = Instant::now();
+        let mut churn = self.churn.lock().unwrap();
+        let entry = churn.entry(ip).or_default();
+        while entry
+            .closed
+            .front()
+            .is_some_and(|&closed| now.saturating_duration_since(closed) >= limits.window)
+        {
+            entry.closed.pop_front();
+        }
+        entry.closed.push_back(now);
+        if entry.closed.len() >= limits.max_short_lived as usize {
+            warn!(
+                "{} connections from {} closed within {:?} of opening in the last {:?}, refusing it for {:?}",
+                entry.closed.len(),
+                ip,
+                limits.min_lifetime,
+                limits.window,
+                limits.cooldown
+            );
+            entry.closed.clear();
+            entry.cooling_until = Some(now + limits.cooldown);
+        }
+    }
+
+    /// Records how a handshake from `ip` ended. A success clears the
+    /// address's failures; enough failures in a row start its cooldown.
+    pub fn record_handshake(&self, ip: IpAddr, succeeded: bool) {
+        let mut failures = self.failures.lock().unwrap();
+        if succeeded {
+            failures.remove(&ip);
+            return;
+        }
+        if self.limits.max_failures == 0 {
+            return;
+        }
+        let entry = failures.entry(ip).or_default();
+        entry.in_a_row += 1;
+        if entry.in_a_row >= self.limits.max_failures {
+            warn!(
+                "{} failed handshakes in a row from {}, refusing it for {:?}",
+                entry.in_a_row, ip, self.limits.cooldown
+            );
+            entry.in_a_row = 0;
+            entry.cooling_until = Some(Instant::now() + self.limits.cooldown);
+        }
+    }
+
+    /// Counts one more connection against `key`, or returns `None` if that
+    /// would exceed its limit. The count is released when the permit drops.
+    pub fn acquire(self: &Arc<Self>, key: ConnectionKey) -> Option<ConnectionPermit> {
+        let limit = match key {
+            ConnectionKey::User(_) => self.max_per_user,
+            ConnectionKey::Ip(_) => self.max_per_ip,
+        };
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(key.clone()).or_default();
+        if limit != 0 && *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionPermit {
+            registry: self.clone(),
+            key,
+        })
+    }
+
+    /// Number of open connections counted against `key`.
+    pub fn count(&self, key: &ConnectionKey) -> u32 {
+        self.counts.lock().unwrap().get(key).copied().unwrap_or(0)
+    }
+
+    /// Lists an opened connection, along with the trace of its frames,
+    /// until the returned entry drops.
+    pub fn list(
+        self: &Arc<Self>,
+        info: ConnectionInfo,
+        trace: Arc<FrameTrace>,
+        frames: Arc<FrameCounters>,
+    ) -> ConnectionEntry {
+        let id = info.id;
+        self.open
+            .lock()
+            .unwrap()
+            .insert(id, OpenConnection { info, trace, frames });
+        ConnectionEntry {
+            registry: self.clone(),
+            id,
+        }
+    }
+
+    /// The opened connections, by ascending id.
+    pub fn open_connections(&self) -> Vec<ConnectionInfo> {
+        self.open.lock().unwrap().values().map(OpenConnection::info).collect()
+    }
+
+    /// The open connection with `id`.
+    pub fn connection(&self, id: u64) -> Option<ConnectionInfo> {
+        self.open.lock().unwrap().get(&id).map(OpenConnection::info)
+    }
+
+    /// Records how many channels the open connection with `id` has.
+    pub fn set_channels(&self, id: u64, channels: usize) {
+        if let Some(open) = self.open.lock().unwrap().get_mut(&id) {
+            open.info.channels = channels;
+        }
+    }
+
+    /// The frame trace of the open connection with `id`.
+    pub fn trace(&self, id: u64) -> Option<Arc<FrameTrace>> {
+        self.open.lock().unwrap().get(&id).map(|open| open.trace.clone())
+    }
+}
+
+/// Keeps a connection in the list of open connections.
+#[derive(Debug)]
+pub struct ConnectionEntry {
+    registry: Arc<ConnectionRegistry>,
+    id: u64,
+}
+
+impl Drop for ConnectionEntry {
+    fn drop(&mut self) {
+        self.registry.open.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// A slot held by an open connection.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    registry: Arc<ConnectionRegistry>,
+    key: ConnectionKey,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut counts = self.registry.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permits_are_released_on_drop() {
+        let registry = Arc::new(ConnectionRegistry::new(2, 0));
+        let key = ConnectionKey::User("guest".into());
+        let first = registry.acquire(key.clone()).unwrap();
+        let _second = registry.acquire(key.clone()).unwrap();
+        assert!(registry.acquire(key.clone()).is_none());
+
+        drop(first);
+        assert_eq!(registry.count(&key), 1);
+        assert!(registry.acquire(key.clone()).is_some());
+    }
+
+    #[test]
+    fn test_listed_connections_leave_on_drop() {
+        let registry = Arc::new(ConnectionRegistry::new(0, 0));
+        let info = |id| ConnectionInfo {
+            id,
+            user: Some("guest".into()),
+            vhost: "/".into(),
+            peer: None,
+            client_properties: FieldTable::new(),
+            name: None,
+            capabilities: Vec::new(),
+            negotiated: Negotiated::default(),
+            frames: FrameCounts::default(),
+            channels: 0,
+        };
+        let second = registry.list(info(2), Arc::default(), Arc::default());
+        let first = registry.list(info(1), Arc::default(), Arc::default());
+        let ids = |registry: &ConnectionRegistry| registry.open_connections().iter().map(|c| c.id).collect::<Vec<_>>();
+        assert_eq!(ids(&registry), vec![1, 2]);
+        drop(second);
+        assert_eq!(ids(&registry), vec![1]);
+        drop(first);
+        assert!(registry.open_connections().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_slots_hold_back_accepts() {
+        let registry = ConnectionRegistry::new(0, 0).with_handshake_limits(HandshakeLimits {
+            max_pending: 2,
+            ..Default::default()
+        });
+        let first = registry.reserve_handshake().await;
+        let _second = registry.reserve_handshake().await;
+        let wait = Duration::from_millis(20);
+        assert!(tokio::time::timeout(wait, registry.reserve_handshake()).await.is_err());
+        drop(first);
+        assert!(tokio::time::timeout(wait, registry.reserve_handshake()).await.is_ok());
+    }
+
+    #[test]
+    fn test_repeated_failures_start_a_cooldown() {
+        let registry = ConnectionRegistry::new(0, 0).with_handshake_limits(HandshakeLimits {
+            max_failures: 2,
+            cooldown: Duration::from_secs(60),
+            ..Default::default()
+        });
+        let (ip, other) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        registry.record_handshake(ip, false);
+        registry.record_handshake(ip, true);
+        registry.record_handshake(ip, false);
+        assert!(!registry.is_cooling_down(ip));
+        registry.record_handshake(ip, false);
+        assert!(registry.is_cooling_down(ip));
+        assert!(!registry.is_cooling_down(other));
+    }
+
+    #[test]
+    fn test_short_lived_connections_start_a_cooldown() {
+        let registry = ConnectionRegistry::new(0, 0).with_churn_limits(ChurnLimits {
+            max_short_lived: 3,
+            min_lifetime: Duration::from_secs(1),
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(60),
+        });
+        let ip = "10.0.0.1".parse().unwrap();
+        registry.record_close(ip, Duration::from_millis(5));
+        registry.record_close(ip, Duration::from_secs(5));
+        registry.record_close(ip, Duration::from_millis(5));
+        assert!(!registry.is_cooling_down(ip));
+        registry.record_close(ip, Duration::from_millis(5));
+        assert!(registry.is_cooling_down(ip));
+
+        // Without limits nothing is tracked.
+        let registry = ConnectionRegistry::new(0, 0);
+        for _ in 0..100 {
+            registry.record_close(ip, Duration::ZERO);
+        }
+        assert!(!registry.is_cooling_down(ip));
+    }
+
+    #[test]
+    fn test_churn_outside_the_window_is_forgotten() {
+        let registry = ConnectionRegistry::new(0, 0).with_churn_limits(ChurnLimits {
+            max_short_lived: 2,
+            min_lifetime: Duration::from_secs(1),
+            window: Duration::from_millis(20),
+            cooldown: Duration::from_secs(60),
+        });
+        let ip = "10.0.0.1".parse().unwrap();
+        registry.record_close(ip, Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(30));
+        registry.record_close(ip, Duration::ZERO);
+        assert!(!registry.is_cooling_down(ip));
+        registry.record_close(ip, Duration::ZERO);
+        assert!(registry.is_cooling_down(ip));
+    }
+
+    #[test]
+    fn test_zero_limit_is_unlimited() {
+        let registry = Arc::new(ConnectionRegistry::new(0, 0));
+        let ip = ConnectionKey::Ip("127.0.0.1".parse().unwrap());
+        let permits: Vec<_> = (0..100).map(|_| registry.acquire(ip.clone()).unwrap()).collect();
+        assert_eq!(registry.count(&ip), permits.len() as u32);
+    }
+}
@@ -0,0 +1,185 @@
+// src/replay.rs
+
+//! Replays captured client frames against an in-process broker and checks
+//! what the broker answers against a golden file.
+//!
+//! A fixture under `testdata/replay/` is a `.frames` file holding what the
+//! client sends, one frame per line in hex (the protocol header counts as a
+//! frame); blank lines and lines starting with `#` are ignored. A JSON
+//! document as `GET /api/connections/{id}/trace` returns it works as well,
+//! of which only the frames the client sent are replayed, and so does a
+//! binary `wirelog` capture. The frames are
+//! written to `handle_connection` over an in-memory duplex stream, and
+//! everything the broker writes back until it closes the stream is split
+//! into frames and rendered like a fixture, each frame under a comment
+//! naming it. The rendering must match the fixture's `.golden` file.
+//!
+//! Run the tests with `HAYMQ_BLESS=1` to write the goldens afresh instead
+//! of comparing against them.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::Engine;
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::broker::Broker;
+use crate::config::Config;
+use crate::connection::handle_connection;
+use crate::methods::Method;
+use crate::protocol::{frame_len, FRAME_BODY, FRAME_HEADER, FRAME_METHOD};
+use crate::trace::{Direction, TracedFrame};
+use crate::wirelog;
+
+/// How long the broker gets to finish answering a replay.
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn fixture_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/replay")
+}
+
+/// The client frames of fixture `contents`.
+fn parse_fixture(contents: &[u8]) -> Vec<Vec<u8>> {
+    if wirelog::is_wire_log(contents) {
+        return wirelog::decode(contents)
+            .expect("invalid wire log")
+            .into_iter()
+            .filter(|frame| frame.direction == Direction::In)
+            .map(|frame| frame.bytes)
+            .collect();
+    }
+    let text = std::str::from_utf8(contents).expect("fixture is neither a wire log nor text");
+    if text.trim_start().starts_with('{') {
+        let trace: Value = serde_json::from_str(text).expect("invalid trace JSON");
+        return trace["frames"]
+            .as_array()
+            .expect("trace without frames")
+            .iter()
+            .filter(|frame| frame["direction"] == "in")
+            .map(|frame| {
+                let encoded = frame["frame"].as_str().expect("frame is not a string");
+                base64::engine::general_purpose::STANDARD
+                    .decode(encoded)
+                    .expect("frame is not base64")
+            })
+            .collect();
+    }
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| decode_hex(line).unwrap_or_else(|| panic!("invalid hex line '{}'", line)))
+        .collect()
+}
+
+fn decode_hex(line: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = line.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Renders the bytes the broker wrote, frame by frame.
+fn render(mut bytes: &[u8]) -> String {
+    let mut out = String::new();
+    while !bytes.is_empty() {
+        let Some(len) = frame_len(bytes).filter(|&len| len <= bytes.len()) else {
+            out.push_str(&format!("# incomplete frame\n{}\n", encode_hex(bytes)));
+            break;
+        };
+        let (frame, rest) = bytes.split_at(len);
+        let channel = u16::from_be_bytes([frame[1], frame[2]]);
+        let payload = &frame[7..len - 1];
+        let name = match frame[0] {
+            FRAME_METHOD => match Method::decode(payload) {
+                Ok(method) => {
+                    let debug = format!("{:?}", method);
+                    debug.split([' ', '{']).next().unwrap_or_default().to_string()
+                }
+                Err(_) => "undecodable method".into(),
+            },
+            FRAME_HEADER => "content header".into(),
+            FRAME_BODY => format!("body, {} bytes", payload.len()),
+            other => format!("frame type {}", other),
+        };
+        out.push_str(&format!("# channel {}: {}\n{}\n", channel, name, encode_hex(frame)));
+        bytes = rest;
+    }
+    out
+}
+
+/// Feeds `frames` to a fresh broker and returns everything it wrote back.
+async fn replay(frames: &[Vec<u8>]) -> Vec<u8> {
+    let broker = Arc::new(Broker::new(Config::default()));
+    let (mut client, server) = tokio::io::duplex(1 << 16);
+    let served = tokio::spawn(async move {
+        let _ = handle_connection(server, broker).await;
+    });
+    for frame in frames {
+        client.write_all(frame).await.unwrap();
+    }
+    let mut written = Vec::new();
+    tokio::time::timeout(REPLAY_TIMEOUT, client.read_to_end(&mut written))
+        .await
+        .expect("the broker did not close the connection")
+        .unwrap();
+    served.await.unwrap();
+    written
+}
+
+/// Replays fixture `name` and compares the answer with its golden file,
+/// or writes the golden file when `HAYMQ_BLESS` is set.
+async fn check_golden(name: &str) {
+    let dir = fixture_dir();
+    let fixture = std::fs::read(dir.join(format!("{}.frames", name))).unwrap();
+    let rendered = render(&replay(&parse_fixture(&fixture)).await);
+    let golden = dir.join(format!("{}.golden", name));
+    if std::env::var_os("HAYMQ_BLESS").is_some() {
+        std::fs::write(&golden, rendered).unwrap();
+        return;
+    }
+    let expected = std::fs::read_to_string(&golden)
+        .unwrap_or_else(|e| panic!("cannot read {}: {}; run with HAYMQ_BLESS=1", golden.display(), e));
+    assert!(
+        rendered == expected,
+        "replay of {} differs from {}; run with HAYMQ_BLESS=1 if the change is intended\n{}",
+        name,
+        golden.display(),
+        rendered
+    );
+}
+
+#[tokio::test]
+async fn test_replay_handshake() {
+    check_golden("handshake").await;
+}
+
+#[test]
+fn test_trace_documents_replay_client_frames() {
+    let trace = r#"{"active": false, "frames": [
+        {"direction": "in", "timestamp": 1, "frame": "QU1RUA=="},
+        {"direction": "out", "timestamp": 2, "frame": "AAAA"}
+    ]}"#;
+    assert_eq!(parse_fixture(trace.as_bytes()), vec![b"AMQP".to_vec()]);
+    assert_eq!(parse_fixture(b"# header\n414d 5150\n\n"), vec![b"AMQP".to_vec()]);
+
+    let captured = [
+        (Direction::In, b"AMQP".to_vec()),
+        (Direction::Out, b"\0\0\0\0".to_vec()),
+    ];
+    let log = wirelog::encode(&captured.map(|(direction, bytes)| TracedFrame {
+        direction,
+        timestamp_ms: 1,
+        bytes,
+    }));
+    assert_eq!(parse_fixture(&log), vec![b"AMQP".to_vec()]);
+}
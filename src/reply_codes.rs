@@ -0,0 +1,114 @@
+// src/reply_codes.rs
+
+//! AMQP 0.9.1 reply codes used in `Channel.Close` and `Connection.Close`.
+
+use crate::methods::Method;
+
+pub const REPLY_SUCCESS: u16 = 200;
+pub const CONTENT_TOO_LARGE: u16 = 311;
+pub const NO_ROUTE: u16 = 312;
+pub const NO_CONSUMERS: u16 = 313;
+pub const CONNECTION_FORCED: u16 = 320;
+pub const INVALID_PATH: u16 = 402;
+pub const ACCESS_REFUSED: u16 = 403;
+pub const NOT_FOUND: u16 = 404;
+pub const RESOURCE_LOCKED: u16 = 405;
+pub const PRECONDITION_FAILED: u16 = 406;
+pub const FRAME_ERROR: u16 = 501;
+pub const SYNTAX_ERROR: u16 = 502;
+pub const COMMAND_INVALID: u16 = 503;
+pub const CHANNEL_ERROR: u16 = 504;
+pub const UNEXPECTED_FRAME: u16 = 505;
+pub const RESOURCE_ERROR: u16 = 506;
+pub const NOT_ALLOWED: u16 = 530;
+pub const NOT_IMPLEMENTED: u16 = 540;
+pub const INTERNAL_ERROR: u16 = 541;
+
+/// Whether `code` is a hard error, which the spec only allows to be raised
+/// as a connection exception.
+pub fn is_hard_error(code: u16) -> bool {
+    matches!(
+        code,
+        CONNECTION_FORCED | INVALID_PATH | FRAME_ERROR
+            ..=RESOURCE_ERROR | NOT_ALLOWED | NOT_IMPLEMENTED | INTERNAL_ERROR
+    )
+}
+
+/// The spec's name for `code`, as used at the start of reply texts.
+pub fn name(code: u16) -> Option<&'static str> {
+    Some(match code {
+        REPLY_SUCCESS => "REPLY_SUCCESS",
+        CONTENT_TOO_LARGE => "CONTENT_TOO_LARGE",
+        NO_ROUTE => "NO_ROUTE",
+        NO_CONSUMERS => "NO_CONSUMERS",
+        CONNECTION_FORCED => "CONNECTION_FORCED",
+        INVALID_PATH => "INVALID_PATH",
+        ACCESS_REFUSED => "ACCESS_REFUSED",
+        NOT_FOUND => "NOT_FOUND",
+        RESOURCE_LOCKED => "RESOURCE_LOCKED",
+        PRECONDITION_FAILED => "PRECONDITION_FAILED",
+        FRAME_ERROR => "FRAME_ERROR",
+        SYNTAX_ERROR => "SYNTAX_ERROR",
+        COMMAND_INVALID => "COMMAND_INVALID",
+        CHANNEL_ERROR => "CHANNEL_ERROR",
+        UNEXPECTED_FRAME => "UNEXPECTED_FRAME",
+        RESOURCE_ERROR => "RESOURCE_ERROR",
+        NOT_ALLOWED => "NOT_ALLOWED",
+        NOT_IMPLEMENTED => "NOT_IMPLEMENTED",
+        INTERNAL_ERROR => "INTERNAL_ERROR",
+        _ => return None,
+    })
+}
+
+/// Builds the `Connection.Close` that reports `reply_code` for the method
+/// `class_id`/`method_id`; both are zero when no method caused it.
+pub fn connection_close(reply_code: u16, reply_text: impl Into<String>, class_id: u16, method_id: u16) -> Method {
+    Method::ConnectionClose {
+        reply_code,
+        reply_text: reply_text.into(),
+        class_id,
+        method_id,
+    }
+}
+
+/// Builds the `Channel.Close` that reports `reply_code` for the method
+/// `class_id`/`method_id`.
+pub fn channel_close(reply_code: u16, reply_text: impl Into<String>, class_id: u16, method_id: u16) -> Method {
+    Method::ChannelClose {
+        reply_code,
+        reply_text: reply_text.into(),
+        class_id,
+        method_id,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::methods::CLASS_QUEUE;
+    use crate::protocol::AmqpFrame;
+
+    #[test]
+    fn test_channel_close_encoding() {
+        let close = channel_close(NOT_FOUND, "NOT_FOUND - no queue 'q'", CLASS_QUEUE, 10);
+        let text = b"NOT_FOUND - no queue 'q'";
+        let mut payload = vec![0, 20, 0, 40, 0x01, 0x94, text.len() as u8];
+        payload.extend_from_slice(text);
+        payload.extend_from_slice(&[0, 50, 0, 10]);
+        let mut expected = vec![1, 0, 3];
+        expected.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        expected.extend_from_slice(&payload);
+        expected.push(0xCE);
+        assert_eq!(AmqpFrame::method(3, &close).encode(), expected);
+    }
+
+    #[test]
+    fn test_names_and_hard_errors() {
+        assert_eq!(name(PRECONDITION_FAILED), Some("PRECONDITION_FAILED"));
+        assert_eq!(name(999), None);
+        assert!(is_hard_error(INTERNAL_ERROR));
+        assert!(is_hard_error(INVALID_PATH));
+        assert!(!is_hard_error(RESOURCE_LOCKED));
+        assert!(!is_hard_error(NO_CONSUMERS));
+    }
+}
@@ -0,0 +1,132 @@
+// src/rewrite.rs
+
+//! Routing keys rewritten as messages come in.
+//!
+//! `Config::routing_key_rewrites` is an ordered list of rules. Each rule
+//! matches the exchange a message is published to and its routing key
+//! against glob patterns, and transforms the key when both match. Every
+//! matching rule applies in turn, to the key as the rules before it left
+//! it, so a rule that lowercases keys may be followed by one that strips a
+//! prefix from the lowercased key. On the default exchange the routing key
+//! names the queue, so a routing key pattern there matches queue names.
+//!
+//! Rules run once, on a client's `Basic.Publish`, before interceptors see
+//! the message and before bindings are evaluated; messages carry the key
+//! they were routed with. Messages the broker routes itself are left
+//! alone: a dead-lettered message goes to its dead letter exchange with the
+//! queue's `x-dead-letter-routing-key`, or with the key it was routed with.
+//! A `Basic.Return` hands the publisher back the key it sent.
+
+/// How a rule changes a routing key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyTransform {
+    /// ASCII letters to lowercase.
+    Lowercase,
+    /// ASCII letters to uppercase.
+    Uppercase,
+    /// Drops the prefix from keys that start with it.
+    StripPrefix(String),
+}
+
+impl KeyTransform {
+    fn apply(&self, key: &str) -> String {
+        match self {
+            KeyTransform::Lowercase => key.to_ascii_lowercase(),
+            KeyTransform::Uppercase => key.to_ascii_uppercase(),
+            KeyTransform::StripPrefix(prefix) => key.strip_prefix(prefix.as_str()).unwrap_or(key).to_string(),
+        }
+    }
+}
+
+/// A routing key rewrite, as the config file lists it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RewriteRule {
+    /// Glob of the exchanges the rule applies to; `*` for all of them.
+    pub exchange: String,
+    /// Glob of the routing keys the rule applies to.
+    pub routing_key: String,
+    pub transform: KeyTransform,
+}
+
+/// Applies `rules`, in order, to a message published to `exchange` with
+/// `routing_key`. Returns the new key, or `None` when no rule changed it.
+pub fn rewrite(rules: &[RewriteRule], exchange: &str, routing_key: &str) -> Option<String> {
+    let mut key = None;
+    for rule in rules {
+        let current = key.as_deref().unwrap_or(routing_key);
+        if glob_matches(&rule.exchange, exchange) && glob_matches(&rule.routing_key, current) {
+            key = Some(rule.transform.apply(current));
+        }
+    }
+    key.filter(|key| key != routing_key)
+}
+
+/// Whether `name` matches the glob `pattern`: `*` matches any run of
+/// characters, `?` exactly one, and everything else itself.
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was and where in `name` its match ends so far.
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(exchange: &str, routing_key: &str, transform: KeyTransform) -> RewriteRule {
+        RewriteRule {
+            exchange: exchange.into(),
+            routing_key: routing_key.into(),
+            transform,
+        }
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("deploy-*", "deploy-42"));
+        assert!(glob_matches("deploy-*", "deploy-"));
+        assert!(glob_matches("*.jobs", "eu.west.jobs"));
+        assert!(glob_matches("a?c*z", "abcxyz"));
+        assert!(!glob_matches("deploy-*", "predeploy-42"));
+        assert!(!glob_matches("a?c", "ac"));
+        assert!(!glob_matches("*.jobs", "jobs"));
+    }
+
+    #[test]
+    fn test_rules_apply_in_order_to_matching_messages() {
+        let rules = [
+            rule("orders", "*", KeyTransform::Lowercase),
+            rule("*", "legacy.*", KeyTransform::StripPrefix("legacy.".into())),
+        ];
+        assert_eq!(
+            rewrite(&rules, "orders", "Legacy.EU.Created").as_deref(),
+            Some("eu.created")
+        );
+        assert_eq!(rewrite(&rules, "audit", "legacy.Created").as_deref(), Some("Created"));
+        // The prefix is matched case-sensitively when the exchange is not lowercased.
+        assert_eq!(rewrite(&rules, "audit", "Legacy.Created"), None);
+        assert_eq!(rewrite(&rules, "orders", "eu.created"), None);
+    }
+}
@@ -0,0 +1,329 @@
+// src/selftest.rs
+
+//! A startup self-test that exercises the broker the way a client would.
+//!
+//! With `Config::self_test` set the broker is checked before any listener
+//! accepts traffic: an in-process client connects over a duplex stream,
+//! completes the handshake, declares a temporary exchange and queue, binds
+//! them, publishes one message with publisher confirms, consumes and acks
+//! it, and deletes what it declared. Any step that does not get the answer
+//! a client expects fails the self-test, naming the step.
+//!
+//! The client logs in as the first configured user with access to the
+//! default vhost, or as `guest` when no users are configured.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+use crate::broker::Broker;
+use crate::connection::handle_connection;
+use crate::field_table::FieldTable;
+use crate::message::Message;
+use crate::methods::Method;
+use crate::properties::BasicProperties;
+use crate::protocol::{frame_len, parse_amqp_frame, AmqpFrame, FRAME_BODY, FRAME_METHOD, PROTOCOL_HEADER};
+use crate::vhost::DEFAULT_VHOST;
+
+/// How long the whole self-test may take.
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(5);
+const CHANNEL: u16 = 1;
+const BODY: &[u8] = b"haymq self-test";
+
+/// A self-test step that did not go as a client expects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestError {
+    pub step: &'static str,
+    pub detail: String,
+}
+
+impl fmt::Display for SelfTestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "self-test failed at {}: {}", self.step, self.detail)
+    }
+}
+
+impl std::error::Error for SelfTestError {}
+
+/// Runs the self-test against `broker`.
+pub async fn run(broker: Arc<Broker>) -> Result<(), SelfTestError> {
+    let (username, password) = credentials(&broker);
+    let (stream, server) = tokio::io::duplex(1 << 16);
+    let served = tokio::spawn(handle_connection(server, broker));
+    let mut client = SelfTestClient {
+        stream,
+        buf: Vec::new(),
+        step: "connect",
+    };
+    let result = tokio::time::timeout(SELF_TEST_TIMEOUT, client.exercise(&username, &password)).await;
+    served.abort();
+    match result {
+        Ok(result) => result,
+        Err(_) => Err(client.fail(format!("no answer within {:?}", SELF_TEST_TIMEOUT))),
+    }
+}
+
+fn credentials(broker: &Broker) -> (String, String) {
+    broker
+        .config()
+        .users
+        .iter()
+        .find(|user| user.vhosts.iter().any(|vhost| vhost == DEFAULT_VHOST))
+        .map(|user| (user.name.clone(), user.password.clone()))
+        .unwrap_or_else(|| ("guest".into(), "guest".into()))
+}
+
+struct SelfTestClient {
+    stream: DuplexStream,
+    buf: Vec<u8>,
+    /// The step under way, reported if it fails.
+    step: &'static str,
+}
+
+impl SelfTestClient {
+    async fn exercise(&mut self, username: &str, password: &str) -> Result<(), SelfTestError> {
+        let name = format!("haymq.self-test.{}", std::process::id());
+
+        self.step = "handshake";
+        self.write(&PROTOCOL_HEADER).await?;
+        self.expect(|m| matches!(m, Method::ConnectionStart { .. })).await?;
+        let start_ok = Method::ConnectionStartOk {
+            client_properties: FieldTable::new(),
+            mechanism: "PLAIN".into(),
+            response: format!("\0{}\0{}", username, password).into_bytes(),
+            locale: "en_US".into(),
+        };
+        self.send(0, &start_ok).await?;
+        let (tune_ok, frame_max) = match self.recv_method().await? {
+            Method::ConnectionTune {
+                channel_max,
+                frame_max,
+                heartbeat,
+            } => {
+                let tune_ok = Method::ConnectionTuneOk {
+                    channel_max,
+                    frame_max,
+                    heartbeat,
+                };
+                (tune_ok, frame_max)
+            }
+            other => return Err(self.unexpected(&other)),
+        };
+        self.send(0, &tune_ok).await?;
+        let open = Method::ConnectionOpen {
+            virtual_host: DEFAULT_VHOST.into(),
+        };
+        self.send(0, &open).await?;
+        self.expect(|m| *m == Method::ConnectionOpenOk).await?;
+        self.send(CHANNEL, &Method::ChannelOpen).await?;
+        self.expect(|m| *m == Method::ChannelOpenOk).await?;
+
+        self.step = "declare";
+        let declare_exchange = Method::ExchangeDeclare {
+            exchange: name.clone(),
+            kind: "direct".into(),
+            passive: false,
+            durable: false,
+            auto_delete: false,
+            internal: false,
+            nowait: false,
+            arguments: FieldTable::new(),
+        };
+        self.send(CHANNEL, &declare_exchange).await?;
+        self.expect(|m| *m == Method::ExchangeDeclareOk).await?;
+        let declare_queue = Method::QueueDeclare {
+            queue: name.clone(),
+            passive: false,
+            durable: false,
+            exclusive: true,
+            auto_delete: false,
+            nowait: false,
+            arguments: FieldTable::new(),
+        };
+        self.send(CHANNEL, &declare_queue).await?;
+        self.expect(|m| matches!(m, Method::QueueDeclareOk { .. })).await?;
+        let bind = Method::QueueBind {
+            queue: name.clone(),
+            exchange: name.clone(),
+            routing_key: name.clone(),
+            nowait: false,
+            arguments: FieldTable::new(),
+        };
+        self.send(CHANNEL, &bind).await?;
+        self.expect(|m| *m == Method::QueueBindOk).await?;
+
+        self.step = "publish";
+        self.send(CHANNEL, &Method::ConfirmSelect { nowait: false }).await?;
+        self.expect(|m| *m == Method::ConfirmSelectOk).await?;
+        let publish = Method::BasicPublish {
+            exchange: name.clone(),
+            routing_key: name.clone(),
+            mandatory: true,
+            immediate: false,
+        };
+        let message = Message {
+            exchange: name.clone(),
+            routing_key: name.clone(),
+            properties: BasicProperties::default(),
+            body: BODY.to_vec(),
+        };
+        for frame in message.into_frames(CHANNEL, &publish, frame_max) {
+            self.write(&frame.encode()).await?;
+        }
+        let ack = Method::BasicAck {
+            delivery_tag: 1,
+            multiple: false,
+        };
+        self.expect(|m| *m == ack).await?;
+
+        self.step = "consume";
+        let consume = Method::BasicConsume {
+            queue: name.clone(),
+            consumer_tag: String::new(),
+            no_local: false,
+            no_ack: false,
+            exclusive: false,
+            nowait: false,
+            arguments: FieldTable::new(),
+        };
+        self.send(CHANNEL, &consume).await?;
+        self.expect(|m| matches!(m, Method::BasicConsumeOk { .. })).await?;
+        let delivery_tag = match self.recv_method().await? {
+            Method::BasicDeliver { delivery_tag, .. } => delivery_tag,
+            other => return Err(self.unexpected(&other)),
+        };
+        let header = self.recv().await?;
+        let mut body = Vec::new();
+        while body.len() < BODY.len() {
+            let frame = self.recv().await?;
+            if frame.frame_type != FRAME_BODY {
+                return Err(self.fail(format!("expected the message body after {:?}", header)));
+            }
+            body.extend(frame.payload);
+        }
+        if body != BODY {
+            return Err(self.fail(format!("consumed {:?} instead of the published message", body)));
+        }
+        let ack = Method::BasicAck {
+            delivery_tag,
+            multiple: false,
+        };
+        self.send(CHANNEL, &ack).await?;
+
+        self.step = "teardown";
+        let delete_queue = Method::QueueDelete {
+            queue: name.clone(),
+            if_unused: false,
+            if_empty: true,
+            nowait: false,
+        };
+        self.send(CHANNEL, &delete_queue).await?;
+        self.expect(|m| *m == Method::QueueDeleteOk { message_count: 0 })
+            .await?;
+        let delete_exchange = Method::ExchangeDelete {
+            exchange: name,
+            if_unused: false,
+            nowait: false,
+        };
+        self.send(CHANNEL, &delete_exchange).await?;
+        self.expect(|m| *m == Method::ExchangeDeleteOk).await?;
+        let close = Method::ConnectionClose {
+            reply_code: 200,
+            reply_text: "self-test done".into(),
+            class_id: 0,
+            method_id: 0,
+        };
+        self.send(0, &close).await?;
+        self.expect(|m| *m == Method::ConnectionCloseOk).await?;
+        Ok(())
+    }
+
+    fn fail(&self, detail: String) -> SelfTestError {
+        SelfTestError {
+            step: self.step,
+            detail,
+        }
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> Result<(), SelfTestError> {
+        self.stream
+            .write_all(bytes)
+            .await
+            .map_err(|e| self.fail(format!("cannot write to the broker: {}", e)))
+    }
+
+    async fn send(&mut self, channel: u16, method: &Method) -> Result<(), SelfTestError> {
+        self.write(&AmqpFrame::method(channel, method).encode()).await
+    }
+
+    async fn recv(&mut self) -> Result<AmqpFrame, SelfTestError> {
+        loop {
+            if let Some(len) = frame_len(&self.buf).filter(|&len| self.buf.len() >= len) {
+                let frame = parse_amqp_frame(&self.buf[..len]).map_err(|e| self.fail(e.to_string()))?;
+                self.buf.drain(..len);
+                return Ok(frame);
+            }
+            let mut chunk = [0u8; 4096];
+            match self.stream.read(&mut chunk).await {
+                Ok(0) => return Err(self.fail("the broker closed the connection".into())),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(e) => return Err(self.fail(format!("cannot read from the broker: {}", e))),
+            }
+        }
+    }
+
+    fn unexpected(&self, method: &Method) -> SelfTestError {
+        self.fail(format!("unexpected {:?}", method))
+    }
+
+    async fn recv_method(&mut self) -> Result<Method, SelfTestError> {
+        let frame = self.recv().await?;
+        if frame.frame_type != FRAME_METHOD {
+            return Err(self.fail(format!("expected a method, got {:?}", frame)));
+        }
+        Method::decode(&frame.payload).map_err(|e| self.fail(format!("{:?}", e)))
+    }
+
+    /// Reads the next method, which must satisfy `expected`.
+    async fn expect(&mut self, expected: impl Fn(&Method) -> bool) -> Result<(), SelfTestError> {
+        let method = self.recv_method().await?;
+        if !expected(&method) {
+            return Err(self.unexpected(&method));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::intercept::{InterceptAction, Interceptor, PublishCtx};
+
+    #[tokio::test]
+    async fn test_self_test_passes_on_a_healthy_broker() {
+        let broker = Arc::new(Broker::new(Config::default()));
+        run(broker.clone()).await.unwrap();
+        assert!(broker.queue_summaries().is_empty());
+    }
+
+    /// Refuses every publish, as if publishing were switched off.
+    struct RefuseAll;
+
+    #[async_trait::async_trait]
+    impl Interceptor for RefuseAll {
+        async fn on_publish(&self, _message: &mut Message, _ctx: &PublishCtx) -> InterceptAction {
+            InterceptAction::Reject("publishing is disabled".into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_self_test_fails_without_publishing() {
+        let broker = Arc::new(Broker::with_interceptors(Config::default(), vec![Box::new(RefuseAll)]));
+        let err = run(broker).await.unwrap_err();
+        assert_eq!(err.step, "publish");
+        assert!(err.detail.contains("BasicNack"), "{}", err);
+    }
+}
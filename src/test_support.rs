@@ -0,0 +1,291 @@
+// src/test_support.rs
+
+//! An in-process AMQP client for exercising `handle_connection` in tests.
+
+#![allow(dead_code)]
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::task::JoinHandle;
+
+use crate::broker::Broker;
+use crate::config::Config;
+use crate::connection::handle_tls_connection;
+use crate::field_table::FieldTable;
+use crate::methods::Method;
+use crate::properties::{BasicProperties, ContentHeader};
+use crate::protocol::{frame_len, parse_amqp_frame, AmqpFrame, FRAME_METHOD};
+
+pub struct TestClient<S = DuplexStream> {
+    pub broker: Arc<Broker>,
+    stream: S,
+    buf: Vec<u8>,
+    pub server: JoinHandle<()>,
+}
+
+impl TestClient {
+    pub async fn connect(config: Config) -> TestClient {
+        TestClient::connect_to(Arc::new(Broker::new(config))).await
+    }
+
+    /// Connects to an existing broker and sends the protocol header.
+    pub async fn connect_to(broker: Arc<Broker>) -> TestClient {
+        TestClient::connect_from(broker, None).await
+    }
+
+    /// Connects to an existing broker as if from `peer`.
+    pub async fn connect_from(broker: Arc<Broker>, peer: Option<IpAddr>) -> TestClient {
+        TestClient::connect_tls(broker, peer, None).await
+    }
+
+    /// Connects as if over TLS, with `server_name` sent via SNI.
+    pub async fn connect_tls(broker: Arc<Broker>, peer: Option<IpAddr>, server_name: Option<&str>) -> TestClient {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let server_broker = broker.clone();
+        let server_name = server_name.map(String::from);
+        let server = tokio::spawn(async move {
+            let _ = handle_tls_connection(server, peer, server_name, server_broker).await;
+        });
+        let mut client = TestClient {
+            broker,
+            stream: client,
+            buf: Vec::new(),
+            server,
+        };
+        client.send_raw(b"AMQP\x00\x00\x09\x01").await;
+        client
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> TestClient<S> {
+    /// Wraps an already connected client stream; the caller sends the protocol header.
+    pub fn from_stream(broker: Arc<Broker>, stream: S, server: JoinHandle<()>) -> TestClient<S> {
+        TestClient {
+            broker,
+            stream,
+            buf: Vec::new(),
+            server,
+        }
+    }
+
+    pub async fn send_raw(&mut self, bytes: &[u8]) {
+        self.stream.write_all(bytes).await.unwrap();
+        self.stream.flush().await.unwrap();
+    }
+
+    /// Half-closes the connection: the broker sees EOF, but can still write.
+    pub async fn shutdown(&mut self) {
+        self.stream.shutdown().await.unwrap();
+    }
+
+    pub async fn send_frame(&mut self, frame: &AmqpFrame) {
+        self.send_raw(&frame.encode()).await;
+    }
+
+    pub async fn send_method(&mut self, channel: u16, method: &Method) {
+        self.send_frame(&AmqpFrame::method(channel, method)).await;
+    }
+
+    /// Publishes a message as method, header and (possibly empty) body frames.
+    pub async fn publish(&mut self, channel: u16, exchange: &str, routing_key: &str, properties: BasicProperties, body: &[u8]) {
+        let method = Method::BasicPublish {
+            exchange: exchange.into(),
+            routing_key: routing_key.into(),
+            mandatory: false,
+            immediate: false,
+        };
+        self.send_method(channel, &method).await;
+        let header = ContentHeader {
+            class_id: 60,
+            body_size: body.len() as u64,
+            properties,
+        };
+        self.send_frame(&AmqpFrame::header(channel, &header)).await;
+        if !body.is_empty() {
+            self.send_frame(&AmqpFrame::body(channel, body)).await;
+        }
+    }
+
+    /// Reads the next frame, failing the test if none arrives within a second.
+    pub async fn recv_frame(&mut self) -> AmqpFrame {
+        self.try_recv_frame(Duration::from_secs(1))
+            .await
+            .expect("timed out waiting for a frame")
+    }
+
+    /// Reads the next frame, returning `None` on timeout or EOF.
+    pub async fn try_recv_frame(&mut self, timeout: Duration) -> Option<AmqpFrame> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            if let Some(len) = frame_len(&self.buf).filter(|&len| self.buf.len() >= len) {
+                let frame = parse_amqp_frame(&self.buf[..len]).unwrap();
+                self.buf.drain(..len);
+                return Some(frame);
+            }
+            let mut chunk = [0u8; 4096];
+            let n = tokio::time::timeout_at(deadline, self.stream.read(&mut chunk))
+                .await
+                .ok()?
+                .ok()?;
+            if n == 0 {
+                return None;
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    pub async fn recv_method(&mut self) -> (u16, Method) {
+        let frame = self.recv_frame().await;
+        assert_eq!(frame.frame_type, FRAME_METHOD, "expected a method frame, got {:?}", frame);
+        (frame.channel, Method::decode(&frame.payload).unwrap())
+    }
+
+    pub async fn handshake(&mut self) {
+        self.handshake_with(0).await;
+    }
+
+    /// Completes the handshake, proposing `channel_max` in `Connection.Tune-Ok`.
+    pub async fn handshake_with(&mut self, channel_max: u16) {
+        self.tune(channel_max, None).await;
+        self.open().await;
+    }
+
+    /// Completes the handshake, announcing `client_properties` in
+    /// `Connection.Start-Ok`.
+    pub async fn handshake_with_properties(&mut self, client_properties: FieldTable) {
+        self.tune_with_properties(0, None, client_properties).await;
+        self.open().await;
+    }
+
+    /// Answers `Connection.Start` and `Connection.Tune`, proposing
+    /// `channel_max` and either `frame_max` or the broker's own offer.
+    pub async fn tune(&mut self, channel_max: u16, frame_max: Option<u32>) {
+        self.tune_with_properties(channel_max, frame_max, FieldTable::new())
+            .await;
+    }
+
+    async fn tune_with_properties(&mut self, channel_max: u16, frame_max: Option<u32>, client_properties: FieldTable) {
+        let (_, start) = self.recv_method().await;
+        assert!(matches!(start, Method::ConnectionStart { .. }), "got {:?}", start);
+        self.send_method(
+            0,
+            &Method::ConnectionStartOk {
+                client_properties,
+                mechanism: "PLAIN".into(),
+                response: b"\0guest\0guest".to_vec(),
+                locale: "en_US".into(),
+            },
+        )
+        .await;
+        let (_, tune) = self.recv_method().await;
+        let Method::ConnectionTune {
+            frame_max: offered,
+            heartbeat,
+            ..
+        } = tune
+        else {
+            panic!("expected Connection.Tune, got {:?}", tune);
+        };
+        self.send_method(
+            0,
+            &Method::ConnectionTuneOk {
+                channel_max,
+                frame_max: frame_max.unwrap_or(offered),
+                heartbeat,
+            },
+        )
+        .await;
+    }
+
+    /// Sends `Connection.Open` for `/` and waits for `Open-Ok`.
+    pub async fn open(&mut self) {
+        self.open_vhost("/").await;
+    }
+
+    /// Sends `Connection.Open` for `vhost` and waits for `Open-Ok`.
+    pub async fn open_vhost(&mut self, vhost: &str) {
+        self.send_vhost_open(vhost).await;
+        let (_, open_ok) = self.recv_method().await;
+        assert_eq!(open_ok, Method::ConnectionOpenOk);
+    }
+
+    pub async fn send_vhost_open(&mut self, vhost: &str) {
+        self.send_method(
+            0,
+            &Method::ConnectionOpen {
+                virtual_host: vhost.into(),
+            },
+        )
+        .await;
+    }
+
+    pub async fn open_channel(&mut self, channel: u16) {
+        self.send_method(channel, &Method::ChannelOpen).await;
+        let (id, reply) = self.recv_method().await;
+        assert_eq!((id, reply), (channel, Method::ChannelOpenOk));
+    }
+
+    /// Starts a consumer on `queue` and returns its consumer tag.
+    pub async fn consume(&mut self, channel: u16, queue: &str, no_ack: bool) -> String {
+        self.send_method(
+            channel,
+            &Method::BasicConsume {
+                queue: queue.into(),
+                consumer_tag: "".into(),
+                no_local: false,
+                no_ack,
+                exclusive: false,
+                nowait: false,
+                arguments: FieldTable::new(),
+            },
+        )
+        .await;
+        match self.recv_method().await {
+            (id, Method::BasicConsumeOk { consumer_tag }) if id == channel => consumer_tag,
+            other => panic!("expected Basic.Consume-Ok, got {:?}", other),
+        }
+    }
+
+    /// Reads a `Basic.Deliver` with its content, returning the method and body.
+    /// Returns `None` if nothing arrives within `timeout`.
+    pub async fn try_recv_delivery(&mut self, timeout: Duration) -> Option<(Method, Vec<u8>)> {
+        let frame = self.try_recv_frame(timeout).await?;
+        let deliver = Method::decode(&frame.payload).unwrap();
+        assert!(matches!(deliver, Method::BasicDeliver { .. }), "got {:?}", deliver);
+        let header = ContentHeader::decode(&self.recv_frame().await.payload).unwrap();
+        let mut body = Vec::new();
+        while (body.len() as u64) < header.body_size {
+            body.extend(self.recv_frame().await.payload);
+        }
+        Some((deliver, body))
+    }
+
+    pub async fn recv_delivery(&mut self) -> (Method, Vec<u8>) {
+        self.try_recv_delivery(Duration::from_secs(1))
+            .await
+            .expect("timed out waiting for a delivery")
+    }
+
+    /// Waits for `Connection.Close` and asserts its reply code.
+    pub async fn expect_connection_close(&mut self, reply_code: u16) {
+        let (channel, method) = self.recv_method().await;
+        assert_eq!(channel, 0);
+        match method {
+            Method::ConnectionClose { reply_code: code, .. } => assert_eq!(code, reply_code),
+            other => panic!("expected Connection.Close, got {:?}", other),
+        }
+    }
+
+    /// Waits for `Channel.Close` and asserts its reply code.
+    pub async fn expect_channel_close(&mut self, channel: u16, reply_code: u16) {
+        let (id, method) = self.recv_method().await;
+        assert_eq!(id, channel);
+        match method {
+            Method::ChannelClose { reply_code: code, .. } => assert_eq!(code, reply_code),
+            other => panic!("expected Channel.Close, got {:?}", other),
+        }
+    }
+}
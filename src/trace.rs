@@ -0,0 +1,159 @@
+// src/trace.rs
+
+//! Capture of the frames one connection sends and receives, for debugging
+//! a specific client.
+//!
+//! Every connection owns a `FrameTrace` that does nothing until the
+//! management API starts it. A started trace records each encoded frame
+//! with its direction and time until its deadline passes or it holds
+//! `MAX_TRACE_BYTES` of frames; then it stops by itself and keeps what it
+//! captured for retrieval. The capture is kept as a `wirelog`, which is
+//! also how it can be downloaded for `haymq decode`.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::wirelog;
+
+/// Most frame bytes one trace keeps.
+pub const MAX_TRACE_BYTES: usize = 1024 * 1024;
+/// Longest a trace may run.
+pub const MAX_TRACE_DURATION: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Sent by the client.
+    In,
+    /// Sent by the broker.
+    Out,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TracedFrame {
+    pub direction: Direction,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    /// The frame as it went over the wire.
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+pub struct FrameTrace {
+    /// Checked before taking the lock, so idle traces cost next to nothing.
+    active: AtomicBool,
+    state: Mutex<TraceState>,
+}
+
+#[derive(Debug, Default)]
+struct TraceState {
+    until: Option<Instant>,
+    /// Empty until the first trace starts.
+    log: Vec<u8>,
+    bytes: usize,
+}
+
+impl FrameTrace {
+    /// Starts a fresh trace that runs for at most `duration`, discarding
+    /// whatever an earlier one captured.
+    pub fn start(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        *state = TraceState {
+            until: Some(Instant::now() + duration.min(MAX_TRACE_DURATION)),
+            log: wirelog::new_log(),
+            bytes: 0,
+        };
+        self.active.store(true, Ordering::Release);
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Records `frame` if the trace is running, stopping the trace once it
+    /// is past its deadline or the frame would not fit.
+    pub fn record(&self, direction: Direction, frame: &[u8]) {
+        if !self.is_active() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let expired = state.until.is_none_or(|until| Instant::now() >= until);
+        if expired || state.bytes + frame.len() > MAX_TRACE_BYTES {
+            state.until = None;
+            self.active.store(false, Ordering::Release);
+            return;
+        }
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        state.bytes += frame.len();
+        wirelog::append(&mut state.log, direction, timestamp_ms, frame);
+    }
+
+    /// The frames captured by the current or last trace.
+    pub fn frames(&self) -> Vec<TracedFrame> {
+        let state = self.state.lock().unwrap();
+        if state.log.is_empty() {
+            return Vec::new();
+        }
+        wirelog::decode(&state.log).expect("trace holds an invalid wire log")
+    }
+
+    /// The current or last capture as a wire log.
+    pub fn wire_log(&self) -> Vec<u8> {
+        let state = self.state.lock().unwrap();
+        if state.log.is_empty() {
+            return wirelog::new_log();
+        }
+        state.log.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_records_only_while_started() {
+        let trace = FrameTrace::default();
+        trace.record(Direction::In, b"before");
+        assert!(trace.frames().is_empty());
+
+        trace.start(Duration::from_secs(10));
+        trace.record(Direction::In, b"ping");
+        trace.record(Direction::Out, b"pong");
+        assert_eq!(wirelog::decode(&trace.wire_log()).unwrap(), trace.frames());
+        let frames = trace.frames();
+        assert_eq!(
+            frames.iter().map(|f| (f.direction, &f.bytes[..])).collect::<Vec<_>>(),
+            vec![(Direction::In, &b"ping"[..]), (Direction::Out, &b"pong"[..])]
+        );
+    }
+
+    #[test]
+    fn test_trace_stops_at_its_caps() {
+        let trace = FrameTrace::default();
+        trace.start(Duration::from_secs(10));
+        let big = vec![0; MAX_TRACE_BYTES / 2];
+        trace.record(Direction::In, &big);
+        trace.record(Direction::In, &big);
+        trace.record(Direction::In, b"x");
+        assert!(!trace.is_active());
+        trace.record(Direction::In, b"y");
+        assert_eq!(trace.frames().len(), 2);
+
+        trace.start(Duration::ZERO);
+        trace.record(Direction::Out, b"late");
+        assert!(!trace.is_active());
+        assert!(trace.frames().is_empty());
+    }
+}
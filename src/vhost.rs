@@ -0,0 +1,237 @@
+// src/vhost.rs
+
+//! Virtual hosts: independent namespaces of exchanges and queues.
+//!
+//! A connection opens exactly one virtual host in `Connection.Open` and all
+//! of its channels work inside it. A virtual host can be drained for
+//! maintenance: its queues stop delivering, publishes are held until it is
+//! resumed, and its connections are asked to stop publishing with
+//! `Channel.Flow`.
+//!
+//! A virtual host can also have a quota on the messages its queues hold,
+//! ready or unacked, and on their memory footprint. Publishes beyond it are
+//! either nacked or accepted while the publisher is paused, as its
+//! `quota_policy` says.
+//!
+//! Virtual host names are compared in the form `normalize_name` gives them,
+//! both as clients send them and as the configuration lists them.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use crate::exchange::{Exchange, ExchangeType};
+use crate::message::Message;
+use crate::policy::Policy;
+use crate::queue::Queue;
+use crate::rate_limit::RatePolicy;
+
+/// The virtual host every broker has.
+pub const DEFAULT_VHOST: &str = "/";
+
+/// Longest virtual host name, as `Connection.Open` carries it in a short string.
+const MAX_NAME_LEN: usize = 255;
+
+/// Exchanges every virtual host starts with, as the spec requires.
+const BUILTIN_EXCHANGES: [(&str, ExchangeType); 6] = [
+    ("", ExchangeType::Direct),
+    ("amq.direct", ExchangeType::Direct),
+    ("amq.fanout", ExchangeType::Fanout),
+    ("amq.topic", ExchangeType::Topic),
+    ("amq.headers", ExchangeType::Headers),
+    ("amq.match", ExchangeType::Headers),
+];
+
+/// How long a publisher over its virtual host's quota is paused under the
+/// `flow` policy before it may try again.
+pub const QUOTA_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+pub type QueueRef = Arc<Mutex<Queue>>;
+
+/// The canonical form of virtual host name `name`, or `None` if it is not
+/// a valid name.
+///
+/// Clients take the name from the path of an AMQP URI, where it is
+/// percent-encoded, and some send it without decoding it. `%XX` escapes
+/// are decoded, so `%2F` is the default `/`. The decoded name must be
+/// UTF-8, non-empty, at most 255 bytes long and free of whitespace and
+/// control characters.
+pub fn normalize_name(name: &str) -> Option<String> {
+    let decoded = percent_decode(name)?;
+    let valid = !decoded.is_empty()
+        && decoded.len() <= MAX_NAME_LEN
+        && !decoded.chars().any(|c| c.is_whitespace() || c.is_control());
+    valid.then_some(decoded)
+}
+
+/// Decodes the `%XX` escapes of a URI path segment, such as a virtual host
+/// name, or `None` if an escape is malformed or the result is not UTF-8.
+pub fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// Settings of one virtual host, from its `[vhost_settings.<name>]` table
+/// in the config file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VHostSettings {
+    /// Replaces the `delivery-mode` of every message published in the
+    /// virtual host, before it is routed or stored.
+    pub delivery_mode: Option<DeliveryModeOverride>,
+    /// Most messages the virtual host's queues may hold, counting each
+    /// queue's copy of a message.
+    pub max_messages: Option<u64>,
+    /// Most bytes of messages the virtual host's queues may hold, as
+    /// `Message::memory_footprint` counts them.
+    pub max_bytes: Option<u64>,
+    /// What happens to publishes beyond `max_messages` or `max_bytes`.
+    pub quota_policy: RatePolicy,
+    /// Most queues the virtual host may have.
+    pub max_queues: Option<usize>,
+    /// Most exchanges the virtual host may have, not counting the built-in
+    /// ones.
+    pub max_exchanges: Option<usize>,
+    /// Most bindings the virtual host's exchanges may have together.
+    pub max_bindings: Option<usize>,
+}
+
+/// A `delivery-mode` imposed on all messages of a virtual host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryModeOverride {
+    /// `force_persistent`: every message is persistent. As with a
+    /// persistent message from the publisher, this only matters for
+    /// durable queues; others never store their messages.
+    Persistent,
+    /// `force_transient`: no message is ever stored.
+    Transient,
+}
+
+impl DeliveryModeOverride {
+    /// The `delivery-mode` property value: 2 for persistent, 1 for transient.
+    pub fn delivery_mode(self) -> u8 {
+        match self {
+            DeliveryModeOverride::Persistent => 2,
+            DeliveryModeOverride::Transient => 1,
+        }
+    }
+}
+
+/// The messages held by a virtual host's queues, ready or unacked, and
+/// their memory footprint. Every queue of the virtual host shares it and keeps it
+/// up to date as messages arrive and leave.
+#[derive(Debug, Default)]
+pub struct VHostUsage {
+    messages: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl VHostUsage {
+    pub fn add(&self, bytes: u64) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn remove(&self, bytes: u64) {
+        self.messages.fetch_sub(1, Ordering::Relaxed);
+        self.bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub fn messages(&self) -> u64 {
+        self.messages.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+/// A publish routed while its virtual host was drained.
+#[derive(Debug)]
+pub struct HeldPublish {
+    pub targets: Vec<QueueRef>,
+    pub message: Message,
+}
+
+#[derive(Debug)]
+pub struct VHost {
+    pub name: String,
+    pub exchanges: HashMap<String, Exchange>,
+    pub queues: HashMap<String, QueueRef>,
+    /// Publishes waiting for the virtual host to resume, oldest first.
+    pub held: Vec<HeldPublish>,
+    pub settings: VHostSettings,
+    pub usage: Arc<VHostUsage>,
+    /// Policies of the virtual host, by name.
+    pub policies: BTreeMap<String, Policy>,
+    /// Whether the virtual host is drained; connections watch it to pause
+    /// and resume their channels.
+    drained: watch::Sender<bool>,
+}
+
+impl VHost {
+    pub fn new(name: String) -> Self {
+        let mut exchanges = HashMap::new();
+        for (exchange, kind) in BUILTIN_EXCHANGES {
+            let mut builtin = Exchange::new(exchange.to_string(), kind);
+            builtin.durable = true;
+            builtin.builtin = true;
+            exchanges.insert(exchange.to_string(), builtin);
+        }
+        VHost {
+            name,
+            exchanges,
+            queues: HashMap::new(),
+            held: Vec::new(),
+            settings: VHostSettings::default(),
+            usage: Arc::default(),
+            policies: BTreeMap::new(),
+            drained: watch::channel(false).0,
+        }
+    }
+
+    pub fn is_drained(&self) -> bool {
+        *self.drained.borrow()
+    }
+
+    /// Whether `messages` more messages with a footprint of `bytes` in total
+    /// would take the virtual host past its quota.
+    pub fn exceeds_quota(&self, messages: u64, bytes: u64) -> bool {
+        let over = |limit: Option<u64>, used: u64, more: u64| limit.is_some_and(|limit| used + more > limit);
+        over(self.settings.max_messages, self.usage.messages(), messages)
+            || over(self.settings.max_bytes, self.usage.bytes(), bytes)
+    }
+
+    /// How many exchanges were declared, leaving out the built-in ones.
+    pub fn declared_exchanges(&self) -> usize {
+        self.exchanges.values().filter(|exchange| !exchange.builtin).count()
+    }
+
+    pub fn bindings(&self) -> usize {
+        self.exchanges.values().map(|exchange| exchange.bindings.len()).sum()
+    }
+
+    /// Sets the drained flag and wakes every connection watching it.
+    pub fn set_drained(&self, drained: bool) {
+        self.drained.send_replace(drained);
+    }
+
+    /// Returns a receiver that sees every change of the drained flag.
+    pub fn watch(&self) -> watch::Receiver<bool> {
+        self.drained.subscribe()
+    }
+}
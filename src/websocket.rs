@@ -0,0 +1,245 @@
+// src/websocket.rs
+
+//! AMQP over WebSocket.
+//!
+//! After the WebSocket handshake every binary message carries a slice of the
+//! AMQP byte stream. Message boundaries carry no meaning: a message may hold
+//! part of a frame or several frames, so the stream is adapted to
+//! `AsyncRead + AsyncWrite` and fed to the regular `handle_connection`.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use futures_util::{Sink, Stream};
+use log::{info, warn};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::broker::Broker;
+use crate::connection;
+
+/// Subprotocol echoed back to clients that offer it.
+const SUBPROTOCOL: &str = "amqp";
+
+/// Settings for the optional WebSocket listener.
+#[derive(Debug, Clone)]
+pub struct WebSocketConfig {
+    /// Address the listener binds to.
+    pub bind: String,
+    /// Request path clients must connect to; other paths get a 404.
+    pub path: String,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            bind: "127.0.0.1:15672".into(),
+            path: "/ws".into(),
+        }
+    }
+}
+
+/// A WebSocket connection viewed as a plain byte stream.
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    /// Payload of the last binary message not yet handed to the reader.
+    read_buf: Vec<u8>,
+    read_pos: usize,
+}
+
+impl<S> WsStream<S> {
+    pub fn new(inner: WebSocketStream<S>) -> Self {
+        WsStream {
+            inner,
+            read_buf: Vec::new(),
+            read_pos: 0,
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        loop {
+            if self.read_pos < self.read_buf.len() {
+                let n = buf.remaining().min(self.read_buf.len() - self.read_pos);
+                buf.put_slice(&self.read_buf[self.read_pos..self.read_pos + n]);
+                self.read_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    self.read_buf = data;
+                    self.read_pos = 0;
+                }
+                // Pings are answered by tungstenite itself.
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "AMQP over WebSocket requires binary messages",
+                    )))
+                }
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(io::Error::other(e))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut inner = Pin::new(&mut self.inner);
+        ready!(inner.as_mut().poll_ready(cx)).map_err(io::Error::other)?;
+        inner.start_send(Message::Binary(buf.to_vec())).map_err(io::Error::other)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(io::Error::other)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx).map_err(io::Error::other)
+    }
+}
+
+/// Performs the server side of the WebSocket handshake, accepting only
+/// requests for `path`.
+pub async fn accept<S>(stream: S, path: &str) -> Result<WsStream<S>, tokio_tungstenite::tungstenite::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // The callback signature is fixed by tungstenite.
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, mut response: Response| -> Result<Response, ErrorResponse> {
+        if request.uri().path() != path {
+            let mut error = ErrorResponse::new(Some(format!("no AMQP endpoint at {}", request.uri().path())));
+            *error.status_mut() = StatusCode::NOT_FOUND;
+            return Err(error);
+        }
+        let offered = request
+            .headers()
+            .get_all("Sec-WebSocket-Protocol")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|p| p.trim() == SUBPROTOCOL);
+        if offered {
+            response
+                .headers_mut()
+                .insert("Sec-WebSocket-Protocol", HeaderValue::from_static(SUBPROTOCOL));
+        }
+        Ok(response)
+    };
+    let ws = tokio_tungstenite::accept_hdr_async(stream, callback).await?;
+    Ok(WsStream::new(ws))
+}
+
+/// Accepts WebSocket clients on `listener` and serves AMQP over each of them.
+pub async fn serve(listener: TcpListener, path: String, broker: Arc<Broker>) -> io::Result<()> {
+    let path = Arc::new(path);
+    loop {
+        let slot = broker.connections().reserve_handshake().await;
+        let (socket, addr) = listener.accept().await?;
+        if broker.listeners_paused() || broker.recovering() || broker.connections().is_cooling_down(addr.ip()) {
+            continue;
+        }
+        info!("New WebSocket connection from {:?}", addr);
+        if let Err(e) = broker.config().socket.apply(&socket) {
+            warn!("Failed to set socket options for {:?}: {}", addr, e);
+        }
+
+        let broker = broker.clone();
+        let path = path.clone();
+        tokio::spawn(async move {
+            let upgrade = tokio::time::timeout(broker.config().handshake_timeout, accept(socket, &path));
+            let stream = match upgrade.await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    warn!("WebSocket handshake with {:?} failed: {}", addr, e);
+                    broker.connections().record_handshake(addr.ip(), false);
+                    return;
+                }
+                Err(_) => {
+                    warn!("WebSocket handshake with {:?} timed out", addr);
+                    broker.connections().record_handshake(addr.ip(), false);
+                    return;
+                }
+            };
+            if let Err(e) = connection::handle_accepted_connection(stream, Some(addr.ip()), slot, broker).await {
+                e.log(&format!("{} over WebSocket", addr));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::SinkExt;
+
+    use crate::config::Config;
+    use crate::field_table::FieldTable;
+    use crate::methods::Method;
+    use crate::protocol::AmqpFrame;
+    use crate::test_support::TestClient;
+
+    async fn listen(broker: Arc<Broker>) -> (String, tokio::task::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let _ = serve(listener, "/ws".into(), broker).await;
+        });
+        (format!("ws://{}", addr), server)
+    }
+
+    #[tokio::test]
+    async fn test_handshake_over_websocket() {
+        let broker = Arc::new(Broker::new(Config::default()));
+        let (url, server) = listen(broker.clone()).await;
+        let (ws, _) = tokio_tungstenite::connect_async(format!("{}/ws", url)).await.unwrap();
+        let mut client = TestClient::from_stream(broker, WsStream::new(ws), server);
+        client.send_raw(b"AMQP\x00\x00\x09\x01").await;
+        client.handshake().await;
+        client.open_channel(1).await;
+    }
+
+    #[tokio::test]
+    async fn test_frames_may_span_websocket_messages() {
+        let broker = Arc::new(Broker::new(Config::default()));
+        let (url, server) = listen(broker.clone()).await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("{}/ws", url)).await.unwrap();
+
+        // Protocol header and Start-Ok glued together, then cut mid-frame.
+        let mut bytes = b"AMQP\x00\x00\x09\x01".to_vec();
+        let start_ok = Method::ConnectionStartOk {
+            client_properties: FieldTable::new(),
+            mechanism: "PLAIN".into(),
+            response: b"\0guest\0guest".to_vec(),
+            locale: "en_US".into(),
+        };
+        bytes.extend(AmqpFrame::method(0, &start_ok).encode());
+        let (first, second) = bytes.split_at(11);
+        ws.send(Message::Binary(first.to_vec())).await.unwrap();
+        ws.send(Message::Binary(second.to_vec())).await.unwrap();
+
+        let mut client = TestClient::from_stream(broker, WsStream::new(ws), server);
+        let (_, start) = client.recv_method().await;
+        assert!(matches!(start, Method::ConnectionStart { .. }), "got {:?}", start);
+        let (_, tune) = client.recv_method().await;
+        assert!(matches!(tune, Method::ConnectionTune { .. }), "got {:?}", tune);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_path_is_rejected() {
+        let broker = Arc::new(Broker::new(Config::default()));
+        let (url, _server) = listen(broker).await;
+        assert!(tokio_tungstenite::connect_async(format!("{}/other", url)).await.is_err());
+    }
+}
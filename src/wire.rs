@@ -0,0 +1,54 @@
+// src/wire.rs
+
+//! Primitive AMQP 0.9.1 data types shared by the method and field-table codecs.
+
+use bytes::BufMut;
+use nom::{
+    bytes::complete::take,
+    error::Error as NomError,
+    number::complete::{be_u16, be_u32, be_u64, be_u8},
+    IResult,
+};
+
+/// Most bytes a short string holds.
+pub const SHORTSTR_MAX_LEN: usize = 255;
+
+pub type ParseResult<'a, T> = IResult<&'a [u8], T, NomError<&'a [u8]>>;
+
+pub fn octet(input: &[u8]) -> ParseResult<'_, u8> {
+    be_u8(input)
+}
+
+pub fn short(input: &[u8]) -> ParseResult<'_, u16> {
+    be_u16(input)
+}
+
+pub fn long(input: &[u8]) -> ParseResult<'_, u32> {
+    be_u32(input)
+}
+
+pub fn longlong(input: &[u8]) -> ParseResult<'_, u64> {
+    be_u64(input)
+}
+
+pub fn shortstr(input: &[u8]) -> ParseResult<'_, String> {
+    let (input, len) = octet(input)?;
+    let (input, bytes) = take(len)(input)?;
+    Ok((input, String::from_utf8_lossy(bytes).into_owned()))
+}
+
+pub fn longstr(input: &[u8]) -> ParseResult<'_, Vec<u8>> {
+    let (input, len) = long(input)?;
+    let (input, bytes) = take(len)(input)?;
+    Ok((input, bytes.to_vec()))
+}
+
+pub fn put_shortstr(buf: &mut Vec<u8>, s: &str) {
+    buf.put_u8(s.len() as u8);
+    buf.put_slice(s.as_bytes());
+}
+
+pub fn put_longstr(buf: &mut Vec<u8>, s: &[u8]) {
+    buf.put_u32(s.len() as u32);
+    buf.put_slice(s);
+}
@@ -0,0 +1,202 @@
+// src/wirelog.rs
+
+//! A compact binary format for frame traces, and its hex rendering.
+//!
+//! A wire log starts with the magic bytes `HMQW` and a version byte,
+//! currently 1. Records follow back to back, one per frame:
+//! - 1 byte: direction, 0 for a frame the client sent and 1 for one the
+//!   broker sent
+//! - 8 bytes: timestamp in milliseconds since the Unix epoch
+//! - 4 bytes: frame length
+//! - the frame as it went over the wire
+//!
+//! All integers are big-endian. A reader refuses versions newer than the
+//! one it knows, so a later format can change the record layout.
+//!
+//! For reading by eye, `to_hex` renders a log one record per line as
+//! `in 1700000000000 0100010000000c...`, and `from_hex` reads that back;
+//! blank lines and lines starting with `#` are ignored.
+
+use std::io;
+
+use crate::trace::{Direction, TracedFrame};
+
+pub const MAGIC: &[u8; 4] = b"HMQW";
+pub const VERSION: u8 = 1;
+/// Bytes of a record before its frame.
+const RECORD_HEADER_LEN: usize = 13;
+
+/// A new, empty log.
+pub fn new_log() -> Vec<u8> {
+    let mut log = MAGIC.to_vec();
+    log.push(VERSION);
+    log
+}
+
+/// Appends a record of `frame` to `log`.
+pub fn append(log: &mut Vec<u8>, direction: Direction, timestamp_ms: u64, frame: &[u8]) {
+    log.push(match direction {
+        Direction::In => 0,
+        Direction::Out => 1,
+    });
+    log.extend_from_slice(&timestamp_ms.to_be_bytes());
+    log.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+    log.extend_from_slice(frame);
+}
+
+/// Writes `frames` as a log.
+pub fn encode(frames: &[TracedFrame]) -> Vec<u8> {
+    let mut log = new_log();
+    for frame in frames {
+        append(&mut log, frame.direction, frame.timestamp_ms, &frame.bytes);
+    }
+    log
+}
+
+/// Whether `bytes` start like a wire log.
+pub fn is_wire_log(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+fn invalid(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Reads the frames of a log.
+pub fn decode(log: &[u8]) -> io::Result<Vec<TracedFrame>> {
+    if !is_wire_log(log) {
+        return Err(invalid("not a wire log".into()));
+    }
+    match log.get(MAGIC.len()) {
+        Some(&VERSION) => {}
+        Some(version) => return Err(invalid(format!("unsupported wire log version {}", version))),
+        None => return Err(invalid("wire log without a version".into())),
+    }
+    let mut rest = &log[MAGIC.len() + 1..];
+    let mut frames = Vec::new();
+    while !rest.is_empty() {
+        let Some((header, after)) = rest.split_first_chunk::<RECORD_HEADER_LEN>() else {
+            return Err(invalid(format!("truncated record after {} frames", frames.len())));
+        };
+        let direction = match header[0] {
+            0 => Direction::In,
+            1 => Direction::Out,
+            other => {
+                return Err(invalid(format!(
+                    "invalid direction {} in record {}",
+                    other,
+                    frames.len()
+                )))
+            }
+        };
+        let timestamp_ms = u64::from_be_bytes(header[1..9].try_into().unwrap());
+        let len = u32::from_be_bytes(header[9..13].try_into().unwrap()) as usize;
+        if after.len() < len {
+            return Err(invalid(format!("truncated frame in record {}", frames.len())));
+        }
+        let (bytes, after) = after.split_at(len);
+        frames.push(TracedFrame {
+            direction,
+            timestamp_ms,
+            bytes: bytes.to_vec(),
+        });
+        rest = after;
+    }
+    Ok(frames)
+}
+
+/// Renders a log as text, one record per line.
+pub fn to_hex(log: &[u8]) -> io::Result<String> {
+    let mut out = format!("# wire log version {}\n", VERSION);
+    for frame in decode(log)? {
+        let hex: String = frame.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        out.push_str(&format!(
+            "{} {} {}\n",
+            frame.direction.as_str(),
+            frame.timestamp_ms,
+            hex
+        ));
+    }
+    Ok(out)
+}
+
+/// Reads a log back from the text `to_hex` renders.
+pub fn from_hex(text: &str) -> io::Result<Vec<u8>> {
+    let mut log = new_log();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let bad_line = || invalid(format!("invalid record on line {}", number + 1));
+        let mut fields = line.split_whitespace();
+        let direction = match fields.next() {
+            Some("in") => Direction::In,
+            Some("out") => Direction::Out,
+            _ => return Err(bad_line()),
+        };
+        let timestamp_ms = fields.next().and_then(|t| t.parse().ok()).ok_or_else(bad_line)?;
+        let hex = fields.next().unwrap_or_default();
+        if fields.next().is_some() || !hex.len().is_multiple_of(2) {
+            return Err(bad_line());
+        }
+        let frame = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(bad_line)?;
+        append(&mut log, direction, timestamp_ms, &frame);
+    }
+    Ok(log)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::methods::Method;
+    use crate::protocol::AmqpFrame;
+
+    fn capture() -> Vec<TracedFrame> {
+        let frames = [
+            (Direction::In, b"AMQP\x00\x00\x09\x01".to_vec()),
+            (Direction::Out, AmqpFrame::method(0, &Method::ConnectionOpenOk).encode()),
+            (Direction::In, AmqpFrame::method(1, &Method::ChannelOpen).encode()),
+            (Direction::Out, AmqpFrame::method(1, &Method::ChannelOpenOk).encode()),
+            (Direction::In, Vec::new()),
+        ];
+        frames
+            .into_iter()
+            .enumerate()
+            .map(|(i, (direction, bytes))| TracedFrame {
+                direction,
+                timestamp_ms: 1_700_000_000_000 + i as u64,
+                bytes,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_wire_log_round_trips() {
+        let frames = capture();
+        let log = encode(&frames);
+        assert_eq!(&log[..5], b"HMQW\x01");
+        assert_eq!(decode(&log).unwrap(), frames);
+
+        let hex = to_hex(&log).unwrap();
+        assert!(hex.contains("\nin 1700000000000 414d515000000901\n"), "{}", hex);
+        assert_eq!(from_hex(&hex).unwrap(), log);
+    }
+
+    #[test]
+    fn test_invalid_wire_logs_are_refused() {
+        let log = encode(&capture());
+        assert!(decode(b"AMQP").is_err());
+        let mut newer = log.clone();
+        newer[4] = VERSION + 1;
+        let err = decode(&newer).unwrap_err();
+        assert!(err.to_string().contains("version 2"), "{}", err);
+        assert!(decode(&log[..log.len() - 1]).is_err());
+        assert!(from_hex("sideways 1 00").is_err());
+        assert!(from_hex("in 1 0").is_err());
+    }
+}